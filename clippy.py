@@ -28,6 +28,8 @@ if __name__ == "__main__":
         "kernel": "aarch64-unknown-none",
         "bootloader": "aarch64-unknown-none",
         "chainloader": "aarch64-unknown-none",
+        "kados-runtime": "aarch64-unknown-none",
+        "init": "aarch64-unknown-none",
     }
 
     for pkg in members:
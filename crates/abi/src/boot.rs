@@ -0,0 +1,213 @@
+//! The memory layout the bootloader sets up and the kernel relies on.
+
+/// The physical address the firmware (or the chainloader) loads the kernel image at.
+pub const KERNEL_LOAD_ADDR: usize = 0x8_0000;
+
+/// The physical address the firmware is told to load the initrd at, when there is one.
+///
+/// It's well clear of where the chainloader receives and unpacks images, so a chainloaded
+/// kernel still finds the initrd intact.
+pub const INITRD_LOAD_ADDR: usize = 0x2000_0000;
+
+/// The physical address of the memory the kernel writes a crash record to when it panics,
+/// where the next boot can find it.
+///
+/// It sits just below [`INITRD_LOAD_ADDR`], out of the way of the firmware and the chainloader
+/// on a warm reboot.
+pub const CRASH_REGION_ADDR: usize = INITRD_LOAD_ADDR - CRASH_REGION_SIZE;
+/// The size of the crash region, header included.
+pub const CRASH_REGION_SIZE: usize = 0x4_0000;
+
+/// The offset between physical and virtual addresses when mapped linearly.
+pub const HHDM_PHYSICAL_OFFSET: usize = 0xffff_8000_0000_0000;
+
+/// The base address of the kernel in virtual memory.
+///
+/// This must match the value in the kernel's linker script.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+
+/// The `MAIR_EL1` attribute index of Device-nGnRnE memory, which MMIO is mapped with.
+pub const MAIR_IDX_DEVICE: usize = 0;
+/// The `MAIR_EL1` attribute index of Normal write-back cacheable memory, which RAM is mapped
+/// with.
+pub const MAIR_IDX_NORMAL: usize = 1;
+/// The `MAIR_EL1` attribute index of Normal non-cacheable memory, which buffers shared with DMA
+/// masters are mapped with, so neither side needs cache maintenance to see the other's writes.
+pub const MAIR_IDX_NON_CACHEABLE: usize = 2;
+/// The `MAIR_EL1` value the bootloader installs, giving each of the indices above its
+/// attributes.
+pub const MAIR: u64 = (0x00 << (MAIR_IDX_DEVICE * 8))
+    | (0xff << (MAIR_IDX_NORMAL * 8))
+    | (0x44 << (MAIR_IDX_NON_CACHEABLE * 8));
+
+/// Identifies a [`BootInfo`].
+pub const BOOT_INFO_MAGIC: [u8; 8] = *b"KADOSBI\0";
+/// The current [`BootInfo`] layout version.
+pub const BOOT_INFO_VERSION: u32 = 2;
+/// The most ranges of RAM a [`BootInfo`] can describe.
+pub const BOOT_INFO_MAX_MEMORY: usize = 32;
+
+/// A range of addresses, `start..end`. Empty where there's nothing to describe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AddrRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl AddrRange {
+    /// The range that describes nothing.
+    pub const EMPTY: Self = Self { start: 0, end: 0 };
+
+    #[must_use]
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self {
+            start: start as u64,
+            end: end as u64,
+        }
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Returns the length of the range in bytes.
+    #[must_use]
+    pub const fn len(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// A framebuffer the firmware set up and described with a `simple-framebuffer` node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootFramebuffer {
+    /// Where its pixels are in physical memory. Empty if there isn't one.
+    pub memory: AddrRange,
+    pub width: u32,
+    pub height: u32,
+    /// The bytes from the start of one line to the start of the next.
+    pub stride: u32,
+    /// Reserved, zero.
+    pub reserved: u32,
+    /// The pixel format, like `a8r8g8b8`, padded with NULs.
+    pub format: [u8; 16],
+}
+
+impl BootFramebuffer {
+    /// Returns the pixel format, without the padding.
+    #[must_use]
+    pub fn format(&self) -> &[u8] {
+        let len = self.format.iter().position(|&b| b == 0);
+        &self.format[..len.unwrap_or(self.format.len())]
+    }
+}
+
+/// What the bootloader found out about the machine, handed to the kernel's
+/// `boot_higher_half` by physical address.
+///
+/// The bootloader reads it all from the FDT and its own linker symbols, so the kernel only
+/// has to check it was built against the same layout. Physical addresses are reachable in
+/// the kernel at `hhdm_offset` above themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootInfo {
+    /// Always [`BOOT_INFO_MAGIC`].
+    pub magic: [u8; 8],
+    /// The layout version, [`BOOT_INFO_VERSION`] when written by this bootloader.
+    pub version: u32,
+    /// The size of this structure as the bootloader saw it.
+    pub size: u32,
+    /// The offset the bootloader mapped all of RAM at, [`HHDM_PHYSICAL_OFFSET`].
+    pub hhdm_offset: u64,
+    /// Where the kernel is in physical memory.
+    pub kernel_phys: AddrRange,
+    /// Where the bootloader mapped the kernel in virtual memory.
+    pub kernel_virt: AddrRange,
+    /// The device tree blob. Empty if the firmware didn't pass one.
+    pub dtb: AddrRange,
+    /// The pool the bootloader allocated its page tables from.
+    pub boot_tables: AddrRange,
+    /// The end of the part of `boot_tables` in use.
+    pub boot_tables_used_end: u64,
+    /// How many entries of `memory` are filled in.
+    pub memory_len: u32,
+    /// Reserved, zero.
+    pub reserved: u32,
+    /// The ranges of RAM, from the FDT's `/memory` nodes.
+    pub memory: [AddrRange; BOOT_INFO_MAX_MEMORY],
+    /// The initrd the firmware loaded, from `/chosen`. Empty if there isn't one.
+    pub initrd: AddrRange,
+    /// The command line, from `/chosen/bootargs`, without its NUL. It's inside the DTB.
+    pub cmdline: AddrRange,
+    /// The framebuffer the firmware left, if it described one.
+    pub framebuffer: BootFramebuffer,
+    /// The peripheral window, covering every range in the `ranges` of the FDT's `/soc` node.
+    /// Empty if there's no such node, as on QEMU's `virt` machine.
+    pub peripherals: AddrRange,
+    /// The registers of the PL011 the boot stages reported on, which the kernel keeps using as
+    /// its console.
+    pub uart: AddrRange,
+}
+
+impl BootInfo {
+    /// An empty [`BootInfo`] of the current version, to be filled in.
+    #[allow(clippy::cast_possible_truncation)] // the size is asserted below
+    pub const EMPTY: Self = Self {
+        magic: BOOT_INFO_MAGIC,
+        version: BOOT_INFO_VERSION,
+        size: size_of::<Self>() as u32,
+        hhdm_offset: HHDM_PHYSICAL_OFFSET as u64,
+        kernel_phys: AddrRange::EMPTY,
+        kernel_virt: AddrRange::EMPTY,
+        dtb: AddrRange::EMPTY,
+        boot_tables: AddrRange::EMPTY,
+        boot_tables_used_end: 0,
+        memory_len: 0,
+        reserved: 0,
+        memory: [AddrRange::EMPTY; BOOT_INFO_MAX_MEMORY],
+        initrd: AddrRange::EMPTY,
+        cmdline: AddrRange::EMPTY,
+        framebuffer: BootFramebuffer {
+            memory: AddrRange::EMPTY,
+            width: 0,
+            height: 0,
+            stride: 0,
+            reserved: 0,
+            format: [0; 16],
+        },
+        peripherals: AddrRange::EMPTY,
+        uart: AddrRange::EMPTY,
+    };
+
+    /// Returns `true` if the magic, version and size are the ones this crate describes.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFO_MAGIC
+            && self.version == BOOT_INFO_VERSION
+            && self.size as usize == size_of::<Self>()
+    }
+
+    /// Returns the ranges of RAM that are filled in.
+    #[must_use]
+    pub fn memory(&self) -> &[AddrRange] {
+        let len = (self.memory_len as usize).min(BOOT_INFO_MAX_MEMORY);
+        &self.memory[..len]
+    }
+
+    /// Adds a range of RAM, returning `false` if there's no room for it.
+    pub fn push_memory(&mut self, range: AddrRange) -> bool {
+        let Some(slot) = self.memory.get_mut(self.memory_len as usize) else {
+            return false;
+        };
+        *slot = range;
+        self.memory_len += 1;
+        true
+    }
+}
+
+const _: () = assert!(size_of::<AddrRange>() == 16);
+const _: () = assert!(size_of::<BootFramebuffer>() == 48);
+const _: () = assert!(size_of::<BootInfo>() == 728);
+const _: () = assert!(align_of::<BootInfo>() == 8);
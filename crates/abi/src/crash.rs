@@ -0,0 +1,81 @@
+//! The header of a kernel crash record, as written to the crash region or streamed over serial.
+//!
+//! The payload that follows it is a plain-text report, meant to be read by a person.
+
+/// Identifies a crash record.
+pub const CRASH_RECORD_MAGIC: [u8; 8] = *b"KADOSCR\0";
+/// The current crash record format version.
+pub const CRASH_RECORD_VERSION: u32 = 1;
+
+/// The fixed-size header that starts every crash record.
+///
+/// It is followed by `len` bytes of payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CrashRecordHeader {
+    /// Always [`CRASH_RECORD_MAGIC`].
+    pub magic: [u8; 8],
+    /// The format version, [`CRASH_RECORD_VERSION`] when written by this kernel.
+    pub version: u32,
+    /// The length of the payload in bytes.
+    pub len: u32,
+    /// The CRC-32 (IEEE) of the payload, as computed by [`crc32`](crate::loader::crc32).
+    pub crc32: u32,
+    /// Reserved, zero.
+    pub reserved: u32,
+}
+
+impl CrashRecordHeader {
+    /// Creates a header for a payload of the given length and checksum.
+    #[must_use]
+    pub const fn new(len: u32, crc32: u32) -> Self {
+        Self {
+            magic: CRASH_RECORD_MAGIC,
+            version: CRASH_RECORD_VERSION,
+            len,
+            crc32,
+            reserved: 0,
+        }
+    }
+
+    /// The encoded size of a header.
+    pub const SIZE: usize = 24;
+
+    /// Encodes the header as it's stored and sent, with the integers little-endian.
+    #[must_use]
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.magic);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc32.to_le_bytes());
+        bytes[20..].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a header. It may not be [valid](Self::is_valid).
+    #[must_use]
+    pub fn decode(bytes: &[u8; Self::SIZE]) -> Self {
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let mut magic = [0; 8];
+        magic.copy_from_slice(&bytes[..8]);
+        Self {
+            magic,
+            version: word(8),
+            len: word(12),
+            crc32: word(16),
+            reserved: word(20),
+        }
+    }
+
+    /// Returns `true` if the header's magic and version are ones we understand.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_RECORD_MAGIC && self.version == CRASH_RECORD_VERSION
+    }
+}
+
+const _: () = assert!(size_of::<CrashRecordHeader>() == CrashRecordHeader::SIZE);
+const _: () = assert!(align_of::<CrashRecordHeader>() == 4);
@@ -0,0 +1,14 @@
+//! Binary interfaces shared between the kernel, its boot stages, the host tools, and userspace.
+//!
+//! Everything in here is part of a contract between separately built components, so layouts
+//! are `#[repr(C)]` with their sizes asserted, and values are never renumbered.
+
+#![no_std]
+
+pub mod boot;
+pub mod crash;
+pub mod errno;
+pub mod loader;
+pub mod signal;
+pub mod syscall;
+pub mod trace;
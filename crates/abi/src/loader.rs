@@ -0,0 +1,285 @@
+//! The serial protocol the loader client uses to send a kernel to the chainloader, and the
+//! requests the kernel can make of the client afterwards, or of the server while debugging.
+//!
+//! The exchange goes:
+//!
+//! 1. The chainloader sends [`BREAK`] [`BREAK_COUNT`] times to announce itself.
+//! 2. The client sends frame 0, whose payload is an [`ImageHeader`].
+//! 3. The client sends the image in frames 1, 2, ... of at most [`MAX_CHUNK`] bytes each.
+//! 4. The chainloader checks the [`crc32`] of the whole image against the header, and
+//!    decompresses it if it starts with [`LZ4_MAGIC`]. If all is well, it sends [`DONE`] and
+//!    jumps to the image. If not, it sends [`BAD_IMAGE`] and starts again from step 1.
+//!
+//! A frame is [`SOH`], a [`FrameHeader`], the payload, and the [`crc32`] of the header and
+//! payload as a little-endian `u32`. The chainloader answers every frame with [`ACK`] and the
+//! frame's sequence number as a little-endian `u32`, or with a lone [`NAK`] if the frame was
+//! corrupted or cut short. The client sends the frame again on a [`NAK`], or if no answer comes
+//! within [`ACK_TIMEOUT_MS`]; the sequence numbers let both ends tell a retransmission from
+//! the next frame, and an answer to a retransmission from an answer to the original.
+//!
+//! The chainloader and the bootloader also report how far they've got, as [`PROGRESS`] lines,
+//! so a boot that hangs before the kernel can print anything shows where it stopped. They
+//! only do so where the client isn't waiting on a reply: before the [`BREAK`]s, and after
+//! [`DONE`].
+
+/// The byte the chainloader sends to announce it is ready for an image.
+pub const BREAK: u8 = 0x03;
+/// How many [`BREAK`]s in a row the chainloader sends.
+pub const BREAK_COUNT: usize = 3;
+/// Sent by the chainloader once the whole image has been received and checked.
+pub const DONE: [u8; 4] = *b"TY:)";
+/// Sent by the chainloader if the received image doesn't match the checksum in its header, or
+/// doesn't decompress.
+pub const BAD_IMAGE: [u8; 4] = *b"TY:(";
+
+/// Starts a compressed image. It's followed by the decompressed length as a little-endian `u32`,
+/// then the image compressed as a single LZ4 block.
+pub const LZ4_MAGIC: [u8; 4] = *b"KLZ4";
+
+/// Starts a frame.
+pub const SOH: u8 = 0x01;
+/// Acknowledges a frame, followed by its sequence number.
+pub const ACK: u8 = 0x06;
+/// Asks for the last frame again.
+pub const NAK: u8 = 0x15;
+
+/// The largest payload a frame can carry.
+pub const MAX_CHUNK: usize = 4096;
+/// How long the client waits for an answer to a frame before sending it again.
+pub const ACK_TIMEOUT_MS: u64 = 1000;
+/// How long the chainloader waits for the next byte of a frame before giving up on it.
+pub const BYTE_TIMEOUT_MS: u64 = 100;
+/// How many times the client sends a frame before giving up on the transfer.
+pub const MAX_RETRIES: usize = 16;
+
+/// Prefixes a symbol request from the kernel, followed by the address in decimal and a newline.
+///
+/// The client answers with the mangled symbol name, or [`SYMBOL_UNKNOWN`], and a newline.
+pub const SYMBOL_REQUEST: &[u8] = b"[sym?]";
+/// The client's answer to a symbol request it can't resolve.
+pub const SYMBOL_UNKNOWN: &[u8] = b"unknown";
+
+/// Sent by the kernel's GDB stub when the kernel stops, before any GDB remote protocol traffic.
+///
+/// Everything the kernel sends from then until [`GDB_EXIT`] is meant for the debugger, which lets
+/// the loader server pick it out from the console output.
+pub const GDB_ENTER: &[u8] = b"[gdb>]";
+/// Sent by the kernel's GDB stub when the debugger resumes the kernel.
+pub const GDB_EXIT: &[u8] = b"[gdb<]";
+
+/// Prefixes a progress report from a boot stage, followed by a [`BootStage`] code and `\r\n`.
+pub const PROGRESS: &[u8] = b"[boot]";
+
+/// A point the boot stages before the kernel report reaching, with [`PROGRESS`].
+///
+/// The codes are printable, so the reports still mean something on a plain terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootStage {
+    /// The chainloader has set up the UART, and is about to ask for an image.
+    ChainloaderStart = b'a',
+    /// The chainloader has an image, and is about to jump to it.
+    ChainloaderJump = b'b',
+    /// The bootloader has started, at EL2 with the MMU off.
+    BootloaderStart = b'A',
+    /// The bootloader is mapping RAM into the HHDM.
+    MapRam = b'B',
+    /// The bootloader is mapping the kernel at its virtual address.
+    MapKernel = b'C',
+    /// The bootloader is identity mapping itself.
+    MapBootloader = b'D',
+    /// The bootloader is identity mapping the peripherals.
+    MapPeripherals = b'E',
+    /// The bootloader is identity mapping the DTB.
+    MapDtb = b'F',
+    /// The bootloader is turning on the MMU and dropping to EL1 in the kernel. Anything that
+    /// goes wrong after this is the kernel's to report.
+    EnterKernel = b'G',
+    /// The bootloader ran out of page tables while mapping, and has stopped.
+    OutOfTables = b'!',
+}
+
+impl BootStage {
+    const ALL: [Self; 10] = [
+        Self::ChainloaderStart,
+        Self::ChainloaderJump,
+        Self::BootloaderStart,
+        Self::MapRam,
+        Self::MapKernel,
+        Self::MapBootloader,
+        Self::MapPeripherals,
+        Self::MapDtb,
+        Self::EnterKernel,
+        Self::OutOfTables,
+    ];
+
+    /// Returns the code sent after [`PROGRESS`].
+    #[must_use]
+    pub const fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Decodes a received code, returning `None` for one this version doesn't know.
+    #[must_use]
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|stage| stage.code() == code)
+    }
+
+    /// Returns what the boot was doing when it reported this stage.
+    #[must_use]
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::ChainloaderStart => "chainloader started",
+            Self::ChainloaderJump => "chainloader jumping to the image",
+            Self::BootloaderStart => "bootloader started",
+            Self::MapRam => "mapping RAM into the HHDM",
+            Self::MapKernel => "mapping the kernel",
+            Self::MapBootloader => "identity mapping the bootloader",
+            Self::MapPeripherals => "identity mapping the peripherals",
+            Self::MapDtb => "identity mapping the DTB",
+            Self::EnterKernel => "enabling the MMU and entering the kernel",
+            Self::OutOfTables => "bootloader ran out of page tables",
+        }
+    }
+
+    /// Returns `true` if the boot stopped at this stage.
+    #[must_use]
+    pub const fn is_fatal(self) -> bool {
+        matches!(self, Self::OutOfTables)
+    }
+}
+
+/// Follows [`SOH`] at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// 0 for the [`ImageHeader`], then counting up for each chunk of the image.
+    pub seq: u32,
+    /// The length of the payload, at most [`MAX_CHUNK`].
+    pub len: u16,
+}
+
+impl FrameHeader {
+    /// The encoded size of a frame header.
+    pub const SIZE: usize = 6;
+
+    /// Encodes the header as it is sent.
+    #[must_use]
+    pub const fn encode(&self) -> [u8; Self::SIZE] {
+        let seq = self.seq.to_le_bytes();
+        let len = self.len.to_le_bytes();
+        [seq[0], seq[1], seq[2], seq[3], len[0], len[1]]
+    }
+
+    /// Decodes a received header.
+    #[must_use]
+    pub const fn decode(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            seq: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            len: u16::from_le_bytes([bytes[4], bytes[5]]),
+        }
+    }
+}
+
+/// The payload of frame 0, describing the image that follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// The length of the image in bytes.
+    pub len: u32,
+    /// The [`crc32`] of the whole image.
+    pub crc: u32,
+}
+
+impl ImageHeader {
+    /// The encoded size of an image header.
+    pub const SIZE: usize = 8;
+
+    /// Describes `image`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the image is 4 GiB or larger.
+    #[must_use]
+    pub fn new(image: &[u8]) -> Self {
+        Self {
+            len: u32::try_from(image.len()).expect("image too large"),
+            crc: crc32(image),
+        }
+    }
+
+    /// Encodes the header as it is sent.
+    #[must_use]
+    pub const fn encode(&self) -> [u8; Self::SIZE] {
+        let len = self.len.to_le_bytes();
+        let crc = self.crc.to_le_bytes();
+        [
+            len[0], len[1], len[2], len[3], crc[0], crc[1], crc[2], crc[3],
+        ]
+    }
+
+    /// Decodes a received header.
+    #[must_use]
+    pub const fn decode(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            len: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            crc: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i: u32 = 0;
+    while i < 256 {
+        let mut crc = i;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i as usize] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A CRC-32 (the IEEE 802.3 one, as used by zlib) computed a piece at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    /// Adds `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 = CRC32_TABLE[((self.0 ^ u32::from(b)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Returns the checksum of everything added so far.
+    #[must_use]
+    pub const fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the CRC-32 of `data`.
+#[must_use]
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
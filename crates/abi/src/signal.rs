@@ -0,0 +1,30 @@
+//! Signal numbers and handler values for [`Sysno::Kill`](crate::syscall::Sysno::Kill) and
+//! [`Sysno::Sigaction`](crate::syscall::Sysno::Sigaction).
+//!
+//! Signals are numbered from 1 to [`NSIG`] - 1. Unless a task sets a handler for it, a signal
+//! terminates the task it's sent to.
+
+/// One more than the highest signal number.
+pub const NSIG: usize = 64;
+
+/// Interrupted from the console.
+pub const SIGINT: usize = 2;
+/// The task ran an instruction it isn't allowed to, or that doesn't exist.
+pub const SIGILL: usize = 4;
+/// The task made a misaligned access, or the memory system reported an error for it.
+pub const SIGBUS: usize = 7;
+/// Terminates the task. Can't be handled or ignored.
+pub const SIGKILL: usize = 9;
+/// For use by the program.
+pub const SIGUSR1: usize = 10;
+/// The task accessed memory it shouldn't have.
+pub const SIGSEGV: usize = 11;
+/// For use by the program.
+pub const SIGUSR2: usize = 12;
+/// Asks the task to terminate.
+pub const SIGTERM: usize = 15;
+
+/// Handler value: take the signal's default action.
+pub const SIG_DFL: usize = 0;
+/// Handler value: discard the signal.
+pub const SIG_IGN: usize = 1;
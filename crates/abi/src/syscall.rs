@@ -0,0 +1,174 @@
+//! System call numbers and calling convention.
+//!
+//! A system call is made with `svc #0`, with the number in `x8` and up to six arguments in
+//! `x0`..`x5`. The result comes back in `x0`: non-negative on success, or a negated
+//! [`Errno`](crate::errno::Errno) on failure.
+
+/// A system call number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(usize)]
+pub enum Sysno {
+    /// `exit(code)`: terminates the calling task.
+    Exit = 0,
+    /// `write(fd, buf, len)`: writes bytes to a file descriptor.
+    Write = 1,
+    /// `yield()`: gives up the rest of the calling task's time slice.
+    Yield = 2,
+    /// `futex(addr, op, val)`: waits on or wakes tasks waiting on a 32-bit word in memory.
+    /// See [`FUTEX_WAIT`] and [`FUTEX_WAKE`].
+    Futex = 3,
+    /// `mmap(addr, len, prot, flags)`: maps `len` bytes of memory, returning its address.
+    /// `prot` is a mask of `PROT_*` and `flags` of `MAP_*`; `MAP_ANONYMOUS` is required.
+    Mmap = 4,
+    /// `munmap(addr, len)`: unmaps any pages in `addr..addr + len`.
+    Munmap = 5,
+    /// `brk(addr)`: moves the end of the calling task's heap to `addr`, returning the new
+    /// end. Returns the current end unchanged if `addr` is 0 or it can't be moved there.
+    Brk = 6,
+    /// `kill(pid, sig)`: sends signal `sig` to task `pid`. With `sig` 0, only checks that
+    /// the task exists. See [`signal`](crate::signal).
+    Kill = 7,
+    /// `sigaction(sig, handler, restorer)`: sets what the calling task does with signal
+    /// `sig`, returning the old handler. `handler` is `SIG_DFL`, `SIG_IGN`, or the address of
+    /// a function called with the signal number, which returns to `restorer`.
+    Sigaction = 8,
+    /// `sigreturn()`: restores the registers saved when a signal handler was entered. Only
+    /// meaningful from the `restorer` passed to [`Sysno::Sigaction`].
+    Sigreturn = 9,
+    /// `read(fd, buf, len)`: reads up to `len` bytes from a file descriptor, returning how
+    /// many were read, or 0 at end of file. Sleeps until there's something to read.
+    Read = 10,
+    /// `pipe(fds)`: creates a pipe, writing the file descriptors of its read and write ends
+    /// to `fds[0]` and `fds[1]`, as 32-bit integers.
+    Pipe = 11,
+    /// `close(fd)`: closes a file descriptor.
+    Close = 12,
+    /// `shm_create(size)`: creates a zeroed shared memory object of `size` bytes, returning
+    /// its id.
+    ShmCreate = 13,
+    /// `shm_map(id, addr, prot, flags)`: maps all of shared memory object `id`, returning its
+    /// address. `addr`, `prot` and `flags` are as for [`Sysno::Mmap`], but `MAP_ANONYMOUS`
+    /// isn't allowed.
+    ShmMap = 14,
+    /// `shm_destroy(id)`: takes away shared memory object `id`'s id. Its memory is freed once
+    /// nothing maps it; unmap it with [`Sysno::Munmap`].
+    ShmDestroy = 15,
+    /// `fb_map(info)`: maps the framebuffer uncached into the calling task, returning the
+    /// address of its first pixel, and writes an [`FbInfo`] describing it to `info`. Only
+    /// privileged tasks may call it.
+    FbMap = 16,
+    /// `sched_setaffinity(mask)`: sets the CPUs the calling task may run on, one bit per
+    /// CPU. Fails with `EINVAL` if none of them are online.
+    SchedSetAffinity = 17,
+    /// `sched_getaffinity()`: returns the online CPUs the calling task may run on, one bit
+    /// per CPU.
+    SchedGetAffinity = 18,
+    /// `wait4(pid, status, options)`: waits for child task `pid` to exit, or any child if
+    /// `pid` is [`WAIT_ANY`], frees it, and returns its PID. Unless `status` is null, writes
+    /// how it ended there as a 32-bit [`ExitStatus`]. Fails with `ECHILD` if there's no such
+    /// child. See [`WNOHANG`].
+    Wait4 = 19,
+}
+
+/// [`Sysno::Futex`] op: sleeps until woken, if the word at `addr` still holds `val`.
+/// Fails with `EAGAIN` if it doesn't.
+pub const FUTEX_WAIT: usize = 0;
+/// [`Sysno::Futex`] op: wakes up to `val` tasks waiting on `addr`, returning how many.
+pub const FUTEX_WAKE: usize = 1;
+
+/// [`Sysno::Mmap`] protection: the pages can be read.
+pub const PROT_READ: usize = 1 << 0;
+/// [`Sysno::Mmap`] protection: the pages can be written.
+pub const PROT_WRITE: usize = 1 << 1;
+/// [`Sysno::Mmap`] protection: the pages can be executed.
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// [`Sysno::Mmap`] flag: map at exactly `addr`, replacing anything already mapped there,
+/// instead of treating it as a hint.
+pub const MAP_FIXED: usize = 1 << 0;
+/// [`Sysno::Mmap`] flag: the memory is zero-filled rather than backed by a file.
+pub const MAP_ANONYMOUS: usize = 1 << 1;
+
+/// [`Sysno::Wait4`] pid: waits for any child.
+pub const WAIT_ANY: usize = usize::MAX;
+/// [`Sysno::Wait4`] option: returns 0 straight away if no child has exited yet, instead of
+/// waiting for one to.
+pub const WNOHANG: usize = 1 << 0;
+
+/// How a task ended, as [`Sysno::Wait4`] reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExitStatus {
+    /// It exited with this code.
+    Exited(i32),
+    /// This signal killed it.
+    Signaled(usize),
+}
+
+impl ExitStatus {
+    /// Returns the status as [`Sysno::Wait4`] writes it: the low byte of the exit code
+    /// shifted left 8 bits, or the signal number.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)] // masked to fit
+    pub const fn to_raw(self) -> i32 {
+        match self {
+            Self::Exited(code) => (code & 0xff) << 8,
+            Self::Signaled(sig) => (sig & 0x7f) as i32,
+        }
+    }
+
+    /// Returns the status [`Sysno::Wait4`] wrote as `raw`.
+    #[must_use]
+    #[allow(clippy::cast_sign_loss)] // masked to fit
+    pub const fn from_raw(raw: i32) -> Self {
+        match raw & 0x7f {
+            0 => Self::Exited((raw >> 8) & 0xff),
+            sig => Self::Signaled(sig as usize),
+        }
+    }
+}
+
+/// The framebuffer that [`Sysno::FbMap`] mapped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct FbInfo {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The number of bytes from the start of one line to the next.
+    pub stride: u32,
+    /// The number of bits per pixel.
+    pub bpp: u32,
+}
+
+impl TryFrom<usize> for Sysno {
+    type Error = crate::errno::Errno;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Exit),
+            1 => Ok(Self::Write),
+            2 => Ok(Self::Yield),
+            3 => Ok(Self::Futex),
+            4 => Ok(Self::Mmap),
+            5 => Ok(Self::Munmap),
+            6 => Ok(Self::Brk),
+            7 => Ok(Self::Kill),
+            8 => Ok(Self::Sigaction),
+            9 => Ok(Self::Sigreturn),
+            10 => Ok(Self::Read),
+            11 => Ok(Self::Pipe),
+            12 => Ok(Self::Close),
+            13 => Ok(Self::ShmCreate),
+            14 => Ok(Self::ShmMap),
+            15 => Ok(Self::ShmDestroy),
+            16 => Ok(Self::FbMap),
+            17 => Ok(Self::SchedSetAffinity),
+            18 => Ok(Self::SchedGetAffinity),
+            19 => Ok(Self::Wait4),
+            _ => Err(crate::errno::Errno::ENOSYS),
+        }
+    }
+}
+
+const _: () = assert!(size_of::<Sysno>() == size_of::<usize>());
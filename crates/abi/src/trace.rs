@@ -0,0 +1,105 @@
+//! The kernel's event trace, as dumped over serial for `cargo builder trace` to convert.
+//!
+//! A dump is a run of lines, each starting with [`LINE_PREFIX`] so it can be picked out of
+//! the rest of the console output:
+//!
+//! ```text
+//! ktrace: begin <version>
+//! ktrace: <record>
+//! ...
+//! ktrace: end <records> <lost>
+//! ```
+//!
+//! Each record is a [`TraceRecord`], [encoded](TraceRecord::encode) and written in hex. The
+//! records of each CPU are in the order they happened, but the CPUs are dumped one after
+//! another. `lost` counts the records that were overwritten before the dump.
+
+/// Starts every line of a trace dump.
+pub const LINE_PREFIX: &str = "ktrace: ";
+/// The current trace format version.
+pub const TRACE_VERSION: u32 = 1;
+
+/// What a [`TraceRecord`] records. Its arguments depend on the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum TraceEvent {
+    /// An IRQ's handler started; `arg0` is the IRQ.
+    IrqEntry = 1,
+    /// An IRQ's handler finished; `arg0` is the IRQ.
+    IrqExit = 2,
+    /// The CPU switched tasks; `arg0` is the PID of the task switched out and `arg1` that of
+    /// the task switched in.
+    ContextSwitch = 3,
+    /// A system call started; `arg0` is the system call number and `arg1` its first argument.
+    SyscallEntry = 4,
+    /// A system call returned; `arg0` is the system call number and `arg1` the value returned.
+    SyscallExit = 5,
+}
+
+impl TryFrom<u16> for TraceEvent {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => Self::IrqEntry,
+            2 => Self::IrqExit,
+            3 => Self::ContextSwitch,
+            4 => Self::SyscallEntry,
+            5 => Self::SyscallExit,
+            _ => return Err(value),
+        })
+    }
+}
+
+/// One traced event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct TraceRecord {
+    /// When the event happened, in nanoseconds since boot.
+    pub timestamp: u64,
+    /// The CPU the event happened on.
+    pub cpu: u16,
+    /// The [`TraceEvent`].
+    pub event: u16,
+    /// The event's first argument.
+    pub arg0: u32,
+    /// The event's second argument.
+    pub arg1: u64,
+}
+
+impl TraceRecord {
+    /// The encoded size of a record.
+    pub const SIZE: usize = 24;
+
+    /// Encodes the record, with the integers little-endian.
+    #[must_use]
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.event.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.arg0.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.arg1.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record. Its event may not be one we understand.
+    #[must_use]
+    pub fn decode(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut u64_at = [0; 8];
+        u64_at.copy_from_slice(&bytes[..8]);
+        let timestamp = u64::from_le_bytes(u64_at);
+        u64_at.copy_from_slice(&bytes[16..]);
+        let arg1 = u64::from_le_bytes(u64_at);
+        Self {
+            timestamp,
+            cpu: u16::from_le_bytes([bytes[8], bytes[9]]),
+            event: u16::from_le_bytes([bytes[10], bytes[11]]),
+            arg0: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            arg1,
+        }
+    }
+}
+
+const _: () = assert!(size_of::<TraceRecord>() == TraceRecord::SIZE);
+const _: () = assert!(align_of::<TraceRecord>() == 8);
@@ -0,0 +1,230 @@
+//! Just enough of a flattened device tree reader to fill in the kernel's [`BootInfo`]: where
+//! RAM is, where the SoC's peripherals are, and what the firmware put in `/chosen` or left a
+//! `simple-framebuffer` for.
+
+use kados_abi::boot::{AddrRange, BootFramebuffer, BootInfo};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+unsafe fn read_be32(ptr: *const u8) -> u32 {
+    u32::from_be_bytes(unsafe { ptr.cast::<[u8; 4]>().read_unaligned() })
+}
+
+/// Returns the bytes of the NUL-terminated string at `ptr`, without the NUL.
+unsafe fn c_str<'a>(ptr: *const u8) -> &'a [u8] {
+    let mut len = 0;
+    while unsafe { ptr.add(len).read() } != 0 {
+        len += 1;
+    }
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+/// Reads a number made up of `cells` big-endian 32-bit cells.
+unsafe fn read_cells(ptr: *const u8, cells: usize) -> usize {
+    let mut value = 0;
+    for cell in 0..cells {
+        value = (value << 32) | unsafe { read_be32(ptr.add(cell * 4)) } as usize;
+    }
+    value
+}
+
+/// Returns the total size of the device tree blob at `dtb`, or `None` if it isn't one.
+pub unsafe fn total_size(dtb: *const u8) -> Option<usize> {
+    if unsafe { read_be32(dtb) } != FDT_MAGIC {
+        return None;
+    }
+    Some(unsafe { read_be32(dtb.add(4)) } as usize)
+}
+
+/// How deep in the tree the cell sizes of nodes are kept track of.
+const MAX_DEPTH: usize = 8;
+
+/// The properties of a node that might be a `simple-framebuffer`, as they're read.
+#[derive(Default)]
+struct FramebufferNode {
+    compatible: bool,
+    reg: Option<(usize, usize)>,
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: [u8; 16],
+}
+
+/// Fills in `info`'s RAM, peripheral window, initrd, command line and framebuffer from the
+/// device tree blob at `dtb`, leaving whatever it doesn't describe alone.
+///
+/// Returns `None` if `dtb` doesn't point to a device tree blob.
+pub unsafe fn read_boot_info(dtb: *const u8, info: &mut BootInfo) -> Option<()> {
+    let size = unsafe { total_size(dtb)? };
+    info.dtb = AddrRange::new(dtb as usize, dtb as usize + size);
+    let structs = unsafe { dtb.add(read_be32(dtb.add(8)) as usize) };
+    let strings = unsafe { dtb.add(read_be32(dtb.add(12)) as usize) };
+
+    // the cell sizes of the children of the node at each depth, defaulting as the devicetree
+    // spec says to
+    let mut address_cells = [2; MAX_DEPTH];
+    let mut size_cells = [1; MAX_DEPTH];
+    let mut depth = 0;
+    let mut node_name: &[u8] = &[];
+    let mut in_chosen = false;
+    let mut in_soc = false;
+    // `/soc`'s `ranges`, read once the node's done, since its cell sizes may come after it
+    let mut soc_ranges: &[u8] = &[];
+    let mut framebuffer = FramebufferNode::default();
+    let mut initrd = (0, 0);
+
+    let mut ptr = structs;
+    loop {
+        let token = unsafe { read_be32(ptr) };
+        ptr = unsafe { ptr.add(4) };
+        match token {
+            FDT_BEGIN_NODE => {
+                node_name = unsafe { c_str(ptr) };
+                ptr = unsafe { ptr.add((node_name.len() + 1).next_multiple_of(4)) };
+                depth += 1;
+                // the root is depth 1, so its children are depth 2
+                if depth == 2 {
+                    in_chosen = node_name == b"chosen";
+                    in_soc = node_name == b"soc" || node_name.starts_with(b"soc@");
+                }
+                if depth < MAX_DEPTH {
+                    address_cells[depth] = 2;
+                    size_cells[depth] = 1;
+                }
+                framebuffer = FramebufferNode::default();
+            }
+            FDT_END_NODE => {
+                if framebuffer.compatible
+                    && let Some((base, size)) = framebuffer.reg
+                {
+                    info.framebuffer = BootFramebuffer {
+                        memory: AddrRange::new(base, base + size),
+                        width: framebuffer.width,
+                        height: framebuffer.height,
+                        stride: framebuffer.stride,
+                        reserved: 0,
+                        format: framebuffer.format,
+                    };
+                }
+                framebuffer = FramebufferNode::default();
+                if in_soc && depth == 2 {
+                    info.peripherals = soc_window(
+                        soc_ranges,
+                        address_cells[2],
+                        address_cells[1],
+                        size_cells[2],
+                    );
+                }
+                depth -= 1;
+                if depth < 2 {
+                    in_chosen = false;
+                    in_soc = false;
+                }
+            }
+            FDT_PROP => {
+                let len = unsafe { read_be32(ptr) } as usize;
+                let name_offset = unsafe { read_be32(ptr.add(4)) } as usize;
+                let value = unsafe { ptr.add(8) };
+                ptr = unsafe { value.add(len.next_multiple_of(4)) };
+
+                let name = unsafe { c_str(strings.add(name_offset)) };
+                let bytes = unsafe { core::slice::from_raw_parts(value, len) };
+                let string = &bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(len)];
+                // the cells this node's `reg` is in are set by its parent
+                let parent = (depth - 1).min(MAX_DEPTH - 1);
+                let reg_entries = || {
+                    let entry_size = (address_cells[parent] + size_cells[parent]) * 4;
+                    (0..len / entry_size.max(1)).map(move |entry| {
+                        let entry = unsafe { value.add(entry * entry_size) };
+                        let base = unsafe { read_cells(entry, address_cells[parent]) };
+                        let size = unsafe {
+                            read_cells(entry.add(address_cells[parent] * 4), size_cells[parent])
+                        };
+                        (base, size)
+                    })
+                };
+                match name {
+                    b"#address-cells" if depth < MAX_DEPTH => {
+                        address_cells[depth] = unsafe { read_be32(value) } as usize;
+                    }
+                    b"#size-cells" if depth < MAX_DEPTH => {
+                        size_cells[depth] = unsafe { read_be32(value) } as usize;
+                    }
+                    b"reg"
+                        if depth == 2
+                            && (node_name == b"memory" || node_name.starts_with(b"memory@")) =>
+                    {
+                        for (base, size) in reg_entries() {
+                            if size != 0 {
+                                info.push_memory(AddrRange::new(base, base + size));
+                            }
+                        }
+                    }
+                    b"ranges" if in_soc && depth == 2 => soc_ranges = bytes,
+                    b"reg" => framebuffer.reg = reg_entries().next(),
+                    b"compatible" => {
+                        framebuffer.compatible =
+                            bytes.split(|&b| b == 0).any(|c| c == b"simple-framebuffer");
+                    }
+                    b"width" => framebuffer.width = unsafe { read_be32(value) },
+                    b"height" => framebuffer.height = unsafe { read_be32(value) },
+                    b"stride" => framebuffer.stride = unsafe { read_be32(value) },
+                    b"format" => {
+                        let len = string.len().min(framebuffer.format.len() - 1);
+                        framebuffer.format[..len].copy_from_slice(&string[..len]);
+                    }
+                    b"linux,initrd-start" if in_chosen && depth == 2 => {
+                        initrd.0 = unsafe { read_cells(value, len / 4) };
+                    }
+                    b"linux,initrd-end" if in_chosen && depth == 2 => {
+                        initrd.1 = unsafe { read_cells(value, len / 4) };
+                    }
+                    b"bootargs" if in_chosen && depth == 2 => {
+                        let start = value as usize;
+                        info.cmdline = AddrRange::new(start, start + string.len());
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => return None,
+        }
+    }
+
+    if initrd.0 < initrd.1 {
+        info.initrd = AddrRange::new(initrd.0, initrd.1);
+    }
+    Some(())
+}
+
+/// Returns the smallest range of physical addresses covering every entry of `ranges`, each a
+/// child address, a parent address and a size of the given numbers of cells.
+fn soc_window(
+    ranges: &[u8],
+    child_cells: usize,
+    parent_cells: usize,
+    size_cells: usize,
+) -> AddrRange {
+    let entry_size = (child_cells + parent_cells + size_cells) * 4;
+    let mut window: Option<(usize, usize)> = None;
+    for entry in ranges.chunks_exact(entry_size.max(1)) {
+        let ptr = entry.as_ptr();
+        let base = unsafe { read_cells(ptr.add(child_cells * 4), parent_cells) };
+        let size = unsafe { read_cells(ptr.add((child_cells + parent_cells) * 4), size_cells) };
+        if size == 0 {
+            continue;
+        }
+        window = Some(match window {
+            Some((start, end)) => (start.min(base), end.max(base + size)),
+            None => (base, base + size),
+        });
+    }
+    window.map_or(AddrRange::EMPTY, |(start, end)| AddrRange::new(start, end))
+}
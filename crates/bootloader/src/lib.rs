@@ -7,6 +7,14 @@ use core::{
     panic::PanicInfo,
 };
 
+use kados_abi::{
+    boot::{AddrRange, BootInfo, HHDM_PHYSICAL_OFFSET, MAIR, MAIR_IDX_DEVICE, MAIR_IDX_NORMAL},
+    loader::BootStage,
+};
+use kados_early_uart as uart;
+
+mod fdt;
+
 unsafe extern "C" {
     unsafe static __boot_start: u8;
     unsafe static __boot_stack_bottom: u8;
@@ -19,9 +27,13 @@ unsafe extern "C" {
     unsafe static __kernel_virt_start: u8;
     unsafe static __kernel_virt_end: u8;
 
-    unsafe fn boot_higher_half(dtb_ptr: *const u8) -> !;
+    unsafe fn boot_higher_half(boot_info: *const BootInfo) -> !;
 }
 
+/// What the kernel is told about the machine. It's in the boot image's BSS, which the kernel
+/// keeps out of its memory map.
+static mut BOOT_INFO: BootInfo = BootInfo::EMPTY;
+
 const PAGE_SHIFT: usize = 12;
 
 const PAGE_ENTRY_ADDR_WIDTH: usize = 40;
@@ -32,26 +44,22 @@ const PAGE_FLAG_NON_EXECUTABLE: usize = 0b11 << 53;
 
 const PAGE_FLAG_NON_BLOCK: usize = 1 << 1;
 const PAGE_FLAG_ACCESS: usize = 1 << 10;
-const PAGE_FLAG_NORMAL: usize = 1 << 2;
+const PAGE_FLAG_NORMAL: usize = MAIR_IDX_NORMAL << 2;
 const PAGE_FLAG_INNER_SHAREABLE: usize = 0b11 << 8;
 const PAGE_FLAG_OUTER_SHAREABLE: usize = 0b10 << 8;
 
 const PAGE_FLAG_DEVICE: usize = PAGE_FLAG_PRESENT
     | PAGE_FLAG_NON_BLOCK
     | PAGE_FLAG_ACCESS
-    | (0 << 2) // AttrIdx 0
+    | (MAIR_IDX_DEVICE << 2)
     | (0 << 6) // AP (RW, priv)
     | PAGE_FLAG_OUTER_SHAREABLE
     | PAGE_FLAG_NON_EXECUTABLE;
 
-const PERIPHERAL_BASE: usize = 0xFE00_0000;
-
 const PAGE_ENTRY_ADDR_SIZE: usize = 1 << PAGE_ENTRY_ADDR_WIDTH;
 const PAGE_ENTRY_ADDR_MASK: usize = PAGE_ENTRY_ADDR_SIZE - 1;
 const PAGE_ENTRY_FLAGS_MASK: usize = !(PAGE_ENTRY_ADDR_MASK << PAGE_SHIFT);
 
-const HHDM_PHYSICAL_OFFSET: usize = 0xffff_8000_0000_0000;
-
 #[repr(C, align(4096))]
 pub struct Table([usize; 512]);
 
@@ -105,11 +113,12 @@ pub unsafe extern "C" fn _start(dtb_ptr: *const u8) -> ! {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn boot_el2(dtb_ptr: *const u8) -> ! {
     unsafe {
-        // boot_uart_putc(b'A');
+        uart::init();
+        uart::progress(BootStage::BootloaderStart);
 
-        let mut off = &__boot_table as *const _ as usize;
+        let mut pool = TablePool::new();
 
-        let l0 = alloc_table(&mut off);
+        let l0 = pool.alloc();
 
         let flags = PAGE_FLAG_ACCESS
             | PAGE_FLAG_INNER_SHAREABLE
@@ -117,38 +126,81 @@ pub unsafe extern "C" fn boot_el2(dtb_ptr: *const u8) -> ! {
             | PAGE_FLAG_NORMAL
             | PAGE_FLAG_PRESENT;
 
-        // boot_uart_putc(b'B');
+        uart::progress(BootStage::MapRam);
+
+        let mut info = BootInfo::EMPTY;
+        fdt::read_boot_info(dtb_ptr, &mut info);
 
-        map_range(&mut off, l0, 0, HHDM_PHYSICAL_OFFSET, 0x100000000, flags);
+        // map all of RAM into the HHDM, as the DTB describes it
+        let dtb_addr = dtb_ptr as usize;
+        let dtb_size = info.dtb.len() as usize;
+        let mut dtb_in_hhdm = false;
+        for region in info.memory() {
+            let (base, size) = (region.start as usize, region.len() as usize);
+            map_range(&mut pool, l0, base, HHDM_PHYSICAL_OFFSET + base, size, flags);
+            dtb_in_hhdm |= base <= dtb_addr && dtb_addr + dtb_size <= base + size;
+        }
+        if info.memory().is_empty() {
+            // couldn't find any RAM in the DTB; fall back to the first 4GiB
+            map_range(&mut pool, l0, 0, HHDM_PHYSICAL_OFFSET, 0x100000000, flags);
+            dtb_in_hhdm = dtb_addr + dtb_size <= 0x100000000;
+        }
+        // the kernel reads the DTB through the HHDM
+        if !dtb_in_hhdm && dtb_size != 0 {
+            let dtb_base = dtb_addr & !(FOUR_KB - 1);
+            let dtb_end = (dtb_addr + dtb_size).next_multiple_of(FOUR_KB);
+            map_range(
+                &mut pool,
+                l0,
+                dtb_base,
+                HHDM_PHYSICAL_OFFSET + dtb_base,
+                dtb_end - dtb_base,
+                flags,
+            );
+        }
 
         let kernel_phys = &__kernel_phys_start as *const _ as usize;
         let kernel_phys_end = &__kernel_phys_end as *const _ as usize;
         let kernel_virt = &__kernel_virt_start as *const _ as usize;
         let kernel_size = kernel_phys_end - kernel_phys;
+        info.kernel_phys = AddrRange::new(kernel_phys, kernel_phys_end);
+        info.kernel_virt = AddrRange::new(kernel_virt, kernel_virt + kernel_size);
 
-        // boot_uart_putc(b'C');
-        map_range(&mut off, l0, kernel_phys, kernel_virt, kernel_size, flags);
+        uart::progress(BootStage::MapKernel);
+        map_range(&mut pool, l0, kernel_phys, kernel_virt, kernel_size, flags);
 
         let boot_phys = &__boot_start as *const _ as usize;
         let boot_phys_end = &__boot_end as *const _ as usize;
         let boot_size = boot_phys_end - boot_phys;
 
-        // boot_uart_putc(b'D');
-        map_range(&mut off, l0, boot_phys, boot_phys, boot_size, flags);
+        uart::progress(BootStage::MapBootloader);
+        map_range(&mut pool, l0, boot_phys, boot_phys, boot_size, flags);
 
-        // boot_uart_putc(b'E');
-        map_range(
-            &mut off,
-            l0,
-            PERIPHERAL_BASE,
-            PERIPHERAL_BASE,
-            0x200_0000,
-            PAGE_FLAG_DEVICE,
-        );
+        uart::progress(BootStage::MapPeripherals);
+        let peripherals = info.peripherals;
+        if !peripherals.is_empty() {
+            let base = peripherals.start as usize & !(FOUR_KB - 1);
+            let size = (peripherals.end as usize).next_multiple_of(FOUR_KB) - base;
+            map_range(&mut pool, l0, base, base, size, PAGE_FLAG_DEVICE);
+        }
+        // the UART the kernel's console starts out on, which may not be one of the SoC's own
+        info.uart = AddrRange::new(uart::UART_BASE, uart::UART_BASE + FOUR_KB);
+        let uart_mapped = peripherals.start as usize <= uart::UART_BASE
+            && uart::UART_BASE + FOUR_KB <= peripherals.end as usize;
+        if !uart_mapped {
+            map_range(
+                &mut pool,
+                l0,
+                uart::UART_BASE,
+                uart::UART_BASE,
+                FOUR_KB,
+                PAGE_FLAG_DEVICE,
+            );
+        }
 
-        // boot_uart_putc(b'F');
+        uart::progress(BootStage::MapDtb);
         map_range(
-            &mut off,
+            &mut pool,
             l0,
             dtb_ptr as usize,
             dtb_ptr as usize,
@@ -162,9 +214,16 @@ pub unsafe extern "C" fn boot_el2(dtb_ptr: *const u8) -> ! {
         const TCR1: usize =
             ((64 - 48) << 16) | (0b01 << 24) | (0b01 << 26) | (0b11 << 28) | (0b10 << 30);
 
-        // boot_uart_putc(b'G');
+        info.boot_tables = AddrRange::new(
+            &__boot_table as *const _ as usize,
+            &__boot_table_end as *const _ as usize,
+        );
+        info.boot_tables_used_end = pool.next as u64;
+        (&raw mut BOOT_INFO).write(info);
+
+        uart::progress(BootStage::EnterKernel);
         asm!(
-            "mov x19, {dtb_ptr}",
+            "mov x19, {boot_info}",
 
             // Disable MMU
             "mrs    x0, sctlr_el1",
@@ -225,7 +284,7 @@ pub unsafe extern "C" fn boot_el2(dtb_ptr: *const u8) -> ! {
 
             "eret",
 
-            mair        = in(reg) ((0xff << 8) | 0x00) as u64,
+            mair        = in(reg) MAIR,
             tcr         = in(reg) (TCR0|TCR1) as u64,
             ttbr0       = in(reg) l0,
             ttbr1       = in(reg) l0,
@@ -233,18 +292,53 @@ pub unsafe extern "C" fn boot_el2(dtb_ptr: *const u8) -> ! {
             hcr_set     = in(reg) ((1 << 31) | (1 << 29)) as u64,
             mci         = in(reg) MCI,
             spsr        = in(reg) 0x3C5u64,
-            dtb_ptr     = in(reg) dtb_ptr,
+            boot_info   = in(reg) &raw const BOOT_INFO,
             entry       = in(reg) boot_higher_half,
             options(noreturn)
         );
     }
 }
 
-#[inline]
-pub fn alloc_table(off: &mut usize) -> &'static mut Table {
-    let table = unsafe { &mut *(*off as *mut Table) };
-    *off += size_of::<Table>();
-    table
+/// The page tables the bootloader maps with, handed out in order from the `__boot_table`
+/// region the linker script sets aside.
+pub struct TablePool {
+    next: usize,
+    end: usize,
+}
+
+impl TablePool {
+    /// Returns a pool of the whole `__boot_table` region.
+    ///
+    /// # Safety
+    ///
+    /// Only one pool may exist, since every pool hands out the same tables.
+    pub unsafe fn new() -> Self {
+        Self {
+            next: &raw const __boot_table as usize,
+            end: &raw const __boot_table_end as usize,
+        }
+    }
+
+    /// Takes a zeroed table from the pool.
+    ///
+    /// Stops the boot, after reporting [`BootStage::OutOfTables`], if the pool is empty.
+    pub fn alloc(&mut self) -> &'static mut Table {
+        if self.end - self.next < size_of::<Table>() {
+            out_of_tables();
+        }
+        let table = unsafe { &mut *(self.next as *mut Table) };
+        self.next += size_of::<Table>();
+        table.0.fill(0);
+        table
+    }
+}
+
+#[cold]
+fn out_of_tables() -> ! {
+    uart::progress(BootStage::OutOfTables);
+    loop {
+        unsafe { asm!("wfe") };
+    }
 }
 
 pub const fn l0_index(addr: usize) -> usize {
@@ -276,7 +370,7 @@ pub const fn entry_flags(entry: usize) -> usize {
 }
 
 pub fn next_table(
-    off: &mut usize,
+    pool: &mut TablePool,
     table: &mut Table,
     index: usize,
     insert_flags: usize,
@@ -284,7 +378,7 @@ pub fn next_table(
     let entry = table.0[index];
     let table_addr = entry_addr(entry);
     if table_addr == 0 {
-        let new_table = alloc_table(off);
+        let new_table = pool.alloc();
         set_entry(
             &mut table.0[index],
             entry_addr(new_table as *const _ as usize),
@@ -300,7 +394,7 @@ pub fn next_table(
 }
 
 pub fn map_range(
-    off: &mut usize,
+    pool: &mut TablePool,
     table: &mut Table,
     phys: usize,
     virt: usize,
@@ -313,9 +407,9 @@ pub fn map_range(
         let virt = virt + mapped;
         let block_size = largest_aligned_block_size(phys, virt, size - mapped);
         match block_size {
-            GB => map_to_1gib(off, table, phys, virt, flags),
-            TWO_MB => map_to_2mib(off, table, phys, virt, flags),
-            FOUR_KB => map_to_4kib(off, table, phys, virt, flags),
+            GB => map_to_1gib(pool, table, phys, virt, flags),
+            TWO_MB => map_to_2mib(pool, table, phys, virt, flags),
+            FOUR_KB => map_to_4kib(pool, table, phys, virt, flags),
             _ => unreachable!(),
         }
 
@@ -343,26 +437,26 @@ fn largest_aligned_block_size(phys: usize, virt: usize, size: usize) -> usize {
     }
 }
 
-fn map_to_1gib(off: &mut usize, table: &mut Table, phys: usize, virt: usize, flags: usize) {
+fn map_to_1gib(pool: &mut TablePool, table: &mut Table, phys: usize, virt: usize, flags: usize) {
     let flags = flags & !PAGE_FLAG_NON_BLOCK;
-    let l1 = next_table(off, table, l0_index(virt), 0);
+    let l1 = next_table(pool, table, l0_index(virt), 0);
     let idx = l1_index(virt);
     set_entry(&mut l1.0[idx], phys, flags);
 }
 
-fn map_to_2mib(off: &mut usize, table: &mut Table, phys: usize, virt: usize, flags: usize) {
+fn map_to_2mib(pool: &mut TablePool, table: &mut Table, phys: usize, virt: usize, flags: usize) {
     let flags = flags & !PAGE_FLAG_NON_BLOCK;
-    let l1 = next_table(off, table, l0_index(virt), 0);
-    let l2 = next_table(off, l1, l1_index(virt), 0);
+    let l1 = next_table(pool, table, l0_index(virt), 0);
+    let l2 = next_table(pool, l1, l1_index(virt), 0);
     let idx = l2_index(virt);
     set_entry(&mut l2.0[idx], phys, flags);
 }
 
-fn map_to_4kib(off: &mut usize, table: &mut Table, phys: usize, virt: usize, flags: usize) {
+fn map_to_4kib(pool: &mut TablePool, table: &mut Table, phys: usize, virt: usize, flags: usize) {
     let flags = flags | PAGE_FLAG_NON_BLOCK;
-    let l1 = next_table(off, table, l0_index(virt), 0);
-    let l2 = next_table(off, l1, l1_index(virt), 0);
-    let l3 = next_table(off, l2, l2_index(virt), 0);
+    let l1 = next_table(pool, table, l0_index(virt), 0);
+    let l2 = next_table(pool, l1, l1_index(virt), 0);
+    let l3 = next_table(pool, l2, l2_index(virt), 0);
     let idx = l3_index(virt);
     set_entry(&mut l3.0[idx], phys, flags);
 }
@@ -0,0 +1,53 @@
+//! Decompression of LZ4 blocks, for compressed kernel images.
+
+/// Decompresses the LZ4 block `src` into `dst`, returning the decompressed length, or `None`
+/// if the block is malformed or doesn't fit.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut s = 0;
+    let mut d = 0;
+    loop {
+        let token = *src.get(s)?;
+        s += 1;
+
+        let literals = read_len(src, &mut s, usize::from(token >> 4))?;
+        dst.get_mut(d..d + literals)?
+            .copy_from_slice(src.get(s..s + literals)?);
+        s += literals;
+        d += literals;
+
+        // the last sequence is only literals
+        if s == src.len() {
+            return Some(d);
+        }
+
+        let offset = usize::from(u16::from_le_bytes([*src.get(s)?, *src.get(s + 1)?]));
+        s += 2;
+        if offset == 0 || offset > d {
+            return None;
+        }
+        let len = read_len(src, &mut s, usize::from(token & 0xf))? + 4;
+        if d + len > dst.len() {
+            return None;
+        }
+        // byte by byte, since the match may overlap what it's copying
+        for i in d..d + len {
+            dst[i] = dst[i - offset];
+        }
+        d += len;
+    }
+}
+
+/// Reads the rest of a literal or match length whose 4 bits in the token were `len`.
+fn read_len(src: &[u8], s: &mut usize, mut len: usize) -> Option<usize> {
+    if len == 0xf {
+        loop {
+            let b = *src.get(*s)?;
+            *s += 1;
+            len += usize::from(b);
+            if b != 0xff {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
@@ -7,27 +7,23 @@ use core::{
     panic::PanicInfo,
 };
 
-global_asm!(include_str!("start.S"));
-
-const KERNEL_LOAD_ADDR: usize = 0x80000;
-
-const PERIPHERAL_BASE: usize = 0xFE00_0000;
-const GPIO_BASE: usize = PERIPHERAL_BASE + 0x20_0000;
-const UART0_BASE: usize = PERIPHERAL_BASE + 0x20_1000;
+use kados_abi::{
+    boot::{CRASH_REGION_ADDR, KERNEL_LOAD_ADDR},
+    loader::{self, BootStage, Crc32, FrameHeader, ImageHeader},
+};
+use kados_early_uart::{self as uart, getchar, putchar};
 
-const GPFSEL1: *mut u32 = (GPIO_BASE + 0x04) as *mut u32;
-const GPPUD: *mut u32 = (GPIO_BASE + 0x94) as *mut u32;
-const GPPUDCLK0: *mut u32 = (GPIO_BASE + 0x98) as *mut u32;
+mod lz4;
 
-const UART0_DR: *mut u32 = (UART0_BASE + 0x00) as *mut u32;
-const UART0_FR: *mut u32 = (UART0_BASE + 0x18) as *mut u32;
-const UART0_IBRD: *mut u32 = (UART0_BASE + 0x24) as *mut u32;
-const UART0_FBRD: *mut u32 = (UART0_BASE + 0x28) as *mut u32;
-const UART0_LCRH: *mut u32 = (UART0_BASE + 0x2C) as *mut u32;
-const UART0_CR: *mut u32 = (UART0_BASE + 0x30) as *mut u32;
-const UART0_ICR: *mut u32 = (UART0_BASE + 0x44) as *mut u32;
+global_asm!(include_str!("start.S"));
 
-const AUX_ENABLE: *mut u32 = (PERIPHERAL_BASE + 0x00215004) as *mut u32;
+/// Where images are received, before being decompressed or copied to [`KERNEL_LOAD_ADDR`].
+///
+/// Images must fit between here and [`CRASH_REGION_ADDR`], above which are the last boot's
+/// crash record and the initrd.
+const STAGING_ADDR: usize = 0x1000_0000;
+/// The largest image that can be received.
+const MAX_IMAGE_LEN: usize = CRASH_REGION_ADDR - STAGING_ADDR;
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
@@ -38,82 +34,162 @@ fn panic(_info: &PanicInfo) -> ! {
     }
 }
 
-pub fn putchar(c: u8) {
+/// Returns the time since boot in milliseconds, from the generic timer.
+pub fn now_ms() -> u64 {
+    let (count, freq): (u64, u64);
     unsafe {
-        while UART0_FR.read_volatile() & 0x20 != 0 {
-            asm!("nop");
-        }
-        UART0_DR.write_volatile(c as u32);
+        asm!("mrs {}, cntpct_el0", out(reg) count);
+        asm!("mrs {}, cntfrq_el0", out(reg) freq);
     }
+    count / (freq / 1000)
 }
 
-pub fn getchar() -> u8 {
-    unsafe {
-        while UART0_FR.read_volatile() & 0x10 != 0 {
-            asm!("nop");
+/// Like [`getchar`], but gives up if nothing arrives within `timeout_ms`.
+pub fn getchar_timeout(timeout_ms: u64) -> Option<u8> {
+    let start = now_ms();
+    loop {
+        if let Some(c) = uart::try_getchar() {
+            return Some(c);
         }
-        UART0_DR.read_volatile() as u8
-    }
-}
-
-pub fn delay(mut cnt: usize) {
-    unsafe {
-        while cnt != 0 {
-            asm!("nop");
-            cnt -= 1;
+        if now_ms() - start >= timeout_ms {
+            return None;
         }
     }
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn recv(_load_addr: usize) -> ! {
-    unsafe {
-        UART0_CR.write_volatile(0);
-        AUX_ENABLE.write_volatile(0);
-        let mut r = GPFSEL1.read_volatile();
-        r &= !((7 << 12) | (7 << 15));
-        r |= (4 << 12) | (4 << 15);
-        GPFSEL1.write_volatile(r);
-        GPPUD.write_volatile(0);
-        delay(150);
-        GPPUDCLK0.write_volatile((1 << 14) | (1 << 15));
-        delay(150);
-        GPPUDCLK0.write_volatile(0);
-
-        UART0_ICR.write_volatile(0x7ff);
-        UART0_IBRD.write_volatile(3);
-        UART0_FBRD.write_volatile(16);
-        UART0_LCRH.write_volatile(0x3 << 5);
-        UART0_CR.write_volatile(0x301);
+pub extern "C" fn recv(_load_addr: usize, dtb: usize) -> ! {
+    unsafe { uart::init() };
+    uart::progress(BootStage::ChainloaderStart);
+
+    loop {
+        for _ in 0..loader::BREAK_COUNT {
+            putchar(loader::BREAK);
+        }
+
+        // a whole chunk, since that's what a corrupted header frame may claim to carry
+        let mut header = [0u8; loader::MAX_CHUNK];
+        let image = loop {
+            match unsafe { recv_frame(header.as_mut_ptr()) } {
+                Some(frame) if frame.seq == 0 && usize::from(frame.len) == ImageHeader::SIZE => {
+                    let mut bytes = [0u8; ImageHeader::SIZE];
+                    bytes.copy_from_slice(&header[..ImageHeader::SIZE]);
+                    let image = ImageHeader::decode(bytes);
+                    // refused outright, so the client gives up rather than overrunning what's above
+                    if image.len as usize > MAX_IMAGE_LEN {
+                        nak();
+                        continue;
+                    }
+                    ack(0);
+                    break image;
+                }
+                _ => nak(),
+            }
+        };
+        let image_len = image.len as usize;
+
+        let mut received: usize = 0;
+        let mut seq: u32 = 1;
+        while received < image_len {
+            // a bad frame may scribble up to a chunk past the image, which is only ever free RAM
+            let dst = (STAGING_ADDR + received) as *mut u8;
+            match unsafe { recv_frame(dst) } {
+                Some(frame)
+                    if frame.seq == seq
+                        && frame.len != 0
+                        && received + usize::from(frame.len) <= image_len =>
+                {
+                    ack(seq);
+                    received += usize::from(frame.len);
+                    seq += 1;
+                }
+                // our ACK was lost, and the client sent it again
+                Some(frame) if frame.seq < seq => ack(frame.seq),
+                _ => nak(),
+            }
+        }
+
+        let staged = unsafe { core::slice::from_raw_parts(STAGING_ADDR as *const u8, image_len) };
+        if loader::crc32(staged) == image.crc && unsafe { load(staged) }.is_some() {
+            break;
+        }
+        uart::write_bytes(&loader::BAD_IMAGE);
     }
 
-    putchar(3);
-    putchar(3);
-    putchar(3);
+    uart::write_bytes(&loader::DONE);
+    uart::progress(BootStage::ChainloaderJump);
 
-    let mut kernel_len: u32 = 0;
-    kernel_len |= getchar() as u32;
-    kernel_len |= (getchar() as u32) << 8;
-    kernel_len |= (getchar() as u32) << 16;
-    kernel_len |= (getchar() as u32) << 24;
+    unsafe { asm!("br {}", in(reg) KERNEL_LOAD_ADDR, in("x0") dtb, options(noreturn)) }
+}
 
-    putchar(b'O');
-    putchar(b'K');
+/// Puts the received image at [`KERNEL_LOAD_ADDR`], decompressing it if need be. Returns
+/// `None` if it doesn't fit below [`STAGING_ADDR`], or doesn't decompress to the length its
+/// header says.
+///
+/// # Safety
+///
+/// Nothing else may be using the memory from [`KERNEL_LOAD_ADDR`] up to [`STAGING_ADDR`].
+unsafe fn load(staged: &[u8]) -> Option<()> {
+    let kernel = unsafe {
+        core::slice::from_raw_parts_mut(
+            KERNEL_LOAD_ADDR as *mut u8,
+            STAGING_ADDR - KERNEL_LOAD_ADDR,
+        )
+    };
+    let Some(compressed) = staged.strip_prefix(&loader::LZ4_MAGIC) else {
+        kernel.get_mut(..staged.len())?.copy_from_slice(staged);
+        return Some(());
+    };
+
+    let (len, block) = compressed.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    (lz4::decompress(block, kernel)? == len).then_some(())
+}
 
-    unsafe {
-        let mut i: usize = 0;
-        while i < kernel_len as usize {
-            let c = getchar();
-            ((KERNEL_LOAD_ADDR + i) as *mut u8).write_volatile(c);
-            putchar(c);
-            i += 1;
-        }
+/// Receives a frame, with its payload written to `dst`. Returns its header if it arrived
+/// intact, or `None` if it was corrupted or stopped arriving part way.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of [`loader::MAX_CHUNK`] bytes.
+unsafe fn recv_frame(dst: *mut u8) -> Option<FrameHeader> {
+    while getchar() != loader::SOH {}
+
+    let mut header = [0u8; FrameHeader::SIZE];
+    for b in &mut header {
+        *b = getchar_timeout(loader::BYTE_TIMEOUT_MS)?;
+    }
+    let frame = FrameHeader::decode(header);
+    let len = usize::from(frame.len);
+    if len > loader::MAX_CHUNK {
+        return None;
+    }
+
+    for i in 0..len {
+        let c = getchar_timeout(loader::BYTE_TIMEOUT_MS)?;
+        unsafe { dst.add(i).write_volatile(c) };
+    }
+    let mut crc = [0u8; 4];
+    for b in &mut crc {
+        *b = getchar_timeout(loader::BYTE_TIMEOUT_MS)?;
     }
 
-    putchar(b'T');
-    putchar(b'Y');
-    putchar(b':');
-    putchar(b')');
+    let mut expected = Crc32::new();
+    expected.update(&header);
+    expected.update(unsafe { core::slice::from_raw_parts(dst, len) });
+    (expected.finish() == u32::from_le_bytes(crc)).then_some(frame)
+}
+
+fn ack(seq: u32) {
+    putchar(loader::ACK);
+    for b in seq.to_le_bytes() {
+        putchar(b);
+    }
+}
 
-    unsafe { asm!("mov x0, x20", "br {}", in(reg) KERNEL_LOAD_ADDR, options(noreturn)) }
+/// Asks for the last frame again, once the line has gone quiet so the retransmission doesn't
+/// get mixed up with the rest of the bad one.
+fn nak() {
+    while getchar_timeout(loader::BYTE_TIMEOUT_MS).is_some() {}
+    putchar(loader::NAK);
 }
@@ -0,0 +1,212 @@
+//! A minimal PL011 driver for the boot stages that run before the kernel.
+//!
+//! The chainloader and the bootloader run with the MMU off, so they reach the UART at its
+//! physical address, which depends on the board: pick it with the `rpi4`, `rpi5` or
+//! `qemu-virt` feature, as the builder does from `--board`. The Pi 5 uses the UART on its debug
+//! connector, unless `rp1-uart` picks RP1's UART0 on GPIO 14 and 15 instead.
+//!
+//! The Pi 4's UART and RP1's are set up from scratch. The Pi 5's debug UART and QEMU's are
+//! left configured by the firmware, so they're just enabled.
+
+#![no_std]
+
+use core::arch::asm;
+
+use kados_abi::loader::{self, BootStage};
+
+#[cfg(not(any(feature = "rpi4", feature = "rpi5", feature = "qemu-virt")))]
+compile_error!("pick a board with the `rpi4`, `rpi5` or `qemu-virt` feature");
+
+#[cfg(any(
+    all(feature = "rpi4", feature = "rpi5"),
+    all(feature = "rpi4", feature = "qemu-virt"),
+    all(feature = "rpi5", feature = "qemu-virt"),
+))]
+compile_error!("only one board feature may be enabled");
+
+#[cfg(all(feature = "rp1-uart", not(feature = "rpi5")))]
+compile_error!("`rp1-uart` is only on the Pi 5");
+
+/// The physical address of the UART's registers.
+#[cfg(feature = "rpi4")]
+pub const UART_BASE: usize = PERIPHERAL_BASE + 0x20_1000;
+/// The physical address of the UART's registers.
+#[cfg(all(feature = "rpi5", not(feature = "rp1-uart")))]
+pub const UART_BASE: usize = 0x10_7d00_1000;
+/// The physical address of the UART's registers.
+#[cfg(feature = "rp1-uart")]
+pub const UART_BASE: usize = RP1_BASE + 0x3_0000;
+/// The physical address of the UART's registers.
+#[cfg(feature = "qemu-virt")]
+pub const UART_BASE: usize = 0x0900_0000;
+
+#[cfg(feature = "rpi4")]
+const PERIPHERAL_BASE: usize = 0xFE00_0000;
+
+/// Where RP1's peripherals are, behind the PCI Express window the firmware leaves it in when
+/// `config.txt` has `pciex4_reset=0`.
+#[cfg(feature = "rp1-uart")]
+const RP1_BASE: usize = 0x1f_0000_0000;
+
+const DR: usize = 0x00;
+const FR: usize = 0x18;
+const CR: usize = 0x30;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+fn read(reg: usize) -> u32 {
+    unsafe { ((UART_BASE + reg) as *const u32).read_volatile() }
+}
+
+fn write(reg: usize, value: u32) {
+    unsafe { ((UART_BASE + reg) as *mut u32).write_volatile(value) }
+}
+
+/// Spins for about `cycles` instructions.
+pub fn delay(cycles: usize) {
+    for _ in 0..cycles {
+        unsafe { asm!("nop") };
+    }
+}
+
+/// Sets the UART up for the kernel's 921600 baud, 8N1, with its FIFOs on.
+///
+/// Waits for anything still being sent, such as the chainloader's last words, before touching
+/// the configuration.
+///
+/// # Safety
+///
+/// The UART's registers must be reachable at their physical address, and nothing else may be
+/// using them.
+#[cfg(feature = "rpi4")]
+pub unsafe fn init() {
+    const GPIO_BASE: usize = PERIPHERAL_BASE + 0x20_0000;
+    const GPFSEL1: *mut u32 = (GPIO_BASE + 0x04) as *mut u32;
+    const GPPUD: *mut u32 = (GPIO_BASE + 0x94) as *mut u32;
+    const GPPUDCLK0: *mut u32 = (GPIO_BASE + 0x98) as *mut u32;
+    const AUX_ENABLE: *mut u32 = (PERIPHERAL_BASE + 0x21_5004) as *mut u32;
+
+    while read(FR) & FR_BUSY != 0 {}
+    write(CR, 0);
+
+    unsafe {
+        // the mini UART would otherwise fight over GPIO 14/15
+        AUX_ENABLE.write_volatile(0);
+        // GPIO 14/15 to ALT0 (TXD0/RXD0), without pulls
+        let mut fsel = GPFSEL1.read_volatile();
+        fsel &= !((0b111 << 12) | (0b111 << 15));
+        fsel |= (0b100 << 12) | (0b100 << 15);
+        GPFSEL1.write_volatile(fsel);
+        GPPUD.write_volatile(0);
+        delay(150);
+        GPPUDCLK0.write_volatile((1 << 14) | (1 << 15));
+        delay(150);
+        GPPUDCLK0.write_volatile(0);
+    }
+
+    // 48 MHz / (16 * 3.25)
+    configure(3, 16);
+}
+
+/// Sets the UART up for the kernel's 921600 baud, 8N1, with its FIFOs on, once anything still
+/// being sent has gone, and routes it to GPIO 14 and 15.
+///
+/// # Safety
+///
+/// RP1 must be reachable at its physical address, and nothing else may be using its UART0 or
+/// those pins.
+#[cfg(feature = "rp1-uart")]
+pub unsafe fn init() {
+    const IO_BANK0: usize = RP1_BASE + 0xd_0000;
+    const PADS_BANK0: usize = RP1_BASE + 0xf_0000;
+    const FUNCSEL_UART0: u32 = 4;
+    const PAD_IE: u32 = 1 << 6;
+    const PAD_OD: u32 = 1 << 7;
+
+    while read(FR) & FR_BUSY != 0 {}
+    write(CR, 0);
+
+    // GPIO 14/15 to UART0's TX and RX, with their outputs driven and inputs enabled
+    for pin in [14, 15] {
+        let ctrl = (IO_BANK0 + 0x04 + pin * 8) as *mut u32;
+        let pad = (PADS_BANK0 + 0x04 + pin * 4) as *mut u32;
+        unsafe {
+            ctrl.write_volatile((ctrl.read_volatile() & !0x1f) | FUNCSEL_UART0);
+            pad.write_volatile((pad.read_volatile() & !PAD_OD) | PAD_IE);
+        }
+    }
+
+    // 50 MHz / (16 * 3.39)
+    configure(3, 25);
+}
+
+/// Programs the baud rate divisors, then 8N1 with the FIFOs on, and enables the UART.
+#[cfg(any(feature = "rpi4", feature = "rp1-uart"))]
+fn configure(ibrd: u32, fbrd: u32) {
+    const IBRD: usize = 0x24;
+    const FBRD: usize = 0x28;
+    const LCRH: usize = 0x2C;
+    const ICR: usize = 0x44;
+
+    write(ICR, 0x7ff);
+    write(IBRD, ibrd);
+    write(FBRD, fbrd);
+    write(LCRH, (1 << 4) | (0b11 << 5)); // FEN | WLEN = 8 bits
+    write(CR, CR_RXE | CR_TXE | CR_UARTEN);
+}
+
+/// Enables the UART, keeping the configuration it was left with, once anything still being
+/// sent has gone.
+///
+/// # Safety
+///
+/// The UART's registers must be reachable at their physical address, and nothing else may be
+/// using them.
+#[cfg(not(any(feature = "rpi4", feature = "rp1-uart")))]
+pub unsafe fn init() {
+    while read(FR) & FR_BUSY != 0 {}
+    write(CR, read(CR) | CR_RXE | CR_TXE | CR_UARTEN);
+}
+
+/// Sends a byte, waiting for room in the FIFO.
+pub fn putchar(c: u8) {
+    while read(FR) & FR_TXFF != 0 {}
+    write(DR, u32::from(c));
+}
+
+/// Sends every byte of `bytes`.
+pub fn write_bytes(bytes: &[u8]) {
+    for &b in bytes {
+        putchar(b);
+    }
+}
+
+/// Waits for a byte and returns it.
+#[must_use]
+pub fn getchar() -> u8 {
+    loop {
+        if let Some(c) = try_getchar() {
+            return c;
+        }
+    }
+}
+
+/// Returns a received byte, or `None` if there isn't one yet.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)] // the byte is the bottom of `DR`, above it are errors
+pub fn try_getchar() -> Option<u8> {
+    (read(FR) & FR_RXFE == 0).then(|| read(DR) as u8)
+}
+
+/// Reports reaching `stage` to the loader client, or whoever is watching the serial line.
+pub fn progress(stage: BootStage) {
+    write_bytes(loader::PROGRESS);
+    putchar(stage.code());
+    write_bytes(b"\r\n");
+}
@@ -0,0 +1,118 @@
+//! The BCM2835 ARM interrupt controller (ARMCTRL), which the BCM2711 keeps around for its
+//! legacy peripheral interrupts.
+//!
+//! It sits behind the GIC and is cascaded from one of its SPIs. Its interrupts are numbered
+//! by bank in the device tree: bank 0 is the eight "basic" interrupts, and banks 1 and 2 are
+//! the 64 GPU peripheral interrupts.
+
+use fdt::Fdt;
+
+use crate::{
+    fdt::get_mmio_addr,
+    irq::{Irq, IrqCell, IrqChip, IrqHandler, IrqHandlerDescriptor},
+    syscall::errno::Errno,
+};
+
+use super::drivers::mmio::Mmio;
+
+const COMPATIBLE: &[&str] = &[
+    "brcm,bcm2711-armctrl-ic",
+    "brcm,bcm2836-armctrl-ic",
+    "brcm,bcm2835-armctrl-ic",
+];
+
+/// The pending, enable and disable registers of each bank.
+const PENDING: [usize; 3] = [0x00, 0x04, 0x08];
+const ENABLE: [usize; 3] = [0x18, 0x10, 0x14];
+const DISABLE: [usize; 3] = [0x24, 0x1c, 0x20];
+
+/// The interrupts in each bank. Bank 0's other bits mirror interrupts in banks 1 and 2.
+const BANK_MASK: [u32; 3] = [0xff, u32::MAX, u32::MAX];
+const NUM_BANKS: usize = 3;
+
+/// The legacy ARM interrupt controller. Its IRQ numbers are `bank * 32 + bit`.
+#[derive(Debug, Default)]
+pub struct ArmCtrl {
+    pub regs: Mmio<u32>,
+}
+
+impl ArmCtrl {
+    /// Parses the controller's registers from the device tree.
+    pub fn parse(fdt: &Fdt) -> Result<Mmio<u32>, Errno> {
+        let node = fdt.find_compatible(COMPATIBLE).ok_or(Errno::ENODEV)?;
+        let region = node.reg().and_then(|mut r| r.next()).ok_or(Errno::EINVAL)?;
+        let addr = get_mmio_addr(fdt, &region).ok_or(Errno::EINVAL)?;
+        Ok(Mmio::new(addr.as_hhdm_virt()))
+    }
+
+    fn bank_bit(irq: Irq) -> (usize, u32) {
+        let irq = irq.as_usize();
+        (irq / 32, 1 << (irq % 32))
+    }
+}
+
+impl IrqHandler for ArmCtrl {
+    fn handle_irq(&mut self, _irq: Irq) {
+        log::warn!("handle_irq() called on ArmCtrl (no-op)");
+    }
+}
+
+impl IrqChip for ArmCtrl {
+    fn init(&mut self, fdt: &Fdt, descs: &mut [IrqHandlerDescriptor]) {
+        self.regs = Self::parse(fdt).unwrap();
+        log::debug!("ARMCTRL @ {}", self.regs.addr);
+
+        for bank in 0..NUM_BANKS {
+            unsafe { self.regs.write(DISABLE[bank], BANK_MASK[bank]) };
+        }
+
+        for (i, desc) in descs.iter_mut().enumerate().take(NUM_BANKS * 32) {
+            desc.chip_irq = Irq::from(i as u32);
+            desc.used = true;
+        }
+    }
+
+    fn ack(&mut self) -> Irq {
+        for bank in 0..NUM_BANKS {
+            let pending = unsafe { self.regs.read(PENDING[bank]) } & BANK_MASK[bank];
+            if pending != 0 {
+                return Irq::from((bank * 32) as u32 + pending.trailing_zeros());
+            }
+        }
+        Irq::SPURIOUS
+    }
+
+    fn eoi(&mut self, _irq: Irq) {
+        // interrupts are level-triggered by the devices, so there's nothing to clear here
+    }
+
+    fn translate_irq(&self, irq_data: IrqCell) -> Option<Irq> {
+        let IrqCell::L2(bank, irq) = irq_data else {
+            return None;
+        };
+        let (bank, irq) = (bank as usize, irq as usize);
+        if bank >= NUM_BANKS || irq >= 32 || BANK_MASK[bank] & (1 << irq) == 0 {
+            return None;
+        }
+        Some(Irq::from((bank * 32 + irq) as u32))
+    }
+
+    fn enable_irq(&mut self, irq: Irq) {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe { self.regs.write(ENABLE[bank], bit) };
+    }
+
+    fn disable_irq(&mut self, irq: Irq) {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe { self.regs.write(DISABLE[bank], bit) };
+    }
+
+    fn manual_irq(&mut self, irq: Irq) {
+        log::warn!("ARMCTRL can't trigger IRQ {irq} in software");
+    }
+
+    fn is_irq_pending(&self, irq: Irq) -> bool {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe { self.regs.read(PENDING[bank]) & bit != 0 }
+    }
+}
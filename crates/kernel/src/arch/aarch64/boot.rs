@@ -1,13 +1,15 @@
-use core::arch::asm;
+use core::{arch::asm, ops::Range};
 
+use arrayvec::ArrayVec;
 use fdt::Fdt;
+use kados_abi::boot::{self as abi, AddrRange, CRASH_REGION_ADDR, CRASH_REGION_SIZE};
 
 use crate::{
-    BOOT_INFO, BootInfo,
+    BOOT_INFO, BootInfo, HHDM_PHYSICAL_OFFSET,
     arch::{Arch, Architecture},
     mem::{
-        paging::{MemMapEntries, MemMapEntry},
-        units::{FrameCount, PhysAddr},
+        paging::{BootTables, MemMapEntries},
+        units::PhysAddr,
     },
     println,
 };
@@ -20,10 +22,11 @@ unsafe extern "C" {
     unsafe static __kernel_virt_start: u8;
     unsafe static __bss_start: u8;
     unsafe static __bss_end: u8;
-    unsafe static __kernel_virt_end: u8;
-
 }
 
+/// The most physical memory ranges that can be kept out of the memory map.
+const MAX_RESERVED_REGIONS: usize = 32;
+
 unsafe fn memzero(start: usize, end: usize) {
     unsafe {
         asm!(
@@ -52,81 +55,125 @@ unsafe fn memzero(start: usize, end: usize) {
 /// The higher-half boot function.
 ///
 /// This function is called by the bootloader to initialize the kernel in higher-half memory.
-/// It sets up the BSS section, parses the flattened device tree (FDT),
-/// and calls the `kernel_main` function.
+/// It applies any runtime relocations (for PIE builds), sets up the BSS section, checks the
+/// [`abi::BootInfo`] the bootloader passed, parses the flattened device tree (FDT), and calls
+/// the `kernel_main` function.
+///
+/// `boot_info` is the physical address of the bootloader's [`abi::BootInfo`], which is
+/// identity mapped.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn boot_higher_half(dtb_ptr: *const u8) -> ! {
+pub unsafe extern "C" fn boot_higher_half(boot_info: *const abi::BootInfo) -> ! {
     unsafe {
-        super::serial::init();
+        let relocations = super::reloc::apply_relocations();
+
+        super::serial::init(&*boot_info);
         let bss_start = &raw const __bss_start as usize;
         let bss_end = &raw const __bss_end as usize;
 
         println!();
 
+        if relocations == usize::MAX {
+            println!("unsupported relocation type in .rela.dyn");
+            Arch::hcf();
+        } else if relocations > 0 {
+            println!("applied {} relocations", relocations);
+        }
+
         println!("zeroing BSS 0x{:016x} .. 0x{:016x}", bss_start, bss_end);
         memzero(bss_start, bss_end);
 
+        // copied out, since the boot image it's in is reclaimed later
+        let info = boot_info.read();
+        let kernel_phys_start = &raw const __kernel_phys_start as usize;
+        let kernel_phys_end = &raw const __kernel_phys_end as usize;
+        let boot_phys_start = &raw const __boot_start as usize;
+        let boot_phys_end = &raw const __boot_end as usize;
+        check_boot_info(&info, kernel_phys_start..kernel_phys_end);
+
         println!("parsing FDT");
-        let Ok(fdt) = Fdt::from_ptr(dtb_ptr) else {
+        let dtb_ptr = info.dtb.start as usize;
+        // go through the HHDM so the FDT stays reachable after the identity map is gone
+        let dtb_virt = PhysAddr::new_canonical(dtb_ptr).as_hhdm_virt();
+        let Ok(fdt) = Fdt::from_ptr(dtb_virt.as_raw_ptr()) else {
             println!("FDT parsing failed");
             Arch::hcf();
         };
         let mut mem_map = MemMapEntries::new();
 
-        let kernel_phys_start = &raw const __kernel_phys_start as usize;
-        let kernel_phys_end = &raw const __kernel_phys_end as usize;
-        let boot_phys_start = &raw const __boot_start as usize;
-        let boot_phys_end = &raw const __boot_end as usize;
+        let mut reserved = ArrayVec::<Range<usize>, MAX_RESERVED_REGIONS>::new();
+        // everything below the boot image belongs to the firmware (spin tables and the like)
+        reserved.push(0..boot_phys_start);
+        reserved.push(boot_phys_start..boot_phys_end);
+        reserved.push(kernel_phys_start..kernel_phys_end);
+        crate::fdt::for_each_reserved_region(&fdt, PhysAddr::new_canonical(dtb_ptr), |region| {
+            println!("reserved: 0x{:016x} .. 0x{:016x}", region.start, region.end);
+            if reserved.try_push(region).is_err() {
+                println!("too many reserved regions");
+                Arch::hcf();
+            }
+        });
+
+        let crash_region = reserve_crash_region(info.memory(), &mut reserved);
+
+        reserved.sort_unstable_by_key(|region| region.start);
 
         println!("enumerating memory regions");
-        for region in fdt.memory().regions() {
-            let mut start = (region.starting_address as usize).max(boot_phys_start);
-            let end = start + region.size.unwrap_or(0);
-            if start >= end {
-                continue;
-            }
-            let mut page = start;
-            while page < end {
-                if (kernel_phys_start..kernel_phys_end).contains(&page) {
-                    // we've run into kernel code; end our current chunk and skip past it
-                    if page > start {
-                        mem_map.push_usable(MemMapEntry {
-                            base: PhysAddr::new_canonical(start),
-                            size: FrameCount::from_bytes(page - start),
-                        });
-                    }
-
-                    start = kernel_phys_end;
-                    page = kernel_phys_end;
-                    continue;
-                }
-                if (boot_phys_start..boot_phys_end).contains(&page) {
-                    // we've run into boot code; end our current chunk and skip past it
-                    if page > start {
-                        mem_map.push_usable(MemMapEntry {
-                            base: PhysAddr::new_canonical(start),
-                            size: FrameCount::from_bytes(page - start),
-                        });
-                    }
-
-                    start = boot_phys_end;
-                    page = boot_phys_end;
-                    continue;
-                }
-                page += Arch::PAGE_SIZE;
-            }
-            if start < end {
-                // we've run out of space; add the remaining chunk
-                mem_map.push_usable(MemMapEntry {
-                    base: PhysAddr::new_canonical(start),
-                    size: FrameCount::from_bytes(end - start),
-                });
+        for region in info.memory() {
+            let region = region.start as usize..region.end as usize;
+            println!("memory: 0x{:016x} .. 0x{:016x}", region.start, region.end);
+            if let Err(e) = mem_map.push_usable_except(region, &reserved) {
+                println!("{e}; some of this region will go unused");
             }
         }
+        if mem_map.usable_entries().is_empty() {
+            println!("no usable memory");
+            Arch::hcf();
+        }
+
+        let boot_tables = BootTables {
+            base: PhysAddr::new_canonical(info.boot_tables.start as usize),
+            used_end: PhysAddr::new_canonical(info.boot_tables_used_end as usize),
+            end: PhysAddr::new_canonical(info.boot_tables.end as usize),
+        };
+        println!(
+            "boot page tables: {} .. {} ({} .. {} used)",
+            boot_tables.base, boot_tables.end, boot_tables.base, boot_tables.used_end,
+        );
+
+        let initrd = phys_range(info.initrd);
+        if let Some(initrd) = &initrd {
+            println!("initrd: {} .. {}", initrd.start, initrd.end);
+        }
+
+        // the command line is in the DTB, which is reserved for good
+        let cmdline = phys_range(info.cmdline).map_or("", |range| {
+            let bytes = core::slice::from_raw_parts(
+                range.start.as_hhdm_virt().as_raw_ptr::<u8>(),
+                info.cmdline.len() as usize,
+            );
+            core::str::from_utf8(bytes).unwrap_or_else(|_| {
+                println!("command line isn't UTF-8; ignoring it");
+                ""
+            })
+        });
+
+        let framebuffer = (!info.framebuffer.memory.is_empty()).then_some(info.framebuffer);
+
+        let peripherals = phys_range(info.peripherals);
+        if let Some(peripherals) = &peripherals {
+            println!("peripherals: {} .. {}", peripherals.start, peripherals.end);
+        }
 
         let boot_info = BootInfo {
             fdt: Some(fdt),
+            dtb_phys: PhysAddr::new_canonical(dtb_ptr),
+            initrd,
+            crash_region,
             mem_map,
+            boot_tables,
+            cmdline,
+            framebuffer,
+            peripherals,
         };
 
         BOOT_INFO.call_once(|| boot_info);
@@ -135,3 +182,72 @@ pub unsafe extern "C" fn boot_higher_half(dtb_ptr: *const u8) -> ! {
         crate::kernel_main()
     }
 }
+
+/// Halts unless the bootloader's [`abi::BootInfo`] is one this kernel understands, and agrees
+/// with the kernel about where it is.
+fn check_boot_info(info: &abi::BootInfo, kernel_phys: Range<usize>) {
+    if !info.is_valid() {
+        println!(
+            "bootloader passed a BootInfo of version {} and {} bytes; expected version {} and {} bytes",
+            info.version,
+            info.size,
+            abi::BOOT_INFO_VERSION,
+            size_of::<abi::BootInfo>(),
+        );
+        Arch::hcf();
+    }
+    if info.hhdm_offset as usize != HHDM_PHYSICAL_OFFSET {
+        println!("bootloader put the HHDM at 0x{:016x}", info.hhdm_offset);
+        Arch::hcf();
+    }
+    let kernel_virt = &raw const __kernel_virt_start as usize;
+    let expected_virt = kernel_virt..kernel_virt + kernel_phys.len();
+    if info.kernel_phys != AddrRange::new(kernel_phys.start, kernel_phys.end)
+        || info.kernel_virt != AddrRange::new(expected_virt.start, expected_virt.end)
+    {
+        println!(
+            "bootloader mapped the kernel at {:x?} -> {:x?}, not {:x?} -> {:x?}",
+            info.kernel_phys, info.kernel_virt, kernel_phys, expected_virt,
+        );
+        Arch::hcf();
+    }
+    if info.dtb.is_empty() {
+        println!("bootloader found no FDT");
+        Arch::hcf();
+    }
+}
+
+/// Returns a non-empty range from the bootloader as physical addresses.
+fn phys_range(range: AddrRange) -> Option<Range<PhysAddr>> {
+    (!range.is_empty()).then(|| {
+        PhysAddr::new_canonical(range.start as usize)..PhysAddr::new_canonical(range.end as usize)
+    })
+}
+
+/// Reserves the crash region if it's RAM that nothing else has claimed, returning it.
+fn reserve_crash_region(
+    memory: &[AddrRange],
+    reserved: &mut ArrayVec<Range<usize>, MAX_RESERVED_REGIONS>,
+) -> Option<Range<PhysAddr>> {
+    let crash_region = CRASH_REGION_ADDR..CRASH_REGION_ADDR + CRASH_REGION_SIZE;
+    let in_ram = memory.iter().any(|region| {
+        region.start as usize <= crash_region.start && crash_region.end <= region.end as usize
+    });
+    let free = !reserved
+        .iter()
+        .any(|region| region.start < crash_region.end && crash_region.start < region.end);
+    if !in_ram || !free {
+        println!("crash region unavailable");
+        return None;
+    }
+
+    println!(
+        "crash region: 0x{:016x} .. 0x{:016x}",
+        crash_region.start, crash_region.end
+    );
+    if reserved.try_push(crash_region.clone()).is_err() {
+        println!("too many reserved regions");
+        Arch::hcf();
+    }
+    Some(PhysAddr::new_canonical(crash_region.start)..PhysAddr::new_canonical(crash_region.end))
+}
@@ -0,0 +1,180 @@
+//! Crash reports for user tasks that take a fault they can't recover from.
+//!
+//! A report captures everything needed to tell why a task died after it's gone: its
+//! registers, the exception syndrome, the faulting address, and what the task's page tables
+//! had mapped there. It's logged when the fault is taken and kept on the task's
+//! [`Context`](crate::task::context::Context) for whoever collects its exit status.
+//!
+//! The kernel's own crash records, written when it panics, are in
+//! [`crashdump`](crate::crashdump).
+
+use core::{arch::asm, fmt};
+
+use aarch64_cpu::registers::{
+    CurrentEL, DAIF, ELR_EL1, ESR_EL1, FAR_EL1, Readable, SCTLR_EL1, SPSR_EL1, TTBR0_EL1, TTBR1_EL1,
+};
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::{paging::table::PageFlags, units::VirtAddr},
+    task::context::{self, Pid},
+};
+
+use super::{
+    esr::{Esr, ExceptionClass},
+    vectors::InterruptFrame,
+};
+
+/// The kind of access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read => f.pad("read"),
+            Self::Write => f.pad("write"),
+            Self::Execute => f.pad("execute"),
+        }
+    }
+}
+
+/// What the task's page tables had at the faulting address.
+#[derive(Debug, Clone, Copy)]
+pub enum Mapping {
+    /// Nothing is mapped there.
+    Unmapped,
+    /// A page is mapped there with these flags.
+    Mapped(PageFlags),
+    /// The task has no address space to look in.
+    NoAddrSpace,
+}
+
+impl Mapping {
+    /// Returns `true` if the mapping allows the given access.
+    #[must_use]
+    pub fn allows(&self, access: Access) -> bool {
+        match self {
+            Self::Mapped(flags) => match access {
+                Access::Read => true,
+                Access::Write => flags.is_writable(),
+                Access::Execute => flags.is_executable(),
+            },
+            Self::Unmapped | Self::NoAddrSpace => false,
+        }
+    }
+}
+
+/// Everything known about a fatal fault in a user task.
+#[derive(Clone, Copy)]
+pub struct CrashReport {
+    /// The task that faulted, if there was a current task.
+    pub pid: Option<Pid>,
+    /// The registers at the time of the fault.
+    pub frame: InterruptFrame,
+    /// The faulting address and the access made to it, for aborts.
+    pub fault: Option<(VirtAddr, Access)>,
+    /// What was mapped at the faulting address.
+    pub mapping: Mapping,
+}
+
+impl CrashReport {
+    /// Captures a report for the fault the current task just took.
+    ///
+    /// Must be called from the exception handler, before anything else can fault and
+    /// overwrite `FAR_EL1`.
+    #[must_use]
+    pub fn capture(frame: &InterruptFrame) -> Self {
+        let esr = Esr(frame.iret.esr_el1);
+        let access = match (esr.class(), esr.abort()) {
+            (_, Some(abort)) if !abort.far_valid => None,
+            (_, Some(abort)) if abort.fetch => Some(Access::Execute),
+            (_, Some(abort)) if abort.write => Some(Access::Write),
+            (_, Some(_)) => Some(Access::Read),
+            (ExceptionClass::PcAlignment, _) => Some(Access::Execute),
+            _ => None,
+        };
+        let fault = access.map(|access| {
+            let addr = unsafe { VirtAddr::new_unchecked(FAR_EL1.get() as usize) };
+            (addr, access)
+        });
+
+        let cx = context::current();
+        let pid = cx.as_ref().map(|cx| cx.read().pid);
+        let addr_space = cx.and_then(|cx| cx.read().addr_space.clone());
+        let mapping = match (fault, addr_space) {
+            (Some((addr, _)), Some(addr_space)) => addr_space
+                .read()
+                .table
+                .translate(addr)
+                .ok()
+                .map(|entry| entry.flags())
+                .filter(PageFlags::is_present)
+                .map_or(Mapping::Unmapped, Mapping::Mapped),
+            (Some(_), None) => Mapping::NoAddrSpace,
+            (None, _) => Mapping::Unmapped,
+        };
+
+        Self {
+            pid,
+            frame: *frame,
+            fault,
+            mapping,
+        }
+    }
+
+    /// Writes the report to the log.
+    pub fn log(&self) {
+        let esr = Esr(self.frame.iret.esr_el1);
+        match self.pid {
+            Some(pid) => log::error!("User task {pid} crashed: {esr}"),
+            None => log::error!("User task crashed: {esr}"),
+        }
+        log::error!(
+            "  ESR_EL1 {:#x}, PC {:#x}, SP {:#x}",
+            esr.0,
+            { self.frame.iret.elr_el1 },
+            { self.frame.iret.sp_el0 },
+        );
+        if let Some((addr, access)) = self.fault {
+            match self.mapping {
+                Mapping::Unmapped => log::error!("  {access} of {addr}, which isn't mapped"),
+                Mapping::NoAddrSpace => {
+                    log::error!("  {access} of {addr}, with no address space");
+                }
+                Mapping::Mapped(flags) if self.mapping.allows(access) => {
+                    log::error!("  {access} of {addr}, mapped [{flags}]");
+                }
+                Mapping::Mapped(flags) => {
+                    log::error!("  {access} of {addr}, which violates its mapping [{flags}]");
+                }
+            }
+        }
+        self.frame.dump();
+    }
+}
+
+/// Writes the registers that say what the CPU was last doing for a kernel crash record: the
+/// state of the last exception taken, and where the current code is running.
+pub fn write_registers(out: &mut impl fmt::Write) -> fmt::Result {
+    let (sp, lr): (usize, usize);
+    unsafe { asm!("mov {}, sp", "mov {}, x30", out(reg) sp, out(reg) lr) };
+
+    let esr = ESR_EL1.get() as usize;
+    writeln!(out, "ESR_EL1:   {esr:016x} ({})", Esr(esr))?;
+    writeln!(out, "FAR_EL1:   {:016x}", FAR_EL1.get())?;
+    writeln!(out, "ELR_EL1:   {:016x}", ELR_EL1.get())?;
+    writeln!(out, "SPSR_EL1:  {:016x}", SPSR_EL1.get())?;
+    writeln!(out, "SP:        {sp:016x}")?;
+    writeln!(out, "FP:        {:016x}", Arch::frame_pointer())?;
+    writeln!(out, "LR:        {lr:016x}")?;
+    writeln!(out, "CurrentEL: {}", CurrentEL.get() >> 2)?;
+    writeln!(out, "DAIF:      {:016x}", DAIF.get())?;
+    writeln!(out, "SCTLR_EL1: {:016x}", SCTLR_EL1.get())?;
+    writeln!(out, "TTBR0_EL1: {:016x}", TTBR0_EL1.get())?;
+    writeln!(out, "TTBR1_EL1: {:016x}", TTBR1_EL1.get())
+}
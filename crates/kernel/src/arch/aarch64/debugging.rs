@@ -0,0 +1,832 @@
+//! A GDB remote stub, for debugging the kernel over the serial port.
+//!
+//! Built with the `gdb` feature. Whenever the kernel hits a breakpoint, trips a watchpoint, or
+//! finishes a single step, it stops here and speaks the GDB remote serial protocol over the
+//! UART until the debugger resumes it. The `gdb` shell command stops the kernel on purpose, after
+//! which a debugger can attach with `target remote <serial port>`.
+//!
+//! The stub brackets its traffic with [`loader::GDB_ENTER`] and [`loader::GDB_EXIT`], so the
+//! loader server can tell it apart from the console and hand it to a debugger over TCP instead.
+//!
+//! Only the general purpose registers are exposed; the kernel doesn't use the FP/SIMD registers.
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{FAR_EL1, ID_AA64DFR0_EL1, OSLAR_EL1, PAR_EL1, Readable, Writeable};
+use arrayvec::ArrayVec;
+use kados_abi::loader;
+
+use crate::{
+    __text_end, __text_start,
+    arch::{Architecture, serial},
+    mem::protect,
+    sync::IrqMutex,
+    syscall::errno::Errno,
+};
+
+use super::{
+    AArch64,
+    serial::GpioUart,
+    esr::{Esr, ExceptionClass},
+    vectors::InterruptFrame,
+};
+
+/// The largest packet we accept or send, advertised to the debugger.
+const PACKET_SIZE: usize = 1024;
+/// The number of software breakpoints that can be set at once.
+const MAX_SW_BREAKPOINTS: usize = 64;
+/// The architectural limit on hardware breakpoints and watchpoints.
+const MAX_HW_SLOTS: usize = 16;
+
+/// The immediate of the `brk` instructions we patch in, so they can be told apart from ones
+/// compiled into the kernel.
+const GDB_BRK_IMM: u32 = 0x4db;
+const BRK: u32 = 0xd420_0000;
+
+const SIGTRAP: u8 = 5;
+
+/// The number of registers in a `g` packet: x0-x30, sp, pc and cpsr.
+const NUM_REGS: usize = 34;
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+const MDSCR_MDE: u64 = 1 << 15;
+
+const SPSR_SS: usize = 1 << 21;
+const SPSR_D: usize = 1 << 9;
+const SPSR_I: usize = 1 << 7;
+/// The mode bits of the SPSR, which say which EL and stack pointer the exception came from.
+const SPSR_M: usize = 0b1111;
+const SPSR_M_EL0T: usize = 0b0000;
+
+/// Why the kernel stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A `brk` instruction, either a breakpoint set by the debugger or one compiled in.
+    SwBreakpoint,
+    /// A hardware breakpoint.
+    HwBreakpoint,
+    /// A single step completed.
+    Step,
+    /// A watchpoint was hit by an access to the given address.
+    Watchpoint(usize),
+}
+
+impl StopReason {
+    /// Decodes a debug exception from its syndrome, returning `None` for other exceptions.
+    #[must_use]
+    pub fn from_esr(esr: Esr) -> Option<Self> {
+        match esr.class() {
+            ExceptionClass::Brk64 => Some(Self::SwBreakpoint),
+            ExceptionClass::BreakpointSame => Some(Self::HwBreakpoint),
+            ExceptionClass::StepSame => Some(Self::Step),
+            ExceptionClass::WatchpointSame => Some(Self::Watchpoint(FAR_EL1.get() as usize)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    /// The load/store control bits of `DBGWCR<n>_EL1`.
+    fn lsc(self) -> u64 {
+        match self {
+            Self::Read => 0b01,
+            Self::Write => 0b10,
+            Self::Access => 0b11,
+        }
+    }
+
+    /// The name of the stop reason reported to the debugger.
+    fn stop_name(self) -> &'static str {
+        match self {
+            Self::Write => "watch",
+            Self::Read => "rwatch",
+            Self::Access => "awatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// Returns the value and control register contents that watch this range, or `None` if
+    /// the hardware can't watch it exactly.
+    fn encode(&self) -> Option<(u64, u64)> {
+        const ENABLE_EL1: u64 = 1 | (0b01 << 1);
+
+        let control = ENABLE_EL1 | (self.kind.lsc() << 3);
+        let offset = self.addr % 8;
+        if self.len != 0 && offset + self.len <= 8 {
+            // within one doubleword: select the bytes
+            let bas = ((1u64 << self.len) - 1) << offset;
+            Some(((self.addr - offset) as u64, control | (bas << 5)))
+        } else if self.len.is_power_of_two() && self.len > 8 && self.addr.is_multiple_of(self.len) {
+            // a naturally aligned power-of-two region: mask off the low address bits
+            let mask = u64::from(self.len.trailing_zeros());
+            Some((self.addr as u64, control | (0xff << 5) | (mask << 24)))
+        } else {
+            None
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        // the hardware reports any address in the doublewords being watched
+        let start = self.addr & !7;
+        let end = (self.addr + self.len).next_multiple_of(8);
+        (start..end).contains(&addr)
+    }
+}
+
+struct DebugState {
+    /// Patched-in breakpoints and the instructions they replaced.
+    sw_breakpoints: ArrayVec<(usize, u32), MAX_SW_BREAKPOINTS>,
+    hw_breakpoints: [Option<usize>; MAX_HW_SLOTS],
+    watchpoints: [Option<Watchpoint>; MAX_HW_SLOTS],
+    num_hw_breakpoints: usize,
+    num_watchpoints: usize,
+    /// While single-stepping, whether IRQs were masked before the step.
+    stepping: Option<bool>,
+}
+
+impl DebugState {
+    const fn new() -> Self {
+        Self {
+            sw_breakpoints: ArrayVec::new_const(),
+            hw_breakpoints: [None; MAX_HW_SLOTS],
+            watchpoints: [None; MAX_HW_SLOTS],
+            num_hw_breakpoints: 0,
+            num_watchpoints: 0,
+            stepping: None,
+        }
+    }
+}
+
+static STATE: IrqMutex<DebugState> = IrqMutex::new(DebugState::new());
+
+/// Writes a numbered debug register, e.g. `DBGBVR3_EL1`.
+macro_rules! write_debug_reg {
+    ($reg:literal, $index:expr, $value:expr) => {
+        write_debug_reg!(@arms $reg, $index, $value, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)
+    };
+    (@arms $reg:literal, $index:expr, $value:expr, $($n:literal)*) => {{
+        let value: u64 = $value;
+        match $index {
+            $($n => unsafe {
+                asm!(concat!("msr ", $reg, $n, "_el1, {}"), in(reg) value, options(nostack))
+            },)*
+            _ => unreachable!(),
+        }
+    }};
+}
+
+fn read_mdscr() -> u64 {
+    let mdscr: u64;
+    unsafe { asm!("mrs {}, mdscr_el1", out(reg) mdscr, options(nomem, nostack)) };
+    mdscr
+}
+
+fn write_mdscr(mdscr: u64) {
+    unsafe { asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr, options(nostack)) };
+}
+
+/// Enables debug exceptions at EL1 and clears any breakpoints left over from the firmware.
+pub fn init() {
+    OSLAR_EL1.write(OSLAR_EL1::OSLK::Unlocked);
+
+    let mut state = STATE.lock();
+    state.num_hw_breakpoints =
+        (ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::BRPs) as usize + 1).min(MAX_HW_SLOTS);
+    state.num_watchpoints =
+        (ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::WRPs) as usize + 1).min(MAX_HW_SLOTS);
+    for i in 0..state.num_hw_breakpoints {
+        write_debug_reg!("dbgbcr", i, 0);
+    }
+    for i in 0..state.num_watchpoints {
+        write_debug_reg!("dbgwcr", i, 0);
+    }
+
+    write_mdscr((read_mdscr() | MDSCR_KDE | MDSCR_MDE) & !MDSCR_SS);
+
+    log::info!(
+        "gdb stub ready ({} hardware breakpoints, {} watchpoints)",
+        state.num_hw_breakpoints,
+        state.num_watchpoints
+    );
+}
+
+/// Stops the kernel and waits for a debugger on the serial port.
+pub fn breakpoint() {
+    unsafe { asm!("brk #0xf000") };
+}
+
+/// Handles a debug exception, talking to the debugger until it resumes the kernel.
+pub fn on_irq(frame: &mut InterruptFrame, reason: StopReason) {
+    let mut state = STATE.lock();
+
+    if let Some(irqs_masked) = state.stepping.take() {
+        write_mdscr(read_mdscr() & !MDSCR_SS);
+        let mut spsr = frame.iret.spsr_el1 & !SPSR_SS;
+        if !irqs_masked {
+            spsr &= !SPSR_I;
+        }
+        frame.iret.spsr_el1 = spsr;
+    }
+
+    if reason == StopReason::SwBreakpoint && u32::from(Esr(frame.iret.esr_el1).imm16()) != GDB_BRK_IMM {
+        // a `brk` compiled into the kernel; resuming at it would just stop again
+        frame.iret.elr_el1 += 4;
+    }
+
+    // the kernel may have stopped in the middle of printing something
+    let uart = unsafe { serial::force_lock_uart() };
+    let mut session = Session {
+        uart,
+        state: &mut state,
+        frame,
+    };
+    for &b in loader::GDB_ENTER {
+        session.uart.putchar(b);
+    }
+    session.run(reason);
+    for &b in loader::GDB_EXIT {
+        session.uart.putchar(b);
+    }
+}
+
+/// How the debugger asked the kernel to resume.
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Session<'a, U> {
+    uart: U,
+    state: &'a mut DebugState,
+    frame: &'a mut InterruptFrame,
+}
+
+type Packet = ArrayVec<u8, PACKET_SIZE>;
+
+impl<U: core::ops::DerefMut<Target = GpioUart>> Session<'_, U> {
+    fn run(&mut self, reason: StopReason) {
+        let mut reply = Packet::new();
+        self.stop_reply(reason, &mut reply);
+        self.send(&reply);
+
+        let resume = loop {
+            let packet = self.recv();
+            reply.clear();
+            match self.handle(&packet, reason, &mut reply) {
+                Ok(Some(resume)) => break resume,
+                Ok(None) => {}
+                Err(e) => {
+                    reply.clear();
+                    push_str(&mut reply, "E");
+                    push_hex(&mut reply, &[e as u8]);
+                }
+            }
+            self.send(&reply);
+        };
+
+        match resume {
+            Resume::Continue => {}
+            Resume::Step => {
+                let spsr = self.frame.iret.spsr_el1;
+                self.state.stepping = Some(spsr & SPSR_I != 0);
+                // step with IRQs masked, or the step would land in the IRQ handler
+                self.frame.iret.spsr_el1 = (spsr | SPSR_SS | SPSR_I) & !SPSR_D;
+                write_mdscr(read_mdscr() | MDSCR_SS);
+            }
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason, reply: &mut Packet) {
+        if let StopReason::Watchpoint(addr) = reason
+            && let Some(wp) = self
+                .state
+                .watchpoints
+                .iter()
+                .flatten()
+                .find(|wp| wp.contains(addr))
+        {
+            push_str(reply, "T");
+            push_hex(reply, &[SIGTRAP]);
+            push_str(reply, wp.kind.stop_name());
+            push_str(reply, ":");
+            push_hex_usize(reply, wp.addr);
+            push_str(reply, ";");
+        } else {
+            push_str(reply, "S");
+            push_hex(reply, &[SIGTRAP]);
+        }
+    }
+
+    /// Handles a packet, filling in `reply`. Unsupported packets get an empty reply.
+    fn handle(
+        &mut self,
+        packet: &[u8],
+        reason: StopReason,
+        reply: &mut Packet,
+    ) -> Result<Option<Resume>, Errno> {
+        let Some((&command, args)) = packet.split_first() else {
+            return Ok(None);
+        };
+        match command {
+            b'?' => self.stop_reply(reason, reply),
+            b'q' if args.starts_with(b"Supported") => {
+                push_str(reply, "PacketSize=");
+                push_hex_usize(reply, PACKET_SIZE);
+            }
+            b'q' if args == b"Attached" => push_str(reply, "1"),
+            b'H' => push_str(reply, "OK"),
+            b'g' => {
+                for reg in 0..NUM_REGS {
+                    self.push_reg(reply, reg);
+                }
+            }
+            b'G' => {
+                let mut rest = args;
+                for reg in 0..NUM_REGS {
+                    let Some((value, tail)) = rest.split_at_checked(reg_hex_len(reg)) else {
+                        break;
+                    };
+                    self.write_reg(reg, value)?;
+                    rest = tail;
+                }
+                push_str(reply, "OK");
+            }
+            b'p' => {
+                let reg = parse_hex(args).ok_or(Errno::EINVAL)?;
+                if reg >= NUM_REGS {
+                    return Err(Errno::EINVAL);
+                }
+                self.push_reg(reply, reg);
+            }
+            b'P' => {
+                let (reg, value) = split_at_byte(args, b'=')?;
+                self.write_reg(parse_hex(reg).ok_or(Errno::EINVAL)?, value)?;
+                push_str(reply, "OK");
+            }
+            b'm' => {
+                let (addr, len) = split_at_byte(args, b',')?;
+                let addr = parse_hex(addr).ok_or(Errno::EINVAL)?;
+                // leave room for the framing, two hex digits per byte
+                let len = parse_hex(len)
+                    .ok_or(Errno::EINVAL)?
+                    .min((PACKET_SIZE - 4) / 2);
+                check_accessible(addr, len, false)?;
+                for i in 0..len {
+                    let byte = unsafe { ((addr + i) as *const u8).read_volatile() };
+                    push_hex(reply, &[byte]);
+                }
+            }
+            b'M' => {
+                let (addr, data) = split_at_byte(args, b',')?;
+                let (len, data) = split_at_byte(data, b':')?;
+                let addr = parse_hex(addr).ok_or(Errno::EINVAL)?;
+                let len = parse_hex(len).ok_or(Errno::EINVAL)?;
+                if data.len() != len * 2 {
+                    return Err(Errno::EINVAL);
+                }
+                write_memory(addr, data)?;
+                push_str(reply, "OK");
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    self.frame.iret.elr_el1 = parse_hex(args).ok_or(Errno::EINVAL)?;
+                }
+                return Ok(Some(if command == b's' {
+                    Resume::Step
+                } else {
+                    Resume::Continue
+                }));
+            }
+            b'D' | b'k' => {
+                self.clear_all();
+                if command == b'D' {
+                    self.send(b"OK");
+                }
+                return Ok(Some(Resume::Continue));
+            }
+            b'Z' | b'z' => {
+                self.set_breakpoint(args, command == b'Z')?;
+                push_str(reply, "OK");
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn reg(&self, reg: usize) -> usize {
+        let frame = &*self.frame;
+        match reg {
+            0..=18 => {
+                let scratch = frame.scratch;
+                let regs = [
+                    scratch.x0,
+                    scratch.x1,
+                    scratch.x2,
+                    scratch.x3,
+                    scratch.x4,
+                    scratch.x5,
+                    scratch.x6,
+                    scratch.x7,
+                    scratch.x8,
+                    scratch.x9,
+                    scratch.x10,
+                    scratch.x11,
+                    scratch.x12,
+                    scratch.x13,
+                    scratch.x14,
+                    scratch.x15,
+                    scratch.x16,
+                    scratch.x17,
+                    scratch.x18,
+                ];
+                regs[reg]
+            }
+            19..=30 => {
+                let preserved = frame.preserved;
+                let regs = [
+                    preserved.x19,
+                    preserved.x20,
+                    preserved.x21,
+                    preserved.x22,
+                    preserved.x23,
+                    preserved.x24,
+                    preserved.x25,
+                    preserved.x26,
+                    preserved.x27,
+                    preserved.x28,
+                    preserved.x29,
+                    preserved.x30,
+                ];
+                regs[reg - 19]
+            }
+            REG_SP => self.interrupted_sp(),
+            REG_PC => frame.iret.elr_el1,
+            REG_CPSR => frame.iret.spsr_el1,
+            _ => unreachable!(),
+        }
+    }
+
+    fn push_reg(&self, reply: &mut Packet, reg: usize) {
+        let value = self.reg(reg);
+        if reg == REG_CPSR {
+            push_hex(reply, &(value as u32).to_le_bytes());
+        } else {
+            push_hex(reply, &value.to_le_bytes());
+        }
+    }
+
+    fn write_reg(&mut self, reg: usize, hex: &[u8]) -> Result<(), Errno> {
+        let mut bytes = [0; 8];
+        if reg >= NUM_REGS || hex.len() != reg_hex_len(reg) {
+            return Err(Errno::EINVAL);
+        }
+        decode_hex(hex, &mut bytes[..hex.len() / 2])?;
+        let value = usize::from_le_bytes(bytes);
+
+        let frame = &mut *self.frame;
+        match reg {
+            0..=18 => {
+                let regs = [
+                    &raw mut frame.scratch.x0,
+                    &raw mut frame.scratch.x1,
+                    &raw mut frame.scratch.x2,
+                    &raw mut frame.scratch.x3,
+                    &raw mut frame.scratch.x4,
+                    &raw mut frame.scratch.x5,
+                    &raw mut frame.scratch.x6,
+                    &raw mut frame.scratch.x7,
+                    &raw mut frame.scratch.x8,
+                    &raw mut frame.scratch.x9,
+                    &raw mut frame.scratch.x10,
+                    &raw mut frame.scratch.x11,
+                    &raw mut frame.scratch.x12,
+                    &raw mut frame.scratch.x13,
+                    &raw mut frame.scratch.x14,
+                    &raw mut frame.scratch.x15,
+                    &raw mut frame.scratch.x16,
+                    &raw mut frame.scratch.x17,
+                    &raw mut frame.scratch.x18,
+                ];
+                unsafe { regs[reg].write_unaligned(value) };
+            }
+            19..=30 => {
+                let regs = [
+                    &raw mut frame.preserved.x19,
+                    &raw mut frame.preserved.x20,
+                    &raw mut frame.preserved.x21,
+                    &raw mut frame.preserved.x22,
+                    &raw mut frame.preserved.x23,
+                    &raw mut frame.preserved.x24,
+                    &raw mut frame.preserved.x25,
+                    &raw mut frame.preserved.x26,
+                    &raw mut frame.preserved.x27,
+                    &raw mut frame.preserved.x28,
+                    &raw mut frame.preserved.x29,
+                    &raw mut frame.preserved.x30,
+                ];
+                unsafe { regs[reg - 19].write_unaligned(value) };
+            }
+            REG_SP => {
+                // the kernel's own stack pointer is where the frame lives; leave it alone
+                if frame.iret.spsr_el1 & SPSR_M == SPSR_M_EL0T {
+                    frame.iret.sp_el0 = value;
+                } else if value != self.interrupted_sp() {
+                    return Err(Errno::EPERM);
+                }
+            }
+            REG_PC => frame.iret.elr_el1 = value,
+            REG_CPSR => frame.iret.spsr_el1 = value,
+            _ => return Err(Errno::EINVAL),
+        }
+        Ok(())
+    }
+
+    /// Returns the stack pointer of the interrupted code.
+    fn interrupted_sp(&self) -> usize {
+        if self.frame.iret.spsr_el1 & SPSR_M == SPSR_M_EL0T {
+            self.frame.iret.sp_el0
+        } else {
+            // the frame was pushed onto the interrupted stack
+            core::ptr::from_ref(&*self.frame) as usize + size_of::<InterruptFrame>()
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &[u8], insert: bool) -> Result<(), Errno> {
+        let (kind, rest) = split_at_byte(args, b',')?;
+        let (addr, len) = split_at_byte(rest, b',')?;
+        let addr = parse_hex(addr).ok_or(Errno::EINVAL)?;
+        let len = parse_hex(len).ok_or(Errno::EINVAL)?;
+
+        match (kind, insert) {
+            (b"0", true) => self.insert_sw_breakpoint(addr),
+            (b"0", false) => self.remove_sw_breakpoint(addr),
+            (b"1", true) => {
+                let slot = free_slot(&self.state.hw_breakpoints[..self.state.num_hw_breakpoints])?;
+                self.state.hw_breakpoints[slot] = Some(addr);
+                write_debug_reg!("dbgbvr", slot, addr as u64);
+                // enabled, EL1 only, matching the whole instruction
+                write_debug_reg!("dbgbcr", slot, 1 | (0b01 << 1) | (0b1111 << 5));
+                Ok(())
+            }
+            (b"1", false) => {
+                let slot = self.state.hw_breakpoints[..self.state.num_hw_breakpoints]
+                    .iter()
+                    .position(|&bp| bp == Some(addr))
+                    .ok_or(Errno::ENOENT)?;
+                self.state.hw_breakpoints[slot] = None;
+                write_debug_reg!("dbgbcr", slot, 0);
+                Ok(())
+            }
+            (b"2" | b"3" | b"4", _) => {
+                let kind = match kind {
+                    b"2" => WatchKind::Write,
+                    b"3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let watchpoint = Watchpoint { addr, len, kind };
+                let watchpoints = &mut self.state.watchpoints[..self.state.num_watchpoints];
+                if insert {
+                    let (value, control) = watchpoint.encode().ok_or(Errno::EINVAL)?;
+                    let slot = free_slot(watchpoints)?;
+                    watchpoints[slot] = Some(watchpoint);
+                    write_debug_reg!("dbgwvr", slot, value);
+                    write_debug_reg!("dbgwcr", slot, control);
+                } else {
+                    let slot = watchpoints
+                        .iter()
+                        .position(|&wp| wp == Some(watchpoint))
+                        .ok_or(Errno::ENOENT)?;
+                    watchpoints[slot] = None;
+                    write_debug_reg!("dbgwcr", slot, 0);
+                }
+                unsafe { asm!("isb") };
+                Ok(())
+            }
+            _ => Err(Errno::ENOSYS),
+        }
+    }
+
+    fn insert_sw_breakpoint(&mut self, addr: usize) -> Result<(), Errno> {
+        if self.state.sw_breakpoints.iter().any(|&(a, _)| a == addr) {
+            return Ok(());
+        }
+        if self.state.sw_breakpoints.is_full() {
+            return Err(Errno::ENOSPC);
+        }
+        if !addr.is_multiple_of(4) {
+            return Err(Errno::EINVAL);
+        }
+        check_accessible(addr, 4, false)?;
+
+        let original = unsafe { (addr as *const u32).read_volatile() };
+        let brk = BRK | (GDB_BRK_IMM << 5);
+        unsafe { protect::patch_text(addr, &brk.to_ne_bytes())? };
+        self.state.sw_breakpoints.push((addr, original));
+        Ok(())
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: usize) -> Result<(), Errno> {
+        let index = self
+            .state
+            .sw_breakpoints
+            .iter()
+            .position(|&(a, _)| a == addr)
+            .ok_or(Errno::ENOENT)?;
+        let (addr, original) = self.state.sw_breakpoints.swap_remove(index);
+        unsafe { protect::patch_text(addr, &original.to_ne_bytes()) }
+    }
+
+    /// Removes every breakpoint and watchpoint, for when the debugger goes away.
+    fn clear_all(&mut self) {
+        while let Some(&(addr, _)) = self.state.sw_breakpoints.last() {
+            self.remove_sw_breakpoint(addr).ok();
+        }
+        for slot in 0..self.state.num_hw_breakpoints {
+            self.state.hw_breakpoints[slot] = None;
+            write_debug_reg!("dbgbcr", slot, 0);
+        }
+        for slot in 0..self.state.num_watchpoints {
+            self.state.watchpoints[slot] = None;
+            write_debug_reg!("dbgwcr", slot, 0);
+        }
+        unsafe { asm!("isb") };
+    }
+
+    /// Waits for a well-formed packet, acknowledging it, and returns its contents.
+    fn recv(&mut self) -> Packet {
+        loop {
+            // skip acks and interrupt requests until a packet starts
+            while self.uart.getchar() != b'$' {}
+
+            let mut packet = Packet::new();
+            let mut checksum = 0u8;
+            let mut overflowed = false;
+            loop {
+                match self.uart.getchar() {
+                    b'#' => break,
+                    b => {
+                        checksum = checksum.wrapping_add(b);
+                        overflowed |= packet.try_push(b).is_err();
+                    }
+                }
+            }
+            let expected = [self.uart.getchar(), self.uart.getchar()];
+            let mut expected_checksum = [0];
+            if !overflowed
+                && decode_hex(&expected, &mut expected_checksum).is_ok()
+                && expected_checksum[0] == checksum
+            {
+                self.uart.putchar(b'+');
+                return packet;
+            }
+            self.uart.putchar(b'-');
+        }
+    }
+
+    /// Sends a packet, retrying until the debugger acknowledges it.
+    fn send(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let mut trailer = ArrayVec::<u8, 2>::new();
+        for digit in [checksum >> 4, checksum & 0xf] {
+            trailer.push(HEX_DIGITS[digit as usize]);
+        }
+        loop {
+            self.uart.putchar(b'$');
+            for &b in data {
+                self.uart.putchar(b);
+            }
+            self.uart.putchar(b'#');
+            for &b in &trailer {
+                self.uart.putchar(b);
+            }
+            match self.uart.getchar() {
+                b'-' => {}
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Returns the number of hex digits a register takes up in a packet.
+fn reg_hex_len(reg: usize) -> usize {
+    if reg == REG_CPSR { 8 } else { 16 }
+}
+
+fn free_slot<T>(slots: &[Option<T>]) -> Result<usize, Errno> {
+    slots.iter().position(Option::is_none).ok_or(Errno::ENOSPC)
+}
+
+/// Checks that `len` bytes at `addr` are mapped, and writable if `write` is set, so the stub
+/// can touch them without faulting.
+fn check_accessible(addr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+    let mut page = addr & !(AArch64::PAGE_SIZE - 1);
+    while page < end {
+        // ask the MMU rather than walking the tables, so block mappings are handled too
+        unsafe {
+            if write {
+                asm!("at s1e1w, {}", "isb", in(reg) page, options(nostack));
+            } else {
+                asm!("at s1e1r, {}", "isb", in(reg) page, options(nostack));
+            }
+        }
+        if PAR_EL1.is_set(PAR_EL1::F) {
+            return Err(Errno::EFAULT);
+        }
+        page += AArch64::PAGE_SIZE;
+    }
+    Ok(())
+}
+
+fn write_memory(addr: usize, hex: &[u8]) -> Result<(), Errno> {
+    let len = hex.len() / 2;
+    let mut bytes = [0; PACKET_SIZE / 2];
+    let bytes = &mut bytes[..len];
+    decode_hex(hex, bytes)?;
+
+    // the text is never writable, so the debugger's patches to code go in through an alias
+    if (__text_start()..__text_end()).contains(&addr) {
+        return unsafe { protect::patch_text(addr, bytes) };
+    }
+    check_accessible(addr, len, true)?;
+    for (i, &byte) in bytes.iter().enumerate() {
+        unsafe { ((addr + i) as *mut u8).write_volatile(byte) };
+    }
+    Ok(())
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn push_str(packet: &mut Packet, s: &str) {
+    for &b in s.as_bytes() {
+        packet.try_push(b).ok();
+    }
+}
+
+fn push_hex(packet: &mut Packet, bytes: &[u8]) {
+    for &byte in bytes {
+        packet.try_push(HEX_DIGITS[usize::from(byte >> 4)]).ok();
+        packet.try_push(HEX_DIGITS[usize::from(byte & 0xf)]).ok();
+    }
+}
+
+/// Pushes a number in big-endian hex without leading zeros, as used in packet fields.
+fn push_hex_usize(packet: &mut Packet, value: usize) {
+    let digits = value.bit_width().div_ceil(4).max(1);
+    for i in (0..digits).rev() {
+        packet.try_push(HEX_DIGITS[(value >> (i * 4)) & 0xf]).ok();
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    char::from(digit).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0usize, |acc, &d| {
+        Some((acc << 4) | usize::from(hex_value(d)?))
+    })
+}
+
+fn decode_hex(hex: &[u8], out: &mut [u8]) -> Result<(), Errno> {
+    let (pairs, []) = hex.as_chunks::<2>() else {
+        return Err(Errno::EINVAL);
+    };
+    if pairs.len() != out.len() {
+        return Err(Errno::EINVAL);
+    }
+    for (byte, &[hi, lo]) in out.iter_mut().zip(pairs) {
+        *byte = (hex_value(hi).ok_or(Errno::EINVAL)? << 4) | hex_value(lo).ok_or(Errno::EINVAL)?;
+    }
+    Ok(())
+}
+
+fn split_at_byte(bytes: &[u8], separator: u8) -> Result<(&[u8], &[u8]), Errno> {
+    let index = bytes
+        .iter()
+        .position(|&b| b == separator)
+        .ok_or(Errno::EINVAL)?;
+    Ok((&bytes[..index], &bytes[index + 1..]))
+}
@@ -0,0 +1,297 @@
+//! Bounce buffers for I/O buffers a DMA master can't address directly.
+//!
+//! Callers wrap their buffer in a [`DmaBuffer`] along with the device's [`DmaConstraints`],
+//! hand [`DmaBuffer::phys`] to the device, and call [`DmaBuffer::finish`] once the transfer
+//! is done. If the buffer already satisfies the constraints it is used in place; otherwise
+//! the data goes through a staging copy in low memory.
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use buddy_system_allocator::LockedHeap;
+
+use crate::{
+    HHDM_PHYSICAL_OFFSET,
+    arch::{Architecture, clean_data_cache, invalidate_data_cache},
+    mem::{
+        heap::KERNEL_HEAP_START,
+        paging::allocator,
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
+    syscall::errno::Errno,
+};
+
+use super::super::AArch64;
+
+/// The size of the staging pool bounced requests are copied through.
+pub const BOUNCE_POOL_SIZE: usize = AArch64::PAGE_SIZE * 256;
+
+/// Staging buffers are cache-line aligned so cache maintenance never touches a neighbor.
+const CACHE_LINE_SIZE: usize = 64;
+
+static BOUNCE_POOL: LockedHeap<32> = LockedHeap::empty();
+
+/// Allocates the bounce buffer pool.
+///
+/// This must run while the frame allocator is still handing out frames from the bottom of
+/// memory, so the pool ends up somewhere every DMA master can reach.
+///
+/// # Panics
+///
+/// This function will panic if the pool cannot be allocated.
+pub fn init() {
+    let base = allocator::reserve(
+        "bounce buffers",
+        FrameCount::from_bytes(BOUNCE_POOL_SIZE),
+        AArch64::PAGE_SIZE,
+    )
+    .expect("failed to allocate bounce buffer pool");
+    let end = base.add_bytes(BOUNCE_POOL_SIZE);
+    log::debug!("bounce buffer pool: {base} .. {end}");
+
+    unsafe {
+        BOUNCE_POOL
+            .lock()
+            .add_to_heap(base.as_hhdm_virt().value(), end.as_hhdm_virt().value());
+    }
+}
+
+/// The addressing constraints of a DMA master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The required alignment of the buffer's physical address.
+    pub align: usize,
+    /// The (exclusive) end of the physical address range the device can reach.
+    pub limit: PhysAddr,
+}
+
+impl DmaConstraints {
+    /// Creates a new set of constraints.
+    #[must_use]
+    pub const fn new(align: usize, limit: PhysAddr) -> Self {
+        Self { align, limit }
+    }
+
+    /// Returns `true` if the device can access `len` bytes at `phys` directly.
+    #[must_use]
+    pub fn allows(&self, phys: PhysAddr, len: usize) -> bool {
+        phys.is_aligned(self.align)
+            && phys
+                .value()
+                .checked_add(len)
+                .is_some_and(|end| end <= self.limit.value())
+    }
+}
+
+/// The direction data moves in during a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    /// The device both reads and writes the buffer.
+    Bidirectional,
+}
+
+impl DmaDirection {
+    fn device_reads(self) -> bool {
+        matches!(self, Self::ToDevice | Self::Bidirectional)
+    }
+
+    fn device_writes(self) -> bool {
+        matches!(self, Self::FromDevice | Self::Bidirectional)
+    }
+}
+
+#[derive(Debug)]
+enum Source<'a> {
+    Shared(&'a [u8]),
+    Exclusive(&'a mut [u8]),
+}
+
+impl Source<'_> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Shared(buf) => buf,
+            Self::Exclusive(buf) => buf,
+        }
+    }
+}
+
+/// A staging copy allocated from the bounce pool.
+#[derive(Debug)]
+struct Staging {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl Staging {
+    fn alloc(len: usize, constraints: &DmaConstraints) -> Result<Self, Errno> {
+        let align = constraints.align.max(CACHE_LINE_SIZE);
+        let layout = Layout::from_size_align(len, align).map_err(|_| Errno::EINVAL)?;
+        let ptr = BOUNCE_POOL
+            .lock()
+            .alloc(layout)
+            .map_err(|()| Errno::ENOMEM)?;
+        let this = Self { ptr, layout };
+        if !constraints.allows(this.phys(), len) {
+            // the pool itself is out of the device's reach
+            return Err(Errno::EFAULT);
+        }
+        Ok(this)
+    }
+
+    fn phys(&self) -> PhysAddr {
+        VirtAddr::new_canonical(self.ptr.as_ptr() as usize).as_hhdm_phys()
+    }
+
+    fn as_slice_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        BOUNCE_POOL.lock().dealloc(self.ptr, self.layout);
+    }
+}
+
+/// An I/O buffer prepared for a DMA transfer, bounced through low memory if necessary.
+///
+/// The data cache is cleaned when the buffer is created and invalidated when it is
+/// finished, so callers don't need to do their own maintenance on it.
+#[derive(Debug)]
+#[must_use = "the buffer must be kept alive until the transfer completes"]
+pub struct DmaBuffer<'a> {
+    source: Source<'a>,
+    staging: Option<Staging>,
+    phys: PhysAddr,
+    direction: DmaDirection,
+    finished: bool,
+}
+
+impl<'a> DmaBuffer<'a> {
+    /// Prepares a buffer the device will read from.
+    pub fn to_device(buf: &'a [u8], constraints: &DmaConstraints) -> Result<Self, Errno> {
+        Self::new(Source::Shared(buf), constraints, DmaDirection::ToDevice)
+    }
+
+    /// Prepares a buffer the device will write to.
+    pub fn from_device(buf: &'a mut [u8], constraints: &DmaConstraints) -> Result<Self, Errno> {
+        Self::new(
+            Source::Exclusive(buf),
+            constraints,
+            DmaDirection::FromDevice,
+        )
+    }
+
+    /// Prepares a buffer the device will both read from and write to.
+    pub fn bidirectional(buf: &'a mut [u8], constraints: &DmaConstraints) -> Result<Self, Errno> {
+        Self::new(
+            Source::Exclusive(buf),
+            constraints,
+            DmaDirection::Bidirectional,
+        )
+    }
+
+    fn new(
+        source: Source<'a>,
+        constraints: &DmaConstraints,
+        direction: DmaDirection,
+    ) -> Result<Self, Errno> {
+        let buf = source.as_slice();
+        if buf.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+
+        let direct = contiguous_phys(buf).filter(|&phys| constraints.allows(phys, buf.len()));
+        let (staging, phys) = if let Some(phys) = direct {
+            (None, phys)
+        } else {
+            let mut staging = Staging::alloc(buf.len(), constraints)?;
+            if direction.device_reads() {
+                staging.as_slice_mut().copy_from_slice(buf);
+            }
+            let phys = staging.phys();
+            (Some(staging), phys)
+        };
+
+        let this = Self {
+            source,
+            staging,
+            phys,
+            direction,
+            finished: false,
+        };
+        // write back anything the device is about to read, and make sure no dirty lines
+        // get evicted over what it writes
+        unsafe { clean_data_cache(this.device_ptr(), this.len()) };
+        Ok(this)
+    }
+
+    /// Returns the physical address to hand to the device.
+    #[must_use]
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the length of the buffer in bytes.
+    #[must_use]
+    #[allow(clippy::len_without_is_empty)] // buffers are never empty
+    pub fn len(&self) -> usize {
+        self.source.as_slice().len()
+    }
+
+    /// Returns `true` if the data is going through a staging copy.
+    #[must_use]
+    pub fn is_bounced(&self) -> bool {
+        self.staging.is_some()
+    }
+
+    /// Completes the transfer, copying data the device wrote back into the caller's buffer.
+    ///
+    /// The transfer must have finished before this is called. Dropping the buffer does the
+    /// same thing.
+    pub fn finish(mut self) {
+        self.finish_inner();
+    }
+
+    fn device_ptr(&self) -> *const u8 {
+        match &self.staging {
+            Some(staging) => staging.ptr.as_ptr(),
+            None => self.source.as_slice().as_ptr(),
+        }
+    }
+
+    fn finish_inner(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        if !self.direction.device_writes() {
+            return;
+        }
+        unsafe { invalidate_data_cache(self.device_ptr(), self.len()) };
+        if let (Some(staging), Source::Exclusive(buf)) = (&mut self.staging, &mut self.source) {
+            buf.copy_from_slice(staging.as_slice_mut());
+        }
+    }
+}
+
+impl Drop for DmaBuffer<'_> {
+    fn drop(&mut self) {
+        self.finish_inner();
+    }
+}
+
+/// Returns the physical address of `buf` if it lives in the HHDM, where virtually
+/// contiguous memory is also physically contiguous.
+fn contiguous_phys(buf: &[u8]) -> Option<PhysAddr> {
+    let start = buf.as_ptr() as usize;
+    let end = start.checked_add(buf.len())?;
+    if start < HHDM_PHYSICAL_OFFSET || end > KERNEL_HEAP_START {
+        return None;
+    }
+    Some(VirtAddr::new_canonical(start).as_hhdm_phys())
+}
@@ -0,0 +1,538 @@
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::vec::Vec;
+use fdt::{
+    Fdt,
+    node::{FdtNode, NodeProperty},
+};
+use spin::Once;
+
+use crate::{
+    arch::driver::Driver,
+    fdt::get_mmio_addr,
+    irq::{Irq, IrqHandler, get_irq, register_irq},
+    mem::units::{PhysAddr, VirtAddr},
+    sync::IrqMutex,
+    syscall::errno::Errno,
+};
+
+use super::{bounce::DmaConstraints, dma_alloc, dma_free, mmio::Mmio};
+
+const DMA_CS: usize = 0x00;
+const DMA_CONBLK_AD: usize = 0x04;
+const DMA_DEBUG: usize = 0x20;
+const DMA_INT_STATUS: usize = 0xfe0;
+const DMA_ENABLE: usize = 0xff0;
+
+const CHANNEL_STRIDE: usize = 0x100;
+/// Channel 15 lives in a separate register block and isn't handed to the ARM on the Pi.
+const NUM_CHANNELS: usize = 15;
+
+const CS_ACTIVE: u32 = 1 << 0;
+const CS_END: u32 = 1 << 1;
+const CS_INT: u32 = 1 << 2;
+const CS_ERROR: u32 = 1 << 8;
+const CS_PRIORITY: u32 = 8 << 16;
+const CS_PANIC_PRIORITY: u32 = 15 << 20;
+const CS_WAIT_FOR_OUTSTANDING_WRITES: u32 = 1 << 28;
+const CS_ABORT: u32 = 1 << 30;
+const CS_RESET: u32 = 1 << 31;
+
+const TI_INTEN: u32 = 1 << 0;
+const TI_WAIT_RESP: u32 = 1 << 3;
+const TI_DEST_INC: u32 = 1 << 4;
+const TI_DEST_WIDTH: u32 = 1 << 5;
+const TI_SRC_INC: u32 = 1 << 8;
+const TI_SRC_WIDTH: u32 = 1 << 9;
+const TI_BURST_LENGTH_SHIFT: u32 = 12;
+
+const DEBUG_LITE: u32 = 1 << 28;
+
+/// Legacy DMA masters see the first GiB of RAM through this (L2-uncached) bus alias.
+const BUS_RAM_ALIAS: u32 = 0xc000_0000;
+const BUS_RAM_LIMIT: usize = 0x4000_0000;
+
+/// The largest transfer a single control block can describe on a full channel.
+const MAX_LEN_FULL: usize = 1 << 29;
+/// DMA Lite channels only have a 16-bit length field.
+const MAX_LEN_LITE: usize = 0x8000;
+
+/// The addressing constraints of the legacy DMA engines, for use with
+/// [`DmaBuffer`](super::bounce::DmaBuffer).
+pub const CONSTRAINTS: DmaConstraints =
+    DmaConstraints::new(4, PhysAddr::new_canonical(BUS_RAM_LIMIT));
+
+static DMA: Once<IrqMutex<DmaController>> = Once::new();
+
+static COMPLETE: [AtomicBool; NUM_CHANNELS] = [const { AtomicBool::new(false) }; NUM_CHANNELS];
+
+/// Converts a physical RAM address into the address a legacy DMA master uses for it.
+pub fn bus_addr(phys: PhysAddr) -> Result<u32, Errno> {
+    if phys.value() >= BUS_RAM_LIMIT {
+        return Err(Errno::EFAULT);
+    }
+    Ok(phys.value() as u32 | BUS_RAM_ALIAS)
+}
+
+/// A DMA control block, as read by the DMA engine.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(32))]
+pub struct ControlBlock {
+    pub ti: u32,
+    pub source_ad: u32,
+    pub dest_ad: u32,
+    pub txfr_len: u32,
+    pub stride: u32,
+    pub nextconbk: u32,
+    _reserved: [u32; 2],
+}
+
+/// A builder for a memory-to-memory [`ControlBlock`].
+#[derive(Debug, Clone, Copy)]
+#[must_use = "call `build()` to get the control block"]
+pub struct ControlBlockBuilder {
+    cb: ControlBlock,
+}
+
+impl ControlBlockBuilder {
+    /// Creates a new builder for an incrementing memory-to-memory copy using wide bursts.
+    pub fn new() -> Self {
+        Self {
+            cb: ControlBlock {
+                ti: TI_WAIT_RESP
+                    | TI_SRC_INC
+                    | TI_DEST_INC
+                    | TI_SRC_WIDTH
+                    | TI_DEST_WIDTH
+                    | (4 << TI_BURST_LENGTH_SHIFT),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets the source address.
+    pub fn source(mut self, src: PhysAddr) -> Result<Self, Errno> {
+        self.cb.source_ad = bus_addr(src)?;
+        Ok(self)
+    }
+
+    /// Sets the destination address.
+    pub fn dest(mut self, dst: PhysAddr) -> Result<Self, Errno> {
+        self.cb.dest_ad = bus_addr(dst)?;
+        Ok(self)
+    }
+
+    /// Sets the transfer length in bytes.
+    pub fn len(mut self, len: u32) -> Self {
+        self.cb.txfr_len = len;
+        self
+    }
+
+    /// Sets whether the source address is incremented after each read.
+    pub fn source_inc(mut self, inc: bool) -> Self {
+        self.set_ti(TI_SRC_INC, inc);
+        self
+    }
+
+    /// Sets whether the destination address is incremented after each write.
+    pub fn dest_inc(mut self, inc: bool) -> Self {
+        self.set_ti(TI_DEST_INC, inc);
+        self
+    }
+
+    /// Sets whether an interrupt is raised when this control block completes.
+    pub fn interrupt(mut self, enable: bool) -> Self {
+        self.set_ti(TI_INTEN, enable);
+        self
+    }
+
+    /// Sets the control block to chain to once this one completes.
+    pub fn next(mut self, next_bus_addr: u32) -> Self {
+        self.cb.nextconbk = next_bus_addr;
+        self
+    }
+
+    fn set_ti(&mut self, bit: u32, set: bool) {
+        if set {
+            self.cb.ti |= bit;
+        } else {
+            self.cb.ti &= !bit;
+        }
+    }
+
+    /// Finishes building the control block.
+    #[must_use]
+    pub fn build(self) -> ControlBlock {
+        self.cb
+    }
+}
+
+/// A chain of control blocks allocated from the DMA heap.
+#[derive(Debug)]
+struct ControlBlockChain {
+    blocks: Vec<NonNull<ControlBlock>>,
+}
+
+// the blocks are only ever touched by whoever owns the chain (and the DMA engine), and only
+// their addresses are read through a shared reference
+unsafe impl Send for ControlBlockChain {}
+unsafe impl Sync for ControlBlockChain {}
+
+impl ControlBlockChain {
+    fn new(cbs: &[ControlBlock]) -> Result<Self, Errno> {
+        let mut blocks = Vec::with_capacity(cbs.len());
+        for _ in cbs {
+            let cb = NonNull::new(dma_alloc::<ControlBlock>()).ok_or(Errno::ENOMEM)?;
+            blocks.push(cb);
+        }
+        let this = Self { blocks };
+
+        for (i, cb) in cbs.iter().enumerate() {
+            let mut cb = *cb;
+            if let Some(next) = this.blocks.get(i + 1) {
+                cb.nextconbk = Self::cb_bus_addr(*next)?;
+            }
+            unsafe { this.blocks[i].as_ptr().write_volatile(cb) };
+        }
+
+        Ok(this)
+    }
+
+    fn cb_bus_addr(cb: NonNull<ControlBlock>) -> Result<u32, Errno> {
+        bus_addr(VirtAddr::new_canonical(cb.as_ptr() as usize).as_hhdm_phys())
+    }
+
+    fn head_bus_addr(&self) -> Result<u32, Errno> {
+        Self::cb_bus_addr(*self.blocks.first().ok_or(Errno::EINVAL)?)
+    }
+}
+
+impl Drop for ControlBlockChain {
+    fn drop(&mut self) {
+        for cb in self.blocks.drain(..) {
+            dma_free(cb.as_ptr());
+        }
+    }
+}
+
+/// An allocated DMA channel. The channel is returned to the pool when dropped.
+#[derive(Debug)]
+pub struct DmaChannel {
+    index: usize,
+    regs: Mmio<u32>,
+}
+
+impl DmaChannel {
+    /// Returns the channel number.
+    #[must_use]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns `true` if this is a DMA Lite channel (reduced bandwidth, 64KiB transfers).
+    #[must_use]
+    pub fn is_lite(&self) -> bool {
+        unsafe { self.regs.read(DMA_DEBUG) & DEBUG_LITE != 0 }
+    }
+
+    /// Returns the largest transfer a single control block can describe on this channel.
+    #[must_use]
+    pub fn max_len(&self) -> usize {
+        if self.is_lite() {
+            MAX_LEN_LITE
+        } else {
+            MAX_LEN_FULL
+        }
+    }
+
+    /// Resets the channel, aborting any transfer in progress.
+    pub fn reset(&mut self) {
+        unsafe {
+            if self.regs.read(DMA_CS) & CS_ACTIVE != 0 {
+                self.regs.write(DMA_CS, CS_ABORT);
+                self.regs.spin_while_hi(DMA_CS, CS_ABORT);
+            }
+            self.regs.write(DMA_CS, CS_RESET);
+            self.regs.write(DMA_CS, CS_INT | CS_END);
+        }
+        COMPLETE[self.index].store(false, Ordering::Release);
+    }
+
+    /// Starts executing the control block chain at the given bus address.
+    unsafe fn start(&mut self, head_bus_addr: u32) {
+        COMPLETE[self.index].store(false, Ordering::Release);
+        unsafe {
+            self.regs.write(DMA_CONBLK_AD, head_bus_addr);
+            self.regs.write(
+                DMA_CS,
+                CS_ACTIVE | CS_WAIT_FOR_OUTSTANDING_WRITES | CS_PRIORITY | CS_PANIC_PRIORITY,
+            );
+        }
+    }
+
+    /// Returns `true` once the channel has finished the last control block of its chain.
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        if COMPLETE[self.index].load(Ordering::Acquire) {
+            return true;
+        }
+        // interrupts are usually masked while callers wait, so poll the hardware too
+        let cs = unsafe { self.regs.read(DMA_CS) };
+        cs & CS_ACTIVE == 0 && unsafe { self.regs.read(DMA_CONBLK_AD) } == 0
+    }
+
+    /// Returns `true` if the channel reported an error.
+    #[must_use]
+    pub fn has_error(&self) -> bool {
+        unsafe { self.regs.read(DMA_CS) & CS_ERROR != 0 }
+    }
+}
+
+impl Drop for DmaChannel {
+    fn drop(&mut self) {
+        if let Some(dma) = DMA.get() {
+            dma.lock().free_mask |= 1 << self.index;
+        }
+    }
+}
+
+/// An in-flight DMA transfer.
+///
+/// Dropping a transfer waits for it to complete, so the control blocks are never freed
+/// out from under the DMA engine.
+#[derive(Debug)]
+#[must_use = "the transfer will be waited on when dropped"]
+pub struct DmaTransfer {
+    channel: DmaChannel,
+    chain: Option<ControlBlockChain>,
+}
+
+impl DmaTransfer {
+    /// Returns `true` if the transfer has completed.
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.chain.is_none() || self.channel.is_done()
+    }
+
+    /// Waits for the transfer to complete.
+    pub fn wait(mut self) -> Result<(), Errno> {
+        self.wait_inner()
+    }
+
+    fn wait_inner(&mut self) -> Result<(), Errno> {
+        if self.chain.is_none() {
+            return Ok(());
+        }
+        crate::util::spin_while(|| !self.channel.is_done() && !self.channel.has_error());
+        let result = if self.channel.has_error() {
+            log::error!("DMA channel {} reported an error", self.channel.index);
+            Err(Errno::EIO)
+        } else {
+            Ok(())
+        };
+        self.channel.reset();
+        self.chain = None;
+        result
+    }
+}
+
+impl Drop for DmaTransfer {
+    fn drop(&mut self) {
+        self.wait_inner().ok();
+    }
+}
+
+/// The BCM2711 legacy DMA controller (`brcm,bcm2835-dma`).
+pub struct DmaController {
+    base: VirtAddr,
+    free_mask: u32,
+}
+
+impl DmaController {
+    /// Parses the DMA controller from its FDT node.
+    pub fn parse(fdt: &Fdt, node: &FdtNode) -> Result<Self, Errno> {
+        let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+            return Err(Errno::EINVAL);
+        };
+
+        let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+            return Err(Errno::EINVAL);
+        };
+
+        // channels reserved for the VPU are masked off by the firmware
+        let mask = node
+            .property("brcm,dma-channel-mask")
+            .and_then(NodeProperty::as_usize)
+            .unwrap_or(0);
+
+        let this = Self {
+            base: mmio_addr.as_hhdm_virt(),
+            free_mask: mask as u32 & ((1 << NUM_CHANNELS) - 1),
+        };
+        Ok(this)
+    }
+
+    fn channel_regs(&self, index: usize) -> Mmio<u32> {
+        Mmio::new(self.base.add_bytes(index * CHANNEL_STRIDE))
+    }
+
+    /// Allocates a free channel, preferring full channels over DMA Lite ones.
+    pub fn alloc_channel(&mut self) -> Result<DmaChannel, Errno> {
+        let is_free = |i: &usize| self.free_mask & (1 << i) != 0;
+        let is_lite = |i: usize| unsafe { self.channel_regs(i).read(DMA_DEBUG) & DEBUG_LITE != 0 };
+        let index = (0..NUM_CHANNELS)
+            .filter(is_free)
+            .find(|&i| !is_lite(i))
+            .or_else(|| (0..NUM_CHANNELS).find(is_free))
+            .ok_or(Errno::EBUSY)?;
+
+        self.free_mask &= !(1 << index);
+
+        let mut channel = DmaChannel {
+            index,
+            regs: self.channel_regs(index),
+        };
+        channel.reset();
+        Ok(channel)
+    }
+}
+
+/// Handles completion interrupts for the DMA channels wired to a single IRQ line.
+struct DmaIrqHandler {
+    base: VirtAddr,
+    channels: u32,
+}
+
+impl IrqHandler for DmaIrqHandler {
+    fn handle_irq(&mut self, _irq: Irq) {
+        let status = unsafe { Mmio::<u32>::new(self.base).read(DMA_INT_STATUS) };
+        for (index, complete) in COMPLETE.iter().enumerate() {
+            if self.channels & status & (1 << index) == 0 {
+                continue;
+            }
+            let mut regs = Mmio::<u32>::new(self.base.add_bytes(index * CHANNEL_STRIDE));
+            unsafe { regs.write(DMA_CS, CS_INT) };
+            complete.store(true, Ordering::Release);
+        }
+    }
+
+    fn is_pending(&self, _irq: Irq) -> bool {
+        let status = unsafe { Mmio::<u32>::new(self.base).read(DMA_INT_STATUS) };
+        status & self.channels != 0
+    }
+}
+
+/// Returns the index of the interrupt named `dma{channel}`, falling back to the channel number.
+fn interrupt_index(node: &FdtNode, channel: usize) -> usize {
+    let Some(names) = node.property("interrupt-names") else {
+        return channel;
+    };
+    let mut name = arrayvec::ArrayString::<8>::new();
+    if core::fmt::write(&mut name, format_args!("dma{channel}")).is_err() {
+        return channel;
+    }
+    names
+        .value
+        .split(|&b| b == 0)
+        .position(|n| n == name.as_bytes())
+        .unwrap_or(channel)
+}
+
+/// The DMA controller's driver.
+pub static DRIVER: Driver = Driver {
+    name: "dma",
+    compatible: &["brcm,bcm2835-dma"],
+    depends_on: &[],
+    probe,
+};
+
+/// Initializes the DMA controller and registers its completion interrupts.
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if DMA.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let dma = DmaController::parse(fdt, node)?;
+    log::debug!("dma @ {}, channel mask {:#x}", dma.base, dma.free_mask);
+
+    // group channels by IRQ line, since some of them share one
+    let mut lines: Vec<(Irq, u32)> = Vec::new();
+    for channel in 0..NUM_CHANNELS {
+        if dma.free_mask & (1 << channel) == 0 {
+            continue;
+        }
+        let Some(irq) = get_irq(fdt, node, interrupt_index(node, channel)) else {
+            continue;
+        };
+        match lines.iter_mut().find(|(i, _)| *i == irq) {
+            Some((_, mask)) => *mask |= 1 << channel,
+            None => lines.push((irq, 1 << channel)),
+        }
+    }
+
+    unsafe {
+        Mmio::<u32>::new(dma.base).set(DMA_ENABLE, dma.free_mask);
+    }
+
+    let base = dma.base;
+    DMA.call_once(|| IrqMutex::new(dma));
+
+    for (irq, channels) in lines {
+        log::debug!("dma channels {channels:#x} on irq {irq}");
+        unsafe { register_irq(irq, DmaIrqHandler { base, channels }) };
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if the DMA controller has been initialized.
+pub fn is_available() -> bool {
+    DMA.get().is_some()
+}
+
+/// Allocates a DMA channel.
+pub fn alloc_channel() -> Result<DmaChannel, Errno> {
+    DMA.get().ok_or(Errno::ENODEV)?.lock().alloc_channel()
+}
+
+/// Starts copying `len` bytes from `src` to `dst` using a DMA channel.
+///
+/// Both ranges must lie in the first GiB of RAM. The caller is responsible for cleaning
+/// `src` and invalidating `dst` in the data cache around the transfer.
+pub fn dma_copy(dst: PhysAddr, src: PhysAddr, len: usize) -> Result<DmaTransfer, Errno> {
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    if src.value() + len > BUS_RAM_LIMIT || dst.value() + len > BUS_RAM_LIMIT {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut channel = alloc_channel()?;
+    let max_len = channel.max_len();
+
+    let mut cbs = Vec::with_capacity(len.div_ceil(max_len));
+    let mut offset = 0;
+    while offset < len {
+        let chunk = (len - offset).min(max_len);
+        let last = offset + chunk == len;
+        cbs.push(
+            ControlBlockBuilder::new()
+                .source(src.add_bytes(offset))?
+                .dest(dst.add_bytes(offset))?
+                .len(chunk as u32)
+                .interrupt(last)
+                .build(),
+        );
+        offset += chunk;
+    }
+
+    let chain = ControlBlockChain::new(&cbs)?;
+    unsafe { channel.start(chain.head_bus_addr()?) };
+
+    Ok(DmaTransfer {
+        channel,
+        chain: Some(chain),
+    })
+}
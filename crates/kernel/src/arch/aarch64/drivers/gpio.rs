@@ -0,0 +1,291 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use fdt::{Fdt, node::FdtNode};
+use spin::Once;
+
+use crate::{
+    arch::driver::Driver,
+    fdt::get_mmio_addr,
+    irq::{Irq, IrqCell, IrqChip, IrqHandler, IrqHandlerDescriptor},
+    sync::{IrqMutex, IrqMutexGuard},
+    syscall::errno::Errno,
+};
+
+use super::mmio::Mmio;
+
+const COMPATIBLE: &[&str] = &["brcm,bcm2711-gpio", "brcm,bcm2835-gpio"];
+
+const GPFSEL0: usize = 0x00;
+const GPSET0: usize = 0x1c;
+const GPCLR0: usize = 0x28;
+const GPLEV0: usize = 0x34;
+const GPEDS0: usize = 0x40;
+const GPREN0: usize = 0x4c;
+const GPFEN0: usize = 0x58;
+const GPHEN0: usize = 0x64;
+const GPLEN0: usize = 0x70;
+
+/// The triggers a GPIO interrupt's second specifier cell can ask for.
+const TRIGGER_EDGE_RISING: u8 = 1;
+const TRIGGER_EDGE_FALLING: u8 = 2;
+const TRIGGER_LEVEL_HIGH: u8 = 4;
+const TRIGGER_LEVEL_LOW: u8 = 8;
+const GPIO_PUP_PDN_CNTRL_REG0: usize = 0xe4;
+
+/// The number of GPIO pins on the BCM2711.
+pub const NUM_PINS: u32 = 58;
+
+static GPIO: Once<IrqMutex<Gpio>> = Once::new();
+
+/// The function a GPIO pin is muxed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+/// The pull-up/pull-down resistor state of a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Pull {
+    None = 0b00,
+    Up = 0b01,
+    Down = 0b10,
+}
+
+/// The BCM2711 GPIO controller.
+pub struct Gpio {
+    regs: Mmio<u32>,
+}
+
+impl Gpio {
+    /// Parses the GPIO controller from its FDT node.
+    pub fn parse(fdt: &Fdt, node: &FdtNode) -> Result<Self, Errno> {
+        let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+            return Err(Errno::EINVAL);
+        };
+
+        let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+            return Err(Errno::EINVAL);
+        };
+
+        Ok(Self {
+            regs: Mmio::new(mmio_addr.as_hhdm_virt()),
+        })
+    }
+
+    fn check_pin(pin: u32) -> Result<(), Errno> {
+        if pin < NUM_PINS {
+            Ok(())
+        } else {
+            Err(Errno::EINVAL)
+        }
+    }
+
+    /// Muxes the given pin to the given function.
+    pub fn set_function(&mut self, pin: u32, function: Function) -> Result<(), Errno> {
+        Self::check_pin(pin)?;
+        let reg = GPFSEL0 + (pin as usize / 10) * 4;
+        let shift = (pin % 10) * 3;
+        unsafe {
+            let mut sel = self.regs.read(reg);
+            sel &= !(0b111 << shift);
+            sel |= (function as u32) << shift;
+            self.regs.write(reg, sel);
+        }
+        Ok(())
+    }
+
+    /// Configures the pull-up/pull-down resistor of the given pin.
+    pub fn set_pull(&mut self, pin: u32, pull: Pull) -> Result<(), Errno> {
+        Self::check_pin(pin)?;
+        let reg = GPIO_PUP_PDN_CNTRL_REG0 + (pin as usize / 16) * 4;
+        let shift = (pin % 16) * 2;
+        unsafe {
+            let mut value = self.regs.read(reg);
+            value &= !(0b11 << shift);
+            value |= (pull as u32) << shift;
+            self.regs.write(reg, value);
+        }
+        Ok(())
+    }
+
+    /// Drives the given output pin high or low.
+    pub fn write(&mut self, pin: u32, high: bool) -> Result<(), Errno> {
+        Self::check_pin(pin)?;
+        let bank = (pin as usize / 32) * 4;
+        let reg = if high { GPSET0 } else { GPCLR0 };
+        unsafe { self.regs.write(reg + bank, 1 << (pin % 32)) };
+        Ok(())
+    }
+
+    /// Reads the current level of the given pin.
+    pub fn read(&self, pin: u32) -> Result<bool, Errno> {
+        Self::check_pin(pin)?;
+        let bank = (pin as usize / 32) * 4;
+        Ok(unsafe { self.regs.read(GPLEV0 + bank) } & (1 << (pin % 32)) != 0)
+    }
+}
+
+/// The GPIO controller's driver.
+pub static DRIVER: Driver = Driver {
+    name: "gpio",
+    compatible: COMPATIBLE,
+    depends_on: &[],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    let gpio = Gpio::parse(fdt, node)?;
+    log::debug!("gpio @ {}", gpio.regs.addr);
+    GPIO.call_once(|| IrqMutex::new(gpio));
+    Ok(())
+}
+
+/// Locks the GPIO controller for exclusive access.
+///
+/// # Panics
+///
+/// This function will panic if the GPIO controller has not been initialized.
+pub fn gpio<'a>() -> IrqMutexGuard<'a, Gpio> {
+    GPIO.get().expect("GPIO not initialized").lock()
+}
+
+/// Locks the GPIO controller, breaking the lock if it's already held. Returns `None` if the
+/// controller hasn't been initialized.
+///
+/// # Safety
+///
+/// Whoever held the lock must never run again while the returned guard is alive, as when the
+/// kernel has panicked.
+pub unsafe fn force_gpio<'a>() -> Option<IrqMutexGuard<'a, Gpio>> {
+    let gpio = GPIO.get()?;
+    if gpio.is_locked() {
+        unsafe { gpio.force_unlock() };
+    }
+    Some(gpio.lock())
+}
+
+/// The GPIO controller's interrupts, one per pin, cascaded from the GIC.
+///
+/// The trigger of each pin comes from the device tree when its interrupt is translated, and
+/// is programmed when it's enabled.
+pub struct GpioIrqChip {
+    regs: Mmio<u32>,
+    triggers: [AtomicU8; NUM_PINS as usize],
+}
+
+impl Default for GpioIrqChip {
+    fn default() -> Self {
+        Self {
+            regs: Mmio::default(),
+            triggers: [const { AtomicU8::new(TRIGGER_LEVEL_HIGH) }; NUM_PINS as usize],
+        }
+    }
+}
+
+impl GpioIrqChip {
+    /// The event detect enable registers, and the trigger each one is for.
+    const DETECT: [(usize, u8); 4] = [
+        (GPREN0, TRIGGER_EDGE_RISING),
+        (GPFEN0, TRIGGER_EDGE_FALLING),
+        (GPHEN0, TRIGGER_LEVEL_HIGH),
+        (GPLEN0, TRIGGER_LEVEL_LOW),
+    ];
+
+    fn bank_bit(irq: Irq) -> (usize, u32) {
+        let pin = irq.as_usize();
+        ((pin / 32) * 4, 1 << (pin % 32))
+    }
+}
+
+impl IrqHandler for GpioIrqChip {
+    fn handle_irq(&mut self, _irq: Irq) {
+        log::warn!("handle_irq() called on GpioIrqChip (no-op)");
+    }
+}
+
+impl IrqChip for GpioIrqChip {
+    fn init(&mut self, fdt: &Fdt, descs: &mut [IrqHandlerDescriptor]) {
+        let node = fdt.find_compatible(COMPATIBLE).unwrap();
+        self.regs = Gpio::parse(fdt, &node).unwrap().regs;
+
+        for bank in [0, 4] {
+            unsafe {
+                for (reg, _) in Self::DETECT {
+                    self.regs.write(reg + bank, 0);
+                }
+                self.regs.write(GPEDS0 + bank, u32::MAX);
+            }
+        }
+
+        for (i, desc) in descs.iter_mut().enumerate().take(NUM_PINS as usize) {
+            desc.chip_irq = Irq::from(i as u32);
+            desc.used = true;
+        }
+    }
+
+    fn ack(&mut self) -> Irq {
+        for bank in [0, 4] {
+            let enabled = Self::DETECT.iter().fold(0, |acc, &(reg, _)| {
+                acc | unsafe { self.regs.read(reg + bank) }
+            });
+            let pending = unsafe { self.regs.read(GPEDS0 + bank) } & enabled;
+            if pending != 0 {
+                return Irq::from(bank as u32 * 8 + pending.trailing_zeros());
+            }
+        }
+        Irq::SPURIOUS
+    }
+
+    fn eoi(&mut self, irq: Irq) {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe { self.regs.write(GPEDS0 + bank, bit) };
+    }
+
+    fn translate_irq(&self, irq_data: IrqCell) -> Option<Irq> {
+        let IrqCell::L2(pin, flags) = irq_data else {
+            return None;
+        };
+        Gpio::check_pin(pin).ok()?;
+        self.triggers[pin as usize].store(flags as u8 & 0xf, Ordering::Relaxed);
+        Some(Irq::from(pin))
+    }
+
+    fn enable_irq(&mut self, irq: Irq) {
+        let (bank, bit) = Self::bank_bit(irq);
+        let flags = self.triggers[irq.as_usize()].load(Ordering::Relaxed);
+        unsafe {
+            for (reg, trigger) in Self::DETECT {
+                if flags & trigger != 0 {
+                    self.regs.set(reg + bank, bit);
+                }
+            }
+        }
+    }
+
+    fn disable_irq(&mut self, irq: Irq) {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe {
+            for (reg, _) in Self::DETECT {
+                self.regs.clear(reg + bank, bit);
+            }
+        }
+    }
+
+    fn manual_irq(&mut self, irq: Irq) {
+        log::warn!("GPIO can't trigger IRQ {irq} in software");
+    }
+
+    fn is_irq_pending(&self, irq: Irq) -> bool {
+        let (bank, bit) = Self::bank_bit(irq);
+        unsafe { self.regs.read(GPEDS0 + bank) & bit != 0 }
+    }
+}
@@ -2,34 +2,47 @@ use core::{arch::asm, fmt::Debug};
 
 use bitflags::bitflags;
 use derive_more::{Deref, DerefMut, TryFrom};
-use fdt::Fdt;
+use fdt::{Fdt, node::FdtNode};
+use spin::Once;
 use thiserror::Error;
 
 use crate::{
-    arch::{Architecture, clean_data_cache, invalidate_data_cache},
+    arch::{Architecture, clean_data_cache, driver::Driver},
     fdt::{Phandle, get_mmio_addr},
     framebuffer::FramebufferInfo,
     mem::{
-        paging::table::{PageFlags, PageTable, TableKind},
-        units::{PhysAddr, VirtAddr},
+        paging::{
+            allocator::KernelFrameAllocator,
+            table::{PageFlags, PageTable, TableKind},
+        },
+        units::{FrameCount, PhysAddr},
     },
+    sync::IrqMutex,
     syscall::errno::Errno,
     util::{DebugCheckedPanic, DebugPanic},
 };
 
 use crate::arch::Arch;
 use props::{
-    AllocateBuffer, GetDepth, GetFirmwareRevision, GetPhysicalSize, GetPitch, SetDepth,
-    SetPhysicalSize, SetPixelOrder, SetVirtualSize,
+    AllocateBuffer, BlankScreen, GetDepth, GetEdidBlock, GetFirmwareRevision, GetPhysicalSize,
+    GetPitch, SetCursorInfo, SetCursorState, SetDepth, SetPhysicalSize, SetPixelOrder,
+    SetVirtualOffset, SetVirtualSize,
 };
 
-use super::{dma_alloc, dma_free};
+use super::{
+    dma_alloc, dma_free,
+    mmio::{Register, RegisterBlock},
+};
 
 pub mod props;
+pub mod vsync;
 
-// from config.txt
+/// The framebuffer width used if the firmware doesn't report the display's current mode.
 pub const FRAMEBUFFER_WIDTH: usize = 1280;
+/// The framebuffer height used if the firmware doesn't report the display's current mode.
 pub const FRAMEBUFFER_HEIGHT: usize = 720;
+/// The largest hardware cursor image the firmware takes, in pixels on a side.
+pub const MAX_CURSOR_SIZE: usize = 64;
 
 bitflags! {
     pub struct MailboxStatus: u32 {
@@ -233,20 +246,16 @@ impl Drop for MailboxResponse {
 #[derive(Debug)]
 pub struct Mailbox {
     pub phandle: Phandle,
-    pub base: VirtAddr,
+    pub regs: RegisterBlock,
 }
 
 impl Mailbox {
-    const READ: usize = 0x00;
-    const STATUS: usize = 0x18;
-    const WRITE: usize = 0x20;
-
-    /// Parses the mailbox from the FDT.
-    pub fn parse(fdt: &Fdt) -> Result<Self, Errno> {
-        let Some(mbox) = fdt.find_compatible(&["brcm,bcm2835-mbox"]) else {
-            return Err(Errno::EINVAL);
-        };
+    const READ: Register<u32> = Register::new(0x00);
+    const STATUS: Register<u32> = Register::new(0x18);
+    const WRITE: Register<u32> = Register::new(0x20);
 
+    /// Parses the mailbox from its FDT node.
+    pub fn parse(fdt: &Fdt, mbox: &FdtNode) -> Result<Self, Errno> {
         let Some(phandle) = mbox.property("phandle") else {
             return Err(Errno::EINVAL);
         };
@@ -269,7 +278,7 @@ impl Mailbox {
 
         Ok(Self {
             phandle: Phandle::new(phandle),
-            base: mmio_addr.as_hhdm_virt(),
+            regs: RegisterBlock::new(mmio_addr.as_hhdm_virt()),
         })
     }
 
@@ -280,9 +289,7 @@ impl Mailbox {
     /// This function will panic if the read operation fails.
     #[must_use]
     pub fn status(&self) -> MailboxStatus {
-        MailboxStatus::from_bits_truncate(unsafe {
-            self.base.add_bytes(Self::STATUS).read_volatile().unwrap()
-        })
+        MailboxStatus::from_bits_truncate(unsafe { self.regs.read(Self::STATUS) })
     }
 
     /// Calls the mailbox with a request and channel, returning the response.
@@ -310,29 +317,22 @@ impl Mailbox {
             return Err(MailboxError);
         };
 
-        unsafe {
-            asm!("dsb ishst");
-            clean_data_cache(buf.cast(), (*buf).buffer_size() as usize * size_of::<u32>());
-            asm!("dsb ish; isb");
-        }
+        // the buffer comes from the non-cacheable DMA heap, so the firmware sees it as soon as
+        // the writes to it have completed
+        unsafe { asm!("dsb sy") };
 
         // send it along
         while self.status().contains(MailboxStatus::MAILBOX_FULL) {
             core::hint::spin_loop();
         }
-        unsafe {
-            self.base
-                .add_bytes(Self::WRITE)
-                .write_volatile(message.raw())
-                .unwrap();
-        };
+        unsafe { self.regs.write(Self::WRITE, message.raw()) };
 
         // wait for response
         let resp = loop {
             while self.status().contains(MailboxStatus::MAILBOX_EMPTY) {
                 core::hint::spin_loop();
             }
-            let resp = unsafe { self.base.add_bytes(Self::READ).read_volatile().unwrap() };
+            let resp = unsafe { self.regs.read(Self::READ) };
             let resp = MailboxMessage::from_raw(resp);
             if resp.channel() == message.channel() && resp.payload() == message.payload() {
                 break resp;
@@ -341,10 +341,7 @@ impl Mailbox {
 
         let buf = resp.decode();
 
-        unsafe {
-            asm!("dsb ish; isb");
-            invalidate_data_cache(buf.cast(), (*buf).buffer_size() as usize * size_of::<u32>());
-        }
+        unsafe { asm!("dsb sy") };
 
         let code = unsafe { (*buf).request_code() };
         let response = MailboxResponse { buf };
@@ -357,25 +354,211 @@ impl Mailbox {
     }
 }
 
-/// Initializes the GPU framebuffer.
+static MAILBOX: Once<IrqMutex<Mailbox>> = Once::new();
+
+/// Runs `f` with the mailbox locked and interrupts disabled.
+///
+/// Requests should be built and their responses dropped inside `f`, so the message buffers
+/// only ever come from (and go back to) the DMA heap with interrupts disabled.
+pub(super) fn with_mailbox<R>(f: impl FnOnce(&mut Mailbox) -> Result<R, Errno>) -> Result<R, Errno> {
+    MAILBOX.get().ok_or(Errno::ENODEV)?.lock_irqsave(f)
+}
+
+/// Returns the resolution of the attached display's preferred mode, read from its EDID.
+///
+/// Returns `ENODEV` if the firmware can't read the EDID (for example, if no display is
+/// attached or we're running under an emulator).
+pub fn native_resolution() -> Result<(usize, usize), Errno> {
+    let edid = with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(GetEdidBlock { block: 0 });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        response.decode::<GetEdidBlock>().ok_or(Errno::EIO)
+    })?;
+    if edid.status != 0 || edid.data[..8] != EDID_HEADER {
+        return Err(Errno::ENODEV);
+    }
+
+    // the first detailed timing descriptor is the preferred mode
+    let dtd = &edid.data[54..72];
+    let width = usize::from(dtd[2]) | (usize::from(dtd[4] & 0xf0) << 4);
+    let height = usize::from(dtd[5]) | (usize::from(dtd[7] & 0xf0) << 4);
+    if width == 0 || height == 0 {
+        return Err(Errno::ENODEV);
+    }
+    Ok((width, height))
+}
+
+/// Asks the firmware to blank the display, letting it power down, or to bring it back.
+pub fn set_display_blanked(blanked: bool) -> Result<(), Errno> {
+    with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(BlankScreen {
+            state: u32::from(blanked),
+        });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        response.decode::<BlankScreen>().ok_or(Errno::EIO)?;
+        Ok(())
+    })
+}
+
+/// Scans the display out from `(x, y)` in the framebuffer's virtual size, which is how a
+/// framebuffer with several buffers flips between them. The firmware switches over at the
+/// next vertical sync.
+pub fn set_virtual_offset(x: usize, y: usize) -> Result<(), Errno> {
+    let x = u32::try_from(x).map_err(|_| Errno::EINVAL)?;
+    let y = u32::try_from(y).map_err(|_| Errno::EINVAL)?;
+    with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(SetVirtualOffset { x, y });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        let offset = response.decode::<SetVirtualOffset>().ok_or(Errno::EIO)?;
+        if offset.x != x || offset.y != y {
+            return Err(Errno::EINVAL);
+        }
+        Ok(())
+    })
+}
+
+/// The frames holding the hardware cursor's image, which the firmware reads from for as long
+/// as it's shown.
+static CURSOR_IMAGE: IrqMutex<Option<(PhysAddr, FrameCount)>> = IrqMutex::new(None);
+
+/// Sets the hardware cursor's image to `pixels`, `width` x `height` of them in ARGB, with
+/// its hotspot `hotspot` pixels from the top left. Its position is set with
+/// [`set_cursor_state`].
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if the image is empty, bigger than [`MAX_CURSOR_SIZE`] on a
+/// side, or isn't `width * height` pixels, or if the firmware rejects it.
+pub fn set_cursor_image(
+    pixels: &[u32],
+    width: usize,
+    height: usize,
+    hotspot: (usize, usize),
+) -> Result<(), Errno> {
+    if !(1..=MAX_CURSOR_SIZE).contains(&width)
+        || !(1..=MAX_CURSOR_SIZE).contains(&height)
+        || pixels.len() != width * height
+        || hotspot.0 >= width
+        || hotspot.1 >= height
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    let pages = FrameCount::from_bytes(size_of_val(pixels));
+    let phys = unsafe { KernelFrameAllocator.allocate(pages) }.map_err(|_| Errno::ENOMEM)?;
+    let image = phys.as_hhdm_virt();
+    unsafe {
+        core::ptr::copy_nonoverlapping(pixels.as_ptr(), image.as_raw_ptr_mut(), pixels.len());
+        clean_data_cache(image.as_raw_ptr(), size_of_val(pixels));
+    }
+
+    let result = with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(SetCursorInfo {
+            width: width as u32,
+            height: height as u32,
+            unused: 0,
+            pixels: u32::try_from(phys.value()).map_err(|_| Errno::EINVAL)?,
+            hotspot_x: hotspot.0 as u32,
+            hotspot_y: hotspot.1 as u32,
+        });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        match response.decode::<SetCursorInfo>() {
+            Some(info) if info.status == 0 => Ok(()),
+            Some(_) => Err(Errno::EINVAL),
+            None => Err(Errno::EIO),
+        }
+    });
+
+    // whichever image the firmware isn't using any more can go
+    let unused = match result {
+        Ok(()) => CURSOR_IMAGE.lock().replace((phys, pages)),
+        Err(_) => Some((phys, pages)),
+    };
+    if let Some((phys, pages)) = unused {
+        KernelFrameAllocator.free(phys, pages).ok();
+    }
+    result
+}
+
+/// Shows the hardware cursor with its hotspot at `(x, y)` in the framebuffer, or hides it.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if the firmware rejects the position, which it does if no
+/// image has been set with [`set_cursor_image`].
+pub fn set_cursor_state(visible: bool, x: usize, y: usize) -> Result<(), Errno> {
+    let x = u32::try_from(x).map_err(|_| Errno::EINVAL)?;
+    let y = u32::try_from(y).map_err(|_| Errno::EINVAL)?;
+    with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(SetCursorState {
+            enable: u32::from(visible),
+            x,
+            y,
+            flags: 1, // framebuffer coordinates rather than display ones
+        });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        match response.decode::<SetCursorState>() {
+            Some(state) if state.status == 0 => Ok(()),
+            Some(_) => Err(Errno::EINVAL),
+            None => Err(Errno::EIO),
+        }
+    })
+}
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// Asks the firmware for a new framebuffer at the given resolution and maps it.
+///
+/// The firmware reallocates the buffer, so the previous one must not be touched again
+/// once this succeeds. The new buffer may not be at the exact resolution requested, and
+/// has two buffers to flip between only if the firmware allows it; the returned
+/// [`FramebufferInfo`] describes what was actually allocated.
 ///
-/// # Panics
+/// # Safety
 ///
-/// This function will panic if the mailbox call fails or if the framebuffer cannot be initialized.
-pub fn init(fdt: &Fdt) {
-    let mut mbox = Mailbox::parse(fdt).unwrap();
-    log::debug!("mailbox @ {}", mbox.base);
+/// Nothing may access the previous framebuffer during or after this call.
+pub unsafe fn set_mode(width: usize, height: usize) -> Result<FramebufferInfo, Errno> {
+    with_mailbox(|mbox| allocate_framebuffer(mbox, width, height))
+}
+
+/// Allocates a framebuffer with two buffers to flip between, falling back to one.
+fn allocate_framebuffer(
+    mbox: &mut Mailbox,
+    width: usize,
+    height: usize,
+) -> Result<FramebufferInfo, Errno> {
+    allocate_buffers(mbox, width, height, 2).or_else(|e| {
+        log::debug!("no room for a second framebuffer ({e:?}), so it won't flip");
+        allocate_buffers(mbox, width, height, 1)
+    })
+}
+
+/// Allocates a framebuffer with `buffers` screens stacked in its virtual size.
+fn allocate_buffers(
+    mbox: &mut Mailbox,
+    width: usize,
+    height: usize,
+    buffers: usize,
+) -> Result<FramebufferInfo, Errno> {
+    let virtual_height = u32::try_from(height * buffers).map_err(|_| Errno::EINVAL)?;
+    let width = u32::try_from(width).map_err(|_| Errno::EINVAL)?;
+    let height = u32::try_from(height).map_err(|_| Errno::EINVAL)?;
+    if width == 0 || height == 0 {
+        return Err(Errno::EINVAL);
+    }
 
     let request = MailboxRequest::new()
-        .encode(GetFirmwareRevision {})
-        .encode(SetPhysicalSize {
-            width: FRAMEBUFFER_WIDTH as u32,
-            height: FRAMEBUFFER_HEIGHT as u32,
-        })
+        .encode(SetPhysicalSize { width, height })
         .encode(SetVirtualSize {
-            width: FRAMEBUFFER_WIDTH as u32,
-            height: FRAMEBUFFER_HEIGHT as u32,
+            width,
+            height: virtual_height,
         })
+        .encode(SetVirtualOffset { x: 0, y: 0 })
         .encode(SetPixelOrder { order: 0x0 }) // BGR
         .encode(SetDepth { bpp: 32 })
         .encode(AllocateBuffer { align: 0 })
@@ -383,42 +566,100 @@ pub fn init(fdt: &Fdt) {
         .encode(GetPhysicalSize {})
         .encode(GetDepth {});
 
-    let response = unsafe { mbox.call(request, MailboxChannel::TagsArmToVc).unwrap() };
-    let rev = response.decode::<GetFirmwareRevision>().unwrap();
-    log::debug!("firmware revision: {:#x}", rev.revision);
-    let buffer = response.decode::<AllocateBuffer>().unwrap();
+    let response =
+        unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+    let buffer = response.decode::<AllocateBuffer>().ok_or(Errno::EIO)?;
+    let phys_size = response.decode::<GetPhysicalSize>().ok_or(Errno::EIO)?;
+    let virtual_size = response.decode::<SetVirtualSize>().ok_or(Errno::EIO)?;
+    let pitch = response.decode::<GetPitch>().ok_or(Errno::EIO)?;
+    let depth = response.decode::<GetDepth>().ok_or(Errno::EIO)?;
+    if buffer.size == 0 {
+        return Err(Errno::ENOMEM);
+    }
+    // each buffer is a whole screen, one below the other
+    let buffer_size = pitch.pitch as usize * phys_size.height as usize;
+    if (virtual_size.height as usize) < phys_size.height as usize * buffers
+        || (buffer.size as usize) < buffer_size * buffers
+    {
+        return Err(Errno::ENOMEM);
+    }
+
     let base_addr = buffer.bus_addr & 0x3FFF_FFFF;
     log::debug!(
         "buffer: 0x{:016x} .. 0x{:016x}",
         base_addr,
         base_addr + buffer.size
     );
-    let phys_size = response.decode::<GetPhysicalSize>().unwrap();
     log::debug!("physical size = {}x{}", phys_size.width, phys_size.height);
-    let pitch = response.decode::<GetPitch>().unwrap();
+    log::debug!(
+        "virtual size = {}x{}",
+        virtual_size.width,
+        virtual_size.height
+    );
     log::debug!("pitch = {}", pitch.pitch);
-    let depth = response.decode::<GetDepth>().unwrap();
     log::debug!("depth = {}", depth.depth);
 
-    // map the framebuffer
+    // map the framebuffer, replacing whatever an earlier mode left there
     let mut mapper = PageTable::current(TableKind::Kernel);
     let frame = PhysAddr::new_canonical(base_addr as usize);
     let page = frame.as_hhdm_virt();
     let flush = mapper
-        .kernel_map_range(
+        .kernel_remap_range(
             page,
             frame,
             buffer.size as usize,
             PageFlags::new().writable(),
         )
-        .unwrap();
+        .map_err(|_| Errno::ENOMEM)?;
     flush.flush();
 
-    crate::framebuffer::FRAMEBUFFER_INFO.call_once(|| FramebufferInfo {
+    Ok(FramebufferInfo {
         start_addr: page,
-        size_bytes: buffer.size as usize,
+        size_bytes: buffer_size,
+        buffers,
         width: phys_size.width as usize,
         height: phys_size.height as usize,
         bpp: depth.depth as usize,
-    });
+    })
+}
+
+/// The mailbox's driver, which also sets up the framebuffer through it.
+pub static DRIVER: Driver = Driver {
+    name: "mailbox",
+    compatible: &["brcm,bcm2835-mbox"],
+    depends_on: &[],
+    probe,
+};
+
+/// Initializes the mailbox and the GPU framebuffer.
+///
+/// The framebuffer keeps the mode the firmware brought the display up in (from
+/// `config.txt`), falling back to [`FRAMEBUFFER_WIDTH`]x[`FRAMEBUFFER_HEIGHT`] if it
+/// doesn't report one.
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if MAILBOX.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let mut mbox = Mailbox::parse(fdt, node)?;
+    log::debug!("mailbox @ {}", mbox.regs.addr);
+
+    let request = MailboxRequest::new()
+        .encode(GetFirmwareRevision {})
+        .encode(GetPhysicalSize {});
+    let response =
+        unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+    let rev = response.decode::<GetFirmwareRevision>().ok_or(Errno::EIO)?;
+    log::debug!("firmware revision: {:#x}", rev.revision);
+    let (width, height) = match response.decode::<GetPhysicalSize>() {
+        Some(size) if size.width != 0 && size.height != 0 => {
+            (size.width as usize, size.height as usize)
+        }
+        _ => (FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT),
+    };
+    drop(response);
+
+    let info = allocate_framebuffer(&mut mbox, width, height)?;
+    MAILBOX.call_once(|| IrqMutex::new(mbox));
+    crate::framebuffer::FRAMEBUFFER_INFO.call_once(|| info);
+    Ok(())
 }
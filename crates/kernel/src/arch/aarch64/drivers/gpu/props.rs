@@ -86,6 +86,43 @@ prop!(0x48004 {
     }
 });
 
+prop!(0x48009 {
+    pub request SetVirtualOffset {
+        pub x,
+        pub y,
+    }
+    pub response SetVirtualOffsetResponse {
+        pub x,
+        pub y,
+    }
+});
+
+prop!(0x8010 {
+    pub request SetCursorInfo {
+        pub width,
+        pub height,
+        pub unused,
+        pub pixels,
+        pub hotspot_x,
+        pub hotspot_y,
+    }
+    pub response SetCursorInfoResponse {
+        pub status,
+    }
+});
+
+prop!(0x8011 {
+    pub request SetCursorState {
+        pub enable,
+        pub x,
+        pub y,
+        pub flags,
+    }
+    pub response SetCursorStateResponse {
+        pub status,
+    }
+});
+
 prop!(0x48005 {
     pub request SetDepth {
         pub bpp,
@@ -95,6 +132,15 @@ prop!(0x48005 {
     }
 });
 
+prop!(0x40002 {
+    pub request BlankScreen {
+        pub state,
+    }
+    pub response BlankScreenResponse {
+        pub state,
+    }
+});
+
 prop!(0x40008 {
     pub request GetPitch {}
     pub response GetPitchResponse {
@@ -126,6 +172,38 @@ prop!(0x48006 {
     }
 });
 
+prop!(0x30001 {
+    pub request GetClockState {
+        pub clock_id,
+    }
+    pub response GetClockStateResponse {
+        pub clock_id,
+        pub state,
+    }
+});
+
+prop!(0x30002 {
+    pub request GetClockRate {
+        pub clock_id,
+    }
+    pub response GetClockRateResponse {
+        pub clock_id,
+        pub rate,
+    }
+});
+
+prop!(0x38002 {
+    pub request SetClockRate {
+        pub clock_id,
+        pub rate,
+        pub skip_turbo,
+    }
+    pub response SetClockRateResponse {
+        pub clock_id,
+        pub rate,
+    }
+});
+
 prop!(0x38001 {
     pub request SetClockState {
         pub clock_id,
@@ -157,3 +235,62 @@ prop!(0x28001 {
         pub state,
     }
 });
+
+prop!(0x30087 {
+    pub request GetRtcReg {
+        pub reg,
+    }
+    pub response GetRtcRegResponse {
+        pub reg,
+        pub value,
+    }
+});
+
+prop!(0x38041 {
+    pub request SetGpioState {
+        pub gpio,
+        pub state,
+    }
+    pub response SetGpioStateResponse {
+        pub gpio,
+        pub status,
+    }
+});
+
+/// Reads a 128-byte block of the attached display's EDID.
+#[derive(Clone, Debug, Default)]
+#[repr(C)]
+pub struct GetEdidBlock {
+    pub block: u32,
+}
+
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct GetEdidBlockResponse {
+    pub block: u32,
+    /// Zero on success.
+    pub status: u32,
+    pub data: [u8; 128],
+}
+
+impl MailboxProperty for GetEdidBlock {
+    const TAG: u32 = 0x30020;
+    type Response = GetEdidBlockResponse;
+
+    fn encode_request(self, request: MailboxRequest) -> MailboxRequest {
+        request.int(self.block)
+    }
+
+    fn decode_response(response: &[u32]) -> Option<GetEdidBlockResponse> {
+        let (&[block, status], words) = response.split_first_chunk::<2>()?;
+        let mut data = [0; 128];
+        for (chunk, word) in data.as_chunks_mut::<4>().0.iter_mut().zip(words) {
+            *chunk = word.to_le_bytes();
+        }
+        Some(GetEdidBlockResponse {
+            block,
+            status,
+            data,
+        })
+    }
+}
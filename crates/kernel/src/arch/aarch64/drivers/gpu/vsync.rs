@@ -0,0 +1,117 @@
+//! Vertical sync interrupts, taken from the Secondary Memory Interface (SMI).
+//!
+//! The firmware raises the SMI's interrupt at the start of every frame, which is how the
+//! Raspberry Pi's own framebuffer driver waits for vertical sync. The SMI is otherwise left
+//! alone. Its node is often disabled in the device tree, in which case there's no telling
+//! when a frame starts.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fdt::{Fdt, node::FdtNode};
+use spin::Once;
+
+use crate::{
+    arch::{
+        driver::Driver,
+        drivers::mmio::{Register, RegisterBlock, map_device},
+    },
+    fdt::get_mmio_addr,
+    irq::{Irq, IrqHandler, get_irq, register_irq},
+    mem::units::VirtAddr,
+    sync::{IrqMutex, WaitQueue},
+    syscall::errno::Errno,
+};
+
+/// The SMI control and status register.
+const SMICS: Register<u32> = Register::new(0x00);
+/// The SMI's interrupt flags in [`SMICS`].
+const SMICS_INTERRUPTS: u32 = (1 << 9) | (1 << 10) | (1 << 11);
+const REGS_SIZE: usize = 0x1000;
+
+static BASE: Once<VirtAddr> = Once::new();
+/// How many vertical syncs there have been since the interrupt was registered.
+static COUNT: AtomicU64 = AtomicU64::new(0);
+static WAITERS: WaitQueue = WaitQueue::new();
+static CALLBACK: IrqMutex<Option<fn()>> = IrqMutex::new(None);
+
+struct VsyncIrqHandler {
+    base: VirtAddr,
+}
+
+impl IrqHandler for VsyncIrqHandler {
+    fn handle_irq(&mut self, _irq: Irq) {
+        let mut regs = RegisterBlock::new(self.base);
+        unsafe { regs.clear(SMICS, SMICS_INTERRUPTS) };
+
+        COUNT.fetch_add(1, Ordering::Release);
+        WAITERS.wake_all();
+        let callback = *CALLBACK.lock();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    fn is_pending(&self, _irq: Irq) -> bool {
+        let regs = RegisterBlock::new(self.base);
+        unsafe { regs.read(SMICS) & SMICS_INTERRUPTS != 0 }
+    }
+}
+
+/// The driver for the SMI, which only takes its vertical sync interrupts.
+pub static DRIVER: Driver = Driver {
+    name: "vsync",
+    compatible: &["brcm,bcm2835-smi"],
+    depends_on: &[],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if BASE.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+        return Err(Errno::EINVAL);
+    };
+    let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+        return Err(Errno::EINVAL);
+    };
+    let irq = get_irq(fdt, node, 0).ok_or(Errno::ENODEV)?;
+    let base = map_device(mmio_addr, REGS_SIZE).map_err(|_| Errno::ENOMEM)?;
+    log::debug!("smi @ {base}, vsync on irq {irq}");
+
+    // whatever was raised before now isn't a frame we can count
+    unsafe { RegisterBlock::new(base).clear(SMICS, SMICS_INTERRUPTS) };
+    BASE.call_once(|| base);
+    unsafe { register_irq(irq, VsyncIrqHandler { base }) };
+    Ok(())
+}
+
+/// Returns `true` if vertical sync interrupts are being taken.
+pub fn is_available() -> bool {
+    BASE.is_completed()
+}
+
+/// Returns how many vertical syncs there have been. It only changes if [`is_available`].
+pub fn count() -> u64 {
+    COUNT.load(Ordering::Acquire)
+}
+
+/// Sleeps until the next vertical sync, returning the new [`count`].
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if vertical sync interrupts aren't available.
+pub fn wait() -> Result<u64, Errno> {
+    if !is_available() {
+        return Err(Errno::ENODEV);
+    }
+    let start = count();
+    WAITERS.wait_until(|| count() != start);
+    Ok(count())
+}
+
+/// Sets a function to call from the interrupt handler on every vertical sync, replacing the
+/// last one. It mustn't sleep.
+pub fn set_callback(callback: Option<fn()>) {
+    *CALLBACK.lock() = callback;
+}
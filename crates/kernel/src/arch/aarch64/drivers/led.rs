@@ -0,0 +1,233 @@
+//! The activity LED, blinked from the timer tick to show the kernel is alive.
+//!
+//! The LED is the `gpio-leds` child labelled `ACT`. On the Pi 4 it's on one of the chip's own GPIO
+//! pins, which is written straight from the timer callback. Boards that have it on the firmware's GPIO
+//! expander instead are driven through the mailbox, from a worker task, and only when the
+//! LED changes.
+//!
+//! Each second is split into [`STEPS`] steps, and a [`Pattern`] is which of them the LED is lit
+//! for.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use fdt::{
+    Fdt,
+    node::{FdtNode, NodeProperty},
+};
+use spin::Once;
+
+use crate::{
+    arch::{driver::Driver, time::spin_for},
+    syscall::errno::Errno,
+    task::workqueue::{Work, schedule_work},
+    timer,
+};
+
+use super::{
+    gpio::{self, Function},
+    gpu::{
+        MailboxChannel, MailboxRequest,
+        props::{SetGpioState, SetGpioStateResponse},
+        with_mailbox,
+    },
+};
+
+/// How many steps a pattern has, each lasting [`STEP`].
+const STEPS: usize = 20;
+const STEP: Duration = Duration::from_millis(50);
+
+/// The compatibles of the chip's own GPIO controller.
+const SOC_GPIO: &[&str] = &["brcm,bcm2711-gpio", "brcm,bcm2835-gpio"];
+/// The compatible of the firmware's GPIO expander.
+const FIRMWARE_GPIO: &str = "raspberrypi,firmware-gpio";
+/// Where the firmware's numbering of the expander's lines starts.
+const FIRMWARE_GPIO_BASE: u32 = 128;
+/// Set in a `gpios` specifier's flags if the line is active low.
+const GPIO_ACTIVE_LOW: u32 = 1 << 0;
+
+static LED: Once<Led> = Once::new();
+static PATTERN: AtomicU8 = AtomicU8::new(Pattern::Heartbeat as u8);
+
+/// What the activity LED is showing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Pattern {
+    /// Two short blinks a second, while all is well.
+    Heartbeat = 0,
+    /// Fast blinking, after a panic.
+    Panic = 1,
+    /// Half a second on, half a second off, after a kernel test failed.
+    TestFailure = 2,
+}
+
+impl Pattern {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            1 => Self::Panic,
+            2 => Self::TestFailure,
+            _ => Self::Heartbeat,
+        }
+    }
+
+    /// The steps the LED is lit for, bit `n` for step `n`.
+    const fn steps(self) -> u32 {
+        match self {
+            Self::Heartbeat => 0b11_0011,
+            Self::Panic => 0b0011_0011_0011_0011_0011,
+            Self::TestFailure => 0b11_1111_1111,
+        }
+    }
+
+    fn is_lit(self, step: usize) -> bool {
+        self.steps() & (1 << step) != 0
+    }
+}
+
+/// A GPIO line the LED can be on.
+#[derive(Debug, Clone, Copy)]
+enum Line {
+    /// A pin of the chip's own GPIO controller.
+    Soc(u32),
+    /// A line of the firmware's expander, by the firmware's numbering.
+    Firmware(u32),
+}
+
+#[derive(Debug)]
+struct Led {
+    line: Line,
+    active_low: bool,
+    /// Whether the LED was last lit, so the firmware is only asked to change it when it
+    /// changes.
+    lit: AtomicBool,
+}
+
+impl Led {
+    fn parse(fdt: &Fdt, node: &FdtNode) -> Result<Self, Errno> {
+        let gpios = node.property("gpios").ok_or(Errno::ENODEV)?.value;
+        let mut cells = gpios
+            .as_chunks::<4>()
+            .0
+            .iter()
+            .map(|&cell| u32::from_be_bytes(cell));
+        let (Some(phandle), Some(pin), Some(flags)) = (cells.next(), cells.next(), cells.next())
+        else {
+            return Err(Errno::EINVAL);
+        };
+
+        let controller = fdt.find_phandle(phandle).ok_or(Errno::ENODEV)?;
+        let compatible = controller.compatible().ok_or(Errno::ENODEV)?;
+        let line = if compatible.all().any(|c| SOC_GPIO.contains(&c)) {
+            Line::Soc(pin)
+        } else if compatible.all().any(|c| c == FIRMWARE_GPIO) {
+            Line::Firmware(FIRMWARE_GPIO_BASE + pin)
+        } else {
+            return Err(Errno::ENODEV);
+        };
+
+        Ok(Self {
+            line,
+            active_low: flags & GPIO_ACTIVE_LOW != 0,
+            lit: AtomicBool::new(false),
+        })
+    }
+
+    fn set(&self, lit: bool) {
+        let level = lit != self.active_low;
+        match self.line {
+            Line::Soc(pin) => {
+                gpio::gpio().write(pin, level).ok();
+            }
+            Line::Firmware(line) => {
+                if self.lit.swap(lit, Ordering::Relaxed) != lit {
+                    let arg = (line as usize) << 1 | usize::from(level);
+                    schedule_work(Work::new(set_firmware_line, arg)).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Sets a line of the firmware's expander, packed as its number above its level.
+fn set_firmware_line(arg: usize) {
+    let property = SetGpioState {
+        gpio: (arg >> 1) as u32,
+        state: (arg & 1) as u32,
+    };
+    let result = with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(property);
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        response.decode::<SetGpioState>().ok_or(Errno::EIO)
+    });
+    match result {
+        Ok(SetGpioStateResponse { status: 0, .. }) => {}
+        Ok(SetGpioStateResponse { status, .. }) => {
+            log::debug!("led: firmware refused to set GPIO {}: {status}", arg >> 1);
+        }
+        Err(e) => log::debug!("led: can't set GPIO {}: {e:?}", arg >> 1),
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "leds",
+    compatible: &["gpio-leds"],
+    depends_on: &["gpio", "mailbox"],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if LED.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let act = node
+        .children()
+        .find(|child| {
+            let label = child.property("label").and_then(NodeProperty::as_str);
+            matches!(child.name, "led-act" | "act") || matches!(label, Some("ACT" | "led0"))
+        })
+        .ok_or(Errno::ENODEV)?;
+    let led = Led::parse(fdt, &act)?;
+    if let Line::Soc(pin) = led.line {
+        gpio::gpio().set_function(pin, Function::Output)?;
+    }
+    log::debug!("led: activity LED on {:?}", led.line);
+    LED.call_once(|| led);
+
+    let mut step = 0;
+    timer::periodic(STEP, move || {
+        let Some(led) = LED.get() else { return };
+        led.set(Pattern::from_raw(PATTERN.load(Ordering::Relaxed)).is_lit(step));
+        step = (step + 1) % STEPS;
+    });
+    Ok(())
+}
+
+/// Switches the activity LED to `pattern`, from the next step on.
+pub fn set_pattern(pattern: Pattern) {
+    PATTERN.store(pattern as u8, Ordering::Relaxed);
+}
+
+/// Blinks the panic pattern on the activity LED forever, if it's on one of the chip's pins. Returns
+/// otherwise, since nothing will run the mailbox calls a firmware line needs.
+///
+/// # Safety
+///
+/// Nothing else may run again, since this takes the GPIO controller from whoever had it.
+pub unsafe fn blink_panic() {
+    let Some(led) = LED.get() else { return };
+    let Line::Soc(pin) = led.line else { return };
+    set_pattern(Pattern::Panic);
+    let Some(mut gpio) = (unsafe { gpio::force_gpio() }) else {
+        return;
+    };
+    loop {
+        for step in 0..STEPS {
+            let lit = Pattern::Panic.is_lit(step);
+            gpio.write(pin, lit != led.active_low).ok();
+            spin_for(STEP);
+        }
+    }
+}
@@ -1,3 +1,19 @@
+//! Memory-mapped device registers.
+//!
+//! Drivers describe their registers as [`Register`] and [`RegisterArray`] constants, which
+//! carry each register's offset and width, and access them through a [`RegisterBlock`] at the
+//! device's base address:
+//!
+//! ```ignore
+//! const FR: Register<u32> = Register::new(0x18);
+//! const FR_TXFF: u32 = 1 << 5;
+//!
+//! regs.spin_while_hi(FR, FR_TXFF);
+//! ```
+//!
+//! Every access is volatile and surrounded by barriers, so it's seen by the device in program
+//! order. [`Mmio`] is the older, untyped interface, taking raw offsets.
+
 use core::{
     arch::asm,
     fmt::{Binary, Debug, LowerHex, UpperHex},
@@ -5,7 +21,37 @@ use core::{
     ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not},
 };
 
-use crate::mem::units::VirtAddr;
+use crate::{
+    arch::{AArch64, Architecture},
+    mem::{
+        MemError,
+        paging::table::{BlockSize, PageFlags, PageTable, TableKind},
+        units::{PhysAddr, VirtAddr},
+    },
+};
+
+/// Maps a device's registers into the HHDM as device memory, returning their virtual address.
+///
+/// Anything already mapped in the range, like the peripheral window mapped at boot, is
+/// mapped again as device memory.
+pub fn map_device(phys: PhysAddr, size: usize) -> Result<VirtAddr, MemError> {
+    let start = phys.align_down(AArch64::PAGE_SIZE);
+    let end = phys.add_bytes(size).align_up(AArch64::PAGE_SIZE);
+    let mut table = PageTable::current(TableKind::Kernel);
+    let mut frame = start;
+    while frame < end {
+        table
+            .remap_to(
+                frame.as_hhdm_virt(),
+                frame,
+                BlockSize::Page4KiB,
+                PageFlags::new_device(),
+            )?
+            .flush();
+        frame = frame.add_bytes(AArch64::PAGE_SIZE);
+    }
+    Ok(phys.as_hhdm_virt())
+}
 
 pub trait MmioValue:
     'static
@@ -43,6 +89,215 @@ impl MmioValue for u64 {
     const ZERO: Self = 0;
 }
 
+/// A device register at a fixed offset from the start of its block, `T` wide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register<T: MmioValue> {
+    offset: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: MmioValue> Register<T> {
+    #[must_use]
+    pub const fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub const fn offset(self) -> usize {
+        self.offset
+    }
+}
+
+/// A run of registers of the same width, `stride` bytes apart, like the GIC's per-IRQ banks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterArray<T: MmioValue> {
+    offset: usize,
+    stride: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: MmioValue> RegisterArray<T> {
+    /// Creates an array whose registers are packed together, one after the other.
+    #[must_use]
+    pub const fn new(offset: usize) -> Self {
+        Self::with_stride(offset, size_of::<T>())
+    }
+
+    #[must_use]
+    pub const fn with_stride(offset: usize, stride: usize) -> Self {
+        Self {
+            offset,
+            stride,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the register at `index`.
+    #[must_use]
+    pub const fn at(self, index: usize) -> Register<T> {
+        Register::new(self.offset + index * self.stride)
+    }
+}
+
+/// A device's registers, at the virtual address they're mapped at.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterBlock {
+    pub addr: VirtAddr,
+}
+
+impl RegisterBlock {
+    #[must_use]
+    pub const fn new(addr: VirtAddr) -> Self {
+        Self { addr }
+    }
+
+    /// Reads a register.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    #[must_use]
+    pub unsafe fn read<T: MmioValue>(&self, reg: Register<T>) -> T {
+        unsafe {
+            asm!("dsb sy", "isb");
+            self.addr.add_bytes(reg.offset).read_volatile().unwrap()
+        }
+    }
+
+    /// Writes a register.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    pub unsafe fn write<T: MmioValue>(&mut self, reg: Register<T>, value: T) {
+        unsafe {
+            self.addr
+                .add_bytes(reg.offset)
+                .write_volatile(value)
+                .unwrap();
+            asm!("dsb sy", "isb");
+        }
+    }
+
+    /// Writes a register and asserts that it reads back the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value read back differs, or if the register is misaligned.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn write_assert<T: MmioValue>(&mut self, reg: Register<T>, value: T) {
+        unsafe {
+            self.write(reg, value);
+            assert_eq!(self.read(reg), value);
+        }
+    }
+
+    /// Reads a register, and writes back what `f` makes of its value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    pub unsafe fn modify<T: MmioValue>(&mut self, reg: Register<T>, f: impl FnOnce(T) -> T) {
+        unsafe {
+            let value = self.read(reg);
+            self.write(reg, f(value));
+        }
+    }
+
+    /// Sets some bits of a register, leaving the rest as they were.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    pub unsafe fn set<T: MmioValue>(&mut self, reg: Register<T>, bits: T) {
+        unsafe { self.modify(reg, |value| value | bits) };
+    }
+
+    /// Clears some bits of a register, leaving the rest as they were.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    pub unsafe fn clear<T: MmioValue>(&mut self, reg: Register<T>, bits: T) {
+        unsafe { self.modify(reg, |value| value & !bits) };
+    }
+
+    /// Replaces the bits of a register in `mask` with those of `bits`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is misaligned.
+    #[inline]
+    pub unsafe fn clear_set<T: MmioValue>(&mut self, reg: Register<T>, mask: T, bits: T) {
+        unsafe { self.modify(reg, |value| (value & !mask) | (bits & mask)) };
+    }
+
+    /// Sets some bits of a register, asserting that they read back set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value read back differs, or if the register is misaligned.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn set_assert<T: MmioValue>(&mut self, reg: Register<T>, bits: T) {
+        unsafe {
+            let value = self.read(reg);
+            self.write_assert(reg, value | bits);
+        }
+    }
+
+    /// Clears some bits of a register, asserting that they read back clear.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value read back differs, or if the register is misaligned.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn clear_assert<T: MmioValue>(&mut self, reg: Register<T>, bits: T) {
+        unsafe {
+            let value = self.read(reg);
+            self.write_assert(reg, value & !bits);
+        }
+    }
+
+    /// Spins until all the bits in `mask` are set.
+    #[inline]
+    pub unsafe fn spin_until_hi<T: MmioValue>(&self, reg: Register<T>, mask: T) {
+        crate::util::spin_while(|| unsafe { self.read(reg) & mask != mask });
+    }
+
+    /// Spins while all the bits in `mask` are set.
+    #[inline]
+    pub unsafe fn spin_while_hi<T: MmioValue>(&self, reg: Register<T>, mask: T) {
+        crate::util::spin_while(|| unsafe { self.read(reg) & mask == mask });
+    }
+
+    /// Spins until all the bits in `mask` are clear.
+    #[inline]
+    pub unsafe fn spin_until_lo<T: MmioValue>(&self, reg: Register<T>, mask: T) {
+        crate::util::spin_while(|| unsafe { self.read(reg) & mask != T::ZERO });
+    }
+
+    /// Spins while all the bits in `mask` are clear.
+    #[inline]
+    pub unsafe fn spin_while_lo<T: MmioValue>(&self, reg: Register<T>, mask: T) {
+        crate::util::spin_while(|| unsafe { self.read(reg) & mask == T::ZERO });
+    }
+}
+
+/// A device's registers, all `T` wide, at raw offsets from `addr`.
+///
+/// Its accesses go through [`RegisterBlock`]; new drivers should use that directly, with
+/// [`Register`] constants.
 #[derive(Debug, Default)]
 pub struct Mmio<T: MmioValue> {
     pub addr: VirtAddr,
@@ -58,6 +313,11 @@ impl<T: MmioValue> Mmio<T> {
         }
     }
 
+    #[inline]
+    fn block(&self) -> RegisterBlock {
+        RegisterBlock::new(self.addr)
+    }
+
     /// Reads a value from the MMIO address at the specified offset.
     ///
     /// # Panics
@@ -66,10 +326,7 @@ impl<T: MmioValue> Mmio<T> {
     #[inline]
     #[must_use]
     pub unsafe fn read(&self, offset: usize) -> T {
-        unsafe {
-            asm!("dsb sy", "isb");
-            self.addr.add_bytes(offset).read_volatile().unwrap()
-        }
+        unsafe { self.block().read(Register::new(offset)) }
     }
 
     /// Writes a value to the MMIO address at the specified offset.
@@ -79,10 +336,7 @@ impl<T: MmioValue> Mmio<T> {
     /// This function will panic if the write operation fails.
     #[inline]
     pub unsafe fn write(&mut self, offset: usize, value: T) {
-        unsafe {
-            self.addr.add_bytes(offset).write_volatile(value).unwrap();
-            asm!("dsb sy", "isb");
-        }
+        unsafe { self.block().write(Register::new(offset), value) };
     }
 
     /// Writes a value to the MMIO address at the specified offset and asserts that the value was written correctly.
@@ -93,10 +347,7 @@ impl<T: MmioValue> Mmio<T> {
     #[inline]
     #[track_caller]
     pub unsafe fn write_assert(&mut self, offset: usize, value: T) {
-        unsafe {
-            self.write(offset, value);
-            assert_eq!(self.read(offset), value);
-        }
+        unsafe { self.block().write_assert(Register::new(offset), value) };
     }
 
     /// Reads a value from the MMIO address at the specified offset and sets some of its bits.
@@ -106,11 +357,7 @@ impl<T: MmioValue> Mmio<T> {
     /// This function will panic if either the read or write operation fails.
     #[inline]
     pub unsafe fn set(&mut self, offset: usize, bits: T) {
-        unsafe {
-            let mut value = self.read(offset);
-            value |= bits;
-            self.write(offset, value);
-        }
+        unsafe { self.block().set(Register::new(offset), bits) };
     }
 
     /// Reads a value from the MMIO address at the specified offset and clears some of its bits.
@@ -120,11 +367,7 @@ impl<T: MmioValue> Mmio<T> {
     /// This function will panic if either the read or write operation fails.
     #[inline]
     pub unsafe fn clear(&mut self, offset: usize, bits: T) {
-        unsafe {
-            let mut value = self.read(offset);
-            value &= !bits;
-            self.write(offset, value);
-        }
+        unsafe { self.block().clear(Register::new(offset), bits) };
     }
 
     /// Reads a value from the MMIO address at the specified offset and sets some of its bits, asserting that the value was written correctly.
@@ -136,11 +379,7 @@ impl<T: MmioValue> Mmio<T> {
     #[inline]
     #[track_caller]
     pub unsafe fn set_assert(&mut self, offset: usize, bits: T) {
-        unsafe {
-            let mut value = self.read(offset);
-            value |= bits;
-            self.write_assert(offset, value);
-        }
+        unsafe { self.block().set_assert(Register::new(offset), bits) };
     }
 
     /// Reads a value from the MMIO address at the specified offset and clears some of its bits, asserting that the value was written correctly.
@@ -152,30 +391,26 @@ impl<T: MmioValue> Mmio<T> {
     #[inline]
     #[track_caller]
     pub unsafe fn clear_assert(&mut self, offset: usize, bits: T) {
-        unsafe {
-            let mut value = self.read(offset);
-            value &= !bits;
-            self.write_assert(offset, value);
-        }
+        unsafe { self.block().clear_assert(Register::new(offset), bits) };
     }
 
     #[inline]
     pub unsafe fn spin_until_hi(&self, offset: usize, mask: T) {
-        crate::util::spin_while(|| unsafe { self.read(offset) & mask != mask });
+        unsafe { self.block().spin_until_hi(Register::new(offset), mask) };
     }
 
     #[inline]
     pub unsafe fn spin_while_hi(&self, offset: usize, mask: T) {
-        crate::util::spin_while(|| unsafe { self.read(offset) & mask == mask });
+        unsafe { self.block().spin_while_hi(Register::new(offset), mask) };
     }
 
     #[inline]
     pub unsafe fn spin_until_lo(&self, offset: usize, mask: T) {
-        crate::util::spin_while(|| unsafe { self.read(offset) & mask != T::ZERO });
+        unsafe { self.block().spin_until_lo(Register::new(offset), mask) };
     }
 
     #[inline]
     pub unsafe fn spin_while_lo(&self, offset: usize, mask: T) {
-        crate::util::spin_while(|| unsafe { self.read(offset) & mask == T::ZERO });
+        unsafe { self.block().spin_while_lo(Register::new(offset), mask) };
     }
 }
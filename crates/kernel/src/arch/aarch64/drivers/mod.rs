@@ -3,11 +3,11 @@ use core::{alloc::Layout, ptr::NonNull};
 use buddy_system_allocator::LockedHeap;
 
 use crate::{
-    arch::Architecture,
+    arch::{Architecture, driver::Driver, invalidate_data_cache},
     mem::{
         paging::{
-            allocator::KernelFrameAllocator,
-            table::{BlockSize, PageFlags, PageTable},
+            allocator,
+            table::{PageFlags, PageTable, TableKind},
         },
         units::FrameCount,
     },
@@ -15,36 +15,73 @@ use crate::{
 
 use super::AArch64;
 
+pub mod bounce;
+pub mod dma;
+pub mod gpio;
 pub mod gpu;
+pub mod led;
 pub mod mmio;
+pub mod pl031;
+pub mod power;
+pub mod rng200;
+pub mod rtc;
+pub mod spi;
+pub mod virtio;
+
+/// The drivers probed against the device tree at boot.
+pub static DRIVERS: &[&Driver] = &[
+    &gpu::DRIVER,
+    &gpu::vsync::DRIVER,
+    &gpio::DRIVER,
+    &spi::DRIVER,
+    &dma::DRIVER,
+    &virtio::DRIVER,
+    &led::DRIVER,
+    &rng200::DRIVER,
+    &pl031::DRIVER,
+    &rtc::DRIVER,
+];
+
+/// The size of the DMA heap. The builder sets this with `--dma-size`.
+pub const DMA_SIZE: usize = match option_env!("KADOS_DMA_SIZE") {
+    Some(size) => match usize::from_str_radix(size, 10) {
+        Ok(size) => size,
+        Err(_) => panic!("KADOS_DMA_SIZE must be a decimal number"),
+    },
+    None => AArch64::PAGE_SIZE * 32,
+};
+const _: () = assert!(
+    DMA_SIZE.is_multiple_of(AArch64::PAGE_SIZE),
+    "the DMA heap must be a whole number of pages"
+);
 
-pub const DMA_SIZE: usize = AArch64::PAGE_SIZE * 32;
 static DMA_HEAP: LockedHeap<32> = LockedHeap::empty();
 
 /// Initializes the dedicated Direct Memory Access (DMA) heap.
 ///
+/// The heap's memory is remapped in the HHDM as non-cacheable, so what's allocated from it
+/// needs no cache maintenance around transfers.
+///
 /// # Panics
 ///
-/// This function will panic if the memory allocation fails.
-pub fn dma_init(mapper: &mut PageTable) {
-    let base = unsafe {
-        KernelFrameAllocator
-            .allocate(FrameCount::from_bytes(DMA_SIZE))
-            .unwrap()
-    };
-
+/// This function will panic if the memory allocation or the remapping fails.
+pub fn dma_init() {
+    let base = allocator::reserve(
+        "DMA heap",
+        FrameCount::from_bytes(DMA_SIZE),
+        AArch64::PAGE_SIZE,
+    )
+    .expect("failed to reserve the DMA heap");
+    let virt = base.as_hhdm_virt();
     unsafe {
-        mapper
-            .map_range_with_block_size(
-                base.as_identity_virt(),
-                base,
-                DMA_SIZE,
-                BlockSize::Page4KiB,
-                PageFlags::new_for_data_segment(),
-            )
-            .unwrap()
-            .ignore();
-    };
+        PageTable::current(TableKind::Kernel)
+            .kernel_remap_range(virt, base, DMA_SIZE, PageFlags::new_dma())
+            .expect("failed to remap the DMA heap")
+            .flush();
+        // drop whatever was cached through the old mapping, so accesses through the new one
+        // can't hit it
+        invalidate_data_cache(virt.as_raw_ptr(), DMA_SIZE);
+    }
 
     unsafe {
         DMA_HEAP.lock().add_to_heap(
@@ -52,6 +89,8 @@ pub fn dma_init(mapper: &mut PageTable) {
             base.as_hhdm_virt().add_bytes(DMA_SIZE).value(),
         );
     };
+
+    bounce::init();
 }
 
 /// Allocates a zero-initialized object of type `T` from the DMA heap.
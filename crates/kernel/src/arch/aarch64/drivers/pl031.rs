@@ -0,0 +1,42 @@
+//! The PL031 real-time clock on QEMU's `virt` board, which sets the kernel's
+//! [wall clock](crate::time::wall).
+
+use core::time::Duration;
+
+use fdt::{Fdt, node::FdtNode};
+
+use crate::{
+    arch::driver::Driver,
+    fdt::get_mmio_addr,
+    syscall::errno::Errno,
+    time::wall::{self, Source},
+};
+
+use super::mmio::{Register, RegisterBlock, map_device};
+
+/// The data register, holding the UNIX time in seconds.
+const RTCDR: Register<u32> = Register::new(0x00);
+const REGS_SIZE: usize = 0x1000;
+
+pub static DRIVER: Driver = Driver {
+    name: "pl031",
+    compatible: &["arm,pl031"],
+    depends_on: &[],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+        return Err(Errno::EINVAL);
+    };
+    let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+        return Err(Errno::EINVAL);
+    };
+    let base = map_device(mmio_addr, REGS_SIZE).map_err(|_| Errno::ENOMEM)?;
+    let regs = RegisterBlock::new(base);
+    log::debug!("pl031 @ {base}");
+
+    let secs = unsafe { regs.read(RTCDR) };
+    wall::set(Duration::from_secs(secs.into()), Source::Rtc);
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Power domains and clocks, managed by the `VideoCore` firmware through the mailbox.
+//!
+//! The firmware leaves some blocks powered down at boot, depending on the board and
+//! `config.txt`, so drivers for them should [`power_on`] their domain when they're probed
+//! rather than assume it's up.
+
+use core::fmt;
+
+use crate::syscall::errno::Errno;
+
+use super::gpu::{
+    MailboxChannel, MailboxProperty, MailboxRequest,
+    props::{
+        GetClockRate, GetClockState, GetPowerState, SetClockRate, SetClockState, SetPowerState,
+    },
+    with_mailbox,
+};
+
+/// Set in a power or clock state if the block is on.
+const STATE_ON: u32 = 1 << 0;
+/// Set in a power or clock state if the firmware doesn't know the block.
+const STATE_MISSING: u32 = 1 << 1;
+/// Set in a power state request to wait for the domain to settle before replying.
+const STATE_WAIT: u32 = 1 << 1;
+
+/// A power domain the firmware can switch on and off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PowerDomain {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
+
+impl PowerDomain {
+    /// Every power domain, in firmware ID order.
+    pub const ALL: [Self; 9] = [
+        Self::SdCard,
+        Self::Uart0,
+        Self::Uart1,
+        Self::UsbHcd,
+        Self::I2c0,
+        Self::I2c1,
+        Self::I2c2,
+        Self::Spi,
+        Self::Ccp2tx,
+    ];
+}
+
+/// A clock the firmware generates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Hevc = 11,
+    Emmc2 = 12,
+    M2mc = 13,
+    PixelBvb = 14,
+}
+
+/// Whether a power domain or clock is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Off,
+    On,
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::On => write!(f, "on"),
+        }
+    }
+}
+
+impl PowerState {
+    fn from_raw(state: u32) -> Result<Self, Errno> {
+        if state & STATE_MISSING != 0 {
+            Err(Errno::ENODEV)
+        } else if state & STATE_ON != 0 {
+            Ok(Self::On)
+        } else {
+            Ok(Self::Off)
+        }
+    }
+}
+
+fn call<P: MailboxProperty>(property: P) -> Result<P::Response, Errno> {
+    with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(property);
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        response.decode::<P>().ok_or(Errno::EIO)
+    })
+}
+
+/// Returns whether a power domain is on.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the mailbox isn't available or the firmware doesn't know the
+/// domain, and [`Errno::EIO`] if the call fails.
+pub fn power_state(domain: PowerDomain) -> Result<PowerState, Errno> {
+    let response = call(GetPowerState {
+        device_id: domain as u32,
+    })?;
+    PowerState::from_raw(response.state)
+}
+
+/// Switches a power domain on or off, waiting for it to settle.
+///
+/// # Errors
+///
+/// As for [`power_state`], and [`Errno::EIO`] if the domain didn't end up in the requested
+/// state.
+pub fn set_power_state(domain: PowerDomain, state: PowerState) -> Result<(), Errno> {
+    let on = if state == PowerState::On { STATE_ON } else { 0 };
+    let response = call(SetPowerState {
+        device_id: domain as u32,
+        state: on | STATE_WAIT,
+    })?;
+    if PowerState::from_raw(response.state)? == state {
+        Ok(())
+    } else {
+        Err(Errno::EIO)
+    }
+}
+
+/// Switches a power domain on, if it isn't already.
+///
+/// # Errors
+///
+/// See [`set_power_state`].
+pub fn power_on(domain: PowerDomain) -> Result<(), Errno> {
+    if power_state(domain)? == PowerState::On {
+        return Ok(());
+    }
+    log::debug!("powering on {domain:?}");
+    set_power_state(domain, PowerState::On)
+}
+
+/// Returns whether a clock is running.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the mailbox isn't available or the firmware doesn't know the
+/// clock, and [`Errno::EIO`] if the call fails.
+pub fn clock_state(clock: Clock) -> Result<PowerState, Errno> {
+    let response = call(GetClockState {
+        clock_id: clock as u32,
+    })?;
+    PowerState::from_raw(response.state)
+}
+
+/// Starts or stops a clock.
+///
+/// # Errors
+///
+/// As for [`clock_state`], and [`Errno::EIO`] if the clock didn't end up in the requested
+/// state.
+pub fn set_clock_state(clock: Clock, state: PowerState) -> Result<(), Errno> {
+    let response = call(SetClockState {
+        clock_id: clock as u32,
+        state: if state == PowerState::On { STATE_ON } else { 0 },
+    })?;
+    if PowerState::from_raw(response.state)? == state {
+        Ok(())
+    } else {
+        Err(Errno::EIO)
+    }
+}
+
+/// Returns a clock's rate in Hz.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the mailbox isn't available or the firmware doesn't know the
+/// clock, and [`Errno::EIO`] if the call fails.
+pub fn clock_rate(clock: Clock) -> Result<u32, Errno> {
+    let response = call(GetClockRate {
+        clock_id: clock as u32,
+    })?;
+    match response.rate {
+        0 => Err(Errno::ENODEV),
+        rate => Ok(rate),
+    }
+}
+
+/// Asks the firmware to run a clock at `rate` Hz, returning the rate it actually chose.
+///
+/// # Errors
+///
+/// See [`clock_rate`].
+pub fn set_clock_rate(clock: Clock, rate: u32) -> Result<u32, Errno> {
+    let response = call(SetClockRate {
+        clock_id: clock as u32,
+        rate,
+        skip_turbo: 0,
+    })?;
+    match response.rate {
+        0 => Err(Errno::ENODEV),
+        rate => Ok(rate),
+    }
+}
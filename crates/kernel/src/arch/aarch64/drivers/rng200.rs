@@ -0,0 +1,138 @@
+//! The RNG200 hardware random number generator on the BCM2711 and BCM2712, registered as the
+//! kernel's [`rng`](crate::rng) source.
+
+use core::time::Duration;
+
+use fdt::{Fdt, node::FdtNode};
+use spin::Once;
+
+use crate::{
+    arch::driver::Driver, fdt::get_mmio_addr, rng, sync::IrqMutex, syscall::errno::Errno, time,
+};
+
+use super::mmio::Mmio;
+
+const RNG_CTRL: usize = 0x00;
+const RNG_SOFT_RESET: usize = 0x04;
+const RBG_SOFT_RESET: usize = 0x08;
+const RNG_INT_STATUS: usize = 0x18;
+const RNG_FIFO_DATA: usize = 0x20;
+const RNG_FIFO_COUNT: usize = 0x24;
+
+const CTRL_RBGEN_MASK: u32 = 0x1fff;
+const CTRL_RBGEN_ENABLE: u32 = 1 << 0;
+
+const INT_STATUS_NIST_FAIL: u32 = 1 << 5;
+const INT_STATUS_MASTER_FAIL_LOCKUP: u32 = 1 << 31;
+
+const FIFO_COUNT_MASK: u32 = 0xff;
+
+/// How long to wait for the FIFO to fill before giving up on a read.
+const FILL_TIMEOUT: Duration = Duration::from_millis(100);
+
+static RNG200: Once<IrqMutex<Rng200>> = Once::new();
+
+pub struct Rng200 {
+    regs: Mmio<u32>,
+}
+
+impl Rng200 {
+    /// Parses the RNG from its FDT node.
+    pub fn parse(fdt: &Fdt, node: &FdtNode) -> Result<Self, Errno> {
+        let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+            return Err(Errno::EINVAL);
+        };
+
+        let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+            return Err(Errno::EINVAL);
+        };
+
+        Ok(Self {
+            regs: Mmio::new(mmio_addr.as_hhdm_virt()),
+        })
+    }
+
+    fn enable(&mut self) {
+        unsafe {
+            let ctrl = self.regs.read(RNG_CTRL) & !CTRL_RBGEN_MASK;
+            self.regs.write(RNG_CTRL, ctrl | CTRL_RBGEN_ENABLE);
+        }
+    }
+
+    fn disable(&mut self) {
+        unsafe { self.regs.clear(RNG_CTRL, CTRL_RBGEN_MASK) };
+    }
+
+    /// Resets the generator after it's reported a failure, discarding what's in the FIFO.
+    fn reset(&mut self) {
+        self.disable();
+        unsafe {
+            self.regs.write(RNG_SOFT_RESET, 1);
+            self.regs.write(RBG_SOFT_RESET, 1);
+            self.regs.write(RNG_SOFT_RESET, 0);
+            self.regs.write(RBG_SOFT_RESET, 0);
+            self.regs.write(RNG_INT_STATUS, u32::MAX);
+        }
+        self.enable();
+    }
+
+    /// Fills `buf` from the FIFO, waiting for it to fill as needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ETIMEDOUT`] if the FIFO stays empty for [`FILL_TIMEOUT`].
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<(), Errno> {
+        let mut chunks = buf.chunks_mut(4);
+        let mut deadline = time::uptime() + FILL_TIMEOUT;
+        while chunks.len() != 0 {
+            let status = unsafe { self.regs.read(RNG_INT_STATUS) };
+            if status & (INT_STATUS_NIST_FAIL | INT_STATUS_MASTER_FAIL_LOCKUP) != 0 {
+                log::warn!("rng200: generator failed ({status:#x}); resetting it");
+                self.reset();
+            }
+
+            let available = unsafe { self.regs.read(RNG_FIFO_COUNT) } & FIFO_COUNT_MASK;
+            if available == 0 {
+                if time::uptime() > deadline {
+                    return Err(Errno::ETIMEDOUT);
+                }
+                core::hint::spin_loop();
+                continue;
+            }
+            for chunk in chunks.by_ref().take(available as usize) {
+                let word = unsafe { self.regs.read(RNG_FIFO_DATA) };
+                chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+            }
+            deadline = time::uptime() + FILL_TIMEOUT;
+        }
+        Ok(())
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "rng200",
+    compatible: &["brcm,bcm2711-rng200", "brcm,iproc-rng200"],
+    depends_on: &[],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if RNG200.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let mut rng = Rng200::parse(fdt, node)?;
+    rng.enable();
+    log::debug!("rng200 @ {}", rng.regs.addr);
+    RNG200.call_once(|| IrqMutex::new(rng));
+    rng::register_source("rng200", read)
+}
+
+/// Fills `buf` straight from the hardware.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the RNG hasn't been probed, or [`Errno::ETIMEDOUT`] if it
+/// stopped producing output.
+pub fn read(buf: &mut [u8]) -> Result<(), Errno> {
+    RNG200.get().ok_or(Errno::ENODEV)?.lock().read(buf)
+}
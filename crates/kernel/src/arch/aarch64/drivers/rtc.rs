@@ -0,0 +1,52 @@
+//! The Raspberry Pi 5's real-time clock, which the firmware reads on the kernel's behalf and
+//! which sets the kernel's [wall clock](crate::time::wall).
+//!
+//! The Pi 4 has no RTC; it starts its wall clock from `time=` on the command line or the build
+//! date. The kernel has no I2C driver yet, so neither do add-on RTC boards.
+
+use core::time::Duration;
+
+use fdt::{Fdt, node::FdtNode};
+
+use crate::{
+    arch::driver::Driver,
+    syscall::errno::Errno,
+    time::wall::{self, Source},
+};
+
+use super::gpu::{
+    MailboxChannel, MailboxRequest,
+    props::{GetRtcReg, GetRtcRegResponse},
+    with_mailbox,
+};
+
+/// The register holding the UNIX time in seconds.
+const RTC_TIME: u32 = 0;
+
+pub static DRIVER: Driver = Driver {
+    name: "rtc",
+    compatible: &["raspberrypi,rpi-rtc"],
+    depends_on: &["mailbox"],
+    probe,
+};
+
+fn probe(_fdt: &Fdt, _node: &FdtNode) -> Result<(), Errno> {
+    let secs = read_reg(RTC_TIME)?;
+    if secs == 0 {
+        // the clock's never been set, and has no battery
+        return Err(Errno::ENODEV);
+    }
+    wall::set(Duration::from_secs(secs.into()), Source::Rtc);
+    Ok(())
+}
+
+/// Reads one of the RTC's registers through the firmware.
+fn read_reg(reg: u32) -> Result<u32, Errno> {
+    let GetRtcRegResponse { value, .. } = with_mailbox(|mbox| {
+        let request = MailboxRequest::new().encode(GetRtcReg { reg });
+        let response =
+            unsafe { mbox.call(request, MailboxChannel::TagsArmToVc) }.map_err(|_| Errno::EIO)?;
+        response.decode::<GetRtcReg>().ok_or(Errno::EIO)
+    })?;
+    Ok(value)
+}
@@ -0,0 +1,241 @@
+use fdt::{Fdt, node::FdtNode};
+use spin::Once;
+
+use crate::{
+    arch::driver::Driver,
+    fdt::get_mmio_addr,
+    sync::{IrqMutex, IrqMutexGuard},
+    syscall::errno::Errno,
+};
+
+use super::{
+    gpio::{Function, Gpio, gpio},
+    mmio::Mmio,
+    power::{self, PowerDomain},
+};
+
+const SPI_CS: usize = 0x00;
+const SPI_FIFO: usize = 0x04;
+const SPI_CLK: usize = 0x08;
+
+const CS_CPHA: u32 = 1 << 2;
+const CS_CPOL: u32 = 1 << 3;
+const CS_CLEAR_TX: u32 = 1 << 4;
+const CS_CLEAR_RX: u32 = 1 << 5;
+const CS_TA: u32 = 1 << 7;
+const CS_DONE: u32 = 1 << 16;
+const CS_RXD: u32 = 1 << 17;
+const CS_TXD: u32 = 1 << 18;
+
+/// The VPU core clock that feeds the SPI block (`core_freq` on the Pi 4).
+pub const CORE_CLOCK_HZ: u32 = 500_000_000;
+
+/// GPIO pins for SPI0 (MISO, MOSI, SCLK), muxed to ALT0.
+const SPI0_PINS: [u32; 3] = [9, 10, 11];
+
+/// The GPIO pins wired to SPI0's CE0 and CE1 lines.
+pub const SPI0_CE0: u32 = 8;
+pub const SPI0_CE1: u32 = 7;
+
+static SPI0: Once<IrqMutex<Spi>> = Once::new();
+
+/// The SPI clock polarity/phase mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpiMode {
+    /// CPOL = 0, CPHA = 0
+    #[default]
+    Mode0,
+    /// CPOL = 0, CPHA = 1
+    Mode1,
+    /// CPOL = 1, CPHA = 0
+    Mode2,
+    /// CPOL = 1, CPHA = 1
+    Mode3,
+}
+
+impl SpiMode {
+    fn cs_bits(self) -> u32 {
+        match self {
+            Self::Mode0 => 0,
+            Self::Mode1 => CS_CPHA,
+            Self::Mode2 => CS_CPOL,
+            Self::Mode3 => CS_CPOL | CS_CPHA,
+        }
+    }
+}
+
+/// A device attached to an SPI bus, selected by a GPIO chip-select line.
+#[derive(Debug, Clone, Copy)]
+pub struct SpiDevice {
+    /// The GPIO pin used as this device's chip-select.
+    pub cs_pin: u32,
+    /// Whether the chip-select line is asserted high instead of low.
+    pub cs_active_high: bool,
+    /// The clock polarity/phase mode.
+    pub mode: SpiMode,
+    /// The desired SCLK frequency. The actual frequency is rounded down.
+    pub clock_hz: u32,
+}
+
+impl SpiDevice {
+    /// Creates a new device on the given chip-select pin with an active-low CS and mode 0.
+    #[must_use]
+    pub const fn new(cs_pin: u32, clock_hz: u32) -> Self {
+        Self {
+            cs_pin,
+            cs_active_high: false,
+            mode: SpiMode::Mode0,
+            clock_hz,
+        }
+    }
+
+    fn select(&self, gpio: &mut Gpio, selected: bool) -> Result<(), Errno> {
+        gpio.write(self.cs_pin, selected == self.cs_active_high)
+    }
+}
+
+/// An SPI master controller (`brcm,bcm2835-spi`).
+pub struct Spi {
+    regs: Mmio<u32>,
+}
+
+impl Spi {
+    /// Parses an SPI controller from its FDT node.
+    pub fn parse(fdt: &Fdt, node: &FdtNode) -> Result<Self, Errno> {
+        let Some(region) = node.reg().and_then(|mut r| r.next()) else {
+            return Err(Errno::EINVAL);
+        };
+
+        let Some(mmio_addr) = get_mmio_addr(fdt, &region) else {
+            return Err(Errno::EINVAL);
+        };
+
+        Ok(Self {
+            regs: Mmio::new(mmio_addr.as_hhdm_virt()),
+        })
+    }
+
+    /// Muxes the bus pins and resets the controller.
+    pub fn init(&mut self) -> Result<(), Errno> {
+        let mut gpio = gpio();
+        for pin in SPI0_PINS {
+            gpio.set_function(pin, Function::Alt0)?;
+        }
+        unsafe { self.regs.write(SPI_CS, CS_CLEAR_TX | CS_CLEAR_RX) };
+        Ok(())
+    }
+
+    /// Configures a device's chip-select pin as an output and deasserts it.
+    pub fn add_device(&mut self, device: &SpiDevice) -> Result<(), Errno> {
+        let mut gpio = gpio();
+        device.select(&mut gpio, false)?;
+        gpio.set_function(device.cs_pin, Function::Output)
+    }
+
+    /// Performs a full-duplex transfer, clocking out `tx` while reading into `rx`.
+    ///
+    /// `tx` and `rx` must be the same length.
+    pub fn transfer(&mut self, device: &SpiDevice, tx: &[u8], rx: &mut [u8]) -> Result<(), Errno> {
+        if tx.len() != rx.len() {
+            return Err(Errno::EINVAL);
+        }
+        self.transfer_inner(device, Some(tx), Some(rx), tx.len())
+    }
+
+    /// Writes `tx` to the device, discarding whatever is clocked in.
+    pub fn write(&mut self, device: &SpiDevice, tx: &[u8]) -> Result<(), Errno> {
+        self.transfer_inner(device, Some(tx), None, tx.len())
+    }
+
+    /// Reads into `rx` from the device, clocking out zeroes.
+    pub fn read(&mut self, device: &SpiDevice, rx: &mut [u8]) -> Result<(), Errno> {
+        let len = rx.len();
+        self.transfer_inner(device, None, Some(rx), len)
+    }
+
+    fn clock_divider(clock_hz: u32) -> u32 {
+        // CDIV must be even; 0 means 65536
+        let div = CORE_CLOCK_HZ.div_ceil(clock_hz.max(1));
+        let div = (div + 1) & !1;
+        if div >= 65536 { 0 } else { div.max(2) }
+    }
+
+    fn begin(&mut self, device: &SpiDevice) -> Result<(), Errno> {
+        let mut gpio = gpio();
+        unsafe {
+            self.regs
+                .write(SPI_CLK, Self::clock_divider(device.clock_hz));
+            self.regs
+                .write(SPI_CS, device.mode.cs_bits() | CS_CLEAR_TX | CS_CLEAR_RX);
+        }
+        device.select(&mut gpio, true)?;
+        unsafe { self.regs.set(SPI_CS, CS_TA) };
+        Ok(())
+    }
+
+    fn end(&mut self, device: &SpiDevice) -> Result<(), Errno> {
+        unsafe {
+            self.regs.spin_until_hi(SPI_CS, CS_DONE);
+            self.regs.clear(SPI_CS, CS_TA);
+        }
+        device.select(&mut gpio(), false)
+    }
+
+    fn transfer_inner(
+        &mut self,
+        device: &SpiDevice,
+        tx: Option<&[u8]>,
+        mut rx: Option<&mut [u8]>,
+        len: usize,
+    ) -> Result<(), Errno> {
+        self.begin(device)?;
+
+        let mut tx_idx = 0;
+        let mut rx_idx = 0;
+        while rx_idx < len {
+            while tx_idx < len && unsafe { self.regs.read(SPI_CS) } & CS_TXD != 0 {
+                let byte = tx.map_or(0, |tx| tx[tx_idx]);
+                unsafe { self.regs.write(SPI_FIFO, u32::from(byte)) };
+                tx_idx += 1;
+            }
+            while rx_idx < len && unsafe { self.regs.read(SPI_CS) } & CS_RXD != 0 {
+                let byte = unsafe { self.regs.read(SPI_FIFO) } as u8;
+                if let Some(rx) = rx.as_deref_mut() {
+                    rx[rx_idx] = byte;
+                }
+                rx_idx += 1;
+            }
+        }
+
+        self.end(device)
+    }
+}
+
+/// The SPI controller's driver. Only the first enabled controller is used, as SPI0.
+pub static DRIVER: Driver = Driver {
+    name: "spi",
+    compatible: &["brcm,bcm2835-spi"],
+    depends_on: &["gpio", "mailbox"],
+    probe,
+};
+
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    if SPI0.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let mut spi = Spi::parse(fdt, node)?;
+    power::power_on(PowerDomain::Spi)?;
+    spi.init()?;
+    log::debug!("spi0 @ {}", spi.regs.addr);
+    SPI0.call_once(|| IrqMutex::new(spi));
+    Ok(())
+}
+
+/// Locks the SPI0 controller for exclusive access.
+///
+/// # Panics
+///
+/// This function will panic if SPI0 has not been initialized.
+pub fn spi0<'a>() -> IrqMutexGuard<'a, Spi> {
+    SPI0.get().expect("SPI0 not initialized").lock()
+}
@@ -0,0 +1,159 @@
+//! The virtio block device driver.
+
+use alloc::{boxed::Box, format};
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    mem::{paging::allocator::KernelFrameAllocator, units::PhysAddr},
+    syscall::errno::Errno,
+    task::switch::switch,
+};
+
+use super::{
+    super::bounce::DmaBuffer,
+    CONSTRAINTS, Transport,
+    queue::{Segment, VirtQueue},
+};
+
+const F_RO: u64 = 1 << 5;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// The most sectors moved by one request, to keep bounced requests within the pool.
+const MAX_SECTORS_PER_REQUEST: usize = 128;
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Where the status byte goes in the request page, after the header.
+const STATUS_OFFSET: usize = size_of::<RequestHeader>();
+
+/// A virtio block device.
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: VirtQueue,
+    capacity: u64,
+    read_only: bool,
+    /// A page for the header and status of the request in flight.
+    request_page: PhysAddr,
+}
+
+impl VirtioBlk {
+    /// Sets up the block device behind `transport`.
+    pub fn new(mut transport: Transport) -> Result<Self, Errno> {
+        let features = transport.negotiate(F_RO)?;
+        let queue = transport.setup_queue(0)?;
+        let request_page =
+            unsafe { KernelFrameAllocator.allocate_one() }.map_err(|_| Errno::ENOMEM)?;
+        transport.driver_ok();
+
+        let capacity =
+            u64::from(transport.config_u32(0)) | u64::from(transport.config_u32(4)) << 32;
+        Ok(Self {
+            transport,
+            queue,
+            capacity,
+            read_only: features & F_RO != 0,
+            request_page,
+        })
+    }
+
+    fn request(&mut self, kind: u32, sector: u64, data: &DmaBuffer) -> Result<(), Errno> {
+        let page = self.request_page.as_hhdm_virt();
+        unsafe {
+            page.as_raw_ptr_mut::<RequestHeader>()
+                .write_volatile(RequestHeader {
+                    kind,
+                    reserved: 0,
+                    sector,
+                });
+            page.add_bytes(STATUS_OFFSET)
+                .as_raw_ptr_mut::<u8>()
+                .write_volatile(u8::MAX);
+        }
+
+        let segments = [
+            Segment {
+                phys: self.request_page,
+                len: size_of::<RequestHeader>() as u32,
+                device_writes: false,
+            },
+            Segment {
+                phys: data.phys(),
+                len: data.len() as u32,
+                device_writes: kind == T_IN,
+            },
+            Segment {
+                phys: self.request_page.add_bytes(STATUS_OFFSET),
+                len: 1,
+                device_writes: true,
+            },
+        ];
+        let head = unsafe { self.queue.push(&segments)? };
+        self.transport.notify(&self.queue);
+        while !matches!(self.queue.pop_used(), Some((h, _)) if h == head) {
+            switch();
+        }
+
+        let status = unsafe {
+            page.add_bytes(STATUS_OFFSET)
+                .as_raw_ptr::<u8>()
+                .read_volatile()
+        };
+        match status {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(Errno::EOPNOTSUPP),
+            _ => Err(Errno::EIO),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        for (i, chunk) in buf
+            .chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE)
+            .enumerate()
+        {
+            let sector = sector + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            let data = DmaBuffer::from_device(chunk, &CONSTRAINTS)?;
+            self.request(T_IN, sector, &data)?;
+            data.finish();
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        for (i, chunk) in buf
+            .chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE)
+            .enumerate()
+        {
+            let sector = sector + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            let data = DmaBuffer::to_device(chunk, &CONSTRAINTS)?;
+            self.request(T_OUT, sector, &data)?;
+            data.finish();
+        }
+        Ok(())
+    }
+}
+
+/// Sets up a block device and registers it as `vd<letter>`.
+pub fn init(transport: Transport, index: usize) -> Result<(), Errno> {
+    let name = format!("vd{}", char::from(b'a' + (index % 26) as u8));
+    block::register(name, Box::new(VirtioBlk::new(transport)?))?;
+    Ok(())
+}
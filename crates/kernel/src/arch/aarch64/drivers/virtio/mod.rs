@@ -0,0 +1,312 @@
+//! Virtio devices behind the virtio-mmio transport, as found on QEMU's `virt` machine.
+//!
+//! Each `virtio,mmio` node in the FDT is a transport slot, most of them empty. Slots with a
+//! device we have a driver for are set up and handed to the block or network layer.
+//! Both the legacy (version 1) and modern (version 2) register layouts are supported, since
+//! QEMU defaults to the legacy one.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use fdt::{Fdt, node::FdtNode};
+
+use crate::{
+    arch::driver::Driver,
+    fdt::get_mmio_addr,
+    irq::{Irq, IrqHandler, get_irq, register_irq},
+    mem::units::PhysAddr,
+    syscall::errno::Errno,
+};
+
+use super::{
+    bounce::DmaConstraints,
+    mmio::{Mmio, map_device},
+};
+
+use queue::VirtQueue;
+
+pub mod blk;
+pub mod net;
+pub mod queue;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG: usize = 0x100;
+
+/// "virt", little-endian.
+const MAGIC: u32 = 0x7472_6976;
+const REGS_SIZE: usize = 0x200;
+
+const STATUS_ACKNOWLEDGE: u32 = 1 << 0;
+const STATUS_DRIVER: u32 = 1 << 1;
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+const STATUS_FAILED: u32 = 1 << 7;
+
+/// Set by modern devices, and required of modern drivers.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The largest queue a driver asks for; devices often offer far more than we'd use.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// Virtio devices can reach all of memory.
+pub const CONSTRAINTS: DmaConstraints =
+    DmaConstraints::new(1, PhysAddr::new_canonical(0x000F_FFFF_FFFF_FFFF));
+
+/// The kinds of virtio device, by their device ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Net,
+    Block,
+    Other(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        match id {
+            1 => Self::Net,
+            2 => Self::Block,
+            id => Self::Other(id),
+        }
+    }
+}
+
+/// The registers of a virtio-mmio device.
+#[derive(Debug)]
+pub struct Transport {
+    regs: Mmio<u32>,
+    version: u32,
+    device_type: DeviceType,
+}
+
+impl Transport {
+    /// Checks for a device in the transport slot at `phys`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ENODEV`] if the slot is empty, and [`Errno::EINVAL`] if it isn't a
+    /// virtio-mmio transport at all.
+    pub fn probe(phys: PhysAddr) -> Result<Self, Errno> {
+        let base = map_device(phys, REGS_SIZE).map_err(|_| Errno::ENOMEM)?;
+        let regs = Mmio::<u32>::new(base);
+        let (magic, version, device_id) = unsafe {
+            (
+                regs.read(MAGIC_VALUE),
+                regs.read(VERSION),
+                regs.read(DEVICE_ID),
+            )
+        };
+        if magic != MAGIC || !matches!(version, 1 | 2) {
+            return Err(Errno::EINVAL);
+        }
+        if device_id == 0 {
+            return Err(Errno::ENODEV);
+        }
+        Ok(Self {
+            regs,
+            version,
+            device_type: DeviceType::from(device_id),
+        })
+    }
+
+    /// Returns the kind of device in the slot.
+    #[must_use]
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// Resets the device and agrees on the features both it and the driver support out of
+    /// `wanted`, returning them.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EIO`] if the device rejects the features.
+    pub fn negotiate(&mut self, wanted: u64) -> Result<u64, Errno> {
+        unsafe {
+            self.regs.write(STATUS, 0);
+            self.regs.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+            let mut offered = 0;
+            for sel in 0..2 {
+                self.regs.write(DEVICE_FEATURES_SEL, sel);
+                offered |= u64::from(self.regs.read(DEVICE_FEATURES)) << (32 * sel);
+            }
+            let wanted = if self.is_legacy() {
+                wanted
+            } else {
+                wanted | F_VERSION_1
+            };
+            let accepted = offered & wanted;
+            for sel in 0..2 {
+                self.regs.write(DRIVER_FEATURES_SEL, sel);
+                self.regs
+                    .write(DRIVER_FEATURES, (accepted >> (32 * sel)) as u32);
+            }
+
+            if self.is_legacy() {
+                self.regs.write(GUEST_PAGE_SIZE, queue::USED_ALIGN as u32);
+            } else {
+                self.regs.set(STATUS, STATUS_FEATURES_OK);
+                if self.regs.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                    self.fail();
+                    return Err(Errno::EIO);
+                }
+            }
+            Ok(accepted)
+        }
+    }
+
+    /// Sets up queue `index`, as big as the device allows up to a limit.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ENODEV`] if the device doesn't have the queue, or
+    /// [`Errno::ENOMEM`] if there's no memory for it.
+    pub fn setup_queue(&mut self, index: u16) -> Result<VirtQueue, Errno> {
+        unsafe {
+            self.regs.write(QUEUE_SEL, u32::from(index));
+            let max = self.regs.read(QUEUE_NUM_MAX);
+            if max == 0 {
+                return Err(Errno::ENODEV);
+            }
+            // queue sizes have to be powers of two
+            let size = 1 << max.min(u32::from(MAX_QUEUE_SIZE)).ilog2();
+            let queue = VirtQueue::new(index, size)?;
+            self.regs.write(QUEUE_NUM, u32::from(size));
+
+            if self.is_legacy() {
+                self.regs.write(QUEUE_ALIGN, queue::USED_ALIGN as u32);
+                self.regs.write(
+                    QUEUE_PFN,
+                    (queue.desc_phys().value() / queue::USED_ALIGN) as u32,
+                );
+            } else {
+                let addrs = [
+                    (QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.desc_phys()),
+                    (QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, queue.avail_phys()),
+                    (QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, queue.used_phys()),
+                ];
+                for (low, high, addr) in addrs {
+                    self.regs.write(low, addr.value() as u32);
+                    self.regs.write(high, (addr.value() >> 32) as u32);
+                }
+                self.regs.write(QUEUE_READY, 1);
+            }
+            Ok(queue)
+        }
+    }
+
+    /// Tells the device the driver is ready to go.
+    pub fn driver_ok(&mut self) {
+        unsafe { self.regs.set(STATUS, STATUS_DRIVER_OK) };
+    }
+
+    /// Tells the device setup went wrong.
+    pub fn fail(&mut self) {
+        unsafe { self.regs.set(STATUS, STATUS_FAILED) };
+    }
+
+    /// Tells the device there are new buffers in the given queue.
+    pub fn notify(&mut self, queue: &VirtQueue) {
+        unsafe { self.regs.write(QUEUE_NOTIFY, u32::from(queue.index())) };
+    }
+
+    /// Reads a byte of the device-specific configuration.
+    #[must_use]
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        unsafe { Mmio::<u8>::new(self.regs.addr).read(CONFIG + offset) }
+    }
+
+    /// Reads an aligned word of the device-specific configuration.
+    #[must_use]
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        unsafe { self.regs.read(CONFIG + offset) }
+    }
+
+    /// Registers a handler that acknowledges the device's interrupts, so a level-triggered
+    /// line doesn't keep firing while the driver polls its queues.
+    fn register_irq(&self, irq: Irq) {
+        let handler = InterruptAck {
+            regs: Mmio::new(self.regs.addr),
+        };
+        unsafe { register_irq(irq, handler) };
+    }
+}
+
+struct InterruptAck {
+    regs: Mmio<u32>,
+}
+
+impl IrqHandler for InterruptAck {
+    fn handle_irq(&mut self, _irq: Irq) {
+        unsafe {
+            let status = self.regs.read(INTERRUPT_STATUS);
+            self.regs.write(INTERRUPT_ACK, status);
+        }
+    }
+
+    fn is_pending(&self, _irq: Irq) -> bool {
+        unsafe { self.regs.read(INTERRUPT_STATUS) != 0 }
+    }
+}
+
+/// The virtio-mmio transport's driver, which binds to every slot with a device in it.
+pub static DRIVER: Driver = Driver {
+    name: "virtio-mmio",
+    compatible: &["virtio,mmio"],
+    depends_on: &[],
+    probe,
+};
+
+/// Probes a virtio-mmio slot and sets up the device in it.
+///
+/// Returns [`Errno::ENODEV`] if the slot is empty.
+fn probe(fdt: &Fdt, node: &FdtNode) -> Result<(), Errno> {
+    static BLK_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static NET_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let phys = node
+        .reg()
+        .and_then(|mut r| r.next())
+        .and_then(|region| get_mmio_addr(fdt, &region))
+        .ok_or(Errno::EINVAL)?;
+
+    let transport = Transport::probe(phys)?;
+    if let Some(irq) = get_irq(fdt, node, 0) {
+        transport.register_irq(irq);
+    }
+
+    match transport.device_type() {
+        DeviceType::Block => blk::init(transport, BLK_COUNT.fetch_add(1, Ordering::Relaxed)),
+        DeviceType::Net => net::init(transport, NET_COUNT.fetch_add(1, Ordering::Relaxed)),
+        DeviceType::Other(id) => {
+            log::debug!("{}: no driver for virtio device {id}", node.name);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! The virtio network device driver.
+
+use alloc::{boxed::Box, format, vec, vec::Vec};
+
+use crate::{
+    mem::{
+        paging::allocator::KernelFrameAllocator,
+        units::{FrameCount, PhysAddr},
+    },
+    net::{self, NetDevice},
+    syscall::errno::Errno,
+    task::switch::switch,
+};
+
+use super::{
+    F_VERSION_1, Transport,
+    queue::{Segment, VirtQueue},
+};
+
+const F_MAC: u64 = 1 << 5;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// The largest Ethernet frame sent or received, without a frame check sequence.
+const MAX_FRAME_LEN: usize = 1514;
+/// The size of each packet buffer, big enough for a header and the largest frame.
+const BUFFER_SIZE: usize = 2048;
+const RX_BUFFERS: usize = 16;
+
+/// What's used when the device doesn't have a MAC address of its own: QEMU's default.
+const FALLBACK_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// A virtio network device.
+pub struct VirtioNet {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    mac: [u8; 6],
+    /// The length of the header in front of every packet, which grew a field in version 1.
+    header_len: usize,
+    rx_buffers: PhysAddr,
+    /// Which receive buffer each descriptor the device has is for.
+    rx_slots: Vec<Option<usize>>,
+    tx_buffer: PhysAddr,
+}
+
+impl VirtioNet {
+    /// Sets up the network device behind `transport`.
+    pub fn new(mut transport: Transport) -> Result<Self, Errno> {
+        let features = transport.negotiate(F_MAC)?;
+        let rx = transport.setup_queue(RX_QUEUE)?;
+        let tx = transport.setup_queue(TX_QUEUE)?;
+        let rx_buffers = unsafe {
+            KernelFrameAllocator.allocate(FrameCount::from_bytes(RX_BUFFERS * BUFFER_SIZE))
+        }
+        .map_err(|_| Errno::ENOMEM)?;
+        let tx_buffer =
+            unsafe { KernelFrameAllocator.allocate_one() }.map_err(|_| Errno::ENOMEM)?;
+
+        let mac = if features & F_MAC == 0 {
+            FALLBACK_MAC
+        } else {
+            core::array::from_fn(|i| transport.config_u8(i))
+        };
+        let rx_slots = vec![None; rx.size() as usize];
+
+        let mut this = Self {
+            transport,
+            rx,
+            tx,
+            mac,
+            header_len: if features & F_VERSION_1 == 0 { 10 } else { 12 },
+            rx_buffers,
+            rx_slots,
+            tx_buffer,
+        };
+        for slot in 0..RX_BUFFERS.min(this.rx.size() as usize) {
+            this.post_rx(slot)?;
+        }
+        this.transport.driver_ok();
+        this.transport.notify(&this.rx);
+        Ok(this)
+    }
+
+    fn rx_buffer(&self, slot: usize) -> PhysAddr {
+        self.rx_buffers.add_bytes(slot * BUFFER_SIZE)
+    }
+
+    /// Hands receive buffer `slot` to the device.
+    fn post_rx(&mut self, slot: usize) -> Result<(), Errno> {
+        let segment = Segment {
+            phys: self.rx_buffer(slot),
+            len: BUFFER_SIZE as u32,
+            device_writes: true,
+        };
+        let head = unsafe { self.rx.push(&[segment])? };
+        self.rx_slots[head as usize] = Some(slot);
+        Ok(())
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn max_frame_len(&self) -> usize {
+        MAX_FRAME_LEN
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), Errno> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(Errno::EMSGSIZE);
+        }
+        let buf = self.tx_buffer.as_hhdm_virt();
+        unsafe {
+            // no offloads were negotiated, so the header is all zeroes
+            buf.fill(0, self.header_len).map_err(|_| Errno::EFAULT)?;
+            buf.add_bytes(self.header_len)
+                .write_bytes(frame)
+                .map_err(|_| Errno::EFAULT)?;
+        }
+
+        let segment = Segment {
+            phys: self.tx_buffer,
+            len: (self.header_len + frame.len()) as u32,
+            device_writes: false,
+        };
+        let head = unsafe { self.tx.push(&[segment])? };
+        self.transport.notify(&self.tx);
+        while !matches!(self.tx.pop_used(), Some((h, _)) if h == head) {
+            switch();
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Errno> {
+        let Some((head, len)) = self.rx.pop_used() else {
+            return Ok(None);
+        };
+        let slot = self.rx_slots[head as usize].take().ok_or(Errno::EIO)?;
+        let len = (len as usize).saturating_sub(self.header_len);
+
+        let result = if len > buf.len() {
+            Err(Errno::EMSGSIZE)
+        } else {
+            let frame = self
+                .rx_buffer(slot)
+                .as_hhdm_virt()
+                .add_bytes(self.header_len);
+            unsafe { frame.read_bytes(&mut buf[..len]) }
+                .map(|_| Some(len))
+                .map_err(|_| Errno::EFAULT)
+        };
+
+        self.post_rx(slot)?;
+        self.transport.notify(&self.rx);
+        result
+    }
+}
+
+/// Sets up a network device and registers it as `eth<index>`.
+pub fn init(transport: Transport, index: usize) -> Result<(), Errno> {
+    net::register(format!("eth{index}"), Box::new(VirtioNet::new(transport)?))?;
+    Ok(())
+}
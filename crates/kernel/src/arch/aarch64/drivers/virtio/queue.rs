@@ -0,0 +1,219 @@
+//! The split virtqueue: a descriptor table, the driver's available ring, and the device's
+//! used ring, laid out the way legacy devices need them so the same queue works for both.
+
+use core::sync::atomic::{Ordering, fence};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{AArch64, Architecture},
+    mem::{
+        paging::allocator::KernelFrameAllocator,
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
+    syscall::errno::Errno,
+};
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// The alignment of the used ring, which legacy devices are told with `QueueAlign`.
+pub const USED_ALIGN: usize = AArch64::PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer in a request chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub phys: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer rather than reading from it.
+    pub device_writes: bool,
+}
+
+/// A split virtqueue and the memory it lives in.
+///
+/// The memory is never freed: once a device has been told where a queue is, there's no
+/// telling when it stops looking.
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    phys: PhysAddr,
+    free: Vec<u16>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// the queue's memory is owned by the queue and only touched through it
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocates and zeroes a queue with `size` descriptors.
+    pub fn new(index: u16, size: u16) -> Result<Self, Errno> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(Errno::EINVAL);
+        }
+        let frames = FrameCount::from_bytes(Self::used_offset(size) + Self::used_len(size));
+        let phys = unsafe { KernelFrameAllocator.allocate(frames) }.map_err(|_| Errno::ENOMEM)?;
+        unsafe {
+            phys.as_hhdm_virt()
+                .fill(0, frames.to_bytes())
+                .map_err(|_| Errno::EFAULT)?;
+        }
+
+        Ok(Self {
+            index,
+            size,
+            phys,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    const fn avail_offset(size: u16) -> usize {
+        size_of::<Descriptor>() * size as usize
+    }
+
+    const fn used_offset(size: u16) -> usize {
+        // flags, idx, the ring, and used_event
+        (Self::avail_offset(size) + 6 + 2 * size as usize).next_multiple_of(USED_ALIGN)
+    }
+
+    const fn used_len(size: u16) -> usize {
+        // flags, idx, the ring of (id, len), and avail_event
+        6 + 8 * size as usize
+    }
+
+    /// Returns the queue's index on its device.
+    #[must_use]
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the number of descriptors in the queue.
+    #[must_use]
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the physical address of the descriptor table, where the queue starts.
+    #[must_use]
+    pub fn desc_phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the physical address of the available ring.
+    #[must_use]
+    pub fn avail_phys(&self) -> PhysAddr {
+        self.phys.add_bytes(Self::avail_offset(self.size))
+    }
+
+    /// Returns the physical address of the used ring.
+    #[must_use]
+    pub fn used_phys(&self) -> PhysAddr {
+        self.phys.add_bytes(Self::used_offset(self.size))
+    }
+
+    fn virt(&self, offset: usize) -> VirtAddr {
+        self.phys.as_hhdm_virt().add_bytes(offset)
+    }
+
+    fn desc(&self, idx: u16) -> *mut Descriptor {
+        self.virt(size_of::<Descriptor>() * idx as usize)
+            .as_raw_ptr_mut()
+    }
+
+    /// Makes a chain of the given buffers available to the device, returning the index of
+    /// its head descriptor.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EAGAIN`] if there aren't enough free descriptors.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay valid until the chain comes back from [`VirtQueue::pop_used`].
+    pub unsafe fn push(&mut self, segments: &[Segment]) -> Result<u16, Errno> {
+        if segments.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+        if segments.len() > self.free.len() {
+            return Err(Errno::EAGAIN);
+        }
+
+        let idxs = self.free.split_off(self.free.len() - segments.len());
+        for (i, (segment, &idx)) in segments.iter().zip(&idxs).enumerate() {
+            let next = idxs.get(i + 1).copied();
+            let mut flags = 0;
+            if next.is_some() {
+                flags |= DESC_F_NEXT;
+            }
+            if segment.device_writes {
+                flags |= DESC_F_WRITE;
+            }
+            let desc = Descriptor {
+                addr: segment.phys.value() as u64,
+                len: segment.len,
+                flags,
+                next: next.unwrap_or(0),
+            };
+            unsafe { self.desc(idx).write_volatile(desc) };
+        }
+
+        let head = idxs[0];
+        let avail = Self::avail_offset(self.size);
+        let slot = 4 + 2 * (self.avail_idx % self.size) as usize;
+        unsafe {
+            self.virt(avail + slot)
+                .as_raw_ptr_mut::<u16>()
+                .write_volatile(head);
+        }
+        // the descriptors and ring entry have to be visible before the index moves
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe {
+            self.virt(avail + 2)
+                .as_raw_ptr_mut::<u16>()
+                .write_volatile(self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Takes the next chain the device is done with, returning its head descriptor and how
+    /// many bytes the device wrote to it, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = Self::used_offset(self.size);
+        let used_idx = unsafe { self.virt(used + 2).as_raw_ptr::<u16>().read_volatile() };
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = 4 + 8 * (self.last_used % self.size) as usize;
+        let elem = self.virt(used + slot);
+        let head = unsafe { elem.as_raw_ptr::<u32>().read_volatile() } as u16;
+        let len = unsafe { elem.add_bytes(4).as_raw_ptr::<u32>().read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut idx = head;
+        loop {
+            self.free.push(idx);
+            let desc = unsafe { self.desc(idx).read_volatile() };
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            idx = desc.next;
+        }
+        Some((head, len))
+    }
+}
@@ -0,0 +1,88 @@
+//! A write-only serial console for when nothing else can be trusted.
+//!
+//! It drives the PL011 the boot stages reported on, at the address the board feature gives it
+//! at compile time, so it needs nothing from the FDT, the heap or the boot info, and takes no
+//! locks. That makes it usable from the first instruction of `kernel_main`, and from a panic
+//! that struck while the console driver, the heap or a lock was in a bad state. Output from
+//! several CPUs at once can interleave.
+//!
+//! Its registers are reached through the bootloader's identity map until [`use_hhdm`] moves
+//! it to the HHDM, which the kernel's page tables map the UART into.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{HHDM_PHYSICAL_OFFSET, mem::units::PhysAddr};
+
+/// The physical address of the UART's registers, for the board the kernel was built for.
+pub const UART_BASE: usize = kados_early_uart::UART_BASE;
+
+const DR: usize = 0x00;
+const FR: usize = 0x18;
+
+const FR_TXFF: u32 = 1 << 5;
+
+/// How many times to check for room in the FIFO before giving up on a byte, so a UART that's
+/// gone away can't hang a panic.
+const TX_SPINS: usize = 1 << 20;
+
+/// The virtual offset the UART is currently reachable at.
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the physical address of the UART's registers.
+#[must_use]
+pub fn uart_phys() -> PhysAddr {
+    PhysAddr::new_canonical(UART_BASE)
+}
+
+/// Sends a byte, or drops it if the FIFO stays full for too long.
+pub fn putchar(c: u8) {
+    let base = UART_BASE + OFFSET.load(Ordering::Relaxed);
+    let fr = (base + FR) as *const u32;
+    let dr = (base + DR) as *mut u32;
+    unsafe {
+        for _ in 0..TX_SPINS {
+            if fr.read_volatile() & FR_TXFF == 0 {
+                dr.write_volatile(u32::from(c));
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Sends every byte of `bytes`, with a carriage return before each newline.
+pub fn write_bytes(bytes: &[u8]) {
+    for &b in bytes {
+        if b == b'\n' {
+            putchar(b'\r');
+        }
+        putchar(b);
+    }
+}
+
+/// Writes to the early console.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EarlyCon;
+
+impl Write for EarlyCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes a formatted string to the early console.
+pub fn write_fmt(args: fmt::Arguments) {
+    EarlyCon.write_fmt(args).ok();
+}
+
+/// Switches to reaching the UART through the HHDM.
+///
+/// Must be called after the kernel's page tables (which map the UART in the HHDM) are made
+/// current, and before the bootloader's identity mapping is torn down.
+pub fn use_hhdm() {
+    OFFSET.store(HHDM_PHYSICAL_OFFSET, Ordering::Release);
+}
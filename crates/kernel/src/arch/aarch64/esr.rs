@@ -0,0 +1,290 @@
+//! Decoding of `ESR_EL1`, the syndrome the CPU records for every synchronous exception and
+//! `SError` taken to EL1.
+//!
+//! The top bits hold the exception class, which says what kind of exception it was; the
+//! instruction-specific syndrome (ISS) below them means something different for each class.
+//! Only the classes the kernel handles or can expect to see are named, and only aborts have
+//! their ISS picked apart, since they're the ones worth explaining.
+//!
+//! [`Esr`]'s `Display` is the one-line explanation used in fault reports, e.g.
+//! `data abort from EL0: write, translation fault at level 3`.
+
+use core::fmt;
+
+/// The exception class field, `ESR_EL1.EC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Unknown,
+    WfiWfe,
+    FpAccess,
+    IllegalState,
+    Svc64,
+    SysReg,
+    InstrAbortLower,
+    InstrAbortSame,
+    PcAlignment,
+    DataAbortLower,
+    DataAbortSame,
+    SpAlignment,
+    Fp64,
+    SError,
+    BreakpointLower,
+    BreakpointSame,
+    StepLower,
+    StepSame,
+    WatchpointLower,
+    WatchpointSame,
+    Brk64,
+    /// A class the kernel has no name for.
+    Other(u8),
+}
+
+impl ExceptionClass {
+    #[must_use]
+    pub fn from_raw(ec: u8) -> Self {
+        match ec {
+            0x00 => Self::Unknown,
+            0x01 => Self::WfiWfe,
+            0x07 => Self::FpAccess,
+            0x0e => Self::IllegalState,
+            0x15 => Self::Svc64,
+            0x18 => Self::SysReg,
+            0x20 => Self::InstrAbortLower,
+            0x21 => Self::InstrAbortSame,
+            0x22 => Self::PcAlignment,
+            0x24 => Self::DataAbortLower,
+            0x25 => Self::DataAbortSame,
+            0x26 => Self::SpAlignment,
+            0x2c => Self::Fp64,
+            0x2f => Self::SError,
+            0x30 => Self::BreakpointLower,
+            0x31 => Self::BreakpointSame,
+            0x32 => Self::StepLower,
+            0x33 => Self::StepSame,
+            0x34 => Self::WatchpointLower,
+            0x35 => Self::WatchpointSame,
+            0x3c => Self::Brk64,
+            ec => Self::Other(ec),
+        }
+    }
+
+    /// Returns `true` for instruction and data aborts, whose ISS is decoded by
+    /// [`Esr::abort`].
+    #[must_use]
+    pub fn is_abort(self) -> bool {
+        matches!(
+            self,
+            Self::InstrAbortLower
+                | Self::InstrAbortSame
+                | Self::DataAbortLower
+                | Self::DataAbortSame
+        )
+    }
+
+    /// Returns a short description, such as "data abort from EL0".
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown reason",
+            Self::WfiWfe => "trapped WFI/WFE",
+            Self::FpAccess => "trapped FP/SIMD access",
+            Self::IllegalState => "illegal execution state",
+            Self::Svc64 => "supervisor call",
+            Self::SysReg => "trapped system register access",
+            Self::InstrAbortLower => "instruction abort from EL0",
+            Self::InstrAbortSame => "instruction abort from EL1",
+            Self::PcAlignment => "PC alignment fault",
+            Self::DataAbortLower => "data abort from EL0",
+            Self::DataAbortSame => "data abort from EL1",
+            Self::SpAlignment => "SP alignment fault",
+            Self::Fp64 => "floating-point exception",
+            Self::SError => "SError",
+            Self::BreakpointLower => "hardware breakpoint from EL0",
+            Self::BreakpointSame => "hardware breakpoint from EL1",
+            Self::StepLower => "software step from EL0",
+            Self::StepSame => "software step from EL1",
+            Self::WatchpointLower => "watchpoint from EL0",
+            Self::WatchpointSame => "watchpoint from EL1",
+            Self::Brk64 => "breakpoint instruction",
+            Self::Other(_) => "unhandled exception",
+        }
+    }
+}
+
+/// The fault status code of an abort, `ISS.DFSC` or `ISS.IFSC`. Where a fault happened
+/// during a table walk, the level of the table is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    SyncExternal,
+    SyncExternalWalk(u8),
+    Parity,
+    ParityWalk(u8),
+    Alignment,
+    TlbConflict,
+    UnsupportedAtomic,
+    /// A code the kernel has no name for.
+    Other(u8),
+}
+
+impl FaultStatus {
+    #[must_use]
+    pub fn from_raw(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc {
+            0b00_0000..=0b00_0011 => Self::AddressSize(level),
+            0b00_0100..=0b00_0111 => Self::Translation(level),
+            0b00_1000..=0b00_1011 => Self::AccessFlag(level),
+            0b00_1100..=0b00_1111 => Self::Permission(level),
+            0b01_0000 => Self::SyncExternal,
+            0b01_0100..=0b01_0111 => Self::SyncExternalWalk(level),
+            0b01_1000 => Self::Parity,
+            0b01_1100..=0b01_1111 => Self::ParityWalk(level),
+            0b10_0001 => Self::Alignment,
+            0b11_0000 => Self::TlbConflict,
+            0b11_0001 => Self::UnsupportedAtomic,
+            fsc => Self::Other(fsc),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::AddressSize(level) => write!(f, "address size fault at level {level}"),
+            Self::Translation(level) => write!(f, "translation fault at level {level}"),
+            Self::AccessFlag(level) => write!(f, "access flag fault at level {level}"),
+            Self::Permission(level) => write!(f, "permission fault at level {level}"),
+            Self::SyncExternal => write!(f, "synchronous external abort"),
+            Self::SyncExternalWalk(level) => {
+                write!(f, "synchronous external abort walking level {level}")
+            }
+            Self::Parity => write!(f, "parity or ECC error"),
+            Self::ParityWalk(level) => write!(f, "parity or ECC error walking level {level}"),
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::TlbConflict => write!(f, "TLB conflict abort"),
+            Self::UnsupportedAtomic => write!(f, "unsupported atomic hardware update"),
+            Self::Other(fsc) => write!(f, "fault status {fsc:#08b}"),
+        }
+    }
+}
+
+/// The ISS of an instruction or data abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // they're the syndrome's own bits
+pub struct Abort {
+    pub status: FaultStatus,
+    /// The abort was on an instruction fetch, rather than a data access.
+    pub fetch: bool,
+    /// `WnR`: the access was a write. Always `false` for instruction aborts.
+    pub write: bool,
+    /// `S1PTW`: the fault was on a stage 2 translation of a stage 1 table walk.
+    pub s1ptw: bool,
+    /// `EA`: the external abort type, implementation defined.
+    pub ea: bool,
+    /// `CM`: the fault came from a cache maintenance instruction. Always `false` for
+    /// instruction aborts.
+    pub cache_maintenance: bool,
+    /// `FnV` clear: `FAR_EL1` holds the faulting address.
+    pub far_valid: bool,
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match (self.fetch, self.write) {
+            (true, _) => "fetch",
+            (false, true) => "write",
+            (false, false) => "read",
+        };
+        f.write_str(access)?;
+        if self.cache_maintenance {
+            write!(f, " by cache maintenance")?;
+        }
+        write!(f, ", {}", self.status)?;
+        if self.s1ptw {
+            write!(f, " on a stage 1 table walk")?;
+        }
+        if self.ea {
+            write!(f, ", external")?;
+        }
+        if !self.far_valid {
+            write!(f, ", FAR invalid")?;
+        }
+        Ok(())
+    }
+}
+
+/// A raw `ESR_EL1` value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub usize);
+
+impl Esr {
+    #[must_use]
+    pub fn class(self) -> ExceptionClass {
+        ExceptionClass::from_raw(((self.0 >> 26) & 0x3f) as u8)
+    }
+
+    /// Returns the instruction-specific syndrome, `ESR_EL1.ISS`.
+    #[must_use]
+    pub fn iss(self) -> u32 {
+        (self.0 & 0x01ff_ffff) as u32
+    }
+
+    /// Returns `true` if the trapped instruction was 32 bits long, rather than a 16-bit `T32`
+    /// instruction. Always `true` for exceptions from 64-bit code.
+    #[must_use]
+    pub fn il(self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    /// Returns the immediate of the `svc`, `hvc` or `brk` that caused the exception.
+    #[must_use]
+    pub fn imm16(self) -> u16 {
+        (self.iss() & 0xffff) as u16
+    }
+
+    /// Decodes the ISS of an instruction or data abort, returning `None` for other classes.
+    #[must_use]
+    pub fn abort(self) -> Option<Abort> {
+        let class = self.class();
+        if !class.is_abort() {
+            return None;
+        }
+        let data = matches!(
+            class,
+            ExceptionClass::DataAbortLower | ExceptionClass::DataAbortSame
+        );
+        let iss = self.iss();
+        let bit = |n: u32| iss & (1 << n) != 0;
+        Some(Abort {
+            status: FaultStatus::from_raw((iss & 0x3f) as u8),
+            fetch: !data,
+            write: data && bit(6),
+            s1ptw: bit(7),
+            cache_maintenance: data && bit(8),
+            ea: bit(9),
+            far_valid: !bit(10),
+        })
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = self.class();
+        write!(f, "{}", class.description())?;
+        if let Some(abort) = self.abort() {
+            write!(f, ": {abort}")?;
+        }
+        match class {
+            ExceptionClass::Svc64 | ExceptionClass::Brk64 => {
+                write!(f, " #{:#x}", self.imm16())?;
+            }
+            ExceptionClass::Other(ec) => write!(f, " (EC {ec:#x})")?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
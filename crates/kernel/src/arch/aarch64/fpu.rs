@@ -0,0 +1,137 @@
+//! Lazy switching of the FP/SIMD registers, which only user tasks use.
+//!
+//! The kernel is built without FP/SIMD, so the registers only ever hold a user task's values.
+//! Using them traps (through `CPACR_EL1.FPEN`) until the running task first does, when the trap
+//! loads the task's saved registers and lets it carry on. Only a task that used them since it
+//! was switched in has them saved when it's switched out, after which they trap again.
+
+use core::{arch::asm, fmt, mem::offset_of};
+
+/// Where `CPACR_EL1` keeps `FPEN`. `0b11` lets FP/SIMD through at EL0 and EL1; `0b00` traps it
+/// at both.
+const CPACR_FPEN_SHIFT: u64 = 20;
+const CPACR_FPEN_MASK: u64 = 0b11 << CPACR_FPEN_SHIFT;
+
+/// A task's FP/SIMD registers, as [`save`] and [`restore`] lay them out.
+#[derive(Clone, Default)]
+#[repr(C, align(16))]
+pub struct FpState {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl fmt::Debug for FpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FpState")
+            .field("fpcr", &self.fpcr)
+            .field("fpsr", &self.fpsr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Makes FP/SIMD trap until a task uses it.
+pub fn init() {
+    set_enabled(false);
+}
+
+/// Returns `true` if FP/SIMD is let through, which is to say the running task has used it
+/// since it was switched in.
+#[must_use]
+pub fn is_enabled() -> bool {
+    let cpacr: u64;
+    unsafe { asm!("mrs {}, cpacr_el1", out(reg) cpacr) };
+    cpacr & CPACR_FPEN_MASK == CPACR_FPEN_MASK
+}
+
+/// Lets FP/SIMD through, or makes it trap.
+pub fn set_enabled(enabled: bool) {
+    unsafe {
+        let mut cpacr: u64;
+        asm!("mrs {}, cpacr_el1", out(reg) cpacr);
+        cpacr &= !CPACR_FPEN_MASK;
+        if enabled {
+            cpacr |= CPACR_FPEN_MASK;
+        }
+        asm!("msr cpacr_el1, {}", "isb", in(reg) cpacr);
+    }
+}
+
+/// Saves the FP/SIMD registers to `state`.
+///
+/// # Safety
+///
+/// FP/SIMD must be enabled.
+pub unsafe fn save(state: &mut FpState) {
+    unsafe {
+        asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "stp q0, q1, [{state}, #0]",
+            "stp q2, q3, [{state}, #32]",
+            "stp q4, q5, [{state}, #64]",
+            "stp q6, q7, [{state}, #96]",
+            "stp q8, q9, [{state}, #128]",
+            "stp q10, q11, [{state}, #160]",
+            "stp q12, q13, [{state}, #192]",
+            "stp q14, q15, [{state}, #224]",
+            "stp q16, q17, [{state}, #256]",
+            "stp q18, q19, [{state}, #288]",
+            "stp q20, q21, [{state}, #320]",
+            "stp q22, q23, [{state}, #352]",
+            "stp q24, q25, [{state}, #384]",
+            "stp q26, q27, [{state}, #416]",
+            "stp q28, q29, [{state}, #448]",
+            "stp q30, q31, [{state}, #480]",
+            "mrs {tmp}, fpcr",
+            "str {tmp}, [{state}, #{fpcr}]",
+            "mrs {tmp}, fpsr",
+            "str {tmp}, [{state}, #{fpsr}]",
+            state = in(reg) &raw mut *state,
+            tmp = out(reg) _,
+            fpcr = const offset_of!(FpState, fpcr),
+            fpsr = const offset_of!(FpState, fpsr),
+            options(nostack),
+        );
+    }
+}
+
+/// Loads the FP/SIMD registers from `state`.
+///
+/// # Safety
+///
+/// FP/SIMD must be enabled.
+pub unsafe fn restore(state: &FpState) {
+    // nothing in the kernel uses these registers, so there's nothing to tell the compiler
+    unsafe {
+        asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "ldp q0, q1, [{state}, #0]",
+            "ldp q2, q3, [{state}, #32]",
+            "ldp q4, q5, [{state}, #64]",
+            "ldp q6, q7, [{state}, #96]",
+            "ldp q8, q9, [{state}, #128]",
+            "ldp q10, q11, [{state}, #160]",
+            "ldp q12, q13, [{state}, #192]",
+            "ldp q14, q15, [{state}, #224]",
+            "ldp q16, q17, [{state}, #256]",
+            "ldp q18, q19, [{state}, #288]",
+            "ldp q20, q21, [{state}, #320]",
+            "ldp q22, q23, [{state}, #352]",
+            "ldp q24, q25, [{state}, #384]",
+            "ldp q26, q27, [{state}, #416]",
+            "ldp q28, q29, [{state}, #448]",
+            "ldp q30, q31, [{state}, #480]",
+            "ldr {tmp}, [{state}, #{fpcr}]",
+            "msr fpcr, {tmp}",
+            "ldr {tmp}, [{state}, #{fpsr}]",
+            "msr fpsr, {tmp}",
+            state = in(reg) &raw const *state,
+            tmp = out(reg) _,
+            fpcr = const offset_of!(FpState, fpcr),
+            fpsr = const offset_of!(FpState, fpsr),
+            options(nostack, readonly),
+        );
+    }
+}
@@ -4,26 +4,36 @@ use fdt::Fdt;
 
 use crate::{
     fdt::get_mmio_addr,
-    irq::{Irq, IrqCell, IrqChip, IrqHandler, IrqHandlerDescriptor},
+    irq::{
+        Irq, IrqCell, IrqChip, IrqHandler, IrqHandlerDescriptor, PRIORITY_DEFAULT,
+        PRIORITY_MASK_NONE,
+    },
     mem::units::{PhysAddr, VirtAddr},
     syscall::errno::Errno,
 };
 
-use super::drivers::mmio::Mmio;
-
-const GICD_CTLR: usize = 0x000;
-const GICD_TYPER: usize = 0x004;
-const GICD_ISENABLER: usize = 0x100;
-const GICD_ISPENDR: usize = 0x200;
-const GICD_ICENABLER: usize = 0x180;
-const GICD_IPRIORITY: usize = 0x400;
-const GICD_ITARGETSR: usize = 0x800;
-const GICD_ICFGR: usize = 0xc00;
-
-const GICC_EOIR: usize = 0x0010;
-const GICC_IAR: usize = 0x000c;
-const GICC_CTLR: usize = 0x0000;
-const GICC_PMR: usize = 0x0004;
+use super::drivers::mmio::{Register, RegisterArray, RegisterBlock};
+
+const GICD_CTLR: Register<u32> = Register::new(0x000);
+const GICD_TYPER: Register<u32> = Register::new(0x004);
+/// One bit per IRQ.
+const GICD_ISENABLER: RegisterArray<u32> = RegisterArray::new(0x100);
+const GICD_ICENABLER: RegisterArray<u32> = RegisterArray::new(0x180);
+const GICD_ISPENDR: RegisterArray<u32> = RegisterArray::new(0x200);
+/// One byte per IRQ.
+const GICD_IPRIORITYR: RegisterArray<u8> = RegisterArray::new(0x400);
+const GICD_ITARGETSR: RegisterArray<u8> = RegisterArray::new(0x800);
+/// Two bits per IRQ.
+const GICD_ICFGR: RegisterArray<u32> = RegisterArray::new(0xc00);
+const GICD_SGIR: Register<u32> = Register::new(0xf00);
+
+/// The SGI other CPUs are interrupted with.
+const IPI_SGI: u32 = 0;
+
+const GICC_CTLR: Register<u32> = Register::new(0x0000);
+const GICC_PMR: Register<u32> = Register::new(0x0004);
+const GICC_IAR: Register<u32> = Register::new(0x000c);
+const GICC_EOIR: Register<u32> = Register::new(0x0010);
 
 /// The physical addresses of the GIC distributor and CPU interface.
 #[derive(Clone, Copy, Debug, Default)]
@@ -131,6 +141,14 @@ impl IrqChip for Gic {
         unsafe { self.dist.disable_irq(irq) }
     }
 
+    fn set_priority(&mut self, irq: Irq, priority: u8) {
+        unsafe { self.dist.set_priority(irq, priority) }
+    }
+
+    fn set_priority_mask(&mut self, mask: u8) {
+        unsafe { self.cpu.set_priority_mask(mask) }
+    }
+
     fn translate_irq(&self, irq_data: IrqCell) -> Option<Irq> {
         let off = match irq_data {
             IrqCell::L3(0, irq, _flags) => irq as usize,
@@ -144,6 +162,17 @@ impl IrqChip for Gic {
         unsafe { self.dist.manual_irq(irq) }
     }
 
+    fn ipi(&self) -> Option<Irq> {
+        Some(Irq::from(IPI_SGI))
+    }
+
+    fn send_ipi(&mut self, cpu: usize) {
+        // the target list only has the first eight CPU interfaces
+        if cpu < 8 {
+            unsafe { self.dist.regs.write(GICD_SGIR, (1 << (16 + cpu)) | IPI_SGI) };
+        }
+    }
+
     fn is_irq_pending(&self, irq: Irq) -> bool {
         unsafe { self.dist.is_irq_pending(irq) }
     }
@@ -152,8 +181,8 @@ impl IrqChip for Gic {
 /// The GIC distributor structure.
 #[derive(Debug, Default)]
 pub struct GicDist {
-    /// The MMIO base address for the GIC distributor.
-    pub base: Mmio<u32>,
+    /// The GIC distributor's registers.
+    pub regs: RegisterBlock,
     /// The number of IRQs supported by the GIC distributor.
     pub num_irqs: u32,
 }
@@ -161,25 +190,29 @@ pub struct GicDist {
 impl GicDist {
     /// Initializes the GIC distributor with the given MMIO address.
     pub unsafe fn init(&mut self, addr: VirtAddr) {
-        self.base.addr = addr;
+        self.regs.addr = addr;
 
         unsafe {
-            self.base.write_assert(GICD_CTLR, 0);
+            self.regs.write_assert(GICD_CTLR, 0);
 
-            let typer = self.base.read(GICD_TYPER);
+            let typer = self.regs.read(GICD_TYPER);
             let num_cpus = ((typer & (0x7 << 5)) >> 5) + 1;
             let num_irqs = ((typer & 0x1f) + 1) * 32;
             log::debug!("GIC_DIST supports {} CPUs and {} IRQs", num_cpus, num_irqs);
             self.num_irqs = num_irqs;
 
+            for irq in 0..num_irqs as usize {
+                self.set_priority(Irq::from(irq as u32), PRIORITY_DEFAULT);
+            }
+
             // let bit = 1 << ((irq as u32 % 16) * 2 + 1);
-            // self.base.write_assert(off, bit); // level-trigger
+            // self.regs.write_assert(off, bit); // level-trigger
 
             // for irq in 0..num_irqs as usize {
 
             // }
 
-            self.base.write_assert(GICD_CTLR, 1 << 0);
+            self.regs.write_assert(GICD_CTLR, 1 << 0);
         }
     }
 
@@ -188,51 +221,48 @@ impl GicDist {
         let irq = irq.as_usize();
         log::debug!("enabling IRQ {irq} in ISENABLER");
         if irq > 31 {
-            let ext_off = GICD_ITARGETSR + ((irq / 4) * 4);
-            let int_off = (irq % 4) * 8;
-            unsafe { self.base.set(ext_off, 1 << int_off) }; // target cpu 0
+            unsafe { self.regs.set(GICD_ITARGETSR.at(irq), 1) }; // target cpu 0
         }
 
-        let ext_off = GICD_IPRIORITY + ((irq / 4) * 4);
-        let int_off = (irq % 4) * 8;
-        unsafe { self.base.set(ext_off, 0xa0 << int_off) }; // priority
-
-        let off = GICD_ICFGR + ((irq / 16) * 4);
         let bit = 0b11 << ((irq as u32 % 16) * 2);
-        unsafe { self.base.clear(off, bit) }; // edge-trigger
+        unsafe { self.regs.clear(GICD_ICFGR.at(irq / 16), bit) }; // edge-trigger
 
-        let off = GICD_ISENABLER + ((irq / 32) * 4);
         let bit = 1 << (irq % 32);
         unsafe {
-            self.base.set_assert(off, bit); // enable
+            self.regs.set_assert(GICD_ISENABLER.at(irq / 32), bit); // enable
         }
     }
 
+    /// Sets the priority of the given IRQ in the GIC distributor.
+    pub unsafe fn set_priority(&mut self, irq: Irq, priority: u8) {
+        let irq = irq.as_usize();
+        unsafe { self.regs.write(GICD_IPRIORITYR.at(irq), priority) };
+    }
+
     /// Checks if the given IRQ is pending in the GIC distributor.
     #[must_use]
     pub unsafe fn is_irq_pending(&self, irq: Irq) -> bool {
-        let off = GICD_ISPENDR + ((irq.as_usize() / 32) * 4);
         let bit = 1 << (irq.as_usize() % 32);
-        unsafe { self.base.read(off) & bit == bit }
+        unsafe { self.regs.read(GICD_ISPENDR.at(irq.as_usize() / 32)) & bit == bit }
     }
 
     /// Disables the given IRQ in the GIC distributor.
     pub unsafe fn disable_irq(&mut self, irq: Irq) {
         log::debug!("disabling IRQ {irq} in ICENABLER");
-        let off = GICD_ICENABLER + ((irq.as_usize() / 32) * 4);
         let bit = 1 << (irq.as_usize() % 32);
         unsafe {
-            self.base.write_assert(off, bit);
+            self.regs
+                .write_assert(GICD_ICENABLER.at(irq.as_usize() / 32), bit);
         }
     }
 
     /// Manually triggers the given IRQ in the GIC distributor.
     pub unsafe fn manual_irq(&mut self, irq: Irq) {
         log::debug!("manually triggering IRQ {irq} in ISPENDR");
-        let off = GICD_ISPENDR + ((irq.as_usize() / 32) * 4);
         let bit = 1 << (irq.as_usize() % 32);
         unsafe {
-            self.base.write_assert(off, bit);
+            self.regs
+                .write_assert(GICD_ISPENDR.at(irq.as_usize() / 32), bit);
         }
     }
 }
@@ -240,29 +270,35 @@ impl GicDist {
 /// The GIC CPU interface structure.
 #[derive(Debug, Default)]
 pub struct GicCpu {
-    /// The MMIO base address for the GIC CPU interface.
-    pub base: Mmio<u32>,
+    /// The GIC CPU interface's registers.
+    pub regs: RegisterBlock,
 }
 
 impl GicCpu {
     /// Initializes the GIC CPU interface with the given MMIO address.
     pub unsafe fn init(&mut self, addr: VirtAddr) {
-        self.base.addr = addr;
+        self.regs.addr = addr;
 
         unsafe {
-            self.base.write_assert(GICC_CTLR, 0);
-            self.base.write_assert(GICC_PMR, 0xf0);
-            self.base.write_assert(GICC_CTLR, 1 << 0);
+            self.regs.write_assert(GICC_CTLR, 0);
+            self.regs
+                .write_assert(GICC_PMR, u32::from(PRIORITY_MASK_NONE));
+            self.regs.write_assert(GICC_CTLR, 1 << 0);
         }
     }
 
+    /// Sets the priority mask: only IRQs with a lower priority value are signalled.
+    pub unsafe fn set_priority_mask(&mut self, mask: u8) {
+        unsafe { self.regs.write(GICC_PMR, u32::from(mask)) };
+    }
+
     /// Acknowledges the next pending IRQ and returns its number.
     pub unsafe fn ack_irq(&mut self) -> Irq {
-        unsafe { Irq::from(self.base.read(GICC_IAR)) }
+        unsafe { Irq::from(self.regs.read(GICC_IAR)) }
     }
 
     /// Sends an end-of-interrupt (EOI) signal for the given IRQ.
     pub unsafe fn eoi_irq(&mut self, irq: Irq) {
-        unsafe { self.base.write(GICC_EOIR, irq.value()) };
+        unsafe { self.regs.write(GICC_EOIR, irq.value()) };
     }
 }
@@ -0,0 +1,347 @@
+//! The GIC version 3 interrupt controller (`arm,gic-v3`), as found on QEMU's `virt` machine.
+//!
+//! Unlike the GIC-400, the v3 GIC has a redistributor per CPU for that CPU's SGIs and PPIs,
+//! and the CPU interface is reached through the `ICC_*` system registers rather than MMIO.
+//! Affinity routing is always on, so SPIs are routed to a CPU by its MPIDR. LPIs (and so
+//! the ITS) aren't supported.
+
+use core::arch::asm;
+
+use arrayvec::ArrayVec;
+use fdt::{Fdt, node::NodeProperty};
+
+use crate::{
+    fdt::get_mmio_addr,
+    irq::{
+        Irq, IrqCell, IrqChip, IrqHandler, IrqHandlerDescriptor, PRIORITY_DEFAULT,
+        PRIORITY_MASK_NONE,
+    },
+    mem::units::{PhysAddr, VirtAddr},
+    syscall::errno::Errno,
+};
+
+use super::drivers::mmio::{Register, RegisterArray, RegisterBlock, map_device};
+
+const GICD_CTLR: Register<u32> = Register::new(0x0000);
+const GICD_TYPER: Register<u32> = Register::new(0x0004);
+/// One bit per IRQ.
+const GICD_IGROUPR: RegisterArray<u32> = RegisterArray::new(0x0080);
+const GICD_ISENABLER: RegisterArray<u32> = RegisterArray::new(0x0100);
+const GICD_ICENABLER: RegisterArray<u32> = RegisterArray::new(0x0180);
+const GICD_ISPENDR: RegisterArray<u32> = RegisterArray::new(0x0200);
+/// One byte per IRQ.
+const GICD_IPRIORITYR: RegisterArray<u8> = RegisterArray::new(0x0400);
+/// One affinity per IRQ.
+const GICD_IROUTER: RegisterArray<u64> = RegisterArray::new(0x6000);
+
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+
+const GICR_TYPER: Register<u64> = Register::new(0x0008);
+const GICR_WAKER: Register<u32> = Register::new(0x0014);
+/// The offset of the SGI/PPI frame from the start of a redistributor.
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: Register<u32> = Register::new(GICR_SGI_BASE + 0x0080);
+const GICR_ISENABLER0: Register<u32> = Register::new(GICR_SGI_BASE + 0x0100);
+const GICR_ICENABLER0: Register<u32> = Register::new(GICR_SGI_BASE + 0x0180);
+const GICR_ISPENDR0: Register<u32> = Register::new(GICR_SGI_BASE + 0x0200);
+/// One byte per SGI or PPI.
+const GICR_IPRIORITYR: RegisterArray<u8> = RegisterArray::new(GICR_SGI_BASE + 0x0400);
+
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// The first SPI; everything below is private to a CPU.
+const FIRST_SPI: usize = 32;
+/// INTIDs from here on are special (like 1023, "nothing pending") or LPIs.
+const MAX_INTID: usize = 1020;
+
+/// The SGI other CPUs are interrupted with.
+const IPI_SGI: u8 = 0;
+
+/// Where to send a software-generated interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SgiTarget {
+    /// The CPU with the given MPIDR affinity.
+    Cpu(u64),
+    /// Every CPU but the one sending it.
+    Others,
+}
+
+/// A version 3 GIC: the distributor, plus the redistributor of the CPU it was set up on.
+#[derive(Debug, Default)]
+pub struct GicV3 {
+    pub dist: RegisterBlock,
+    pub redist: RegisterBlock,
+    pub num_irqs: usize,
+}
+
+/// The physical addresses of the distributor and redistributor regions.
+struct GicV3Regions {
+    dist: (usize, usize),
+    redist: ArrayVec<(usize, usize), 8>,
+    stride: Option<usize>,
+}
+
+impl GicV3 {
+    /// Parses the register regions from the device tree.
+    fn parse(fdt: &Fdt) -> Result<GicV3Regions, Errno> {
+        let node = fdt.find_compatible(&["arm,gic-v3"]).ok_or(Errno::ENODEV)?;
+        let redist_regions = node
+            .property("#redistributor-regions")
+            .and_then(NodeProperty::as_usize)
+            .unwrap_or(1);
+
+        let mut regions = node.reg().ok_or(Errno::EINVAL)?.map(|region| {
+            let addr = get_mmio_addr(fdt, &region).ok_or(Errno::EINVAL)?;
+            Ok((addr.value(), region.size.unwrap_or(0)))
+        });
+        let dist = regions.next().ok_or(Errno::EINVAL)??;
+        let mut redist = ArrayVec::new();
+        for region in regions.take(redist_regions) {
+            redist.try_push(region?).map_err(|_| Errno::E2BIG)?;
+        }
+        if redist.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+
+        Ok(GicV3Regions {
+            dist,
+            redist,
+            stride: node
+                .property("redistributor-stride")
+                .and_then(NodeProperty::as_usize),
+        })
+    }
+
+    /// Finds the redistributor whose affinity matches the calling CPU's.
+    fn find_redist(regions: &GicV3Regions) -> Result<VirtAddr, Errno> {
+        let affinity = mpidr_affinity();
+        for &(base, size) in &regions.redist {
+            let base =
+                map_device(PhysAddr::new_canonical(base), size).map_err(|_| Errno::ENOMEM)?;
+
+            let mut offset = 0;
+            while offset < size {
+                let frame = RegisterBlock::new(base.add_bytes(offset));
+                let typer = unsafe { frame.read(GICR_TYPER) };
+                // GICR_TYPER has the affinity as Aff3.Aff2.Aff1.Aff0, one byte each
+                let frame_affinity = typer >> 32;
+                let mpidr_packed = (affinity & 0xff_ffff) | ((affinity >> 32 & 0xff) << 24);
+                if frame_affinity == mpidr_packed {
+                    return Ok(base.add_bytes(offset));
+                }
+                if typer & GICR_TYPER_LAST != 0 {
+                    break;
+                }
+                // each redistributor has an RD and an SGI frame, plus two more with vLPIs
+                offset += regions.stride.unwrap_or(if typer & GICR_TYPER_VLPIS == 0 {
+                    0x2_0000
+                } else {
+                    0x4_0000
+                });
+            }
+        }
+        Err(Errno::ENODEV)
+    }
+
+    /// Sends SGI `sgi` (0 through 15) to the given CPUs.
+    pub fn send_sgi(sgi: u8, target: SgiTarget) {
+        let intid = u64::from(sgi & 0xf) << 24;
+        let value = match target {
+            SgiTarget::Cpu(mpidr) => {
+                let aff0 = mpidr & 0xff;
+                let aff1 = (mpidr >> 8) & 0xff;
+                let aff2 = (mpidr >> 16) & 0xff;
+                let aff3 = (mpidr >> 32) & 0xff;
+                // the target list covers sixteen Aff0 values, picked by the range selector
+                (aff3 << 48)
+                    | (aff2 << 32)
+                    | ((aff0 / 16) << 44)
+                    | (aff1 << 16)
+                    | (1 << (aff0 % 16))
+            }
+            SgiTarget::Others => 1 << 40,
+        };
+        unsafe {
+            asm!("msr icc_sgi1r_el1, {}", "isb", in(reg) value | intid);
+        }
+    }
+
+    /// Returns the registers and bit for a private (SGI/PPI) or shared interrupt.
+    fn bank(
+        &mut self,
+        irq: Irq,
+        redist_reg: Register<u32>,
+        dist_regs: RegisterArray<u32>,
+    ) -> (&mut RegisterBlock, Register<u32>, u32) {
+        let irq = irq.as_usize();
+        let bit = 1 << (irq % 32);
+        if irq < FIRST_SPI {
+            (&mut self.redist, redist_reg, bit)
+        } else {
+            (&mut self.dist, dist_regs.at(irq / 32), bit)
+        }
+    }
+
+    unsafe fn init_dist(&mut self) {
+        unsafe {
+            self.dist.write(GICD_CTLR, 0);
+            self.dist.spin_while_hi(GICD_CTLR, GICD_CTLR_RWP);
+
+            let typer = self.dist.read(GICD_TYPER);
+            self.num_irqs = (((typer & 0x1f) as usize + 1) * 32).min(MAX_INTID);
+            log::debug!("GICv3 distributor supports {} IRQs", self.num_irqs);
+
+            let affinity = mpidr_affinity();
+            for irq in FIRST_SPI..self.num_irqs {
+                self.dist
+                    .write(GICD_ICENABLER.at(irq / 32), 1 << (irq % 32));
+                self.dist.set(GICD_IGROUPR.at(irq / 32), 1 << (irq % 32));
+                self.dist.write(GICD_IPRIORITYR.at(irq), PRIORITY_DEFAULT);
+                self.dist.write(GICD_IROUTER.at(irq), affinity);
+            }
+
+            self.dist
+                .write(GICD_CTLR, GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+            self.dist.spin_while_hi(GICD_CTLR, GICD_CTLR_RWP);
+        }
+    }
+
+    unsafe fn init_redist(&mut self) {
+        unsafe {
+            self.redist.clear(GICR_WAKER, GICR_WAKER_PROCESSOR_SLEEP);
+            self.redist
+                .spin_while_hi(GICR_WAKER, GICR_WAKER_CHILDREN_ASLEEP);
+
+            self.redist.write(GICR_ICENABLER0, u32::MAX);
+            self.redist.write(GICR_IGROUPR0, u32::MAX);
+            for irq in 0..FIRST_SPI {
+                self.redist.write(GICR_IPRIORITYR.at(irq), PRIORITY_DEFAULT);
+            }
+        }
+    }
+
+    unsafe fn init_cpu_interface() {
+        unsafe {
+            // system register access, with IRQ and FIQ bypass disabled
+            asm!("msr icc_sre_el1, {}", "isb", in(reg) 0b111_u64);
+            asm!("msr icc_pmr_el1, {}", in(reg) u64::from(PRIORITY_MASK_NONE));
+            asm!("msr icc_bpr1_el1, {}", in(reg) 0_u64);
+            asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1_u64);
+        }
+    }
+}
+
+impl IrqHandler for GicV3 {
+    fn handle_irq(&mut self, _irq: Irq) {
+        log::warn!("handle_irq() called on GicV3 (no-op)");
+    }
+}
+
+impl IrqChip for GicV3 {
+    fn init(&mut self, fdt: &Fdt, descs: &mut [IrqHandlerDescriptor]) {
+        let regions = Self::parse(fdt).unwrap();
+        let (dist_phys, dist_size) = regions.dist;
+        self.dist.addr = map_device(PhysAddr::new_canonical(dist_phys), dist_size).unwrap();
+        self.redist.addr = Self::find_redist(&regions).unwrap();
+        log::debug!("GICD @ {}, GICR @ {}", self.dist.addr, self.redist.addr);
+
+        unsafe {
+            self.init_dist();
+            self.init_redist();
+            Self::init_cpu_interface();
+        }
+
+        for (i, desc) in descs.iter_mut().enumerate().take(self.num_irqs) {
+            desc.chip_irq = Irq::from(i as u32);
+            desc.used = true;
+        }
+    }
+
+    fn ack(&mut self) -> Irq {
+        let intid: u64;
+        unsafe { asm!("mrs {}, icc_iar1_el1", out(reg) intid) };
+        Irq::from(intid as u32)
+    }
+
+    fn eoi(&mut self, irq: Irq) {
+        unsafe { asm!("msr icc_eoir1_el1, {}", "isb", in(reg) u64::from(irq.value())) };
+    }
+
+    fn enable_irq(&mut self, irq: Irq) {
+        log::debug!("enabling IRQ {irq}");
+        let (regs, reg, bit) = self.bank(irq, GICR_ISENABLER0, GICD_ISENABLER);
+        unsafe { regs.write(reg, bit) };
+    }
+
+    fn disable_irq(&mut self, irq: Irq) {
+        log::debug!("disabling IRQ {irq}");
+        let (regs, reg, bit) = self.bank(irq, GICR_ICENABLER0, GICD_ICENABLER);
+        unsafe { regs.write(reg, bit) };
+    }
+
+    fn set_priority(&mut self, irq: Irq, priority: u8) {
+        let irq = irq.as_usize();
+        unsafe {
+            if irq < FIRST_SPI {
+                self.redist.write(GICR_IPRIORITYR.at(irq), priority);
+            } else {
+                self.dist.write(GICD_IPRIORITYR.at(irq), priority);
+            }
+        }
+    }
+
+    fn set_priority_mask(&mut self, mask: u8) {
+        unsafe { asm!("msr icc_pmr_el1, {}", "isb", in(reg) u64::from(mask)) };
+    }
+
+    fn translate_irq(&self, irq_data: IrqCell) -> Option<Irq> {
+        let intid = match irq_data {
+            IrqCell::L3(0, spi, _flags) => spi as usize + FIRST_SPI,
+            IrqCell::L3(1, ppi, _flags) => ppi as usize + 16,
+            _ => return None,
+        };
+        (intid < self.num_irqs).then(|| Irq::from(intid as u32))
+    }
+
+    fn manual_irq(&mut self, irq: Irq) {
+        if irq.as_usize() < 16 {
+            Self::send_sgi(irq.as_usize() as u8, SgiTarget::Cpu(mpidr_affinity()));
+        } else {
+            let (regs, reg, bit) = self.bank(irq, GICR_ISPENDR0, GICD_ISPENDR);
+            unsafe { regs.write(reg, bit) };
+        }
+    }
+
+    fn ipi(&self) -> Option<Irq> {
+        Some(Irq::from(u32::from(IPI_SGI)))
+    }
+
+    fn send_ipi(&mut self, cpu: usize) {
+        // the other cores are in the same cluster, so only Aff0 differs
+        let target = (mpidr_affinity() & !0xff) | (cpu as u64 & 0xff);
+        Self::send_sgi(IPI_SGI, SgiTarget::Cpu(target));
+    }
+
+    fn is_irq_pending(&self, irq: Irq) -> bool {
+        let irq = irq.as_usize();
+        let bit = 1 << (irq % 32);
+        let pending = if irq < FIRST_SPI {
+            unsafe { self.redist.read(GICR_ISPENDR0) }
+        } else {
+            unsafe { self.dist.read(GICD_ISPENDR.at(irq / 32)) }
+        };
+        pending & bit == bit
+    }
+}
+
+/// Returns the calling CPU's affinity fields from `MPIDR_EL1`, laid out as in `GICD_IROUTER`.
+fn mpidr_affinity() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr & 0xff_00ff_ffff
+}
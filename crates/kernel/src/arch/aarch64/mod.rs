@@ -1,49 +1,81 @@
-use core::arch::asm;
+use core::{arch::asm, ops::Range};
 
-use aarch64_cpu::registers::{Readable, Writeable, TPIDR_EL1, ReadWriteable, DAIF};
+use aarch64_cpu::registers::{Readable, Writeable, TPIDR_EL1, MPIDR_EL1, ReadWriteable, DAIF};
 use alloc::boxed::Box;
-use serial::PERIPHERAL_BASE;
+use arrayvec::ArrayVec;
+use kados_abi::boot::{MAIR_IDX_DEVICE, MAIR_IDX_NON_CACHEABLE, MAIR_IDX_NORMAL};
 
 use crate::{
     BOOT_INFO,
-    cpu_local::CpuLocalBlock,
     irq::IrqChip,
     mem::{
-        paging::{
-            allocator::KernelFrameAllocator,
-            table::{BlockSize, PageFlags, PageTable, TableKind},
-        },
+        paging::table::{PageFlags, PageTable, PageTableEntry, TableKind},
         units::{PhysAddr, VirtAddr},
     },
 };
 
 use super::Architecture;
 
+pub mod armctrl;
 pub mod boot;
+pub mod crash;
+#[cfg(feature = "gdb")]
+pub mod debugging;
 pub mod drivers;
+pub mod earlycon;
+pub mod esr;
+pub mod fpu;
 pub mod gic;
+pub mod gicv3;
+#[cfg(feature = "pauth")]
+pub mod pauth;
+pub mod pmu;
+pub mod psci;
+pub mod reloc;
+pub mod semihosting;
 pub mod serial;
+pub mod signal;
+#[cfg(feature = "stack-protector")]
+pub mod stack_protector;
+pub mod string;
 pub mod syscall;
 pub mod task;
 pub mod time;
 pub mod vectors;
 
+/// Where a `TTBRn_EL1` (and a `tlbi` by ASID operand) keeps the ASID.
+const TTBR_ASID_SHIFT: usize = 48;
+const TTBR_ASID_MASK: usize = 0xffff << TTBR_ASID_SHIFT;
+
 pub struct AArch64;
 
 impl AArch64 {
     pub const PAGE_FLAG_NON_BLOCK: usize = 1 << 1;
     pub const PAGE_FLAG_ACCESS: usize = 1 << 10;
-    pub const PAGE_FLAG_NORMAL: usize = 1 << 2;
+    pub const PAGE_FLAG_NORMAL: usize = MAIR_IDX_NORMAL << 2;
+    pub const PAGE_FLAG_NON_CACHEABLE: usize = MAIR_IDX_NON_CACHEABLE << 2;
     pub const PAGE_FLAG_INNER_SHAREABLE: usize = 0b11 << 8;
     pub const PAGE_FLAG_OUTER_SHAREABLE: usize = 0b10 << 8;
 
     pub const PAGE_FLAG_DEVICE: usize = Self::PAGE_FLAG_PRESENT      
             | Self::PAGE_FLAG_NON_BLOCK
             | Self::PAGE_FLAG_ACCESS 
-            | (0 << 2) // AttrIdx 0
+            | (MAIR_IDX_DEVICE << 2)
             | (0 << 6) // AP (RW, priv)
             | Self::PAGE_FLAG_OUTER_SHAREABLE
             | Self::PAGE_FLAG_NON_EXECUTABLE;
+
+    /// Marks a page's code as guarded, so that with BTI an indirect branch into it has to land
+    /// on a landing pad.
+    pub const PAGE_FLAG_GUARDED: usize = 1 << 50;
+
+    pub const PAGE_FLAG_DMA: usize = Self::PAGE_FLAG_PRESENT
+            | Self::PAGE_FLAG_NON_BLOCK
+            | Self::PAGE_FLAG_ACCESS
+            | Self::PAGE_FLAG_NON_CACHEABLE
+            | Self::PAGE_FLAG_READWRITE
+            | Self::PAGE_FLAG_INNER_SHAREABLE
+            | Self::PAGE_FLAG_NON_EXECUTABLE;
 }
 
 impl Architecture for AArch64 {
@@ -81,60 +113,81 @@ impl Architecture for AArch64 {
 
     const PAGE_FLAG_HUGE: usize = 0;
 
+    // the bootloader leaves `TCR_EL1.AS` clear
+    const ASID_BITS: usize = 8;
+
     #[inline]
-    unsafe fn init_pre_kernel_main() {}
+    unsafe fn init_pre_kernel_main() {
+        // its reset value is UNKNOWN, and nothing has a per-CPU area yet
+        TPIDR_EL1.set(0);
+        fpu::init();
+    }
 
     unsafe fn init_mem(mapper: &mut PageTable) {
-        const PERIPHERAL_SIZE: usize = 0x200_0000;
-
-        let frame = PhysAddr::new_canonical(PERIPHERAL_BASE);
-        let page = frame.as_hhdm_virt();
-
-        
+        let boot_info = BOOT_INFO.get().unwrap();
+        let uart = serial::uart_phys();
+        let uart = uart..uart.add_bytes(Self::PAGE_SIZE);
+
+        let mut windows = ArrayVec::<Range<PhysAddr>, 3>::new();
+        if let Some(peripherals) = &boot_info.peripherals {
+            windows.push(
+                peripherals.start.align_down(Self::PAGE_SIZE)
+                    ..peripherals.end.align_up(Self::PAGE_SIZE),
+            );
+        }
+        // the console may be on a UART outside the SoC, like RP1's on the Pi 5, and the
+        // early console on another than the one the boot stages said they used
+        let earlycon = earlycon::uart_phys();
+        let earlycon = earlycon..earlycon.add_bytes(Self::PAGE_SIZE);
+        for uart in [uart, earlycon] {
+            if !windows
+                .iter()
+                .any(|window| window.start <= uart.start && uart.end <= window.end)
+            {
+                windows.push(uart);
+            }
+        }
 
-        unsafe {
-            let mut bytes_mapped = 0;
-            while bytes_mapped < PERIPHERAL_SIZE {
+        for window in windows {
+            let size = window.end.value() - window.start.value();
+            unsafe {
                 mapper
-                    .map_to(
-                        page.add_bytes(bytes_mapped),
-                        frame.add_bytes(bytes_mapped),
-                        BlockSize::Page4KiB,
+                    .kernel_map_range(
+                        window.start.as_hhdm_virt(),
+                        window.start,
+                        size,
                         PageFlags::new_device(),
                     )
                     .unwrap()
                     .ignore();
-                bytes_mapped += BlockSize::Page4KiB.size();
             }
-        };
+        }
 
-        drivers::dma_init(mapper);
+        drivers::dma_init();
     }
 
     unsafe fn init_drivers() {
         let boot_info = BOOT_INFO.get().unwrap();
         let fdt = boot_info.fdt.as_ref().unwrap();
 
-        drivers::gpu::init(fdt);
+        super::driver::probe_all(fdt, drivers::DRIVERS);
     }
 
-    unsafe fn init_interrupts() {}
+    unsafe fn init_interrupts() {
+        #[cfg(feature = "gdb")]
+        debugging::init();
+    }
 
-    unsafe fn init_cpu_local_block() {
-        unsafe {
-            let frame = KernelFrameAllocator.allocate_one().unwrap();
-            let virt = frame.as_hhdm_virt().as_raw_ptr_mut::<CpuLocalBlock>();
-            let block = CpuLocalBlock::init();
-            virt.write(block);
-            TPIDR_EL1.set(virt as u64);
-        }
+    unsafe fn set_percpu_base(base: usize) {
+        TPIDR_EL1.set(base as u64);
     }
 
     unsafe fn init_syscalls() {}
 
     #[inline]
     unsafe fn enable_interrupts() {
-        DAIF.modify(DAIF::I::CLEAR);
+        // debug exceptions are masked along with IRQs, so unmask them together too
+        DAIF.modify(DAIF::D::CLEAR + DAIF::I::CLEAR);
     }
 
     #[inline]
@@ -167,6 +220,35 @@ impl Architecture for AArch64 {
         unsafe { asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb") }
     }
 
+    unsafe fn break_before_make(entry: VirtAddr, new: PageTableEntry) {
+        // one asm block, so the compiler can't put anything between the break and the make
+        unsafe {
+            asm!(
+                // in case the mapping of `entry` was only just written
+                "dsb ishst",
+                "isb",
+                "str xzr, [{entry}]",
+                "dsb ishst",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                "str {new}, [{entry}]",
+                "dsb ishst",
+                "isb",
+                entry = in(reg) entry.value(),
+                new = in(reg) new.raw(),
+                options(nostack),
+            );
+        }
+    }
+
+    unsafe fn sync_icache(start: VirtAddr, end: VirtAddr) {
+        for line in (start.value() & !63..end.value()).step_by(64) {
+            unsafe { asm!("dc cvau, {0}", "dsb ish", "ic ivau, {0}", in(reg) line) };
+        }
+        unsafe { asm!("dsb ish", "isb") };
+    }
+
     #[inline]
     unsafe fn current_page_table(kind: TableKind) -> PhysAddr {
         let addr: usize;
@@ -176,7 +258,8 @@ impl Architecture for AArch64 {
                 TableKind::User => asm!("mrs {}, ttbr0_el1", out(reg) addr),
             }
         }
-        PhysAddr::new_canonical(addr)
+        // without the ASID
+        PhysAddr::new_canonical(addr & !TTBR_ASID_MASK)
     }
 
     #[inline]
@@ -213,6 +296,26 @@ impl Architecture for AArch64 {
         }
     }
 
+    #[inline]
+    unsafe fn switch_user_page_table(addr: PhysAddr, asid: u16) {
+        let ttbr = addr.value() | (usize::from(asid) << TTBR_ASID_SHIFT);
+        unsafe { asm!("msr ttbr0_el1, {0}", "isb", in(reg) ttbr, options(nostack)) };
+    }
+
+    #[inline]
+    unsafe fn invalidate_asid(asid: u16) {
+        let operand = usize::from(asid) << TTBR_ASID_SHIFT;
+        unsafe {
+            asm!(
+                "dsb ishst",
+                "tlbi aside1is, {0}",
+                "dsb ish",
+                "isb",
+                in(reg) operand,
+                options(nostack),
+            );
+        }
+    }
 
     #[inline]
     fn stack_pointer() -> usize {
@@ -223,6 +326,37 @@ impl Architecture for AArch64 {
         sp
     }
 
+    unsafe fn set_stack_pointer_post_mapping(
+        stack_top: VirtAddr,
+        continuation: extern "C" fn() -> !,
+    ) -> ! {
+        unsafe {
+            // clear fp and lr so backtraces stop at the new stack's first frame
+            asm!(
+                "mov sp, {sp}",
+                "mov fp, xzr",
+                "mov lr, xzr",
+                // through x16, which a BTI landing pad for calls accepts
+                "br x16",
+                sp = in(reg) stack_top.value(),
+                in("x16") continuation,
+                options(noreturn),
+            )
+        }
+    }
+
+    #[inline]
+    fn strip_return_address(addr: usize) -> usize {
+        // a pointer authentication code goes in the bits above the address, which are all
+        // set for kernel addresses and all clear for user ones
+        const ADDR_MASK: usize = (1 << 48) - 1;
+        if addr & (1 << 55) == 0 {
+            addr & ADDR_MASK
+        } else {
+            addr | !ADDR_MASK
+        }
+    }
+
     #[inline]
     fn frame_pointer() -> usize {
         let fp: usize;
@@ -232,13 +366,24 @@ impl Architecture for AArch64 {
         fp
     }
 
-    fn current_cpu_local_block() -> VirtAddr {
-        VirtAddr::new_canonical(TPIDR_EL1.get() as usize)
+    fn percpu_base() -> usize {
+        TPIDR_EL1.get() as usize
+    }
+
+    fn cpu_id() -> usize {
+        // Aff0 numbers the cores within a cluster, and the Pis only have the one cluster
+        (MPIDR_EL1.get() & 0xff) as usize
     }
 
     fn new_irq_chip(compatible: &str) -> Option<Box<dyn IrqChip>> {
         if compatible.contains("arm,gic-400") {
             Some(Box::new(gic::Gic::default()))
+        } else if compatible.contains("arm,gic-v3") {
+            Some(Box::new(gicv3::GicV3::default()))
+        } else if compatible.contains("armctrl-ic") {
+            Some(Box::new(armctrl::ArmCtrl::default()))
+        } else if compatible.contains("bcm2711-gpio") || compatible.contains("bcm2835-gpio") {
+            Some(Box::new(drivers::gpio::GpioIrqChip::default()))
         } else {
             log::warn!("No interrupt chip driver for {compatible}");
             None
@@ -246,12 +391,9 @@ impl Architecture for AArch64 {
     }
 
     fn emergency_reset() -> ! {
-        unsafe {
-            asm!("hvc   #0",
-                 in("x0")  0x8400_0009_usize,
-                 options(noreturn),
-            )
-        }
+        let e = psci::system_reset();
+        crate::println!("PSCI system reset failed: {e:?}");
+        Self::hcf()
     }
 
     fn exit_qemu(code: u32) -> ! {
@@ -264,6 +406,11 @@ impl Architecture for AArch64 {
         unsafe { asm!("wfe") }
     }
 
+    #[inline]
+    fn wait_for_interrupt() {
+        unsafe { asm!("wfi") }
+    }
+
     #[inline]
     fn nop() {
         unsafe { asm!("nop") }
@@ -0,0 +1,101 @@
+//! Pointer authentication and branch target identification for the kernel's own code.
+//!
+//! With the `pauth` feature, the builder compiles the kernel to sign the return addresses it
+//! saves on the stack (`pac-ret`) and to start every function with a BTI landing pad. Both
+//! are hint instructions, which a core without `FEAT_PAuth` or `FEAT_BTI` runs as NOPs, so the
+//! same kernel boots either way. [`init`] installs a key and turns signing on if the core has
+//! it, and the kernel's text is mapped guarded, so that a core with BTI faults an indirect
+//! branch to anywhere but a landing pad.
+//!
+//! Return addresses saved outside of stack frames, in a switched out task's context, are
+//! signed by the context switch against the task's stack pointer. Anything that follows the
+//! frame chain strips the codes off with [`Architecture::strip_return_address`].
+
+use core::{
+    arch::{asm, naked_asm},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(doc)]
+use crate::arch::Architecture;
+
+/// `SCTLR_EL1.EnIA`, which turns on signing and authenticating with the A instruction key.
+const SCTLR_ENIA: u64 = 1 << 31;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on pointer authentication, if the core has it.
+///
+/// Every function still on the stack when signing turns on returns through an unsigned
+/// return address, which would fail authentication, so this is inlined into
+/// [`kernel_main`](crate::kernel_main), which never returns, and has to be called before any
+/// task is created.
+///
+/// # Safety
+///
+/// See above.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub unsafe fn init() {
+    if !is_supported() {
+        return;
+    }
+    let (lo, hi) = key();
+    unsafe { enable(lo, hi) };
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns `true` if [`init`] turned pointer authentication on.
+#[must_use]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the core implements address authentication with some algorithm.
+fn is_supported() -> bool {
+    let isar1: u64;
+    let isar2: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64isar1_el1", out(reg) isar1, options(nomem, nostack));
+        // ID_AA64ISAR2_EL1, which reads as zero on cores from before it was defined
+        asm!("mrs {}, s3_0_c0_c6_2", out(reg) isar2, options(nomem, nostack));
+    }
+    let apa = (isar1 >> 4) & 0xf;
+    let api = (isar1 >> 8) & 0xf;
+    let apa3 = (isar2 >> 12) & 0xf;
+    apa != 0 || api != 0 || apa3 != 0
+}
+
+/// Derives the instruction key from the counter. Nothing in the kernel reads the key back,
+/// so it only has to differ from boot to boot.
+fn key() -> (u64, u64) {
+    let mut seed: u64;
+    unsafe { asm!("mrs {}, cntpct_el0", out(reg) seed, options(nomem, nostack)) };
+    let mut next = || {
+        // splitmix64
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    (next(), next())
+}
+
+/// Installs `lo` and `hi` as the A instruction key and turns signing with it on.
+///
+/// It's naked so that it doesn't authenticate its own return address, which its caller
+/// didn't sign.
+#[unsafe(naked)]
+unsafe extern "C" fn enable(lo: u64, hi: u64) {
+    naked_asm!(
+        "msr s3_0_c2_c1_0, x0", // APIAKeyLo_EL1
+        "msr s3_0_c2_c1_1, x1", // APIAKeyHi_EL1
+        "mrs x2, sctlr_el1",
+        "orr x2, x2, #{enia}",
+        "msr sctlr_el1, x2",
+        "isb",
+        "ret",
+        enia = const SCTLR_ENIA,
+    );
+}
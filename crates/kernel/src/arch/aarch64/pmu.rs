@@ -0,0 +1,342 @@
+//! The Performance Monitors Extension (`PMUv3`): the cycle counter and the event counters.
+//!
+//! [`init`] turns the cycle counter on and points the event counters at [`DEFAULT_EVENTS`], as
+//! many as the core has counters for and implements. From then on they count everything the
+//! CPU does at EL1 and EL0, and [`read`] samples them. [`measure`] counts a region of code,
+//! and the `pmu` shell command a shell command, reporting instructions per cycle and cache miss
+//! rates.
+//!
+//! Only the CPU that calls [`init`] is set up, and EL0 can't read the counters.
+
+use core::{arch::asm, fmt};
+
+use aarch64_cpu::registers::{ID_AA64DFR0_EL1, Readable};
+use arrayvec::{ArrayString, ArrayVec};
+use spin::Once;
+
+use crate::{
+    serial_print,
+    shell::{self, Args, Command},
+    sync::{IrqMutex, with_irqs_disabled},
+    syscall::errno::Errno,
+};
+
+/// The most event counters the architecture allows for.
+pub const MAX_COUNTERS: usize = 31;
+
+/// What the event counters count unless [`set_events`] changes it.
+pub const DEFAULT_EVENTS: &[Event] = &[
+    Event::InstRetired,
+    Event::L1dCache,
+    Event::L1dCacheRefill,
+    Event::L2dCache,
+    Event::L2dCacheRefill,
+    Event::BranchMispredict,
+];
+
+/// `PMCR_EL0.E`: counting is enabled.
+const PMCR_E: u64 = 1 << 0;
+/// `PMCR_EL0.P`: resets the event counters.
+const PMCR_P: u64 = 1 << 1;
+/// `PMCR_EL0.C`: resets the cycle counter.
+const PMCR_C: u64 = 1 << 2;
+/// `PMCR_EL0.LC`: the cycle counter overflows at 64 bits rather than 32.
+const PMCR_LC: u64 = 1 << 6;
+const PMCR_N_SHIFT: u64 = 11;
+const PMCR_N_MASK: u64 = 0x1f;
+
+/// The cycle counter's bit in `PMCNTEN{SET,CLR}_EL0` and `PMOVSCLR_EL0`.
+const CYCLE_COUNTER: u64 = 1 << 31;
+
+/// `PMEVTYPER<n>_EL0.P` and `.U` clear, so EL1 and EL0 are both counted.
+const EVTYPER_COUNT_EL1_EL0: u64 = 0;
+
+/// `ID_AA64DFR0_EL1.PMUVer` of an `IMPLEMENTATION DEFINED` PMU, which isn't `PMUv3`.
+const PMUVER_IMP_DEF: u64 = 0xf;
+
+static PMU: Once<IrqMutex<Pmu>> = Once::new();
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack)) };
+        value
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe {
+            asm!(concat!("msr ", $reg, ", {}"), "isb", in(reg) value, options(nostack));
+        }
+    }};
+}
+
+/// A `PMUv3` common event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Event {
+    /// Loads and stores that missed the L1 data cache.
+    L1dCacheRefill = 0x03,
+    /// Loads and stores that looked up the L1 data cache.
+    L1dCache = 0x04,
+    /// Instructions executed.
+    InstRetired = 0x08,
+    /// Branches that were mispredicted.
+    BranchMispredict = 0x10,
+    /// Accesses to the L2 cache.
+    L2dCache = 0x16,
+    /// Accesses that missed the L2 cache.
+    L2dCacheRefill = 0x17,
+}
+
+impl Event {
+    /// Returns `true` if the core counts the event.
+    fn is_implemented(self) -> bool {
+        // PMCEID0_EL0 has a bit for each of the common events 0x00 to 0x1f
+        read_sysreg!("pmceid0_el0") & (1 << self as u16) != 0
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::L1dCacheRefill => "L1D refills",
+            Self::L1dCache => "L1D accesses",
+            Self::InstRetired => "instructions",
+            Self::BranchMispredict => "branch mispredicts",
+            Self::L2dCache => "L2 accesses",
+            Self::L2dCacheRefill => "L2 refills",
+        }
+    }
+}
+
+struct Pmu {
+    /// How many event counters the core has.
+    counters: usize,
+    /// What each event counter in use counts.
+    events: ArrayVec<Event, MAX_COUNTERS>,
+}
+
+impl Pmu {
+    fn enable_mask(&self) -> u64 {
+        CYCLE_COUNTER | ((1 << self.events.len()) - 1)
+    }
+
+    fn program(&mut self, events: &[Event]) {
+        write_sysreg!("pmcntenclr_el0", u64::from(u32::MAX));
+        self.events.clear();
+        for (counter, &event) in events.iter().enumerate() {
+            write_sysreg!("pmselr_el0", counter as u64);
+            write_sysreg!("pmxevtyper_el0", EVTYPER_COUNT_EL1_EL0 | event as u64);
+            write_sysreg!("pmxevcntr_el0", 0);
+            self.events.push(event);
+        }
+        write_sysreg!("pmovsclr_el0", u64::from(u32::MAX));
+        write_sysreg!("pmcntenset_el0", self.enable_mask());
+    }
+
+    fn read(&self) -> Sample {
+        let mut sample = Sample {
+            cycles: read_sysreg!("pmccntr_el0"),
+            counts: ArrayVec::new(),
+        };
+        for (counter, &event) in self.events.iter().enumerate() {
+            write_sysreg!("pmselr_el0", counter as u64);
+            sample.counts.push((event, read_sysreg!("pmxevcntr_el0")));
+        }
+        sample
+    }
+}
+
+/// The counters at some point, or the difference between two such points.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// CPU cycles.
+    pub cycles: u64,
+    /// Each event counted, and its count.
+    pub counts: ArrayVec<(Event, u64), MAX_COUNTERS>,
+}
+
+impl Sample {
+    /// Returns the count of `event`, if it was counted.
+    #[must_use]
+    pub fn count(&self, event: Event) -> Option<u64> {
+        self.counts
+            .iter()
+            .find_map(|&(counted, count)| (counted == event).then_some(count))
+    }
+
+    /// Returns what was counted between `earlier` and this sample.
+    #[must_use]
+    pub fn since(&self, earlier: &Self) -> Self {
+        let mut counts = self.counts.clone();
+        for ((_, count), &(_, before)) in counts.iter_mut().zip(&earlier.counts) {
+            // the event counters are 32 bits wide
+            *count = count.wrapping_sub(before) & u64::from(u32::MAX);
+        }
+        Self {
+            cycles: self.cycles.wrapping_sub(earlier.cycles),
+            counts,
+        }
+    }
+}
+
+/// Formats `part / whole` to two decimal places, scaled by `scale`.
+fn ratio(part: u64, whole: u64, scale: u64) -> ArrayString<24> {
+    let mut out = ArrayString::new();
+    if whole == 0 {
+        out.push('-');
+    } else {
+        let hundredths = u128::from(part) * u128::from(scale) * 100 / u128::from(whole);
+        fmt::write(
+            &mut out,
+            format_args!("{}.{:02}", hundredths / 100, hundredths % 100),
+        )
+        .ok();
+    }
+    out
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>20}: {}", "cycles", self.cycles)?;
+        for &(event, count) in &self.counts {
+            write!(f, "{:>20}: {count}", event.name())?;
+            let derived = match event {
+                Event::InstRetired => Some((count, self.cycles, 1, " IPC")),
+                Event::L1dCacheRefill => self
+                    .count(Event::L1dCache)
+                    .map(|accesses| (count, accesses, 100, "% miss")),
+                Event::L2dCacheRefill => self
+                    .count(Event::L2dCache)
+                    .map(|accesses| (count, accesses, 100, "% miss")),
+                _ => None,
+            };
+            if let Some((part, whole, scale, unit)) = derived {
+                write!(f, " ({}{unit})", ratio(part, whole, scale))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the cycle counter and the event counters, counting [`DEFAULT_EVENTS`], and adds
+/// the `pmu` shell command. Does nothing on a core without `PMUv3`.
+pub fn init() {
+    let version = ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::PMUVer);
+    if version == 0 || version == PMUVER_IMP_DEF {
+        log::info!("pmu: no PMUv3");
+        return;
+    }
+    let counters = ((read_sysreg!("pmcr_el0") >> PMCR_N_SHIFT) & PMCR_N_MASK) as usize;
+
+    // EL0 can't touch the counters
+    write_sysreg!("pmuserenr_el0", 0);
+    write_sysreg!("pmcr_el0", PMCR_E | PMCR_P | PMCR_C | PMCR_LC);
+    let mut pmu = Pmu {
+        counters,
+        events: ArrayVec::new(),
+    };
+    let events = DEFAULT_EVENTS
+        .iter()
+        .copied()
+        .filter(|event| event.is_implemented())
+        .take(counters)
+        .collect::<ArrayVec<_, MAX_COUNTERS>>();
+    pmu.program(&events);
+    PMU.call_once(|| IrqMutex::new(pmu));
+    log::info!(
+        "pmu: PMUv3 with {counters} event counters, counting {} events",
+        events.len()
+    );
+
+    shell::register(Command {
+        name: "pmu",
+        usage: "[reset | <command> [args]]",
+        help: "show the performance counters, or what they count while a command runs",
+        run: cmd_pmu,
+    });
+}
+
+/// Points the event counters at `events`, zeroing them.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if there's no PMU, [`Errno::ENOSPC`] if there are more events
+/// than counters, or [`Errno::EOPNOTSUPP`] if the core doesn't count one of them.
+pub fn set_events(events: &[Event]) -> Result<(), Errno> {
+    let mut pmu = PMU.get().ok_or(Errno::ENODEV)?.lock();
+    if events.len() > pmu.counters {
+        return Err(Errno::ENOSPC);
+    }
+    if !events.iter().all(|event| event.is_implemented()) {
+        return Err(Errno::EOPNOTSUPP);
+    }
+    pmu.program(events);
+    Ok(())
+}
+
+/// Zeroes every counter.
+pub fn reset() {
+    if PMU.get().is_some() {
+        write_sysreg!("pmcr_el0", read_sysreg!("pmcr_el0") | PMCR_P | PMCR_C);
+    }
+}
+
+/// Starts the counters counting, if [`stop`] stopped them.
+pub fn start() {
+    if let Some(pmu) = PMU.get() {
+        write_sysreg!("pmcntenset_el0", pmu.lock().enable_mask());
+    }
+}
+
+/// Stops the counters, leaving their counts as they are.
+pub fn stop() {
+    if PMU.get().is_some() {
+        write_sysreg!("pmcntenclr_el0", u64::from(u32::MAX));
+    }
+}
+
+/// Returns the counters' counts, or `None` if there's no PMU.
+#[must_use]
+pub fn read() -> Option<Sample> {
+    Some(PMU.get()?.lock().read())
+}
+
+/// Runs `f` with interrupts disabled, so it stays on this CPU and nothing else is counted,
+/// and returns what the counters counted while it ran.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Option<Sample>) {
+    with_irqs_disabled(|| {
+        let before = read();
+        let result = f();
+        let counted = read()
+            .zip(before)
+            .map(|(after, before)| after.since(&before));
+        (result, counted)
+    })
+}
+
+fn cmd_pmu(mut args: Args) -> Result<(), Errno> {
+    let Some(first) = args.next() else {
+        serial_print!("{}", read().ok_or(Errno::ENODEV)?);
+        return Ok(());
+    };
+    if first == "reset" {
+        reset();
+        return Ok(());
+    }
+
+    // the counters keep counting through interrupts and other tasks, so this counts the
+    // whole CPU while the command runs
+    let mut line = ArrayString::<{ shell::MAX_LINE_LEN }>::new();
+    for arg in core::iter::once(first).chain(args) {
+        line.try_push_str(arg).ok();
+        line.try_push(' ').ok();
+    }
+    let before = read().ok_or(Errno::ENODEV)?;
+    shell::execute(&line);
+    let after = read().ok_or(Errno::ENODEV)?;
+    serial_print!("{}", after.since(&before));
+    Ok(())
+}
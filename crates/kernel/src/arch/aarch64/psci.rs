@@ -0,0 +1,227 @@
+//! The Power State Coordination Interface, for turning CPUs and the system on and off.
+//!
+//! PSCI calls go to firmware at a higher exception level. The FDT's `psci` node says which
+//! instruction (the conduit) reaches it. For PSCI 0.1 it also gives the function IDs, since
+//! they were only standardized in 0.2.
+
+use core::{arch::asm, fmt};
+
+use fdt::{Fdt, node::NodeProperty};
+use spin::Once;
+
+use crate::{mem::units::PhysAddr, syscall::errno::Errno};
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_OFF: u32 = 0x8400_0002;
+const CPU_ON: u32 = 0xc400_0003;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+static PSCI: Once<Psci> = Once::new();
+
+/// The instruction used to call into the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    /// `hvc #0`, for firmware at EL2.
+    Hvc,
+    /// `smc #0`, for firmware at EL3.
+    Smc,
+}
+
+/// A PSCI version, as reported by the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The firmware interface found in the FDT.
+#[derive(Debug, Clone, Copy)]
+pub struct Psci {
+    pub conduit: Conduit,
+    pub version: Version,
+    cpu_on: Option<u32>,
+    cpu_off: Option<u32>,
+    system_off: Option<u32>,
+    system_reset: Option<u32>,
+}
+
+impl Psci {
+    /// What's assumed when the FDT has no `psci` node: PSCI 0.2 behind `hvc`, which is what
+    /// the kernel has always used to reset.
+    const FALLBACK: Self = Self::standard(Conduit::Hvc, Version { major: 0, minor: 2 });
+
+    const fn standard(conduit: Conduit, version: Version) -> Self {
+        Self {
+            conduit,
+            version,
+            cpu_on: Some(CPU_ON),
+            cpu_off: Some(CPU_OFF),
+            system_off: Some(SYSTEM_OFF),
+            system_reset: Some(SYSTEM_RESET),
+        }
+    }
+
+    /// Parses the `psci` node from the FDT, asking the firmware for its version if the node
+    /// says it can.
+    pub fn parse(fdt: &Fdt) -> Result<Self, Errno> {
+        let Some(node) = fdt.find_compatible(&["arm,psci-1.0", "arm,psci-0.2", "arm,psci"]) else {
+            return Err(Errno::ENODEV);
+        };
+
+        let conduit = match node.property("method").and_then(NodeProperty::as_str) {
+            Some("hvc") => Conduit::Hvc,
+            Some("smc") => Conduit::Smc,
+            _ => return Err(Errno::EINVAL),
+        };
+
+        let standard = node
+            .compatible()
+            .is_some_and(|c| c.all().any(|c| c == "arm,psci-1.0" || c == "arm,psci-0.2"));
+        if !standard {
+            // 0.1 has no version call, and only the functions the node lists
+            let function = |name| {
+                node.property(name)
+                    .and_then(NodeProperty::as_usize)
+                    .map(|id| id as u32)
+            };
+            return Ok(Self {
+                conduit,
+                version: Version { major: 0, minor: 1 },
+                cpu_on: function("cpu_on"),
+                cpu_off: function("cpu_off"),
+                system_off: None,
+                system_reset: None,
+            });
+        }
+
+        let version = unsafe { call(conduit, PSCI_VERSION, 0, 0, 0) } as u32;
+        let version = Version {
+            major: (version >> 16) as u16,
+            minor: version as u16,
+        };
+        Ok(Self::standard(conduit, version))
+    }
+
+    fn call(
+        &self,
+        function: Option<u32>,
+        arg0: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> Result<(), Errno> {
+        let function = function.ok_or(Errno::EOPNOTSUPP)?;
+        let ret = unsafe { call(self.conduit, function, arg0, arg1, arg2) };
+        match ret as i32 {
+            0 => Ok(()),
+            err => Err(to_errno(err)),
+        }
+    }
+}
+
+/// Probes the firmware interface from the FDT.
+pub fn init(fdt: &Fdt) {
+    match Psci::parse(fdt) {
+        Ok(psci) => {
+            log::info!("PSCI {} via {:?}", psci.version, psci.conduit);
+            PSCI.call_once(|| psci);
+        }
+        Err(e) => log::warn!("No usable PSCI node in the FDT: {e:?}"),
+    }
+}
+
+/// Returns the firmware interface found by [`init`], if there was one.
+pub fn get() -> Option<&'static Psci> {
+    PSCI.get()
+}
+
+fn psci() -> &'static Psci {
+    PSCI.get().unwrap_or(&Psci::FALLBACK)
+}
+
+/// Powers on the CPU with the given MPIDR affinity, which starts executing at the physical
+/// address `entry` with the MMU off and `context` in `x0`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EALREADY`] if the CPU is already on, and [`Errno::EINPROGRESS`] if it's
+/// already being turned on.
+pub fn cpu_on(target: u64, entry: PhysAddr, context: usize) -> Result<(), Errno> {
+    let psci = psci();
+    psci.call(psci.cpu_on, target as usize, entry.value(), context)
+}
+
+/// Powers off the calling CPU. Only returns if the firmware refuses.
+#[must_use]
+pub fn cpu_off() -> Errno {
+    let psci = psci();
+    psci.call(psci.cpu_off, 0, 0, 0).err().unwrap_or(Errno::EIO)
+}
+
+/// Resets the whole system. Only returns if the firmware refuses.
+#[must_use]
+pub fn system_reset() -> Errno {
+    let psci = psci();
+    psci.call(psci.system_reset, 0, 0, 0)
+        .err()
+        .unwrap_or(Errno::EIO)
+}
+
+/// Powers off the whole system. Only returns if the firmware refuses.
+#[must_use]
+pub fn system_off() -> Errno {
+    let psci = psci();
+    psci.call(psci.system_off, 0, 0, 0)
+        .err()
+        .unwrap_or(Errno::EIO)
+}
+
+/// Maps a PSCI return code to the closest [`Errno`].
+fn to_errno(code: i32) -> Errno {
+    match code {
+        -1 => Errno::EOPNOTSUPP,
+        -2 => Errno::EINVAL,
+        -3 => Errno::EPERM,
+        -4 => Errno::EALREADY,
+        -5 => Errno::EINPROGRESS,
+        -7 | -8 => Errno::ENODEV,
+        -9 => Errno::EFAULT,
+        _ => Errno::EIO,
+    }
+}
+
+/// Makes a PSCI call through `conduit`, returning what the firmware left in `x0`.
+///
+/// # Safety
+///
+/// The call must be one the firmware implements and whose effects the caller is ready for.
+unsafe fn call(conduit: Conduit, function: u32, arg0: usize, arg1: usize, arg2: usize) -> usize {
+    let mut ret = function as usize;
+    unsafe {
+        match conduit {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                clobber_abi("C"),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                clobber_abi("C"),
+            ),
+        }
+    }
+    ret
+}
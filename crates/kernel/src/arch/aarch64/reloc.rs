@@ -0,0 +1,84 @@
+use crate::KERNEL_OFFSET;
+
+unsafe extern "C" {
+    unsafe static __kernel_virt_start: u8;
+    unsafe static __kernel_virt_end: u8;
+    unsafe static __rela_dyn_start: Rela;
+    unsafe static __rela_dyn_end: Rela;
+}
+
+/// `R_AARCH64_RELATIVE`: `*offset = load_base + addend`
+const R_AARCH64_RELATIVE: u64 = 1027;
+
+/// An `Elf64_Rela` entry as emitted into `.rela.dyn` by the linker.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Returns the difference between the address the kernel is running at and the
+/// address it was linked at.
+///
+/// `__kernel_virt_start` is resolved PC-relatively (`adrp`/`add`), so it reflects the
+/// runtime address even before any relocations have been applied.
+#[inline]
+fn load_displacement() -> usize {
+    (&raw const __kernel_virt_start as usize).wrapping_sub(KERNEL_OFFSET)
+}
+
+/// Applies the kernel's dynamic relocations.
+///
+/// This must run before anything dereferences an absolute address stored in the image
+/// (vtables, `static` pointers, string tables, etc.), so it can't print, panic, or index slices.
+/// Kernels built without the `pie` feature have nothing to relocate and this is a no-op.
+///
+/// Returns the number of relocations applied, or `usize::MAX` if an unsupported relocation
+/// type was encountered.
+///
+/// # Safety
+///
+/// Must be called exactly once, before any other Rust code in the kernel image runs.
+#[inline(never)]
+#[must_use]
+pub unsafe fn apply_relocations() -> usize {
+    if !cfg!(feature = "pie") {
+        return 0;
+    }
+
+    let displacement = load_displacement();
+    let image_start = &raw const __kernel_virt_start as usize;
+    let image_end = &raw const __kernel_virt_end as usize;
+
+    let mut rela = &raw const __rela_dyn_start;
+    let rela_end = &raw const __rela_dyn_end;
+
+    let mut applied = 0;
+    while rela < rela_end {
+        let Rela {
+            offset,
+            info,
+            addend,
+        } = unsafe { rela.read() };
+        rela = unsafe { rela.add(1) };
+
+        if info & 0xffff_ffff != R_AARCH64_RELATIVE {
+            return usize::MAX;
+        }
+
+        let target = (offset as usize).wrapping_add(displacement);
+        if !(image_start..image_end).contains(&target) {
+            // the bootloader's own fixups live in the identity-mapped boot image and have
+            // already been resolved by the static link
+            continue;
+        }
+
+        let value = (addend as usize).wrapping_add(displacement);
+        unsafe { (target as *mut usize).write_volatile(value) };
+        applied += 1;
+    }
+
+    applied
+}
@@ -0,0 +1,41 @@
+//! Output to the host through Arm semihosting.
+//!
+//! QEMU (with `-semihosting`) and attached debuggers catch the `hlt #0xf000` a call is made
+//! with. Anywhere else it's an undefined instruction, so this is only for runs that are
+//! known to be under one of them.
+
+use core::{arch::asm, fmt};
+
+const SYS_WRITE0: usize = 0x04;
+
+/// Writes a string to the host's console.
+pub fn write_str(s: &str) {
+    // SYS_WRITE0 takes a NUL-terminated string, so go through a buffer a chunk at a time
+    let mut buf = [0u8; 128];
+    for chunk in s.as_bytes().chunks(buf.len() - 1) {
+        buf[..chunk.len()].copy_from_slice(chunk);
+        buf[chunk.len()] = 0;
+        unsafe {
+            asm!(
+                "hlt #0xf000",
+                inout("x0") SYS_WRITE0 => _,
+                in("x1") buf.as_ptr(),
+                options(nostack),
+            );
+        }
+    }
+}
+
+struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+/// Writes formatted text to the host's console.
+pub fn write_fmt(args: fmt::Arguments) -> fmt::Result {
+    fmt::Write::write_fmt(&mut Writer, args)
+}
@@ -1,7 +1,20 @@
-use core::fmt::{self, Write};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 use spin::{Mutex, MutexGuard};
 
+use kados_abi::boot as abi;
+
+use crate::{
+    HHDM_PHYSICAL_OFFSET, cmdline,
+    fdt::Fdt,
+    mem::units::{PhysAddr, VirtAddr},
+};
+
+use super::drivers::mmio::{Register, RegisterBlock};
+
 /* -------- base addresses ------------------------------------------------ */
 
 /// The base address for the BCM2711 peripherals.
@@ -10,29 +23,118 @@ pub const PERIPHERAL_BASE: usize = 0xFE00_0000; // BCM2711 peripheral window
 pub const GPIO_BASE: usize = PERIPHERAL_BASE + 0x20_0000;
 /// The base address for the clock manager registers.
 pub const CM_BASE: usize = PERIPHERAL_BASE + 0x10_0000; // clock manager
-/// The base address for the UART0 registers.
+/// The base address for the BCM2711's UART0 registers.
 pub const UART0_BASE: usize = PERIPHERAL_BASE + 0x20_1000;
 
 /* -------- GPIO registers we need --------------------------------------- */
 
-const GPFSEL1: *mut u32 = (GPIO_BASE + 0x04) as *mut u32;
-const GPPUD: *mut u32 = (GPIO_BASE + 0x94) as *mut u32;
-const GPPUDCLK0: *mut u32 = (GPIO_BASE + 0x98) as *mut u32;
+const GPFSEL1: Register<u32> = Register::new(0x04);
+const GPPUD: Register<u32> = Register::new(0x94);
+const GPPUDCLK0: Register<u32> = Register::new(0x98);
 
 /* -------- CM UART clock (GPCLK UART) ----------------------------------- */
 
-const CM_UARTCTL: *mut u32 = (CM_BASE + 0x1F68) as *mut u32; // CTL
-const CM_UARTDIV: *mut u32 = (CM_BASE + 0x1F6C) as *mut u32; // DIV
+const CM_UARTCTL: Register<u32> = Register::new(0x1F68); // CTL
+const CM_UARTDIV: Register<u32> = Register::new(0x1F6C); // DIV
 
 /* -------- PL011 register block ----------------------------------------- */
 
-const DR: *mut u32 = (UART0_BASE + 0x00) as *mut u32;
-const FR: *mut u32 = (UART0_BASE + 0x18) as *mut u32;
-const IBRD: *mut u32 = (UART0_BASE + 0x24) as *mut u32;
-const FBRD: *mut u32 = (UART0_BASE + 0x28) as *mut u32;
-const LCRH: *mut u32 = (UART0_BASE + 0x2C) as *mut u32;
-const CR: *mut u32 = (UART0_BASE + 0x30) as *mut u32;
-const ICR: *mut u32 = (UART0_BASE + 0x44) as *mut u32;
+const DR: Register<u32> = Register::new(0x00);
+const FR: Register<u32> = Register::new(0x18);
+const IBRD: Register<u32> = Register::new(0x24);
+const FBRD: Register<u32> = Register::new(0x28);
+const LCRH: Register<u32> = Register::new(0x2C);
+const CR: Register<u32> = Register::new(0x30);
+const ICR: Register<u32> = Register::new(0x44);
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+/* -------- mini UART, relative to the AUX block -------------------------- */
+
+const AUX_ENABLES: Register<u32> = Register::new(0x04);
+const MU_IO: Register<u32> = Register::new(0x40);
+const MU_IER: Register<u32> = Register::new(0x44);
+const MU_IIR: Register<u32> = Register::new(0x48);
+const MU_LCR: Register<u32> = Register::new(0x4C);
+const MU_MCR: Register<u32> = Register::new(0x50);
+const MU_LSR: Register<u32> = Register::new(0x54);
+const MU_CNTL: Register<u32> = Register::new(0x60);
+const MU_BAUD: Register<u32> = Register::new(0x68);
+
+/// Where the mini UART's registers are in the AUX block.
+const MU_OFFSET: usize = 0x40;
+const MINI_UART_COMPATIBLE: &str = "brcm,bcm2835-aux-uart";
+
+const AUX_ENABLES_MINI_UART: u32 = 1 << 0;
+const MU_LSR_DATA_READY: u32 = 1 << 0;
+const MU_LSR_TX_EMPTY: u32 = 1 << 5;
+const MU_LSR_TX_IDLE: u32 = 1 << 6;
+
+/// The physical address of the console UART, the one the boot stages reported on. For the
+/// mini UART, it's the address of the AUX block it's part of.
+static UART_BASE: AtomicUsize = AtomicUsize::new(UART0_BASE);
+/// Whether the console UART is the mini UART rather than a PL011.
+static MINI: AtomicBool = AtomicBool::new(false);
+
+/// The virtual offset the peripherals are currently reachable at.
+///
+/// The bootloader identity-maps the peripheral window, so this starts out at 0 and moves
+/// to the HHDM once the kernel's own page tables are live (see [`use_hhdm`]).
+static MMIO_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the registers of the peripheral at physical address `base`, wherever they're
+/// currently reachable.
+#[inline]
+fn regs(base: usize) -> RegisterBlock {
+    RegisterBlock::new(VirtAddr::new_canonical(
+        base + MMIO_OFFSET.load(Ordering::Relaxed),
+    ))
+}
+
+/// A kind of UART the console can be on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartKind {
+    /// An Arm PL011, like the BCM2711's UART0 and the ones the Pi 5 and QEMU have.
+    Pl011,
+    /// The BCM2711's mini UART, one of its auxiliary peripherals.
+    Mini,
+}
+
+impl UartKind {
+    /// Returns the kind of UART a `console=` option names, as Linux calls them on the Pi:
+    /// `ttyAMA0` for a PL011 and `ttyS0` for the mini UART, with or without a `,<baud>` after.
+    #[must_use]
+    pub fn from_console_option(console: &str) -> Option<Self> {
+        match console.split(',').next()? {
+            "ttyAMA0" => Some(Self::Pl011),
+            "ttyS0" => Some(Self::Mini),
+            _ => None,
+        }
+    }
+}
+
+/// Routes GPIO 14 and 15 to the given alternate `function`, without pulls.
+unsafe fn mux_pins(function: u32) {
+    let mut gpio = regs(GPIO_BASE);
+    unsafe {
+        gpio.clear_set(
+            GPFSEL1,
+            (0b111 << 12) | (0b111 << 15),
+            (function << 12) | (function << 15),
+        );
+        gpio.write(GPPUD, 0);
+        for _ in 0..150 {
+            core::arch::asm!("nop");
+        }
+        gpio.write(GPPUDCLK0, (1 << 14) | (1 << 15));
+        for _ in 0..150 {
+            core::arch::asm!("nop");
+        }
+        gpio.write(GPPUDCLK0, 0);
+    }
+}
 
 /// An instance of the GPIO UART driver.
 pub struct GpioUart {
@@ -41,88 +143,117 @@ pub struct GpioUart {
 
 impl GpioUart {
     /// Initializes the GPIO UART driver.
+    ///
+    /// Only the BCM2711's UART0 and the mini UART are set up from scratch. Any other was
+    /// configured by the boot stages, so it's just enabled.
     pub fn init(&mut self) {
-        use core::ptr::{read_volatile, write_volatile};
+        if MINI.load(Ordering::Relaxed) {
+            Self::init_mini();
+            return;
+        }
+        let mut uart = regs(uart_base());
+        if uart_base() != UART0_BASE {
+            unsafe {
+                uart.spin_while_hi(FR, FR_BUSY);
+                let cr = uart.read(CR);
+                uart.write(CR, cr | (1 << 9) | (1 << 8) | 1); // RXE | TXE | UARTEN
+            }
+            return;
+        }
+
+        let mut cm = regs(CM_BASE);
         // thanks, chatGPT
         unsafe {
             /* 0 ─── Enable the 48‑MHz UART clock (GPCLK UART) */
             //
-            //  DIV = 3  → 48 MHz   (PLLD: 540 MHz / 3 / 5 = 36 MHz; CM mixes 3 & 0 settings,
-            //                       but 48 MHz is what the Pi firmware & Linux use)
+            //  DIV = 3  → 48 MHz   (PLLD: 540 MHz / 3 / 5 = 36 MHz; CM mixes 3 & 0 settings,
+            //                       but 48 MHz is what the Pi firmware & Linux use)
             //  SRC = 6  → PLLD
             //  ENAB bit must be set last.
             //
-            write_volatile(CM_UARTDIV, 3); // DIVI = 3
-            write_volatile(CM_UARTCTL, 0x0000_2160); // ENAB | BUSY | SRC=PLLD | KILL=0
+            cm.write(CM_UARTDIV, 3); // DIVI = 3
+            cm.write(CM_UARTCTL, 0x0000_2160); // ENAB | BUSY | SRC=PLLD | KILL=0
             for _ in 0..150 {
                 core::arch::asm!("nop");
             } // ~150 core cycles
 
             /* 1 ─── Pin‑mux: GPIO 14/15 to ALT0 (TXD0/RXD0) */
-            let mut sel = read_volatile(GPFSEL1);
-            sel &= !((0b111 << 12) | (0b111 << 15)); // clear both fields
-            sel |= (0b100 << 12) | (0b100 << 15); // ALT0 = 0b100
-            write_volatile(GPFSEL1, sel);
-            // disable pulls
-            write_volatile(GPPUD, 0);
-            for _ in 0..150 {
-                core::arch::asm!("nop");
-            }
-            write_volatile(GPPUDCLK0, (1 << 14) | (1 << 15));
-            for _ in 0..150 {
-                core::arch::asm!("nop");
-            }
-            write_volatile(GPPUDCLK0, 0);
+            mux_pins(0b100);
 
             /* 2 ─── Disable UART, wait until BUSY clears */
-            write_volatile(CR, 0);
-            while read_volatile(FR) & (1 << 3) != 0 {} // BUSY
+            uart.write(CR, 0);
+            uart.spin_while_hi(FR, FR_BUSY);
 
             /* 3 ─── Clear pending interrupts */
-            write_volatile(ICR, 0x7FF);
+            uart.write(ICR, 0x7FF);
 
             // /* 4 ─── Baud: 921600 bps */
-            write_volatile(IBRD, 3);
-            write_volatile(FBRD, 16);
+            uart.write(IBRD, 3);
+            uart.write(FBRD, 16);
 
             /* 5 ─── 8 data bits, FIFO enabled */
-            write_volatile(LCRH, (1 << 4) | (3 << 5)); // FEN | WLEN=0b11 (8 bits)
+            uart.write(LCRH, (1 << 4) | (3 << 5)); // FEN | WLEN=0b11 (8 bits)
 
             /* 6 ─── Enable RX, TX and the UART */
-            write_volatile(CR, (1 << 9) | (1 << 8) | 1); // RXE | TXE | UARTEN
-            core::arch::asm!("dsb sy; isb");
+            uart.write(CR, (1 << 9) | (1 << 8) | 1); // RXE | TXE | UARTEN
+        }
+    }
+
+    /// Sets the mini UART up for the kernel's 921600 baud, 8N1, on GPIO 14 and 15.
+    fn init_mini() {
+        let mut aux = regs(uart_base());
+        unsafe {
+            aux.set(AUX_ENABLES, AUX_ENABLES_MINI_UART);
+            aux.write(MU_CNTL, 0);
+            aux.write(MU_IER, 0);
+            aux.write(MU_LCR, 0b11); // 8 bits
+            aux.write(MU_MCR, 0);
+            aux.write(MU_IIR, 0b11 << 1); // clear both FIFOs
+            // it runs off the core clock, which `enable_uart=1` has the firmware hold at
+            // 500 MHz: 500 MHz / (8 * (67 + 1)) is within 0.3% of 921600
+            aux.write(MU_BAUD, 67);
+
+            // GPIO 14/15 to ALT5 (TXD1/RXD1)
+            mux_pins(0b010);
+
+            aux.write(MU_CNTL, 0b11); // RX and TX enabled
+        }
+    }
+
+    /// Waits until everything written to the UART has been sent.
+    pub fn flush(&mut self) {
+        let uart = regs(uart_base());
+        unsafe {
+            if MINI.load(Ordering::Relaxed) {
+                uart.spin_while_lo(MU_LSR, MU_LSR_TX_IDLE);
+            } else {
+                uart.spin_while_hi(FR, FR_BUSY);
+            }
         }
     }
 
     /// Writes a character to the UART.
     #[inline]
     pub fn putchar(&mut self, c: u8) {
+        let mut uart = regs(uart_base());
         unsafe {
-            loop {
-                let fr = FR.read_volatile();
-                if fr & (1 << 5) != 0 {
-                    core::arch::asm!("nop");
-                } else {
-                    break;
-                }
+            if MINI.load(Ordering::Relaxed) {
+                uart.spin_while_lo(MU_LSR, MU_LSR_TX_EMPTY);
+                uart.write(MU_IO, u32::from(c));
+            } else {
+                uart.spin_while_hi(FR, FR_TXFF);
+                uart.write(DR, u32::from(c));
             }
-            DR.write_volatile(u32::from(c));
         }
     }
 
     /// Waits for a character to be available and reads it from the UART.
     #[inline]
     pub fn getchar(&mut self) -> u8 {
-        unsafe {
-            loop {
-                let fr = FR.read_volatile();
-                if fr & 0x10 != 0 {
-                    core::arch::asm!("nop");
-                } else {
-                    break;
-                }
+        loop {
+            if let Some(c) = self.try_getchar() {
+                return c;
             }
-            DR.read_volatile() as u8
         }
     }
 
@@ -131,12 +262,14 @@ impl GpioUart {
     /// Returns `Some(byte)` if a character is available, or `None` if not.
     #[inline]
     pub fn try_getchar(&mut self) -> Option<u8> {
+        let uart = regs(uart_base());
         unsafe {
-            let fr = FR.read_volatile();
-            if fr & 0x10 != 0 {
+            if MINI.load(Ordering::Relaxed) {
+                (uart.read(MU_LSR) & MU_LSR_DATA_READY != 0).then(|| uart.read(MU_IO) as u8)
+            } else if uart.read(FR) & FR_RXFE != 0 {
                 None
             } else {
-                Some(DR.read_volatile() as u8)
+                Some(uart.read(DR) as u8)
             }
         }
     }
@@ -161,12 +294,94 @@ pub fn lock_uart<'a>() -> MutexGuard<'a, GpioUart> {
     UART.lock()
 }
 
+/// Locks the UART, breaking the lock if it's already held.
+///
+/// # Safety
+///
+/// Whoever held the lock must never run again while the returned guard is alive, as when the
+/// debugger stub stops the kernel in the middle of a print, or it panics in the middle of one.
+pub unsafe fn force_lock_uart<'a>() -> MutexGuard<'a, GpioUart> {
+    if UART.is_locked() {
+        unsafe { UART.force_unlock() };
+    }
+    UART.lock()
+}
+
 /// Writes a formatted string to the UART.
 pub fn write_fmt(args: fmt::Arguments) {
     UART.lock().write_fmt(args).ok();
 }
 
-/// Initializes the GPIO UART driver.
-pub fn init() {
+/// Switches UART register accesses over to the HHDM.
+///
+/// Must be called after the kernel's page tables (which map the peripherals in the HHDM)
+/// are made current, and before the bootloader's identity mapping is torn down.
+pub fn use_hhdm() {
+    let _uart = UART.lock();
+    MMIO_OFFSET.store(HHDM_PHYSICAL_OFFSET, Ordering::Release);
+}
+
+#[inline]
+fn uart_base() -> usize {
+    UART_BASE.load(Ordering::Relaxed)
+}
+
+/// Returns the physical address of the console UART's registers.
+#[must_use]
+pub fn uart_phys() -> PhysAddr {
+    PhysAddr::new_canonical(uart_base())
+}
+
+/// Initializes the GPIO UART driver, on the UART the bootloader says the boot stages used, or
+/// the BCM2711's UART0 if it didn't say.
+pub fn init(boot_info: &abi::BootInfo) {
+    if boot_info.is_valid() && !boot_info.uart.is_empty() {
+        UART_BASE.store(boot_info.uart.start as usize, Ordering::Relaxed);
+    }
     UART.lock().init();
 }
+
+/// Moves the console to the mini UART, if the command line or the FDT ask for it.
+///
+/// `console=ttyS0` picks the mini UART, and `console=ttyAMA0` keeps the PL011 the boot stages
+/// used. Without either, the FDT's `/chosen/stdout-path` is followed if it names the mini UART,
+/// as the Pi 4's does when the firmware has given UART0 to Bluetooth. The boot stages only
+/// drive PL011s, so any other console stays on theirs.
+///
+/// Must be called before the kernel's page tables are made current, since they only map the
+/// console UART outside the peripheral window.
+pub fn select_console(fdt: &Fdt) {
+    let is_mini = |node: &crate::fdt::node::FdtNode| {
+        node.compatible()
+            .is_some_and(|compatible| compatible.all().any(|c| c == MINI_UART_COMPATIBLE))
+    };
+    let wanted = cmdline::get("console").and_then(UartKind::from_console_option);
+    let node = match wanted {
+        Some(UartKind::Pl011) => return,
+        Some(UartKind::Mini) => fdt.find_compatible(&[MINI_UART_COMPATIBLE]),
+        None => crate::fdt::stdout_node(fdt)
+            .map(|(node, _)| node)
+            .filter(is_mini),
+    };
+    let Some(node) = node else {
+        if wanted.is_some() {
+            log::warn!("console: no mini UART in the FDT; staying on the PL011");
+        }
+        return;
+    };
+    let Some(base) = node
+        .reg()
+        .and_then(|mut reg| reg.next())
+        .and_then(|region| crate::fdt::get_mmio_addr(fdt, &region))
+    else {
+        log::warn!("console: can't find the mini UART's registers; staying on the PL011");
+        return;
+    };
+
+    log::info!("console: moving to the mini UART at {base}");
+    let mut uart = UART.lock();
+    uart.flush();
+    UART_BASE.store(base.value() - MU_OFFSET, Ordering::Relaxed);
+    MINI.store(true, Ordering::Relaxed);
+    uart.init();
+}
@@ -0,0 +1,70 @@
+//! Entering and returning from user signal handlers.
+
+use crate::{
+    mem::user::{UserData, UserPtr},
+    syscall::errno::Errno,
+};
+
+use super::vectors::InterruptFrame;
+
+/// The condition flags, the only bits of `SPSR_EL1` a handler gets to change. Everything else
+/// is forced back to `EL0t` with interrupts unmasked on the way out of it.
+const SPSR_NZCV: usize = 0xf << 28;
+
+/// Pushed onto the user stack when a handler is entered, and popped off by `sigreturn`.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SignalFrame {
+    regs: InterruptFrame,
+    blocked: u64,
+}
+
+unsafe impl UserData for SignalFrame {}
+
+/// Changes `frame` to call `handler(sig)` when it's returned to, with `restorer` as the
+/// handler's return address. The registers it held before are saved on the user stack, along
+/// with the `blocked` signal mask.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if the user stack can't hold the saved registers, in which case
+/// `frame` is unchanged.
+pub fn enter_handler(
+    frame: &mut InterruptFrame,
+    sig: usize,
+    handler: usize,
+    restorer: usize,
+    blocked: u64,
+) -> Result<(), Errno> {
+    let sp = frame
+        .stack_pointer()
+        .checked_sub(size_of::<SignalFrame>())
+        .ok_or(Errno::EFAULT)?
+        & !0xf;
+    UserPtr::new(sp).write(SignalFrame {
+        regs: *frame,
+        blocked,
+    })?;
+
+    frame.set_stack_pointer(sp);
+    frame.set_instr_pointer(handler);
+    frame.scratch.x0 = sig;
+    frame.preserved.x30 = restorer;
+    Ok(())
+}
+
+/// Restores the registers saved by [`enter_handler`], which are at the user stack pointer in
+/// `frame` once the handler has returned, and returns the signal mask saved with them.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if the saved registers can't be read, in which case `frame` is
+/// unchanged.
+pub fn return_from_handler(frame: &mut InterruptFrame) -> Result<u64, Errno> {
+    let saved = UserPtr::<SignalFrame>::new(frame.stack_pointer()).read()?;
+    let esr = frame.iret.esr_el1;
+    *frame = saved.regs;
+    frame.iret.esr_el1 = esr;
+    frame.iret.spsr_el1 = saved.regs.iret.spsr_el1 & SPSR_NZCV;
+    Ok(saved.blocked)
+}
@@ -0,0 +1,60 @@
+//! Stack canaries for the kernel's own code.
+//!
+//! With the `stack-protector` feature, the builder compiles the kernel with
+//! `-Zstack-protector=strong`: functions with arrays or addresses of locals on the stack save
+//! a copy of [`__stack_chk_guard`] below their return address, and call [`__stack_chk_fail`]
+//! if it's changed by the time they return.
+//!
+//! The guard starts out as a constant, so the boot code can run with it, and is replaced with
+//! a random one by [`init`] once the RNG driver has had the chance to seed the kernel's
+//! [`rng`].
+
+use core::{arch::naked_asm, ptr::addr_of_mut};
+
+use crate::{rng, symbols};
+
+/// The canary every protected frame saves, and checks against before returning.
+#[unsafe(no_mangle)]
+pub static mut __stack_chk_guard: u64 = 0x00c0_ffee_d00d_5eed;
+
+/// Replaces the guard with a random one.
+///
+/// Every function still on the stack saved the old guard, and would fail its check if it
+/// returned, so this is inlined into [`kernel_main`](crate::kernel_main), which never
+/// returns, and has to be called before any task is created.
+///
+/// # Safety
+///
+/// See above.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub unsafe fn init() {
+    // a zero low byte stops string functions from reading the canary or writing past it
+    let guard = rng::next_u64() & !0xff;
+    unsafe { addr_of_mut!(__stack_chk_guard).write_volatile(guard) };
+    if !rng::is_hardware_seeded() {
+        log::warn!("stack protector: no hardware RNG; the guard is only as random as the timer");
+    }
+}
+
+/// Called by a protected function whose canary was overwritten, in place of returning.
+///
+/// It's naked so the address it was called from, which identifies the function, is still in
+/// the link register.
+#[unsafe(no_mangle)]
+#[unsafe(naked)]
+pub unsafe extern "C" fn __stack_chk_fail() -> ! {
+    naked_asm!(
+        "mov x0, x30",
+        "b {report}",
+        report = sym report,
+    );
+}
+
+extern "C" fn report(return_addr: usize) -> ! {
+    let caller = return_addr - 4;
+    match symbols::resolve(caller) {
+        Some(symbol) => panic!("stack smashing detected in {symbol} ({caller:#x})"),
+        None => panic!("stack smashing detected at {caller:#x}"),
+    }
+}
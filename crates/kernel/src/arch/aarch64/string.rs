@@ -0,0 +1,288 @@
+//! `memcpy`, `memmove` and `memset`, tuned for the kernel's biggest users of them: presenting
+//! the framebuffer and zeroing frames.
+//!
+//! These replace `compiler_builtins`' generic versions, whose symbols are weak. Copies move a
+//! 64-byte cache line at a time with paired loads and stores, once the destination is
+//! word-aligned; a source that can't be aligned along with it is shifted into place a word at
+//! a time, since the kernel is built for strict alignment. Large zero fills clear whole cache
+//! lines with `DC ZVA`, without reading them in first.
+//!
+//! NEON would move more per instruction, but the kernel is built without FP/SIMD, whose
+//! registers only ever hold a user task's values (see [`fpu`](super::fpu)).
+//!
+//! Nothing here may be used on device memory, which `DC ZVA` faults on and which is only ever
+//! accessed through [`Mmio`](super::drivers::mmio::Mmio). Byte and word loops use volatile accesses, so
+//! LLVM doesn't turn them back into calls to these very functions.
+
+// every word access is to an address the code has just aligned
+#![allow(clippy::cast_ptr_alignment)]
+
+use core::{arch::asm, ffi::c_void};
+
+/// How many bytes the bulk loops move at a time: a cache line.
+const BLOCK_SIZE: usize = 64;
+/// Copies shorter than this aren't worth aligning, and go a byte at a time.
+const MIN_ALIGNED: usize = 16;
+/// Zero fills at least this long clear whole blocks with `DC ZVA`.
+const ZVA_THRESHOLD: usize = 256;
+const WORD: usize = size_of::<u64>();
+
+/// `DCZID_EL0.DZP`: `DC ZVA` is prohibited.
+const DCZID_DZP: u64 = 1 << 4;
+const DCZID_BS_MASK: u64 = 0xf;
+
+/// Copies `n` bytes from `src` to `dst`, which mustn't overlap.
+///
+/// # Safety
+///
+/// `src` must be valid for reads and `dst` for writes of `n` bytes, both in normal memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memcpy(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
+    unsafe { copy_forward(dst.cast(), src.cast(), n) };
+    dst
+}
+
+/// Copies `n` bytes from `src` to `dst`, which may overlap.
+///
+/// # Safety
+///
+/// `src` must be valid for reads and `dst` for writes of `n` bytes, both in normal memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memmove(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
+    // copying forward is only a problem if `dst` starts inside the source
+    if dst.addr().wrapping_sub(src.addr()) >= n {
+        unsafe { copy_forward(dst.cast(), src.cast(), n) };
+    } else {
+        unsafe { copy_backward(dst.cast(), src.cast(), n) };
+    }
+    dst
+}
+
+/// Fills `n` bytes at `dst` with the low byte of `c`.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `n` bytes, in normal memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memset(dst: *mut c_void, c: i32, n: usize) -> *mut c_void {
+    unsafe { fill(dst.cast(), c as u8, n) };
+    dst
+}
+
+/// Returns the size of the block `DC ZVA` zeroes, or `None` if it can't be used.
+fn zva_block_size() -> Option<usize> {
+    let dczid: u64;
+    unsafe { asm!("mrs {}, dczid_el0", out(reg) dczid, options(nomem, nostack)) };
+    // BS is the log2 of the block size in 4-byte words
+    let size = 4 << (dczid & DCZID_BS_MASK);
+    (dczid & DCZID_DZP == 0 && size >= WORD).then_some(size)
+}
+
+unsafe fn copy_forward(mut dst: *mut u8, mut src: *const u8, mut n: usize) {
+    unsafe {
+        if n >= MIN_ALIGNED {
+            while !dst.addr().is_multiple_of(WORD) {
+                dst.write_volatile(src.read_volatile());
+                (dst, src, n) = (dst.add(1), src.add(1), n - 1);
+            }
+            let copied = if src.addr().is_multiple_of(WORD) {
+                let blocks = n & !(BLOCK_SIZE - 1);
+                if blocks != 0 {
+                    copy_blocks_forward(dst, src, blocks);
+                }
+                let words = (n - blocks) & !(WORD - 1);
+                for i in (blocks..blocks + words).step_by(WORD) {
+                    let word = src.add(i).cast::<u64>().read_volatile();
+                    dst.add(i).cast::<u64>().write_volatile(word);
+                }
+                blocks + words
+            } else {
+                copy_words_shifted(dst, src, n / WORD)
+            };
+            (dst, src, n) = (dst.add(copied), src.add(copied), n - copied);
+        }
+        for i in 0..n {
+            dst.add(i).write_volatile(src.add(i).read_volatile());
+        }
+    }
+}
+
+unsafe fn copy_backward(dst: *mut u8, src: *const u8, mut n: usize) {
+    unsafe {
+        if n >= MIN_ALIGNED && (dst.addr() ^ src.addr()).is_multiple_of(WORD) {
+            while !dst.add(n).addr().is_multiple_of(WORD) {
+                n -= 1;
+                dst.add(n).write_volatile(src.add(n).read_volatile());
+            }
+            let blocks = n & !(BLOCK_SIZE - 1);
+            if blocks != 0 {
+                copy_blocks_backward(dst.add(n), src.add(n), blocks);
+                n -= blocks;
+            }
+            while n >= WORD {
+                n -= WORD;
+                let word = src.add(n).cast::<u64>().read_volatile();
+                dst.add(n).cast::<u64>().write_volatile(word);
+            }
+        }
+        // a source that doesn't line up with the destination goes a byte at a time; overlapping
+        // moves to a higher address are rare enough not to be worth shifting
+        for i in (0..n).rev() {
+            dst.add(i).write_volatile(src.add(i).read_volatile());
+        }
+    }
+}
+
+/// Copies `len`, a non-zero multiple of [`BLOCK_SIZE`], bytes forward from `src` to `dst`,
+/// both word-aligned. Each block is loaded whole before it's stored, so `dst` may overlap the
+/// source from below.
+unsafe fn copy_blocks_forward(dst: *mut u8, src: *const u8, len: usize) {
+    unsafe {
+        asm!(
+            "2:",
+            "ldp {t0}, {t1}, [{src}]",
+            "ldp {t2}, {t3}, [{src}, #16]",
+            "ldp {t4}, {t5}, [{src}, #32]",
+            "ldp {t6}, {t7}, [{src}, #48]",
+            "add {src}, {src}, #64",
+            "stp {t0}, {t1}, [{dst}]",
+            "stp {t2}, {t3}, [{dst}, #16]",
+            "stp {t4}, {t5}, [{dst}, #32]",
+            "stp {t6}, {t7}, [{dst}, #48]",
+            "add {dst}, {dst}, #64",
+            "subs {len}, {len}, #64",
+            "b.ne 2b",
+            dst = inout(reg) dst => _,
+            src = inout(reg) src => _,
+            len = inout(reg) len => _,
+            t0 = out(reg) _, t1 = out(reg) _, t2 = out(reg) _, t3 = out(reg) _,
+            t4 = out(reg) _, t5 = out(reg) _, t6 = out(reg) _, t7 = out(reg) _,
+            options(nostack),
+        );
+    }
+}
+
+/// Copies `len`, a non-zero multiple of [`BLOCK_SIZE`], bytes backward to `dst_end` from
+/// `src_end`, the word-aligned ends of the two ranges. `dst` may overlap the source from
+/// above.
+unsafe fn copy_blocks_backward(dst_end: *mut u8, src_end: *const u8, len: usize) {
+    unsafe {
+        asm!(
+            "2:",
+            "ldp {t0}, {t1}, [{src}, #-16]",
+            "ldp {t2}, {t3}, [{src}, #-32]",
+            "ldp {t4}, {t5}, [{src}, #-48]",
+            "ldp {t6}, {t7}, [{src}, #-64]",
+            "sub {src}, {src}, #64",
+            "stp {t0}, {t1}, [{dst}, #-16]",
+            "stp {t2}, {t3}, [{dst}, #-32]",
+            "stp {t4}, {t5}, [{dst}, #-48]",
+            "stp {t6}, {t7}, [{dst}, #-64]",
+            "sub {dst}, {dst}, #64",
+            "subs {len}, {len}, #64",
+            "b.ne 2b",
+            dst = inout(reg) dst_end => _,
+            src = inout(reg) src_end => _,
+            len = inout(reg) len => _,
+            t0 = out(reg) _, t1 = out(reg) _, t2 = out(reg) _, t3 = out(reg) _,
+            t4 = out(reg) _, t5 = out(reg) _, t6 = out(reg) _, t7 = out(reg) _,
+            options(nostack),
+        );
+    }
+}
+
+/// Copies `words` words to the word-aligned `dst` from the misaligned `src`, loading aligned
+/// words and shifting each pair into place. Returns how many bytes were copied.
+unsafe fn copy_words_shifted(dst: *mut u8, src: *const u8, words: usize) -> usize {
+    let shift = (src.addr() % WORD) * 8;
+    let aligned = src.map_addr(|addr| addr & !(WORD - 1)).cast::<u64>();
+    let dst = dst.cast::<u64>();
+    unsafe {
+        // the last load reaches up to 7 bytes past the end of the source, but never past the
+        // end of the aligned word, so never into another page
+        let mut prev = aligned.read_volatile();
+        for i in 0..words {
+            let next = aligned.add(i + 1).read_volatile();
+            dst.add(i)
+                .write_volatile((prev >> shift) | (next << (64 - shift)));
+            prev = next;
+        }
+    }
+    words * WORD
+}
+
+unsafe fn fill(mut dst: *mut u8, byte: u8, mut n: usize) {
+    unsafe {
+        if n >= MIN_ALIGNED {
+            while !dst.addr().is_multiple_of(WORD) {
+                dst.write_volatile(byte);
+                (dst, n) = (dst.add(1), n - 1);
+            }
+            let word = u64::from(byte) * 0x0101_0101_0101_0101;
+            if byte == 0
+                && n >= ZVA_THRESHOLD
+                && let Some(block) = zva_block_size()
+                && n >= 2 * block
+            {
+                while !dst.addr().is_multiple_of(block) {
+                    dst.cast::<u64>().write_volatile(0);
+                    (dst, n) = (dst.add(WORD), n - WORD);
+                }
+                let zeroed = n & !(block - 1);
+                zero_blocks(dst, zeroed, block);
+                (dst, n) = (dst.add(zeroed), n - zeroed);
+            }
+            let blocks = n & !(BLOCK_SIZE - 1);
+            if blocks != 0 {
+                fill_blocks(dst, word, blocks);
+                (dst, n) = (dst.add(blocks), n - blocks);
+            }
+            while n >= WORD {
+                dst.cast::<u64>().write_volatile(word);
+                (dst, n) = (dst.add(WORD), n - WORD);
+            }
+        }
+        for i in 0..n {
+            dst.add(i).write_volatile(byte);
+        }
+    }
+}
+
+/// Fills `len`, a non-zero multiple of [`BLOCK_SIZE`], bytes at the word-aligned `dst` with
+/// `word`.
+unsafe fn fill_blocks(dst: *mut u8, word: u64, len: usize) {
+    unsafe {
+        asm!(
+            "2:",
+            "stp {word}, {word}, [{dst}]",
+            "stp {word}, {word}, [{dst}, #16]",
+            "stp {word}, {word}, [{dst}, #32]",
+            "stp {word}, {word}, [{dst}, #48]",
+            "add {dst}, {dst}, #64",
+            "subs {len}, {len}, #64",
+            "b.ne 2b",
+            dst = inout(reg) dst => _,
+            len = inout(reg) len => _,
+            word = in(reg) word,
+            options(nostack),
+        );
+    }
+}
+
+/// Zeroes `len`, a non-zero multiple of `block`, bytes at `dst`, aligned to `block`, with
+/// `DC ZVA`.
+unsafe fn zero_blocks(dst: *mut u8, len: usize, block: usize) {
+    unsafe {
+        asm!(
+            "2:",
+            "dc zva, {dst}",
+            "add {dst}, {dst}, {block}",
+            "subs {len}, {len}, {block}",
+            "b.ne 2b",
+            dst = inout(reg) dst => _,
+            len = inout(reg) len => _,
+            block = in(reg) block,
+            options(nostack),
+        );
+    }
+}
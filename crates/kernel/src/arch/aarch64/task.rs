@@ -1,8 +1,17 @@
-use core::mem::offset_of;
+use core::{arch::asm, fmt, mem::offset_of};
 
-use crate::task::{context::Context, stack::Stack};
+use alloc::boxed::Box;
 
-use super::vectors::{InterruptFrame, enter_usermode};
+use crate::{
+    arch::Architecture,
+    task::{context::Context, stack::Stack},
+};
+
+use super::{
+    AArch64,
+    fpu::{self, FpState},
+    vectors::{InterruptFrame, enter_usermode, swap_exception_depth},
+};
 
 /// The architecture-specific context for a task.
 #[derive(Debug, Clone, Default)]
@@ -25,6 +34,11 @@ pub struct ArchContext {
     x21: usize,
     x20: usize,
     x19: usize,
+    /// How many exception handlers the task was nested in when it was switched out.
+    exception_depth: usize,
+    /// The task's FP/SIMD registers, from when it was last switched out having used them.
+    /// `None` until it first uses them.
+    fp_state: Option<Box<FpState>>,
 }
 
 impl ArchContext {
@@ -47,15 +61,109 @@ impl ArchContext {
         }
 
         self.sp = stack_top as usize;
+        self.lr = sign_return_address(self.lr, self.sp);
+    }
+
+    /// Sets where a user task whose kernel stack is `stack` starts running in userspace once
+    /// the entry function given to [`setup_initial_call`](Self::setup_initial_call) returns: at
+    /// `pc`, with the stack pointer at `sp`.
+    pub fn set_user_entry(stack: &Stack, pc: usize, sp: usize) {
+        let frame = unsafe {
+            &mut *stack
+                .initial_top()
+                .sub(size_of::<InterruptFrame>())
+                .cast::<InterruptFrame>()
+        };
+        frame.set_instr_pointer(pc);
+        frame.set_stack_pointer(sp);
+    }
+
+    /// Returns the saved kernel stack pointer.
+    #[must_use]
+    pub fn stack_pointer(&self) -> usize {
+        self.sp
+    }
+
+    /// Returns the saved frame pointer (`x29`).
+    #[must_use]
+    pub fn frame_pointer(&self) -> usize {
+        self.fp
+    }
+
+    /// Returns the address the task will resume at once it is switched back in (`x30`).
+    #[must_use]
+    pub fn return_address(&self) -> usize {
+        AArch64::strip_return_address(self.lr)
+    }
+
+    /// Lets the task use FP/SIMD after it trapped trying to, loading its registers as they
+    /// were when it was last switched out, or zeroed if it hasn't used them before.
+    pub fn restore_fp(&mut self) {
+        let state = self.fp_state.get_or_insert_default();
+        fpu::set_enabled(true);
+        unsafe { fpu::restore(state) };
     }
 }
 
+impl fmt::Display for ArchContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let callee_saved = [
+            ("x19", self.x19),
+            ("x20", self.x20),
+            ("x21", self.x21),
+            ("x22", self.x22),
+            ("x23", self.x23),
+            ("x24", self.x24),
+            ("x25", self.x25),
+            ("x26", self.x26),
+            ("x27", self.x27),
+            ("x28", self.x28),
+        ];
+        for pair in callee_saved.chunks(2) {
+            for (name, value) in pair {
+                write!(f, "{name:>8}={value:#018x} ")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "{:>8}={:#018x} {:>8}={:#018x}", "fp", self.fp, "lr", self.lr)?;
+        writeln!(f, "{:>8}={:#018x} {:>8}={:#018x}", "sp", self.sp, "sp_el0", self.sp_el0)?;
+        writeln!(
+            f,
+            "{:>8}={:#018x} {:>8}={:#018x}",
+            "elr_el1", self.elr_el1, "spsr_el1", self.spsr_el1
+        )?;
+        write!(f, "{:>8}={:#018x}", "esr_el1", self.esr_el1)
+    }
+}
+
+/// Signs `lr` against `sp`, as [`switch_to_inner`] does a switched out task's return address.
+fn sign_return_address(lr: usize, sp: usize) -> usize {
+    let signed;
+    unsafe {
+        asm!(
+            "pacia1716",
+            inout("x17") lr => signed,
+            in("x16") sp,
+            options(pure, nomem, nostack),
+        );
+    }
+    signed
+}
+
 /// Switches the current task's context to the next task's context.
 ///
 /// # Panics
 ///
 /// This function will panic if there is no current CPU-local block.
 pub unsafe fn switch_to(prev: &mut Context, next: &mut Context) {
+    prev.arch.exception_depth = swap_exception_depth(next.arch.exception_depth);
+    if fpu::is_enabled() {
+        // `prev` used FP/SIMD, so its registers are live; `next` gets them by trapping
+        if let Some(state) = prev.arch.fp_state.as_deref_mut() {
+            unsafe { fpu::save(state) };
+        }
+        fpu::set_enabled(false);
+    }
     unsafe {
         switch_to_inner(&mut prev.arch, &mut next.arch);
     }
@@ -98,7 +206,12 @@ unsafe extern "C" fn switch_to_inner(_prev: &mut ArchContext, _next: &mut ArchCo
         str x29, [x0, #{off_x29}]
         ldr x29, [x1, #{off_x29}]
 
-        str x30, [x0, #{off_x30}]
+        // sign the return address against the stack it goes with; `pacia1716` and
+        // `autia1716` are hints, which do nothing unless pointer authentication is on
+        mov x17, x30
+        mov x16, sp
+        pacia1716
+        str x17, [x0, #{off_x30}]
         ldr x30, [x1, #{off_x30}]
 
         mrs x2, elr_el1
@@ -126,6 +239,11 @@ unsafe extern "C" fn switch_to_inner(_prev: &mut ArchContext, _next: &mut ArchCo
         ldr x2, [x1, #{off_sp}]
         mov sp, x2
 
+        mov x17, x30
+        mov x16, sp
+        autia1716
+        mov x30, x17
+
         b {switch_hook}
         ",
         off_x19 = const(offset_of!(ArchContext, x19)),
@@ -1,4 +1,7 @@
-use core::time::Duration;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use aarch64_cpu::{
     asm::barrier,
@@ -9,10 +12,15 @@ use aarch64_cpu::{
 use fdt::Fdt;
 
 use crate::{
-    irq::{Irq, IrqHandler, register_irq},
-    task::switch::switch,
+    irq::{self, Irq, IrqHandler, PRIORITY_TIMER, register_irq},
+    task::switch::request_switch,
 };
 
+/// The number of timer interrupts taken so far.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The uptime when the last timer interrupt was taken, in nanoseconds.
+static LAST_TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+
 /// Initializes the generic timer for the `AArch64` architecture.
 pub fn init(_fdt: &Fdt) {
     let mut timer = GenericTimer::default();
@@ -20,6 +28,7 @@ pub fn init(_fdt: &Fdt) {
 
     let irq = Irq::from(30);
     unsafe { register_irq(irq, timer) };
+    irq::set_priority(irq, PRIORITY_TIMER);
 }
 
 /// The generic timer for the `AArch64` architecture.
@@ -59,9 +68,19 @@ impl GenericTimer {
 
 impl IrqHandler for GenericTimer {
     fn handle_irq(&mut self, _irq: Irq) {
+        let now = uptime();
+        LAST_TICK_NANOS.store(now.as_nanos() as u64, Ordering::Relaxed);
+        TICKS.fetch_add(1, Ordering::Release);
         self.clear_irq();
-        switch();
         self.reload_count();
+        crate::time::tick(now);
+        // switching here would leave the IRQ chip locked and the interrupt active in the next task
+        request_switch();
+    }
+
+    fn is_pending(&self, _irq: Irq) -> bool {
+        // ISTATUS reflects the timer condition even while the interrupt is masked
+        CNTP_CTL_EL0.is_set(CNTP_CTL_EL0::ISTATUS)
     }
 }
 
@@ -79,6 +98,18 @@ pub fn uptime() -> Duration {
     Duration::new(secs as u64, nanos)
 }
 
+/// Returns the number of timer interrupts taken so far.
+#[must_use]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Returns the uptime at which the last timer interrupt was taken.
+#[must_use]
+pub fn last_tick() -> Duration {
+    Duration::from_nanos(LAST_TICK_NANOS.load(Ordering::Relaxed))
+}
+
 /// Spins for the specified duration, busy-waiting until the duration has elapsed.
 #[inline]
 pub fn spin_for(dur: Duration) {
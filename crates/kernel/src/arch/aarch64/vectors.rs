@@ -1,8 +1,24 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
 use aarch64_cpu::registers::{FAR_EL1, Readable};
+use kados_abi::{
+    signal::{SIGBUS, SIGILL, SIGSEGV},
+    syscall::Sysno,
+};
 
-use crate::irq::irq_chip;
+use crate::irq;
 use crate::mem::paging::table::{PageTable, TableKind};
 use crate::mem::units::VirtAddr;
+use crate::syscall;
+use crate::task::{context, signal};
+
+use super::{
+    crash::CrashReport,
+    esr::{Esr, ExceptionClass, FaultStatus},
+};
 
 core::arch::global_asm!(
     r#"
@@ -64,6 +80,137 @@ pub unsafe fn exception_vector_table() -> VirtAddr {
     unsafe { VirtAddr::new_unchecked(&raw const __exception_vectors as usize) }
 }
 
+/// The kind of exception a vector handles, in vector table order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    Sync,
+    Irq,
+    Fiq,
+    SError,
+}
+
+impl ExceptionKind {
+    const ALL: [Self; 4] = [Self::Sync, Self::Irq, Self::Fiq, Self::SError];
+}
+
+impl fmt::Display for ExceptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Sync => "sync",
+            Self::Irq => "irq",
+            Self::Fiq => "fiq",
+            Self::SError => "serror",
+        })
+    }
+}
+
+/// Where an exception was taken from, in vector table order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionOrigin {
+    /// The current EL, running on `SP_EL0`.
+    CurrentElSp0,
+    /// The current EL, running on `SP_EL1`.
+    CurrentElSpx,
+    /// A lower EL running in `AArch64`.
+    LowerElA64,
+    /// A lower EL running in `AArch32`.
+    LowerElA32,
+}
+
+impl ExceptionOrigin {
+    const ALL: [Self; 4] = [
+        Self::CurrentElSp0,
+        Self::CurrentElSpx,
+        Self::LowerElA64,
+        Self::LowerElA32,
+    ];
+}
+
+impl fmt::Display for ExceptionOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::CurrentElSp0 => "current EL, SP0",
+            Self::CurrentElSpx => "current EL, SPx",
+            Self::LowerElA64 => "lower EL, AArch64",
+            Self::LowerElA32 => "lower EL, AArch32",
+        })
+    }
+}
+
+const VECTOR_COUNT: usize = 16;
+
+static VECTOR_ENTRIES: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+static VECTOR_EXITS: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+
+/// How many exception handlers the running task is nested in.
+static EXCEPTION_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// The deepest [`EXCEPTION_DEPTH`] has been since boot.
+static MAX_EXCEPTION_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+fn vector_index(origin: ExceptionOrigin, kind: ExceptionKind) -> usize {
+    origin as usize * ExceptionKind::ALL.len() + kind as usize
+}
+
+/// Counts a handler from entry until it's dropped on the way back out of the vector.
+struct VectorGuard {
+    index: usize,
+}
+
+impl VectorGuard {
+    fn enter(origin: ExceptionOrigin, kind: ExceptionKind) -> Self {
+        let index = vector_index(origin, kind);
+        VECTOR_ENTRIES[index].fetch_add(1, Ordering::Relaxed);
+        let depth = EXCEPTION_DEPTH.fetch_add(1, Ordering::Relaxed) + 1;
+        MAX_EXCEPTION_DEPTH.fetch_max(depth, Ordering::Relaxed);
+        Self { index }
+    }
+}
+
+impl Drop for VectorGuard {
+    fn drop(&mut self) {
+        EXCEPTION_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        VECTOR_EXITS[self.index].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How often one exception vector has been taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorCounts {
+    pub origin: ExceptionOrigin,
+    pub kind: ExceptionKind,
+    /// The number of times the vector was entered.
+    pub entries: u64,
+    /// The number of times its handler returned. This lags behind `entries` for handlers
+    /// that are still running, including those of tasks switched out from an interrupt.
+    pub exits: u64,
+}
+
+/// Returns the counts for every exception vector, in vector table order.
+pub fn vector_counts() -> impl Iterator<Item = VectorCounts> {
+    ExceptionOrigin::ALL.into_iter().flat_map(|origin| {
+        ExceptionKind::ALL.into_iter().map(move |kind| {
+            let index = vector_index(origin, kind);
+            VectorCounts {
+                origin,
+                kind,
+                entries: VECTOR_ENTRIES[index].load(Ordering::Relaxed),
+                exits: VECTOR_EXITS[index].load(Ordering::Relaxed),
+            }
+        })
+    })
+}
+
+/// Returns the deepest exception handlers have been nested since boot.
+#[must_use]
+pub fn max_exception_depth() -> usize {
+    MAX_EXCEPTION_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Swaps the exception nesting depth of the outgoing task for that of the incoming one.
+pub(super) fn swap_exception_depth(depth: usize) -> usize {
+    EXCEPTION_DEPTH.swap(depth, Ordering::Relaxed)
+}
+
 /// Registers used for returning from an interrupt or exception.
 #[derive(Default, Clone, Copy)]
 #[repr(C, packed)]
@@ -82,7 +229,7 @@ impl IretRegs {
     pub fn dump(&self) {
         log::error!("ELR_EL1: {:>016X}", { self.elr_el1 });
         log::error!("SPSR_EL1: {:>016X}", { self.spsr_el1 });
-        log::error!("ESR_EL1: {:>016X}", { self.esr_el1 });
+        log::error!("ESR_EL1: {:>016X} ({})", { self.esr_el1 }, Esr(self.esr_el1));
         log::error!("SP_EL0: {:>016X}", { self.sp_el0 });
     }
 }
@@ -302,11 +449,12 @@ macro_rules! pop_special {
 
 #[macro_export]
 macro_rules! exception_stack {
-    ($name:ident, |$stack:ident| $code:block) => {
+    ($name:ident, $origin:ident, $kind:ident, |$stack:ident| $code:block) => {
         #[unsafe(naked)]
         #[unsafe(no_mangle)]
         pub unsafe extern "C" fn $name(stack: &mut InterruptFrame) {
             unsafe extern "C" fn inner($stack: &mut InterruptFrame) {
+                let _vector = VectorGuard::enter(ExceptionOrigin::$origin, ExceptionKind::$kind);
                 $code
             }
             core::arch::naked_asm!(concat!(
@@ -320,17 +468,31 @@ macro_rules! exception_stack {
                 "mov x0, sp\n",
                 "bl {}",
 
+                // Act on pending signals if we're going back to userspace
+                "mov x0, sp\n",
+                "bl {}",
+
                 // Restore all userspace registers
                 pop_special!(),
                 pop_scratch!(),
                 pop_preserved!(),
 
                 "eret\n",
-            ), sym inner);
+            ), sym inner, sym exception_exit);
         }
     };
 }
 
+/// The mode bits of `SPSR_EL1`, which are all clear when returning to EL0.
+const SPSR_MODE_MASK: usize = 0b1111;
+
+/// Runs just before every exception handler restores the interrupted registers.
+unsafe extern "C" fn exception_exit(stack: &mut InterruptFrame) {
+    if stack.iret.spsr_el1 & SPSR_MODE_MASK == 0 {
+        signal::deliver(stack);
+    }
+}
+
 #[unsafe(naked)]
 pub unsafe extern "C" fn enter_usermode() -> ! {
     core::arch::naked_asm!(concat!(
@@ -343,128 +505,125 @@ pub unsafe extern "C" fn enter_usermode() -> ! {
     ));
 }
 
-#[must_use]
-pub fn exception_code(esr: usize) -> u8 {
-    ((esr >> 26) & 0x3f) as u8
-}
-
-exception_stack!(__sync_current_el_sp0, |stack| {
+exception_stack!(__sync_current_el_sp0, CurrentElSp0, Sync, |stack| {
     stack.dump();
     panic!("{}", stringify!(__sync_current_el_sp0))
 });
-exception_stack!(__irq_current_el_sp0, |_stack| {
+exception_stack!(__irq_current_el_sp0, CurrentElSp0, Irq, |_stack| {
     handle_irq();
 });
-exception_stack!(__fiq_current_el_sp0, |stack| {
+exception_stack!(__fiq_current_el_sp0, CurrentElSp0, Fiq, |stack| {
     stack.dump();
     panic!("{}", stringify!(__fiq_current_el_sp0))
 });
-exception_stack!(__serr_current_el_sp0, |stack| {
+exception_stack!(__serr_current_el_sp0, CurrentElSp0, SError, |stack| {
     stack.dump();
     panic!("{}", stringify!(__serr_current_el_sp0))
 });
-exception_stack!(__sync_current_el_spx, |stack| {
-    let error_code = exception_code(stack.iret.esr_el1);
-    // if error_code == 0x3c {
-    //     super::debugging::on_irq(stack, StopReason::SwBreakpoint);
-    //     return;
-    // } else if error_code == 0x0e {
-    //     super::debugging::on_irq(stack, StopReason::HwBreakpoint);
-    //     return;
-    // }
+exception_stack!(__sync_current_el_spx, CurrentElSpx, Sync, |stack| {
+    let esr = Esr(stack.iret.esr_el1);
+    #[cfg(feature = "gdb")]
+    if let Some(reason) = super::debugging::StopReason::from_esr(esr) {
+        super::debugging::on_irq(stack, reason);
+        return;
+    }
     log::error!("SYNCHRONOUS EXCEPTION (current EL, SPX)");
-    log::error!("Code: {error_code:#x}");
-    if error_code == 0x25 {
-        log::error!("Translation Fault");
-        let faulted_addr = unsafe { VirtAddr::new_unchecked(FAR_EL1.get() as usize) };
-        log::error!("Faulted addr: {faulted_addr}");
-
-        let iss = stack.iret.esr_el1 & 0x01ff_ffff;
-        let wn_r = (iss >> 6) & 1 == 1;
-        let dfsc = iss & 0x3f;
-
-        match dfsc {
-            0b00_0000..=0b00_0011 => page_not_present(faulted_addr, wn_r, dfsc),
-            0b00_1101..=0b00_1111 => permission_fault(faulted_addr, wn_r, dfsc),
-            0b00_1001..=0b00_1011 => access_flag_fault(faulted_addr, wn_r, dfsc),
-            _ => unhandled_fault(faulted_addr, wn_r, dfsc),
+    log::error!("{esr}");
+    if let Some(abort) = esr.abort() {
+        if abort.far_valid {
+            let faulted_addr = unsafe { VirtAddr::new_unchecked(FAR_EL1.get() as usize) };
+            log::error!("Faulted addr: {faulted_addr}");
         }
+        let table = PageTable::current(TableKind::Kernel);
+        log::error!("current table: {}", table.phys_addr());
     }
     panic!("{}", stringify!(__sync_current_el_spx))
 });
-exception_stack!(__irq_current_el_spx, |_stack| {
+exception_stack!(__irq_current_el_spx, CurrentElSpx, Irq, |_stack| {
     handle_irq();
 });
-exception_stack!(__fiq_current_el_spx, |stack| {
+exception_stack!(__fiq_current_el_spx, CurrentElSpx, Fiq, |stack| {
     stack.dump();
     panic!("{}", stringify!(__fiq_current_el_spx))
 });
-exception_stack!(__serr_current_el_spx, |stack| {
+exception_stack!(__serr_current_el_spx, CurrentElSpx, SError, |stack| {
     stack.dump();
     panic!("{}", stringify!(__serr_current_el_spx))
 });
-exception_stack!(__sync_lower_el_a64, |stack| {
-    match exception_code(stack.iret.esr_el1) {
-        0b01_0101 => {
-            log::debug!("Syscall!");
-        }
-        code => {
-            log::error!("{:#b}", code);
+exception_stack!(__sync_lower_el_a64, LowerElA64, Sync, |stack| {
+    let esr = Esr(stack.iret.esr_el1);
+    if esr.class() == ExceptionClass::Svc64 {
+        let ScratchRegs { x0, x1, x2, x3, x4, x5, x8, .. } = stack.scratch;
+        if x8 == Sysno::Sigreturn as usize {
+            signal::sigreturn(stack);
+            return;
         }
+        stack.scratch.x0 = syscall::handle(x8, [x0, x1, x2, x3, x4, x5]);
+        return;
+    }
+    if esr.class() == ExceptionClass::FpAccess
+        && let Some(cx) = context::current()
+    {
+        cx.write().arch.restore_fp();
+        return;
     }
 
-    stack.dump();
-    panic!("{}", stringify!(__sync_lower_el_a64))
+    let sig = match (esr.class(), esr.abort()) {
+        (_, Some(abort)) if abort.status == FaultStatus::Alignment => SIGBUS,
+        (_, Some(_)) => SIGSEGV,
+        (ExceptionClass::PcAlignment | ExceptionClass::SpAlignment, _) => SIGBUS,
+        _ => SIGILL,
+    };
+    kill_faulting_task(stack, sig, stringify!(__sync_lower_el_a64));
 });
-exception_stack!(__irq_lower_el_a64, |_stack| {
+exception_stack!(__irq_lower_el_a64, LowerElA64, Irq, |_stack| {
     handle_irq();
 });
-exception_stack!(__fiq_lower_el_a64, |stack| {
+exception_stack!(__fiq_lower_el_a64, LowerElA64, Fiq, |stack| {
     stack.dump();
     panic!("{}", stringify!(__fiq_lower_el_a64))
 });
-exception_stack!(__serr_lower_el_a64, |stack| {
-    stack.dump();
-    panic!("{}", stringify!(__serr_lower_el_a64))
+exception_stack!(__serr_lower_el_a64, LowerElA64, SError, |stack| {
+    kill_faulting_task(stack, SIGBUS, stringify!(__serr_lower_el_a64));
 });
-exception_stack!(__sync_lower_el_a32, |stack| {
-    stack.dump();
-    panic!("{}", stringify!(__sync_lower_el_a32))
+exception_stack!(__sync_lower_el_a32, LowerElA32, Sync, |stack| {
+    // tasks never run in AArch32, so one that got there did something illegal
+    kill_faulting_task(stack, SIGILL, stringify!(__sync_lower_el_a32));
 });
-exception_stack!(__irq_lower_el_a32, |_stack| {
+exception_stack!(__irq_lower_el_a32, LowerElA32, Irq, |_stack| {
     handle_irq();
 });
-exception_stack!(__fiq_lower_el_a32, |stack| {
+exception_stack!(__fiq_lower_el_a32, LowerElA32, Fiq, |stack| {
     stack.dump();
     panic!("{}", stringify!(__fiq_lower_el_a32))
 });
-exception_stack!(__serr_lower_el_a32, |stack| {
-    stack.dump();
-    panic!("{}", stringify!(__serr_lower_el_a32))
+exception_stack!(__serr_lower_el_a32, LowerElA32, SError, |stack| {
+    kill_faulting_task(stack, SIGBUS, stringify!(__serr_lower_el_a32));
 });
 
-fn page_not_present(_faulted_addr: VirtAddr, caused_by_write: bool, _dfsc: usize) {
-    log::error!("Page not present (write = {caused_by_write})");
-}
-fn permission_fault(_faulted_addr: VirtAddr, caused_by_write: bool, _dfsc: usize) {
-    log::error!("Permission fault (write = {caused_by_write})");
-}
-fn access_flag_fault(_faulted_addr: VirtAddr, caused_by_write: bool, _dfsc: usize) {
-    log::error!("Access flag fault (write = {caused_by_write})");
-}
-fn unhandled_fault(_faulted_addr: VirtAddr, caused_by_write: bool, dfsc: usize) {
-    log::error!("Unhandled fault (write = {caused_by_write})");
-    log::error!("dfsc: {dfsc:#b}");
-
-    let table = PageTable::current(TableKind::Kernel);
-    log::error!("current table: {}", table.phys_addr());
+/// Kills the current task for an exception it took in userspace, as if by signal `sig`,
+/// keeping a [`CrashReport`] on it, and switches to another task.
+///
+/// # Panics
+///
+/// Panics if there's no current user task to blame, which means the kernel's own state is
+/// broken.
+fn kill_faulting_task(stack: &InterruptFrame, sig: usize, vector: &str) -> ! {
+    let report = CrashReport::capture(stack);
+    report.log();
+    let Some(cx) = context::current().filter(|cx| cx.read().userspace) else {
+        panic!("{vector} with no user task to blame");
+    };
+    {
+        let mut cx = cx.write();
+        cx.stats.record_fault();
+        cx.crash = Some(report);
+    }
+    drop(cx);
+    signal::terminate(sig);
+    unreachable!()
 }
 
 fn handle_irq() {
-    let mut chip = irq_chip();
-    let irq = chip.ack();
-
-    log::trace!("IRQ {irq} caught");
-    chip.handle_irq(irq);
-    chip.eoi(irq);
+    irq::dispatch();
 }
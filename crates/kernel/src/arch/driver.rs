@@ -1,13 +1,142 @@
-use fdt::Fdt;
+//! Matching device tree nodes to the drivers that handle them.
+//!
+//! Each driver declares the `compatible` strings it binds to and the drivers it needs
+//! probed first. [`probe_all`] walks the FDT, matches every enabled node against the
+//! drivers, and probes the matches in dependency order, recording the outcome of each so
+//! it can be listed later with [`devices`].
+
+use alloc::{string::String, vec::Vec};
+use fdt::{
+    Fdt,
+    node::{FdtNode, NodeProperty},
+};
+use spin::RwLock;
 
 use crate::syscall::errno::Errno;
 
-use super::Architecture;
+static DEVICES: RwLock<Vec<Device>> = RwLock::new(Vec::new());
+
+/// A driver, and the device tree nodes it can bind to.
+pub struct Driver {
+    /// The driver's name, which other drivers refer to it by in [`Driver::depends_on`].
+    pub name: &'static str,
+    /// The `compatible` strings of the nodes the driver binds to.
+    pub compatible: &'static [&'static str],
+    /// The drivers that must have bound a device before this one is probed.
+    pub depends_on: &'static [&'static str],
+    /// Sets up the device described by a matching node.
+    ///
+    /// Returning [`Errno::ENODEV`] means there turned out to be nothing there to bind to,
+    /// such as an empty slot, and the node isn't recorded as a device.
+    pub probe: fn(&Fdt, &FdtNode<'_, '_>) -> Result<(), Errno>,
+}
+
+/// The outcome of probing a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    /// The driver bound to the device.
+    Bound,
+    /// The driver's probe failed.
+    Failed(Errno),
+    /// The device wasn't probed, because the named driver it depends on never bound.
+    MissingDependency(&'static str),
+}
+
+/// A device tree node that a driver matched.
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// The name of the node.
+    pub node: String,
+    /// The name of the driver that matched it.
+    pub driver: &'static str,
+    /// The outcome of probing it.
+    pub status: DeviceStatus,
+}
+
+fn is_enabled(node: &FdtNode) -> bool {
+    node.property("status")
+        .and_then(NodeProperty::as_str)
+        .is_none_or(|status| status == "okay" || status == "ok")
+}
+
+/// Finds the driver for `node`, preferring its most specific `compatible` string.
+fn find_driver(node: &FdtNode, drivers: &[&'static Driver]) -> Option<&'static Driver> {
+    node.compatible()?.all().find_map(|compatible| {
+        drivers
+            .iter()
+            .find(|driver| driver.compatible.contains(&compatible))
+            .copied()
+    })
+}
+
+fn is_bound(devices: &[Device], driver: &str) -> bool {
+    devices
+        .iter()
+        .any(|device| device.driver == driver && device.status == DeviceStatus::Bound)
+}
+
+/// Probes a driver for every enabled node in `fdt` that one of `drivers` matches.
+///
+/// A device is probed once all the drivers it depends on have bound a device. Devices whose
+/// dependencies never bind aren't probed at all.
+pub fn probe_all(fdt: &Fdt, drivers: &[&'static Driver]) {
+    let mut pending: Vec<_> = fdt
+        .all_nodes()
+        .filter(is_enabled)
+        .filter_map(|node| Some((find_driver(&node, drivers)?, node)))
+        .collect();
 
-pub trait Driver: 'static {
-    type Arch: Architecture;
+    let mut devices = Vec::new();
+    while let Some(ready) = pending
+        .iter()
+        .position(|(driver, _)| driver.depends_on.iter().all(|dep| is_bound(&devices, dep)))
+    {
+        let (driver, node) = pending.remove(ready);
+        let status = match (driver.probe)(fdt, &node) {
+            Ok(()) => {
+                log::debug!("{}: bound to {}", node.name, driver.name);
+                DeviceStatus::Bound
+            }
+            Err(Errno::ENODEV) => {
+                log::debug!("{}: no device for {}", node.name, driver.name);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("{}: {} failed to probe: {e:?}", node.name, driver.name);
+                DeviceStatus::Failed(e)
+            }
+        };
+        devices.push(Device {
+            node: String::from(node.name),
+            driver: driver.name,
+            status,
+        });
+    }
 
-    const CONST_DEFAULT: Self;
+    for (driver, node) in pending {
+        let missing = driver
+            .depends_on
+            .iter()
+            .find(|dep| !is_bound(&devices, dep))
+            .copied()
+            .unwrap_or_default();
+        log::warn!(
+            "{}: {} needs {missing}, which isn't available",
+            node.name,
+            driver.name
+        );
+        devices.push(Device {
+            node: String::from(node.name),
+            driver: driver.name,
+            status: DeviceStatus::MissingDependency(missing),
+        });
+    }
+
+    DEVICES.write().extend(devices);
+}
 
-    unsafe fn init(&mut self, fdt: &Fdt) -> Result<(), Errno>;
+/// Returns every device a driver matched, in the order they were probed.
+#[must_use]
+pub fn devices() -> Vec<Device> {
+    DEVICES.read().clone()
 }
@@ -11,7 +11,7 @@ pub mod driver;
 use crate::{
     irq::IrqChip,
     mem::{
-        paging::table::{PageTable, TableKind},
+        paging::table::{PageTable, PageTableEntry, TableKind},
         units::{PhysAddr, VirtAddr},
     },
 };
@@ -101,6 +101,10 @@ pub trait Architecture {
     /// This is typically used for large pages (e.g., 2MB or 1GB pages).
     const PAGE_FLAG_HUGE: usize;
 
+    /// The number of bits in an address space ID, which tags the TLB entries of the user
+    /// page table.
+    const ASID_BITS: usize;
+
     /* Derived constants */
 
     /// The size of a page in bytes.
@@ -166,8 +170,8 @@ pub trait Architecture {
     /// Initializes architecture-specific interrupt components.
     unsafe fn init_interrupts();
 
-    /// Initializes the architecture-specific CPU-local block.
-    unsafe fn init_cpu_local_block();
+    /// Points the calling CPU's per-CPU base at `base`, its copy of the per-CPU variables.
+    unsafe fn set_percpu_base(base: usize);
 
     /// Initializes the architecture-specific system call interface.
     unsafe fn init_syscalls();
@@ -204,22 +208,67 @@ pub trait Architecture {
     /// to reload the page table entry from memory.
     unsafe fn invalidate_all();
 
+    /// Replaces the page table entry at `entry` with `new` by break-before-make: the entry is
+    /// made invalid and the whole TLB flushed before `new` is written, so no CPU ever holds
+    /// translations from both. Nothing but the entry is read or written in between, not even
+    /// the stack.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must be mapped writable by something other than what it maps. Nothing may use
+    /// the memory it maps while it's broken, so interrupts must be disabled, and the code
+    /// calling this must not be in it.
+    unsafe fn break_before_make(entry: VirtAddr, new: PageTableEntry);
+
+    /// Makes instructions written to `start..end` visible to instruction fetches.
+    unsafe fn sync_icache(start: VirtAddr, end: VirtAddr);
+
     /// Returns the current page table's physical address.
     unsafe fn current_page_table(kind: TableKind) -> PhysAddr;
 
-    /// Sets the current page table to the specified physical address.
+    /// Sets the current page table to the specified physical address, and flushes the TLB.
     unsafe fn set_current_page_table(addr: PhysAddr, kind: TableKind);
 
+    /// Makes the user page table at `addr` current, with TLB entries for it tagged `asid`.
+    ///
+    /// Nothing is flushed, so entries left from an earlier table with the same ASID must
+    /// already have been invalidated.
+    unsafe fn switch_user_page_table(addr: PhysAddr, asid: u16);
+
+    /// Invalidates every TLB entry tagged `asid`.
+    unsafe fn invalidate_asid(asid: u16);
+
     /* CPU state */
 
     /// Returns the curernt stack pointer.
     fn stack_pointer() -> usize;
 
+    /// Switches to the given stack and calls `continuation` on it, abandoning the current stack.
+    ///
+    /// Used once the kernel's own page tables are live to move off the stack the bootloader
+    /// set up, onto a kernel-owned stack with a guard page below it.
+    ///
+    /// # Safety
+    ///
+    /// `stack_top` must be the top of a mapped, otherwise unused stack. Nothing on the
+    /// current stack may be referenced after the switch.
+    unsafe fn set_stack_pointer_post_mapping(
+        stack_top: VirtAddr,
+        continuation: extern "C" fn() -> !,
+    ) -> !;
+
     /// Returns the current frame pointer (also known as the base pointer or link register).
     fn frame_pointer() -> usize;
 
-    /// Returns the virtual address of the current CPU-local block.
-    fn current_cpu_local_block() -> VirtAddr;
+    /// Removes whatever the architecture's pointer authentication added to a return address
+    /// saved on the stack, leaving the address itself.
+    fn strip_return_address(addr: usize) -> usize;
+
+    /// Returns the calling CPU's per-CPU base, or 0 if it hasn't been set.
+    fn percpu_base() -> usize;
+
+    /// Returns the index of the calling CPU, counting from 0.
+    fn cpu_id() -> usize;
 
     /* Drivers */
 
@@ -239,6 +288,10 @@ pub trait Architecture {
     /// Halts the CPU until the next interrupt.
     fn halt();
 
+    /// Puts the CPU in its lowest-power wait until an interrupt is pending, ignoring the
+    /// events [`halt`](Self::halt) may also wake up for.
+    fn wait_for_interrupt();
+
     /// Performs a no-operation (NOP) instruction.
     fn nop();
 
@@ -0,0 +1,247 @@
+//! Scheduler benchmarks, for judging scheduler and locking changes by numbers.
+//!
+//! Three things are measured, each with whatever else is runnable at the time competing:
+//!
+//! - the round trip of switching to another task and back;
+//! - the latency from a timer interrupt to the task waiting for it running again;
+//! - how long it takes to spawn a task, and how many can be spawned and run to exit a second.
+//!
+//! Run them with the shell's `bench` command. Kernels built with the `bench` feature run them
+//! at boot instead, report over semihosting as well as the serial console, and exit QEMU.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{Arch, Architecture, time as arch_time},
+    irq, serial_println,
+    shell::{self, Args, Command},
+    syscall::errno::Errno,
+    task::{self, context, switch::switch},
+    time::uptime,
+};
+
+/// How many samples each latency benchmark takes.
+pub const SAMPLES: usize = 256;
+/// How many tasks the spawn benchmark spawns.
+pub const SPAWN_COUNT: usize = 64;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Registers the `bench` command, and with the `bench` feature, starts the benchmarks.
+pub fn init() -> Result<(), Errno> {
+    shell::register(Command {
+        name: "bench",
+        usage: "",
+        help: "measure context switch, wakeup and spawn latencies",
+        run: cmd_bench,
+    });
+    #[cfg(feature = "bench")]
+    task::SpawnBuilder::new().name("bench").spawn(bench_main)?;
+    Ok(())
+}
+
+/// Statistics over a set of timings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub samples: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    pub stddev: Duration,
+}
+
+impl Summary {
+    /// Summarizes the given timings, or returns `None` if there are none.
+    #[must_use]
+    pub fn new(samples: &mut [Duration]) -> Option<Self> {
+        samples.sort_unstable();
+        let (&min, &max) = (samples.first()?, samples.last()?);
+        let count = samples.len() as u128;
+
+        let mean = samples.iter().map(Duration::as_nanos).sum::<u128>() / count;
+        let variance = samples
+            .iter()
+            .map(|sample| sample.as_nanos().abs_diff(mean).pow(2))
+            .sum::<u128>()
+            / count;
+
+        Some(Self {
+            samples: samples.len(),
+            min,
+            median: samples[samples.len() / 2],
+            mean: Duration::from_nanos(mean as u64),
+            max,
+            stddev: Duration::from_nanos(variance.isqrt() as u64),
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} min={:?} median={:?} mean={:?} max={:?} stddev={:?}",
+            self.samples, self.min, self.median, self.mean, self.max, self.stddev
+        )
+    }
+}
+
+/// The results of a run of every benchmark.
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    /// Switching to another task and back.
+    pub switch_round_trip: Summary,
+    /// A timer interrupt to the task waiting for it running.
+    pub irq_wakeup: Summary,
+    /// Spawning a task.
+    pub spawn: Summary,
+    /// Tasks spawned and run to exit per second.
+    pub spawn_exit_per_sec: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "switch round trip: {}", self.switch_round_trip)?;
+        writeln!(f, "irq to wakeup:     {}", self.irq_wakeup)?;
+        writeln!(f, "spawn:             {}", self.spawn)?;
+        write!(f, "spawn + exit:      {} tasks/s", self.spawn_exit_per_sec)
+    }
+}
+
+/// Runs every benchmark.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBUSY`] if the benchmarks are already running, or whatever spawning a
+/// task fails with.
+pub fn run() -> Result<Report, Errno> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(Errno::EBUSY);
+    }
+    let report = run_all();
+    RUNNING.store(false, Ordering::Release);
+    report
+}
+
+fn run_all() -> Result<Report, Errno> {
+    let mut round_trips = switch_round_trips()?;
+    let mut wakeups = irq_wakeups();
+    let (mut spawns, spawn_exit) = spawn_exits()?;
+
+    let empty = || Errno::EINVAL;
+    Ok(Report {
+        switch_round_trip: Summary::new(&mut round_trips).ok_or_else(empty)?,
+        irq_wakeup: Summary::new(&mut wakeups).ok_or_else(empty)?,
+        spawn: Summary::new(&mut spawns).ok_or_else(empty)?,
+        spawn_exit_per_sec: (SPAWN_COUNT as u128 * 1_000_000_000 / spawn_exit.as_nanos().max(1))
+            as u64,
+    })
+}
+
+/// Bumped by the benchmark to an odd number to ping the partner task, which bumps it back
+/// to even to answer.
+static PING: AtomicUsize = AtomicUsize::new(0);
+static PARTNER_STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn switch_partner() {
+    while !PARTNER_STOP.load(Ordering::Acquire) {
+        if !PING.load(Ordering::Acquire).is_multiple_of(2) {
+            PING.fetch_add(1, Ordering::AcqRel);
+        }
+        switch();
+    }
+    context::exit_current();
+}
+
+fn switch_round_trips() -> Result<Vec<Duration>, Errno> {
+    PING.store(0, Ordering::Release);
+    PARTNER_STOP.store(false, Ordering::Release);
+    task::SpawnBuilder::new()
+        .name("bench-partner")
+        .spawn(switch_partner)?;
+
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let start = uptime();
+        let ping = PING.fetch_add(1, Ordering::AcqRel) + 1;
+        while PING.load(Ordering::Acquire) == ping {
+            switch();
+        }
+        samples.push(uptime().saturating_sub(start));
+    }
+
+    PARTNER_STOP.store(true, Ordering::Release);
+    Ok(samples)
+}
+
+fn irq_wakeups() -> Vec<Duration> {
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let ticks = arch_time::ticks();
+        while arch_time::ticks() == ticks {
+            if irq::is_polled() {
+                irq::poll();
+            } else {
+                Arch::halt();
+            }
+        }
+        samples.push(uptime().saturating_sub(arch_time::last_tick()));
+    }
+    samples
+}
+
+static EXITED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn exit_immediately() {
+    EXITED.fetch_add(1, Ordering::AcqRel);
+    context::exit_current();
+}
+
+/// Returns how long each spawn took, and how long it took for all of them to exit.
+fn spawn_exits() -> Result<(Vec<Duration>, Duration), Errno> {
+    EXITED.store(0, Ordering::Release);
+
+    let mut samples = Vec::with_capacity(SPAWN_COUNT);
+    let start = uptime();
+    for _ in 0..SPAWN_COUNT {
+        let spawn_start = uptime();
+        task::spawn(false, exit_immediately)?;
+        samples.push(uptime().saturating_sub(spawn_start));
+    }
+    while EXITED.load(Ordering::Acquire) < SPAWN_COUNT {
+        switch();
+    }
+    Ok((samples, uptime().saturating_sub(start)))
+}
+
+fn cmd_bench(_args: Args) -> Result<(), Errno> {
+    serial_println!("running scheduler benchmarks...");
+    let report = run()?;
+    serial_println!("{report}");
+    Ok(())
+}
+
+#[cfg(feature = "bench")]
+extern "C" fn bench_main() {
+    use crate::arch::semihosting;
+
+    match run() {
+        Ok(report) => {
+            serial_println!("{report}");
+            semihosting::write_fmt(format_args!("{report}\n")).ok();
+            Arch::exit_qemu(0)
+        }
+        Err(e) => {
+            serial_println!("benchmarks failed: {e:?}");
+            semihosting::write_fmt(format_args!("benchmarks failed: {e:?}\n")).ok();
+            Arch::exit_qemu(1)
+        }
+    }
+}
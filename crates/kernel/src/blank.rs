@@ -0,0 +1,150 @@
+//! Blanks the framebuffer console after a period without console input.
+//!
+//! Useful for installations that stay on for a long time: the screen is cleared to black
+//! (and the display optionally told to power down) until something is typed on the console.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::drivers::gpu,
+    framebuffer::FRAMEBUFFER,
+    irq, serial_println,
+    shell::{self, Args, Command},
+    syscall::errno::Errno,
+    task, time, timer,
+};
+
+/// How long the console waits for input before blanking, unless changed with the `blank`
+/// command.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_mins(10);
+
+/// How often the blanking task checks for inactivity.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The inactivity timeout in seconds, or zero if blanking is disabled.
+static TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT.as_secs());
+/// Whether to ask the display to power down while blanked.
+static POWER_DOWN: AtomicBool = AtomicBool::new(true);
+/// The uptime of the last console input, in milliseconds.
+static LAST_ACTIVITY_MS: AtomicU64 = AtomicU64::new(0);
+static BLANKED: AtomicBool = AtomicBool::new(false);
+
+/// Registers the `blank` command and starts the task that blanks the console when it's idle.
+pub fn init() -> Result<(), Errno> {
+    poke();
+    shell::register(Command {
+        name: "blank",
+        usage: "[now | off | <seconds> | power on|off]",
+        help: "show or change the console blanking timeout",
+        run: cmd_blank,
+    });
+    task::SpawnBuilder::new().name("blank").spawn(blank_main)?;
+    Ok(())
+}
+
+/// Records console input, unblanking the screen if it was blanked.
+pub fn poke() {
+    LAST_ACTIVITY_MS.store(now_ms(), Ordering::Relaxed);
+    if BLANKED.load(Ordering::Acquire) {
+        set_blanked(false);
+    }
+}
+
+/// Returns the current inactivity timeout, or `None` if blanking is disabled.
+#[must_use]
+pub fn timeout() -> Option<Duration> {
+    match TIMEOUT_SECS.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Sets the inactivity timeout. `None` disables blanking.
+pub fn set_timeout(timeout: Option<Duration>) {
+    TIMEOUT_SECS.store(timeout.map_or(0, |t| t.as_secs().max(1)), Ordering::Relaxed);
+}
+
+/// Returns `true` if the console is blanked.
+#[must_use]
+pub fn is_blanked() -> bool {
+    BLANKED.load(Ordering::Acquire)
+}
+
+/// Blanks or unblanks the console right away.
+pub fn set_blanked(blanked: bool) {
+    if BLANKED.swap(blanked, Ordering::AcqRel) == blanked {
+        return;
+    }
+
+    // the framebuffer comes back before the display does, so it's never seen stale
+    if let Some(fb) = FRAMEBUFFER.get() {
+        fb.write().set_blanked(blanked);
+    }
+    if POWER_DOWN.load(Ordering::Relaxed)
+        && let Err(e) = gpu::set_display_blanked(blanked)
+    {
+        log::debug!("failed to change display power: {e:?}");
+    }
+}
+
+fn now_ms() -> u64 {
+    time::uptime().as_millis() as u64
+}
+
+extern "C" fn blank_main() {
+    loop {
+        if !is_blanked()
+            && let Some(timeout) = timeout()
+        {
+            let idle = now_ms().saturating_sub(LAST_ACTIVITY_MS.load(Ordering::Relaxed));
+            if idle >= timeout.as_millis() as u64 {
+                set_blanked(true);
+            }
+        }
+
+        if irq::is_polled() {
+            irq::poll();
+        } else {
+            timer::sleep(CHECK_INTERVAL);
+        }
+    }
+}
+
+fn cmd_blank(mut args: Args) -> Result<(), Errno> {
+    match args.next() {
+        None => {
+            if let Some(timeout) = timeout() {
+                serial_println!("timeout: {}s", timeout.as_secs());
+            } else {
+                serial_println!("timeout: off");
+            }
+            serial_println!(
+                "power down: {}",
+                if POWER_DOWN.load(Ordering::Relaxed) {
+                    "on"
+                } else {
+                    "off"
+                }
+            );
+            serial_println!("blanked: {}", is_blanked());
+        }
+        Some("now") => set_blanked(true),
+        Some("off") => set_timeout(None),
+        Some("power") => {
+            let power_down = match args.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(Errno::EINVAL),
+            };
+            POWER_DOWN.store(power_down, Ordering::Relaxed);
+        }
+        Some(secs) => {
+            let secs = secs.parse().map_err(|_| Errno::EINVAL)?;
+            set_timeout(Some(Duration::from_secs(secs)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Block devices and the request queue in front of them.
+//!
+//! Tasks don't talk to a [`BlockDevice`] directly. They submit reads and writes to its
+//! [`RequestQueue`], where the [I/O scheduler](sched) decides what the device does next,
+//! merging requests for adjacent sectors into one transfer along the way.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    sync::TaskMutex,
+    syscall::errno::Errno,
+    task::{context, switch::switch},
+};
+
+use sched::{Batch, IoScheduler};
+
+pub mod sched;
+
+/// The size of a sector, the unit block devices are addressed in.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The registered block devices, by name.
+static DEVICES: TaskMutex<Vec<(String, Arc<RequestQueue>)>> = TaskMutex::new(Vec::new());
+
+/// Registers a block device under `name` and returns its request queue.
+///
+/// # Errors
+///
+/// Returns [`Errno::EEXIST`] if a device is already registered under that name.
+pub fn register(name: String, device: Box<dyn BlockDevice>) -> Result<Arc<RequestQueue>, Errno> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(n, _)| *n == name) {
+        return Err(Errno::EEXIST);
+    }
+    let queue = Arc::new(RequestQueue::new(device));
+    log::info!("block device {name}: {} sectors", queue.sector_count());
+    devices.push((name, queue.clone()));
+    Ok(queue)
+}
+
+/// Returns the request queue of the block device registered under `name`.
+#[must_use]
+pub fn get(name: &str) -> Option<Arc<RequestQueue>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, queue)| queue.clone())
+}
+
+/// A device that stores data in fixed-size sectors, such as an SD card.
+pub trait BlockDevice: Send {
+    /// Returns the number of sectors on the device.
+    fn sector_count(&self) -> u64;
+
+    /// Reads whole sectors starting at `sector` into `buf`.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), Errno>;
+
+    /// Writes whole sectors starting at `sector` from `buf`.
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), Errno>;
+}
+
+/// Whether a request reads from or writes to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+/// A block device along with the requests waiting on it.
+pub struct RequestQueue {
+    sectors: u64,
+    device: TaskMutex<Box<dyn BlockDevice>>,
+    sched: TaskMutex<IoScheduler>,
+}
+
+impl RequestQueue {
+    /// Creates a request queue for the given device.
+    #[must_use]
+    pub fn new(device: Box<dyn BlockDevice>) -> Self {
+        Self {
+            sectors: device.sector_count(),
+            device: TaskMutex::new(device),
+            sched: TaskMutex::new(IoScheduler::new()),
+        }
+    }
+
+    /// Returns the number of sectors on the device.
+    #[must_use]
+    pub fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Reads whole sectors starting at `sector` into `buf`, waiting until it's done.
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.submit(Op::Read, sector, buf.as_mut_ptr(), buf.len())
+    }
+
+    /// Writes whole sectors starting at `sector` from `buf`, waiting until it's done.
+    pub fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        // the buffer is only ever read from for a write
+        self.submit(Op::Write, sector, buf.as_ptr().cast_mut(), buf.len())
+    }
+
+    fn submit(&self, op: Op, sector: u64, buf: *mut u8, len: usize) -> Result<(), Errno> {
+        if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(Errno::EINVAL);
+        }
+        let count = (len / SECTOR_SIZE) as u64;
+        let end = sector.checked_add(count).ok_or(Errno::EINVAL)?;
+        if end > self.sector_count() {
+            return Err(Errno::EINVAL);
+        }
+
+        let pid = context::current().map(|cx| cx.read().pid);
+        let id = self.sched.lock().enqueue(op, sector, count, buf, pid);
+
+        // whoever gets to the device first runs whatever the scheduler picks next, which
+        // may well be someone else's request
+        loop {
+            if let Some(result) = self.sched.lock().take_completion(id) {
+                return result;
+            }
+            if let Some(mut device) = self.device.try_lock() {
+                let batch = self.sched.lock().next_batch();
+                if let Some(batch) = batch {
+                    let result = run_batch(&mut **device, &batch);
+                    drop(device);
+                    self.sched.lock().complete(&batch, result);
+                }
+            } else {
+                switch();
+            }
+        }
+    }
+}
+
+/// Carries out a batch of merged requests as a single transfer.
+fn run_batch(device: &mut dyn BlockDevice, batch: &Batch) -> Result<(), Errno> {
+    let [request] = batch.requests.as_slice() else {
+        // merged: go through one buffer covering the whole range
+        let mut buf = vec![0; batch.count as usize * SECTOR_SIZE];
+        return match batch.op {
+            Op::Read => {
+                device.read_sectors(batch.sector, &mut buf)?;
+                for request in &batch.requests {
+                    let offset = (request.sector - batch.sector) as usize * SECTOR_SIZE;
+                    unsafe { request.buf_mut() }.copy_from_slice(&buf[offset..][..request.len()]);
+                }
+                Ok(())
+            }
+            Op::Write => {
+                for request in &batch.requests {
+                    let offset = (request.sector - batch.sector) as usize * SECTOR_SIZE;
+                    buf[offset..][..request.len()].copy_from_slice(unsafe { request.buf() });
+                }
+                device.write_sectors(batch.sector, &buf)
+            }
+        };
+    };
+
+    match batch.op {
+        Op::Read => device.read_sectors(request.sector, unsafe { request.buf_mut() }),
+        Op::Write => device.write_sectors(request.sector, unsafe { request.buf() }),
+    }
+}
@@ -0,0 +1,225 @@
+//! An elevator-style I/O scheduler with deadlines.
+//!
+//! Requests are served in sector order, sweeping up from wherever the device left off and
+//! wrapping around at the end, so the device isn't sent back and forth across the disk.
+//! The sweep is interrupted so nobody waits forever:
+//!
+//! - a request that has been waiting for longer than [`EXPIRE`] goes next;
+//! - a task gets at most [`MAX_TASK_STREAK`] batches in a row while another task waits.
+//!
+//! Whatever request is picked is merged with any others for the same operation on adjacent
+//! sectors, up to [`MAX_MERGE_SECTORS`] in total.
+
+use core::time::Duration;
+
+use alloc::{collections::btree_map::BTreeMap, vec, vec::Vec};
+
+use crate::{syscall::errno::Errno, task::context::Pid, time::uptime};
+
+use super::{Op, SECTOR_SIZE};
+
+/// How long a request can be passed over by the sweep before it's served out of order.
+pub const EXPIRE: Duration = Duration::from_millis(500);
+/// How many batches in a row one task can have while other tasks have requests waiting.
+pub const MAX_TASK_STREAK: usize = 4;
+/// The most sectors requests are merged into a single transfer for.
+pub const MAX_MERGE_SECTORS: u64 = 256;
+
+/// Identifies a request for as long as it's pending or waiting to be collected.
+pub type RequestId = u64;
+
+/// A read or write waiting on the device.
+pub struct Request {
+    pub id: RequestId,
+    pub op: Op,
+    pub sector: u64,
+    pub count: u64,
+    pid: Option<Pid>,
+    submitted: Duration,
+    buf: *mut u8,
+}
+
+// the buffer belongs to a task that waits for the request to complete before touching it again
+unsafe impl Send for Request {}
+
+impl Request {
+    /// Returns the length of the request's buffer in bytes.
+    #[must_use]
+    #[allow(clippy::len_without_is_empty)] // requests are never empty
+    pub fn len(&self) -> usize {
+        self.count as usize * SECTOR_SIZE
+    }
+
+    fn end(&self) -> u64 {
+        self.sector + self.count
+    }
+
+    /// Returns the buffer data is written from.
+    ///
+    /// # Safety
+    ///
+    /// The request must not have completed yet.
+    #[must_use]
+    pub unsafe fn buf(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.buf, self.len()) }
+    }
+
+    /// Returns the buffer data is read into.
+    ///
+    /// # Safety
+    ///
+    /// The request must be a read that hasn't completed yet.
+    #[allow(clippy::mut_from_ref)] // the submitting task gave up the buffer until completion
+    #[must_use]
+    pub unsafe fn buf_mut(&self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buf, self.len()) }
+    }
+}
+
+/// Requests for a contiguous run of sectors, to be carried out as one transfer.
+pub struct Batch {
+    pub op: Op,
+    pub sector: u64,
+    pub count: u64,
+    /// The merged requests, in sector order.
+    pub requests: Vec<Request>,
+}
+
+/// Decides the order the requests on a queue are carried out in.
+pub struct IoScheduler {
+    pending: Vec<Request>,
+    completed: BTreeMap<RequestId, Result<(), Errno>>,
+    next_id: RequestId,
+    /// The sector after the end of the last batch.
+    head: u64,
+    /// The task the last batch was picked for, and how many batches in a row it has had.
+    streak: (Option<Pid>, usize),
+}
+
+impl IoScheduler {
+    /// Creates a scheduler with nothing pending.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            completed: BTreeMap::new(),
+            next_id: 0,
+            head: 0,
+            streak: (None, 0),
+        }
+    }
+
+    /// Adds a request, returning the ID to collect its result with.
+    ///
+    /// `buf` must stay valid for `count` sectors until the result has been collected.
+    pub fn enqueue(
+        &mut self,
+        op: Op,
+        sector: u64,
+        count: u64,
+        buf: *mut u8,
+        pid: Option<Pid>,
+    ) -> RequestId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Request {
+            id,
+            op,
+            sector,
+            count,
+            pid,
+            submitted: uptime(),
+            buf,
+        });
+        id
+    }
+
+    /// Takes the result of a completed request, or returns `None` if it's still pending.
+    pub fn take_completion(&mut self, id: RequestId) -> Option<Result<(), Errno>> {
+        self.completed.remove(&id)
+    }
+
+    /// Records the result of a batch for each of its requests.
+    pub fn complete(&mut self, batch: &Batch, result: Result<(), Errno>) {
+        for request in &batch.requests {
+            self.completed.insert(request.id, result);
+        }
+    }
+
+    /// Picks the next batch to carry out, removing its requests from the pending list.
+    pub fn next_batch(&mut self) -> Option<Batch> {
+        let index = self
+            .expired()
+            .or_else(|| self.other_task())
+            .or_else(|| self.elevator())?;
+        let first = self.pending.swap_remove(index);
+
+        if first.pid == self.streak.0 {
+            self.streak.1 += 1;
+        } else {
+            self.streak = (first.pid, 1);
+        }
+
+        let mut batch = Batch {
+            op: first.op,
+            sector: first.sector,
+            count: first.count,
+            requests: vec![first],
+        };
+        while let Some(index) = self.pending.iter().position(|request| {
+            request.op == batch.op
+                && batch.count + request.count <= MAX_MERGE_SECTORS
+                && (request.sector == batch.sector + batch.count || request.end() == batch.sector)
+        }) {
+            let request = self.pending.swap_remove(index);
+            batch.sector = batch.sector.min(request.sector);
+            batch.count += request.count;
+            batch.requests.push(request);
+        }
+        batch
+            .requests
+            .sort_unstable_by_key(|request| request.sector);
+
+        self.head = batch.sector + batch.count;
+        Some(batch)
+    }
+
+    /// Returns the oldest request that has waited too long, if any.
+    fn expired(&self) -> Option<usize> {
+        let now = uptime();
+        self.oldest(|request| now.saturating_sub(request.submitted) >= EXPIRE)
+    }
+
+    /// Returns the oldest request from a task other than the one that's had the device to
+    /// itself for too long, if any.
+    fn other_task(&self) -> Option<usize> {
+        let (pid, streak) = self.streak;
+        if streak < MAX_TASK_STREAK {
+            return None;
+        }
+        self.oldest(|request| request.pid != pid)
+    }
+
+    /// Returns the next request in the sweep: the lowest sector at or past the head,
+    /// wrapping around to the lowest sector overall.
+    fn elevator(&self) -> Option<usize> {
+        let lowest = |ahead: bool| {
+            self.pending
+                .iter()
+                .enumerate()
+                .filter(|(_, request)| !ahead || request.sector >= self.head)
+                .min_by_key(|(_, request)| request.sector)
+                .map(|(index, _)| index)
+        };
+        lowest(true).or_else(|| lowest(false))
+    }
+
+    fn oldest(&self, filter: impl Fn(&Request) -> bool) -> Option<usize> {
+        self.pending
+            .iter()
+            .enumerate()
+            .filter(|(_, request)| filter(request))
+            .min_by_key(|(_, request)| request.submitted)
+            .map(|(index, _)| index)
+    }
+}
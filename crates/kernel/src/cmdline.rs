@@ -0,0 +1,69 @@
+//! The kernel command line, which the bootloader finds in the FDT's `/chosen/bootargs`.
+//!
+//! It's a whitespace-separated list of `key=value` options, or bare `key`s, which read as an
+//! empty value. Unknown keys are ignored, since the Pi's firmware adds plenty of its own for
+//! Linux. The kernel looks at:
+//!
+//! - `log=trace|debug|info|warn|error|off`: the log level, overriding `KADOS_LOG`.
+//! - `console=serial|fb|ttyAMA0|ttyS0`: log to only that console, rather than both. `ttyAMA0`
+//!   and `ttyS0` are the serial console on the PL011 or the Pi 4's mini UART.
+//! - `ktest=on|off`: whether a `ktest` kernel runs its tests.
+//! - `crashdump=memory|serial|memory,serial|off`: where a panic's crash record goes.
+//! - `trace=on|off`: whether to record the event trace from boot.
+//! - `time=<seconds>`: the current UNIX time, for boards without an RTC.
+//! - `init=<path>`: the program in the initrd to run first, rather than `init`.
+//! - `fbfont=8x16|10x20`: the framebuffer console's font. It's 10x20 by default.
+//! - `fbscale=1|2|3`: how many times bigger the framebuffer console's font is drawn.
+//! - `keymap=us|de`: the keyboard layout. It's `us` by default, and the `keymap` shell command
+//!   changes it.
+//! - `panic=halt|reboot[,<seconds>]`: whether to halt after a panic, or reset the system,
+//!   straight away or that many seconds later. It halts by default.
+//!
+//! `cargo builder --cmdline '...'` passes a command line to QEMU, and puts it in `cmdline.txt`
+//! on the SD card for the Pi's firmware.
+
+use spin::Once;
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Sets the command line the bootloader passed. Until this is called, it's empty.
+///
+/// Runs before the heap is up, so it mustn't allocate.
+pub fn init(bootargs: &'static str) {
+    CMDLINE.call_once(|| bootargs.trim_end_matches('\0'));
+}
+
+/// Returns the whole command line.
+#[must_use]
+pub fn raw() -> &'static str {
+    CMDLINE.get().copied().unwrap_or_default()
+}
+
+/// Returns the value of the last `key` option, or `None` if there isn't one.
+#[must_use]
+pub fn get(key: &str) -> Option<&'static str> {
+    raw()
+        .split_ascii_whitespace()
+        .rev()
+        .find_map(|option| match option.split_once('=') {
+            Some((k, value)) => (k == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+}
+
+/// Returns whether the `key` option is on, or `default` if it isn't given.
+///
+/// A bare `key`, `on`, `yes`, `true` and `1` are on; `off`, `no`, `false` and `0` are off.
+/// Anything else is warned about and taken as `default`.
+#[must_use]
+pub fn enabled(key: &str, default: bool) -> bool {
+    match get(key) {
+        None => default,
+        Some("" | "on" | "yes" | "true" | "1") => true,
+        Some("off" | "no" | "false" | "0") => false,
+        Some(value) => {
+            log::warn!("cmdline: `{key}={value}` isn't on or off");
+            default
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! A reader for cpio archives in the "newc" format, as made by `cpio -H newc` and used for the
+//! initrd.
+//!
+//! Each entry is a 110-byte ASCII header, the NUL-terminated path, then the contents, with the
+//! path and contents each padded to a multiple of 4 bytes. The header is the magic `070701`
+//! (or `070702`, which adds a checksum we ignore) followed by thirteen 8-digit hex fields. The
+//! archive ends with an entry named `TRAILER!!!`.
+//!
+//! Nothing is copied: entries borrow their paths and contents from the archive.
+
+use crate::syscall::errno::Errno;
+
+const HEADER_SIZE: usize = 110;
+const MAGIC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+/// The file type bits of a mode.
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// What kind of file an entry is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// A device, FIFO or socket, which an archive can describe but not hold.
+    Other,
+}
+
+/// A file in an archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// The path, without any leading `/` or `./`.
+    pub path: &'a str,
+    pub ino: u32,
+    /// The file type and permission bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    /// The modification time, in seconds since the Unix epoch.
+    pub mtime: u32,
+    /// The contents, or for a symlink, the path it points to.
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    #[must_use]
+    pub fn kind(&self) -> FileKind {
+        match self.mode & S_IFMT {
+            S_IFREG => FileKind::File,
+            S_IFDIR => FileKind::Dir,
+            S_IFLNK => FileKind::Symlink,
+            _ => FileKind::Other,
+        }
+    }
+
+    /// Returns the permission bits.
+    #[must_use]
+    pub fn permissions(&self) -> u32 {
+        self.mode & !S_IFMT
+    }
+
+    /// Returns the last component of the path.
+    #[must_use]
+    pub fn name(&self) -> &'a str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+
+    /// Returns the path a symlink points to, or `None` if this isn't a symlink.
+    #[must_use]
+    pub fn link_target(&self) -> Option<&'a str> {
+        if self.kind() != FileKind::Symlink {
+            return None;
+        }
+        core::str::from_utf8(self.data).ok()
+    }
+}
+
+/// A cpio archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Reads an archive from `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EINVAL`] if `data` doesn't start with a newc header.
+    pub fn new(data: &'a [u8]) -> Result<Self, Errno> {
+        let magic = data.get(..MAGIC.len()).ok_or(Errno::EINVAL)?;
+        if magic != MAGIC && magic != MAGIC_CRC {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Self { data })
+    }
+
+    /// Returns the entries in the order they're stored, stopping at the trailer or at the first
+    /// malformed entry, which is yielded as [`Errno::EINVAL`].
+    #[must_use]
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Finds the entry at `path`, which may start with `/` or `./`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ENOENT`] if there's no such entry, or [`Errno::EINVAL`] if the archive
+    /// is malformed before it's found.
+    pub fn lookup(&self, path: &str) -> Result<Entry<'a>, Errno> {
+        let path = normalize(path);
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.path == path {
+                return Ok(entry);
+            }
+        }
+        Err(Errno::ENOENT)
+    }
+
+    /// Returns the entries directly inside the directory at `path`, with `""` or `/` being
+    /// the root.
+    ///
+    /// Directories that only appear as part of other entries' paths aren't listed, since
+    /// `cpio` always stores them itself.
+    pub fn read_dir(&self, path: &str) -> impl Iterator<Item = Result<Entry<'a>, Errno>> {
+        let dir = normalize(path);
+        self.entries().filter(move |entry| match entry {
+            Ok(entry) => parent(entry.path) == dir && entry.path != dir,
+            Err(_) => true,
+        })
+    }
+}
+
+/// An iterator over the entries of an [`Archive`].
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Errno>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Entries<'a> {
+    /// Parses the entry at `offset` and moves past it, returning `None` at the trailer.
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, Errno> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(Errno::EINVAL)?;
+        let magic = &header[..MAGIC.len()];
+        if magic != MAGIC && magic != MAGIC_CRC {
+            return Err(Errno::EINVAL);
+        }
+        let field = |index: usize| -> Result<u32, Errno> {
+            let start = MAGIC.len() + index * 8;
+            let digits =
+                core::str::from_utf8(&header[start..start + 8]).map_err(|_| Errno::EINVAL)?;
+            u32::from_str_radix(digits, 16).map_err(|_| Errno::EINVAL)
+        };
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name_size = field(11)? as usize;
+        let name = self
+            .data
+            .get(name_start..name_start + name_size)
+            .and_then(|name| name.strip_suffix(b"\0"))
+            .ok_or(Errno::EINVAL)?;
+        let name = core::str::from_utf8(name).map_err(|_| Errno::EINVAL)?;
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data_size = field(6)? as usize;
+        let data = self
+            .data
+            .get(data_start..data_start + data_size)
+            .ok_or(Errno::EINVAL)?;
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        self.offset = (data_start + data_size).next_multiple_of(4);
+
+        Ok(Some(Entry {
+            path: normalize(name),
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            data,
+        }))
+    }
+}
+
+/// Strips the leading `/`s, `./`s and trailing `/`s from `path`, turning `.` into the root.
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if path == "." {
+            return "";
+        } else {
+            return path;
+        }
+    }
+}
+
+/// Returns the directory a normalized path is in.
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
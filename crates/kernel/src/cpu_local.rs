@@ -3,10 +3,14 @@ use core::cell::{Cell, RefCell};
 use alloc::sync::Arc;
 
 use crate::{
-    arch::{Arch, Architecture},
+    percpu::percpu,
     task::{addr_space::AddrSpaceLock, switch::CpuLocalSwitchState},
 };
 
+percpu! {
+    static BLOCK: CpuLocalBlock = CpuLocalBlock::new();
+}
+
 /// A block of data that is unique to each CPU core.
 pub struct CpuLocalBlock {
     pub switch_state: CpuLocalSwitchState,
@@ -16,19 +20,20 @@ pub struct CpuLocalBlock {
 }
 
 impl CpuLocalBlock {
-    /// Initializes a new `CpuLocalBlock` for the current CPU core.
-    #[must_use]
-    pub fn init() -> Self {
+    const fn new() -> Self {
         Self {
-            switch_state: CpuLocalSwitchState::default(),
+            switch_state: CpuLocalSwitchState::new(),
             current_addr_space: RefCell::new(None),
             next_addr_space: Cell::new(None),
         }
     }
 
-    /// Returns a reference to the current CPU local block.
+    /// Returns a reference to the current CPU local block, or `None` before the CPU's per-CPU
+    /// variables are set up.
     #[must_use]
     pub fn current() -> Option<&'static Self> {
-        unsafe { Arch::current_cpu_local_block().deref().ok() }
+        // only the boot CPU runs tasks so far, so nothing can move to another CPU while it
+        // holds on to the block
+        unsafe { BLOCK.current() }
     }
 }
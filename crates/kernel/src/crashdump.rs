@@ -0,0 +1,221 @@
+//! Crash records: what the kernel knew when it panicked, kept for after it's gone.
+//!
+//! On a panic, the kernel writes a plain-text report to the crash region, a block of RAM at
+//! [`CRASH_REGION_ADDR`](kados_abi::boot::CRASH_REGION_ADDR) that's reserved at boot and that a
+//! warm reboot leaves alone. It holds the panic message, the CPU's registers, a backtrace, the
+//! tasks, and the tail of the log, after a [`CrashRecordHeader`] with its length and checksum.
+//! The next boot finds it and the shell's `crash` command prints it.
+//!
+//! The record can also be streamed over the serial line, header and all, where the loader
+//! saves it to a file. `crashdump=` on the command line picks where records go: `memory` (the
+//! default), `serial`, `memory,serial`, or `off`.
+
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use kados_abi::{crash::CrashRecordHeader, loader::crc32};
+use spin::Once;
+
+use crate::{
+    BootInfo,
+    arch::{
+        crash::write_registers,
+        serial::{force_lock_uart, lock_uart},
+    },
+    cmdline, logging, panicking,
+    shell::{self, Args, Command},
+    syscall::errno::Errno,
+    task::context,
+    time::{
+        self,
+        wall::{self, DateTime},
+    },
+};
+
+/// Whether records are written to the crash region.
+static MEMORY: AtomicBool = AtomicBool::new(true);
+/// Whether records are streamed over the serial line.
+static SERIAL: AtomicBool = AtomicBool::new(false);
+
+static REGION: Once<Region> = Once::new();
+
+/// The crash region, through its HHDM mapping.
+struct Region {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// only touched by `init`, the `crash` command, and a panicking kernel, which has stopped
+// everything else
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    fn bytes(&self) -> &'static mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    /// Returns the payload of the record in the region, or `None` if there isn't a whole one.
+    fn record(&self) -> Option<&'static [u8]> {
+        let bytes = self.bytes();
+        let (header, payload) = bytes.split_at(CrashRecordHeader::SIZE);
+        let header = CrashRecordHeader::decode(header.try_into().ok()?);
+        if !header.is_valid() {
+            return None;
+        }
+        let payload = payload.get(..header.len as usize)?;
+        (crc32(payload) == header.crc32).then_some(payload)
+    }
+
+    fn clear(&self) {
+        self.bytes()[..CrashRecordHeader::SIZE].fill(0);
+    }
+}
+
+/// Formats into a byte buffer, dropping whatever doesn't fit.
+struct RecordWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for RecordWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Reads where crash records should go from the command line, looks for one left by the last
+/// boot, and registers the `crash` shell command.
+///
+/// Must be called after the kernel's page tables are in use, since it reaches the crash region
+/// through the HHDM mapping they have for it.
+pub fn init(boot_info: &BootInfo) {
+    match cmdline::get("crashdump").unwrap_or("memory") {
+        "off" => MEMORY.store(false, Ordering::Relaxed),
+        "memory" => {}
+        "serial" => {
+            MEMORY.store(false, Ordering::Relaxed);
+            SERIAL.store(true, Ordering::Relaxed);
+        }
+        "memory,serial" | "serial,memory" => SERIAL.store(true, Ordering::Relaxed),
+        other => log::warn!("cmdline: unknown `crashdump={other}`, writing to memory"),
+    }
+
+    let Some(range) = &boot_info.crash_region else {
+        log::warn!("no crash region, so panics won't leave crash records");
+        return;
+    };
+    let region = REGION.call_once(|| Region {
+        ptr: range.start.as_hhdm_virt().as_raw_ptr_mut(),
+        len: range.end.value() - range.start.value(),
+    });
+
+    match region.record() {
+        Some(record) => log::warn!(
+            "the last boot crashed, leaving a {} byte record; `crash` shows it",
+            record.len()
+        ),
+        None => region.clear(),
+    }
+
+    shell::register(Command {
+        name: "crash",
+        usage: "[clear]",
+        help: "show or clear the crash record left by the last boot",
+        run: cmd_crash,
+    });
+}
+
+/// Writes a crash record for a panic to wherever the command line asked for it.
+///
+/// Only to be called from the panic handler, since it takes over the UART and log for good.
+pub fn on_panic(info: &PanicInfo) {
+    let memory = MEMORY.load(Ordering::Relaxed);
+    let serial = SERIAL.load(Ordering::Relaxed);
+    let Some(region) = REGION.get().filter(|_| memory || serial) else {
+        return;
+    };
+
+    let (header, payload) = region.bytes().split_at_mut(CrashRecordHeader::SIZE);
+    let mut out = RecordWriter {
+        buf: payload,
+        len: 0,
+    };
+    write_report(&mut out, info).ok();
+    let payload = &out.buf[..out.len];
+    let record = CrashRecordHeader::new(payload.len() as u32, crc32(payload)).encode();
+    header.copy_from_slice(&record);
+
+    if serial {
+        let mut uart = unsafe { force_lock_uart() };
+        for &b in record.iter().chain(payload) {
+            uart.putchar(b);
+        }
+    }
+    if !memory {
+        region.clear();
+    }
+}
+
+fn write_report(out: &mut impl Write, info: &PanicInfo) -> fmt::Result {
+    writeln!(out, "panic: {info}")?;
+    writeln!(out, "uptime: {:?}", time::uptime())?;
+    writeln!(
+        out,
+        "time: {} (from the {})",
+        DateTime::from_unix(wall::now()),
+        wall::source()
+    )?;
+
+    writeln!(out, "\nregisters:")?;
+    write_registers(out)?;
+
+    writeln!(out, "\nbacktrace:")?;
+    if let Err(e) = panicking::write_backtrace(out) {
+        writeln!(out, "error unwinding stack: {e}")?;
+    }
+
+    writeln!(out, "\ntasks:")?;
+    context::for_each(|cx| {
+        let mode = if cx.userspace { "user" } else { "kernel" };
+        writeln!(
+            out,
+            "{:>5}  {:<16} {:<6} {:?}",
+            cx.pid, cx.name, mode, cx.status
+        )
+        .ok();
+    });
+
+    writeln!(out, "\nlog:")?;
+    // the kernel has panicked, so nothing that held the log will run again
+    unsafe { logging::write_log_tail(out) };
+    Ok(())
+}
+
+fn cmd_crash(mut args: Args) -> Result<(), Errno> {
+    let region = REGION.get().ok_or(Errno::ENOENT)?;
+    match args.next() {
+        None => {
+            let record = region.record().ok_or(Errno::ENOENT)?;
+            let mut uart = lock_uart();
+            for &b in record {
+                if b == b'\n' {
+                    uart.putchar(b'\r');
+                }
+                uart.putchar(b);
+            }
+            Ok(())
+        }
+        Some("clear") => {
+            region.clear();
+            Ok(())
+        }
+        Some(_) => Err(Errno::EINVAL),
+    }
+}
@@ -0,0 +1,137 @@
+//! A reader for the ELF executables userspace programs are built as.
+//!
+//! Only what's needed to load a static program is read: the file header, to check the file is a
+//! 64-bit little-endian `AArch64` executable and find its entry point, and the `PT_LOAD` program
+//! headers, which say what goes where in memory. Sections, symbols and dynamic linking are
+//! ignored.
+//!
+//! Nothing is copied: segments borrow their contents from the file.
+
+use crate::syscall::errno::Errno;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_AARCH64: u16 = 183;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// A loadable segment of an executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// The address the segment is loaded at.
+    pub vaddr: usize,
+    /// The size of the segment in memory. Anything past the end of [`data`](Self::data) is
+    /// zeroed.
+    pub mem_size: usize,
+    /// The bytes the segment starts with.
+    pub data: &'a [u8],
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// A static `AArch64` ELF executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Executable<'a> {
+    data: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Executable<'a> {
+    /// Reads an executable from `data`, checking its program headers are all there.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ENOEXEC`] if `data` isn't a 64-bit little-endian `AArch64` executable,
+    /// or is truncated.
+    pub fn new(data: &'a [u8]) -> Result<Self, Errno> {
+        let header = data.get(..HEADER_SIZE).ok_or(Errno::ENOEXEC)?;
+        if &header[..MAGIC.len()] != MAGIC || header[4] != CLASS_64 || header[5] != DATA_LSB {
+            return Err(Errno::ENOEXEC);
+        }
+        if read_u16(header, 16) != TYPE_EXEC || read_u16(header, 18) != MACHINE_AARCH64 {
+            return Err(Errno::ENOEXEC);
+        }
+        if usize::from(read_u16(header, 54)) != PROGRAM_HEADER_SIZE {
+            return Err(Errno::ENOEXEC);
+        }
+
+        let elf = Self {
+            data,
+            entry: read_u64(header, 24) as usize,
+            phoff: read_u64(header, 32) as usize,
+            phnum: usize::from(read_u16(header, 56)),
+        };
+        let table_end = elf
+            .phnum
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff))
+            .ok_or(Errno::ENOEXEC)?;
+        if table_end > data.len() {
+            return Err(Errno::ENOEXEC);
+        }
+        Ok(elf)
+    }
+
+    /// Returns the address execution starts at.
+    #[must_use]
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Returns the loadable segments, in the order the file lists them.
+    ///
+    /// # Errors
+    ///
+    /// Yields [`Errno::ENOEXEC`] for a segment whose contents aren't in the file, or that's
+    /// bigger in the file than in memory.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment<'a>, Errno>> {
+        let data = self.data;
+        (0..self.phnum)
+            .map(move |i| &data[self.phoff + i * PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE])
+            .filter(|header| read_u32(header, 0) == PT_LOAD)
+            .map(move |header| {
+                let flags = read_u32(header, 4);
+                let offset = read_u64(header, 8) as usize;
+                let file_size = read_u64(header, 32) as usize;
+                let mem_size = read_u64(header, 40) as usize;
+                if file_size > mem_size {
+                    return Err(Errno::ENOEXEC);
+                }
+                let contents = offset
+                    .checked_add(file_size)
+                    .and_then(|end| data.get(offset..end))
+                    .ok_or(Errno::ENOEXEC)?;
+                Ok(Segment {
+                    vaddr: read_u64(header, 16) as usize,
+                    mem_size,
+                    data: contents,
+                    readable: flags & PF_R != 0,
+                    writable: flags & PF_W != 0,
+                    executable: flags & PF_X != 0,
+                })
+            })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
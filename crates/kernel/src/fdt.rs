@@ -1,5 +1,7 @@
 //! A lot of this code was taken from and inspired by Redox
 
+use core::ops::Range;
+
 use alloc::vec::Vec;
 use fdt::standard_nodes::MemoryRegion;
 pub use fdt::*;
@@ -49,6 +51,81 @@ pub fn dump(fdt: &Fdt) {
     log::debug!("END FDT DUMP");
 }
 
+/// Calls `f` with each range of physical memory the FDT says the kernel must leave alone.
+///
+/// That's everything in the memory reservation block and under `/reserved-memory`, any
+/// `simple-framebuffer` the firmware set up, the initrd, and the DTB itself (at `dtb_phys`).
+/// Runs before the heap is up, so it mustn't allocate.
+pub fn for_each_reserved_region(fdt: &Fdt, dtb_phys: PhysAddr, mut f: impl FnMut(Range<usize>)) {
+    let mut reserve = |base: usize, size: usize| {
+        if size != 0 {
+            f(base..base.saturating_add(size));
+        }
+    };
+
+    reserve(dtb_phys.value(), fdt.total_size());
+
+    if let Some(initrd) = initrd_range(fdt) {
+        reserve(initrd.start, initrd.len());
+    }
+
+    for reservation in fdt.memory_reservations() {
+        reserve(reservation.address() as usize, reservation.size());
+    }
+
+    // children with only a `size` are pools for the OS to place itself, not firmware memory
+    if let Some(reserved) = fdt.find_node("/reserved-memory") {
+        for child in reserved.children() {
+            for region in child.reg().into_iter().flatten() {
+                reserve(region.starting_address as usize, region.size.unwrap_or(0));
+            }
+        }
+    }
+
+    for node in fdt.all_nodes() {
+        let is_framebuffer = node
+            .compatible()
+            .is_some_and(|compatible| compatible.all().any(|c| c == "simple-framebuffer"));
+        if is_framebuffer {
+            for region in node.reg().into_iter().flatten() {
+                reserve(region.starting_address as usize, region.size.unwrap_or(0));
+            }
+        }
+    }
+}
+
+/// Returns the physical range of the initrd, from `linux,initrd-start` and `linux,initrd-end`
+/// in `/chosen`, or `None` if there isn't one.
+#[must_use]
+pub fn initrd_range(fdt: &Fdt) -> Option<Range<usize>> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    (start < end).then_some(start..end)
+}
+
+/// Returns the node `/chosen/stdout-path` names as the console, and the options after its `:`,
+/// like `115200n8`, if there are any.
+///
+/// The path may be an alias, like the Pi's `serial0`, rather than a full path.
+#[must_use]
+pub fn stdout_node<'b, 'a>(fdt: &'b Fdt<'a>) -> Option<(node::FdtNode<'b, 'a>, Option<&'a str>)> {
+    let stdout_path = fdt
+        .find_node("/chosen")?
+        .property("stdout-path")?
+        .as_str()?;
+    let (path, options) = match stdout_path.split_once(':') {
+        Some((path, options)) => (path, Some(options)),
+        None => (stdout_path, None),
+    };
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        fdt.aliases()?.resolve(path)?
+    };
+    Some((fdt.find_node(path)?, options))
+}
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Phandle(u32);
 
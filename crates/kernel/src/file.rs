@@ -0,0 +1,119 @@
+//! Open files and the per-task tables of file descriptors that refer to them.
+//!
+//! There's no filesystem behind these yet: a [`File`] is anything that can be read or
+//! written, like the serial [`Console`] or either end of a [pipe](crate::ipc::pipe). Every
+//! task starts with the console open as standard input, output and error.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{arch::serial::lock_uart, syscall::errno::Errno};
+
+/// The most file descriptors a task can have open at once.
+pub const MAX_FILES: usize = 64;
+
+/// Something a file descriptor can refer to.
+pub trait File: Send + Sync {
+    /// Reads into `buf`, returning how many bytes were read. Returns 0 at end of file.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EBADF`] if the file can't be read.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let _ = buf;
+        Err(Errno::EBADF)
+    }
+
+    /// Writes from `buf`, returning how many bytes were written.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EBADF`] if the file can't be written.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let _ = buf;
+        Err(Errno::EBADF)
+    }
+}
+
+/// The serial console.
+///
+/// Reading it always sees end of file, since the shell owns the serial input.
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut uart = lock_uart();
+        for &b in buf {
+            uart.putchar(b);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// A task's open files, indexed by file descriptor.
+#[derive(Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    /// Creates a table with the console open as file descriptors 0, 1 and 2.
+    #[must_use]
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+        Self {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    /// Returns the file open as `fd`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EBADF`] if `fd` isn't open.
+    pub fn get(&self, fd: usize) -> Result<Arc<dyn File>, Errno> {
+        self.files
+            .get(fd)
+            .and_then(Option::clone)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Opens `file` as the lowest free file descriptor, returning it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EMFILE`] if [`MAX_FILES`] are already open.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<usize, Errno> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FILES {
+            return Err(Errno::EMFILE);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Closes `fd`, returning the file it referred to.
+    ///
+    /// The file itself is only closed once the returned reference and any others are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EBADF`] if `fd` isn't open.
+    pub fn remove(&mut self, fd: usize) -> Result<Arc<dyn File>, Errno> {
+        let file = self
+            .files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)?;
+        while self.files.last().is_some_and(Option::is_none) {
+            self.files.pop();
+        }
+        Ok(file)
+    }
+}
@@ -1,35 +1,81 @@
-use core::ops::Add;
+use core::ops::{Add, Range};
 
-use alloc::boxed::Box;
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use arrayvec::ArrayVec;
 use embedded_graphics::{
     Pixel,
-    mono_font::{MonoFont, MonoTextStyle, ascii},
     prelude::{Size, *},
-    text::Text,
 };
 use spin::Once;
 
 use embedded_graphics::pixelcolor::Rgb888;
+use kados_abi::{boot::BootFramebuffer, syscall::FbInfo};
 
 use crate::{
-    arch::clean_data_cache, mem::units::VirtAddr, sync::IrqMutex, util::DebugCheckedPanic,
+    arch::{
+        Arch, Architecture, clean_data_cache,
+        drivers::{
+            dma::{self, DmaTransfer},
+            gpu::{self, vsync},
+        },
+        invalidate_data_cache,
+    },
+    mem::{
+        paging::{
+            allocator::KernelFrameAllocator,
+            table::{PageFlags, PageTable, TableKind},
+        },
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
+    sync::RwLock,
+    syscall::errno::Errno,
+    task::addr_space::{AddrSpace, Backing, Protection},
+    util::DebugCheckedPanic,
 };
 
 /// Represents a pixel color in the framebuffer.
 pub type Color = Rgb888;
 
-const FONT: MonoFont = ascii::FONT_10X20;
+mod glyphs;
 
-/// The width of the framebuffer's text buffer.
-pub const TEXT_BUFFER_WIDTH: usize = 80;
-/// The height of the framebuffer's text buffer.
-pub const TEXT_BUFFER_HEIGHT: usize = 25;
+/// The most columns the text buffer can have, however wide the display is.
+pub const MAX_TEXT_COLUMNS: usize = 256;
+/// The most rows the text buffer can have, however tall the display is.
+pub const MAX_TEXT_ROWS: usize = 128;
+/// The default number of lines kept after they scroll off the top of the screen.
+pub const DEFAULT_SCROLLBACK_LINES: usize = 500;
+
+type TextRow = [Option<FbChar>; MAX_TEXT_COLUMNS];
+
+/// The maximum number of parameters kept for a single control sequence.
+const MAX_CSI_PARAMS: usize = 16;
+
+/// The standard 16-color ANSI palette (the xterm defaults), normal colors followed by bright ones.
+const ANSI_PALETTE: [Color; 16] = [
+    Color::new(0x00, 0x00, 0x00),
+    Color::new(0xcd, 0x00, 0x00),
+    Color::new(0x00, 0xcd, 0x00),
+    Color::new(0xcd, 0xcd, 0x00),
+    Color::new(0x00, 0x00, 0xee),
+    Color::new(0xcd, 0x00, 0xcd),
+    Color::new(0x00, 0xcd, 0xcd),
+    Color::new(0xe5, 0xe5, 0xe5),
+    Color::new(0x7f, 0x7f, 0x7f),
+    Color::new(0xff, 0x00, 0x00),
+    Color::new(0x00, 0xff, 0x00),
+    Color::new(0xff, 0xff, 0x00),
+    Color::new(0x5c, 0x5c, 0xff),
+    Color::new(0xff, 0x00, 0xff),
+    Color::new(0x00, 0xff, 0xff),
+    Color::new(0xff, 0xff, 0xff),
+];
 
 /// A character in the framebuffer's text buffer.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct FbChar {
     char: u8,
     fg: Color,
+    bg: Color,
 }
 
 impl FbChar {
@@ -37,47 +83,148 @@ impl FbChar {
     pub const DEFAULT: Self = Self {
         char: b' ',
         fg: Color::BLACK,
+        bg: Color::BLACK,
     };
 
     /// Creates a new [`FbChar`] with the given character and foreground color.
     #[must_use]
     pub fn new(char: u8, fg: Color) -> Self {
-        Self { char, fg }
+        Self {
+            char,
+            fg,
+            bg: Color::BLACK,
+        }
     }
 
-    /// Converts the [`FbChar`] to a [`Text`] object for rendering.
+    /// Returns a copy of this character with the given background color.
     #[must_use]
-    pub fn as_text(
-        &'_ self,
-        top_left: Point,
-        x: usize,
-        y: usize,
-    ) -> Text<'_, MonoTextStyle<'_, Color>> {
-        Text::new(
-            core::str::from_utf8(core::slice::from_ref(&self.char)).unwrap_or(" "),
-            top_left
-                + Point::new(
-                    FONT.character_size.width as i32 * (x as i32 + 1),
-                    FONT.character_size.height as i32 * (y as i32 + 1),
-                ),
-            MonoTextStyle::new(&FONT, self.fg),
-        )
+    pub fn with_bg(self, bg: Color) -> Self {
+        Self { bg, ..self }
+    }
+}
+
+/// The parameters of a control sequence (`ESC [ ... <final>`) being parsed.
+#[derive(Debug, Clone, Default)]
+struct CsiParams {
+    params: ArrayVec<u16, MAX_CSI_PARAMS>,
+    current: Option<u16>,
+    /// Set for private sequences like `ESC [ ? 25 l`, which we don't implement.
+    private: bool,
+}
+
+impl CsiParams {
+    fn push_digit(&mut self, digit: u8) {
+        let value = self.current.unwrap_or(0);
+        self.current = Some(value.saturating_mul(10).saturating_add(u16::from(digit)));
+    }
+
+    fn end_param(&mut self) {
+        // excess parameters are dropped
+        self.params.try_push(self.current.take().unwrap_or(0)).ok();
+    }
+
+    fn finish(&mut self) {
+        if self.current.is_some() || !self.params.is_empty() {
+            self.end_param();
+        }
+    }
+
+    /// Returns the `idx`th parameter, or `default` if it is missing or zero.
+    fn get_or(&self, idx: usize, default: u16) -> u16 {
+        match self.params.get(idx) {
+            Some(0) | None => default,
+            Some(&value) => value,
+        }
+    }
+}
+
+/// The state of the ANSI/VT100 escape sequence parser.
+#[derive(Debug, Clone, Default)]
+enum AnsiState {
+    /// Plain text.
+    #[default]
+    Ground,
+    /// Saw `ESC`.
+    Escape,
+    /// Inside a control sequence (`ESC [`).
+    Csi(CsiParams),
+}
+
+/// Returns a color from the 16-color palette.
+fn ansi_color(index: u16, bright: bool) -> Color {
+    ANSI_PALETTE[usize::from(index & 7) + if bright { 8 } else { 0 }]
+}
+
+/// Parses the rest of an extended SGR color (`38;5;n` or `38;2;r;g;b`).
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => {
+            let n = params.next()?;
+            Some(match n {
+                0..=15 => ansi_color(n, n >= 8),
+                16..=231 => {
+                    // 6x6x6 color cube
+                    let n = n - 16;
+                    let level = |v: u16| if v == 0 { 0 } else { (v * 40 + 55) as u8 };
+                    Color::new(level(n / 36), level((n / 6) % 6), level(n % 6))
+                }
+                _ => {
+                    let gray = (8 + (n.min(255) - 232) * 10) as u8;
+                    Color::new(gray, gray, gray)
+                }
+            })
+        }
+        2 => {
+            let mut channel = || params.next().map(|v| v.min(255) as u8);
+            Some(Color::new(channel()?, channel()?, channel()?))
+        }
+        _ => None,
     }
 }
 
 /// Represents a framebuffer for rendering graphics and text.
 #[derive(Debug)]
 pub struct FrameBuffer {
+    /// The first buffer. The second, if there is one, follows it.
     start_addr: VirtAddr,
+    /// The size of each buffer in bytes.
     size_bytes: usize,
+    /// How many buffers there are to flip between: one or two.
+    buffers: usize,
+    /// The buffer being displayed.
+    front: usize,
     width: usize,
     height: usize,
     bpp: usize,
-    back_buffer: Box<[u32]>,
-    text_buf: Box<[[Option<FbChar>; TEXT_BUFFER_WIDTH]]>, // TEXT_BUFFER_WIDTH x TEXT_BUFFER_HEIGHT
+    back_buffer: &'static mut [u32],
+    back_buffer_phys: PhysAddr,
+    pending_present: Option<DmaTransfer>,
+    /// The pixel rows of the back buffer that haven't been presented yet.
+    dirty_rows: Option<Range<usize>>,
+    /// The pixel rows presented to the front buffer but not the other one.
+    stale_rows: Option<Range<usize>>,
+    /// The vertical sync count when the display was last flipped.
+    flipped_at: Option<u64>,
+    /// The text rows that have changed since they were last rendered.
+    dirty_text_rows: [bool; MAX_TEXT_ROWS],
+    /// The size of the text grid that fits on the screen, in characters.
+    text_columns: usize,
+    text_rows: usize,
+    text_buf: Vec<TextRow>, // text_columns (used) x text_rows
+    /// Lines that scrolled off the top of `text_buf`, oldest first.
+    scrollback: VecDeque<TextRow>,
+    scrollback_capacity: usize,
+    /// How many lines the view is scrolled back from the live text.
+    view_offset: usize,
     text_cursor_x: usize,
     text_cursor_y: usize,
+    saved_cursor: (usize, usize),
+    /// Whether the screen is blanked, in which case drawing only reaches the back buffer.
+    blanked: bool,
     text_fgcolor: Color,
+    text_bgcolor: Color,
+    bold: bool,
+    ansi: AnsiState,
 }
 
 impl FrameBuffer {
@@ -111,6 +258,18 @@ impl FrameBuffer {
         self.size_bytes
     }
 
+    /// Returns the number of columns in the text buffer.
+    #[must_use]
+    pub fn text_columns(&self) -> usize {
+        self.text_columns
+    }
+
+    /// Returns the number of rows in the text buffer.
+    #[must_use]
+    pub fn text_rows(&self) -> usize {
+        self.text_rows
+    }
+
     /// Sets the foreground color for text rendering.
     pub fn set_text_fgcolor(&mut self, color: Color) {
         self.text_fgcolor = color;
@@ -121,18 +280,157 @@ impl FrameBuffer {
         self.text_fgcolor = Color::WHITE;
     }
 
+    /// Sets the background color for text rendering.
+    pub fn set_text_bgcolor(&mut self, color: Color) {
+        self.text_bgcolor = color;
+    }
+
+    /// Sets the background color for text rendering to the default color (black).
+    pub fn set_text_bgcolor_default(&mut self) {
+        self.text_bgcolor = Color::BLACK;
+    }
+
+    /// Moves the text cursor to the given row and column, clamped to the text buffer.
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.text_cursor_y = row.min(self.text_rows - 1);
+        self.text_cursor_x = col.min(self.text_columns - 1);
+        self.cursor_color_hook();
+    }
+
     /// Renders the text buffer to the framebuffer.
     pub fn render_text_buf(&mut self) {
-        for line in 0..TEXT_BUFFER_HEIGHT {
-            for col in 0..TEXT_BUFFER_WIDTH {
-                if let Some(ch) = self.text_buf[line][col] {
-                    let text = ch.as_text(self.bounding_box().top_left, col, line);
-                    text.draw(self).ok();
+        self.dirty_text_rows = [false; MAX_TEXT_ROWS];
+        for row in 0..self.text_rows {
+            self.draw_text_row(row);
+        }
+    }
+
+    /// Draws the characters of the given text row from the glyph cache, each as an opaque
+    /// cell of its foreground and background colors.
+    fn draw_text_row(&mut self, row: usize) {
+        let pixels = self.text_row_pixels(row);
+        if pixels.is_empty() {
+            return;
+        }
+        self.wait_for_present();
+        self.mark_rows_dirty(pixels.clone());
+
+        let font = glyphs::font();
+        let chars = *self.visible_row(row);
+        for (col, ch) in chars.iter().take(self.text_columns).enumerate() {
+            let Some(ch) = ch else { continue };
+            let x = font.width() * (col + 1);
+            let rows = glyphs::glyph(ch.char);
+            let (fg, bg) = (ch.fg.into_storage(), ch.bg.into_storage());
+            if x + font.width() <= self.width && pixels.len() == font.height() {
+                glyphs::blit(self.back_buffer, self.width, x, pixels.start, rows, fg, bg);
+                continue;
+            }
+
+            // clipped by the edge of a display too small for the text grid
+            let top = font.height() * (row + 1) - font.baseline();
+            for y in pixels.clone() {
+                let mask = rows[y - top];
+                for dx in 0..font.width().min(self.width.saturating_sub(x)) {
+                    let lit = mask >> dx & 1 != 0;
+                    self.back_buffer[y * self.width + x + dx] = if lit { fg } else { bg };
                 }
             }
         }
     }
 
+    /// Returns the text row shown on the given screen row, taking scrollback into account.
+    fn visible_row(&self, row: usize) -> &TextRow {
+        // the top `view_offset` rows of the screen come from the scrollback
+        if row < self.view_offset {
+            &self.scrollback[self.scrollback.len() - self.view_offset + row]
+        } else {
+            &self.text_buf[row - self.view_offset]
+        }
+    }
+
+    /// Returns the number of lines in the scrollback history.
+    #[must_use]
+    pub fn scrollback_len(&self) -> usize {
+        self.scrollback.len()
+    }
+
+    /// Sets how many lines of scrollback history are kept, discarding the oldest if needed.
+    pub fn set_scrollback_capacity(&mut self, lines: usize) {
+        self.scrollback_capacity = lines;
+        while self.scrollback.len() > lines {
+            self.scrollback.pop_front();
+        }
+        self.scroll_to(self.view_offset);
+    }
+
+    /// Scrolls the view back through the history by the given number of lines.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll_to(self.view_offset.saturating_add(lines));
+    }
+
+    /// Scrolls the view forward towards the live text by the given number of lines.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll_to(self.view_offset.saturating_sub(lines));
+    }
+
+    /// Scrolls the view back by one screen.
+    pub fn page_up(&mut self) {
+        self.scroll_up(self.text_rows - 1);
+    }
+
+    /// Scrolls the view forward by one screen.
+    pub fn page_down(&mut self) {
+        self.scroll_down(self.text_rows - 1);
+    }
+
+    /// Returns the view to the live text.
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_to(0);
+    }
+
+    fn scroll_to(&mut self, offset: usize) {
+        let offset = offset.min(self.scrollback.len());
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.dirty_text_rows = [true; MAX_TEXT_ROWS];
+        }
+    }
+
+    /// Re-renders only the text rows that changed since they were last rendered,
+    /// clearing each of their pixel rows first.
+    pub fn render_dirty_text(&mut self) {
+        for row in 0..self.text_rows {
+            if !core::mem::take(&mut self.dirty_text_rows[row]) {
+                continue;
+            }
+
+            let pixels = self.text_row_pixels(row);
+            self.wait_for_present();
+            self.back_buffer[pixels.start * self.width..pixels.end * self.width]
+                .fill(Color::BLACK.into_storage());
+            self.mark_rows_dirty(pixels);
+            self.draw_text_row(row);
+        }
+    }
+
+    /// Returns the pixel rows covered by the given text row.
+    fn text_row_pixels(&self, row: usize) -> Range<usize> {
+        let font = glyphs::font();
+        let top = (font.height() * (row + 1) - font.baseline()).min(self.height);
+        let bottom = (top + font.height()).min(self.height);
+        top..bottom
+    }
+
+    fn mark_rows_dirty(&mut self, rows: Range<usize>) {
+        self.dirty_rows = Some(row_union(self.dirty_rows.take(), rows));
+    }
+
+    /// Returns the address of the buffer being displayed.
+    fn front_addr(&self) -> VirtAddr {
+        self.start_addr.add_bytes(self.front * self.size_bytes)
+    }
+
     /// Clears the framebuffer by filling it with black pixels.
     pub fn clear_pixels(&mut self) {
         self.clear(Color::BLACK).debug_checked_unwrap(); // should never fail
@@ -142,21 +440,208 @@ impl FrameBuffer {
     pub fn frame_mut(&mut self) -> &mut [u32] {
         unsafe {
             core::slice::from_raw_parts_mut(
-                self.start_addr.as_raw_ptr_mut(),
+                self.front_addr().as_raw_ptr_mut(),
                 self.size_bytes() / size_of::<u32>(),
             )
         }
     }
 
+    /// Switches to a newly allocated framebuffer, reallocating the back buffer and resizing
+    /// the text grid to fit. The whole screen is redrawn.
+    ///
+    /// Rows that no longer fit are pushed into the scrollback, and columns that no longer
+    /// fit are discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ENOMEM` if the new back buffer cannot be allocated, in which case the
+    /// framebuffer is left unchanged.
+    pub fn apply_mode(&mut self, info: FramebufferInfo) -> Result<(), Errno> {
+        self.wait_for_present();
+
+        let (back_buffer, back_buffer_phys) = alloc_back_buffer(info.size_bytes)?;
+        let old = core::mem::replace(&mut self.back_buffer, back_buffer);
+        let old_phys = core::mem::replace(&mut self.back_buffer_phys, back_buffer_phys);
+        let old_size = FrameCount::from_bytes(size_of_val(old));
+        if let Err(e) = KernelFrameAllocator.free(old_phys, old_size) {
+            log::warn!("failed to free old framebuffer back buffer: {e}");
+        }
+
+        self.start_addr = info.start_addr;
+        self.size_bytes = info.size_bytes;
+        self.buffers = info.buffers;
+        self.front = 0;
+        self.width = info.width;
+        self.height = info.height;
+        self.bpp = info.bpp;
+        self.dirty_rows = None;
+        self.stale_rows = None;
+        self.flipped_at = None;
+
+        let (columns, rows) = text_grid_size(info.width, info.height);
+        self.resize_text(columns, rows);
+
+        self.clear_pixels();
+        self.dirty_text_rows = [true; MAX_TEXT_ROWS];
+        self.render_dirty_text();
+        self.present();
+        Ok(())
+    }
+
+    fn resize_text(&mut self, columns: usize, rows: usize) {
+        // keep the bottom of the screen (where the cursor usually is) in view
+        let excess = self.text_buf.len().saturating_sub(rows);
+        for row in self.text_buf.drain(..excess) {
+            if self.scrollback_capacity > 0 {
+                if self.scrollback.len() >= self.scrollback_capacity {
+                    self.scrollback.pop_front();
+                }
+                self.scrollback.push_back(row);
+            }
+        }
+        self.text_buf.resize(rows, [None; MAX_TEXT_COLUMNS]);
+        for row in &mut self.text_buf {
+            row[columns..].fill(None);
+        }
+
+        self.text_columns = columns;
+        self.text_rows = rows;
+        self.text_cursor_y = self.text_cursor_y.saturating_sub(excess).min(rows - 1);
+        self.text_cursor_x = self.text_cursor_x.min(columns - 1);
+        self.saved_cursor = (0, 0);
+        self.view_offset = 0;
+    }
+
     /// Writes a single byte to the framebuffer's text buffer at the current cursor position.
     /// The cursor position is updated accordingly, wrapping to the next line if necessary.
+    ///
+    /// ANSI escape sequences are interpreted: SGR colors, cursor movement, and erasing
+    /// the display or line. Unsupported sequences are swallowed.
     pub fn write_byte(&mut self, byte: u8) {
+        match core::mem::take(&mut self.ansi) {
+            AnsiState::Ground if byte == 0x1b => self.ansi = AnsiState::Escape,
+            AnsiState::Ground => self.put_byte(byte),
+            AnsiState::Escape if byte == b'[' => self.ansi = AnsiState::Csi(CsiParams::default()),
+            // other escapes (charset selection, etc.) are a single byte long
+            AnsiState::Escape => {}
+            AnsiState::Csi(mut csi) => match byte {
+                b'0'..=b'9' => {
+                    csi.push_digit(byte - b'0');
+                    self.ansi = AnsiState::Csi(csi);
+                }
+                b';' | b':' => {
+                    csi.end_param();
+                    self.ansi = AnsiState::Csi(csi);
+                }
+                b'<'..=b'?' => {
+                    csi.private = true;
+                    self.ansi = AnsiState::Csi(csi);
+                }
+                // intermediate bytes
+                0x20..=0x2f => self.ansi = AnsiState::Csi(csi),
+                0x40..=0x7e => {
+                    csi.finish();
+                    self.execute_csi(byte, &csi);
+                }
+                // anything else aborts the sequence
+                _ => {}
+            },
+        }
+    }
+
+    fn execute_csi(&mut self, action: u8, csi: &CsiParams) {
+        if csi.private {
+            return;
+        }
+        let n = usize::from(csi.get_or(0, 1));
+        match action {
+            b'A' => self.text_cursor_y = self.text_cursor_y.saturating_sub(n),
+            b'B' => self.text_cursor_y = (self.text_cursor_y + n).min(self.text_rows - 1),
+            b'C' => self.text_cursor_x = (self.text_cursor_x + n).min(self.text_columns - 1),
+            b'D' => self.text_cursor_x = self.text_cursor_x.saturating_sub(n),
+            b'E' => {
+                self.text_cursor_y = (self.text_cursor_y + n).min(self.text_rows - 1);
+                self.text_cursor_x = 0;
+            }
+            b'F' => {
+                self.text_cursor_y = self.text_cursor_y.saturating_sub(n);
+                self.text_cursor_x = 0;
+            }
+            b'G' => self.set_cursor_position(self.text_cursor_y, n - 1),
+            b'd' => self.set_cursor_position(n - 1, self.text_cursor_x),
+            b'H' | b'f' => {
+                let col = usize::from(csi.get_or(1, 1));
+                self.set_cursor_position(n - 1, col - 1);
+            }
+            b'J' => match csi.get_or(0, 0) {
+                0 => self.clear_until_end(),
+                1 => self.clear_until_beginning(),
+                2 | 3 => self.clear_text(),
+                _ => {}
+            },
+            b'K' => match csi.get_or(0, 0) {
+                0 => self.clear_until_eol(),
+                1 => self.clear_from_bol(),
+                2 => self.clear_line(),
+                _ => {}
+            },
+            b'm' => self.select_graphic_rendition(&csi.params),
+            b's' => self.saved_cursor = (self.text_cursor_y, self.text_cursor_x),
+            b'u' => self.set_cursor_position(self.saved_cursor.0, self.saved_cursor.1),
+            _ => {}
+        }
+        self.cursor_color_hook();
+    }
+
+    /// Applies an SGR (`ESC [ ... m`) sequence.
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.reset_text_attributes();
+            return;
+        }
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.reset_text_attributes(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.text_fgcolor = ansi_color(param - 30, self.bold),
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.text_fgcolor = color;
+                    }
+                }
+                39 => self.set_text_fgcolor_default(),
+                40..=47 => self.text_bgcolor = ansi_color(param - 40, false),
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.text_bgcolor = color;
+                    }
+                }
+                49 => self.set_text_bgcolor_default(),
+                90..=97 => self.text_fgcolor = ansi_color(param - 90, true),
+                100..=107 => self.text_bgcolor = ansi_color(param - 100, true),
+                _ => {}
+            }
+        }
+    }
+
+    fn reset_text_attributes(&mut self) {
+        self.set_text_fgcolor_default();
+        self.set_text_bgcolor_default();
+        self.bold = false;
+    }
+
+    /// Writes a byte to the text buffer without interpreting escape sequences.
+    fn put_byte(&mut self, byte: u8) {
+        // new output always brings the view back to the live text
+        self.scroll_to_bottom();
         match byte {
             0x8 => self.backspace(),
             b'\n' => self.new_line(),
             b'\r' => self.text_cursor_x = 0,
             byte => {
-                if self.text_cursor_x >= TEXT_BUFFER_WIDTH - 1 {
+                if self.text_cursor_x >= self.text_columns - 1 {
                     self.new_line();
                 }
 
@@ -166,7 +651,9 @@ impl FrameBuffer {
                 self.text_buf[row][col] = Some(FbChar {
                     char: byte,
                     fg: self.text_fgcolor,
+                    bg: self.text_bgcolor,
                 });
+                self.dirty_text_rows[row] = true;
                 self.move_right();
             }
         }
@@ -178,8 +665,10 @@ impl FrameBuffer {
         if x >= self.width || y >= self.height {
             return;
         }
+        self.wait_for_present();
 
         self.back_buffer[x + y * self.width] = color.into_storage();
+        self.mark_rows_dirty(y..y + 1);
     }
 
     /// Sets a pixel at the given coordinates to the specified raw color value.
@@ -198,15 +687,128 @@ impl FrameBuffer {
         }
     }
 
-    /// Copies the back buffer to the framebuffer, making the changes visible.
+    /// Waits for an in-flight DMA present to finish, so the back buffer can be touched again.
+    pub fn wait_for_present(&mut self) {
+        if let Some(transfer) = self.pending_present.take()
+            && let Err(e) = transfer.wait()
+        {
+            log::warn!("framebuffer DMA present failed: {e:?}");
+        }
+    }
+
+    /// Copies the changed rows of the back buffer to the framebuffer, making them visible.
+    ///
+    /// With one buffer, the rows are copied straight to the screen. The copy is offloaded to
+    /// a DMA channel when one is available; it completes in the background and is waited on
+    /// before the back buffer is next modified.
+    ///
+    /// With two, the rows are copied to the buffer that isn't displayed, and the display flips
+    /// to it, so nothing is seen half drawn. If vertical sync interrupts are available and the
+    /// last flip hasn't taken effect yet, the changes are held back until the next one.
+    ///
+    /// While the screen is blanked, the changes are held back until it is unblanked.
     pub fn present(&mut self) {
+        self.wait_for_present();
+        if self.blanked {
+            return;
+        }
+        if self.buffers > 1 {
+            self.present_flipped();
+            return;
+        }
+
+        let Some(rows) = self.dirty_rows.take() else {
+            return;
+        };
+        self.pending_present = self.copy_rows(rows, self.front);
+    }
+
+    fn present_flipped(&mut self) {
+        // the display scans the old front buffer out until the flip takes effect
+        if vsync::is_available() && self.flipped_at == Some(vsync::count()) {
+            return;
+        }
+        let Some(rows) = self.dirty_rows.take() else {
+            return;
+        };
+
+        // the buffer being drawn also missed what went to the other one last time
+        let back = self.front ^ 1;
+        let copy = row_union(self.stale_rows.take(), rows.clone());
+        self.pending_present = self.copy_rows(copy, back);
+        self.wait_for_present();
+
+        match gpu::set_virtual_offset(0, back * self.height) {
+            Ok(()) => {
+                self.front = back;
+                self.stale_rows = Some(rows);
+                self.flipped_at = Some(vsync::count());
+            }
+            Err(e) => {
+                log::warn!("framebuffer flip failed, so it won't flip any more: {e:?}");
+                self.buffers = 1;
+                self.mark_rows_dirty(0..self.height);
+                self.present();
+            }
+        }
+    }
+
+    /// Copies the given pixel rows of the back buffer to buffer `buffer`, returning the DMA
+    /// transfer doing it if there is one.
+    fn copy_rows(&self, rows: Range<usize>, buffer: usize) -> Option<DmaTransfer> {
+        let offset = rows.start * self.width * size_of::<u32>();
+        let len = rows.len() * self.width * size_of::<u32>();
+        let src = unsafe { self.back_buffer.as_ptr().byte_add(offset) };
+        let dst = self
+            .start_addr
+            .add_bytes(buffer * self.size_bytes)
+            .add_bytes(offset);
+
+        if dma::is_available() {
+            unsafe {
+                clean_data_cache(src.cast(), len);
+                invalidate_data_cache(dst.as_raw_ptr(), len);
+            }
+            if let Ok(transfer) = dma::dma_copy(
+                dst.as_hhdm_phys(),
+                self.back_buffer_phys.add_bytes(offset),
+                len,
+            ) {
+                return Some(transfer);
+            }
+        }
+
         unsafe {
             core::ptr::copy_nonoverlapping(
-                self.back_buffer.as_ptr(),
-                self.frame_mut().as_mut_ptr(),
-                self.size_bytes() / size_of::<u32>(),
+                src,
+                dst.as_raw_ptr_mut::<u32>(),
+                len / size_of::<u32>(),
             );
-            clean_data_cache(self.frame_mut().as_mut_ptr().cast(), self.size_bytes());
+            clean_data_cache(dst.as_raw_ptr(), len);
+        }
+        None
+    }
+
+    /// Returns `true` if the screen is blanked.
+    #[must_use]
+    pub fn is_blanked(&self) -> bool {
+        self.blanked
+    }
+
+    /// Blanks the screen, or restores it with everything drawn in the meantime.
+    pub fn set_blanked(&mut self, blanked: bool) {
+        if blanked == self.blanked {
+            return;
+        }
+        self.wait_for_present();
+        self.blanked = blanked;
+
+        if blanked {
+            self.frame_mut().fill(Color::BLACK.into_storage());
+            unsafe { clean_data_cache(self.front_addr().as_raw_ptr(), self.size_bytes) };
+        } else {
+            self.mark_rows_dirty(0..self.height);
+            self.present();
         }
     }
 
@@ -220,6 +822,7 @@ impl FrameBuffer {
         let row = self.text_cursor_y;
         let col = self.text_cursor_x.saturating_sub(1);
         self.text_buf[row][col] = None;
+        self.dirty_text_rows[row] = true;
         self.text_cursor_x = col;
         self.cursor_color_hook();
     }
@@ -235,14 +838,17 @@ impl FrameBuffer {
     /// If the cursor is already at the last line, it scrolls the text buffer up.
     /// The cursor is reset to the beginning of the new line.
     pub fn new_line(&mut self) {
-        if self.text_cursor_y >= TEXT_BUFFER_HEIGHT - 1 {
-            for row in 1..TEXT_BUFFER_HEIGHT {
-                for col in 0..TEXT_BUFFER_WIDTH {
-                    let character = self.text_buf[row][col];
-                    self.text_buf[row - 1][col] = character;
+        if self.text_cursor_y >= self.text_rows - 1 {
+            if self.scrollback_capacity > 0 {
+                if self.scrollback.len() >= self.scrollback_capacity {
+                    self.scrollback.pop_front();
                 }
+                self.scrollback.push_back(self.text_buf[0]);
             }
-            self.text_cursor_y = TEXT_BUFFER_HEIGHT - 1;
+            self.text_buf.copy_within(1.., 0);
+            // everything moved up a row, so the whole screen has to be redrawn
+            self.dirty_text_rows = [true; MAX_TEXT_ROWS];
+            self.text_cursor_y = self.text_rows - 1;
             self.clear_row(self.text_cursor_y);
             self.text_cursor_x = 0;
         } else {
@@ -254,18 +860,20 @@ impl FrameBuffer {
 
     /// Clears the specified row in the text buffer.
     pub fn clear_row(&mut self, row: usize) {
-        for col in 0..TEXT_BUFFER_WIDTH {
+        for col in 0..self.text_columns {
             self.text_buf[row][col] = None;
         }
+        self.dirty_text_rows[row] = true;
         self.cursor_color_hook();
     }
 
     /// Clears the text buffer from the current cursor position to the end of the text buffer.
     pub fn clear_until_end(&mut self) {
-        for col in self.text_cursor_x..TEXT_BUFFER_WIDTH {
+        for col in self.text_cursor_x..self.text_columns {
             self.text_buf[self.text_cursor_y][col] = None;
         }
-        for row in self.text_cursor_y + 1..TEXT_BUFFER_HEIGHT {
+        self.dirty_text_rows[self.text_cursor_y] = true;
+        for row in self.text_cursor_y + 1..self.text_rows {
             self.clear_row(row);
         }
         self.cursor_color_hook();
@@ -276,7 +884,8 @@ impl FrameBuffer {
         for col in 0..self.text_cursor_x {
             self.text_buf[self.text_cursor_y][col] = None;
         }
-        for row in 0..self.text_cursor_y - 1 {
+        self.dirty_text_rows[self.text_cursor_y] = true;
+        for row in 0..self.text_cursor_y {
             self.clear_row(row);
         }
         self.cursor_color_hook();
@@ -284,9 +893,10 @@ impl FrameBuffer {
 
     /// Clears the text buffer from the current cursor position to the end of the line.
     pub fn clear_until_eol(&mut self) {
-        for col in self.text_cursor_x..TEXT_BUFFER_WIDTH {
+        for col in self.text_cursor_x..self.text_columns {
             self.text_buf[self.text_cursor_y][col] = None;
         }
+        self.dirty_text_rows[self.text_cursor_y] = true;
         self.cursor_color_hook();
     }
 
@@ -295,6 +905,7 @@ impl FrameBuffer {
         for col in 0..self.text_cursor_x {
             self.text_buf[self.text_cursor_y][col] = None;
         }
+        self.dirty_text_rows[self.text_cursor_y] = true;
         self.cursor_color_hook();
     }
 
@@ -305,7 +916,7 @@ impl FrameBuffer {
 
     /// Clears the entire text buffer.
     pub fn clear_text(&mut self) {
-        for row in 0..TEXT_BUFFER_HEIGHT {
+        for row in 0..self.text_rows {
             self.clear_row(row);
         }
         self.cursor_color_hook();
@@ -320,7 +931,7 @@ impl FrameBuffer {
 
     /// Moves the text cursor down by one line, if possible.
     pub fn move_down(&mut self) {
-        let new_y = self.text_cursor_y.add(1).min(TEXT_BUFFER_HEIGHT - 1);
+        let new_y = self.text_cursor_y.add(1).min(self.text_rows - 1);
         self.text_cursor_y = new_y;
         self.cursor_color_hook();
     }
@@ -333,7 +944,7 @@ impl FrameBuffer {
 
     /// Moves the text cursor to the right by one character, if possible.
     pub fn move_right(&mut self) {
-        self.text_cursor_x = self.text_cursor_x.add(1).min(TEXT_BUFFER_WIDTH - 1);
+        self.text_cursor_x = self.text_cursor_x.add(1).min(self.text_columns - 1);
         self.cursor_color_hook();
     }
 }
@@ -365,7 +976,9 @@ impl DrawTarget for FrameBuffer {
 
     fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
         let color = color.into_storage();
+        self.wait_for_present();
         self.back_buffer.fill(color);
+        self.mark_rows_dirty(0..self.height);
 
         Ok(())
     }
@@ -377,17 +990,14 @@ impl OriginDimensions for FrameBuffer {
     }
 }
 
-/// A global framebuffer instance, protected by an IRQ mutex.
-pub static FRAMEBUFFER: Once<IrqMutex<FrameBuffer>> = Once::new();
+/// A global framebuffer instance, protected by a reader-writer lock that disables interrupts.
+pub static FRAMEBUFFER: Once<RwLock<FrameBuffer>> = Once::new();
 
-/// Runs the provided function with the framebuffer locked.
+/// Runs the provided function with the framebuffer locked for writing, or returns `None` if
+/// it's locked already.
 pub fn with_fb<R>(f: impl FnOnce(&mut FrameBuffer) -> R) -> Option<R> {
-    let fb = FRAMEBUFFER.get()?;
-    let result = {
-        let mut fb = fb.try_lock().ok()?;
-        f(&mut fb)
-    };
-    Some(result)
+    let mut fb = FRAMEBUFFER.get()?.try_write()?;
+    Some(f(&mut fb))
 }
 
 /// Prints a formatted string to the framebuffer's text buffer.
@@ -410,8 +1020,7 @@ pub fn write_fmt(args: core::fmt::Arguments) {
     use core::fmt::Write;
     with_fb(|fb| {
         fb.write_fmt(args).ok();
-        fb.clear_pixels();
-        fb.render_text_buf();
+        fb.render_dirty_text();
         fb.present();
     });
 }
@@ -420,20 +1029,76 @@ pub fn write_fmt(args: core::fmt::Arguments) {
 #[derive(Debug, Clone, Copy)]
 pub struct FramebufferInfo {
     pub start_addr: VirtAddr,
+    /// The size of each buffer in bytes.
     pub size_bytes: usize,
+    /// How many buffers there are, one after another: two if the display can flip between
+    /// them, otherwise one.
+    pub buffers: usize,
     pub width: usize,
     pub height: usize,
     pub bpp: usize,
 }
 
 /// A static reference to the framebuffer information, set by the kernel during device initialization.
+///
+/// This describes the mode the framebuffer was brought up in; see [`FrameBuffer::width`] and
+/// friends for the current one.
 pub static FRAMEBUFFER_INFO: Once<FramebufferInfo> = Once::new();
 
+/// Sets [`FRAMEBUFFER_INFO`] to the framebuffer the firmware left, unless a driver has already
+/// brought one up, mapping it into the HHDM.
+///
+/// # Errors
+///
+/// Returns [`Errno::EOPNOTSUPP`] if its pixels aren't the 32-bit `x8r8g8b8` the console draws,
+/// packed with no padding at the ends of lines, or [`Errno::ENOMEM`] if it can't be mapped.
+pub fn adopt_boot_framebuffer(fb: &BootFramebuffer) -> Result<(), Errno> {
+    if FRAMEBUFFER_INFO.is_completed() {
+        return Ok(());
+    }
+    if !matches!(fb.format(), b"x8r8g8b8" | b"a8r8g8b8") || fb.stride != fb.width * 4 {
+        return Err(Errno::EOPNOTSUPP);
+    }
+
+    let frame = PhysAddr::new_canonical(fb.memory.start as usize);
+    let page = frame.as_hhdm_virt();
+    PageTable::current(TableKind::Kernel)
+        .kernel_remap_range(
+            page,
+            frame,
+            fb.memory.len() as usize,
+            PageFlags::new().writable(),
+        )
+        .map_err(|_| Errno::ENOMEM)?
+        .flush();
+
+    log::info!(
+        "using the firmware's {}x{} framebuffer at {}",
+        fb.width,
+        fb.height,
+        frame
+    );
+    FRAMEBUFFER_INFO.call_once(|| FramebufferInfo {
+        start_addr: page,
+        size_bytes: fb.memory.len() as usize,
+        buffers: 1,
+        width: fb.width as usize,
+        height: fb.height as usize,
+        bpp: 32,
+    });
+    Ok(())
+}
+
 /// Initializes the global [`FRAMEBUFFER`] from the predefined [`FRAMEBUFFER_INFO`].
+///
+/// # Panics
+///
+/// This function will panic if the back buffer cannot be allocated.
 pub fn init() {
     let Some(FramebufferInfo {
         start_addr,
         size_bytes,
+        buffers,
         width,
         height,
         bpp,
@@ -442,17 +1107,39 @@ pub fn init() {
         return;
     };
 
+    let (back_buffer, back_buffer_phys) =
+        alloc_back_buffer(size_bytes).expect("failed to allocate framebuffer back buffer");
+    let (text_columns, text_rows) = text_grid_size(width, height);
+
     let mut framebuf = FrameBuffer {
         start_addr,
         size_bytes,
+        buffers,
+        front: 0,
         width,
         height,
         bpp,
-        back_buffer: alloc::vec![0; size_bytes / size_of::<u32>()].into_boxed_slice(),
-        text_buf: alloc::vec![[None; TEXT_BUFFER_WIDTH]; TEXT_BUFFER_HEIGHT].into_boxed_slice(),
+        back_buffer,
+        back_buffer_phys,
+        pending_present: None,
+        dirty_rows: None,
+        stale_rows: None,
+        flipped_at: None,
+        dirty_text_rows: [false; MAX_TEXT_ROWS],
+        text_columns,
+        text_rows,
+        text_buf: alloc::vec![[None; MAX_TEXT_COLUMNS]; text_rows],
+        scrollback: VecDeque::new(),
+        scrollback_capacity: DEFAULT_SCROLLBACK_LINES,
+        view_offset: 0,
         text_cursor_x: 0,
         text_cursor_y: 0,
+        saved_cursor: (0, 0),
+        blanked: false,
         text_fgcolor: Color::WHITE,
+        text_bgcolor: Color::BLACK,
+        bold: false,
+        ansi: AnsiState::Ground,
     };
 
     log::debug!(
@@ -467,7 +1154,183 @@ pub fn init() {
     framebuf.render_text_buf();
     framebuf.present();
 
-    FRAMEBUFFER.call_once(|| IrqMutex::new(framebuf));
+    FRAMEBUFFER.call_once(|| RwLock::new(framebuf));
+    vsync::set_callback(Some(present_held_back));
+
+    crate::shell::register(crate::shell::Command {
+        name: "scroll",
+        usage: "up|down|pgup|pgdn|bottom [lines]",
+        help: "scroll the framebuffer console through its history",
+        run: cmd_scroll,
+    });
+    crate::shell::register(crate::shell::Command {
+        name: "mode",
+        usage: "[native | <width>x<height>]",
+        help: "show or change the display resolution",
+        run: cmd_mode,
+    });
+
+    let font = glyphs::font();
+    log::info!(
+        "Framebuffer resolution: {width}x{height} ({text_columns}x{text_rows} text, {} font at {}x)",
+        font.name,
+        font.scale
+    );
+}
+
+/// Presents whatever was held back until the framebuffer's last flip took effect.
+/// Called at every vertical sync.
+fn present_held_back() {
+    with_fb(|fb| {
+        if fb.buffers > 1 && fb.dirty_rows.is_some() {
+            fb.present();
+        }
+    });
+}
 
-    log::info!("Framebuffer resolution: {width}x{height}");
+/// Returns the smallest range of rows covering both `a` and `b`.
+fn row_union(a: Option<Range<usize>>, b: Range<usize>) -> Range<usize> {
+    match a {
+        Some(a) => a.start.min(b.start)..a.end.max(b.end),
+        None => b,
+    }
+}
+
+/// Allocates a back buffer of the given size.
+///
+/// The back buffer is physically contiguous so it can be the source of a DMA copy.
+fn alloc_back_buffer(size_bytes: usize) -> Result<(&'static mut [u32], PhysAddr), Errno> {
+    let phys = unsafe { KernelFrameAllocator.allocate(FrameCount::from_bytes(size_bytes)) }
+        .map_err(|_| Errno::ENOMEM)?;
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(
+            phys.as_hhdm_virt().as_raw_ptr_mut::<u32>(),
+            size_bytes / size_of::<u32>(),
+        )
+    };
+    Ok((buf, phys))
+}
+
+/// Returns the size of the text grid, in characters, that fits on a screen of the given size.
+fn text_grid_size(width: usize, height: usize) -> (usize, usize) {
+    // leave a character's worth of margin on each side, and room below the last baseline
+    let font = glyphs::font();
+    let columns = (width / font.width()).saturating_sub(2);
+    let rows = (height / font.height()).saturating_sub(1);
+    (
+        columns.clamp(1, MAX_TEXT_COLUMNS),
+        rows.clamp(1, MAX_TEXT_ROWS),
+    )
+}
+
+/// Changes the display resolution at runtime and re-initializes the [`FRAMEBUFFER`] to match.
+///
+/// # Errors
+///
+/// Returns `ENODEV` if there is no framebuffer, or an error from the GPU driver if the
+/// firmware rejects the mode.
+pub fn set_mode(width: usize, height: usize) -> Result<(), Errno> {
+    let (info, columns, rows) = {
+        let mut fb = FRAMEBUFFER.get().ok_or(Errno::ENODEV)?.write();
+        // nothing can be copying into the old buffer while the firmware swaps it out
+        fb.wait_for_present();
+        let info = unsafe { gpu::set_mode(width, height)? };
+        fb.apply_mode(info)?;
+        (info, fb.text_columns(), fb.text_rows())
+    };
+
+    log::info!(
+        "Framebuffer resolution: {}x{} ({columns}x{rows} text)",
+        info.width,
+        info.height
+    );
+    Ok(())
+}
+
+/// Maps the framebuffer into `addr_space` for a userspace display server, returning the
+/// address of its first pixel and what it looks like.
+///
+/// It's mapped uncached, so what's written reaches the display without cache maintenance.
+/// The console keeps drawing to it too, and the mapping isn't moved if the mode changes.
+/// Only the buffer being displayed is mapped, so the console stops flipping.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if there is no framebuffer, or any error from
+/// [`AddrSpace::map`].
+pub fn map_into(addr_space: &mut AddrSpace) -> Result<(VirtAddr, FbInfo), Errno> {
+    let (phys, size, info) = {
+        let mut fb = FRAMEBUFFER.get().ok_or(Errno::ENODEV)?.write();
+        fb.wait_for_present();
+        fb.buffers = 1;
+        let info = FbInfo {
+            width: fb.width as u32,
+            height: fb.height as u32,
+            stride: (fb.width * size_of::<u32>()) as u32,
+            bpp: fb.bpp as u32,
+        };
+        (fb.front_addr().as_hhdm_phys(), fb.size_bytes, info)
+    };
+    // the firmware's framebuffer needn't start on a page boundary
+    let offset = phys.value() & Arch::PAGE_OFFSET_MASK;
+    let start = addr_space.map(
+        VirtAddr::NULL,
+        size + offset,
+        false,
+        Protection::READ | Protection::WRITE,
+        Backing::Device(PhysAddr::new_canonical(phys.value() - offset)),
+    )?;
+    Ok((start.add_bytes(offset), info))
+}
+
+fn cmd_mode(mut args: crate::shell::Args) -> Result<(), Errno> {
+    let Some(mode) = args.next() else {
+        let (width, height, buffers) = {
+            let fb = FRAMEBUFFER.get().ok_or(Errno::ENODEV)?.read();
+            (fb.width(), fb.height(), fb.buffers)
+        };
+        crate::serial_println!("current: {width}x{height}, {buffers} buffer(s)");
+        crate::serial_println!(
+            "vsync:   {}",
+            if vsync::is_available() { "yes" } else { "no" }
+        );
+        match gpu::native_resolution() {
+            Ok((width, height)) => crate::serial_println!("native:  {width}x{height}"),
+            Err(e) => crate::serial_println!("native:  unknown ({e:?})"),
+        }
+        return Ok(());
+    };
+
+    let (width, height) = if mode == "native" {
+        gpu::native_resolution()?
+    } else {
+        let (width, height) = mode.split_once('x').ok_or(Errno::EINVAL)?;
+        (
+            width.parse().map_err(|_| Errno::EINVAL)?,
+            height.parse().map_err(|_| Errno::EINVAL)?,
+        )
+    };
+    set_mode(width, height)
+}
+
+fn cmd_scroll(mut args: crate::shell::Args) -> Result<(), Errno> {
+    let direction = args.next().ok_or(Errno::EINVAL)?;
+    let lines = match args.next() {
+        Some(lines) => lines.parse().map_err(|_| Errno::EINVAL)?,
+        None => 1,
+    };
+    with_fb(|fb| {
+        match direction {
+            "up" => fb.scroll_up(lines),
+            "down" => fb.scroll_down(lines),
+            "pgup" => fb.page_up(),
+            "pgdn" => fb.page_down(),
+            "bottom" => fb.scroll_to_bottom(),
+            _ => return Err(Errno::EINVAL),
+        }
+        fb.render_dirty_text();
+        fb.present();
+        Ok(())
+    })
+    .ok_or(Errno::EBUSY)?
 }
@@ -0,0 +1,233 @@
+//! The console font, a cache of its glyphs as bitmasks, and blitting them into the back buffer.
+//!
+//! Drawing text through `embedded-graphics` rasterizes every glyph from the font's packed
+//! image, a pixel at a time, each time it's drawn. Instead, each glyph is rasterized once,
+//! scaled up, into one mask per pixel row, and drawn by expanding two bits of a mask at a time
+//! into a pair of pixels with a single 64-bit store.
+
+use alloc::{vec, vec::Vec};
+use embedded_graphics::{
+    Pixel,
+    mono_font::{MonoFont, MonoTextStyle, ascii},
+    prelude::{DrawTarget, Drawable, OriginDimensions, Point, RgbColor, Size},
+    text::Text,
+};
+use spin::Once;
+
+use crate::cmdline;
+
+use super::Color;
+
+/// A font the console can draw text in.
+#[derive(Clone, Copy, Debug)]
+pub struct Font {
+    /// The name the `fbfont` option picks it by.
+    pub name: &'static str,
+    mono: &'static MonoFont<'static>,
+    /// The size of a character cell before scaling, which may be taller than the glyphs. They
+    /// sit in the middle of it.
+    cell: (usize, usize),
+    /// How many times bigger than its glyphs the font is drawn.
+    pub scale: usize,
+}
+
+impl Font {
+    /// Returns the width of a character in pixels.
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.cell.0 * self.scale
+    }
+
+    /// Returns the height of a character in pixels.
+    #[must_use]
+    pub fn height(&self) -> usize {
+        self.cell.1 * self.scale
+    }
+
+    /// Returns how far a character's baseline is below its top, in pixels.
+    #[must_use]
+    pub fn baseline(&self) -> usize {
+        (self.padding() + self.mono.baseline as usize) * self.scale
+    }
+
+    /// Returns the unscaled gap between the top of a character cell and its glyph.
+    fn padding(&self) -> usize {
+        (self.cell.1 - self.mono.character_size.height as usize) / 2
+    }
+}
+
+/// The fonts the `fbfont` option can pick from.
+const FONTS: [Font; 2] = [
+    Font {
+        name: "8x16",
+        mono: &ascii::FONT_8X13,
+        cell: (8, 16),
+        scale: 1,
+    },
+    Font {
+        name: "10x20",
+        mono: &ascii::FONT_10X20,
+        cell: (10, 20),
+        scale: 1,
+    },
+];
+
+/// The font in [`FONTS`] used unless the command line picks another.
+const DEFAULT_FONT: Font = FONTS[1];
+
+/// The most the `fbscale` option can scale a font up by.
+pub const MAX_FONT_SCALE: usize = 3;
+
+const _: () = {
+    let mut i = 0;
+    while i < FONTS.len() {
+        assert!(
+            FONTS[i].cell.0 * MAX_FONT_SCALE <= 32,
+            "glyph rows are kept as `u32` masks"
+        );
+        i += 1;
+    }
+};
+
+static FONT: Once<Font> = Once::new();
+
+/// Returns the console font, picked by the `fbfont` and `fbscale` options on the
+/// [command line](crate::cmdline).
+pub fn font() -> &'static Font {
+    FONT.call_once(|| {
+        let font = match cmdline::get("fbfont") {
+            None => DEFAULT_FONT,
+            Some(name) => FONTS
+                .into_iter()
+                .find(|font| font.name == name)
+                .unwrap_or_else(|| {
+                    log::warn!(
+                        "cmdline: unknown `fbfont={name}`, using {}",
+                        DEFAULT_FONT.name
+                    );
+                    DEFAULT_FONT
+                }),
+        };
+
+        let scale = match cmdline::get("fbscale").map(str::parse) {
+            None => 1,
+            Some(Ok(scale)) if (1..=MAX_FONT_SCALE).contains(&scale) => scale,
+            Some(_) => {
+                log::warn!("cmdline: `fbscale` isn't from 1 to {MAX_FONT_SCALE}, so it's ignored");
+                1
+            }
+        };
+        Font { scale, ..font }
+    })
+}
+
+/// The bytes the cache has glyphs for. Anything else is drawn as a space.
+const GLYPH_COUNT: usize = 128;
+
+static GLYPHS: Once<GlyphCache> = Once::new();
+
+/// The console font's glyphs, scaled up, with bit `x` of row `y` set where pixel `(x, y)` is
+/// lit. Each glyph is [`Font::height`] rows.
+struct GlyphCache {
+    masks: Vec<u32>,
+}
+
+impl GlyphCache {
+    fn new(font: &Font) -> Self {
+        let height = font.height();
+        let mut cache = Self {
+            masks: vec![0; height * GLYPH_COUNT],
+        };
+        let style = MonoTextStyle::new(font.mono, Color::WHITE);
+        let baseline = (font.padding() + font.mono.baseline as usize) as i32;
+        for (byte, mask) in cache.masks.chunks_exact_mut(height).enumerate() {
+            let c = [byte as u8];
+            let s = core::str::from_utf8(&c).unwrap_or(" ");
+            let mut target = MaskTarget { mask, font };
+            Text::new(s, Point::new(0, baseline), style)
+                .draw(&mut target)
+                .ok();
+        }
+        cache
+    }
+}
+
+/// Records a glyph's lit pixels into its masks, scaled up.
+struct MaskTarget<'a> {
+    mask: &'a mut [u32],
+    font: &'a Font,
+}
+
+impl DrawTarget for MaskTarget<'_> {
+    type Color = Color;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let scale = self.font.scale;
+        let block = (1 << scale) - 1;
+        for Pixel(point, color) in pixels {
+            if color == Color::WHITE
+                && let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y))
+                && x < self.font.cell.0
+                && y < self.font.cell.1
+            {
+                for row in &mut self.mask[y * scale..(y + 1) * scale] {
+                    *row |= block << (x * scale);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OriginDimensions for MaskTarget<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.font.cell.0 as u32, self.font.cell.1 as u32)
+    }
+}
+
+/// Returns the pixel rows of the glyph for `byte`, top to bottom.
+pub fn glyph(byte: u8) -> &'static [u32] {
+    let font = font();
+    let glyphs = GLYPHS.call_once(|| GlyphCache::new(font));
+    let index = usize::from(byte);
+    let index = if index < GLYPH_COUNT {
+        index
+    } else {
+        usize::from(b' ')
+    };
+    &glyphs.masks[index * font.height()..(index + 1) * font.height()]
+}
+
+/// Draws a glyph's pixel rows into `buf`, a `stride` pixels wide image, with its top left
+/// corner at `(x, y)`. The glyph must fit entirely within `buf`.
+pub fn blit(buf: &mut [u32], stride: usize, x: usize, y: usize, rows: &[u32], fg: u32, bg: u32) {
+    let width = font().width();
+    // every combination of a pair of pixels, the left one in the low half
+    let pairs = [
+        u64::from(bg) | u64::from(bg) << 32,
+        u64::from(fg) | u64::from(bg) << 32,
+        u64::from(bg) | u64::from(fg) << 32,
+        u64::from(fg) | u64::from(fg) << 32,
+    ];
+    for (dy, &mask) in rows.iter().enumerate() {
+        let start = (y + dy) * stride + x;
+        let row = &mut buf[start..start + width];
+        let (pixel_pairs, rest) = row.as_chunks_mut::<2>();
+        for (i, pair) in pixel_pairs.iter_mut().enumerate() {
+            let bits = (mask >> (i * 2)) & 0b11;
+            // a pair of pixels is only as aligned as one of them
+            unsafe {
+                pair.as_mut_ptr()
+                    .cast::<u64>()
+                    .write_unaligned(pairs[bits as usize]);
+            };
+        }
+        if let [last] = rest {
+            *last = if mask >> (width - 1) & 1 == 0 { bg } else { fg };
+        }
+    }
+}
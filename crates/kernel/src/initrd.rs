@@ -0,0 +1,119 @@
+//! The initial ramdisk: a cpio archive the firmware loads alongside the kernel.
+//!
+//! The firmware records where it put the archive in the FDT's `/chosen` node, which the boot
+//! code reads into [`BootInfo::initrd`]. Its memory is reserved from the frame allocator and
+//! mapped read-only into the HHDM, where it stays for the life of the kernel.
+//!
+//! `cargo builder --initrd <path>` has QEMU or the Pi's firmware load one. The shell's `ls`
+//! and `cat` commands look inside it.
+
+use spin::Once;
+
+use crate::{
+    BootInfo,
+    arch::serial::lock_uart,
+    cpio::{Archive, FileKind},
+    serial_print, serial_println,
+    shell::{self, Args, Command},
+    syscall::errno::Errno,
+};
+
+static INITRD: Once<&'static [u8]> = Once::new();
+
+/// Finds the initrd the firmware loaded, if any, and registers the shell commands for it.
+///
+/// Must be called after the kernel's page tables are in use, since it reads the initrd through
+/// the HHDM mapping they have for it.
+pub fn init(boot_info: &BootInfo) {
+    let Some(range) = &boot_info.initrd else {
+        log::info!("no initrd");
+        return;
+    };
+
+    let len = range.end.value() - range.start.value();
+    // reserved from the frame allocator and mapped for good, so nothing else touches it
+    let initrd =
+        unsafe { core::slice::from_raw_parts(range.start.as_hhdm_virt().as_raw_ptr(), len) };
+    log::info!("initrd: {} .. {} ({len} bytes)", range.start, range.end);
+    if Archive::new(initrd).is_err() {
+        log::warn!("initrd isn't a newc cpio archive");
+    }
+    INITRD.call_once(|| initrd);
+
+    shell::register(Command {
+        name: "ls",
+        usage: "[dir]",
+        help: "list a directory in the initrd",
+        run: cmd_ls,
+    });
+    shell::register(Command {
+        name: "cat",
+        usage: "<file>",
+        help: "print a file from the initrd",
+        run: cmd_cat,
+    });
+}
+
+/// Returns the contents of the initrd, or `None` if there isn't one.
+#[must_use]
+pub fn get() -> Option<&'static [u8]> {
+    INITRD.get().copied()
+}
+
+/// Returns the initrd as an archive.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENOENT`] if there's no initrd, or [`Errno::EINVAL`] if it isn't a cpio
+/// archive.
+pub fn archive() -> Result<Archive<'static>, Errno> {
+    Archive::new(get().ok_or(Errno::ENOENT)?)
+}
+
+fn cmd_ls(mut args: Args) -> Result<(), Errno> {
+    let path = args.next().unwrap_or("/");
+    let archive = archive()?;
+    if !path.trim_matches('/').is_empty() && archive.lookup(path)?.kind() != FileKind::Dir {
+        return Err(Errno::ENOTDIR);
+    }
+
+    for entry in archive.read_dir(path) {
+        let entry = entry?;
+        let kind = match entry.kind() {
+            FileKind::File => '-',
+            FileKind::Dir => 'd',
+            FileKind::Symlink => 'l',
+            FileKind::Other => '?',
+        };
+        serial_print!(
+            "{kind}{:04o} {:>8}  {}",
+            entry.permissions(),
+            entry.data.len(),
+            entry.name()
+        );
+        match entry.link_target() {
+            Some(target) => serial_println!(" -> {target}"),
+            None => serial_println!(),
+        }
+    }
+    Ok(())
+}
+
+fn cmd_cat(mut args: Args) -> Result<(), Errno> {
+    let path = args.next().ok_or(Errno::EINVAL)?;
+    let entry = archive()?.lookup(path)?;
+    match entry.kind() {
+        FileKind::File => {}
+        FileKind::Dir => return Err(Errno::EISDIR),
+        FileKind::Symlink | FileKind::Other => return Err(Errno::EINVAL),
+    }
+
+    let mut uart = lock_uart();
+    for &b in entry.data {
+        if b == b'\n' {
+            uart.putchar(b'\r');
+        }
+        uart.putchar(b);
+    }
+    Ok(())
+}
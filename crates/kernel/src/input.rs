@@ -0,0 +1,390 @@
+//! Keyboard input: key events from every keyboard, in one form.
+//!
+//! Keys are named by their USB HID usage IDs, whichever keyboard they come from. A keyboard
+//! driver reports presses and releases with [`key`], which works out what they type through
+//! the current [`keymap`]. Sources that already know what was typed, like the
+//! [serial console](serial), report whole events with [`report`].
+//!
+//! Every reader gets its own [`EventQueue`] from [`subscribe`], which all events go to.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use bitflags::bitflags;
+
+use crate::{
+    blank, cmdline, serial_println,
+    shell::{self, Args, Command},
+    sync::{IrqMutex, WaitQueue},
+    syscall::errno::Errno,
+};
+
+pub mod hid;
+pub mod keymap;
+pub mod serial;
+
+use self::keymap::{KEYMAPS, Keymap};
+
+/// A key, by its usage ID on the USB HID keyboard page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyCode(pub u8);
+
+#[allow(missing_docs)]
+impl KeyCode {
+    /// No key, for events from sources that can't tell which key it was.
+    pub const NONE: Self = Self(0x00);
+    pub const A: Self = Self(0x04);
+    pub const B: Self = Self(0x05);
+    pub const C: Self = Self(0x06);
+    pub const D: Self = Self(0x07);
+    pub const E: Self = Self(0x08);
+    pub const F: Self = Self(0x09);
+    pub const G: Self = Self(0x0a);
+    pub const H: Self = Self(0x0b);
+    pub const I: Self = Self(0x0c);
+    pub const J: Self = Self(0x0d);
+    pub const K: Self = Self(0x0e);
+    pub const L: Self = Self(0x0f);
+    pub const M: Self = Self(0x10);
+    pub const N: Self = Self(0x11);
+    pub const O: Self = Self(0x12);
+    pub const P: Self = Self(0x13);
+    pub const Q: Self = Self(0x14);
+    pub const R: Self = Self(0x15);
+    pub const S: Self = Self(0x16);
+    pub const T: Self = Self(0x17);
+    pub const U: Self = Self(0x18);
+    pub const V: Self = Self(0x19);
+    pub const W: Self = Self(0x1a);
+    pub const X: Self = Self(0x1b);
+    pub const Y: Self = Self(0x1c);
+    pub const Z: Self = Self(0x1d);
+    pub const DIGIT_1: Self = Self(0x1e);
+    pub const DIGIT_2: Self = Self(0x1f);
+    pub const DIGIT_3: Self = Self(0x20);
+    pub const DIGIT_4: Self = Self(0x21);
+    pub const DIGIT_5: Self = Self(0x22);
+    pub const DIGIT_6: Self = Self(0x23);
+    pub const DIGIT_7: Self = Self(0x24);
+    pub const DIGIT_8: Self = Self(0x25);
+    pub const DIGIT_9: Self = Self(0x26);
+    pub const DIGIT_0: Self = Self(0x27);
+    pub const ENTER: Self = Self(0x28);
+    pub const ESCAPE: Self = Self(0x29);
+    pub const BACKSPACE: Self = Self(0x2a);
+    pub const TAB: Self = Self(0x2b);
+    pub const SPACE: Self = Self(0x2c);
+    pub const MINUS: Self = Self(0x2d);
+    pub const EQUAL: Self = Self(0x2e);
+    pub const LEFT_BRACKET: Self = Self(0x2f);
+    pub const RIGHT_BRACKET: Self = Self(0x30);
+    pub const BACKSLASH: Self = Self(0x31);
+    /// The key right of the apostrophe on ISO keyboards.
+    pub const NON_US_HASH: Self = Self(0x32);
+    pub const SEMICOLON: Self = Self(0x33);
+    pub const APOSTROPHE: Self = Self(0x34);
+    pub const GRAVE: Self = Self(0x35);
+    pub const COMMA: Self = Self(0x36);
+    pub const PERIOD: Self = Self(0x37);
+    pub const SLASH: Self = Self(0x38);
+    pub const CAPS_LOCK: Self = Self(0x39);
+    pub const F1: Self = Self(0x3a);
+    pub const F2: Self = Self(0x3b);
+    pub const F3: Self = Self(0x3c);
+    pub const F4: Self = Self(0x3d);
+    pub const F5: Self = Self(0x3e);
+    pub const F6: Self = Self(0x3f);
+    pub const F7: Self = Self(0x40);
+    pub const F8: Self = Self(0x41);
+    pub const F9: Self = Self(0x42);
+    pub const F10: Self = Self(0x43);
+    pub const F11: Self = Self(0x44);
+    pub const F12: Self = Self(0x45);
+    pub const INSERT: Self = Self(0x49);
+    pub const HOME: Self = Self(0x4a);
+    pub const PAGE_UP: Self = Self(0x4b);
+    pub const DELETE: Self = Self(0x4c);
+    pub const END: Self = Self(0x4d);
+    pub const PAGE_DOWN: Self = Self(0x4e);
+    pub const RIGHT: Self = Self(0x4f);
+    pub const LEFT: Self = Self(0x50);
+    pub const DOWN: Self = Self(0x51);
+    pub const UP: Self = Self(0x52);
+    /// The key left of Z on ISO keyboards.
+    pub const NON_US_BACKSLASH: Self = Self(0x64);
+    pub const LEFT_CTRL: Self = Self(0xe0);
+    pub const LEFT_SHIFT: Self = Self(0xe1);
+    pub const LEFT_ALT: Self = Self(0xe2);
+    pub const LEFT_GUI: Self = Self(0xe3);
+    pub const RIGHT_CTRL: Self = Self(0xe4);
+    pub const RIGHT_SHIFT: Self = Self(0xe5);
+    /// `AltGr` on keyboards that have it.
+    pub const RIGHT_ALT: Self = Self(0xe6);
+    pub const RIGHT_GUI: Self = Self(0xe7);
+
+    /// Returns the modifier this key is, if it is one.
+    #[must_use]
+    pub fn modifier(self) -> Option<Modifiers> {
+        match self.0 {
+            0xe0..=0xe7 => Modifiers::from_bits(1 << (self.0 - 0xe0)),
+            _ => None,
+        }
+    }
+}
+
+bitflags! {
+    /// The modifier keys held, and the lock keys on, when a key event happened.
+    ///
+    /// The low byte is laid out like a USB HID keyboard report's modifier byte.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Modifiers: u16 {
+        const LEFT_CTRL = 1 << 0;
+        const LEFT_SHIFT = 1 << 1;
+        const LEFT_ALT = 1 << 2;
+        const LEFT_GUI = 1 << 3;
+        const RIGHT_CTRL = 1 << 4;
+        const RIGHT_SHIFT = 1 << 5;
+        const RIGHT_ALT = 1 << 6;
+        const RIGHT_GUI = 1 << 7;
+        const CAPS_LOCK = 1 << 8;
+    }
+}
+
+impl Modifiers {
+    /// Returns `true` if either Shift is held.
+    #[must_use]
+    pub fn shift(self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    /// Returns `true` if either Ctrl is held.
+    #[must_use]
+    pub fn ctrl(self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    /// Returns `true` if the left Alt is held. The right one is [`AltGr`](Self::altgr).
+    #[must_use]
+    pub fn alt(self) -> bool {
+        self.contains(Self::LEFT_ALT)
+    }
+
+    /// Returns `true` if `AltGr` (the right Alt) is held.
+    #[must_use]
+    pub fn altgr(self) -> bool {
+        self.contains(Self::RIGHT_ALT)
+    }
+}
+
+/// A key being pressed or released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+    /// What the key typed, if anything. Only presses type.
+    pub text: Option<char>,
+}
+
+impl KeyEvent {
+    /// Returns a press of `code` that typed `text`.
+    #[must_use]
+    pub fn press(code: KeyCode, modifiers: Modifiers, text: Option<char>) -> Self {
+        Self {
+            code,
+            pressed: true,
+            modifiers,
+            text,
+        }
+    }
+
+    /// Returns a release of `code`.
+    #[must_use]
+    pub fn release(code: KeyCode, modifiers: Modifiers) -> Self {
+        Self {
+            code,
+            pressed: false,
+            modifiers,
+            text: None,
+        }
+    }
+}
+
+/// How many events an [`EventQueue`] holds before it starts dropping the oldest.
+pub const EVENT_QUEUE_LEN: usize = 64;
+
+/// The key events for one reader, oldest first.
+///
+/// A reader that falls behind loses the oldest events, rather than holding up the keyboards.
+pub struct EventQueue {
+    events: IrqMutex<VecDeque<KeyEvent>>,
+    waiters: WaitQueue,
+}
+
+impl EventQueue {
+    /// Creates an empty queue. It only gets events once [`subscribe`]d.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            events: IrqMutex::new(VecDeque::with_capacity(EVENT_QUEUE_LEN)),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Adds `event` to the queue, dropping the oldest event if it's full.
+    pub fn push(&self, event: KeyEvent) {
+        {
+            let mut events = self.events.lock();
+            if events.len() == EVENT_QUEUE_LEN {
+                events.pop_front();
+            }
+            events.push_back(event);
+        }
+        self.waiters.wake_all();
+    }
+
+    /// Takes the oldest event, sleeping until there is one.
+    #[must_use]
+    pub fn pop(&self) -> KeyEvent {
+        let mut event = None;
+        self.waiters.wait_until(|| {
+            event = self.try_pop();
+            event.is_some()
+        });
+        event.unwrap_or_else(|| unreachable!())
+    }
+
+    /// Takes the oldest event if there is one, without sleeping.
+    #[must_use]
+    pub fn try_pop(&self) -> Option<KeyEvent> {
+        self.events.lock().pop_front()
+    }
+
+    /// Returns how many events are waiting.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.lock().len()
+    }
+
+    /// Returns `true` if no events are waiting.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The queues events go to. Each is dropped from here once its reader drops it.
+static QUEUES: IrqMutex<Vec<Weak<EventQueue>>> = IrqMutex::new(Vec::new());
+
+/// Whether Caps Lock is on, toggled by pressing it on any keyboard.
+static CAPS_LOCK: IrqMutex<bool> = IrqMutex::new(false);
+
+/// The index in [`KEYMAPS`] of the keymap keyboards type through.
+static KEYMAP: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a new queue that gets every key event from now on.
+#[must_use]
+pub fn subscribe() -> Arc<EventQueue> {
+    let queue = Arc::new(EventQueue::new());
+    let mut queues = QUEUES.lock();
+    queues.retain(|queue| queue.strong_count() != 0);
+    queues.push(Arc::downgrade(&queue));
+    queue
+}
+
+/// Sends `event` to every subscribed queue, and counts it as console activity.
+///
+/// Unblanking the console can redraw the whole screen, so this isn't for interrupt handlers.
+pub fn report(event: KeyEvent) {
+    blank::poke();
+    let queues = QUEUES
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    for queue in queues {
+        queue.push(event);
+    }
+}
+
+/// Reports `code` being pressed or released on a keyboard while `modifiers` are held,
+/// typing whatever the current keymap says it does.
+pub fn key(code: KeyCode, pressed: bool, modifiers: Modifiers) {
+    let caps_lock = {
+        let mut caps_lock = CAPS_LOCK.lock();
+        if pressed && code == KeyCode::CAPS_LOCK {
+            *caps_lock = !*caps_lock;
+        }
+        *caps_lock
+    };
+    let mut modifiers = modifiers;
+    modifiers.set(Modifiers::CAPS_LOCK, caps_lock);
+
+    let event = if pressed {
+        KeyEvent::press(code, modifiers, keymap().translate(code, modifiers))
+    } else {
+        KeyEvent::release(code, modifiers)
+    };
+    report(event);
+}
+
+/// Returns the keymap keyboards type through.
+#[must_use]
+pub fn keymap() -> &'static Keymap {
+    KEYMAPS[KEYMAP.load(Ordering::Relaxed)]
+}
+
+/// Switches keyboards to the keymap called `name`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no keymap with that name.
+pub fn set_keymap(name: &str) -> Result<(), Errno> {
+    let index = KEYMAPS
+        .iter()
+        .position(|keymap| keymap.name == name)
+        .ok_or(Errno::EINVAL)?;
+    KEYMAP.store(index, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Picks the keymap given by the `keymap` option on the [`cmdline`], and registers the
+/// `keymap` command.
+pub fn init() {
+    if let Some(name) = cmdline::get("keymap")
+        && set_keymap(name).is_err()
+    {
+        log::warn!("cmdline: unknown `keymap={name}`, using {}", keymap().name);
+    }
+    shell::register(Command {
+        name: "keymap",
+        usage: "[<name>]",
+        help: "show or change the keyboard layout",
+        run: cmd_keymap,
+    });
+}
+
+fn cmd_keymap(mut args: Args) -> Result<(), Errno> {
+    if let Some(name) = args.next() {
+        return set_keymap(name);
+    }
+    for keymap in KEYMAPS {
+        let current = if core::ptr::eq(*keymap, self::keymap()) {
+            "*"
+        } else {
+            " "
+        };
+        serial_println!("{current} {}", keymap.name);
+    }
+    Ok(())
+}
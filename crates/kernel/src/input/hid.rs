@@ -0,0 +1,73 @@
+//! USB HID keyboards in the boot protocol.
+//!
+//! A boot protocol keyboard doesn't report presses and releases, just the state of every key
+//! held: a modifier byte, a reserved byte, and up to six keys. Key events are what changed
+//! since the last report.
+
+use arrayvec::ArrayVec;
+
+use super::{KeyCode, Modifiers};
+
+/// The length of a boot protocol keyboard report.
+pub const REPORT_LEN: usize = 8;
+
+/// What a keyboard reports in every key slot when more keys are held than it can report.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// The most key events one report can make: eight modifiers, six releases and six presses.
+pub const MAX_REPORT_EVENTS: usize = 20;
+
+/// Turns a boot protocol keyboard's reports into key events.
+#[derive(Debug, Default)]
+pub struct BootKeyboard {
+    last: [u8; REPORT_LEN],
+}
+
+impl BootKeyboard {
+    /// Creates the state of a keyboard with nothing held.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the keys pressed and released since the last report, each with whether it was
+    /// pressed, and the modifiers held once `report` is taken.
+    ///
+    /// A report that says too many keys are held changes nothing, as it doesn't say which.
+    pub fn update(
+        &mut self,
+        report: &[u8; REPORT_LEN],
+    ) -> (ArrayVec<(KeyCode, bool), MAX_REPORT_EVENTS>, Modifiers) {
+        let mut events = ArrayVec::new();
+        if report[2..].contains(&ERROR_ROLL_OVER) {
+            return (events, Modifiers::from_bits_truncate(self.last[0].into()));
+        }
+
+        let changed = self.last[0] ^ report[0];
+        for bit in (0..8).filter(|bit| changed & (1 << bit) != 0) {
+            events.push((KeyCode(0xe0 + bit), report[0] & (1 << bit) != 0));
+        }
+        let held = |keys: &[u8], key: u8| keys.contains(&key);
+        for &key in self.last[2..].iter().filter(|&&key| key != 0) {
+            if !held(&report[2..], key) {
+                events.push((KeyCode(key), false));
+            }
+        }
+        for &key in report[2..].iter().filter(|&&key| key != 0) {
+            if !held(&self.last[2..], key) {
+                events.push((KeyCode(key), true));
+            }
+        }
+
+        self.last = *report;
+        (events, Modifiers::from_bits_truncate(report[0].into()))
+    }
+
+    /// Takes a report from the keyboard, reporting what changed as key events.
+    pub fn report(&mut self, report: &[u8; REPORT_LEN]) {
+        let (events, modifiers) = self.update(report);
+        for (code, pressed) in events {
+            super::key(code, pressed, modifiers);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Keyboard layouts: what each key types.
+//!
+//! A keymap only lists the keys whose characters differ from the usual: letters type
+//! themselves, and control keys like Enter and Backspace type the same in every layout.
+
+use super::{KeyCode, Modifiers};
+
+/// What a key types on its own, with Shift, and with `AltGr`.
+type Key = (KeyCode, char, char, Option<char>);
+
+/// A keyboard layout.
+#[derive(Debug)]
+pub struct Keymap {
+    /// The name the `keymap` option picks it by.
+    pub name: &'static str,
+    keys: &'static [Key],
+}
+
+/// The keymaps that can be picked, the default first.
+pub static KEYMAPS: &[&Keymap] = &[&US, &DE];
+
+/// US English.
+pub static US: Keymap = Keymap {
+    name: "us",
+    keys: &[
+        (KeyCode::DIGIT_1, '1', '!', None),
+        (KeyCode::DIGIT_2, '2', '@', None),
+        (KeyCode::DIGIT_3, '3', '#', None),
+        (KeyCode::DIGIT_4, '4', '$', None),
+        (KeyCode::DIGIT_5, '5', '%', None),
+        (KeyCode::DIGIT_6, '6', '^', None),
+        (KeyCode::DIGIT_7, '7', '&', None),
+        (KeyCode::DIGIT_8, '8', '*', None),
+        (KeyCode::DIGIT_9, '9', '(', None),
+        (KeyCode::DIGIT_0, '0', ')', None),
+        (KeyCode::MINUS, '-', '_', None),
+        (KeyCode::EQUAL, '=', '+', None),
+        (KeyCode::LEFT_BRACKET, '[', '{', None),
+        (KeyCode::RIGHT_BRACKET, ']', '}', None),
+        (KeyCode::BACKSLASH, '\\', '|', None),
+        (KeyCode::NON_US_HASH, '\\', '|', None),
+        (KeyCode::SEMICOLON, ';', ':', None),
+        (KeyCode::APOSTROPHE, '\'', '"', None),
+        (KeyCode::GRAVE, '`', '~', None),
+        (KeyCode::COMMA, ',', '<', None),
+        (KeyCode::PERIOD, '.', '>', None),
+        (KeyCode::SLASH, '/', '?', None),
+        (KeyCode::NON_US_BACKSLASH, '\\', '|', None),
+    ],
+};
+
+/// German (QWERTZ).
+pub static DE: Keymap = Keymap {
+    name: "de",
+    keys: &[
+        (KeyCode::Y, 'z', 'Z', None),
+        (KeyCode::Z, 'y', 'Y', None),
+        (KeyCode::Q, 'q', 'Q', Some('@')),
+        (KeyCode::E, 'e', 'E', Some('€')),
+        (KeyCode::M, 'm', 'M', Some('µ')),
+        (KeyCode::DIGIT_1, '1', '!', None),
+        (KeyCode::DIGIT_2, '2', '"', Some('²')),
+        (KeyCode::DIGIT_3, '3', '§', Some('³')),
+        (KeyCode::DIGIT_4, '4', '$', None),
+        (KeyCode::DIGIT_5, '5', '%', None),
+        (KeyCode::DIGIT_6, '6', '&', None),
+        (KeyCode::DIGIT_7, '7', '/', Some('{')),
+        (KeyCode::DIGIT_8, '8', '(', Some('[')),
+        (KeyCode::DIGIT_9, '9', ')', Some(']')),
+        (KeyCode::DIGIT_0, '0', '=', Some('}')),
+        (KeyCode::MINUS, 'ß', '?', Some('\\')),
+        (KeyCode::EQUAL, '´', '`', None),
+        (KeyCode::LEFT_BRACKET, 'ü', 'Ü', None),
+        (KeyCode::RIGHT_BRACKET, '+', '*', Some('~')),
+        (KeyCode::BACKSLASH, '#', '\'', None),
+        (KeyCode::NON_US_HASH, '#', '\'', None),
+        (KeyCode::SEMICOLON, 'ö', 'Ö', None),
+        (KeyCode::APOSTROPHE, 'ä', 'Ä', None),
+        (KeyCode::GRAVE, '^', '°', None),
+        (KeyCode::COMMA, ',', ';', None),
+        (KeyCode::PERIOD, '.', ':', None),
+        (KeyCode::SLASH, '-', '_', None),
+        (KeyCode::NON_US_BACKSLASH, '<', '>', Some('|')),
+    ],
+};
+
+impl Keymap {
+    /// Returns what pressing `code` with `modifiers` types, if anything.
+    #[must_use]
+    pub fn translate(&self, code: KeyCode, modifiers: Modifiers) -> Option<char> {
+        let (plain, shifted, altgr) = self.key(code)?;
+        let c = if modifiers.altgr() {
+            altgr?
+        } else if modifiers.shift()
+            != (modifiers.contains(Modifiers::CAPS_LOCK) && plain.is_alphabetic())
+        {
+            shifted
+        } else {
+            plain
+        };
+
+        if modifiers.ctrl() && c.is_ascii_alphabetic() {
+            // Ctrl-A is 0x01, and so on
+            Some(char::from(c.to_ascii_uppercase() as u8 - b'@'))
+        } else {
+            Some(c)
+        }
+    }
+
+    /// Returns the key that types `c`, and the modifiers it needs, if there's one.
+    #[must_use]
+    pub fn key_for(&self, c: char) -> Option<(KeyCode, Modifiers)> {
+        (0..=u8::MAX).map(KeyCode).find_map(|code| {
+            let (plain, shifted, altgr) = self.key(code)?;
+            if plain == c {
+                Some((code, Modifiers::empty()))
+            } else if shifted == c {
+                Some((code, Modifiers::LEFT_SHIFT))
+            } else if altgr == Some(c) {
+                Some((code, Modifiers::RIGHT_ALT))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns what `code` types on its own, with Shift, and with `AltGr`.
+    fn key(&self, code: KeyCode) -> Option<(char, char, Option<char>)> {
+        if let Some(&(_, plain, shifted, altgr)) = self.keys.iter().find(|key| key.0 == code) {
+            return Some((plain, shifted, altgr));
+        }
+        let same = |c| Some((c, c, None));
+        match code {
+            _ if (KeyCode::A..=KeyCode::Z).contains(&code) => {
+                let c = char::from(b'a' + (code.0 - KeyCode::A.0));
+                Some((c, c.to_ascii_uppercase(), None))
+            }
+            KeyCode::ENTER => same('\n'),
+            KeyCode::ESCAPE => same('\x1b'),
+            KeyCode::BACKSPACE => same('\x7f'),
+            KeyCode::TAB => same('\t'),
+            KeyCode::SPACE => same(' '),
+            _ => None,
+        }
+    }
+}
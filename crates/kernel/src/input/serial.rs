@@ -0,0 +1,196 @@
+//! Key events from a terminal on the serial console.
+//!
+//! A terminal sends what was typed, not which keys were pressed, and keys that don't type
+//! anything as escape sequences. Each character becomes a press and release of the key that
+//! types it in the current keymap, or of [`KeyCode::NONE`] if there isn't one.
+
+use arrayvec::ArrayVec;
+
+use crate::sync::IrqMutex;
+
+use super::{KeyCode, KeyEvent, Modifiers};
+
+/// The most parameters kept from a control sequence. Any more are ignored.
+const MAX_PARAMS: usize = 2;
+
+/// Where a [`Decoder`] is in an escape sequence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    /// After an ESC.
+    Escape,
+    /// In a control sequence, `ESC [`, with the parameters so far.
+    Csi {
+        params: [u16; MAX_PARAMS],
+        count: usize,
+    },
+    /// After `ESC O`, which some terminals send before F1 to F4.
+    Ss3,
+}
+
+/// Turns the bytes a terminal sends into key events.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    state: State,
+}
+
+impl Decoder {
+    /// Creates a decoder that isn't partway through a sequence.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+        }
+    }
+
+    /// Takes the next byte from the terminal, returning the press and release of the key it
+    /// finished, if any.
+    ///
+    /// An ESC on its own is held until the next byte, which says whether it started a
+    /// sequence or was Alt held with that byte.
+    pub fn feed(&mut self, byte: u8) -> ArrayVec<KeyEvent, 2> {
+        match (self.state, byte) {
+            (State::Ground, 0x1b) => {
+                self.state = State::Escape;
+                ArrayVec::new()
+            }
+            (State::Ground, _) => Self::text(byte, Modifiers::empty()),
+            (State::Escape, b'[') => {
+                self.state = State::Csi {
+                    params: [0; MAX_PARAMS],
+                    count: 0,
+                };
+                ArrayVec::new()
+            }
+            (State::Escape, b'O') => {
+                self.state = State::Ss3;
+                ArrayVec::new()
+            }
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                Self::text(byte, Modifiers::LEFT_ALT)
+            }
+            (State::Csi { mut params, count }, b'0'..=b'9') => {
+                let count = count.max(1);
+                if let Some(param) = params.get_mut(count - 1) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(u16::from(byte - b'0'));
+                }
+                self.state = State::Csi { params, count };
+                ArrayVec::new()
+            }
+            (State::Csi { params, count }, b';') => {
+                self.state = State::Csi {
+                    params,
+                    count: count.max(1) + 1,
+                };
+                ArrayVec::new()
+            }
+            (State::Csi { params, .. }, _) => {
+                self.state = State::Ground;
+                let modifiers = csi_modifiers(params[1]);
+                let code = match byte {
+                    b'A' => KeyCode::UP,
+                    b'B' => KeyCode::DOWN,
+                    b'C' => KeyCode::RIGHT,
+                    b'D' => KeyCode::LEFT,
+                    b'H' => KeyCode::HOME,
+                    b'F' => KeyCode::END,
+                    b'P'..=b'S' => KeyCode(KeyCode::F1.0 + (byte - b'P')),
+                    b'~' => match tilde_key(params[0]) {
+                        Some(code) => code,
+                        None => return ArrayVec::new(),
+                    },
+                    _ => return ArrayVec::new(),
+                };
+                Self::key(code, modifiers, None)
+            }
+            (State::Ss3, b'P'..=b'S') => {
+                self.state = State::Ground;
+                Self::key(
+                    KeyCode(KeyCode::F1.0 + (byte - b'P')),
+                    Modifiers::empty(),
+                    None,
+                )
+            }
+            (State::Ss3, _) => {
+                self.state = State::Ground;
+                ArrayVec::new()
+            }
+        }
+    }
+
+    /// Returns the press and release of the key that types `byte`.
+    fn text(byte: u8, mut modifiers: Modifiers) -> ArrayVec<KeyEvent, 2> {
+        let (code, c) = match byte {
+            b'\r' | b'\n' => (KeyCode::ENTER, '\n'),
+            0x08 | 0x7f => (KeyCode::BACKSPACE, '\x7f'),
+            b'\t' => (KeyCode::TAB, '\t'),
+            0x1b => (KeyCode::ESCAPE, '\x1b'),
+            // Ctrl-A to Ctrl-Z
+            0x01..=0x1a => {
+                modifiers |= Modifiers::LEFT_CTRL;
+                (KeyCode(KeyCode::A.0 + (byte - 1)), char::from(byte))
+            }
+            b' '..=b'~' => {
+                let c = char::from(byte);
+                match super::keymap().key_for(c) {
+                    Some((code, needs)) => {
+                        modifiers |= needs;
+                        (code, c)
+                    }
+                    None => (KeyCode::NONE, c),
+                }
+            }
+            _ => return ArrayVec::new(),
+        };
+        Self::key(code, modifiers, Some(c))
+    }
+
+    fn key(code: KeyCode, modifiers: Modifiers, text: Option<char>) -> ArrayVec<KeyEvent, 2> {
+        ArrayVec::from([
+            KeyEvent::press(code, modifiers, text),
+            KeyEvent::release(code, modifiers),
+        ])
+    }
+}
+
+/// Returns the key a `ESC [ <n> ~` sequence is for.
+fn tilde_key(n: u16) -> Option<KeyCode> {
+    let code = match n {
+        1 | 7 => KeyCode::HOME,
+        2 => KeyCode::INSERT,
+        3 => KeyCode::DELETE,
+        4 | 8 => KeyCode::END,
+        5 => KeyCode::PAGE_UP,
+        6 => KeyCode::PAGE_DOWN,
+        11..=15 => KeyCode(KeyCode::F1.0 + (n - 11) as u8),
+        17..=21 => KeyCode(KeyCode::F6.0 + (n - 17) as u8),
+        23 | 24 => KeyCode(KeyCode::F11.0 + (n - 23) as u8),
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Returns the modifiers given by a control sequence's modifier parameter, which is one more
+/// than a mask of Shift (1), Alt (2) and Ctrl (4).
+fn csi_modifiers(param: u16) -> Modifiers {
+    let mask = param.saturating_sub(1);
+    let mut modifiers = Modifiers::empty();
+    modifiers.set(Modifiers::LEFT_SHIFT, mask & 1 != 0);
+    modifiers.set(Modifiers::LEFT_ALT, mask & 2 != 0);
+    modifiers.set(Modifiers::LEFT_CTRL, mask & 4 != 0);
+    modifiers
+}
+
+static DECODER: IrqMutex<Decoder> = IrqMutex::new(Decoder::new());
+
+/// Takes the next byte from the serial console, reporting any key it finishes.
+pub fn feed(byte: u8) {
+    let events = DECODER.lock().feed(byte);
+    for event in events {
+        super::report(event);
+    }
+}
@@ -0,0 +1,13 @@
+//! Communication between tasks.
+//!
+//! [Pipes](pipe) carry bytes between file descriptors, for userspace. [Channels](channel)
+//! carry typed messages between kernel tasks. Both block a reader until there's something
+//! to read, and a writer until there's room. [Shared memory objects](shm) are memory that
+//! several tasks map at once.
+
+pub mod channel;
+pub mod pipe;
+pub mod shm;
+
+pub use channel::{Receiver, Sender, channel};
+pub use pipe::{PipeReader, PipeWriter, pipe};
@@ -0,0 +1,153 @@
+//! Channels: a bounded queue of typed messages between kernel tasks.
+//!
+//! Any number of [`Sender`]s feed one [`Receiver`]. Receiving from an empty channel sleeps
+//! until a message is sent, and sending to a full one sleeps until a message is received.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+
+use crate::{
+    sync::{Condvar, Mutex},
+    syscall::errno::Errno,
+};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_open: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified when a message is sent or the last sender is dropped.
+    not_empty: Condvar,
+    /// Notified when a message is received or the receiver is dropped.
+    not_full: Condvar,
+}
+
+/// Creates a channel that holds up to `capacity` messages, returning its two ends.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+#[must_use]
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel must hold at least one message");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiver_open: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// The sending end of a channel. Clone it for more senders.
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T: Send> Sender<T> {
+    /// Sends `msg`, sleeping until there's room for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EPIPE`] if the receiver has been dropped, dropping `msg`.
+    pub fn send(&self, msg: T) -> Result<(), Errno> {
+        let shared = &self.0;
+        let mut state = shared.not_full.wait_while(shared.state.lock(), |state| {
+            state.queue.len() == state.capacity && state.receiver_open
+        });
+        if !state.receiver_open {
+            return Err(Errno::EPIPE);
+        }
+        state.queue.push_back(msg);
+        drop(state);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Sends `msg` if there's room for it right away.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EAGAIN`] if the channel is full, or [`Errno::EPIPE`] if the receiver
+    /// has been dropped. Either way, `msg` is dropped.
+    pub fn try_send(&self, msg: T) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        if !state.receiver_open {
+            return Err(Errno::EPIPE);
+        }
+        if state.queue.len() == state.capacity {
+            return Err(Errno::EAGAIN);
+        }
+        state.queue.push_back(msg);
+        drop(state);
+        self.0.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.state.lock().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.senders -= 1;
+        let last = state.senders == 0;
+        drop(state);
+        if last {
+            self.0.not_empty.notify_all();
+        }
+    }
+}
+
+/// The receiving end of a channel.
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T: Send> Receiver<T> {
+    /// Receives the oldest message, sleeping until there is one. Returns `None` once the
+    /// channel is empty and every sender has been dropped.
+    #[must_use]
+    pub fn recv(&self) -> Option<T> {
+        let shared = &self.0;
+        let mut state = shared.not_empty.wait_while(shared.state.lock(), |state| {
+            state.queue.is_empty() && state.senders != 0
+        });
+        let msg = state.queue.pop_front();
+        drop(state);
+        if msg.is_some() {
+            shared.not_full.notify_one();
+        }
+        msg
+    }
+
+    /// Receives the oldest message if there is one, without sleeping.
+    #[must_use]
+    pub fn try_recv(&self) -> Option<T> {
+        let msg = self.0.state.lock().queue.pop_front();
+        if msg.is_some() {
+            self.0.not_full.notify_one();
+        }
+        msg
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.receiver_open = false;
+        // nobody can receive what's left, so it goes now rather than with the last sender
+        let queue = core::mem::take(&mut state.queue);
+        drop(state);
+        self.0.not_full.notify_all();
+        drop(queue);
+    }
+}
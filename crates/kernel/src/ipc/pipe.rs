@@ -0,0 +1,120 @@
+//! Pipes: a one-way stream of bytes through a buffer in the kernel.
+//!
+//! Reading an empty pipe blocks until something is written, or sees end of file once the
+//! write end is closed. Writing a full pipe blocks until something is read, and fails with
+//! [`Errno::EPIPE`] once the read end is closed.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+
+use crate::{
+    file::File,
+    sync::{Condvar, Mutex},
+    syscall::errno::Errno,
+};
+
+/// How many bytes a pipe holds before writers have to wait.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buf: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    state: Mutex<PipeState>,
+    /// Notified when bytes are written or the write end is closed.
+    readable: Condvar,
+    /// Notified when bytes are read or the read end is closed.
+    writable: Condvar,
+}
+
+/// Creates a pipe, returning its read and write ends.
+#[must_use]
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buf: VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+/// The read end of a pipe, closed when dropped.
+pub struct PipeReader(Arc<Pipe>);
+
+impl File for PipeReader {
+    /// Reads whatever is in the pipe, up to `buf.len()` bytes, sleeping until there's
+    /// something to read. Returns 0 once the pipe is empty and the write end is closed.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut state = pipe.readable.wait_while(pipe.state.lock(), |state| {
+            state.buf.is_empty() && state.writer_open
+        });
+        let len = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..len)) {
+            *dst = src;
+        }
+        drop(state);
+        if len != 0 {
+            pipe.writable.notify_all();
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.state.lock().reader_open = false;
+        self.0.writable.notify_all();
+    }
+}
+
+/// The write end of a pipe, closed when dropped.
+pub struct PipeWriter(Arc<Pipe>);
+
+impl File for PipeWriter {
+    /// Writes all of `buf`, sleeping whenever the pipe is full.
+    ///
+    /// If the read end is closed partway through, returns how much was written before then.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EPIPE`] if the read end was closed before anything was written.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let mut state = pipe.writable.wait_while(pipe.state.lock(), |state| {
+                state.buf.len() == PIPE_CAPACITY && state.reader_open
+            });
+            if !state.reader_open {
+                return if written == 0 {
+                    Err(Errno::EPIPE)
+                } else {
+                    Ok(written)
+                };
+            }
+            let len = (PIPE_CAPACITY - state.buf.len()).min(buf.len() - written);
+            state.buf.extend(&buf[written..written + len]);
+            drop(state);
+            written += len;
+            pipe.readable.notify_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.state.lock().writer_open = false;
+        self.0.readable.notify_all();
+    }
+}
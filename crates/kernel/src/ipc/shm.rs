@@ -0,0 +1,173 @@
+//! Shared memory objects: zeroed memory that any number of tasks can map at once.
+//!
+//! An object is physically contiguous and named by an id, which tasks pass to each other to
+//! map it. Destroying an object only takes its id away: its memory is freed once the last
+//! page mapping it is unmapped, so nobody is left with a mapping of freed frames.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::collections::btree_map::BTreeMap;
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::{
+        paging::allocator::KernelFrameAllocator,
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
+    sync::IrqMutex,
+    syscall::errno::Errno,
+    task::addr_space::{AddrSpace, Backing, Protection},
+};
+
+/// The largest shared memory object that can be created.
+pub const MAX_SHM_SIZE: usize = 64 * 1024 * 1024;
+
+struct ShmObject {
+    id: usize,
+    pages: usize,
+    /// How many pages of the object are mapped, counting each mapping separately.
+    mapped: usize,
+    /// Whether the object's id has been taken away.
+    destroyed: bool,
+}
+
+impl ShmObject {
+    fn size(&self) -> usize {
+        self.pages * Arch::PAGE_SIZE
+    }
+}
+
+/// Every shared memory object whose memory hasn't been freed, by the address of its first
+/// frame. Taken when address spaces are dropped, which can happen partway through a switch.
+static OBJECTS: IrqMutex<BTreeMap<PhysAddr, ShmObject>> = IrqMutex::new(BTreeMap::new());
+
+/// Creates a zeroed shared memory object of `size` bytes (rounded up to whole pages),
+/// returning its id.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if `size` is 0 or more than [`MAX_SHM_SIZE`], or
+/// [`Errno::ENOMEM`] if there's no memory for it.
+pub fn create(size: usize) -> Result<usize, Errno> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+    if size == 0 || size > MAX_SHM_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    let pages = FrameCount::from_bytes(size);
+    let phys = unsafe { KernelFrameAllocator.allocate(pages) }.map_err(|_| Errno::ENOMEM)?;
+    let object = ShmObject {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        pages: pages.frame_count(),
+        mapped: 0,
+        destroyed: false,
+    };
+    if unsafe { phys.as_hhdm_virt().fill(0, object.size()) }.is_err() {
+        KernelFrameAllocator.free(phys, pages).ok();
+        return Err(Errno::EFAULT);
+    }
+    let id = object.id;
+    OBJECTS.lock().insert(phys, object);
+    Ok(id)
+}
+
+/// Takes away the id of shared memory object `id`. Its memory goes once nothing maps it.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no object with that id.
+pub fn destroy(id: usize) -> Result<(), Errno> {
+    let mut objects = OBJECTS.lock();
+    let (&phys, object) = objects
+        .iter_mut()
+        .find(|(_, object)| object.id == id && !object.destroyed)
+        .ok_or(Errno::EINVAL)?;
+    object.destroyed = true;
+    free_if_unused(&mut objects, phys);
+    Ok(())
+}
+
+/// Returns the size in bytes of shared memory object `id`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no object with that id.
+pub fn size(id: usize) -> Result<usize, Errno> {
+    OBJECTS
+        .lock()
+        .values()
+        .find(|object| object.id == id && !object.destroyed)
+        .map(ShmObject::size)
+        .ok_or(Errno::EINVAL)
+}
+
+/// Maps all of shared memory object `id` into `addr_space`, returning where it went.
+/// `addr` and `fixed` are as for [`AddrSpace::map`].
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no object with that id, or any error from
+/// [`AddrSpace::map`].
+pub fn map(
+    addr_space: &mut AddrSpace,
+    id: usize,
+    addr: VirtAddr,
+    fixed: bool,
+    prot: Protection,
+) -> Result<VirtAddr, Errno> {
+    let (phys, size) = {
+        let mut objects = OBJECTS.lock();
+        let (&phys, object) = objects
+            .iter_mut()
+            .find(|(_, object)| object.id == id && !object.destroyed)
+            .ok_or(Errno::EINVAL)?;
+        // held while it's mapped, so destroying it meanwhile can't free it from under us
+        object.mapped += 1;
+        (phys, object.size())
+    };
+    let result = addr_space.map(addr, size, fixed, prot, Backing::Shared(phys));
+    page_unmapped(phys);
+    result
+}
+
+/// Notes that a page of a shared memory object has been mapped.
+pub(crate) fn page_mapped(frame: PhysAddr) {
+    let mut objects = OBJECTS.lock();
+    if let Some((_, object)) = containing(&mut objects, frame) {
+        object.mapped += 1;
+    }
+}
+
+/// Notes that a page of a shared memory object has been unmapped, freeing the object if it
+/// was the last one of a destroyed object.
+pub(crate) fn page_unmapped(frame: PhysAddr) {
+    let mut objects = OBJECTS.lock();
+    if let Some((phys, object)) = containing(&mut objects, frame) {
+        object.mapped -= 1;
+        free_if_unused(&mut objects, phys);
+    }
+}
+
+/// Returns the object `frame` belongs to, and the address of its first frame.
+fn containing(
+    objects: &mut BTreeMap<PhysAddr, ShmObject>,
+    frame: PhysAddr,
+) -> Option<(PhysAddr, &mut ShmObject)> {
+    objects
+        .range_mut(..=frame)
+        .next_back()
+        .filter(|(phys, object)| frame < phys.add_bytes(object.size()))
+        .map(|(&phys, object)| (phys, object))
+}
+
+fn free_if_unused(objects: &mut BTreeMap<PhysAddr, ShmObject>, phys: PhysAddr) {
+    if objects
+        .get(&phys)
+        .is_some_and(|object| object.destroyed && object.mapped == 0)
+        && let Some(object) = objects.remove(&phys)
+    {
+        KernelFrameAllocator
+            .free(phys, FrameCount::new(object.pages))
+            .ok();
+    }
+}
@@ -1,19 +1,104 @@
-use core::fmt::Display;
+use core::{
+    fmt::Display,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use fdt::{Fdt, node::FdtNode, standard_nodes::Compatible};
 use spin::Once;
 
 use crate::{
     arch::{Arch, Architecture},
     fdt::Phandle,
+    panicking,
     sync::{IrqMutex, IrqMutexGuard},
+    syscall::errno::Errno,
+    task::switch::switch_if_requested,
+    time,
+    trace::{trace_irq_entry, trace_irq_exit},
     util::DebugCheckedPanic,
 };
 
+pub mod thread;
+
+pub use thread::register_threaded_irq;
+
 /// A static reference to the IRQ chip.
 pub static IRQ_CHIP: Once<IrqMutex<IrqChipDescriptor>> = Once::new();
 
+/// Set when no interrupt controller was found and devices have to be polled instead.
+static POLLED: AtomicBool = AtomicBool::new(false);
+
+/// How many interrupt handlers are running, counting nested ones.
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// The priority of the timer's IRQ. Lower values are more urgent, so it's above every device,
+/// and a priority mask can hold off device IRQs while still letting the timer through.
+pub const PRIORITY_TIMER: u8 = 0x80;
+/// The priority IRQs are given unless [`set_priority`] changes it.
+pub const PRIORITY_DEFAULT: u8 = 0xa0;
+/// A priority mask that lets every IRQ through.
+pub const PRIORITY_MASK_NONE: u8 = 0xf0;
+
+/// How many CPUs interrupt statistics are kept for. Any others are counted as the last one.
+pub const MAX_CPUS: usize = 4;
+
+static CPU_STATS: [CpuIrqStats; MAX_CPUS] = [const { CpuIrqStats::new() }; MAX_CPUS];
+
+/// Interrupt statistics for one CPU.
+pub struct CpuIrqStats {
+    /// Interrupts acked on the CPU.
+    pub acks: AtomicU64,
+    /// Acks that found no interrupt pending, because it went away or was taken by another CPU
+    /// before it could be acked.
+    pub spurious: AtomicU64,
+}
+
+impl CpuIrqStats {
+    const fn new() -> Self {
+        Self {
+            acks: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the statistics for the current CPU.
+    #[must_use]
+    pub fn current() -> &'static Self {
+        &CPU_STATS[current_cpu()]
+    }
+}
+
+/// Returns the statistics of each CPU, indexed by CPU number.
+#[must_use]
+pub fn cpu_stats() -> &'static [CpuIrqStats] {
+    &CPU_STATS
+}
+
+/// Returns the statistics of every IRQ that's been delivered at least once, with a handler or
+/// not.
+///
+/// # Panics
+///
+/// Panics if the IRQ chip has not been initialized yet.
+#[must_use]
+pub fn irq_stats() -> Vec<(Irq, IrqStats)> {
+    with_irq_chip(|chip| {
+        chip.descs
+            .iter()
+            .enumerate()
+            .filter(|(_, desc)| desc.stats.total() > 0)
+            .map(|(index, desc)| (Irq(index as u32), desc.stats))
+            .collect()
+    })
+}
+
+fn current_cpu() -> usize {
+    Arch::cpu_id().min(MAX_CPUS - 1)
+}
+
 /// Initializes the IRQ chip with the given flattened device tree (FDT).
 pub fn init(fdt: &Fdt) {
     #[allow(static_mut_refs)]
@@ -24,10 +109,7 @@ pub fn init(fdt: &Fdt) {
 ///
 /// Note that this will disable interrupts while the guard is held.
 /// The guard must not be held across a context switch or return
-/// from an interrupt handler.
-/// If you need to hold the guard across a context switch,
-/// you can (unsafely) call `force_unlock()` on the guard
-/// as the very last thing before returning.
+/// from an interrupt handler; [`with_irq_chip`] makes that impossible to get wrong.
 ///
 /// # Panics
 ///
@@ -37,38 +119,183 @@ pub fn irq_chip<'a>() -> IrqMutexGuard<'a, IrqChipDescriptor> {
     IRQ_CHIP.get().expect("IRQ chip not initialized").lock()
 }
 
+/// Runs `f` with the IRQ chip descriptor locked and interrupts disabled.
+///
+/// # Panics
+///
+/// Panics if the IRQ chip has not been initialized yet.
+pub fn with_irq_chip<R>(f: impl FnOnce(&mut IrqChipDescriptor) -> R) -> R {
+    IRQ_CHIP
+        .get()
+        .expect("IRQ chip not initialized")
+        .lock_irqsave(f)
+}
+
 /// Registers an IRQ handler for the given IRQ.
 pub unsafe fn register_irq(irq: Irq, handler: impl IrqHandler) {
     if irq.as_usize() >= 1024 {
         log::error!("irq {} >= 1024", irq);
     }
 
-    let mut irq_chip = irq_chip();
-    if irq_chip.descs[irq.as_usize()].handler.is_some() {
+    let registered = with_irq_chip(|irq_chip| {
+        let desc = &irq_chip.descs[irq.as_usize()];
+        if desc.handler.is_some() || desc.thread.is_some() {
+            return false;
+        }
+
+        irq_chip.descs[irq.as_usize()].handler = Some(Box::new(handler));
+        irq_chip.enable_irq(irq);
+        irq_chip.descs[irq.as_usize()]
+            .handler
+            .as_mut()
+            .debug_checked_unwrap() // should never fail here
+            .post_register_hook(irq);
+        true
+    });
+
+    if registered {
+        log::debug!("Registered IRQ handler for {}", irq);
+    } else {
         log::error!("irq {} already registered", irq);
-        return;
     }
-
-    irq_chip.descs[irq.as_usize()].handler = Some(Box::new(handler));
-    irq_chip.enable_irq(irq);
-    irq_chip.descs[irq.as_usize()]
-        .handler
-        .as_mut()
-        .debug_checked_unwrap() // should never fail here
-        .post_register_hook(irq);
-
-    log::debug!("Registered IRQ handler for {}", irq);
 }
 
 /// Enables the given IRQ.
 pub fn enable_irq(irq: Irq) {
-    irq_chip().enable_irq(irq);
+    with_irq_chip(|chip| chip.enable_irq(irq));
+}
+
+/// Sets the priority of the given IRQ, one of the `PRIORITY_*` constants or something between
+/// them. Does nothing on chips without priorities.
+pub fn set_priority(irq: Irq, priority: u8) {
+    with_irq_chip(|chip| chip.set_priority(irq, priority));
+}
+
+/// Returns the IRQ that [`send_ipi`] raises, or `None` if the interrupt controller can't
+/// interrupt other CPUs.
+#[must_use]
+pub fn ipi() -> Option<Irq> {
+    IRQ_CHIP.get()?.lock_irqsave(|chip| chip.ipi())
+}
+
+/// Interrupts the given CPU with the [`ipi`] IRQ.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the interrupt controller can't interrupt other CPUs.
+pub fn send_ipi(cpu: usize) -> Result<(), Errno> {
+    if ipi().is_none() {
+        return Err(Errno::ENODEV);
+    }
+    with_irq_chip(|chip| chip.send_ipi(cpu));
+    Ok(())
+}
+
+/// Interrupts the given CPU with the [`ipi`] IRQ, unless the interrupt controller is locked.
+/// For after a panic, when whoever holds it may never let go.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENODEV`] if the interrupt controller can't interrupt other CPUs, or
+/// [`Errno::EBUSY`] if it's locked.
+pub fn try_send_ipi(cpu: usize) -> Result<(), Errno> {
+    let chip = IRQ_CHIP.get().ok_or(Errno::ENODEV)?;
+    let mut chip = chip.try_lock().map_err(|_| Errno::EBUSY)?;
+    if chip.ipi().is_none() {
+        return Err(Errno::ENODEV);
+    }
+    chip.send_ipi(cpu);
+    Ok(())
+}
+
+/// Sets the calling CPU's priority mask, so only IRQs more urgent than `mask` are taken.
+/// [`PRIORITY_MASK_NONE`] lets them all through again.
+pub fn set_priority_mask(mask: u8) {
+    with_irq_chip(|chip| chip.set_priority_mask(mask));
+}
+
+/// Acknowledges and handles the pending interrupt. Called from the IRQ exception vectors.
+///
+/// The chip is unlocked and the interrupt has ended by the time a handler's requested
+/// context switch happens, so the next task can take interrupts right away.
+pub fn dispatch() {
+    if panicking::is_panicking() {
+        // another CPU panicked, and this is its IPI or this CPU's next tick
+        panicking::halt_this_cpu();
+    }
+    let irq = {
+        let _irq_context = enter_irq_context();
+        with_irq_chip(|chip| {
+            let irq = chip.ack();
+            let cpu = CpuIrqStats::current();
+            cpu.acks.fetch_add(1, Ordering::Relaxed);
+            if chip.is_spurious(irq) {
+                // the chip has nothing to end, either
+                cpu.spurious.fetch_add(1, Ordering::Relaxed);
+                return irq;
+            }
+            trace_irq_entry(irq);
+            chip.handle_irq(irq);
+            chip.eoi(irq);
+            trace_irq_exit(irq);
+            irq
+        })
+    };
+    log::trace!("IRQ {irq} handled");
+
+    switch_if_requested();
+}
+
+/// Returns `true` while an interrupt handler is running.
+#[must_use]
+pub fn in_irq_context() -> bool {
+    IRQ_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Marks an interrupt handler as running until the returned guard is dropped.
+#[must_use = "the handler is only considered running while the guard is alive"]
+pub fn enter_irq_context() -> IrqContextGuard {
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+    IrqContextGuard { _private: () }
+}
+
+/// A guard marking an interrupt handler as running, from [`enter_irq_context`].
+pub struct IrqContextGuard {
+    _private: (),
+}
+
+impl Drop for IrqContextGuard {
+    fn drop(&mut self) {
+        IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if there is no interrupt controller, so devices must be polled with [`poll`].
+pub fn is_polled() -> bool {
+    POLLED.load(Ordering::Relaxed)
+}
+
+/// Runs the handlers of any devices with a pending interrupt, if in polled mode.
+///
+/// This is called from the idle loop (and anything else that busy-waits) when there is no
+/// interrupt controller to deliver interrupts.
+pub fn poll() {
+    if is_polled() {
+        {
+            let _irq_context = enter_irq_context();
+            with_irq_chip(IrqChipDescriptor::poll);
+        }
+        switch_if_requested();
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Irq(u32);
 
 impl Irq {
+    /// What [`IrqChip::ack`] returns when no interrupt is pending.
+    pub const SPURIOUS: Self = Self(1023);
+
     /// Creates a new IRQ from the given number.
     #[must_use]
     pub const fn from(value: u32) -> Self {
@@ -114,6 +341,15 @@ pub trait IrqHandler: Send + Sync + 'static {
 
     /// Handles the IRQ when it is triggered.
     fn handle_irq(&mut self, irq: Irq);
+
+    /// Returns `true` if the device has an interrupt pending, without handling it.
+    ///
+    /// Only used in polled mode (see [`is_polled`]). Can be left unimplemented for devices
+    /// that can't be polled, in which case they are never serviced without an IRQ chip.
+    #[allow(unused)]
+    fn is_pending(&self, irq: Irq) -> bool {
+        false
+    }
 }
 
 /// Represents an IRQ chip that can handle interrupts.
@@ -124,6 +360,9 @@ pub trait IrqChip: IrqHandler {
     fn init(&mut self, fdt: &Fdt, descs: &mut [IrqHandlerDescriptor]);
 
     /// Acknowledges the IRQ and returns the IRQ number.
+    ///
+    /// Returns [`Irq::SPURIOUS`], or any other number the chip doesn't have, if no interrupt
+    /// is pending. Chips cascaded from another are acked until they do.
     fn ack(&mut self) -> Irq;
 
     /// Sends an end-of-interrupt (EOI) signal for the given IRQ.
@@ -138,10 +377,31 @@ pub trait IrqChip: IrqHandler {
     /// Disables the given IRQ.
     fn disable_irq(&mut self, irq: Irq);
 
+    /// Sets the priority of the given IRQ, where lower values are more urgent.
+    /// Can be left unimplemented by chips without priorities.
+    #[allow(unused)]
+    fn set_priority(&mut self, irq: Irq, priority: u8) {}
+
+    /// Sets the calling CPU's priority mask, below which IRQs are signalled.
+    /// Can be left unimplemented by chips without priorities.
+    #[allow(unused)]
+    fn set_priority_mask(&mut self, mask: u8) {}
+
     /// Manually triggers the given IRQ.
     /// This is typically used for software-generated interrupts (SGIs).
     fn manual_irq(&mut self, irq: Irq);
 
+    /// Returns the IRQ [`send_ipi`](Self::send_ipi) raises, or `None` if the chip can't
+    /// interrupt other CPUs.
+    fn ipi(&self) -> Option<Irq> {
+        None
+    }
+
+    /// Raises the chip's [IPI](Self::ipi) on the given CPU.
+    /// Can be left unimplemented by chips without one.
+    #[allow(unused)]
+    fn send_ipi(&mut self, cpu: usize) {}
+
     /// Checks if the given IRQ is pending.
     fn is_irq_pending(&self, irq: Irq) -> bool;
 }
@@ -174,6 +434,74 @@ impl IrqChip for Null {
     }
 }
 
+/// How often an IRQ has been delivered, and how long its handler took.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IrqStats {
+    /// How many times the IRQ was delivered on each CPU.
+    pub count: [u64; MAX_CPUS],
+    /// How many of those found nothing to do: no handler, or a cascaded chip with nothing
+    /// pending. A count that keeps climbing means a device is asserting an interrupt nobody
+    /// clears.
+    pub spurious: u64,
+    /// The total time spent in the handler.
+    pub total_time: Duration,
+    /// The longest the handler took.
+    pub max_time: Duration,
+}
+
+impl IrqStats {
+    const INIT: Self = Self {
+        count: [0; MAX_CPUS],
+        spurious: 0,
+        total_time: Duration::ZERO,
+        max_time: Duration::ZERO,
+    };
+
+    /// Returns how many times the IRQ was delivered, on any CPU.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.count.iter().sum()
+    }
+
+    /// Returns the average time its handler took, or zero if it never ran.
+    #[must_use]
+    pub fn average_time(&self) -> Duration {
+        let handled = self.total() - self.spurious;
+        if handled == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total_time.as_nanos() / u128::from(handled)) as u64)
+    }
+
+    /// Counts a delivery whose handler started running at uptime `start`.
+    fn record(&mut self, start: Duration) {
+        self.record_delivery();
+        self.record_time(start);
+    }
+
+    fn record_delivery(&mut self) {
+        self.count[current_cpu()] += 1;
+    }
+
+    /// Adds the time taken by a handler that started running at uptime `start`.
+    fn record_time(&mut self, start: Duration) {
+        let time = time::uptime().saturating_sub(start);
+        self.total_time += time;
+        self.max_time = self.max_time.max(time);
+    }
+
+    fn record_spurious(&mut self, irq: Irq) {
+        self.count[current_cpu()] += 1;
+        self.spurious += 1;
+        if self.spurious.is_power_of_two() && self.spurious >= 1024 {
+            log::warn!(
+                "irq {irq} has been spurious {} times; is something not clearing it?",
+                self.spurious
+            );
+        }
+    }
+}
+
 /// A descriptor for an IRQ handler.
 ///
 /// This structure contains information about the IRQ handler,
@@ -189,8 +517,14 @@ pub struct IrqHandlerDescriptor {
     /// The IRQ handler itself.
     pub handler: Option<Box<dyn IrqHandler>>,
 
+    /// The task running the handler, for a threaded IRQ.
+    pub thread: Option<Arc<thread::IrqThread>>,
+
     /// Indicates whether this handler is currently in use.
     pub used: bool,
+
+    /// How often the IRQ has been delivered.
+    pub stats: IrqStats,
 }
 
 impl IrqHandlerDescriptor {
@@ -199,21 +533,49 @@ impl IrqHandlerDescriptor {
         index: 0,
         chip_irq: Irq(0),
         handler: None,
+        thread: None,
         used: false,
+        stats: IrqStats::INIT,
     };
 }
 
-/// A descriptor for an IRQ chip.
+/// An interrupt controller and the block of global IRQ numbers its interrupts are given.
 ///
-/// This structure contains the IRQ chip's phandle,
-/// the IRQ chip itself, and an array of IRQ handler descriptors.
-pub struct IrqChipDescriptor {
+/// Chips number their interrupts from zero; the domain offsets them into the global space
+/// that handlers are registered in.
+pub struct IrqDomain {
     /// The phandle of the IRQ chip in the device tree.
     pub phandle: Phandle,
 
     /// The IRQ chip itself.
     pub chip: Box<dyn IrqChip>,
 
+    /// The global IRQ numbers of the chip's interrupts.
+    pub irq_range: Range<usize>,
+
+    /// The global IRQs the chip is cascaded from, or nothing for the root chip.
+    pub parent_irqs: Vec<Irq>,
+}
+
+impl IrqDomain {
+    /// Converts one of the chip's own IRQ numbers to a global one, if it's in the domain.
+    #[must_use]
+    pub fn to_global(&self, irq: Irq) -> Option<Irq> {
+        let global = self.irq_range.start.checked_add(irq.as_usize())?;
+        self.irq_range
+            .contains(&global)
+            .then(|| Irq::from(global as u32))
+    }
+}
+
+/// A descriptor for the IRQ chips in the system.
+///
+/// This structure contains the root IRQ chip, the chips cascaded from it,
+/// and an array of IRQ handler descriptors shared between them.
+pub struct IrqChipDescriptor {
+    /// The IRQ domains, the root chip's first.
+    pub domains: Vec<IrqDomain>,
+
     /// An array of IRQ handler descriptors.
     pub descs: Box<[IrqHandlerDescriptor]>,
 }
@@ -222,112 +584,316 @@ impl IrqChipDescriptor {
     /// Creates a new `IrqChipDescriptor` instance from the given FDT.
     pub fn new(fdt: &Fdt) -> Self {
         let mut this = Self {
-            phandle: Phandle::default(),
+            domains: Vec::new(),
             descs: core::iter::repeat_with(|| IrqHandlerDescriptor::INIT)
                 .take(1024)
-                .collect::<alloc::vec::Vec<_>>()
+                .collect::<Vec<_>>()
                 .into_boxed_slice(),
-            chip: Box::new(Null),
         };
 
-        // find the first interrupt controller node that is compatible with the architecture
+        // every interrupt controller with a driver, and the phandle of its parent
+        let mut pending = Vec::new();
         for node in fdt.all_nodes() {
-            if node.property("interrupt-controller").is_some() {
-                let Some(compatible) = node.compatible().map(Compatible::first) else {
-                    continue;
-                };
-
-                let Some(chip) = Arch::new_irq_chip(compatible) else {
-                    continue;
-                };
-
-                let Some(phandle) = node.property("phandle") else {
-                    log::error!("IRQ chip node {} has no phandle", node.name);
-                    continue;
-                };
-
-                let Some(phandle) = phandle.as_usize() else {
-                    log::error!("IRQ chip node {} has invalid phandle", node.name);
-                    continue;
-                };
-
-                let Ok(phandle) = u32::try_from(phandle) else {
-                    log::error!("IRQ chip node {} has invalid phandle", node.name);
-                    continue;
-                };
-
-                this.phandle = Phandle::new(phandle);
-                let intr_cells = node.interrupt_cells().unwrap_or(1);
-
-                log::debug!(
-                    "{}, compatible = {:?}, intr_cells = {:#x}, phandle = {:#x}",
-                    node.name,
-                    compatible,
-                    intr_cells,
-                    this.phandle.value()
-                );
-
-                if node.interrupt_parent().is_some() {
-                    log::warn!("Interrupt chip parents are NYI");
-                }
-
-                this.chip = chip;
-                break;
+            if node.property("interrupt-controller").is_none() {
+                continue;
             }
+            let Some(compatible) = node.compatible().map(Compatible::first) else {
+                continue;
+            };
+            let Some(chip) = Arch::new_irq_chip(compatible) else {
+                continue;
+            };
+            let Some(phandle) = phandle_of(&node) else {
+                log::error!("IRQ chip node {} has no valid phandle", node.name);
+                continue;
+            };
+            let parent = interrupt_parent(fdt, &node)
+                .and_then(|parent| phandle_of(&parent))
+                .filter(|&parent| parent != phandle);
+
+            log::debug!(
+                "{}, compatible = {:?}, intr_cells = {:#x}, phandle = {:#x}",
+                node.name,
+                compatible,
+                node.interrupt_cells().unwrap_or(1),
+                phandle.value()
+            );
+            pending.push((node, phandle, parent, chip));
         }
 
-        this.chip.init(fdt, &mut this.descs[..]);
+        // the root first, then each chip once its parent has been set up
+        let mut root_found = false;
+        while let Some(idx) = pending.iter().position(|(_, _, parent, _)| match parent {
+            None => !root_found,
+            Some(parent) => this.domains.iter().any(|d| d.phandle == *parent),
+        }) {
+            let (node, phandle, parent, chip) = pending.swap_remove(idx);
+            root_found |= parent.is_none();
+            if let Err(e) = this.add_domain(fdt, &node, phandle, parent, chip) {
+                log::error!("failed to set up IRQ chip {}: {e:?}", node.name);
+            }
+        }
+        for (node, ..) in pending {
+            log::warn!("IRQ chip {} has no usable parent, ignoring it", node.name);
+        }
+
+        if !root_found {
+            log::error!("****************************************************************");
+            log::error!("no supported interrupt controller found in the device tree!");
+            log::error!("falling back to polled mode: devices are serviced from the idle");
+            log::error!("loop, so expect latency and no preemption while tasks are busy");
+            log::error!("****************************************************************");
+            POLLED.store(true, Ordering::Relaxed);
+        }
 
         this
     }
 
+    /// Initializes `chip` and gives it the next free block of IRQ numbers, cascading it from
+    /// its interrupts in the `parent` domain.
+    fn add_domain(
+        &mut self,
+        fdt: &Fdt,
+        node: &FdtNode,
+        phandle: Phandle,
+        parent: Option<Phandle>,
+        mut chip: Box<dyn IrqChip>,
+    ) -> Result<(), Errno> {
+        let parent_irqs = match parent {
+            Some(parent) => (0..)
+                .map_while(|idx| get_interrupt(fdt, node, idx))
+                .filter_map(|cell| self.translate(parent, cell))
+                .collect(),
+            None => Vec::new(),
+        };
+        if parent.is_some() && parent_irqs.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+
+        let start = self.domains.last().map_or(0, |d| d.irq_range.end);
+        let descs = self.descs.get_mut(start..).ok_or(Errno::ENOSPC)?;
+        if descs.is_empty() {
+            return Err(Errno::ENOSPC);
+        }
+        chip.init(fdt, descs);
+        let count = descs.iter().take_while(|desc| desc.used).count();
+
+        log::debug!(
+            "IRQ chip {} has IRQs {}..{}",
+            node.name,
+            start,
+            start + count
+        );
+        self.domains.push(IrqDomain {
+            phandle,
+            chip,
+            irq_range: start..start + count,
+            parent_irqs: parent_irqs.clone(),
+        });
+
+        for irq in parent_irqs {
+            self.enable_irq(irq);
+        }
+        Ok(())
+    }
+
+    /// Returns the domain the given global IRQ belongs to, and its number within the chip.
+    fn domain_of(&mut self, irq: Irq) -> Option<(&mut IrqDomain, Irq)> {
+        let domain = self
+            .domains
+            .iter_mut()
+            .find(|d| d.irq_range.contains(&irq.as_usize()))?;
+        let local = irq.as_usize() - domain.irq_range.start;
+        Some((domain, Irq::from(local as u32)))
+    }
+
+    /// Returns the index of the domain cascaded from the given global IRQ, if there is one.
+    fn cascaded_from(&self, irq: Irq) -> Option<usize> {
+        self.domains
+            .iter()
+            .position(|d| d.parent_irqs.contains(&irq))
+    }
+
     /// Acknowledges the IRQ and returns the IRQ number.
     pub fn ack(&mut self) -> Irq {
-        self.chip.ack()
+        match self.domains.first_mut() {
+            Some(root) => root.chip.ack(),
+            None => Irq::SPURIOUS,
+        }
+    }
+
+    /// Returns `true` if an IRQ number returned by [`ack`](Self::ack) means nothing was
+    /// pending.
+    #[must_use]
+    pub fn is_spurious(&self, irq: Irq) -> bool {
+        self.domains
+            .first()
+            .is_none_or(|root| !root.irq_range.contains(&irq.as_usize()))
     }
 
     /// Sends an end-of-interrupt (EOI) signal for the given IRQ.
     pub fn eoi(&mut self, irq: Irq) {
-        self.chip.eoi(irq);
+        if let Some(root) = self.domains.first_mut() {
+            root.chip.eoi(irq);
+        }
     }
 
     /// Runs the IRQ handler for the given IRQ, if it has been registered.
+    ///
+    /// If a chip is cascaded from the IRQ, every interrupt pending on it is handled instead.
+    /// A threaded IRQ is masked and its task woken to handle it.
     pub fn handle_irq(&mut self, irq: Irq) {
-        if irq.as_usize() < 1024 {
-            if let Some(handler) = &mut self.descs[irq.as_usize()].handler {
+        if let Some(domain) = self.cascaded_from(irq) {
+            let start = time::uptime();
+            let handled = self.handle_cascade(domain);
+            let stats = &mut self.descs[irq.as_usize()].stats;
+            if handled == 0 {
+                stats.record_spurious(irq);
+            } else {
+                stats.record(start);
+            }
+        } else if irq.as_usize() < 1024 {
+            let desc = &mut self.descs[irq.as_usize()];
+            if let Some(thread) = &desc.thread {
+                // masked until its task has run the handler, which then records the time
+                desc.stats.record_delivery();
+                thread.wake();
+                self.disable_irq(irq);
+            } else if let Some(handler) = &mut desc.handler {
+                let start = time::uptime();
                 handler.handle_irq(irq);
+                desc.stats.record(start);
             } else {
                 log::warn!("No handler for irq {}", irq);
+                desc.stats.record_spurious(irq);
+            }
+        }
+    }
+
+    /// Handles every interrupt pending on a cascaded chip, returning how many there were.
+    fn handle_cascade(&mut self, domain: usize) -> usize {
+        let mut handled = 0;
+        loop {
+            let local = self.domains[domain].chip.ack();
+            let Some(irq) = self.domains[domain].to_global(local) else {
+                break handled;
+            };
+            handled += 1;
+            let desc = &self.descs[irq.as_usize()];
+            if desc.handler.is_none() && desc.thread.is_none() && self.cascaded_from(irq).is_none()
+            {
+                // nothing will ever clear it, so stop it from firing again
+                log::warn!("No handler for irq {}, disabling it", irq);
+                self.descs[irq.as_usize()].stats.record_spurious(irq);
+                self.domains[domain].chip.disable_irq(local);
+            } else {
+                self.handle_irq(irq);
+            }
+            self.domains[domain].chip.eoi(local);
+        }
+    }
+
+    /// Runs the handler of every registered IRQ whose device reports a pending interrupt.
+    pub fn poll(&mut self) {
+        for (index, desc) in self.descs.iter_mut().enumerate() {
+            let irq = Irq(index as u32);
+            if let Some(handler) = &mut desc.handler
+                && handler.is_pending(irq)
+            {
+                let start = time::uptime();
+                handler.handle_irq(irq);
+                desc.stats.record(start);
             }
         }
     }
 
     /// Enables the given IRQ.
     pub fn enable_irq(&mut self, irq: Irq) {
-        self.chip.enable_irq(irq);
+        if let Some((domain, local)) = self.domain_of(irq) {
+            domain.chip.enable_irq(local);
+        }
     }
 
     /// Disables the given IRQ.
     pub fn disable_irq(&mut self, irq: Irq) {
-        self.chip.disable_irq(irq);
+        if let Some((domain, local)) = self.domain_of(irq) {
+            domain.chip.disable_irq(local);
+        }
     }
 
-    /// Translates the IRQ data from the device tree into an IRQ number.
+    /// Sets the priority of the given IRQ on the chip it belongs to.
+    ///
+    /// Priorities only order the root chip's interrupts, so one on a cascaded chip has the
+    /// priority of the IRQ it's cascaded from.
+    pub fn set_priority(&mut self, irq: Irq, priority: u8) {
+        if let Some((domain, local)) = self.domain_of(irq) {
+            domain.chip.set_priority(local, priority);
+        }
+    }
+
+    /// Sets the calling CPU's priority mask on the root chip.
+    pub fn set_priority_mask(&mut self, mask: u8) {
+        if let Some(root) = self.domains.first_mut() {
+            root.chip.set_priority_mask(mask);
+        }
+    }
+
+    /// Translates an interrupt specifier for the chip with the given phandle into a global
+    /// IRQ number.
+    #[must_use]
+    pub fn translate(&self, phandle: Phandle, irq_data: IrqCell) -> Option<Irq> {
+        let domain = self.domains.iter().find(|d| d.phandle == phandle)?;
+        domain.to_global(domain.chip.translate_irq(irq_data)?)
+    }
+
+    /// Translates the IRQ data from the device tree for the chip with the given phandle
+    /// into a global IRQ number.
     #[must_use]
-    pub fn translate_irq(&self, irq_data: &[u32]) -> Option<Irq> {
+    pub fn translate_irq(&self, phandle: Phandle, irq_data: &[u32]) -> Option<Irq> {
         let irq_data = match irq_data.len() {
             1 => IrqCell::L1(irq_data[0]),
             2 => IrqCell::L2(irq_data[0], irq_data[1]),
             3 => IrqCell::L3(irq_data[0], irq_data[1], irq_data[2]),
             _ => return None,
         };
-        self.chip.translate_irq(irq_data)
+        self.translate(phandle, irq_data)
     }
 
     /// Manually triggers the given IRQ.
     pub fn manual_irq(&mut self, irq: Irq) {
-        self.chip.manual_irq(irq);
+        if let Some((domain, local)) = self.domain_of(irq) {
+            domain.chip.manual_irq(local);
+        }
     }
+
+    /// Returns the global IRQ the root chip interrupts other CPUs with, if it can.
+    #[must_use]
+    pub fn ipi(&self) -> Option<Irq> {
+        let root = self.domains.first()?;
+        root.to_global(root.chip.ipi()?)
+    }
+
+    /// Raises the root chip's [IPI](Self::ipi) on the given CPU.
+    pub fn send_ipi(&mut self, cpu: usize) {
+        if let Some(root) = self.domains.first_mut() {
+            root.chip.send_ipi(cpu);
+        }
+    }
+}
+
+/// Returns the phandle of the given FDT node, if it has a valid one.
+fn phandle_of(node: &FdtNode) -> Option<Phandle> {
+    let phandle = node.property("phandle")?.as_usize()?;
+    u32::try_from(phandle).ok().map(Phandle::new)
+}
+
+/// Returns the global IRQ number of the `idx`th interrupt of the given FDT node, translated
+/// by whichever chip is its interrupt parent.
+#[must_use]
+pub fn get_irq(fdt: &Fdt, node: &FdtNode, idx: usize) -> Option<Irq> {
+    let cell = get_interrupt(fdt, node, idx)?;
+    let parent = phandle_of(&interrupt_parent(fdt, node)?)?;
+    with_irq_chip(|chip| chip.translate(parent, cell))
 }
 
 /// Returns the parent interrupt node for the given FDT node.
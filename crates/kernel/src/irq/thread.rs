@@ -0,0 +1,116 @@
+//! Threaded IRQ handlers, which run in a task of their own rather than in interrupt context.
+//!
+//! When a threaded IRQ fires, the interrupt handler only masks it at the controller and wakes
+//! the IRQ's task, `irq/<n>`. The task runs the real handler with interrupts enabled, so the
+//! timer can preempt it and other IRQs are taken meanwhile, then unmasks the IRQ. Masking it
+//! in between keeps a level-triggered device from firing again before it's been serviced.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, format, sync::Arc};
+
+use crate::{
+    sync::{IrqMutex, WaitQueue},
+    syscall::errno::Errno,
+    task::{
+        SpawnBuilder,
+        context::{self, Pid, Priority},
+    },
+    time,
+};
+
+use super::{Irq, IrqHandler, is_polled, register_irq, with_irq_chip};
+
+/// A threaded IRQ's half of what its task needs, and the handler the task is to run.
+type Claim = (Arc<IrqThread>, Box<dyn IrqHandler>);
+
+/// Handlers whose tasks haven't started yet, by the PID of the task that will run them.
+static UNCLAIMED: IrqMutex<BTreeMap<Pid, Claim>> = IrqMutex::new(BTreeMap::new());
+
+/// What the interrupt handler and the task of a threaded IRQ share.
+pub struct IrqThread {
+    irq: Irq,
+    pending: AtomicBool,
+    wait: WaitQueue,
+}
+
+impl IrqThread {
+    /// Has the task run the handler. Called in interrupt context, with the IRQ masked.
+    pub(super) fn wake(&self) {
+        self.pending.store(true, Ordering::Release);
+        self.wait.wake_one();
+    }
+}
+
+/// Registers a threaded handler for the given IRQ, spawning the task that runs it.
+///
+/// Without an interrupt controller nothing would wake the task, so the handler is registered
+/// as an ordinary one instead, to be polled.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if the IRQ is out of range, [`Errno::EBUSY`] if it already has
+/// a handler, or whatever spawning the task failed with.
+pub unsafe fn register_threaded_irq(irq: Irq, mut handler: impl IrqHandler) -> Result<(), Errno> {
+    if is_polled() {
+        unsafe { register_irq(irq, handler) };
+        return Ok(());
+    }
+
+    let thread = Arc::new(IrqThread {
+        irq,
+        pending: AtomicBool::new(false),
+        wait: WaitQueue::new(),
+    });
+    with_irq_chip(|chip| {
+        let desc = chip.descs.get_mut(irq.as_usize()).ok_or(Errno::EINVAL)?;
+        if desc.handler.is_some() || desc.thread.is_some() {
+            return Err(Errno::EBUSY);
+        }
+        desc.thread = Some(thread.clone());
+        Ok(())
+    })?;
+
+    handler.post_register_hook(irq);
+    {
+        // held until the task can find its handler, so it can't start before then
+        let mut unclaimed = UNCLAIMED.lock();
+        let spawned = SpawnBuilder::new()
+            .name(format!("irq/{irq}"))
+            .priority(Priority::HIGH)
+            .spawn(irq_thread_main);
+        let cx = match spawned {
+            Ok(cx) => cx,
+            Err(e) => {
+                with_irq_chip(|chip| chip.descs[irq.as_usize()].thread = None);
+                return Err(e);
+            }
+        };
+        let pid = cx.read().pid;
+        unclaimed.insert(pid, (thread, Box::new(handler)));
+    }
+
+    with_irq_chip(|chip| chip.enable_irq(irq));
+    log::debug!("Registered threaded IRQ handler for {}", irq);
+    Ok(())
+}
+
+extern "C" fn irq_thread_main() {
+    let pid = context::current().map(|cx| cx.read().pid);
+    let Some((thread, mut handler)) = pid.and_then(|pid| UNCLAIMED.lock().remove(&pid)) else {
+        log::error!("IRQ thread has no handler to run");
+        return context::exit_current();
+    };
+
+    loop {
+        thread
+            .wait
+            .wait_until(|| thread.pending.swap(false, Ordering::Acquire));
+        let start = time::uptime();
+        handler.handle_irq(thread.irq);
+        with_irq_chip(|chip| {
+            chip.descs[thread.irq.as_usize()].stats.record_time(start);
+            chip.enable_irq(thread.irq);
+        });
+    }
+}
@@ -0,0 +1,111 @@
+//! Tests for reading cpio archives.
+
+use alloc::{format, vec::Vec};
+
+use crate::{
+    cpio::{Archive, FileKind},
+    syscall::errno::Errno,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    lookup_finds_files,
+    read_dir_lists_children,
+    stops_at_trailer,
+    rejects_truncated,
+);
+
+/// Appends a newc entry to `archive`, padded the way `cpio` pads it.
+fn push_entry(archive: &mut Vec<u8>, path: &str, mode: u32, data: &[u8]) {
+    let header = format!(
+        "070701{:08x}{mode:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+        archive.len(),
+        0,
+        0,
+        1,
+        0,
+        data.len(),
+        0,
+        0,
+        0,
+        0,
+        path.len() + 1,
+        0,
+    );
+    archive.extend_from_slice(header.as_bytes());
+    archive.extend_from_slice(path.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+fn sample() -> Vec<u8> {
+    let mut archive = Vec::new();
+    push_entry(&mut archive, ".", 0o040_755, b"");
+    push_entry(&mut archive, "bin", 0o040_755, b"");
+    push_entry(&mut archive, "bin/init", 0o100_755, b"\x7fELF");
+    push_entry(&mut archive, "etc", 0o040_755, b"");
+    push_entry(&mut archive, "etc/motd", 0o100_644, b"hello\n");
+    push_entry(&mut archive, "sbin", 0o120_777, b"bin");
+    push_entry(&mut archive, "TRAILER!!!", 0, b"");
+    archive
+}
+
+fn lookup_finds_files() -> TestResult {
+    let data = sample();
+    let archive = Archive::new(&data)?;
+
+    let motd = archive.lookup("/etc/motd")?;
+    kassert_eq!(motd.kind(), FileKind::File);
+    kassert_eq!(motd.permissions(), 0o644);
+    kassert_eq!(motd.data, b"hello\n");
+    kassert_eq!(motd.name(), "motd");
+
+    kassert_eq!(archive.lookup("./bin/")?.kind(), FileKind::Dir);
+    kassert_eq!(archive.lookup("sbin")?.link_target(), Some("bin"));
+    kassert_eq!(archive.lookup("/etc/passwd").err(), Some(Errno::ENOENT));
+    Ok(())
+}
+
+fn read_dir_lists_children() -> TestResult {
+    let data = sample();
+    let archive = Archive::new(&data)?;
+
+    let root = archive
+        .read_dir("/")
+        .map(|entry| entry.map(|entry| entry.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    kassert_eq!(root, ["bin", "etc", "sbin"]);
+
+    let bin = archive
+        .read_dir("bin")
+        .map(|entry| entry.map(|entry| entry.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    kassert_eq!(bin, ["bin/init"]);
+    Ok(())
+}
+
+fn stops_at_trailer() -> TestResult {
+    let mut data = sample();
+    // anything after the trailer, like the padding the firmware loads, is ignored
+    data.extend_from_slice(&[0; 512]);
+    let archive = Archive::new(&data)?;
+    kassert_eq!(archive.entries().count(), 6);
+    kassert!(archive.entries().all(|entry| entry.is_ok()));
+    Ok(())
+}
+
+fn rejects_truncated() -> TestResult {
+    let data = sample();
+    kassert_eq!(Archive::new(b"not an archive").err(), Some(Errno::EINVAL));
+
+    // cut off in the middle of `etc/motd`'s contents
+    let cut = data.windows(6).position(|w| w == b"hello\n").unwrap_or(0) + 3;
+    let archive = Archive::new(&data[..cut])?;
+    let last = archive.entries().last();
+    kassert!(matches!(last, Some(Err(Errno::EINVAL))));
+    kassert_eq!(archive.lookup("sbin").err(), Some(Errno::EINVAL));
+    Ok(())
+}
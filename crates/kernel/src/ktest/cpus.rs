@@ -0,0 +1,105 @@
+//! Tests for task affinity and parking cores.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{Arch, Architecture},
+    sync::with_irqs_disabled,
+    syscall::errno::Errno,
+    task::{
+        SpawnBuilder,
+        context::{self, CpuMask},
+        cpus,
+    },
+    timer,
+};
+
+use super::{Failure, TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    affinity_needs_an_online_cpu,
+    task_waits_for_its_cpus,
+    last_cpu_stays_unparked,
+);
+
+/// A CPU that can't be online, since it's past the ones the kernel keeps track of.
+const OFFLINE_CPU: usize = 63;
+
+fn affinity_needs_an_online_cpu() -> TestResult {
+    let current = context::current().ok_or_else(|| Failure::new("no current task".into()))?;
+    let pid = with_irqs_disabled(|| current.read().pid);
+    let old = cpus::affinity(pid)?;
+
+    kassert_eq!(
+        cpus::set_affinity(pid, CpuMask(0)).err(),
+        Some(Errno::EINVAL)
+    );
+    kassert_eq!(
+        cpus::set_affinity(pid, CpuMask::single(OFFLINE_CPU)).err(),
+        Some(Errno::EINVAL)
+    );
+    kassert_eq!(cpus::affinity(pid)?, old);
+
+    let this = CpuMask::single(Arch::cpu_id());
+    cpus::set_affinity(pid, this)?;
+    kassert_eq!(cpus::affinity(pid)?, this);
+    cpus::set_affinity(pid, old)?;
+    Ok(())
+}
+
+static RAN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn affine_task() {
+    RAN.store(true, Ordering::Release);
+    context::exit_current();
+}
+
+fn task_waits_for_its_cpus() -> TestResult {
+    RAN.store(false, Ordering::Release);
+    let cx = SpawnBuilder::new()
+        .name("affine")
+        .affinity(CpuMask::single(OFFLINE_CPU))
+        .spawn(affine_task)?;
+    let pid = with_irqs_disabled(|| cx.read().pid);
+
+    // no CPU it may run on is online, so it mustn't have
+    timer::sleep(Duration::from_millis(50));
+    kassert!(!RAN.load(Ordering::Acquire));
+
+    cpus::set_affinity(pid, CpuMask::ALL)?;
+    for _ in 0..100 {
+        if RAN.load(Ordering::Acquire) {
+            break;
+        }
+        timer::sleep(Duration::from_millis(10));
+    }
+    kassert!(RAN.load(Ordering::Acquire));
+    Ok(())
+}
+
+fn last_cpu_stays_unparked() -> TestResult {
+    kassert_eq!(cpus::park(OFFLINE_CPU).err(), Some(Errno::EINVAL));
+    kassert_eq!(cpus::unpark(OFFLINE_CPU).err(), Some(Errno::EINVAL));
+
+    // park every other CPU, so this is the last one running tasks
+    let this = Arch::cpu_id();
+    let others = (0..u64::BITS as usize)
+        .filter(|&cpu| cpu != this && cpus::online().contains(cpu) && !cpus::is_parked(cpu))
+        .collect::<Vec<_>>();
+    for &cpu in &others {
+        cpus::park(cpu)?;
+    }
+    let parked_this = cpus::park(this);
+    for &cpu in &others {
+        cpus::unpark(cpu)?;
+    }
+
+    kassert_eq!(parked_this.err(), Some(Errno::EBUSY));
+    kassert!(!cpus::is_parked(this));
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Tests for reading ELF executables and loading them into an address space.
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{Arch, Architecture},
+    elf::Executable,
+    mem::units::VirtAddr,
+    syscall::errno::Errno,
+    task::{
+        addr_space::{AddrSpace, Protection},
+        exec,
+    },
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    reads_segments,
+    rejects_malformed,
+    load_maps_segments,
+    load_rejects_shared_pages,
+);
+
+const TEXT_ADDR: usize = 0x40_0000;
+const DATA_ADDR: usize = 0x40_1000;
+const ENTRY: usize = TEXT_ADDR + 4;
+const CODE: &[u8] = &[0x1f, 0x20, 0x03, 0xd5, 0xc0, 0x03, 0x5f, 0xd6]; // nop; ret
+const DATA: &[u8] = b"data";
+/// How big the data segment is in memory, most of it zeroed.
+const DATA_MEM_SIZE: usize = 0x1800;
+
+/// A program header: type, flags, file offset, address, size in the file and in memory.
+type Phdr = (u32, u32, usize, usize, usize, usize);
+
+/// Builds an executable with the given program headers, followed by `contents`, which
+/// starts at file offset `0x100`.
+fn build(machine: u16, phdrs: &[Phdr], contents: &[u8]) -> Vec<u8> {
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    elf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf.extend_from_slice(&machine.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(ENTRY as u64).to_le_bytes());
+    elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&(phdrs.len() as u16).to_le_bytes());
+    elf.extend_from_slice(&[0; 6]);
+    for &(kind, flags, offset, vaddr, file_size, mem_size) in phdrs {
+        elf.extend_from_slice(&kind.to_le_bytes());
+        elf.extend_from_slice(&flags.to_le_bytes());
+        for field in [offset, vaddr, vaddr, file_size, mem_size, 0x1000] {
+            elf.extend_from_slice(&(field as u64).to_le_bytes());
+        }
+    }
+    elf.resize(0x100, 0);
+    elf.extend_from_slice(contents);
+    elf
+}
+
+/// An executable with a text segment, a note to skip, and a data segment that's mostly
+/// zeroes.
+fn sample() -> Vec<u8> {
+    let mut contents = CODE.to_vec();
+    contents.extend_from_slice(DATA);
+    build(
+        183,
+        &[
+            (1, 0b101, 0x100, TEXT_ADDR, CODE.len(), CODE.len()),
+            (4, 0b100, 0x100, 0, 0, 0),
+            (
+                1,
+                0b110,
+                0x100 + CODE.len(),
+                DATA_ADDR,
+                DATA.len(),
+                DATA_MEM_SIZE,
+            ),
+        ],
+        &contents,
+    )
+}
+
+fn reads_segments() -> TestResult {
+    let data = sample();
+    let elf = Executable::new(&data)?;
+    kassert_eq!(elf.entry(), ENTRY);
+
+    let segments = elf.segments().collect::<Result<Vec<_>, _>>()?;
+    kassert_eq!(segments.len(), 2);
+    let (text, data) = (&segments[0], &segments[1]);
+    kassert_eq!(text.vaddr, TEXT_ADDR);
+    kassert_eq!(text.data, CODE);
+    kassert!(text.readable && text.executable && !text.writable);
+    kassert_eq!(data.vaddr, DATA_ADDR);
+    kassert_eq!(data.data, DATA);
+    kassert_eq!(data.mem_size, DATA_MEM_SIZE);
+    kassert!(data.readable && data.writable && !data.executable);
+    Ok(())
+}
+
+fn rejects_malformed() -> TestResult {
+    let mut bad_magic = sample();
+    bad_magic[1] = b'X';
+    kassert_eq!(Executable::new(&bad_magic).err(), Some(Errno::ENOEXEC));
+
+    let wrong_machine = build(62, &[], &[]);
+    kassert_eq!(Executable::new(&wrong_machine).err(), Some(Errno::ENOEXEC));
+
+    let truncated = &sample()[..100];
+    kassert_eq!(Executable::new(truncated).err(), Some(Errno::ENOEXEC));
+
+    // a segment whose contents run off the end of the file
+    let past_end = build(183, &[(1, 0b100, 0x100, TEXT_ADDR, 0x1000, 0x1000)], CODE);
+    let elf = Executable::new(&past_end)?;
+    kassert!(matches!(elf.segments().next(), Some(Err(Errno::ENOEXEC))));
+    Ok(())
+}
+
+/// Reads `len` bytes at `addr` in `addr_space`, which must all be in one page.
+fn read_user(addr_space: &AddrSpace, addr: usize, len: usize) -> Result<Vec<u8>, Errno> {
+    let entry = addr_space
+        .table
+        .translate(VirtAddr::new_canonical(addr))
+        .map_err(|_| Errno::EFAULT)?;
+    let frame = entry.addr().map_err(|_| Errno::EFAULT)?;
+    let kernel = frame
+        .add_bytes(addr & Arch::PAGE_OFFSET_MASK)
+        .as_hhdm_virt();
+    Ok(unsafe { core::slice::from_raw_parts(kernel.as_raw_ptr::<u8>(), len) }.to_vec())
+}
+
+fn load_maps_segments() -> TestResult {
+    let data = sample();
+    let elf = Executable::new(&data)?;
+    let mut addr_space = AddrSpace::new_user()?;
+    kassert_eq!(exec::load(&mut addr_space, &elf)?, ENTRY);
+
+    let text = addr_space.region_at(VirtAddr::new(TEXT_ADDR)?).unwrap();
+    kassert_eq!(text.prot, Protection::READ | Protection::EXEC);
+    kassert_eq!(text.size, Arch::PAGE_SIZE);
+    let data_region = addr_space.region_at(VirtAddr::new(DATA_ADDR)?).unwrap();
+    kassert_eq!(data_region.prot, Protection::READ | Protection::WRITE);
+    kassert_eq!(data_region.size, 2 * Arch::PAGE_SIZE);
+
+    kassert_eq!(read_user(&addr_space, TEXT_ADDR, CODE.len())?, CODE);
+    kassert_eq!(read_user(&addr_space, DATA_ADDR, DATA.len())?, DATA);
+    let bss = read_user(&addr_space, DATA_ADDR + 0x1000, 0x800)?;
+    kassert!(bss.iter().all(|&byte| byte == 0));
+    Ok(())
+}
+
+fn load_rejects_shared_pages() -> TestResult {
+    // the data segment starts on the text segment's page
+    let shared = build(
+        183,
+        &[
+            (1, 0b101, 0x100, TEXT_ADDR, CODE.len(), CODE.len()),
+            (1, 0b110, 0x100, TEXT_ADDR + 0x800, CODE.len(), CODE.len()),
+        ],
+        CODE,
+    );
+    let elf = Executable::new(&shared)?;
+    let mut addr_space = AddrSpace::new_user()?;
+    kassert_eq!(
+        exec::load(&mut addr_space, &elf).err(),
+        Some(Errno::ENOEXEC)
+    );
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! Tests for decoding exception syndromes.
+
+use alloc::format;
+
+use crate::arch::esr::{Esr, ExceptionClass, FaultStatus};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    decodes_data_abort,
+    decodes_instruction_abort,
+    decodes_other_classes
+);
+
+fn decodes_data_abort() -> TestResult {
+    // a write to an unmapped page from EL0
+    let esr = Esr(0x9200_0047);
+    kassert_eq!(esr.class(), ExceptionClass::DataAbortLower);
+    let abort = esr.abort().ok_or("not an abort")?;
+    kassert_eq!(abort.status, FaultStatus::Translation(3));
+    kassert!(abort.write && !abort.fetch && abort.far_valid);
+    kassert_eq!(
+        format!("{esr}"),
+        "data abort from EL0: write, translation fault at level 3"
+    );
+    Ok(())
+}
+
+fn decodes_instruction_abort() -> TestResult {
+    // executing a page without execute permission at EL1, with FnV set
+    let esr = Esr(0x8600_040f);
+    kassert_eq!(esr.class(), ExceptionClass::InstrAbortSame);
+    let abort = esr.abort().ok_or("not an abort")?;
+    kassert_eq!(abort.status, FaultStatus::Permission(3));
+    kassert!(abort.fetch && !abort.write && !abort.far_valid);
+    Ok(())
+}
+
+fn decodes_other_classes() -> TestResult {
+    let svc = Esr(0x5600_0000);
+    kassert_eq!(svc.class(), ExceptionClass::Svc64);
+    kassert!(svc.abort().is_none());
+
+    let brk = Esr(0xf200_04db);
+    kassert_eq!(brk.class(), ExceptionClass::Brk64);
+    kassert_eq!(brk.imm16(), 0x4db);
+
+    kassert_eq!(Esr(0xfc00_0000).class(), ExceptionClass::Other(0x3f));
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Tests for futexes.
+
+use core::{
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    sync::with_irqs_disabled,
+    syscall::{errno::Errno, futex},
+    task::{
+        SpawnBuilder,
+        context::{self, Priority},
+        reap,
+    },
+    time, timer,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(wait_wake_twice);
+
+/// How long a waiter may take to queue up before the test gives up on waking it.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+static WORD: AtomicU32 = AtomicU32::new(0);
+static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn waiter_task() {
+    if futex::wait(WORD.as_ptr() as usize, 0).is_ok() {
+        WOKEN.fetch_add(1, Ordering::AcqRel);
+    }
+    context::exit_current();
+}
+
+/// Wakes one waiter on [`WORD`], retrying until one has queued up. Returns whether one did.
+fn wake_one() -> Result<bool, Errno> {
+    let deadline = time::uptime() + WAKE_TIMEOUT;
+    while time::uptime() < deadline {
+        if futex::wake(WORD.as_ptr() as usize, 1)? == 1 {
+            return Ok(true);
+        }
+        timer::sleep(Duration::from_millis(1));
+    }
+    Ok(false)
+}
+
+/// The first waiter is woken, but doesn't run again until a second has queued on the same
+/// word. Finishing its wait mustn't take the second's queue with it.
+fn wait_wake_twice() -> TestResult {
+    WORD.store(0, Ordering::Release);
+    WOKEN.store(0, Ordering::Release);
+
+    let first = SpawnBuilder::new()
+        .name("futex_first")
+        .priority(Priority::LOW)
+        .waitable(true)
+        .spawn(waiter_task)?;
+    kassert!(wake_one()?);
+
+    // outranks the first, so it's queued before the first finishes waiting
+    let second = SpawnBuilder::new()
+        .name("futex_second")
+        .priority(Priority::HIGH)
+        .waitable(true)
+        .spawn(waiter_task)?;
+    kassert!(wake_one()?);
+
+    for task in [first, second] {
+        let pid = with_irqs_disabled(|| task.read().pid);
+        reap::wait(Some(pid), false)?;
+    }
+    kassert_eq!(WOKEN.load(Ordering::Acquire), 2);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! Tests for keymaps, and turning HID reports and terminal input into key events.
+
+use alloc::vec::Vec;
+
+use crate::input::{
+    EVENT_QUEUE_LEN, EventQueue, KeyCode, KeyEvent, Modifiers,
+    hid::BootKeyboard,
+    keymap::{DE, US},
+    serial::Decoder,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    keymap_us_and_de,
+    keymap_caps_lock_and_ctrl,
+    keymap_reverse_lookup,
+    hid_report_diff,
+    hid_roll_over_ignored,
+    serial_decodes_text,
+    serial_decodes_sequences,
+    event_queue_drops_oldest,
+);
+
+fn keymap_us_and_de() -> TestResult {
+    let none = Modifiers::empty();
+    let shift = Modifiers::LEFT_SHIFT;
+    let altgr = Modifiers::RIGHT_ALT;
+    kassert_eq!(US.translate(KeyCode::Z, none), Some('z'));
+    kassert_eq!(DE.translate(KeyCode::Z, none), Some('y'));
+    kassert_eq!(US.translate(KeyCode::DIGIT_2, shift), Some('@'));
+    kassert_eq!(DE.translate(KeyCode::DIGIT_2, shift), Some('"'));
+    kassert_eq!(DE.translate(KeyCode::Q, altgr), Some('@'));
+    kassert_eq!(DE.translate(KeyCode::SEMICOLON, none), Some('ö'));
+    kassert_eq!(DE.translate(KeyCode::NON_US_BACKSLASH, altgr), Some('|'));
+    kassert_eq!(US.translate(KeyCode::Q, altgr), None);
+    kassert_eq!(US.translate(KeyCode::ENTER, none), Some('\n'));
+    kassert_eq!(US.translate(KeyCode::F1, none), None);
+    Ok(())
+}
+
+fn keymap_caps_lock_and_ctrl() -> TestResult {
+    let caps = Modifiers::CAPS_LOCK;
+    kassert_eq!(US.translate(KeyCode::A, caps), Some('A'));
+    kassert_eq!(
+        US.translate(KeyCode::A, caps | Modifiers::RIGHT_SHIFT),
+        Some('a')
+    );
+    kassert_eq!(US.translate(KeyCode::DIGIT_1, caps), Some('1'));
+    kassert_eq!(DE.translate(KeyCode::APOSTROPHE, caps), Some('Ä'));
+    kassert_eq!(US.translate(KeyCode::C, Modifiers::LEFT_CTRL), Some('\x03'));
+    Ok(())
+}
+
+fn keymap_reverse_lookup() -> TestResult {
+    kassert_eq!(US.key_for('a'), Some((KeyCode::A, Modifiers::empty())));
+    kassert_eq!(
+        US.key_for('?'),
+        Some((KeyCode::SLASH, Modifiers::LEFT_SHIFT))
+    );
+    kassert_eq!(DE.key_for('y'), Some((KeyCode::Z, Modifiers::empty())));
+    kassert_eq!(
+        DE.key_for('{'),
+        Some((KeyCode::DIGIT_7, Modifiers::RIGHT_ALT))
+    );
+    kassert_eq!(US.key_for('ö'), None);
+    Ok(())
+}
+
+fn hid_report_diff() -> TestResult {
+    let mut keyboard = BootKeyboard::new();
+    let (events, modifiers) = keyboard.update(&[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+    kassert_eq!(
+        events.as_slice(),
+        &[(KeyCode::LEFT_SHIFT, true), (KeyCode::A, true)]
+    );
+    kassert_eq!(modifiers, Modifiers::LEFT_SHIFT);
+
+    // A stays held in another slot as B is pressed
+    let (events, _) = keyboard.update(&[0x02, 0, 0x05, 0x04, 0, 0, 0, 0]);
+    kassert_eq!(events.as_slice(), &[(KeyCode::B, true)]);
+
+    let (events, modifiers) = keyboard.update(&[0; 8]);
+    kassert_eq!(
+        events.as_slice(),
+        &[
+            (KeyCode::LEFT_SHIFT, false),
+            (KeyCode::B, false),
+            (KeyCode::A, false)
+        ]
+    );
+    kassert_eq!(modifiers, Modifiers::empty());
+    Ok(())
+}
+
+fn hid_roll_over_ignored() -> TestResult {
+    let mut keyboard = BootKeyboard::new();
+    keyboard.update(&[0, 0, 0x04, 0, 0, 0, 0, 0]);
+    let (events, _) = keyboard.update(&[0, 0, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01]);
+    kassert!(events.is_empty());
+    let (events, _) = keyboard.update(&[0, 0, 0x04, 0, 0, 0, 0, 0]);
+    kassert!(events.is_empty());
+    Ok(())
+}
+
+/// Returns the presses `bytes` make, as `(code, modifiers, text)`.
+fn presses(bytes: &[u8]) -> Vec<(KeyCode, Modifiers, Option<char>)> {
+    let mut decoder = Decoder::new();
+    bytes
+        .iter()
+        .flat_map(|&b| decoder.feed(b))
+        .filter(|event| event.pressed)
+        .map(|event| (event.code, event.modifiers, event.text))
+        .collect()
+}
+
+fn serial_decodes_text() -> TestResult {
+    let none = Modifiers::empty();
+    kassert_eq!(
+        presses(b"a?\r\x7f"),
+        [
+            (KeyCode::A, none, Some('a')),
+            (KeyCode::SLASH, Modifiers::LEFT_SHIFT, Some('?')),
+            (KeyCode::ENTER, none, Some('\n')),
+            (KeyCode::BACKSPACE, none, Some('\x7f')),
+        ]
+    );
+    kassert_eq!(
+        presses(b"\x03\x1bx"),
+        [
+            (KeyCode::C, Modifiers::LEFT_CTRL, Some('\x03')),
+            (KeyCode::X, Modifiers::LEFT_ALT, Some('x')),
+        ]
+    );
+
+    // every byte makes a press and a release
+    let mut decoder = Decoder::new();
+    let events = decoder.feed(b'b');
+    kassert_eq!(
+        events.as_slice(),
+        &[
+            KeyEvent::press(KeyCode::B, none, Some('b')),
+            KeyEvent::release(KeyCode::B, none)
+        ]
+    );
+    Ok(())
+}
+
+fn serial_decodes_sequences() -> TestResult {
+    let none = Modifiers::empty();
+    kassert_eq!(
+        presses(b"\x1b[A\x1b[D\x1b[3~\x1b[15~\x1bOP"),
+        [
+            (KeyCode::UP, none, None),
+            (KeyCode::LEFT, none, None),
+            (KeyCode::DELETE, none, None),
+            (KeyCode::F5, none, None),
+            (KeyCode::F1, none, None),
+        ]
+    );
+    kassert_eq!(
+        presses(b"\x1b[1;5C\x1b[1;2H\x1b[99~a"),
+        [
+            (KeyCode::RIGHT, Modifiers::LEFT_CTRL, None),
+            (KeyCode::HOME, Modifiers::LEFT_SHIFT, None),
+            (KeyCode::A, none, Some('a')),
+        ]
+    );
+    Ok(())
+}
+
+fn event_queue_drops_oldest() -> TestResult {
+    let queue = EventQueue::new();
+    for i in 0..=EVENT_QUEUE_LEN {
+        queue.push(KeyEvent::press(KeyCode(i as u8), Modifiers::empty(), None));
+    }
+    kassert_eq!(queue.len(), EVENT_QUEUE_LEN);
+    kassert_eq!(queue.try_pop().map(|event| event.code), Some(KeyCode(1)));
+    kassert_eq!(queue.pop().code, KeyCode(2));
+    while queue.try_pop().is_some() {}
+    kassert!(queue.is_empty());
+    Ok(())
+}
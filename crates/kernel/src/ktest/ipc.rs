@@ -0,0 +1,222 @@
+//! Tests for pipes, channels, shared memory objects, and file descriptor tables.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::{
+    arch::{Arch, Architecture},
+    file::{Console, File, FileTable, MAX_FILES},
+    ipc::{
+        Sender, channel,
+        pipe::{self, PIPE_CAPACITY, PipeWriter},
+        shm,
+    },
+    mem::units::VirtAddr,
+    sync::IrqMutex,
+    syscall::errno::Errno,
+    task::{
+        SpawnBuilder,
+        addr_space::{AddrSpace, Backing, Protection},
+        context,
+    },
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    pipe_round_trip,
+    pipe_eof_after_writer_closes,
+    pipe_write_after_reader_closes,
+    pipe_blocks_both_ends,
+    channel_delivers_in_order,
+    channel_disconnects,
+    shm_shared_between_spaces,
+    shm_outlives_its_id,
+    file_table_reuses_lowest_fd,
+);
+
+fn pipe_round_trip() -> TestResult {
+    let (reader, writer) = pipe::pipe();
+    kassert_eq!(writer.write(b"hello world")?, 11);
+
+    let mut buf = [0; 5];
+    kassert_eq!(reader.read(&mut buf)?, 5);
+    kassert_eq!(&buf, b"hello");
+    let mut buf = [0; 16];
+    kassert_eq!(reader.read(&mut buf)?, 6);
+    kassert_eq!(&buf[..6], b" world");
+    Ok(())
+}
+
+fn pipe_eof_after_writer_closes() -> TestResult {
+    let (reader, writer) = pipe::pipe();
+    writer.write(b"x")?;
+    drop(writer);
+
+    let mut buf = [0; 4];
+    kassert_eq!(reader.read(&mut buf)?, 1);
+    kassert_eq!(reader.read(&mut buf)?, 0);
+    Ok(())
+}
+
+fn pipe_write_after_reader_closes() -> TestResult {
+    let (reader, writer) = pipe::pipe();
+    drop(reader);
+    kassert_eq!(writer.write(b"x").err(), Some(Errno::EPIPE));
+    Ok(())
+}
+
+/// More than the pipe holds, so the writer has to wait for the reader too.
+const STREAM_LEN: usize = 3 * PIPE_CAPACITY + 100;
+
+/// The writer handed to [`pipe_writer_task`].
+static PIPE_WRITER: IrqMutex<Option<PipeWriter>> = IrqMutex::new(None);
+
+fn stream_byte(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+extern "C" fn pipe_writer_task() {
+    let writer = PIPE_WRITER.lock().take();
+    if let Some(writer) = writer {
+        let data = (0..STREAM_LEN).map(stream_byte).collect::<Vec<_>>();
+        writer.write(&data).ok();
+    }
+    context::exit_current();
+}
+
+fn pipe_blocks_both_ends() -> TestResult {
+    let (reader, writer) = pipe::pipe();
+    *PIPE_WRITER.lock() = Some(writer);
+    SpawnBuilder::new()
+        .name("pipe-writer")
+        .spawn(pipe_writer_task)?;
+
+    // the pipe starts empty, so this sleeps until the writer has run
+    let mut received = Vec::new();
+    let mut buf = vec![0; 1000];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..len]);
+    }
+    kassert_eq!(received.len(), STREAM_LEN);
+    kassert!(
+        received
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == stream_byte(i))
+    );
+    Ok(())
+}
+
+const MESSAGES: u32 = 32;
+
+/// The sender handed to [`channel_sender_task`].
+static CHANNEL_SENDER: IrqMutex<Option<Sender<u32>>> = IrqMutex::new(None);
+
+extern "C" fn channel_sender_task() {
+    let sender = CHANNEL_SENDER.lock().take();
+    if let Some(sender) = sender {
+        for msg in 0..MESSAGES {
+            if sender.send(msg).is_err() {
+                break;
+            }
+        }
+    }
+    context::exit_current();
+}
+
+fn channel_delivers_in_order() -> TestResult {
+    // far smaller than the number of messages, so the sender has to wait
+    let (sender, receiver) = channel(4);
+    *CHANNEL_SENDER.lock() = Some(sender);
+    SpawnBuilder::new()
+        .name("channel-sender")
+        .spawn(channel_sender_task)?;
+
+    let mut messages = Vec::new();
+    while let Some(msg) = receiver.recv() {
+        messages.push(msg);
+    }
+    kassert_eq!(messages, (0..MESSAGES).collect::<Vec<_>>());
+    Ok(())
+}
+
+fn channel_disconnects() -> TestResult {
+    let (sender, receiver) = channel(1);
+    let other = sender.clone();
+    sender.try_send(1)?;
+    kassert_eq!(other.try_send(2).err(), Some(Errno::EAGAIN));
+
+    // what was sent is still there once the senders are gone
+    drop(sender);
+    drop(other);
+    kassert_eq!(receiver.recv(), Some(1));
+    kassert_eq!(receiver.recv(), None);
+    kassert_eq!(receiver.try_recv(), None);
+
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    kassert_eq!(sender.send(1).err(), Some(Errno::EPIPE));
+    kassert_eq!(sender.try_send(1).err(), Some(Errno::EPIPE));
+    Ok(())
+}
+
+fn shm_shared_between_spaces() -> TestResult {
+    kassert_eq!(shm::create(0).err(), Some(Errno::EINVAL));
+    let id = shm::create(Arch::PAGE_SIZE + 1)?;
+    kassert_eq!(shm::size(id)?, 2 * Arch::PAGE_SIZE);
+
+    let prot = Protection::READ | Protection::WRITE;
+    let mut a = AddrSpace::new_user()?;
+    let mut b = AddrSpace::new_user()?;
+    let in_a = shm::map(&mut a, id, VirtAddr::NULL, false, prot)?;
+    let in_b = shm::map(&mut b, id, VirtAddr::NULL, false, prot)?;
+    kassert!(matches!(
+        a.region_at(in_a).map(|region| region.backing),
+        Some(Backing::Shared(_))
+    ));
+    for offset in [0, Arch::PAGE_SIZE] {
+        let frame_a = a.table.translate(in_a.add_bytes(offset))?.addr()?;
+        let frame_b = b.table.translate(in_b.add_bytes(offset))?.addr()?;
+        kassert_eq!(frame_a, frame_b);
+    }
+    shm::destroy(id)?;
+    Ok(())
+}
+
+fn shm_outlives_its_id() -> TestResult {
+    let id = shm::create(Arch::PAGE_SIZE)?;
+    let mut addr_space = AddrSpace::new_user()?;
+    let addr = shm::map(&mut addr_space, id, VirtAddr::NULL, false, Protection::READ)?;
+
+    // the id goes straight away, but the mapping stays until it's unmapped
+    shm::destroy(id)?;
+    kassert_eq!(shm::destroy(id).err(), Some(Errno::EINVAL));
+    kassert_eq!(shm::size(id).err(), Some(Errno::EINVAL));
+    kassert_eq!(
+        shm::map(&mut addr_space, id, VirtAddr::NULL, false, Protection::READ).err(),
+        Some(Errno::EINVAL)
+    );
+    kassert!(addr_space.table.translate(addr)?.flags().is_present());
+    addr_space.unmap(addr, Arch::PAGE_SIZE)?;
+    Ok(())
+}
+
+fn file_table_reuses_lowest_fd() -> TestResult {
+    let mut files = FileTable::with_console();
+    kassert!(files.get(2).is_ok());
+    kassert_eq!(files.insert(Arc::new(Console))?, 3);
+    files.remove(1)?;
+    kassert_eq!(files.get(1).err(), Some(Errno::EBADF));
+    kassert_eq!(files.insert(Arc::new(Console))?, 1);
+    kassert_eq!(files.remove(7).err(), Some(Errno::EBADF));
+
+    while files.insert(Arc::new(Console)).is_ok() {}
+    kassert_eq!(files.insert(Arc::new(Console)).err(), Some(Errno::EMFILE));
+    kassert!(files.get(MAX_FILES - 1).is_ok());
+    kassert_eq!(files.get(MAX_FILES).err(), Some(Errno::EBADF));
+    Ok(())
+}
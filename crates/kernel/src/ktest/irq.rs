@@ -0,0 +1,121 @@
+//! Tests for registering and dispatching IRQ handlers.
+
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{format, sync::Arc};
+
+use crate::{
+    irq::{self, Irq, IrqHandler},
+    timer,
+};
+
+use super::{Failure, TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    register_and_dispatch,
+    register_twice_keeps_first,
+    threaded_runs_outside_irq_context
+);
+
+struct CountingHandler(Arc<AtomicUsize>);
+
+impl IrqHandler for CountingHandler {
+    fn handle_irq(&mut self, _irq: Irq) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the times it's run from a task, rather than an interrupt handler.
+struct TaskCountingHandler(Arc<AtomicUsize>);
+
+impl IrqHandler for TaskCountingHandler {
+    fn handle_irq(&mut self, _irq: Irq) {
+        if !irq::in_irq_context() {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn counter() -> Arc<AtomicUsize> {
+    Arc::new(AtomicUsize::new(0))
+}
+
+/// Finds an IRQ near the top of the range that nothing has a handler for, so the tests can
+/// borrow it.
+fn free_irq() -> Result<Irq, Failure> {
+    irq::with_irq_chip(|chip| {
+        (960..1020)
+            .rev()
+            .find(|&irq| chip.descs[irq].handler.is_none() && chip.descs[irq].thread.is_none())
+            .map(|irq| Irq::from(irq as u32))
+    })
+    .ok_or_else(|| Failure::new("no free IRQ to test with".into()))
+}
+
+fn unregister(irq: Irq) {
+    irq::with_irq_chip(|chip| {
+        chip.disable_irq(irq);
+        chip.descs[irq.as_usize()].handler = None;
+        // a threaded IRQ's task is left asleep, with nothing to wake it
+        chip.descs[irq.as_usize()].thread = None;
+    });
+}
+
+/// Runs the handler for `irq` as if the chip had raised it.
+fn raise(irq: Irq) {
+    irq::with_irq_chip(|chip| chip.handle_irq(irq));
+}
+
+fn register_and_dispatch() -> TestResult {
+    let irq = free_irq()?;
+    let count = counter();
+    unsafe { irq::register_irq(irq, CountingHandler(count.clone())) };
+
+    let registered = irq::with_irq_chip(|chip| chip.descs[irq.as_usize()].handler.is_some());
+    raise(irq);
+    raise(irq);
+    unregister(irq);
+
+    kassert!(registered);
+    kassert_eq!(count.load(Ordering::Relaxed), 2);
+    Ok(())
+}
+
+fn register_twice_keeps_first() -> TestResult {
+    let irq = free_irq()?;
+    let first = counter();
+    let second = counter();
+    unsafe {
+        irq::register_irq(irq, CountingHandler(first.clone()));
+        irq::register_irq(irq, CountingHandler(second.clone()));
+    }
+
+    raise(irq);
+    unregister(irq);
+
+    kassert_eq!(first.load(Ordering::Relaxed), 1);
+    kassert_eq!(second.load(Ordering::Relaxed), 0);
+    Ok(())
+}
+
+fn threaded_runs_outside_irq_context() -> TestResult {
+    let irq = free_irq()?;
+    let count = counter();
+    unsafe { irq::register_threaded_irq(irq, TaskCountingHandler(count.clone())) }
+        .map_err(|e| Failure::new(format!("registering failed: {e:?}")))?;
+
+    raise(irq);
+    for _ in 0..100 {
+        if count.load(Ordering::Relaxed) != 0 {
+            break;
+        }
+        timer::sleep(Duration::from_millis(10));
+    }
+    unregister(irq);
+
+    kassert_eq!(count.load(Ordering::Relaxed), 1);
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Tests for address types, the frame allocator and page table mapping.
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::{
+        MemError,
+        paging::{
+            allocator::KernelFrameAllocator,
+            asid::Asid,
+            table::{BlockSize, PageFlags, PageTable, PageTableLevel, TableKind},
+        },
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
+    task::addr_space::{AddrSpace, Backing, Protection},
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    virt_addr_canonical,
+    virt_addr_align_ok,
+    virt_addr_align,
+    frame_alloc_distinct,
+    frame_alloc_contiguous,
+    frame_alloc_aligned,
+    map_translate_unmap,
+    map_protection,
+    map_device_uncached,
+    map_twice_fails,
+    asid_stable_and_distinct,
+    block_split_and_merge,
+    live_block_split_and_merge,
+);
+
+/// A user address nothing else in the tests maps.
+const TEST_ADDR: usize = 0x0000_0040_0000_0000;
+
+fn virt_addr_canonical() -> TestResult {
+    kassert!(VirtAddr::new(0x0000_8000_0000_0000).is_err());
+    kassert!(VirtAddr::new(0xffff_8000_0000_0000).is_ok());
+    kassert!(VirtAddr::new(0x0000_7fff_ffff_ffff).is_ok());
+    kassert_eq!(
+        VirtAddr::new_canonical(0x0000_8000_0000_0000),
+        VirtAddr::MIN_HIGH
+    );
+    kassert!(VirtAddr::MIN_HIGH.is_canonical());
+    Ok(())
+}
+
+fn virt_addr_align_ok() -> TestResult {
+    kassert!(matches!(
+        VirtAddr::NULL.align_ok::<u64>(),
+        Err(MemError::NullVirtAddr)
+    ));
+    kassert!(matches!(
+        VirtAddr::new(0x1001)?.align_ok::<u64>(),
+        Err(MemError::UnalignedVirtAddr(_, 8))
+    ));
+    kassert!(VirtAddr::new(0x1001)?.align_ok::<u8>().is_ok());
+    kassert!(VirtAddr::new(0x1008)?.align_ok::<u64>().is_ok());
+    Ok(())
+}
+
+fn virt_addr_align() -> TestResult {
+    let addr = VirtAddr::new(0x1234)?;
+    kassert_eq!(addr.align_down(Arch::PAGE_SIZE), VirtAddr::new(0x1000)?);
+    kassert_eq!(addr.align_up(Arch::PAGE_SIZE), VirtAddr::new(0x2000)?);
+    kassert_eq!(
+        VirtAddr::new(0x2000)?.align_up(Arch::PAGE_SIZE),
+        VirtAddr::new(0x2000)?
+    );
+    kassert!(VirtAddr::new(0x2000)?.is_aligned(Arch::PAGE_SIZE));
+    kassert!(!addr.is_aligned(Arch::PAGE_SIZE));
+    Ok(())
+}
+
+fn frame_alloc_distinct() -> TestResult {
+    let a = unsafe { KernelFrameAllocator.allocate_one()? };
+    let b = unsafe { KernelFrameAllocator.allocate_one()? };
+    kassert!(a != b);
+    kassert!(a.is_aligned(Arch::PAGE_SIZE) && b.is_aligned(Arch::PAGE_SIZE));
+
+    // each frame is its own memory, reachable through the HHDM
+    unsafe {
+        a.as_hhdm_virt().fill(0xaa, Arch::PAGE_SIZE)?;
+        b.as_hhdm_virt().fill(0x55, Arch::PAGE_SIZE)?;
+        kassert_eq!(a.as_hhdm_virt().read::<u64>()?, 0xaaaa_aaaa_aaaa_aaaa);
+        kassert_eq!(
+            a.as_hhdm_virt()
+                .add_bytes(Arch::PAGE_SIZE - 8)
+                .read::<u64>()?,
+            0xaaaa_aaaa_aaaa_aaaa
+        );
+        kassert_eq!(b.as_hhdm_virt().read::<u64>()?, 0x5555_5555_5555_5555);
+    }
+
+    KernelFrameAllocator.free(a, FrameCount::ONE)?;
+    KernelFrameAllocator.free(b, FrameCount::ONE)?;
+    Ok(())
+}
+
+fn frame_alloc_contiguous() -> TestResult {
+    let count = FrameCount::new(4);
+    let start = unsafe { KernelFrameAllocator.allocate(count)? };
+    kassert!(start.is_aligned(Arch::PAGE_SIZE));
+    unsafe {
+        start.as_hhdm_virt().fill(0, 4 * Arch::PAGE_SIZE)?;
+    }
+    KernelFrameAllocator.free(start, count)?;
+    Ok(())
+}
+
+fn frame_alloc_aligned() -> TestResult {
+    let count = FrameCount::new(3);
+    let align = 16 * Arch::PAGE_SIZE;
+    let start = unsafe { KernelFrameAllocator.allocate_contiguous(count, align)? };
+    kassert!(start.is_aligned(align));
+    KernelFrameAllocator.free_contiguous(start, count, align)?;
+
+    let result = unsafe { KernelFrameAllocator.allocate_contiguous(count, Arch::PAGE_SIZE + 1) };
+    kassert!(matches!(result, Err(MemError::InvalidAlignment(_))));
+    Ok(())
+}
+
+fn map_translate_unmap() -> TestResult {
+    let mut addr_space = AddrSpace::new_user()?;
+    let page = VirtAddr::new(TEST_ADDR)?;
+    let frame = unsafe { KernelFrameAllocator.allocate_one()? };
+
+    let prot = Protection::READ | Protection::WRITE;
+    let mapped = addr_space.map(page, Arch::PAGE_SIZE, true, prot, Backing::Physical(frame))?;
+    kassert_eq!(mapped, page);
+    kassert!(addr_space.region_at(page.add_bytes(8)).is_some());
+
+    let entry = addr_space.table.translate(page)?;
+    kassert!(entry.flags().is_present());
+    kassert!(entry.flags().is_user());
+    kassert!(entry.flags().is_writable());
+    kassert_eq!(entry.addr()?, frame);
+
+    addr_space.unmap(page, Arch::PAGE_SIZE)?;
+    kassert!(addr_space.region_at(page).is_none());
+    kassert!(
+        addr_space
+            .table
+            .translate(page)
+            .is_ok_and(|entry| !entry.flags().is_present())
+    );
+
+    KernelFrameAllocator.free(frame, FrameCount::ONE)?;
+    Ok(())
+}
+
+fn map_protection() -> TestResult {
+    let mut addr_space = AddrSpace::new_user()?;
+    let page = VirtAddr::new(TEST_ADDR)?;
+    addr_space.map(
+        page,
+        2 * Arch::PAGE_SIZE,
+        true,
+        Protection::READ,
+        Backing::Anonymous,
+    )?;
+
+    for offset in [0, Arch::PAGE_SIZE] {
+        let flags = addr_space.table.translate(page.add_bytes(offset))?.flags();
+        kassert!(flags.is_present() && flags.is_user());
+        kassert!(!flags.is_writable());
+    }
+    Ok(())
+}
+
+fn map_device_uncached() -> TestResult {
+    let mut addr_space = AddrSpace::new_user()?;
+    let page = VirtAddr::new(TEST_ADDR)?;
+    let frame = unsafe { KernelFrameAllocator.allocate_one()? };
+    let prot = Protection::READ | Protection::WRITE;
+    addr_space.map(page, Arch::PAGE_SIZE, true, prot, Backing::Device(frame))?;
+
+    let flags = addr_space.table.translate(page)?.flags();
+    kassert!(flags.is_user() && flags.is_writable());
+    kassert!(flags.has_flags(Arch::PAGE_FLAG_NON_CACHEABLE));
+    kassert!(!flags.has_flags(Arch::PAGE_FLAG_NORMAL));
+
+    // the frame isn't the address space's to free
+    addr_space.unmap(page, Arch::PAGE_SIZE)?;
+    KernelFrameAllocator.free(frame, FrameCount::ONE)?;
+    Ok(())
+}
+
+fn map_twice_fails() -> TestResult {
+    let mut addr_space = AddrSpace::new_user()?;
+    let page = VirtAddr::new(TEST_ADDR)?;
+    addr_space.map(
+        page,
+        Arch::PAGE_SIZE,
+        true,
+        Protection::READ,
+        Backing::Anonymous,
+    )?;
+
+    let frame = unsafe { KernelFrameAllocator.allocate_one()? };
+    let result = addr_space
+        .table
+        .map_to(page, frame, BlockSize::Page4KiB, PageFlags::new().user());
+    KernelFrameAllocator.free(frame, FrameCount::ONE)?;
+    kassert!(matches!(result, Err(MemError::PageAlreadyMapped(..))));
+    Ok(())
+}
+
+fn asid_stable_and_distinct() -> TestResult {
+    let a = Asid::new();
+    let b = Asid::new();
+    let first = a.get();
+    kassert!(first != 0);
+    kassert_eq!(a.get(), first);
+    kassert!(b.get() != a.get());
+    Ok(())
+}
+
+fn block_split_and_merge() -> TestResult {
+    let mut addr_space = AddrSpace::new_user()?;
+    let table = &mut addr_space.table;
+    let page = VirtAddr::new(TEST_ADDR)?;
+    // never touched, so it doesn't matter what's there
+    let frame = PhysAddr::new(BlockSize::Block2MiB.size() * 16)?;
+    let flags = PageFlags::new().user().writable();
+    unsafe {
+        table
+            .map_to(page, frame, BlockSize::Block2MiB, flags)?
+            .ignore();
+    }
+
+    let inner = page.add_bytes(Arch::PAGE_SIZE);
+    unsafe {
+        table
+            .protect_range(inner, Arch::PAGE_SIZE, PageFlags::new().user())?
+            .ignore();
+    }
+    let entry = table.translate(inner)?;
+    kassert!(!entry.flags().is_writable());
+    kassert_eq!(entry.addr()?, frame.add_bytes(Arch::PAGE_SIZE));
+    kassert!(table.translate(page)?.flags().is_writable());
+
+    unsafe { table.protect_range(inner, Arch::PAGE_SIZE, flags)?.ignore() };
+    let p2 = table
+        .next_table(page.page_table_index(PageTableLevel::Level4))?
+        .next_table(page.page_table_index(PageTableLevel::Level3))?;
+    kassert!(
+        p2.next_table(page.page_table_index(PageTableLevel::Level2))
+            .is_err()
+    );
+    kassert_eq!(
+        table.translate(inner)?.addr()?,
+        frame.add_bytes(Arch::PAGE_SIZE)
+    );
+    Ok(())
+}
+
+fn live_block_split_and_merge() -> TestResult {
+    // a page of the HHDM, most likely inside a block, in the tables the kernel is running on
+    let mut table = PageTable::current(TableKind::Kernel);
+    let frame = unsafe { KernelFrameAllocator.allocate_one()? };
+    let page = frame.as_hhdm_virt();
+    let flags = table.translate(page)?.flags();
+    unsafe { page.write_volatile(0x5eed_u64)? };
+
+    table
+        .protect_range(page, Arch::PAGE_SIZE, PageFlags::new_for_rodata_segment())?
+        .flush();
+    kassert!(!table.translate(page)?.flags().is_writable());
+    kassert_eq!(unsafe { page.read_volatile::<u64>()? }, 0x5eed);
+
+    table.protect_range(page, Arch::PAGE_SIZE, flags)?.flush();
+    kassert!(table.translate(page)?.flags().is_writable());
+    kassert_eq!(table.translate(page)?.addr()?, frame);
+    kassert_eq!(unsafe { page.read_volatile::<u64>()? }, 0x5eed);
+
+    KernelFrameAllocator.free(frame, FrameCount::ONE)?;
+    Ok(())
+}
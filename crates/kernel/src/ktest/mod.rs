@@ -0,0 +1,209 @@
+//! In-kernel tests, run under QEMU by kernels built with the `ktest` feature.
+//!
+//! Once the kernel is up, a task runs every test in [`SUITES`] and reports each over the
+//! serial console as `ktest: <name> ... ok` or `ktest: <name> ... FAILED`, followed by a
+//! `ktest: <passed> passed, <failed> failed` summary. It then exits QEMU, with a non-zero code
+//! if anything failed. `cargo builder test` builds such a kernel, runs it and checks the
+//! report.
+//!
+//! Tests return a [`TestResult`], checked with [`kassert!`] and [`kassert_eq!`], so one
+//! failing doesn't stop the rest. A test that panics does, since the kernel can't recover;
+//! the panic handler reports it as failed before exiting QEMU.
+
+use core::{
+    fmt,
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::{format, string::String};
+
+use crate::{
+    arch::{Arch, Architecture, drivers::led},
+    serial_println,
+    syscall::errno::Errno,
+    task,
+};
+
+pub mod cpio;
+pub mod cpus;
+pub mod elf;
+pub mod esr;
+pub mod futex;
+pub mod input;
+pub mod ipc;
+pub mod irq;
+pub mod mem;
+pub mod mutex;
+pub mod percpu;
+pub mod pmu;
+pub mod reap;
+pub mod rng;
+pub mod rwlock;
+pub mod string;
+pub mod time;
+pub mod trace;
+
+/// Every test, grouped by the module they're in.
+static SUITES: &[&[Test]] = &[
+    mem::TESTS,
+    percpu::TESTS,
+    irq::TESTS,
+    cpio::TESTS,
+    elf::TESTS,
+    ipc::TESTS,
+    mutex::TESTS,
+    cpus::TESTS,
+    reap::TESTS,
+    input::TESTS,
+    esr::TESTS,
+    rng::TESTS,
+    rwlock::TESTS,
+    time::TESTS,
+    trace::TESTS,
+    pmu::TESTS,
+    string::TESTS,
+    futex::TESTS,
+];
+
+/// The test being run, for the panic handler.
+static CURRENT: AtomicPtr<Test> = AtomicPtr::new(ptr::null_mut());
+
+/// A test function, and the name it's reported under.
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn() -> TestResult,
+}
+
+/// Declares the tests in a module, named after the functions, as `TESTS`.
+macro_rules! tests {
+    ($($test:ident),* $(,)?) => {
+        pub static TESTS: &[$crate::ktest::Test] = &[$(
+            $crate::ktest::Test {
+                name: concat!(module_path!(), "::", stringify!($test)),
+                run: $test,
+            }
+        ),*];
+    };
+}
+pub(crate) use tests;
+
+/// Why a test failed.
+pub struct Failure {
+    message: String,
+    location: &'static Location<'static>,
+}
+
+impl Failure {
+    /// Creates a failure with the given message, located at the caller.
+    #[must_use]
+    #[track_caller]
+    pub fn new(message: String) -> Self {
+        Self {
+            message,
+            location: Location::caller(),
+        }
+    }
+}
+
+// lets tests `?` on anything that can fail; deliberately not `Debug` itself so this doesn't
+// overlap with `From<Failure> for Failure`
+impl<E: fmt::Debug> From<E> for Failure {
+    #[track_caller]
+    fn from(e: E) -> Self {
+        Self::new(format!("{e:?}"))
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.location)
+    }
+}
+
+/// The outcome of a test.
+pub type TestResult = Result<(), Failure>;
+
+/// Fails the test if the condition is false.
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            return Err($crate::ktest::Failure::new(alloc::format!(
+                "assertion failed: {}",
+                stringify!($cond)
+            )));
+        }
+    };
+}
+pub(crate) use kassert;
+
+/// Fails the test if the two values aren't equal.
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {{
+        let (left, right) = (&$left, &$right);
+        if left != right {
+            return Err($crate::ktest::Failure::new(alloc::format!(
+                "assertion failed: {} == {} (left: {left:?}, right: {right:?})",
+                stringify!($left),
+                stringify!($right),
+            )));
+        }
+    }};
+}
+pub(crate) use kassert_eq;
+
+fn short_name(test: &Test) -> &'static str {
+    test.name
+        .strip_prefix("kernel::ktest::")
+        .unwrap_or(test.name)
+}
+
+/// Starts the task that runs the tests and exits QEMU.
+pub fn init() -> Result<(), Errno> {
+    task::SpawnBuilder::new().name("ktest").spawn(ktest_main)?;
+    Ok(())
+}
+
+/// Runs every test, returning how many failed.
+pub fn run_all() -> usize {
+    let count = SUITES.iter().map(|suite| suite.len()).sum::<usize>();
+    serial_println!("ktest: running {count} tests");
+
+    let mut failed = 0;
+    for test in SUITES.iter().copied().flatten() {
+        CURRENT.store(ptr::from_ref(test).cast_mut(), Ordering::Release);
+        match (test.run)() {
+            Ok(()) => serial_println!("ktest: {} ... ok", short_name(test)),
+            Err(failure) => {
+                serial_println!("ktest: {} ... FAILED", short_name(test));
+                serial_println!("ktest:     {failure}");
+                failed += 1;
+            }
+        }
+    }
+    CURRENT.store(ptr::null_mut(), Ordering::Release);
+
+    serial_println!("ktest: {} passed, {failed} failed", count - failed);
+    failed
+}
+
+extern "C" fn ktest_main() {
+    let failed = run_all();
+    if failed != 0 {
+        led::set_pattern(led::Pattern::TestFailure);
+    }
+    Arch::exit_qemu(u32::from(failed != 0))
+}
+
+/// Reports the running test, if any, as failed because of a panic, and exits QEMU.
+pub fn on_panic(info: &core::panic::PanicInfo) -> ! {
+    let test = CURRENT.load(Ordering::Acquire);
+    if !test.is_null() {
+        let test = unsafe { &*test };
+        serial_println!("ktest: {} ... FAILED", short_name(test));
+        serial_println!("ktest:     {info}");
+    }
+    serial_println!("ktest: aborted after a panic");
+    Arch::exit_qemu(1)
+}
@@ -0,0 +1,127 @@
+//! Tests for the blocking mutex, and the priority its holder inherits.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    sync::{Mutex, with_irqs_disabled},
+    task::{
+        SpawnBuilder,
+        context::{self, Context, Priority},
+        reap,
+    },
+    time, timer,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(inherited_priorities_stack, holder_inherits_waiters_priority);
+
+fn inherited_priorities_stack() -> TestResult {
+    let mut cx = Context::new()?;
+    cx.base_priority = Priority::LOW;
+    cx.inherit_priority(1, Some(Priority::LOW));
+    kassert_eq!(cx.priority, Priority::LOW);
+
+    cx.inherit_priority(1, Some(Priority::HIGH));
+    cx.inherit_priority(2, Some(Priority::NORMAL));
+    kassert_eq!(cx.priority, Priority::HIGH);
+    // a lower priority never lowers it below its own
+    cx.inherit_priority(3, Some(Priority::IDLE));
+    kassert_eq!(cx.priority, Priority::HIGH);
+
+    cx.inherit_priority(1, None);
+    kassert_eq!(cx.priority, Priority::NORMAL);
+    cx.inherit_priority(2, None);
+    cx.inherit_priority(3, None);
+    kassert_eq!(cx.priority, Priority::LOW);
+    Ok(())
+}
+
+/// Between the holder's priority and the waiter's, so it would keep the holder from running.
+const MIDDLE: Priority = Priority(u8::midpoint(Priority::LOW.0, Priority::NORMAL.0));
+/// How long the middle task hogs the CPU for, if nothing stops it.
+const HOG_TIME: Duration = Duration::from_millis(200);
+
+static LOCK: Mutex<()> = Mutex::new(());
+static HELD: AtomicBool = AtomicBool::new(false);
+static GO: AtomicBool = AtomicBool::new(false);
+static STOP: AtomicBool = AtomicBool::new(false);
+static HOG_DONE: AtomicBool = AtomicBool::new(false);
+static HOLDER_PRIORITY: AtomicU8 = AtomicU8::new(0);
+static HOGGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn holder_task() {
+    let guard = LOCK.lock();
+    HELD.store(true, Ordering::Release);
+    while !GO.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    if let Some(cx) = context::current() {
+        let priority = with_irqs_disabled(|| cx.read().priority);
+        HOLDER_PRIORITY.store(priority.0, Ordering::Release);
+    }
+    drop(guard);
+    context::exit_current();
+}
+
+extern "C" fn hog_task() {
+    let deadline = time::uptime() + HOG_TIME;
+    while !STOP.load(Ordering::Acquire) && time::uptime() < deadline {
+        core::hint::spin_loop();
+    }
+    HOG_DONE.store(true, Ordering::Release);
+    context::exit_current();
+}
+
+extern "C" fn waiter_task() {
+    let guard = LOCK.lock();
+    HOGGED.store(HOG_DONE.load(Ordering::Acquire), Ordering::Release);
+    drop(guard);
+    STOP.store(true, Ordering::Release);
+    context::exit_current();
+}
+
+/// A low-priority task holds the lock a high-priority one wants, while a task in between
+/// hogs the CPU. Inheriting the waiter's priority, the holder runs anyway and releases it.
+fn holder_inherits_waiters_priority() -> TestResult {
+    for flag in [&HELD, &GO, &STOP, &HOG_DONE, &HOGGED] {
+        flag.store(false, Ordering::Release);
+    }
+    HOLDER_PRIORITY.store(0, Ordering::Release);
+
+    let holder = SpawnBuilder::new()
+        .name("pi_holder")
+        .priority(Priority::LOW)
+        .waitable(true)
+        .spawn(holder_task)?;
+    while !HELD.load(Ordering::Acquire) {
+        timer::sleep(Duration::from_millis(1));
+    }
+
+    let hog = SpawnBuilder::new()
+        .name("pi_hog")
+        .priority(MIDDLE)
+        .waitable(true)
+        .spawn(hog_task)?;
+    let waiter = SpawnBuilder::new()
+        .name("pi_waiter")
+        .priority(Priority::HIGH)
+        .waitable(true)
+        .spawn(waiter_task)?;
+    // the waiter outranks the rest, so it's blocked on the lock before the holder sees this
+    GO.store(true, Ordering::Release);
+
+    for task in [waiter, holder, hog] {
+        let pid = with_irqs_disabled(|| task.read().pid);
+        reap::wait(Some(pid), false)?;
+    }
+    kassert_eq!(
+        Priority(HOLDER_PRIORITY.load(Ordering::Acquire)),
+        Priority::HIGH
+    );
+    kassert!(!HOGGED.load(Ordering::Acquire));
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Tests for per-CPU variables.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::{Arch, Architecture},
+    irq::MAX_CPUS,
+    percpu::percpu,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(cell_accessors, variables_are_separate, for_cpu_is_current);
+
+percpu! {
+    static COUNT: Cell<u64> = Cell::new(7);
+}
+
+percpu! {
+    static OTHER: Cell<u64> = Cell::new(0);
+}
+
+percpu! {
+    static SHARED: AtomicU64 = AtomicU64::new(0);
+}
+
+fn cell_accessors() -> TestResult {
+    kassert_eq!(COUNT.replace(1), 7);
+    COUNT.with(|count| count.set(count.get() + 1));
+    kassert_eq!(COUNT.get(), 2);
+    COUNT.set(3);
+    kassert_eq!(COUNT.get(), 3);
+    Ok(())
+}
+
+fn variables_are_separate() -> TestResult {
+    COUNT.set(10);
+    OTHER.set(20);
+    kassert_eq!(COUNT.get(), 10);
+    kassert_eq!(OTHER.get(), 20);
+    Ok(())
+}
+
+fn for_cpu_is_current() -> TestResult {
+    SHARED.with(|value| value.store(5, Ordering::Relaxed));
+    let copy = SHARED.for_cpu(Arch::cpu_id());
+    kassert!(copy.is_some_and(|value| value.load(Ordering::Relaxed) == 5));
+    kassert!(SHARED.for_cpu(MAX_CPUS).is_none());
+    Ok(())
+}
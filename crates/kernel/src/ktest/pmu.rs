@@ -0,0 +1,23 @@
+//! Tests for the performance counters.
+
+use core::hint::black_box;
+
+use crate::arch::pmu::{self, Event};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(measure_counts_cycles);
+
+fn measure_counts_cycles() -> TestResult {
+    let (sum, counted) = pmu::measure(|| (0..10_000u64).map(black_box).sum::<u64>());
+    kassert_eq!(sum, 49_995_000);
+    // not every core QEMU emulates has a PMU
+    let Some(counted) = counted else {
+        return Ok(());
+    };
+    kassert!(counted.cycles > 0);
+    if let Some(instructions) = counted.count(Event::InstRetired) {
+        kassert!(instructions >= 10_000);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Tests for collecting and freeing tasks that have exited.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use kados_abi::{signal::SIGTERM, syscall::ExitStatus};
+
+use crate::{
+    sync::with_irqs_disabled,
+    syscall::errno::Errno,
+    task::{
+        SpawnBuilder,
+        context::{self, Pid},
+        reap, signal,
+    },
+    timer,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    wait_collects_exit_code,
+    wait_collects_signal,
+    wait_needs_a_child,
+    nohang_doesnt_wait,
+    orphan_is_reaped,
+);
+
+extern "C" fn exit_seven_task() {
+    context::exit_current_with(ExitStatus::Exited(7));
+}
+
+fn wait_collects_exit_code() -> TestResult {
+    let cx = SpawnBuilder::new()
+        .name("exit_seven")
+        .waitable(true)
+        .spawn(exit_seven_task)?;
+    let pid = with_irqs_disabled(|| cx.read().pid);
+
+    kassert_eq!(
+        reap::wait(Some(pid), false)?,
+        Some((pid, ExitStatus::Exited(7)))
+    );
+    kassert!(with_irqs_disabled(|| cx.read().kstack.is_none()));
+    kassert!(context::find(pid).is_none());
+    kassert_eq!(reap::wait(Some(pid), true).err(), Some(Errno::ECHILD));
+    Ok(())
+}
+
+extern "C" fn terminated_task() {
+    signal::terminate(SIGTERM);
+}
+
+fn wait_collects_signal() -> TestResult {
+    let cx = SpawnBuilder::new()
+        .name("terminated")
+        .waitable(true)
+        .spawn(terminated_task)?;
+    let pid = with_irqs_disabled(|| cx.read().pid);
+
+    kassert_eq!(
+        reap::wait(None, false)?,
+        Some((pid, ExitStatus::Signaled(SIGTERM)))
+    );
+    Ok(())
+}
+
+fn wait_needs_a_child() -> TestResult {
+    kassert_eq!(reap::wait(None, true).err(), Some(Errno::ECHILD));
+    // the boot context is nobody's child
+    kassert_eq!(
+        reap::wait(Some(Pid::from_raw(0)), false).err(),
+        Some(Errno::ECHILD)
+    );
+    Ok(())
+}
+
+static RELEASE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn held_task() {
+    while !RELEASE.load(Ordering::Acquire) {
+        timer::sleep(Duration::from_millis(1));
+    }
+    context::exit_current();
+}
+
+fn nohang_doesnt_wait() -> TestResult {
+    RELEASE.store(false, Ordering::Release);
+    let cx = SpawnBuilder::new()
+        .name("held")
+        .waitable(true)
+        .spawn(held_task)?;
+    let pid = with_irqs_disabled(|| cx.read().pid);
+
+    kassert_eq!(reap::wait(Some(pid), true)?, None);
+    RELEASE.store(true, Ordering::Release);
+    kassert_eq!(
+        reap::wait(Some(pid), false)?,
+        Some((pid, ExitStatus::Exited(0)))
+    );
+    Ok(())
+}
+
+extern "C" fn orphan_task() {
+    context::exit_current();
+}
+
+fn orphan_is_reaped() -> TestResult {
+    let cx = SpawnBuilder::new().name("orphan").spawn(orphan_task)?;
+    let pid = with_irqs_disabled(|| cx.read().pid);
+
+    for _ in 0..100 {
+        if context::find(pid).is_none() {
+            break;
+        }
+        timer::sleep(Duration::from_millis(10));
+    }
+    kassert!(context::find(pid).is_none());
+    kassert!(with_irqs_disabled(|| cx.read().kstack.is_none()));
+    kassert_eq!(reap::wait(Some(pid), true).err(), Some(Errno::ECHILD));
+    Ok(())
+}
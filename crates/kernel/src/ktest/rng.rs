@@ -0,0 +1,42 @@
+//! Tests for the kernel's random number generator.
+
+use crate::rng::{self, BLOCK_SIZE, chacha20_block};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(chacha20_zero_key, fill_bytes_differs, next_u64_differs);
+
+/// The first test vector of RFC 8439's appendix A.1: all-zero key, nonce and counter.
+fn chacha20_zero_key() -> TestResult {
+    const EXPECTED: [u8; BLOCK_SIZE] = [
+        0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86, 0xbd,
+        0x28, 0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a, 0xa8, 0x36, 0xef, 0xcc, 0x8b, 0x77,
+        0x0d, 0xc7, 0xda, 0x41, 0x59, 0x7c, 0x51, 0x57, 0x48, 0x8d, 0x77, 0x24, 0xe0, 0x3f, 0xb8,
+        0xd8, 0x4a, 0x37, 0x6a, 0x43, 0xb8, 0xf4, 0x15, 0x18, 0xa1, 0x1c, 0xc3, 0x87, 0xb6, 0x69,
+        0xb2, 0xee, 0x65, 0x86,
+    ];
+    let block = chacha20_block(&[0; 8], 0);
+    for (i, word) in block.into_iter().enumerate() {
+        kassert_eq!(word.to_le_bytes(), EXPECTED[i * 4..i * 4 + 4]);
+    }
+    Ok(())
+}
+
+fn fill_bytes_differs() -> TestResult {
+    let mut a = [0; 100];
+    let mut b = [0; 100];
+    rng::fill_bytes(&mut a);
+    rng::fill_bytes(&mut b);
+    kassert!(a != b);
+    kassert!(a.iter().any(|&byte| byte != 0));
+    // past the first block, too
+    kassert!(a[BLOCK_SIZE..] != b[BLOCK_SIZE..]);
+    Ok(())
+}
+
+fn next_u64_differs() -> TestResult {
+    let a = rng::next_u64();
+    let b = rng::next_u64();
+    kassert!(a != b);
+    Ok(())
+}
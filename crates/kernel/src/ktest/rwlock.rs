@@ -0,0 +1,76 @@
+//! Tests for the reader-writer spinlock.
+
+use crate::{
+    arch::{Arch, Architecture},
+    sync::{RwLock, with_irqs_disabled},
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    readers_share,
+    writer_excludes,
+    tickets_in_order,
+    guards_restore_interrupts
+);
+
+fn readers_share() -> TestResult {
+    let lock = RwLock::new(1);
+    let first = lock.read();
+    let second = lock.try_read();
+    kassert!(second.is_some());
+    kassert!(lock.try_write().is_none());
+    kassert_eq!(*first + *second.unwrap(), 2);
+    drop(first);
+    kassert!(!lock.is_locked());
+    Ok(())
+}
+
+fn writer_excludes() -> TestResult {
+    let lock = RwLock::new(0);
+    {
+        let mut guard = lock.write();
+        *guard = 5;
+        kassert!(lock.try_read().is_none());
+        kassert!(lock.try_write().is_none());
+    }
+    kassert_eq!(*lock.try_read().unwrap(), 5);
+    kassert_eq!(*lock.read(), 5);
+    Ok(())
+}
+
+/// Runs enough reads and writes through to wrap the 16-bit tickets.
+fn tickets_in_order() -> TestResult {
+    let lock = RwLock::new(0u32);
+    for i in 0..70_000 {
+        if i % 3 == 0 {
+            *lock.write() += 1;
+        } else {
+            kassert_eq!(*lock.read(), i / 3 + 1);
+        }
+    }
+    kassert!(!lock.is_locked());
+    Ok(())
+}
+
+fn guards_restore_interrupts() -> TestResult {
+    let lock = RwLock::new(());
+    let enabled = unsafe { Arch::interrupts_enabled() };
+    {
+        let _guard = lock.read();
+        kassert!(!unsafe { Arch::interrupts_enabled() });
+    }
+    kassert_eq!(unsafe { Arch::interrupts_enabled() }, enabled);
+    {
+        let _guard = lock.write();
+        kassert!(!unsafe { Arch::interrupts_enabled() });
+    }
+    kassert_eq!(unsafe { Arch::interrupts_enabled() }, enabled);
+    // and a failed try leaves them as they were
+    with_irqs_disabled(|| {
+        let _guard = lock.write();
+        lock.try_read().is_none()
+    });
+    kassert_eq!(unsafe { Arch::interrupts_enabled() }, enabled);
+    Ok(())
+}
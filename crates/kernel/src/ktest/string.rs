@@ -0,0 +1,143 @@
+//! Tests for `memcpy`, `memmove` and `memset`, and how fast they are.
+
+use core::time::Duration;
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    arch::string::{memcpy, memmove, memset},
+    serial_println, time,
+};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    memcpy_alignments,
+    memmove_overlapping,
+    memset_alignments,
+    throughput
+);
+
+/// Lengths either side of each path's thresholds: words, blocks and `DC ZVA`.
+const LENGTHS: &[usize] = &[
+    0, 1, 7, 8, 9, 15, 16, 17, 63, 64, 65, 127, 128, 200, 255, 256, 257, 1000, 4099,
+];
+/// Bytes either side of the copied or filled range, checked to be left alone.
+const GUARD: usize = 16;
+/// How much the throughput test moves in each call.
+const BENCH_SIZE: usize = 1024 * 1024;
+const BENCH_ROUNDS: usize = 16;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+fn memcpy_alignments() -> TestResult {
+    let max = LENGTHS.iter().max().unwrap() + 2 * GUARD + 8;
+    let src = pattern(max);
+    for &len in LENGTHS {
+        for src_offset in 0..8 {
+            for dst_offset in 0..8 {
+                let mut dst = vec![0xaa; max];
+                let (from, to) = (GUARD + src_offset, GUARD + dst_offset);
+                unsafe {
+                    memcpy(
+                        dst[to..].as_mut_ptr().cast(),
+                        src[from..].as_ptr().cast(),
+                        len,
+                    )
+                };
+                kassert_eq!(dst[to..to + len], src[from..from + len]);
+                kassert!(dst[..to].iter().all(|&byte| byte == 0xaa));
+                kassert!(dst[to + len..].iter().all(|&byte| byte == 0xaa));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn memmove_overlapping() -> TestResult {
+    let max = LENGTHS.iter().max().unwrap() + 2 * GUARD + 160;
+    for &len in LENGTHS {
+        for from in (GUARD..GUARD + 80).step_by(3) {
+            for to in (GUARD..GUARD + 80).step_by(5) {
+                let mut buf = pattern(max);
+                let mut expected = buf.clone();
+                expected.copy_within(from..from + len, to);
+                unsafe {
+                    memmove(
+                        buf[to..].as_mut_ptr().cast(),
+                        buf[from..].as_ptr().cast(),
+                        len,
+                    )
+                };
+                kassert!(buf == expected);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn memset_alignments() -> TestResult {
+    let max = LENGTHS.iter().max().unwrap() + 2 * GUARD + 8;
+    for byte in [0, 0x5a, 0xff] {
+        for &len in LENGTHS.iter().chain(&[8192, 12345]) {
+            for offset in 0..8 {
+                let mut dst = pattern(max.max(len + 2 * GUARD + 8));
+                let expected = dst.clone();
+                let at = GUARD + offset;
+                unsafe { memset(dst[at..].as_mut_ptr().cast(), i32::from(byte), len) };
+                kassert!(dst[at..at + len].iter().all(|&b| b == byte));
+                kassert_eq!(dst[..at], expected[..at]);
+                kassert_eq!(dst[at + len..], expected[at + len..]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` [`BENCH_ROUNDS`] times, and returns how many MiB it got through a second.
+fn mib_per_sec(mut f: impl FnMut()) -> u128 {
+    let start = time::uptime();
+    for _ in 0..BENCH_ROUNDS {
+        f();
+    }
+    let elapsed = time::uptime()
+        .saturating_sub(start)
+        .max(Duration::from_nanos(1));
+    (BENCH_SIZE * BENCH_ROUNDS) as u128 * 1_000_000_000 / elapsed.as_nanos() / (1024 * 1024)
+}
+
+/// Reports each function's throughput on a megabyte. Nothing is asserted of them, since under
+/// emulation they say little.
+fn throughput() -> TestResult {
+    let src = pattern(BENCH_SIZE);
+    let mut dst = vec![0; BENCH_SIZE];
+    let copy = mib_per_sec(|| unsafe {
+        memcpy(dst.as_mut_ptr().cast(), src.as_ptr().cast(), BENCH_SIZE);
+    });
+    let misaligned = mib_per_sec(|| unsafe {
+        memcpy(
+            dst.as_mut_ptr().cast(),
+            src[3..].as_ptr().cast(),
+            BENCH_SIZE - 3,
+        );
+    });
+    let fill = mib_per_sec(|| unsafe {
+        memset(dst.as_mut_ptr().cast(), 0x5a, BENCH_SIZE);
+    });
+    let zero = mib_per_sec(|| unsafe {
+        memset(dst.as_mut_ptr().cast(), 0, BENCH_SIZE);
+    });
+    let bytes = mib_per_sec(|| {
+        for (d, s) in dst.iter_mut().zip(&src) {
+            unsafe { core::ptr::write_volatile(d, *s) };
+        }
+    });
+    serial_println!(
+        "ktest:     memcpy {copy} MiB/s ({misaligned} misaligned), memset {fill} MiB/s \
+         ({zero} zeroing), byte loop {bytes} MiB/s"
+    );
+    kassert!(dst.iter().zip(&src).all(|(d, s)| d == s));
+    Ok(())
+}
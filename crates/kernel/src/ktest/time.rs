@@ -0,0 +1,60 @@
+//! Tests for the wall clock.
+
+use core::time::Duration;
+
+use crate::time::wall::{self, DateTime};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(
+    date_time_epoch,
+    date_time_leap_day,
+    date_time_end_of_year,
+    now_advances
+);
+
+fn date(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+fn date_time_epoch() -> TestResult {
+    kassert_eq!(
+        DateTime::from_unix(Duration::ZERO),
+        date(1970, 1, 1, 0, 0, 0)
+    );
+    Ok(())
+}
+
+fn date_time_leap_day() -> TestResult {
+    kassert_eq!(
+        DateTime::from_unix(Duration::from_secs(951_804_428)),
+        date(2000, 2, 29, 6, 7, 8)
+    );
+    kassert_eq!(
+        DateTime::from_unix(Duration::from_secs(1_709_210_096)),
+        date(2024, 2, 29, 12, 34, 56)
+    );
+    Ok(())
+}
+
+fn date_time_end_of_year() -> TestResult {
+    kassert_eq!(
+        DateTime::from_unix(Duration::from_secs(1_767_225_599)),
+        date(2025, 12, 31, 23, 59, 59)
+    );
+    Ok(())
+}
+
+fn now_advances() -> TestResult {
+    let a = wall::now();
+    crate::arch::time::spin_for(Duration::from_millis(1));
+    kassert!(wall::now() > a);
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! Tests for the event trace.
+
+use crate::trace::{self, RING_SIZE, trace_syscall, trace_syscall_exit};
+
+use super::{TestResult, kassert, kassert_eq, tests};
+
+tests!(records_only_while_enabled);
+
+fn records_only_while_enabled() -> TestResult {
+    let was_enabled = trace::is_enabled();
+    trace::stop();
+    trace::clear()?;
+    trace::start();
+    trace_syscall(usize::MAX, 1);
+    trace_syscall_exit(usize::MAX, 2);
+    trace::stop();
+    // IRQs may have been traced in between, too
+    let recorded = trace::recorded();
+    kassert!(recorded >= 2);
+
+    trace_syscall(usize::MAX, 3);
+    kassert_eq!(trace::recorded(), recorded);
+    kassert!(recorded <= RING_SIZE * crate::irq::MAX_CPUS);
+
+    trace::clear()?;
+    kassert_eq!(trace::recorded(), 0);
+    if was_enabled {
+        trace::start();
+    }
+    Ok(())
+}
@@ -1,16 +1,209 @@
-use core::fmt::Write;
+//! The kernel's logger.
+//!
+//! Logging a message only formats it into a slot of a lock-free queue, so it's safe from any
+//! context and never waits on the UART or framebuffer. Once the scheduler is up, the `klogd`
+//! task writes the queue out to the consoles; before then, and after a panic, whoever logs a
+//! message writes the queue out itself. Messages that don't fit in the queue are dropped and
+//! counted, and the count is logged when there's room again.
+//!
+//! Until [`init`] has run, messages go straight to the [early console](earlycon) instead,
+//! since the consoles and the per-CPU state the queue's prefixes need may not be set up yet.
 
-use alloc::format;
+use core::{
+    cell::UnsafeCell,
+    cmp,
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use arrayvec::ArrayString;
 use embedded_graphics::prelude::{RgbColor, WebColors};
 
 use crate::{
-    arch::serial::lock_uart,
+    arch::{
+        earlycon,
+        serial::{UartKind, force_lock_uart, lock_uart},
+    },
+    cmdline,
     framebuffer::{Color, with_fb},
-    task::context,
+    sync::IrqMutex,
+    syscall::errno::Errno,
+    task::{SpawnBuilder, context},
+    timer,
     util::DebugCheckedPanic,
 };
 
-/// A logger that writes log messages to the serial console and framebuffer.
+/// Whether log messages go to the serial console.
+static TO_SERIAL: AtomicBool = AtomicBool::new(true);
+/// Whether log messages go to the framebuffer.
+static TO_FB: AtomicBool = AtomicBool::new(true);
+
+/// How much of the most recent log output is kept for crash reports.
+const RING_SIZE: usize = 16 * 1024;
+
+/// The most recent log output, without colors, overwriting the oldest once it's full.
+struct LogRing {
+    buf: [u8; RING_SIZE],
+    /// Where the next byte goes.
+    head: usize,
+    /// Whether `buf` has filled up, so the oldest output starts at `head`.
+    wrapped: bool,
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &b in s.as_bytes() {
+            self.buf[self.head] = b;
+            self.head += 1;
+            if self.head == RING_SIZE {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+static RING: IrqMutex<LogRing> = IrqMutex::new(LogRing {
+    buf: [0; RING_SIZE],
+    head: 0,
+    wrapped: false,
+});
+
+/// How many messages can wait to be written before new ones are dropped.
+const QUEUE_SLOTS: usize = 256;
+/// The longest a message can be; anything past it is cut off.
+const MESSAGE_LEN: usize = 256;
+/// How often the drain task writes out the queue.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A message waiting in the queue.
+struct Slot {
+    /// Twice the lap of the queue the slot is free for, plus one once a message for that lap
+    /// has been written to it.
+    seq: AtomicUsize,
+    level: UnsafeCell<log::Level>,
+    text: UnsafeCell<ArrayString<MESSAGE_LEN>>,
+}
+
+/// A bounded lock-free queue of formatted messages, with any number of producers and a single
+/// consumer, which must have set [`DRAINING`].
+///
+/// Producers never wait on anything, so a message can be logged from anywhere, including an
+/// interrupt handler that interrupted the drain or a debugger stop in the middle of a write.
+struct Queue {
+    slots: [Slot; QUEUE_SLOTS],
+    /// The position of the next message to be read.
+    head: AtomicUsize,
+    /// The position the next message is written to.
+    tail: AtomicUsize,
+}
+
+// a slot's contents are only touched by the producer that claimed it, until it's published
+// with `seq`, and then by the consumer
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    // only evaluated at compile time, for `QUEUE`
+    #[allow(clippy::large_stack_arrays)]
+    const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    seq: AtomicUsize::new(0),
+                    level: UnsafeCell::new(log::Level::Info),
+                    text: UnsafeCell::new(ArrayString::new_const()),
+                }
+            }; QUEUE_SLOTS],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds a message, formatted by `write`, returning `false` if the queue is full.
+    fn push(&self, level: log::Level, write: impl FnOnce(&mut Truncating)) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos % QUEUE_SLOTS];
+            let free = 2 * (pos / QUEUE_SLOTS);
+            match slot.seq.load(Ordering::Acquire).cmp(&free) {
+                cmp::Ordering::Equal => {
+                    match self.tail.compare_exchange_weak(
+                        pos,
+                        pos + 1,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break slot,
+                        Err(actual) => pos = actual,
+                    }
+                }
+                // still holds the message from the last lap
+                cmp::Ordering::Less => return false,
+                // claimed by another producer since `pos` was read
+                cmp::Ordering::Greater => pos = self.tail.load(Ordering::Relaxed),
+            }
+        };
+
+        // claimed above, and not published until `seq` is bumped
+        unsafe {
+            *slot.level.get() = level;
+            let text = &mut *slot.text.get();
+            text.clear();
+            write(&mut Truncating(text));
+        }
+        slot.seq
+            .store(2 * (pos / QUEUE_SLOTS) + 1, Ordering::Release);
+        true
+    }
+
+    /// Passes the oldest message to `f` and removes it, returning `false` if there's none.
+    ///
+    /// Only to be called by whoever set [`DRAINING`].
+    fn pop(&self, f: impl FnOnce(log::Level, &str)) -> bool {
+        let pos = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[pos % QUEUE_SLOTS];
+        let lap = pos / QUEUE_SLOTS;
+        if slot.seq.load(Ordering::Acquire) != 2 * lap + 1 {
+            // empty, or its producer is still writing it
+            return false;
+        }
+        // published by its producer, and only read by the one consumer
+        unsafe { f(*slot.level.get(), &*slot.text.get()) };
+        slot.seq.store(2 * (lap + 1), Ordering::Release);
+        self.head.store(pos + 1, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Formats into an [`ArrayString`], cutting off whatever doesn't fit.
+struct Truncating<'a>(&'a mut ArrayString<MESSAGE_LEN>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut end = s.len().min(self.0.remaining_capacity());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        Ok(())
+    }
+}
+
+static QUEUE: Queue = Queue::new();
+/// Set by whoever is draining [`QUEUE`]. Not a lock, since a message logged by an interrupt
+/// handler that interrupted the drain has to be left for it, not waited on.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+/// How many messages were dropped because the queue was full, since that was last reported.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Set once the drain task is running, after which messages are left for it. Until then,
+/// and after a panic, whoever logs a message writes it out.
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+/// Set once [`init`] has run, before which messages go to the early console.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// A logger that queues messages for the serial console and framebuffer.
 pub struct Logger;
 
 impl log::Log for Logger {
@@ -19,28 +212,77 @@ impl log::Log for Logger {
         true
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        drain();
+    }
 
     fn log(&self, record: &log::Record) {
         let level = record.level();
-        let uptime = crate::time::uptime();
-        let uptime_secs = uptime.as_secs();
-        let uptime_subsec_nanos = uptime.subsec_nanos();
-        let pid = match context::current() {
-            Some(cx) => match cx.try_read() {
-                Some(cx) => &format!("[{}]", cx.pid),
-                None => "[-]",
-            },
-            None => "[-]",
-        };
+        if !INITIALIZED.load(Ordering::Acquire) {
+            earlycon::write_fmt(format_args!("[{}] {}\n", level_name(level), record.args()));
+            return;
+        }
+        let queued = QUEUE.push(level, |out| {
+            let uptime = crate::time::uptime();
+            write!(out, "[{}.{:09}] ", uptime.as_secs(), uptime.subsec_nanos()).ok();
+            match context::current().as_ref().and_then(|cx| cx.try_read()) {
+                Some(cx) => write!(out, "[{}]", cx.pid).ok(),
+                None => out.write_str("[-]").ok(),
+            };
+            if level <= log::Level::Warn {
+                write!(out, " [{}", record.file().unwrap_or("??")).ok();
+            } else {
+                let target = record.target().split("::").last().unwrap_or("??");
+                write!(out, " [{target}").ok();
+            }
+            let line = record.line().unwrap_or_default();
+            write!(out, ":{line}] {}", record.args()).ok();
+        });
+        if !queued {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
 
-        let level_str = match level {
-            log::Level::Error => "ERR",
-            log::Level::Warn => "WRN",
-            log::Level::Info => "INF",
-            log::Level::Debug => "DBG",
-            log::Level::Trace => "TRC",
-        };
+        if !DEFERRED.load(Ordering::Acquire) {
+            drain();
+        }
+    }
+}
+
+/// Writes out every queued message, unless someone else already is.
+fn drain() {
+    if DRAINING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    loop {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            let mut text = ArrayString::<64>::new();
+            write!(text, "{dropped} log messages dropped, the queue was full").ok();
+            emit(log::Level::Warn, &text);
+        }
+        if !QUEUE.pop(emit) {
+            break;
+        }
+    }
+    DRAINING.store(false, Ordering::Release);
+}
+
+/// Returns the tag messages of the given level are written with.
+fn level_name(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "ERR",
+        log::Level::Warn => "WRN",
+        log::Level::Info => "INF",
+        log::Level::Debug => "DBG",
+        log::Level::Trace => "TRC",
+    }
+}
+
+/// Writes a message to the consoles and the crash ring.
+fn emit(level: log::Level, text: &str) {
+    let level_str = level_name(level);
+
+    if TO_SERIAL.load(Ordering::Relaxed) {
         let color = match level {
             log::Level::Error => "\x1b[31m", // Red
             log::Level::Warn => "\x1b[33m",  // Yellow
@@ -50,61 +292,85 @@ impl log::Log for Logger {
         };
         let reset = "\x1b[0m"; // Reset color
         let mut uart = lock_uart();
-        let target = record.target().split("::").last().unwrap_or("??");
-        let file = record.file().unwrap_or("??");
-        let line = record.line().unwrap_or_default();
-        uart.write_fmt(format_args!(
-            "{}[{}]{} [{}.{:09}] {} [{}:{}] {}\n",
-            color,
-            level_str,
-            reset,
-            uptime_secs,
-            uptime_subsec_nanos,
-            pid,
-            if level <= log::Level::Warn {
-                file
-            } else {
-                target
-            },
-            line,
-            record.args(),
-        ))
-        .ok();
-        drop(uart);
-
-        with_fb(|fb| {
-            fb.set_text_fgcolor_default();
-            let color = match level {
-                log::Level::Error => Color::RED,
-                log::Level::Warn => Color::YELLOW,
-                log::Level::Info => Color::GREEN,
-                log::Level::Debug => Color::BLUE,
-                log::Level::Trace => Color::CSS_LIGHT_GRAY,
-            };
-            fb.set_text_fgcolor(color);
-            fb.write_fmt(format_args!("[{level_str}]")).ok();
-            fb.set_text_fgcolor_default();
-            fb.write_fmt(format_args!(
-                " [{}.{:09}] {} [{}] {}\n",
-                uptime_secs,
-                uptime_subsec_nanos,
-                pid,
-                target,
-                record.args()
-            ))
+        uart.write_fmt(format_args!("{color}[{level_str}]{reset} {text}\n"))
             .ok();
+    }
 
-            fb.clear_pixels();
-            fb.render_text_buf();
-            fb.present();
-        });
+    // never wait on the ring: a panic may have interrupted whoever holds it
+    if let Ok(mut ring) = RING.try_lock() {
+        ring.write_fmt(format_args!("[{level_str}] {text}\n")).ok();
+    }
+
+    if !TO_FB.load(Ordering::Relaxed) {
+        return;
+    }
+    with_fb(|fb| {
+        let color = match level {
+            log::Level::Error => Color::RED,
+            log::Level::Warn => Color::YELLOW,
+            log::Level::Info => Color::GREEN,
+            log::Level::Debug => Color::BLUE,
+            log::Level::Trace => Color::CSS_LIGHT_GRAY,
+        };
+        fb.set_text_fgcolor(color);
+        fb.write_fmt(format_args!("[{level_str}]")).ok();
+        fb.set_text_fgcolor_default();
+        fb.write_fmt(format_args!(" {text}\n")).ok();
+
+        fb.render_dirty_text();
+        fb.present();
+    });
+}
+
+/// Starts the task that writes out logged messages, so logging no longer waits on the
+/// consoles.
+///
+/// # Errors
+///
+/// Returns whatever spawning the task failed with, in which case messages keep being written
+/// out as they're logged.
+pub fn start_drain() -> Result<(), Errno> {
+    SpawnBuilder::new().name("klogd").spawn(drain_main)?;
+    DEFERRED.store(true, Ordering::Release);
+    Ok(())
+}
+
+extern "C" fn drain_main() {
+    loop {
+        drain();
+        timer::sleep(DRAIN_INTERVAL);
     }
 }
 
-/// Initializes the logger by setting it as the global logger and configuring the log level.
-pub fn init() {
+/// Writes out the queued messages on a panic, and has any logged after it written out right
+/// away.
+///
+/// # Safety
+///
+/// Breaks the locks on the queue and the UART, so whoever held them must never run again, as
+/// when the kernel has panicked.
+pub unsafe fn flush_on_panic() {
+    DEFERRED.store(false, Ordering::Release);
+    DRAINING.store(false, Ordering::Release);
+    drop(unsafe { force_lock_uart() });
+    drain();
+}
+
+/// Sets the logger as the global logger, writing messages to the early console at the `info`
+/// level until [`init`] is called.
+///
+/// Must be called only once, and before anything is logged.
+pub fn init_early() {
     log::set_logger(&Logger).debug_checked_expect("Failed to set logger");
-    let level_env = match option_env!("KADOS_LOG") {
+    log::set_max_level(log::LevelFilter::Info);
+}
+
+/// Configures the log level and consoles, and starts queueing messages for them.
+///
+/// The `log` and `console` options on the [`cmdline`] take precedence over `KADOS_LOG` and
+/// logging to both consoles.
+pub fn init() {
+    let level = match cmdline::get("log").or(option_env!("KADOS_LOG")) {
         Some("trace") => log::LevelFilter::Trace,
         Some("debug") => log::LevelFilter::Debug,
         // Some("info") => log::LevelFilter::Info,
@@ -113,6 +379,49 @@ pub fn init() {
         Some("off") => log::LevelFilter::Off,
         _ => log::LevelFilter::Info,
     };
-    log::set_max_level(level_env);
+    log::set_max_level(level);
+    INITIALIZED.store(true, Ordering::Release);
+
+    match cmdline::get("console") {
+        None => {}
+        Some("serial") => TO_FB.store(false, Ordering::Relaxed),
+        Some("fb") => TO_SERIAL.store(false, Ordering::Relaxed),
+        // names which UART the serial console is on; see `serial::select_console`
+        Some(console) if UartKind::from_console_option(console).is_some() => {
+            TO_FB.store(false, Ordering::Relaxed);
+        }
+        Some(console) => log::warn!("cmdline: unknown console `{console}`"),
+    }
+
     log::info!("Logger initialized");
+    if !cmdline::raw().is_empty() {
+        log::info!("Command line: {}", cmdline::raw());
+    }
+}
+
+/// Writes the most recent log output, oldest first, to `out`.
+///
+/// # Safety
+///
+/// Breaks the lock on the log if it's held, so whoever held it must never run again, as when
+/// the kernel has panicked.
+pub unsafe fn write_log_tail(out: &mut impl Write) {
+    if RING.is_locked() {
+        unsafe { RING.force_unlock() };
+    }
+    let ring = RING.lock();
+    let (older, newer) = if ring.wrapped {
+        (&ring.buf[ring.head..], &ring.buf[..ring.head])
+    } else {
+        (&[][..], &ring.buf[..ring.head])
+    };
+    for part in [older, newer] {
+        // a line cut in half by the wrap, or a multibyte character, is shown lossily
+        for chunk in part.utf8_chunks() {
+            out.write_str(chunk.valid()).ok();
+            if !chunk.invalid().is_empty() {
+                out.write_char(char::REPLACEMENT_CHARACTER).ok();
+            }
+        }
+    }
 }
@@ -14,30 +14,53 @@
 )]
 #![feature(if_let_guard, iter_next_chunk)]
 
+use core::ops::Range;
+
 use arch::{Arch, Architecture};
 use fdt::Fdt;
 use mem::paging::{
-    MemMapEntries,
+    BootTables, MemMapEntries,
     allocator::{init_kernel_frame_allocator, kernel_frame_allocator},
 };
+use mem::units::{PhysAddr, VirtAddr};
 use spin::Once;
 
 extern crate alloc;
 
 pub mod arch;
+pub mod bench;
+pub mod blank;
+pub mod block;
+pub mod cmdline;
+pub mod cpio;
 pub mod cpu_local;
+pub mod crashdump;
+pub mod elf;
 pub mod fdt;
+pub mod file;
+pub mod initrd;
+pub mod input;
+pub mod ipc;
 pub mod logging;
 pub mod syscall;
 pub mod task;
 pub mod time;
+pub mod timer;
+pub mod trace;
 #[macro_use]
 pub mod util;
 #[macro_use]
 pub mod framebuffer;
 pub mod irq;
+#[cfg(feature = "ktest")]
+pub mod ktest;
 pub mod mem;
+pub mod net;
 pub mod panicking;
+pub mod percpu;
+pub mod rng;
+pub mod shell;
+pub mod symbols;
 pub mod sync;
 
 /// Boot information structure.
@@ -46,20 +69,35 @@ pub struct BootInfo {
     /// The flattened device tree blob, if available.
     pub fdt: Option<Fdt<'static>>,
 
+    /// The physical address of the flattened device tree blob.
+    pub dtb_phys: PhysAddr,
+
+    /// Where the initrd is in physical memory, if the firmware loaded one.
+    pub initrd: Option<Range<PhysAddr>>,
+
+    /// The memory crash records are kept in, if it's usable on this machine.
+    pub crash_region: Option<Range<PhysAddr>>,
+
     /// The memory map entries determined by the bootloader.
     pub mem_map: MemMapEntries<32>,
+
+    /// The page table pool the bootloader built its tables in.
+    pub boot_tables: BootTables,
+
+    /// The command line, from the FDT's `/chosen/bootargs`.
+    pub cmdline: &'static str,
+
+    /// The framebuffer the firmware set up, if it left one.
+    pub framebuffer: Option<kados_abi::boot::BootFramebuffer>,
+
+    /// The peripheral window, if the FDT describes one.
+    pub peripherals: Option<Range<PhysAddr>>,
 }
 
 /// The boot information structure, initialized by the bootloader.
 pub static BOOT_INFO: Once<BootInfo> = Once::new();
 
-/// The offset between physical and virtual addresses when mapped linearly.
-pub const HHDM_PHYSICAL_OFFSET: usize = 0xffff_8000_0000_0000;
-
-/// The base address of the kernel in virtual memory.
-///
-/// This must match the value in the linker script.
-pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+pub use kados_abi::boot::{HHDM_PHYSICAL_OFFSET, KERNEL_OFFSET};
 
 macro_rules! elf_offsets {
     ($($name:ident),* $(,)?) => {
@@ -84,7 +122,11 @@ elf_offsets!(
     __text_end,
     __rodata_start,
     __rodata_end,
+    __ksyms_start,
+    __ksyms_end,
     __data_start,
+    __percpu_start,
+    __percpu_end,
     __data_end,
     __bss_start,
     __bss_end,
@@ -102,18 +144,43 @@ pub(crate) extern "C" fn kernel_main() -> ! {
     unsafe {
         Arch::disable_interrupts();
 
+        // inlined, since nothing still on the stack may return once signing is on
+        #[cfg(feature = "pauth")]
+        arch::pauth::init();
+
         Arch::init_pre_kernel_main();
     }
 
-    let boot_info = BOOT_INFO.get().unwrap();
+    logging::init_early();
+
+    let Some(boot_info) = BOOT_INFO.get() else {
+        early_println!("kernel_main: no boot info");
+        Arch::hcf();
+    };
 
     for _ in 0..3 {
         println!();
     }
 
+    cmdline::init(boot_info.cmdline);
+
     logging::init();
+    panicking::init();
+
+    if let Some(fdt) = &boot_info.fdt {
+        arch::serial::select_console(fdt);
+    }
 
     log::info!("kernel starting...");
+    #[cfg(feature = "pauth")]
+    log::info!(
+        "pointer authentication: {}",
+        if arch::pauth::is_enabled() {
+            "on"
+        } else {
+            "not supported"
+        }
+    );
 
     init_kernel_frame_allocator(boot_info);
 
@@ -122,6 +189,20 @@ pub(crate) extern "C" fn kernel_main() -> ! {
         mem::paging::map_memory(boot_info);
     }
 
+    log::info!("switching to the kernel stack...");
+    unsafe {
+        Arch::set_stack_pointer_post_mapping(
+            VirtAddr::new_canonical(mem::paging::KERNEL_STACK_TOP),
+            kernel_main_post_stack,
+        )
+    }
+}
+
+/// The second half of [`kernel_main`], running on the kernel's own stack.
+#[allow(clippy::too_many_lines)] // the boot sequence, which reads best in one place
+extern "C" fn kernel_main_post_stack() -> ! {
+    let boot_info = BOOT_INFO.get().unwrap();
+
     log::info!("initializing interrupts...");
 
     unsafe {
@@ -136,35 +217,114 @@ pub(crate) extern "C" fn kernel_main() -> ! {
     log::info!("initializing frame allocator (post-heap)...");
     kernel_frame_allocator().convert_post_heap().unwrap();
 
+    log::info!("reclaiming boot page tables...");
+    unsafe {
+        mem::paging::reclaim_boot_tables(boot_info);
+    }
+
+    log::info!("reclaiming boot stack...");
+    unsafe {
+        mem::paging::reclaim_boot_stack();
+    }
+
     log::info!("initializing device tree...");
     let fdt = boot_info.fdt.as_ref().unwrap();
     fdt::init(fdt);
 
+    log::info!("finding initrd...");
+    initrd::init(boot_info);
+
+    log::info!("checking for a crash record...");
+    crashdump::init(boot_info);
+
+    log::info!("probing firmware interface...");
+    arch::psci::init(fdt);
+
     log::info!("initializing irq chip...");
     irq::init(fdt);
 
-    log::info!("initializing per-cpu structure...");
-    unsafe {
-        Arch::init_cpu_local_block();
-    }
+    log::info!("initializing per-cpu variables...");
+    percpu::init_cpu().unwrap();
 
     log::info!("initializing timer...");
     arch::time::init(fdt);
+    time::wall::init();
+    trace::init();
+
+    log::info!("initializing performance counters...");
+    arch::pmu::init();
 
     log::info!("running init hooks (post-heap)...");
     unsafe {
         Arch::init_drivers();
     }
 
+    // inlined, since nothing still on the stack may return once the guard changes
+    #[cfg(feature = "stack-protector")]
+    unsafe {
+        arch::stack_protector::init();
+    }
+
     log::info!("initializing framebuffer...");
+    if let Some(fb) = &boot_info.framebuffer
+        && let Err(e) = crate::framebuffer::adopt_boot_framebuffer(fb)
+    {
+        log::warn!("can't use the firmware's framebuffer: {e:?}");
+    }
     crate::framebuffer::init();
 
+    log::info!("protecting kernel mappings...");
+    mem::protect::init(boot_info);
+
     log::info!("initializing task contexts...");
     task::context::init();
+    task::cpus::set_online();
+
+    log::info!("starting log drain...");
+    if let Err(e) = logging::start_drain() {
+        log::warn!("failed to start log drain: {e:?}");
+    }
+
+    log::info!("starting worker tasks...");
+    if let Err(e) = task::workqueue::init() {
+        log::warn!("failed to start worker tasks: {e:?}");
+    }
+
+    log::info!("starting reaper...");
+    if let Err(e) = task::reap::init() {
+        log::warn!("failed to start reaper: {e:?}");
+    }
 
     log::info!("spawning first task...");
+    if let Err(e) = task::exec::spawn_init() {
+        log::warn!("failed to run init: {e:?}");
+        task::SpawnBuilder::new().name("test").spawn(test).unwrap();
+    }
 
-    task::spawn(false, test).unwrap();
+    input::init();
+    task::cpus::init();
+
+    if let Err(e) = shell::spawn() {
+        log::warn!("failed to start debug shell: {e:?}");
+    }
+
+    if let Err(e) = blank::init() {
+        log::warn!("failed to start console blanking: {e:?}");
+    }
+
+    if let Err(e) = bench::init() {
+        log::warn!("failed to set up benchmarks: {e:?}");
+    }
+
+    #[cfg(feature = "ktest")]
+    if cmdline::enabled("ktest", true) {
+        if let Err(e) = ktest::init() {
+            log::error!("failed to start kernel tests: {e:?}");
+            Arch::exit_qemu(1)
+        }
+    } else {
+        log::warn!("kernel tests disabled on the command line");
+    }
 
     #[rustfmt::skip]
     println!(
@@ -184,7 +344,26 @@ welcome to...
 
     unsafe { Arch::enable_interrupts() }
 
-    Arch::hcf()
+    idle()
+}
+
+/// The idle loop, run by the boot context once everything is up.
+fn idle() -> ! {
+    if !irq::is_polled() {
+        loop {
+            if task::cpus::is_parked(Arch::cpu_id()) {
+                Arch::wait_for_interrupt();
+            } else {
+                Arch::halt();
+            }
+        }
+    }
+
+    log::warn!("no interrupt controller; polling devices from the idle loop");
+    loop {
+        irq::poll();
+        core::hint::spin_loop();
+    }
 }
 
 extern "C" fn test() {
@@ -235,3 +414,19 @@ macro_rules! serial_println {
         let _ = $crate::arch::serial::write_fmt(format_args!("\n"));
     });
 }
+
+/// Prints a formatted string to the early console, which works before the serial console
+/// is set up and after it's broken.
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ({
+        $crate::arch::earlycon::write_fmt(format_args!($($arg)*));
+    });
+}
+
+/// Prints a formatted string to the early console, followed by a newline.
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,164 @@
+//! Deterministic fault injection for the frame allocator and the kernel heap.
+//!
+//! Allocation failures are rare enough that the code handling them is hardly ever run. Arming
+//! an allocator's [`FaultInjector`] makes it fail allocations on a fixed schedule, so the
+//! error paths can be exercised on purpose: either every Nth allocation, or every allocation
+//! once a budget of bytes has been handed out. It's armed and disarmed at runtime with the
+//! shell's `fault` command.
+//!
+//! An injected failure counts as survived once the same allocator is next called, since the
+//! kernel evidently carried on. If the kernel panics with one outstanding, the panic handler
+//! says so.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::println;
+
+/// Fault injection for [`FrameAllocator`](super::paging::allocator::FrameAllocator).
+pub static FRAMES: FaultInjector = FaultInjector::new("frames");
+/// Fault injection for the kernel heap.
+pub static HEAP: FaultInjector = FaultInjector::new("heap");
+
+/// When a [`FaultInjector`] fails allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultMode {
+    /// Never.
+    Off,
+    /// Every Nth allocation since it was armed.
+    EveryNth(usize),
+    /// Every allocation once this many bytes have been allocated since it was armed.
+    AfterBytes(usize),
+}
+
+impl fmt::Display for FaultMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::EveryNth(n) => write!(f, "every {n}"),
+            Self::AfterBytes(budget) => write!(f, "after {budget} bytes"),
+        }
+    }
+}
+
+const MODE_OFF: u8 = 0;
+const MODE_EVERY_NTH: u8 = 1;
+const MODE_AFTER_BYTES: u8 = 2;
+
+/// Fails an allocator's allocations on a schedule, and counts the failures.
+///
+/// Everything is atomic, since allocators consult it with their locks held and interrupts
+/// disabled.
+pub struct FaultInjector {
+    name: &'static str,
+    mode: AtomicU8,
+    /// The N of [`FaultMode::EveryNth`] or the budget of [`FaultMode::AfterBytes`].
+    param: AtomicUsize,
+    /// The allocations or bytes seen since the injector was armed, depending on the mode.
+    seen: AtomicUsize,
+    injected: AtomicU64,
+    survived: AtomicU64,
+    /// Set while the last injected failure hasn't been survived yet.
+    outstanding: AtomicBool,
+}
+
+impl FaultInjector {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            mode: AtomicU8::new(MODE_OFF),
+            param: AtomicUsize::new(0),
+            seen: AtomicUsize::new(0),
+            injected: AtomicU64::new(0),
+            survived: AtomicU64::new(0),
+            outstanding: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the name of the allocator the injector is for.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Switches to failing allocations according to `mode`, starting the count afresh.
+    pub fn arm(&self, mode: FaultMode) {
+        let (mode, param) = match mode {
+            FaultMode::Off => (MODE_OFF, 0),
+            FaultMode::EveryNth(n) => (MODE_EVERY_NTH, n.max(1)),
+            FaultMode::AfterBytes(budget) => (MODE_AFTER_BYTES, budget),
+        };
+        self.mode.store(MODE_OFF, Ordering::Relaxed);
+        self.seen.store(0, Ordering::Relaxed);
+        self.param.store(param, Ordering::Relaxed);
+        self.mode.store(mode, Ordering::Release);
+    }
+
+    /// Returns when the injector fails allocations.
+    #[must_use]
+    pub fn mode(&self) -> FaultMode {
+        let param = self.param.load(Ordering::Relaxed);
+        match self.mode.load(Ordering::Acquire) {
+            MODE_EVERY_NTH => FaultMode::EveryNth(param),
+            MODE_AFTER_BYTES => FaultMode::AfterBytes(param),
+            _ => FaultMode::Off,
+        }
+    }
+
+    /// Returns how many failures have been injected, in total.
+    #[must_use]
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many injected failures the kernel has carried on from, in total.
+    #[must_use]
+    pub fn survived(&self) -> u64 {
+        self.survived.load(Ordering::Relaxed)
+    }
+
+    /// Called by the allocator for every allocation of `bytes`. Returns `true` if it should
+    /// fail.
+    pub fn should_fail(&self, bytes: usize) -> bool {
+        if self.outstanding.swap(false, Ordering::Relaxed) {
+            self.survived.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let param = self.param.load(Ordering::Relaxed);
+        let fail = match self.mode.load(Ordering::Acquire) {
+            MODE_EVERY_NTH => (self.seen.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(param),
+            MODE_AFTER_BYTES => self.seen.fetch_add(bytes, Ordering::Relaxed) + bytes > param,
+            _ => false,
+        };
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+            self.outstanding.store(true, Ordering::Relaxed);
+        }
+        fail
+    }
+}
+
+impl fmt::Display for FaultInjector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {} injected, {} survived",
+            self.name,
+            self.mode(),
+            self.injected(),
+            self.survived()
+        )
+    }
+}
+
+/// Mentions any injected failure that hadn't been survived, for the panic handler, since it's
+/// the likely culprit.
+pub fn report_outstanding() {
+    for injector in [&FRAMES, &HEAP] {
+        if injector.outstanding.load(Ordering::Relaxed) {
+            println!("An allocation failure was injected just before this ({injector})");
+        }
+    }
+}
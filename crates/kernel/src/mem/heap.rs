@@ -1,14 +1,314 @@
-use buddy_system_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use buddy_system_allocator::{Heap, LockedHeap};
+use spin::Mutex;
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::{
+        fault,
+        paging::{
+            allocator::try_kernel_frame_allocator,
+            table::{PageFlags, PageTable, TableKind},
+        },
+        slab,
+        units::{FrameCount, VirtAddr},
+    },
+    println,
+    sync::with_irqs_disabled,
+    task::stats,
+};
+
+#[cfg(feature = "heap-debug")]
+use super::redzone;
 
 pub const KERNEL_HEAP_START: usize = 0xFFFF_FE80_0000_0000;
-pub const KERNEL_HEAP_SIZE: usize = 1024 * 1024 * 64;
+
+/// The size of the heap at boot. The builder sets this with `--heap-size`.
+pub const KERNEL_HEAP_SIZE: usize = match option_env!("KADOS_HEAP_SIZE") {
+    Some(size) => match usize::from_str_radix(size, 10) {
+        Ok(size) => size,
+        Err(_) => panic!("KADOS_HEAP_SIZE must be a decimal number"),
+    },
+    None => 1024 * 1024 * 64,
+};
+
+/// The most the heap can grow to. The builder sets this with `--heap-max-size`.
+pub const KERNEL_HEAP_MAX_SIZE: usize = match option_env!("KADOS_HEAP_MAX_SIZE") {
+    Some(size) => match usize::from_str_radix(size, 10) {
+        Ok(size) => size,
+        Err(_) => panic!("KADOS_HEAP_MAX_SIZE must be a decimal number"),
+    },
+    None => 1024 * 1024 * 1024,
+};
+
+const _: () = assert!(
+    KERNEL_HEAP_SIZE <= KERNEL_HEAP_MAX_SIZE,
+    "the heap can't start out bigger than it's allowed to grow"
+);
+const _: () = assert!(
+    KERNEL_HEAP_SIZE.is_multiple_of(Arch::PAGE_SIZE),
+    "the heap must be a whole number of pages"
+);
+
+/// The least the heap grows by at a time.
+const HEAP_GROW_STEP: usize = 1024 * 1024 * 4;
+
+const HEAP_ORDER: usize = 32;
 
 #[global_allocator]
-static HEAP: LockedHeap<32> = LockedHeap::new();
+static HEAP: KernelHeap = KernelHeap(LockedHeap::new());
+
+/// The end of the heap, which is where it grows from next.
+static HEAP_END: Mutex<usize> = Mutex::new(KERNEL_HEAP_START + KERNEL_HEAP_SIZE);
+
+/// The kernel's global allocator, which reports the state of memory when an allocation
+/// fails before handing the failure on to the alloc error handler.
+///
+/// Small allocations are served from the [slab caches](slab), everything else straight
+/// from the buddy allocator. With the `heap-debug` feature, each allocation is padded with
+/// red zones that are checked when it's freed.
+struct KernelHeap(LockedHeap<HEAP_ORDER>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        return unsafe { redzone::alloc(layout, |layout| self.alloc_block(layout)) };
+        #[cfg(not(feature = "heap-debug"))]
+        self.alloc_block(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-debug")]
+        if !ptr.is_null() {
+            unsafe {
+                redzone::dealloc(ptr, layout, |ptr, layout| self.dealloc_block(ptr, layout));
+            }
+        }
+        #[cfg(not(feature = "heap-debug"))]
+        unsafe {
+            self.dealloc_block(ptr, layout);
+        }
+    }
+}
+
+impl KernelHeap {
+    fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        // interrupt handlers allocate and free too (e.g. waking a task drops its waiter), so
+        // the heap's locks are never held with interrupts enabled
+        with_irqs_disabled(|| {
+            if fault::HEAP.should_fail(layout.size()) {
+                return ptr::null_mut();
+            }
+            // the lock is released before growing or reporting, since both take it again
+            let mut ptr = self.try_alloc(layout);
+            if ptr.is_err() && grow(layout) {
+                ptr = self.try_alloc(layout);
+            }
+            if let Ok(ptr) = ptr {
+                stats::account_alloc(layout.size());
+                ptr.as_ptr()
+            } else {
+                report_oom(layout);
+                ptr::null_mut()
+            }
+        })
+    }
+
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
+        };
+        with_irqs_disabled(|| {
+            stats::account_free(layout.size());
+            if slab::handles(layout) {
+                unsafe { slab::dealloc(ptr, layout) };
+            } else {
+                self.0.lock().dealloc(ptr, layout);
+            }
+        });
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        if slab::handles(layout) {
+            slab::alloc(layout).ok_or(())
+        } else {
+            self.0.lock().alloc(layout)
+        }
+    }
+}
+
+const SLAB_LAYOUT: Layout = match Layout::from_size_align(slab::SLAB_SIZE, slab::SLAB_SIZE) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid slab layout"),
+};
+
+/// Takes a block for a new slab from the heap.
+pub(super) fn alloc_slab() -> Option<NonNull<u8>> {
+    HEAP.0.lock().alloc(SLAB_LAYOUT).ok()
+}
+
+/// Returns a slab's block to the heap.
+pub(super) fn free_slab(slab: NonNull<u8>) {
+    HEAP.0.lock().dealloc(slab, SLAB_LAYOUT);
+}
 
 /// Initializes the kernel heap.
 pub unsafe fn init_heap() {
     unsafe {
-        HEAP.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+        HEAP.0.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+    }
+}
+
+/// Maps more memory onto the end of the heap, enough for at least `layout`, returning
+/// `false` if it can't.
+///
+/// The heap won't grow past [`KERNEL_HEAP_MAX_SIZE`], and doesn't grow while it's already
+/// growing: anything the frame allocator or the page tables allocate along the way has to
+/// fit in what's left.
+fn grow(layout: Layout) -> bool {
+    let Some(mut end) = HEAP_END.try_lock() else {
+        return false;
+    };
+
+    // twice the allocation's size guarantees a block of that size aligned to it
+    let wanted = layout
+        .size()
+        .max(layout.align())
+        .next_power_of_two()
+        .saturating_mul(2)
+        .max(HEAP_GROW_STEP);
+    let size = wanted.min(KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE - *end);
+    if size < layout.size().max(layout.align()) {
+        return false;
+    }
+
+    let count = FrameCount::from_bytes(size);
+    // the failed allocation may have come from under the frame allocator's lock
+    let Some(frames) =
+        try_kernel_frame_allocator().and_then(|mut frames| unsafe { frames.allocate(count) }.ok())
+    else {
+        return false;
+    };
+
+    let start = VirtAddr::new_canonical(*end);
+    let mapped = PageTable::current(TableKind::Kernel).kernel_map_range(
+        start,
+        frames,
+        count.to_bytes(),
+        PageFlags::new_for_data_segment(),
+    );
+    let Ok(flush) = mapped else {
+        // some of the frames may be mapped already, so they're leaked rather than freed
+        return false;
+    };
+    flush.flush();
+
+    unsafe { HEAP.0.lock().add_to_heap(*end, *end + count.to_bytes()) };
+    *end += count.to_bytes();
+    true
+}
+
+/// A snapshot of the kernel heap's usage, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The size of the heap.
+    pub total: usize,
+    /// The bytes taken up by allocations, including what the buddy allocator rounded them up by.
+    /// Slabs count as used in their entirety.
+    pub used: usize,
+    /// The bytes callers actually asked for, with slabs counted in their entirety.
+    pub requested: usize,
+    /// The bytes not taken up by any allocation.
+    pub free: usize,
+    /// The size of the biggest block that can still be allocated.
+    pub largest_free_block: usize,
+    /// The size the heap can grow to.
+    pub limit: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} / {} bytes used ({} requested), {} free, largest free block {} bytes, \
+             can grow to {} bytes",
+            self.used, self.total, self.requested, self.free, self.largest_free_block, self.limit
+        )
     }
 }
+
+/// Returns a snapshot of the kernel heap's usage.
+#[must_use]
+pub fn stats() -> HeapStats {
+    // with interrupts disabled, like every other use of the heap's lock
+    with_irqs_disabled(|| {
+        let mut heap = HEAP.0.lock();
+        let total = heap.stats_total_bytes();
+        let used = heap.stats_alloc_actual();
+        HeapStats {
+            total,
+            used,
+            requested: heap.stats_alloc_user(),
+            free: total - used,
+            largest_free_block: largest_free_block(&mut heap),
+            limit: KERNEL_HEAP_MAX_SIZE,
+        }
+    })
+}
+
+/// Finds the biggest block the heap can hand out by trying each block size, largest first.
+///
+/// Every block the buddy allocator hands out is a power of two, so this is exact.
+fn largest_free_block(heap: &mut Heap<HEAP_ORDER>) -> usize {
+    for order in (0..HEAP_ORDER).rev() {
+        let size = 1 << order;
+        let Ok(layout) = Layout::from_size_align(size, 1) else {
+            continue;
+        };
+        if let Ok(block) = heap.alloc(layout) {
+            heap.dealloc(block, layout);
+            return size;
+        }
+    }
+    0
+}
+
+/// Dumps the state of the heap and the frame allocator after a failed allocation.
+///
+/// This goes straight to the console rather than through the logger, since the logger
+/// allocates.
+#[cold]
+fn report_oom(layout: Layout) {
+    static REPORTING: AtomicBool = AtomicBool::new(false);
+
+    // anything that fails while we're reporting is reported by the alloc error handler alone
+    if REPORTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    println!(
+        "Kernel heap allocation of {} bytes (align {}) failed",
+        layout.size(),
+        layout.align()
+    );
+    println!("  heap: {}", stats());
+    // the failed allocation may have come from under the frame allocator's lock
+    if let Some(frames) = try_kernel_frame_allocator() {
+        let free = frames.free_frames();
+        println!(
+            "  frames: {} free ({} bytes)",
+            free.frame_count(),
+            free.to_bytes()
+        );
+    } else {
+        println!("  frames: allocator busy");
+    }
+
+    REPORTING.store(false, Ordering::Release);
+}
@@ -3,9 +3,15 @@ use thiserror::Error;
 use paging::table::PageTableEntry;
 use units::{PhysAddr, VirtAddr};
 
+pub mod fault;
 pub mod heap;
 pub mod paging;
+pub mod protect;
+#[cfg(feature = "heap-debug")]
+pub mod redzone;
+pub mod slab;
 pub mod units;
+pub mod user;
 
 /// Error handling for memory operations.
 #[derive(Debug, Error)]
@@ -29,9 +35,17 @@ pub enum MemError {
     NoNextTable,
     #[error("Virtual address {0} is not a part of the page table at {1}")]
     NotPartOfTable(VirtAddr, PhysAddr),
+    #[error("Page {0} is not mapped")]
+    PageNotMapped(VirtAddr),
     #[error("Page {0} is already mapped to {1:?}")]
     PageAlreadyMapped(VirtAddr, PageTableEntry),
 
     #[error("Out of physical memory")]
     OutOfMemory,
+    #[error("Memory map is full")]
+    MemMapFull,
+    #[error("Invalid alignment {0}")]
+    InvalidAlignment(usize),
+    #[error("Too many reserved regions")]
+    ReservationsFull,
 }
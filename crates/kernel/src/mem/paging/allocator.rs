@@ -1,23 +1,33 @@
+use core::{alloc::Layout, fmt};
+
 use alloc::boxed::Box;
-use spin::{Mutex, MutexGuard, Once};
+use spin::{Once, mutex::SpinMutexGuard};
 
 use crate::{
     BootInfo,
     arch::{Arch, Architecture},
     mem::{
-        MemError,
+        MemError, fault,
         units::{FrameCount, PhysAddr},
     },
+    sync::{IrqMutex, TaskMutex},
 };
 
 use super::MemMapEntry;
 
-static KERNEL_FRAME_ALLOCATOR: Once<Mutex<FrameAllocator>> = Once::new();
+/// The buddy frame allocator's order: its biggest block is `1 << (FRAME_ORDER - 1)` frames.
+const FRAME_ORDER: usize = 33;
+/// The most regions [`reserve`] can set aside.
+const MAX_RESERVATIONS: usize = 16;
+
+static KERNEL_FRAME_ALLOCATOR: Once<TaskMutex<FrameAllocator>> = Once::new();
+static RESERVATIONS: IrqMutex<[Option<Reservation>; MAX_RESERVATIONS]> =
+    IrqMutex::new([None; MAX_RESERVATIONS]);
 
 /// Initializes the global kernel frame allocator with the boot memory map.
 pub fn init_kernel_frame_allocator(boot_info: &'static BootInfo) {
     KERNEL_FRAME_ALLOCATOR
-        .call_once(|| Mutex::new(FrameAllocator::boot(boot_info.mem_map.usable_entries())));
+        .call_once(|| TaskMutex::new(FrameAllocator::boot(boot_info.mem_map.usable_entries())));
 }
 
 /// Returns a guard to the global kernel frame allocator.
@@ -26,13 +36,99 @@ pub fn init_kernel_frame_allocator(boot_info: &'static BootInfo) {
 ///
 /// This function will panic if the kernel frame allocator has not been initialized.
 #[must_use]
-pub fn kernel_frame_allocator<'a>() -> MutexGuard<'a, FrameAllocator> {
+pub fn kernel_frame_allocator<'a>() -> SpinMutexGuard<'a, FrameAllocator> {
     KERNEL_FRAME_ALLOCATOR
         .get()
         .expect("kernel frame allocator not initialized")
         .lock()
 }
 
+/// Returns a guard to the global kernel frame allocator, or `None` if it's locked or hasn't
+/// been initialized yet.
+#[must_use]
+pub fn try_kernel_frame_allocator<'a>() -> Option<SpinMutexGuard<'a, FrameAllocator>> {
+    KERNEL_FRAME_ALLOCATOR.get()?.try_lock()
+}
+
+/// A physically contiguous region set aside for good with [`reserve`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    /// What the region is for.
+    pub name: &'static str,
+    /// The region's first frame.
+    pub base: PhysAddr,
+    /// How many frames the region spans.
+    pub count: FrameCount,
+}
+
+/// Allocates `count` physically contiguous frames aligned to `align` bytes, which are never
+/// freed, and records them under `name`. Meant for drivers' buffers that live as long as the
+/// kernel, reserved at boot while memory is still in few, large pieces.
+///
+/// # Errors
+///
+/// Returns [`MemError::ReservationsFull`] if [`MAX_RESERVATIONS`] regions are already reserved,
+/// or whatever [`FrameAllocator::allocate_contiguous`] failed with.
+pub fn reserve(name: &'static str, count: FrameCount, align: usize) -> Result<PhysAddr, MemError> {
+    let mut reservations = RESERVATIONS.lock();
+    let slot = reservations
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(MemError::ReservationsFull)?;
+    let base = unsafe { kernel_frame_allocator().allocate_contiguous(count, align)? };
+    *slot = Some(Reservation { name, base, count });
+    log::debug!(
+        "reserved {name}: {base} .. {}",
+        base.add_bytes(count.to_bytes())
+    );
+    Ok(base)
+}
+
+/// Calls `f` with each region set aside with [`reserve`], in the order they were reserved.
+pub fn for_each_reservation(mut f: impl FnMut(&Reservation)) {
+    RESERVATIONS.lock().iter().flatten().for_each(&mut f);
+}
+
+/// Returns the alignment in frames for an alignment of `align` bytes.
+fn align_frames(align: usize) -> Result<usize, MemError> {
+    if align.is_power_of_two() && align >= Arch::PAGE_SIZE {
+        Ok(align / Arch::PAGE_SIZE)
+    } else {
+        Err(MemError::InvalidAlignment(align))
+    }
+}
+
+/// A snapshot of the frame allocator's usage, in frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameStats {
+    /// The frames free to allocate.
+    pub free: usize,
+    /// The most contiguous frames that can still be allocated at once.
+    pub largest_free_block: usize,
+    /// The allocations made since the buddy allocator took over.
+    pub allocations: usize,
+    /// The allocations that failed since the buddy allocator took over.
+    pub failures: usize,
+    /// The allocations that failed even though enough frames were free, because no free block
+    /// was big enough, or aligned well enough.
+    pub fragmentation_failures: usize,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} free, largest free block {} frames, {} allocations, {} failed ({} to \
+             fragmentation)",
+            self.free,
+            self.largest_free_block,
+            self.allocations,
+            self.failures,
+            self.fragmentation_failures
+        )
+    }
+}
+
 /// The frame allocator used by the kernel.
 ///
 /// Pre-heap, it uses a bump allocator that allocates frames from the boot memory map.
@@ -70,7 +166,7 @@ impl FrameAllocator {
             let first_size = first_free_area.size.to_bytes() - bump.bump;
             let index = first_base.frame_index();
             let count = FrameCount::from_bytes(first_size);
-            buddy.allocator.add_frame(
+            buddy.add_frames(
                 index.frame_index(),
                 index.frame_index() + count.frame_count(),
             );
@@ -79,9 +175,7 @@ impl FrameAllocator {
             for area in bump.areas.iter().skip(1) {
                 let index = area.base.frame_index();
                 let count = area.size.frame_count();
-                buddy
-                    .allocator
-                    .add_frame(index.frame_index(), index.frame_index() + count);
+                buddy.add_frames(index.frame_index(), index.frame_index() + count);
             }
 
             *self = Self::PostHeap(buddy);
@@ -92,12 +186,54 @@ impl FrameAllocator {
 
     /// Allocates a number of frames.
     pub unsafe fn allocate(&mut self, count: FrameCount) -> Result<PhysAddr, MemError> {
+        if fault::FRAMES.should_fail(count.to_bytes()) {
+            return Err(MemError::OutOfMemory);
+        }
         match self {
             Self::Boot(bump) => unsafe { bump.allocate(count) },
             Self::PostHeap(buddy) => unsafe { buddy.allocate(count) },
         }
     }
 
+    /// Allocates `count` physically contiguous frames, aligned to `align` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MemError::InvalidAlignment`] if `align` isn't a power of two of at least a
+    /// page, or [`MemError::OutOfMemory`] if there's no such run of frames free.
+    pub unsafe fn allocate_contiguous(
+        &mut self,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<PhysAddr, MemError> {
+        if fault::FRAMES.should_fail(count.to_bytes()) {
+            return Err(MemError::OutOfMemory);
+        }
+        match self {
+            Self::Boot(bump) => unsafe { bump.allocate_aligned(count, align) },
+            Self::PostHeap(buddy) => unsafe { buddy.allocate_aligned(count, align) },
+        }
+    }
+
+    /// Frees frames from [`FrameAllocator::allocate_contiguous`], given the same `count` and
+    /// `align` they were allocated with.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MemError::InvalidAlignment`] if `align` isn't a power of two of at least a
+    /// page.
+    pub fn free_contiguous(
+        &mut self,
+        start: PhysAddr,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<(), MemError> {
+        match self {
+            Self::Boot(_) => Ok(()),
+            Self::PostHeap(buddy) => buddy.free_aligned(start, count, align),
+        }
+    }
+
     /// Frees a range of frames.
     pub fn free(&mut self, start: PhysAddr, count: FrameCount) -> Result<(), MemError> {
         match self {
@@ -120,6 +256,29 @@ impl FrameAllocator {
             Self::PostHeap(_) => None,
         }
     }
+
+    /// Returns the number of frames still available for allocation.
+    #[must_use]
+    pub fn free_frames(&self) -> FrameCount {
+        match self {
+            Self::Boot(bump) => bump.free_frames(),
+            Self::PostHeap(buddy) => buddy.free_frames(),
+        }
+    }
+
+    /// Returns a snapshot of the allocator's usage. Only the buddy allocator keeps count of
+    /// allocations.
+    #[must_use]
+    pub fn stats(&mut self) -> FrameStats {
+        match self {
+            Self::Boot(bump) => FrameStats {
+                free: bump.free_frames().frame_count(),
+                largest_free_block: bump.largest_free_block(),
+                ..FrameStats::default()
+            },
+            Self::PostHeap(buddy) => buddy.stats(),
+        }
+    }
 }
 
 /// A handle to the global kernel frame allocator.
@@ -136,6 +295,27 @@ impl KernelFrameAllocator {
         unsafe { self.allocate(FrameCount::new(1)) }
     }
 
+    /// Allocates physically contiguous, aligned frames from the global kernel frame allocator.
+    /// See [`FrameAllocator::allocate_contiguous`].
+    pub unsafe fn allocate_contiguous(
+        &mut self,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<PhysAddr, MemError> {
+        unsafe { kernel_frame_allocator().allocate_contiguous(count, align) }
+    }
+
+    /// Frees frames from [`KernelFrameAllocator::allocate_contiguous`] in the global kernel
+    /// frame allocator.
+    pub fn free_contiguous(
+        &mut self,
+        start: PhysAddr,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<(), MemError> {
+        kernel_frame_allocator().free_contiguous(start, count, align)
+    }
+
     /// Frees a range of frames in the global kernel frame allocator.
     pub fn free(&mut self, start: PhysAddr, count: FrameCount) -> Result<(), MemError> {
         kernel_frame_allocator().free(start, count)
@@ -190,6 +370,36 @@ impl BumpFrameAllocator {
         Ok(block)
     }
 
+    /// Allocates a number of frames aligned to `align` bytes from the bump allocator. The
+    /// frames skipped to align them are lost.
+    pub unsafe fn allocate_aligned(
+        &mut self,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<PhysAddr, MemError> {
+        align_frames(align)?;
+        let size_bytes = count.to_bytes();
+
+        let block = loop {
+            let area = self.areas.first().ok_or(MemError::OutOfMemory)?;
+            let start = area.base.add_bytes(self.bump).align_up(align);
+            let offset = start.value() - area.base.value();
+            if offset > area.size.to_bytes() || area.size.to_bytes() - offset < size_bytes {
+                self.areas = &self.areas[1..];
+                self.bump = 0;
+                continue;
+            }
+            self.bump = offset + size_bytes;
+            break start;
+        };
+
+        unsafe {
+            block.as_hhdm_virt().fill(0, size_bytes)?;
+        }
+
+        Ok(block)
+    }
+
     /// Returns the number of frames currently allocated in the bump allocator.
     #[must_use]
     pub fn usage(&self) -> FrameCount {
@@ -202,11 +412,32 @@ impl BumpFrameAllocator {
 
         FrameCount::from_bytes(total)
     }
+
+    /// Returns the number of frames left in the areas the bump allocator hasn't used up.
+    #[must_use]
+    pub fn free_frames(&self) -> FrameCount {
+        let total: usize = self.areas.iter().map(|area| area.size.to_bytes()).sum();
+        FrameCount::from_bytes(total - self.bump)
+    }
+
+    /// Returns the most frames left in any one area.
+    #[must_use]
+    pub fn largest_free_block(&self) -> usize {
+        let mut areas = self.areas.iter().map(|area| area.size.frame_count());
+        let first = areas.next().map_or(0, |size| {
+            size - FrameCount::from_bytes(self.bump).frame_count()
+        });
+        areas.fold(first, usize::max)
+    }
 }
 
 /// A buddy system allocator for frames of physical memory.
 pub struct BuddySystemFrameAllocator {
-    allocator: buddy_system_allocator::FrameAllocator,
+    allocator: buddy_system_allocator::FrameAllocator<FRAME_ORDER>,
+    free: usize,
+    allocations: usize,
+    failures: usize,
+    fragmentation_failures: usize,
 }
 
 impl BuddySystemFrameAllocator {
@@ -215,36 +446,127 @@ impl BuddySystemFrameAllocator {
     pub const fn const_default() -> Self {
         Self {
             allocator: buddy_system_allocator::FrameAllocator::new(),
+            free: 0,
+            allocations: 0,
+            failures: 0,
+            fragmentation_failures: 0,
         }
     }
 
     /// Creates a new buddy system frame allocator with the given memory map entries for usable memory.
     #[must_use]
     pub fn new(areas: &'static [MemMapEntry]) -> Self {
-        let mut allocator = buddy_system_allocator::FrameAllocator::new();
+        let mut this = Self::const_default();
         for area in areas {
             let base = area.base.value() / Arch::PAGE_SIZE;
-            allocator.add_frame(base, base + area.size.frame_count());
+            this.add_frames(base, base + area.size.frame_count());
         }
-        Self { allocator }
+        this
+    }
+
+    /// Hands the frames in `start..end` (as frame indices) to the allocator.
+    pub fn add_frames(&mut self, start: usize, end: usize) {
+        self.allocator.add_frame(start, end);
+        self.free += end - start;
     }
 
     /// Allocates a number of frames from the buddy system allocator.
     pub unsafe fn allocate(&mut self, count: FrameCount) -> Result<PhysAddr, MemError> {
-        if let Some(frame) = self.allocator.alloc(count.frame_count()) {
-            let addr = FrameCount::new(frame).to_bytes();
-            let addr = PhysAddr::new_canonical(addr);
-            unsafe { addr.as_hhdm_virt().fill(0, count.to_bytes())? };
-            Ok(addr)
-        } else {
-            Err(MemError::OutOfMemory)
-        }
+        let frame = self.allocator.alloc(count.frame_count());
+        // the allocator rounds every block up to a power of two
+        unsafe { self.finish_allocation(frame, count, count.frame_count().next_power_of_two()) }
+    }
+
+    /// Allocates a number of frames aligned to `align` bytes from the buddy system allocator.
+    pub unsafe fn allocate_aligned(
+        &mut self,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<PhysAddr, MemError> {
+        let layout = Self::aligned_layout(count, align)?;
+        let frame = self.allocator.alloc_aligned(layout);
+        // blocks are also rounded up to their alignment
+        let size = layout.size().next_power_of_two().max(layout.align());
+        unsafe { self.finish_allocation(frame, count, size) }
+    }
+
+    /// Accounts for an allocation of `count` frames that took up a block of `size`, or failed
+    /// if `frame` is `None`, and zeroes the frames.
+    unsafe fn finish_allocation(
+        &mut self,
+        frame: Option<usize>,
+        count: FrameCount,
+        size: usize,
+    ) -> Result<PhysAddr, MemError> {
+        let Some(frame) = frame else {
+            self.failures += 1;
+            if self.free >= size {
+                self.fragmentation_failures += 1;
+            }
+            return Err(MemError::OutOfMemory);
+        };
+        self.allocations += 1;
+        self.free -= size;
+        let addr = PhysAddr::new_canonical(FrameCount::new(frame).to_bytes());
+        unsafe { addr.as_hhdm_virt().fill(0, count.to_bytes())? };
+        Ok(addr)
+    }
+
+    /// Returns the layout, in frames, the buddy allocator takes for an aligned allocation.
+    fn aligned_layout(count: FrameCount, align: usize) -> Result<Layout, MemError> {
+        Layout::from_size_align(count.frame_count(), align_frames(align)?)
+            .map_err(|_| MemError::InvalidAlignment(align))
     }
 
     /// Frees a range of frames in the buddy system allocator.
     pub fn free(&mut self, start: PhysAddr, count: FrameCount) -> Result<(), MemError> {
         self.allocator
             .dealloc(start.frame_index().frame_index(), count.frame_count());
+        self.free += count.frame_count().next_power_of_two();
+        Ok(())
+    }
+
+    /// Frees frames allocated with [`BuddySystemFrameAllocator::allocate_aligned`].
+    pub fn free_aligned(
+        &mut self,
+        start: PhysAddr,
+        count: FrameCount,
+        align: usize,
+    ) -> Result<(), MemError> {
+        let layout = Self::aligned_layout(count, align)?;
+        self.allocator
+            .dealloc_aligned(start.frame_index().frame_index(), layout);
+        self.free += layout.size().next_power_of_two().max(layout.align());
         Ok(())
     }
+
+    /// Returns a snapshot of the allocator's usage.
+    pub fn stats(&mut self) -> FrameStats {
+        FrameStats {
+            free: self.free,
+            largest_free_block: self.largest_free_block(),
+            allocations: self.allocations,
+            failures: self.failures,
+            fragmentation_failures: self.fragmentation_failures,
+        }
+    }
+
+    /// Finds the most frames that can be allocated at once by trying each block size, largest
+    /// first.
+    fn largest_free_block(&mut self) -> usize {
+        for order in (0..FRAME_ORDER).rev() {
+            let count = 1 << order;
+            if let Some(frame) = self.allocator.alloc(count) {
+                self.allocator.dealloc(frame, count);
+                return count;
+            }
+        }
+        0
+    }
+
+    /// Returns the number of frames still available for allocation.
+    #[must_use]
+    pub fn free_frames(&self) -> FrameCount {
+        FrameCount::new(self.free)
+    }
 }
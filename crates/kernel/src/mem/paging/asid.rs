@@ -0,0 +1,128 @@
+//! Address space IDs, which tag the TLB entries of user page tables with the address space
+//! they came from, so switching between user address spaces doesn't flush the TLB.
+//!
+//! ASIDs are handed out in generations. When one runs out, the next starts: the whole TLB is
+//! flushed, every ASID is free again, and each address space gets a new one the next time
+//! it's switched to. ASID 0 is never handed out. It goes with [`empty_table`], the user table
+//! kernel tasks run with, which maps nothing for TLB entries to come from.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::units::PhysAddr,
+    sync::IrqMutex,
+};
+
+use super::table::{PageTable, TableKind};
+
+const ASID_COUNT: usize = 1 << Arch::ASID_BITS;
+/// How far up an [`Asid`]'s value the generation is.
+const GENERATION_SHIFT: u32 = 16;
+
+/// The current generation, never 0 so that an [`Asid`] without one never matches it.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+static ALLOCATOR: IrqMutex<Allocator> = IrqMutex::new(Allocator::new());
+static EMPTY_TABLE: Once<PhysAddr> = Once::new();
+
+/// The ASIDs in use in the current generation.
+struct Allocator {
+    used: [u64; ASID_COUNT / 64],
+    /// Where to start looking for a free ASID.
+    next: usize,
+}
+
+impl Allocator {
+    const fn new() -> Self {
+        let mut used = [0; ASID_COUNT / 64];
+        used[0] = 1;
+        Self { used, next: 1 }
+    }
+
+    fn take(&mut self) -> Option<u16> {
+        let asid = (self.next..ASID_COUNT)
+            .chain(1..self.next)
+            .find(|&asid| self.used[asid / 64] & (1 << (asid % 64)) == 0)?;
+        self.used[asid / 64] |= 1 << (asid % 64);
+        self.next = asid + 1;
+        Some(asid as u16)
+    }
+
+    fn free(&mut self, asid: u16) {
+        let asid = usize::from(asid);
+        self.used[asid / 64] &= !(1 << (asid % 64));
+    }
+
+    /// Starts a new generation, with every ASID free and nothing left in the TLB from the
+    /// last one.
+    fn rollover(&mut self) {
+        *self = Self::new();
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+        unsafe { Arch::invalidate_all() };
+        log::debug!("ASIDs ran out; starting a new generation");
+    }
+}
+
+/// An address space's ASID, allocated the first time it's needed in each generation.
+pub struct Asid(AtomicU64);
+
+impl Asid {
+    /// Returns an ASID that doesn't have a value yet.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Returns the ASID to tag the address space's TLB entries with, taking a new one if it
+    /// has none from the current generation.
+    pub fn get(&self) -> u16 {
+        let value = self.0.load(Ordering::Relaxed);
+        if value >> GENERATION_SHIFT == GENERATION.load(Ordering::Relaxed) {
+            return value as u16;
+        }
+
+        let mut allocator = ALLOCATOR.lock();
+        let asid = loop {
+            match allocator.take() {
+                Some(asid) => break asid,
+                None => allocator.rollover(),
+            }
+        };
+        let generation = GENERATION.load(Ordering::Relaxed);
+        self.0.store(
+            (generation << GENERATION_SHIFT) | u64::from(asid),
+            Ordering::Relaxed,
+        );
+        asid
+    }
+}
+
+impl Default for Asid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Asid {
+    fn drop(&mut self) {
+        let value = *self.0.get_mut();
+        let asid = value as u16;
+        let mut allocator = ALLOCATOR.lock();
+        if value >> GENERATION_SHIFT == GENERATION.load(Ordering::Relaxed) {
+            // whoever gets it next mustn't see this address space's entries
+            unsafe { Arch::invalidate_asid(asid) };
+            allocator.free(asid);
+        }
+    }
+}
+
+/// Returns the user table that goes with ASID 0, which has nothing mapped.
+///
+/// # Panics
+///
+/// Panics if the table can't be allocated.
+pub fn empty_table() -> PhysAddr {
+    *EMPTY_TABLE.call_once(|| PageTable::create(TableKind::User).phys_addr())
+}
@@ -0,0 +1,76 @@
+//! Replacing page table entries that may be in use, with break-before-make.
+//!
+//! Arm requires a valid entry to be made invalid, and the TLB flushed, before it's replaced
+//! with one that maps some of the same memory at another size, as when a block is split into
+//! a table or a table merged into a block. Without that, or `FEAT_BBM`, which the Pi 4's and
+//! Pi 5's cores don't have, a CPU can end up holding TLB entries from both, and take a TLB
+//! conflict abort or use either.
+//!
+//! While the entry is broken nothing it maps can be touched, and that may well include the
+//! table it's in, which is normally reached through the HHDM. So the table is reached through
+//! [`FIXMAP_ADDR`] instead: a page of the kernel's address space that's mapped by tables of
+//! its own, which are never split or merged.
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::{
+        MemError,
+        units::{PhysAddr, VirtAddr},
+    },
+    sync::IrqMutex,
+};
+
+use super::table::{PageFlags, PageTable, PageTableEntry};
+
+/// Where the table holding an entry being replaced is mapped, between the kernel stack and
+/// the heap.
+pub const FIXMAP_ADDR: usize = 0xFFFF_FE7F_8000_0000;
+
+/// The physical address of the level-1 entry that maps [`FIXMAP_ADDR`], once the kernel's
+/// table with it in is current.
+static FIXMAP_PTE: IrqMutex<Option<PhysAddr>> = IrqMutex::new(None);
+
+/// Creates the tables that map [`FIXMAP_ADDR`] in the kernel's table, which must be
+/// current, and uses them from then on.
+///
+/// # Errors
+///
+/// Returns an error if a table can't be allocated.
+pub fn init(table: &mut PageTable) -> Result<(), MemError> {
+    let pte = table.create_pte(VirtAddr::new_canonical(FIXMAP_ADDR))?;
+    *FIXMAP_PTE.lock() = Some(pte);
+    Ok(())
+}
+
+/// Replaces the page table entry at the physical address `entry` with `new`, breaking it
+/// first.
+///
+/// Before [`init`], the only tables changed are the kernel's own, which aren't in use yet,
+/// so the entry is just written.
+///
+/// # Safety
+///
+/// Nothing may use the memory the entry maps while it's broken, on any CPU. The kernel's
+/// text is what's running, so it can never be.
+pub(super) unsafe fn replace(entry: PhysAddr, new: PageTableEntry) {
+    let pte = FIXMAP_PTE.lock();
+    let Some(pte) = *pte else {
+        unsafe {
+            entry.as_hhdm_virt().write_volatile(new).unwrap();
+            Arch::invalidate_all();
+        }
+        return;
+    };
+
+    let frame = entry.align_down(Arch::PAGE_SIZE);
+    let slot = VirtAddr::new_canonical(FIXMAP_ADDR).add_bytes(entry.value() - frame.value());
+    unsafe {
+        let mapping = PageTableEntry::new(frame, PageFlags::new_for_data_segment());
+        pte.as_hhdm_virt().write_volatile(mapping).unwrap();
+        Arch::break_before_make(slot, new);
+        pte.as_hhdm_virt()
+            .write_volatile(PageTableEntry::UNUSED)
+            .unwrap();
+        Arch::invalidate_all();
+    }
+}
@@ -1,9 +1,11 @@
+use core::ops::Range;
+
 use allocator::KernelFrameAllocator;
-use table::{BlockSize, PageFlags, PageTable, TableKind};
+use table::{BlockSize, PageFlags, PageTable, PageTableEntry, TableKind};
 
 use crate::{
-    __kernel_phys_end, __kernel_phys_start, __rodata_end, __rodata_start, __text_end, __text_start,
-    BootInfo, KERNEL_OFFSET,
+    __kernel_phys_end, __kernel_phys_start, __rodata_end, __rodata_start, __stack_bottom,
+    __stack_top, __text_end, __text_start, BootInfo, KERNEL_OFFSET,
     arch::{Arch, Architecture},
     mem::{
         heap::{KERNEL_HEAP_SIZE, KERNEL_HEAP_START},
@@ -11,12 +13,26 @@ use crate::{
     },
 };
 
-use super::units::{FrameCount, PhysAddr};
+use super::{
+    MemError,
+    units::{FrameCount, PhysAddr},
+};
 
 pub mod allocator;
+pub mod asid;
+pub mod fixmap;
 pub mod flush;
 pub mod table;
 
+/// The top of the kernel's main stack, which the boot context runs on after
+/// [`map_memory`].
+pub const KERNEL_STACK_TOP: usize = 0xFFFF_FE7F_0000_0000;
+/// The size of the kernel's main stack.
+pub const KERNEL_STACK_SIZE: usize = 256 * 1024;
+/// The bottom of the kernel's main stack. The page below it is never mapped, so an
+/// overflow faults instead of silently corrupting whatever is below.
+pub const KERNEL_STACK_BOTTOM: usize = KERNEL_STACK_TOP - KERNEL_STACK_SIZE;
+
 /// A memory map entry representing a range of physical memory available at boot time.
 #[derive(Clone, Copy)]
 pub struct MemMapEntry {
@@ -32,6 +48,34 @@ impl MemMapEntry {
     };
 }
 
+/// The region of physical memory the bootloader allocated its page tables from.
+///
+/// The bootloader's tables stay live (as the user-half table) until the kernel has switched
+/// to its own, after which the whole pool can be handed back with [`reclaim_boot_tables`].
+#[derive(Clone, Copy, Debug)]
+pub struct BootTables {
+    /// The start of the pool. The root table is always allocated first, so this is also its address.
+    pub base: PhysAddr,
+    /// The end of the tables the bootloader actually allocated.
+    pub used_end: PhysAddr,
+    /// The end of the pool.
+    pub end: PhysAddr,
+}
+
+impl BootTables {
+    /// Returns the size of the whole pool in frames.
+    #[must_use]
+    pub fn pool_size(&self) -> FrameCount {
+        FrameCount::from_bytes(self.end.value() - self.base.value())
+    }
+
+    /// Returns the number of frames the bootloader actually used for tables.
+    #[must_use]
+    pub fn used_size(&self) -> FrameCount {
+        FrameCount::from_bytes(self.used_end.value() - self.base.value())
+    }
+}
+
 /// An array of memory map entries representing the usable memory at boot time.
 ///
 /// The `N` constant defines the maximum number of entries that can be stored.
@@ -51,9 +95,51 @@ impl<const N: usize> MemMapEntries<N> {
     }
 
     /// Adds a new usable memory map entry to the entries.
-    pub fn push_usable(&mut self, entry: MemMapEntry) {
-        self.usable_entries[self.usable_entry_count] = entry;
+    ///
+    /// Returns [`MemError::MemMapFull`] if all `N` entries are already in use.
+    pub fn push_usable(&mut self, entry: MemMapEntry) -> Result<(), MemError> {
+        let slot = self
+            .usable_entries
+            .get_mut(self.usable_entry_count)
+            .ok_or(MemError::MemMapFull)?;
+        *slot = entry;
         self.usable_entry_count += 1;
+        Ok(())
+    }
+
+    /// Adds the page-aligned parts of `range` that don't overlap any of `reserved` as usable
+    /// memory map entries.
+    ///
+    /// `reserved` must be sorted by start address. Returns [`MemError::MemMapFull`] if the
+    /// entries run out, in which case the rest of `range` is left out.
+    pub fn push_usable_except(
+        &mut self,
+        range: Range<usize>,
+        reserved: &[Range<usize>],
+    ) -> Result<(), MemError> {
+        let mut start = range.start.next_multiple_of(Arch::PAGE_SIZE);
+        let end = range.end - range.end % Arch::PAGE_SIZE;
+        for hole in reserved {
+            let hole_start = hole.start - hole.start % Arch::PAGE_SIZE;
+            let hole_end = hole.end.next_multiple_of(Arch::PAGE_SIZE);
+            if hole_end <= start || hole_start >= end {
+                continue;
+            }
+            if hole_start > start {
+                self.push_usable(MemMapEntry {
+                    base: PhysAddr::new_canonical(start),
+                    size: FrameCount::from_bytes(hole_start - start),
+                })?;
+            }
+            start = start.max(hole_end);
+        }
+        if start < end {
+            self.push_usable(MemMapEntry {
+                base: PhysAddr::new_canonical(start),
+                size: FrameCount::from_bytes(end - start),
+            })?;
+        }
+        Ok(())
     }
 
     /// Returns a slice of usable memory map entries.
@@ -96,6 +182,8 @@ pub unsafe fn map_memory(boot_info: &BootInfo) {
         unsafe { flush.ignore() }
     }
 
+    map_boot_modules(&mut table, boot_info);
+
     log::debug!("mapping kernel");
 
     let kernel_base = __kernel_phys_start();
@@ -112,13 +200,7 @@ pub unsafe fn map_memory(boot_info: &BootInfo) {
         let phys = PhysAddr::new_canonical(kernel_base + frame_idx * Arch::PAGE_SIZE);
         let virt = VirtAddr::new_canonical(KERNEL_OFFSET + frame_idx * Arch::PAGE_SIZE);
 
-        let flags = if (__text_start()..__text_end()).contains(&virt.value()) {
-            PageFlags::new_for_text_segment()
-        } else if (__rodata_start()..__rodata_end()).contains(&virt.value()) {
-            PageFlags::new_for_rodata_segment()
-        } else {
-            PageFlags::new_for_data_segment()
-        };
+        let flags = kernel_image_flags(virt);
         let flush = table
             .map_to(virt, phys, BlockSize::Page4KiB, flags)
             .unwrap();
@@ -126,7 +208,7 @@ pub unsafe fn map_memory(boot_info: &BootInfo) {
 
         let virt = phys.as_hhdm_virt();
         let flush = table
-            .map_to(virt, phys, BlockSize::Page4KiB, flags)
+            .map_to(virt, phys, BlockSize::Page4KiB, hhdm_alias_flags(flags))
             .unwrap();
         unsafe { flush.ignore() }
     }
@@ -154,11 +236,196 @@ pub unsafe fn map_memory(boot_info: &BootInfo) {
         .unwrap();
     unsafe { flush.ignore() };
 
+    map_kernel_stack(&mut table);
+
     unsafe {
         Arch::init_mem(&mut table);
         log::debug!("Making new page table current");
         table.make_current();
     }
+    fixmap::init(&mut table).unwrap();
+
+    // the peripherals are mapped in the HHDM now; stop relying on the bootloader's identity map
+    crate::arch::serial::use_hhdm();
+    crate::arch::earlycon::use_hhdm();
 
     log::debug!("New page table: {:?}", table.phys_addr());
 }
+
+/// Returns the flags for the page of the kernel image at `virt`: text is read-only and
+/// executable, rodata read-only, and everything else writable but not executable.
+pub(crate) fn kernel_image_flags(virt: VirtAddr) -> PageFlags {
+    if (__text_start()..__text_end()).contains(&virt.value()) {
+        PageFlags::new_for_text_segment()
+    } else if (__rodata_start()..__rodata_end()).contains(&virt.value()) {
+        PageFlags::new_for_rodata_segment()
+    } else {
+        PageFlags::new_for_data_segment()
+    }
+}
+
+/// Returns the flags for the HHDM alias of a kernel image page mapped with `flags`. The code
+/// only ever runs from the kernel's own mapping, so its alias isn't executable.
+pub(crate) fn hhdm_alias_flags(flags: PageFlags) -> PageFlags {
+    if flags.is_executable() {
+        PageFlags::new_for_rodata_segment()
+    } else {
+        flags
+    }
+}
+
+/// Maps the kernel's main stack, leaving the page below it unmapped as a guard.
+fn map_kernel_stack(table: &mut PageTable) {
+    log::debug!("mapping kernel stack");
+    let frames = unsafe {
+        KernelFrameAllocator
+            .allocate(FrameCount::from_bytes(KERNEL_STACK_SIZE))
+            .unwrap()
+    };
+    log::debug!(
+        ">>> {} .. {} => {} .. {}",
+        frames,
+        frames.add_bytes(KERNEL_STACK_SIZE),
+        VirtAddr::new_canonical(KERNEL_STACK_BOTTOM),
+        VirtAddr::new_canonical(KERNEL_STACK_TOP),
+    );
+    // 4KiB pages only, so nothing can spill a block mapping over the guard page
+    let flush = table
+        .map_range_with_block_size(
+            VirtAddr::new_canonical(KERNEL_STACK_BOTTOM),
+            frames,
+            KERNEL_STACK_SIZE,
+            BlockSize::Page4KiB,
+            PageFlags::new_for_data_segment(),
+        )
+        .unwrap();
+    unsafe { flush.ignore() };
+}
+
+/// Maps what the firmware loaded alongside the kernel, and the crash region, into the HHDM.
+fn map_boot_modules(table: &mut PageTable, boot_info: &BootInfo) {
+    map_fdt(table, boot_info);
+    if let Some(initrd) = &boot_info.initrd {
+        log::debug!("mapping initrd");
+        map_hhdm(table, initrd.start, initrd.end, PageFlags::new_for_rodata_segment());
+    }
+    if let Some(crash_region) = &boot_info.crash_region {
+        log::debug!("mapping crash region");
+        map_hhdm(table, crash_region.start, crash_region.end, PageFlags::new_for_data_segment());
+    }
+}
+
+/// Maps the FDT into the HHDM if it doesn't live in memory we've already mapped there.
+///
+/// The kernel reads the FDT through the HHDM, so it has to stay reachable after the switch.
+fn map_fdt(table: &mut PageTable, boot_info: &BootInfo) {
+    let Some(fdt) = boot_info.fdt.as_ref() else {
+        return;
+    };
+
+    let fdt_phys = boot_info.dtb_phys;
+    let fdt_end = fdt_phys.add_bytes(fdt.total_size());
+    let covered = boot_info.mem_map.usable_entries().iter().any(|entry| {
+        entry.base <= fdt_phys && fdt_end <= entry.base.add_bytes(entry.size.to_bytes())
+    });
+    if covered {
+        return;
+    }
+
+    log::debug!("mapping FDT");
+    map_hhdm(table, fdt_phys, fdt_end, PageFlags::new_for_rodata_segment());
+}
+
+/// Maps the physical range `start..end` into the HHDM, for memory the frame allocator doesn't
+/// own but the kernel still needs.
+fn map_hhdm(table: &mut PageTable, start: PhysAddr, end: PhysAddr, flags: PageFlags) {
+    let base = start.align_down(Arch::PAGE_SIZE);
+    let size = FrameCount::from_bytes(end.value() - base.value());
+    let flush = table
+        .map_range_with_block_size(
+            base.as_hhdm_virt(),
+            base,
+            size.to_bytes(),
+            BlockSize::Page4KiB,
+            flags,
+        )
+        .unwrap();
+    unsafe { flush.ignore() }
+}
+
+/// Unmaps the boot stack from the kernel image and hands its frames back to the frame allocator.
+///
+/// # Safety
+///
+/// Must be called on the kernel stack (see [`KERNEL_STACK_TOP`]), once the frame allocator
+/// can free frames.
+pub unsafe fn reclaim_boot_stack() {
+    let bottom = VirtAddr::new_canonical(__stack_bottom());
+    let size = FrameCount::from_bytes(__stack_top() - __stack_bottom());
+    let phys = PhysAddr::new_canonical(__kernel_phys_start() + (bottom.value() - KERNEL_OFFSET));
+
+    let mut table = PageTable::current(TableKind::Kernel);
+    for frame_idx in 0..size.frame_count() {
+        let page = bottom.add_bytes(frame_idx * Arch::PAGE_SIZE);
+        match table.with_frame_mut(page, |entry| *entry = PageTableEntry::from_raw(0)) {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                log::warn!("failed to unmap boot stack page {page}: {e}");
+                return;
+            }
+        }
+    }
+
+    if let Err(e) = KernelFrameAllocator.free(phys, size) {
+        log::warn!("failed to reclaim boot stack: {e}");
+        return;
+    }
+
+    log::debug!("reclaimed {} boot stack frames", size.frame_count());
+}
+
+/// Hands the bootloader's page table pool back to the frame allocator.
+///
+/// The pool lives in the boot image, which isn't part of the usable memory map, so it's
+/// mapped into the HHDM first. The user-half table (still the bootloader's root table at
+/// this point) is replaced with [`asid::empty_table`] before anything is freed.
+///
+/// # Safety
+///
+/// Must be called after [`map_memory`] and once the frame allocator can free frames.
+/// Nothing may rely on the bootloader's identity mapping after this.
+///
+/// # Panics
+///
+/// This function will panic if the pool cannot be mapped.
+pub unsafe fn reclaim_boot_tables(boot_info: &BootInfo) {
+    let boot_tables = &boot_info.boot_tables;
+
+    unsafe {
+        Arch::set_current_page_table(asid::empty_table(), TableKind::User);
+    }
+
+    let mut kernel_table = PageTable::current(TableKind::Kernel);
+    let size = boot_tables.pool_size();
+    kernel_table
+        .map_range_with_block_size(
+            boot_tables.base.as_hhdm_virt(),
+            boot_tables.base,
+            size.to_bytes(),
+            BlockSize::Page4KiB,
+            PageFlags::new_for_data_segment(),
+        )
+        .unwrap()
+        .flush();
+
+    if let Err(e) = KernelFrameAllocator.free(boot_tables.base, size) {
+        log::warn!("failed to reclaim boot page tables: {e}");
+        return;
+    }
+
+    log::debug!(
+        "reclaimed {} boot table frames ({} were in use)",
+        size.frame_count(),
+        boot_tables.used_size().frame_count(),
+    );
+}
@@ -6,16 +6,18 @@ use core::{
 use derive_more::{BitAnd, BitOr, BitXor};
 
 use crate::{
+    __text_end, __text_start,
     arch::{Arch, Architecture},
     mem::{
         MemError,
-        units::{PhysAddr, VirtAddr},
+        units::{FrameCount, PhysAddr, VirtAddr},
     },
     print, println,
 };
 
 use super::{
     allocator::KernelFrameAllocator,
+    fixmap,
     flush::{PageFlush, PageFlushAll},
 };
 
@@ -172,6 +174,12 @@ impl PageTable {
         }
     }
 
+    /// Returns whether this is a user or kernel page table.
+    #[must_use]
+    pub fn kind(&self) -> TableKind {
+        self.kind
+    }
+
     /// Returns the physical address of the base of the page table.
     #[must_use]
     pub fn phys_addr(&self) -> PhysAddr {
@@ -228,6 +236,17 @@ impl PageTable {
         }
     }
 
+    /// Replaces the entry at `index`, which may be in use, with `entry`, by break-before-make.
+    /// See [`fixmap`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the memory the entry maps until it's replaced, on any CPU.
+    unsafe fn replace_entry(&mut self, index: usize, entry: PageTableEntry) {
+        let addr = self.frame.add_bytes(index * size_of::<PageTableEntry>());
+        unsafe { fixmap::replace(addr, entry) };
+    }
+
     /// Returns the next-down page table at the given entry index, if it exists and this is not a level-1 table.
     pub fn next_table(&self, index: usize) -> Result<PageTable, MemError> {
         let next_level = self.level.next_down().ok_or(MemError::NoNextTable)?;
@@ -255,6 +274,8 @@ impl PageTable {
         if entry.is_table() {
             entry.insert_flags(insert_flags);
             unsafe { self.set_entry(index, entry) };
+        } else if self.is_block(index) {
+            return self.split_block(index, insert_flags);
         } else {
             let frame = unsafe { KernelFrameAllocator.allocate_one()? };
             unsafe { self.set_entry(index, PageTableEntry::new(frame, insert_flags)) };
@@ -268,25 +289,227 @@ impl PageTable {
         })
     }
 
+    /// Creates whichever tables on the way to `page` don't exist yet, down to level 1, and
+    /// returns the physical address of the level-1 entry for it, which is left as it is.
+    pub(super) fn create_pte(&mut self, page: VirtAddr) -> Result<PhysAddr, MemError> {
+        let insert_flags = PageFlags::new_table();
+        let mut p3 =
+            self.next_table_create(page.page_table_index(PageTableLevel::Level4), insert_flags)?;
+        let mut p2 =
+            p3.next_table_create(page.page_table_index(PageTableLevel::Level3), insert_flags)?;
+        let p1 =
+            p2.next_table_create(page.page_table_index(PageTableLevel::Level2), insert_flags)?;
+        let index = page.page_table_index(PageTableLevel::Level1);
+        Ok(p1.frame.add_bytes(index * size_of::<PageTableEntry>()))
+    }
+
     /// Translates a virtual address to a level-1 page table entry, allowing access to the page's frame and flags.
+    ///
+    /// Inside a block, the entry returned is the one the page would have if the block were
+    /// split down to pages.
     pub fn translate(&self, addr: VirtAddr) -> Result<PageTableEntry, MemError> {
-        let p3 = self.next_table(addr.page_table_index(PageTableLevel::Level4))?;
-        let p2 = p3.next_table(addr.page_table_index(PageTableLevel::Level3))?;
-        let p1 = p2.next_table(addr.page_table_index(PageTableLevel::Level2))?;
-        unsafe { Ok(p1.entry(addr.page_table_index(PageTableLevel::Level1))) }
+        let mut table = self.next_table(addr.page_table_index(PageTableLevel::Level4))?;
+        while table.level != PageTableLevel::Level1 {
+            let index = addr.page_table_index(table.level);
+            if table.is_block(index) {
+                let block = unsafe { table.entry(index) };
+                let offset = addr.value() & ((1 << table.level.shift()) - 1);
+                return Ok(PageTableEntry::new(
+                    block.addr()?.add_bytes(offset).align_down(Arch::PAGE_SIZE),
+                    Self::child_flags(block.flags(), PageTableLevel::Level1),
+                ));
+            }
+            table = table.next_table(index)?;
+        }
+        unsafe { Ok(table.entry(addr.page_table_index(PageTableLevel::Level1))) }
+    }
+
+    /// Returns `true` if the entry at `index` maps a block of memory, rather than a page or
+    /// a table.
+    fn is_block(&self, index: usize) -> bool {
+        if self.level == PageTableLevel::Level1 {
+            return false;
+        }
+        let entry = unsafe { self.entry(index) };
+        entry.flags().is_present() && !entry.is_table()
     }
 
-    /// Allows modification of a page table entry at the given virtual address.
+    /// Returns the flags for the entries of a `level` table that map the pieces of a block,
+    /// or the page or block that several of them merge into, mapped with `flags`.
+    fn child_flags(flags: PageFlags, level: PageTableLevel) -> PageFlags {
+        let is_page = level == PageTableLevel::Level1;
+        #[cfg(target_arch = "aarch64")]
+        let flags = flags.with_flag(Arch::PAGE_FLAG_NON_BLOCK, is_page); // pages have the "table" bit set
+        flags.with_flag(Arch::PAGE_FLAG_HUGE, !is_page)
+    }
+
+    /// Returns the next-down table at `index`, splitting the block there into one if that's
+    /// what the entry maps.
+    fn next_table_split(&mut self, index: usize) -> Result<PageTable, MemError> {
+        if self.is_block(index) {
+            self.split_block(index, PageFlags::new_table())
+        } else {
+            self.next_table(index)
+        }
+    }
+
+    /// Demotes the block at `index` into a table of entries of the next size down, which map
+    /// the same memory with the same flags, and returns the table.
+    ///
+    /// The block is broken before the table replaces it (see [`fixmap`]), so nothing may use
+    /// the memory it maps meanwhile, on any CPU. The kernel's text is mapped with pages for
+    /// that reason, so it never has to be split.
+    fn split_block(
+        &mut self,
+        index: usize,
+        insert_flags: PageFlags,
+    ) -> Result<PageTable, MemError> {
+        let next_level = self.level.next_down().ok_or(MemError::NoNextTable)?;
+        let block = unsafe { self.entry(index) };
+        let base = block.addr()?;
+        let flags = Self::child_flags(block.flags(), next_level);
+
+        let frame = unsafe { KernelFrameAllocator.allocate_one()? };
+        let mut table = PageTable {
+            frame,
+            level: next_level,
+            kind: self.kind,
+        };
+        for i in 0..Arch::PAGE_ENTRIES {
+            let entry = PageTableEntry::new(base.add_bytes(i << next_level.shift()), flags);
+            unsafe { table.set_entry(i, entry) };
+        }
+        unsafe { self.replace_entry(index, PageTableEntry::new(frame, insert_flags)) };
+        Ok(table)
+    }
+
+    /// Promotes the table at `index` to a single block, freeing it, if its entries are all
+    /// pages (or all blocks) mapping contiguous, suitably aligned memory with the same flags.
+    /// Returns `true` if it was promoted.
+    ///
+    /// The table is broken before the block replaces it, as in [`Self::split_block`].
+    fn try_merge(&mut self, index: usize) -> bool {
+        // blocks are only 2MiB or 1GiB
+        if !matches!(self.level, PageTableLevel::Level2 | PageTableLevel::Level3) {
+            return false;
+        }
+        let Ok(child) = self.next_table(index) else {
+            return false;
+        };
+        let first = unsafe { child.entry(0) };
+        let Ok(base) = first.addr() else {
+            return false;
+        };
+        if !first.flags().is_present() || !base.is_aligned(1 << self.level.shift()) {
+            return false;
+        }
+        let uniform = (0..Arch::PAGE_ENTRIES).all(|i| {
+            let entry = unsafe { child.entry(i) };
+            entry.flags().raw() == first.flags().raw()
+                && (child.level == PageTableLevel::Level1 || child.is_block(i))
+                && entry
+                    .addr()
+                    .is_ok_and(|addr| addr == base.add_bytes(i << child.level.shift()))
+        });
+        if !uniform {
+            return false;
+        }
+
+        let flags = Self::child_flags(first.flags(), self.level);
+        unsafe { self.replace_entry(index, PageTableEntry::new(base, flags)) };
+        KernelFrameAllocator.free(child.frame, FrameCount::ONE).ok();
+        true
+    }
+
+    /// Promotes the tables mapping `page..page + size` to blocks wherever they've come to map
+    /// whole blocks' worth of memory the same way, as after a split block's pages all get
+    /// their old flags back.
+    ///
+    /// The kernel's text is never merged, since it couldn't run while its table was broken.
+    pub fn merge_range(&mut self, page: VirtAddr, size: usize) -> PageFlushAll {
+        let end = page.value().saturating_add(size);
+        let text = __text_start()..__text_end();
+        for level in [PageTableLevel::Level2, PageTableLevel::Level3] {
+            let block = 1 << level.shift();
+            let mut addr = page.value() & !(block - 1);
+            while addr < end {
+                let virt = VirtAddr::new_canonical(addr);
+                let has_text = addr < text.end && text.start < addr.saturating_add(block);
+                if !has_text && let Ok(mut table) = self.table_at(virt, level) {
+                    table.try_merge(virt.page_table_index(level));
+                }
+                addr = match addr.checked_add(block) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+        }
+        PageFlushAll
+    }
+
+    /// Returns the `level` table on the way to `addr`, if there is one.
+    fn table_at(&self, addr: VirtAddr, level: PageTableLevel) -> Result<PageTable, MemError> {
+        let mut table = PageTable {
+            frame: self.frame,
+            level: self.level,
+            kind: self.kind,
+        };
+        while table.level != level {
+            table = table.next_table(addr.page_table_index(table.level))?;
+        }
+        Ok(table)
+    }
+
+    /// Sets the flags of every page mapped in `page..page + size` (rounded out to whole pages)
+    /// to `flags`, splitting blocks that are only partly in the range and merging what it can
+    /// afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MemError::PageNotMapped`] for a page in the range that isn't mapped, or
+    /// whatever splitting a block failed with. Pages before it have already been changed.
+    pub fn protect_range(
+        &mut self,
+        page: VirtAddr,
+        size: usize,
+        flags: PageFlags,
+    ) -> Result<PageFlushAll, MemError> {
+        let start = page.align_down(Arch::PAGE_SIZE);
+        let end = page.add_bytes(size).align_up(Arch::PAGE_SIZE);
+        let mut virt = start;
+        while virt < end {
+            let mut missing = false;
+            let flush = self.with_frame_mut(virt, |entry| match entry.addr() {
+                Ok(frame) if entry.flags().is_present() => {
+                    *entry = PageTableEntry::new(frame, flags);
+                }
+                _ => missing = true,
+            })?;
+            unsafe { flush.ignore() };
+            if missing {
+                return Err(MemError::PageNotMapped(virt));
+            }
+            virt = virt.add_bytes(Arch::PAGE_SIZE);
+        }
+        unsafe {
+            self.merge_range(start, end.value() - start.value())
+                .ignore();
+        }
+        Ok(PageFlushAll)
+    }
+
+    /// Allows modification of a page table entry at the given virtual address, splitting any
+    /// block it's in down to pages first.
     ///
     /// Returns a [`PageFlush`] that must be flushed after the modification.
-    pub fn with_frame_mut<R>(
+    pub fn with_frame_mut(
         &mut self,
         addr: VirtAddr,
         f: impl FnOnce(&mut PageTableEntry),
     ) -> Result<PageFlush, MemError> {
-        let p3 = self.next_table(addr.page_table_index(PageTableLevel::Level4))?;
-        let p2 = p3.next_table(addr.page_table_index(PageTableLevel::Level3))?;
-        let mut p1 = p2.next_table(addr.page_table_index(PageTableLevel::Level2))?;
+        let mut p3 = self.next_table(addr.page_table_index(PageTableLevel::Level4))?;
+        let mut p2 = p3.next_table_split(addr.page_table_index(PageTableLevel::Level3))?;
+        let mut p1 = p2.next_table_split(addr.page_table_index(PageTableLevel::Level2))?;
         let mut entry = unsafe { p1.entry(addr.page_table_index(PageTableLevel::Level1)) };
         f(&mut entry);
         unsafe {
@@ -373,6 +596,9 @@ impl PageTable {
     }
 
     /// Remaps a range of pages to frames in the kernel address space.
+    ///
+    /// Blocks the range only partly covers are split, and afterwards anything that can be is
+    /// merged back into blocks, such as when the range is remapped the way it already was.
     pub fn kernel_remap_range(
         &mut self,
         mut page: VirtAddr,
@@ -380,6 +606,7 @@ impl PageTable {
         mut size: usize,
         flags: PageFlags,
     ) -> Result<PageFlushAll, MemError> {
+        let (start, total) = (page, size);
         while size != 0 {
             let block_size = BlockSize::largest_aligned(page, frame, size);
             let flush = self.remap_to(page, frame, block_size, flags)?;
@@ -389,6 +616,7 @@ impl PageTable {
             frame = frame.add_bytes(block_size.size());
             size -= block_size.size();
         }
+        unsafe { self.merge_range(start, total).ignore() };
         Ok(PageFlushAll)
     }
 
@@ -408,12 +636,21 @@ impl PageTable {
         let idx = page.page_table_index(PageTableLevel::Level3);
         let entry = unsafe { p3.entry(idx) };
         if entry.is_unused() || remap {
-            unsafe {
-                p3.set_entry(
-                    idx,
-                    PageTableEntry::new(frame, flags.with_flag(Arch::PAGE_FLAG_HUGE, true)),
-                );
-            };
+            let block = PageTableEntry::new(frame, flags.with_flag(Arch::PAGE_FLAG_HUGE, true));
+            if entry.flags().is_present() {
+                // a table, or a block of other memory, that may be in use
+                unsafe { p3.replace_entry(idx, block) };
+            } else {
+                unsafe { p3.set_entry(idx, block) };
+            }
+            if entry.is_table() {
+                PageTable {
+                    frame: entry.addr()?,
+                    level: PageTableLevel::Level2,
+                    kind: self.kind,
+                }
+                .free_tables();
+            }
         } else {
             return Err(MemError::PageAlreadyMapped(page, entry));
         }
@@ -439,12 +676,21 @@ impl PageTable {
         let entry = unsafe { p2.entry(idx) };
 
         if entry.is_unused() || remap {
-            unsafe {
-                p2.set_entry(
-                    idx,
-                    PageTableEntry::new(frame, flags.with_flag(Arch::PAGE_FLAG_HUGE, true)),
-                );
-            };
+            let block = PageTableEntry::new(frame, flags.with_flag(Arch::PAGE_FLAG_HUGE, true));
+            if entry.flags().is_present() {
+                // a table, or a block of other memory, that may be in use
+                unsafe { p2.replace_entry(idx, block) };
+            } else {
+                unsafe { p2.set_entry(idx, block) };
+            }
+            if entry.is_table() {
+                PageTable {
+                    frame: entry.addr()?,
+                    level: PageTableLevel::Level1,
+                    kind: self.kind,
+                }
+                .free_tables();
+            }
         } else {
             return Err(MemError::PageAlreadyMapped(page, entry));
         }
@@ -476,6 +722,18 @@ impl PageTable {
         Ok(PageFlush::new(page))
     }
 
+    /// Frees this table, and the tables below it. What they map is left alone.
+    fn free_tables(self) {
+        if self.level != PageTableLevel::Level1 {
+            for index in 0..Arch::PAGE_ENTRIES {
+                if let Ok(next) = self.next_table(index) {
+                    next.free_tables();
+                }
+            }
+        }
+        KernelFrameAllocator.free(self.frame, FrameCount::ONE).ok();
+    }
+
     /// Dumps the page table entries to the console, showing their addresses and flags.
     /// This is VERY verbose and should only be used for debugging purposes.
     pub fn dump(&self) {
@@ -600,13 +858,17 @@ pub struct PageFlags(usize);
 
 impl PageFlags {
     /// Creates a new set of page flags with default values.
+    ///
+    /// Pages are global, since the kernel half is the same in every address space. User pages
+    /// are made non-global by [`PageFlags::user`], so their TLB entries are tagged with the
+    /// address space's ASID.
     #[must_use]
     pub const fn new() -> Self {
         Self(
             Arch::PAGE_FLAG_PAGE_DEFAULTS
                 | Arch::PAGE_FLAG_READONLY
                 | Arch::PAGE_FLAG_NON_EXECUTABLE
-                | Arch::PAGE_FLAG_NON_GLOBAL,
+                | Arch::PAGE_FLAG_GLOBAL,
         )
     }
 
@@ -622,14 +884,14 @@ impl PageFlags {
         Self(Arch::PAGE_FLAG_TABLE_DEFAULTS)
     }
 
-    /// Creates a new set of page flags for a text segment, which is executable, and writable in debug builds.
+    /// Creates a new set of page flags for a text segment, which is executable but never
+    /// writable. Breakpoints are patched in with [`crate::mem::protect::patch_text`].
     #[must_use]
     pub const fn new_for_text_segment() -> Self {
-        if cfg!(debug_assertions) {
-            Self::new().executable().writable() // for inserting breakpoints
-        } else {
-            Self::new().executable()
-        }
+        let flags = Self::new().executable();
+        #[cfg(all(target_arch = "aarch64", feature = "pauth"))]
+        let flags = flags.with_flag(Arch::PAGE_FLAG_GUARDED, true);
+        flags
     }
 
     /// Creates a new set of page flags for a read-only data segment.
@@ -651,6 +913,14 @@ impl PageFlags {
         Self::from_raw(Arch::PAGE_FLAG_DEVICE)
     }
 
+    /// Creates a new set of page flags for memory shared with DMA masters: writable, and not
+    /// cached, so the CPU and the device see each other's writes without cache maintenance.
+    #[cfg(target_arch = "aarch64")]
+    #[must_use]
+    pub fn new_dma() -> Self {
+        Self::from_raw(Arch::PAGE_FLAG_DMA)
+    }
+
     /// Creates a new set of page flags from a raw unsigned double word value.
     #[must_use]
     pub const fn from_raw(raw: usize) -> Self {
@@ -712,12 +982,35 @@ impl PageFlags {
         self.0 & (Arch::PAGE_FLAG_READONLY | Arch::PAGE_FLAG_READWRITE) == Arch::PAGE_FLAG_READWRITE
     }
 
+    /// Returns `true` if the page flags contain the "user" flag.
+    #[must_use]
+    pub const fn is_user(&self) -> bool {
+        self.has_flags(Arch::PAGE_FLAG_USER)
+    }
+
+    /// Sets the "user" flag in the page flags, making the page accessible from userspace, and
+    /// makes it non-global.
+    #[must_use]
+    pub const fn user(self) -> Self {
+        self.with_flag(Arch::PAGE_FLAG_USER, true)
+            .with_flag(Arch::PAGE_FLAG_GLOBAL, false)
+            .with_flag(Arch::PAGE_FLAG_NON_GLOBAL, true)
+    }
+
     /// Sets the "writable" flag in the page flags, clearing the "readonly" flag.
     #[must_use]
     pub const fn writable(self) -> Self {
         self.with_flag(Arch::PAGE_FLAG_READONLY | Arch::PAGE_FLAG_READWRITE, false)
             .with_flag(Arch::PAGE_FLAG_READWRITE, true)
     }
+
+    /// Makes the page normal memory that isn't cached, so a device reading it sees the CPU's
+    /// writes without cache maintenance.
+    #[must_use]
+    pub const fn uncached(self) -> Self {
+        self.with_flag(Arch::PAGE_FLAG_NORMAL, false)
+            .with_flag(Arch::PAGE_FLAG_NON_CACHEABLE, true)
+    }
 }
 
 impl Debug for PageFlags {
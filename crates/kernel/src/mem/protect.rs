@@ -0,0 +1,238 @@
+//! Protections on the kernel's own mappings, enforced and audited once init is done.
+//!
+//! The kernel image is mapped W^X from the start: text read-only and executable, rodata
+//! read-only, and data, BSS and the stacks writable but not executable. Its HHDM alias is never
+//! executable. Both are mapped with pages, not blocks, so changing one page's permissions never
+//! splits a block the kernel is running from. [`init`] puts any page of the image that has
+//! drifted from that back, unlinks whatever is still mapped in the kernel table below the HHDM,
+//! where only identity mappings would be, and then walks the whole kernel table reporting any
+//! page that's both writable and executable.
+//!
+//! Code is only ever changed with [`patch_text`], which writes through a writable alias that
+//! exists just long enough to do so.
+
+use core::ops::Range;
+
+use crate::{
+    __kernel_phys_end, __kernel_phys_start, __text_end, __text_start, BootInfo, KERNEL_OFFSET,
+    arch::{Arch, Architecture},
+    mem::{
+        paging::{
+            KERNEL_STACK_BOTTOM, asid, hhdm_alias_flags, kernel_image_flags,
+            table::{PageFlags, PageTable, PageTableEntry, TableKind},
+        },
+        units::{PhysAddr, VirtAddr},
+    },
+    syscall::errno::Errno,
+};
+
+/// The lowest address the kernel table maps.
+const KERNEL_HALF_BASE: usize = 0xFFFF_0000_0000_0000;
+
+/// How many bytes an entry of the kernel table's root maps.
+const ROOT_ENTRY_SHIFT: usize = Arch::PAGE_SHIFT + Arch::PAGE_ENTRY_SHIFT * 3;
+
+/// Enforces W^X on the kernel image, removes leftover identity mappings, and reports anything
+/// in the kernel table that's still writable and executable, and whether the kernel stack's
+/// guard page is mapped.
+///
+/// To be called once drivers are up, since they may map memory while initializing.
+pub fn init(boot_info: &BootInfo) {
+    let mut table = PageTable::current(TableKind::Kernel);
+
+    let fixed = protect_image(&mut table);
+    if fixed != 0 {
+        log::warn!("protect: restored the permissions of {fixed} kernel image pages");
+    }
+
+    let stripped = strip_identity_mappings(&mut table);
+    if stripped != 0 {
+        log::warn!("protect: unlinked {stripped} identity mapped regions from the kernel table");
+    }
+    if PageTable::current(TableKind::User).phys_addr() == boot_info.boot_tables.base {
+        log::error!("protect: the bootloader's identity map is still in use; replacing it");
+        unsafe { Arch::set_current_page_table(asid::empty_table(), TableKind::User) };
+    }
+
+    let guard = VirtAddr::new_canonical(KERNEL_STACK_BOTTOM - Arch::PAGE_SIZE);
+    if table
+        .translate(guard)
+        .is_ok_and(|entry| entry.flags().is_present())
+    {
+        log::error!("protect: the kernel stack's guard page at {guard} is mapped");
+    }
+
+    let mut writable_exec = 0;
+    let mut run: Option<Range<usize>> = None;
+    for_each_leaf(
+        &table,
+        KERNEL_HALF_BASE,
+        ROOT_ENTRY_SHIFT,
+        &mut |addr, size, entry| {
+            let flags = entry.flags();
+            if !flags.is_writable() || !flags.is_executable() {
+                return;
+            }
+            writable_exec += size / Arch::PAGE_SIZE;
+            match &mut run {
+                Some(run) if run.end == addr => run.end += size,
+                run => {
+                    if let Some(done) = run.replace(addr..addr + size) {
+                        report_writable_exec(&done);
+                    }
+                }
+            }
+        },
+    );
+    if let Some(done) = run {
+        report_writable_exec(&done);
+    }
+
+    if writable_exec == 0 {
+        log::info!("protect: no writable and executable kernel pages");
+    } else {
+        log::error!("protect: {writable_exec} kernel pages are writable and executable");
+    }
+}
+
+fn report_writable_exec(range: &Range<usize>) {
+    log::error!(
+        "protect: writable and executable: {} .. {}",
+        VirtAddr::new_canonical(range.start),
+        VirtAddr::new_canonical(range.end),
+    );
+}
+
+/// Returns the physical address of the kernel image page at `virt`.
+fn kernel_phys(virt: usize) -> PhysAddr {
+    PhysAddr::new_canonical(__kernel_phys_start() + (virt - KERNEL_OFFSET))
+}
+
+/// Sets the flags of every page of the kernel image, and of its HHDM alias, to what
+/// [`kernel_image_flags`] says they should be. Returns how many had to change.
+fn protect_image(table: &mut PageTable) -> usize {
+    let size = __kernel_phys_end() - __kernel_phys_start();
+    let mut fixed = 0;
+    for offset in (0..size).step_by(Arch::PAGE_SIZE) {
+        let virt = VirtAddr::new_canonical(KERNEL_OFFSET + offset);
+        let flags = kernel_image_flags(virt);
+        let alias = kernel_phys(virt.value()).as_hhdm_virt();
+        for (page, flags) in [(virt, flags), (alias, hhdm_alias_flags(flags))] {
+            let result = table.with_frame_mut(page, |entry| {
+                // pages unmapped on purpose, like the boot stack's, stay that way
+                if entry.flags().is_present()
+                    && entry.flags().raw() != flags.raw()
+                    && let Ok(frame) = entry.addr()
+                {
+                    *entry = PageTableEntry::new(frame, flags);
+                    fixed += 1;
+                }
+            });
+            match result {
+                Ok(flush) => unsafe { flush.ignore() },
+                Err(e) => log::warn!("protect: can't check kernel image page {page}: {e}"),
+            }
+        }
+    }
+    unsafe { Arch::invalidate_all() };
+    fixed
+}
+
+/// Unlinks every entry of the kernel table's root below the HHDM. Nothing the kernel maps
+/// belongs there, so anything that is was mapped at its physical address. The tables below
+/// those entries are leaked.
+fn strip_identity_mappings(table: &mut PageTable) -> usize {
+    let hhdm = VirtAddr::new_canonical(crate::HHDM_PHYSICAL_OFFSET);
+    let end = (hhdm.value() - KERNEL_HALF_BASE) >> ROOT_ENTRY_SHIFT;
+    let mut stripped = 0;
+    for index in 0..end {
+        let entry = unsafe { table.entry(index) };
+        if entry.flags().is_present() {
+            log::warn!(
+                "protect: unlinking {} .. {}",
+                VirtAddr::new_canonical(KERNEL_HALF_BASE + (index << ROOT_ENTRY_SHIFT)),
+                VirtAddr::new_canonical(KERNEL_HALF_BASE + ((index + 1) << ROOT_ENTRY_SHIFT)),
+            );
+            unsafe { table.set_entry(index, PageTableEntry::UNUSED) };
+            stripped += 1;
+        }
+    }
+    unsafe { Arch::invalidate_all() };
+    stripped
+}
+
+/// Calls `f` with the address, size and entry of every present leaf mapping in `table`,
+/// whose entries each map `1 << shift` bytes starting at `base`.
+fn for_each_leaf(
+    table: &PageTable,
+    base: usize,
+    shift: usize,
+    f: &mut impl FnMut(usize, usize, PageTableEntry),
+) {
+    for index in 0..Arch::PAGE_ENTRIES {
+        let entry = unsafe { table.entry(index) };
+        if !entry.flags().is_present() {
+            continue;
+        }
+        let addr = base + (index << shift);
+        match table.next_table(index) {
+            Ok(next) => for_each_leaf(&next, addr, shift - Arch::PAGE_ENTRY_SHIFT, f),
+            Err(_) => f(addr, 1 << shift, entry),
+        }
+    }
+}
+
+/// Writes `bytes` over the kernel's code at `addr`, and makes the change visible to
+/// instruction fetches.
+///
+/// The text itself is never writable, so each page is written through its HHDM alias, which
+/// is made writable just for the write and then put back.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if any of the range is outside the kernel's text, or a page's
+/// alias can't be remapped.
+///
+/// # Safety
+///
+/// Nothing may be running the code being replaced.
+pub unsafe fn patch_text(addr: usize, bytes: &[u8]) -> Result<(), Errno> {
+    let end = addr.checked_add(bytes.len()).ok_or(Errno::EFAULT)?;
+    if addr < __text_start() || end > __text_end() {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut table = PageTable::current(TableKind::Kernel);
+    let mut written = 0;
+    while written < bytes.len() {
+        let virt = addr + written;
+        let page = virt & !(Arch::PAGE_SIZE - 1);
+        let len = (page + Arch::PAGE_SIZE - virt).min(bytes.len() - written);
+        let frame = kernel_phys(page);
+        let alias = frame.as_hhdm_virt();
+
+        // just the one page, since merging the alias back into a block would have it split
+        // again by the next patch
+        let mut remap = |flags: PageFlags| -> Result<(), Errno> {
+            let flush = table
+                .with_frame_mut(alias, |entry| *entry = PageTableEntry::new(frame, flags))
+                .map_err(|_| Errno::EFAULT)?;
+            unsafe {
+                flush.ignore();
+                Arch::invalidate_all();
+            }
+            Ok(())
+        };
+        remap(PageFlags::new_for_data_segment())?;
+        unsafe {
+            let dst = alias.add_bytes(virt - page).as_raw_ptr_mut::<u8>();
+            core::ptr::copy_nonoverlapping(bytes[written..].as_ptr(), dst, len);
+        }
+        remap(hhdm_alias_flags(PageFlags::new_for_text_segment()))?;
+
+        written += len;
+    }
+
+    unsafe { Arch::sync_icache(VirtAddr::new_canonical(addr), VirtAddr::new_canonical(end)) };
+    Ok(())
+}
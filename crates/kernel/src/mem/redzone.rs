@@ -0,0 +1,128 @@
+//! A debugging layer over the kernel heap, catching writes past the ends of allocations,
+//! double frees, and frees of things that were never allocated.
+//!
+//! With the `heap-debug` feature, every allocation is padded with a header and a red zone on
+//! each side:
+//!
+//! ```text
+//! | padding | size | magic | red zone | allocation | red zone |
+//!                                     ^ returned
+//! ```
+//!
+//! New allocations are filled with [`ALLOC_POISON`] and freed ones with [`FREE_POISON`], so
+//! reads of uninitialized or freed memory stand out. Freeing checks the magic and both red
+//! zones, and panics with the allocation's address if any of them were overwritten.
+
+use core::{alloc::Layout, ptr};
+
+/// The header, `size` then `magic`. The size is first, so that the magic survives the free
+/// list link the allocators underneath write at the start of a freed block.
+const HEADER_SIZE: usize = 2 * size_of::<usize>();
+const RED_ZONE_SIZE: usize = 16;
+
+/// The magic of an allocation that's live.
+const MAGIC_LIVE: usize = 0xa110_ca7e_d0d0_a110;
+/// The magic of an allocation that's been freed.
+const MAGIC_FREED: usize = 0xf4ee_d0d0_f4ee_d0d0;
+
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// What new allocations are filled with.
+pub const ALLOC_POISON: u8 = 0xcd;
+/// What freed allocations are filled with.
+pub const FREE_POISON: u8 = 0xdd;
+
+/// Returns the layout of the padded block for `layout`, and how far into it the allocation
+/// starts.
+fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    let front = (HEADER_SIZE + RED_ZONE_SIZE).next_multiple_of(layout.align());
+    let size = front
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    let layout = Layout::from_size_align(size, layout.align().max(RED_ZONE_SIZE)).ok()?;
+    Some((layout, front))
+}
+
+/// Returns the header of the allocation at `ptr`.
+#[allow(clippy::cast_ptr_alignment)] // allocations are at least 16-byte aligned, so it's too
+fn header(ptr: *mut u8) -> *mut usize {
+    ptr.wrapping_sub(RED_ZONE_SIZE + HEADER_SIZE).cast()
+}
+
+/// Allocates `layout` padded with red zones, taking the padded block from `alloc`.
+///
+/// # Safety
+///
+/// `alloc` must return either null or a block fitting the layout it's given.
+pub unsafe fn alloc(layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let Some((padded, front)) = padded(layout) else {
+        return ptr::null_mut();
+    };
+    let block = alloc(padded);
+    if block.is_null() {
+        return block;
+    }
+    unsafe {
+        let ptr = block.add(front);
+        let header = header(ptr);
+        header.write(layout.size());
+        header.add(1).write(MAGIC_LIVE);
+        ptr.sub(RED_ZONE_SIZE)
+            .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        ptr.add(layout.size())
+            .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr
+    }
+}
+
+/// Checks the allocation at `ptr` is intact and poisons it, then frees its padded block with
+/// `dealloc`.
+///
+/// # Panics
+///
+/// Panics if the allocation was already freed, wasn't allocated by [`alloc`] with `layout`,
+/// or had either of its red zones overwritten.
+///
+/// # Safety
+///
+/// `ptr` must have come from [`alloc`], and `dealloc` must free blocks from the `alloc` given
+/// to it.
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout, dealloc: impl FnOnce(*mut u8, Layout)) {
+    let (padded, front) = padded(layout).expect("freed a layout that can't have been allocated");
+    unsafe {
+        let header = header(ptr);
+        match header.add(1).read() {
+            MAGIC_LIVE => {}
+            MAGIC_FREED => panic!("heap: double free of {ptr:p} ({} bytes)", layout.size()),
+            _ => panic!(
+                "heap: free of {ptr:p} ({} bytes), which wasn't allocated or whose header was \
+                 overwritten",
+                layout.size()
+            ),
+        }
+        let size = header.read();
+        assert_eq!(
+            size,
+            layout.size(),
+            "heap: {ptr:p} was freed with a different size than it was allocated with"
+        );
+        check_red_zone(ptr, ptr.sub(RED_ZONE_SIZE), "before");
+        check_red_zone(ptr, ptr.add(size), "after");
+
+        ptr.write_bytes(FREE_POISON, size);
+        header.add(1).write(MAGIC_FREED);
+        dealloc(ptr.sub(front), padded);
+    }
+}
+
+/// Panics if any of the red zone at `zone`, `side` the allocation at `ptr`, was overwritten.
+unsafe fn check_red_zone(ptr: *mut u8, zone: *const u8, side: &str) {
+    let zone = unsafe { core::slice::from_raw_parts(zone, RED_ZONE_SIZE) };
+    if let Some(offset) = zone.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+        panic!(
+            "heap: red zone {side} {ptr:p} overwritten at {:p} (found {:#04x})",
+            zone[offset..].as_ptr(),
+            zone[offset]
+        );
+    }
+}
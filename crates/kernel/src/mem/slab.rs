@@ -0,0 +1,262 @@
+//! Slab caches for the kernel heap's small, fixed-size allocations.
+//!
+//! Task contexts, IRQ handlers and the like are allocated and freed over and over. Rather
+//! than each of those going through the buddy allocator, allocations up to
+//! [`MAX_OBJECT_SIZE`] are rounded up to a power-of-two size class and carved out of slabs:
+//! blocks of [`SLAB_SIZE`] bytes taken from the heap, each holding objects of one class.
+//! Each class has its own lock, so the heap lock is only taken to grow or shrink a cache.
+//!
+//! Every slab starts with a [`SlabHeader`] tracking its free objects. Slabs are aligned to
+//! their size, so the header of an object's slab is found by masking its address.
+
+use core::{
+    alloc::Layout,
+    ptr::{self, NonNull},
+};
+
+use spin::Mutex;
+
+use crate::sync::with_irqs_disabled;
+
+use super::heap;
+
+/// The size (and alignment) of each slab.
+pub const SLAB_SIZE: usize = 16 * 1024;
+/// The smallest size class.
+pub const MIN_OBJECT_SIZE: usize = 32;
+/// The largest size class. Anything bigger goes straight to the heap.
+pub const MAX_OBJECT_SIZE: usize = 1024;
+
+const CLASS_COUNT: usize = (MAX_OBJECT_SIZE.ilog2() - MIN_OBJECT_SIZE.ilog2() + 1) as usize;
+
+/// How many completely free slabs a cache holds on to before giving them back to the heap.
+const MAX_EMPTY_SLABS: usize = 1;
+
+static CACHES: [Mutex<SlabCache>; CLASS_COUNT] = {
+    let mut caches = [const { Mutex::new(SlabCache::new(0)) }; CLASS_COUNT];
+    let mut class = 0;
+    while class < CLASS_COUNT {
+        caches[class] = Mutex::new(SlabCache::new(MIN_OBJECT_SIZE << class));
+        class += 1;
+    }
+    caches
+};
+
+/// A free object, linked into its slab's free list.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The bookkeeping at the start of every slab.
+struct SlabHeader {
+    free: *mut FreeObject,
+    in_use: usize,
+    /// Links in the cache's list of slabs with free objects.
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+}
+
+/// The slabs of one size class.
+struct SlabCache {
+    object_size: usize,
+    /// Slabs with at least one free object. Full slabs aren't tracked; freeing an object
+    /// finds its slab from the object's address.
+    partial: *mut SlabHeader,
+    slabs: usize,
+    empty_slabs: usize,
+    in_use: usize,
+}
+
+// the raw pointers are only ever followed with the cache's lock held
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: ptr::null_mut(),
+            slabs: 0,
+            empty_slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    /// Objects start at the first multiple of the object size past the header, so they're
+    /// aligned to their size.
+    fn first_object_offset(&self) -> usize {
+        size_of::<SlabHeader>().next_multiple_of(self.object_size)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (SLAB_SIZE - self.first_object_offset()) / self.object_size
+    }
+
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        if self.partial.is_null() {
+            self.grow()?;
+        }
+
+        let slab = unsafe { &mut *self.partial };
+        let object = slab.free;
+        slab.free = unsafe { (*object).next };
+        if slab.in_use == 0 {
+            self.empty_slabs -= 1;
+        }
+        slab.in_use += 1;
+        self.in_use += 1;
+        if slab.free.is_null() {
+            unsafe { self.unlink(slab) };
+        }
+
+        NonNull::new(object.cast())
+    }
+
+    /// Frees an object allocated from this cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been returned by [`SlabCache::alloc`] on this cache and not freed since.
+    unsafe fn free(&mut self, object: NonNull<u8>) {
+        let slab = (object.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let slab = unsafe { &mut *slab };
+
+        let was_full = slab.free.is_null();
+        let object = object.cast::<FreeObject>().as_ptr();
+        unsafe { object.write(FreeObject { next: slab.free }) };
+        slab.free = object;
+        slab.in_use -= 1;
+        self.in_use -= 1;
+        if was_full {
+            unsafe { self.push(slab) };
+        }
+
+        if slab.in_use == 0 {
+            if self.empty_slabs < MAX_EMPTY_SLABS {
+                self.empty_slabs += 1;
+            } else {
+                unsafe {
+                    self.unlink(slab);
+                    heap::free_slab(NonNull::from(slab).cast());
+                }
+                self.slabs -= 1;
+            }
+        }
+    }
+
+    /// Adds a fresh slab from the heap to the cache.
+    fn grow(&mut self) -> Option<()> {
+        let base = heap::alloc_slab()?;
+
+        // thread every object onto the free list, lowest address first
+        let mut free = ptr::null_mut();
+        let first = self.first_object_offset();
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = unsafe { base.add(first + index * self.object_size) };
+            let object = object.cast::<FreeObject>().as_ptr();
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        let slab = base.cast::<SlabHeader>().as_ptr();
+        unsafe {
+            slab.write(SlabHeader {
+                free,
+                in_use: 0,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            });
+            self.push(&mut *slab);
+        }
+        self.slabs += 1;
+        self.empty_slabs += 1;
+        Some(())
+    }
+
+    /// Links a slab into the front of the partial list.
+    unsafe fn push(&mut self, slab: &mut SlabHeader) {
+        slab.prev = ptr::null_mut();
+        slab.next = self.partial;
+        if !self.partial.is_null() {
+            unsafe { (*self.partial).prev = slab };
+        }
+        self.partial = slab;
+    }
+
+    /// Unlinks a slab from the partial list.
+    unsafe fn unlink(&mut self, slab: &mut SlabHeader) {
+        if slab.prev.is_null() {
+            self.partial = slab.next;
+        } else {
+            unsafe { (*slab.prev).next = slab.next };
+        }
+        if !slab.next.is_null() {
+            unsafe { (*slab.next).prev = slab.prev };
+        }
+        slab.prev = ptr::null_mut();
+        slab.next = ptr::null_mut();
+    }
+}
+
+/// Returns the size class an allocation falls into, or `None` if it's too big for a slab.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_OBJECT_SIZE)
+        .next_power_of_two();
+    (size <= MAX_OBJECT_SIZE).then(|| (size.ilog2() - MIN_OBJECT_SIZE.ilog2()) as usize)
+}
+
+/// Returns `true` if allocations with the given layout are served from a slab cache.
+#[must_use]
+pub fn handles(layout: Layout) -> bool {
+    size_class(layout).is_some()
+}
+
+/// Allocates an object for `layout` from its slab cache.
+///
+/// Returns `None` if the layout is too big for a slab, or if the heap has no room for
+/// another slab.
+pub fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    CACHES[size_class(layout)?].lock().alloc()
+}
+
+/// Frees an object allocated with [`alloc`].
+///
+/// # Safety
+///
+/// `ptr` must have been returned by [`alloc`] with the same `layout`, and not freed since.
+pub unsafe fn dealloc(ptr: NonNull<u8>, layout: Layout) {
+    if let Some(class) = size_class(layout) {
+        unsafe { CACHES[class].lock().free(ptr) };
+    }
+}
+
+/// A snapshot of one slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// The size of the objects in the cache.
+    pub object_size: usize,
+    /// The number of objects currently allocated.
+    pub in_use: usize,
+    /// The number of objects the cache's slabs can hold.
+    pub capacity: usize,
+    /// The number of slabs the cache holds.
+    pub slabs: usize,
+}
+
+/// Returns a snapshot of every slab cache, smallest size class first.
+pub fn stats() -> impl Iterator<Item = SlabStats> {
+    CACHES.iter().map(|cache| {
+        // interrupt handlers free into the caches, so they're never locked with IRQs enabled
+        with_irqs_disabled(|| {
+            let cache = cache.lock();
+            SlabStats {
+                object_size: cache.object_size,
+                in_use: cache.in_use,
+                capacity: cache.slabs * cache.objects_per_slab(),
+                slabs: cache.slabs,
+            }
+        })
+    })
+}
@@ -127,6 +127,9 @@ impl PhysAddr {
 }
 
 /// Represents an address in virtual memory.
+///
+/// Its accessors trust the address they're given, so userspace addresses must go through
+/// [`user`](super::user) instead.
 #[derive(
     Clone,
     Copy,
@@ -0,0 +1,323 @@
+//! Safe access to the current task's user memory from the kernel.
+//!
+//! System call arguments are untrusted: a pointer from userspace might point into the kernel,
+//! at nothing, or at memory the task can't write. Every page of a user range is looked up in
+//! the task's page tables (and checked to be accessible from userspace) before anything is
+//! copied, and the copy goes through the HHDM with the address space locked, so it can't
+//! fault or race with the range being unmapped.
+
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{Arch, Architecture},
+    syscall::errno::Errno,
+    task::{addr_space::AddrSpace, context},
+};
+
+use super::units::VirtAddr;
+
+/// Types that can be copied to and from userspace byte for byte.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and it mustn't contain padding.
+pub unsafe trait UserData: Copy + 'static {}
+
+macro_rules! impl_user_data {
+    ($($ty:ty),*) => {
+        $(unsafe impl UserData for $ty {})*
+    };
+}
+
+impl_user_data!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: UserData, const N: usize> UserData for [T; N] {}
+
+unsafe impl UserData for kados_abi::syscall::FbInfo {}
+
+/// Checks that `addr..addr + len` lies entirely in the user half.
+fn check_range(addr: usize, len: usize) -> Result<(), Errno> {
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if end > VirtAddr::MAX_LOW.value() {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+/// Returns the kernel address that user address `addr` is mapped at, if the page is
+/// accessible from userspace (and writable, if `write` is set).
+fn translate(addr_space: &AddrSpace, addr: usize, write: bool) -> Result<VirtAddr, Errno> {
+    let virt = VirtAddr::new(addr).map_err(|_| Errno::EFAULT)?;
+    let entry = addr_space
+        .table
+        .translate(virt)
+        .map_err(|_| Errno::EFAULT)?;
+    let flags = entry.flags();
+    if !flags.is_present() || !flags.is_user() || (write && !flags.is_writable()) {
+        return Err(Errno::EFAULT);
+    }
+    let frame = entry.addr().map_err(|_| Errno::EFAULT)?;
+    Ok(frame
+        .add_bytes(addr & Arch::PAGE_OFFSET_MASK)
+        .as_hhdm_virt())
+}
+
+/// Calls `f` with the kernel address and length of each page-sized piece of the user range
+/// `addr..addr + len`, in order, along with the offset of the piece into the range.
+///
+/// Every page is checked before `f` is first called, so it's either called for the whole
+/// range or not at all.
+fn for_each_chunk(
+    addr: usize,
+    len: usize,
+    write: bool,
+    f: impl FnMut(VirtAddr, usize, usize),
+) -> Result<(), Errno> {
+    check_range(addr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let addr_space = cx.read().addr_space.clone().ok_or(Errno::EFAULT)?;
+    for_each_chunk_in(&addr_space.read(), addr, len, write, f)
+}
+
+/// Like [`for_each_chunk`], but in `addr_space` rather than the current task's.
+fn for_each_chunk_in(
+    addr_space: &AddrSpace,
+    addr: usize,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(VirtAddr, usize, usize),
+) -> Result<(), Errno> {
+    check_range(addr, len)?;
+
+    let chunks = || {
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset == len {
+                return None;
+            }
+            let chunk_addr = addr + offset;
+            let chunk_len =
+                (Arch::PAGE_SIZE - (chunk_addr & Arch::PAGE_OFFSET_MASK)).min(len - offset);
+            let chunk = (chunk_addr, offset, chunk_len);
+            offset += chunk_len;
+            Some(chunk)
+        })
+    };
+
+    for (chunk_addr, _, _) in chunks() {
+        translate(addr_space, chunk_addr, write)?;
+    }
+    for (chunk_addr, offset, chunk_len) in chunks() {
+        f(translate(addr_space, chunk_addr, write)?, offset, chunk_len);
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes from user address `src` into `dst`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if any of the source range isn't readable from userspace, in
+/// which case nothing is copied.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno> {
+    for_each_chunk(src, dst.len(), false, |kernel, offset, len| unsafe {
+        core::ptr::copy_nonoverlapping(
+            kernel.as_raw_ptr::<u8>(),
+            dst[offset..offset + len].as_mut_ptr(),
+            len,
+        );
+    })
+}
+
+/// Copies `src` to user address `dst`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if any of the destination range isn't writable from userspace,
+/// in which case nothing is copied.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno> {
+    for_each_chunk(dst, src.len(), true, |kernel, offset, len| unsafe {
+        core::ptr::copy_nonoverlapping(
+            src[offset..offset + len].as_ptr(),
+            kernel.as_raw_ptr_mut::<u8>(),
+            len,
+        );
+    })
+}
+
+/// Copies `src` to user address `dst` in `addr_space`, which needn't be the current task's.
+///
+/// The destination only has to be mapped for userspace, not writable by it, and what's copied
+/// is made visible to instruction fetches, so this can fill in a program's code before it
+/// starts.
+///
+/// # Errors
+///
+/// Returns [`Errno::EFAULT`] if any of the destination range isn't mapped for userspace, in
+/// which case nothing is copied.
+pub fn copy_to_addr_space(addr_space: &AddrSpace, dst: usize, src: &[u8]) -> Result<(), Errno> {
+    for_each_chunk_in(
+        addr_space,
+        dst,
+        src.len(),
+        false,
+        |kernel, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(
+                src[offset..offset + len].as_ptr(),
+                kernel.as_raw_ptr_mut::<u8>(),
+                len,
+            );
+            Arch::sync_icache(kernel, kernel.add_bytes(len));
+        },
+    )
+}
+
+/// A pointer to a `T` in the current task's user memory.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: UserData> UserPtr<T> {
+    /// Wraps a user address, such as a system call argument.
+    #[must_use]
+    pub const fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the user address.
+    #[must_use]
+    pub const fn addr(self) -> usize {
+        self.addr
+    }
+
+    fn check_align(self) -> Result<(), Errno> {
+        if self.addr.is_multiple_of(align_of::<T>()) {
+            Ok(())
+        } else {
+            Err(Errno::EFAULT)
+        }
+    }
+
+    /// Copies the value in from userspace.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EFAULT`] if the pointer is misaligned, or the value isn't readable
+    /// from userspace.
+    pub fn read(self) -> Result<T, Errno> {
+        self.check_align()?;
+        let mut val = core::mem::MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(val.as_mut_ptr().cast::<u8>(), size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Copies `val` out to userspace.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EFAULT`] if the pointer is misaligned, or the value isn't writable
+    /// from userspace.
+    pub fn write(self, val: T) -> Result<(), Errno> {
+        self.check_align()?;
+        let bytes =
+            unsafe { core::slice::from_raw_parts((&raw const val).cast::<u8>(), size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// A range of bytes in the current task's user memory.
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    /// Wraps a user address and length, such as a system call's buffer arguments.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EFAULT`] if the range doesn't lie entirely in the user half. Whether
+    /// it's mapped is only checked when it's accessed.
+    pub fn new(addr: usize, len: usize) -> Result<Self, Errno> {
+        check_range(addr, len)?;
+        Ok(Self { addr, len })
+    }
+
+    /// Returns the length of the range in bytes.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the range is empty.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the part of the range from `offset`, at most `len` bytes long.
+    #[must_use]
+    pub fn subslice(&self, offset: usize, len: usize) -> Self {
+        let offset = offset.min(self.len);
+        Self {
+            addr: self.addr + offset,
+            len: len.min(self.len - offset),
+        }
+    }
+
+    /// Copies the start of the range into `buf`, returning how many bytes were copied.
+    ///
+    /// # Errors
+    ///
+    /// See [`copy_from_user`].
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let len = buf.len().min(self.len);
+        copy_from_user(&mut buf[..len], self.addr)?;
+        Ok(len)
+    }
+
+    /// Copies the whole range into a new vector.
+    ///
+    /// # Errors
+    ///
+    /// See [`copy_from_user`].
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, Errno> {
+        let mut buf = alloc::vec![0; self.len];
+        copy_from_user(&mut buf, self.addr)?;
+        Ok(buf)
+    }
+
+    /// Copies `buf` to the start of the range, returning how many bytes were copied.
+    ///
+    /// # Errors
+    ///
+    /// See [`copy_to_user`].
+    pub fn write_from(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let len = buf.len().min(self.len);
+        copy_to_user(self.addr, &buf[..len])?;
+        Ok(len)
+    }
+}
@@ -0,0 +1,58 @@
+//! Network devices.
+//!
+//! There's no network stack yet. Drivers register a [`NetDevice`] here, and whatever wants
+//! to send or receive raw Ethernet frames looks it up by name.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+use crate::{sync::TaskMutex, syscall::errno::Errno};
+
+/// A shared handle to a registered network device.
+pub type NetDeviceRef = Arc<TaskMutex<Box<dyn NetDevice>>>;
+
+/// The registered network devices, by name.
+static DEVICES: TaskMutex<Vec<(String, NetDeviceRef)>> = TaskMutex::new(Vec::new());
+
+/// A device that sends and receives Ethernet frames.
+pub trait NetDevice: Send {
+    /// Returns the device's MAC address.
+    fn mac_address(&self) -> [u8; 6];
+
+    /// Returns the largest frame the device sends or receives, headers included.
+    fn max_frame_len(&self) -> usize;
+
+    /// Sends a frame, waiting until the device has taken it.
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), Errno>;
+
+    /// Copies the next received frame into `buf` and returns its length, or returns `None`
+    /// if nothing has arrived.
+    ///
+    /// Frames that don't fit in `buf` are dropped with [`Errno::EMSGSIZE`].
+    fn receive(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Errno>;
+}
+
+/// Registers a network device under `name` and returns a handle to it.
+///
+/// # Errors
+///
+/// Returns [`Errno::EEXIST`] if a device is already registered under that name.
+pub fn register(name: String, device: Box<dyn NetDevice>) -> Result<NetDeviceRef, Errno> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(n, _)| *n == name) {
+        return Err(Errno::EEXIST);
+    }
+    log::info!("net device {name}: MAC {:02x?}", device.mac_address());
+    let device = Arc::new(TaskMutex::new(device));
+    devices.push((name, device.clone()));
+    Ok(device)
+}
+
+/// Returns the network device registered under `name`.
+#[must_use]
+pub fn get(name: &str) -> Option<NetDeviceRef> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, device)| device.clone())
+}
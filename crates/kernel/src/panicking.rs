@@ -1,40 +1,189 @@
+//! The panic handler.
+//!
+//! The first CPU to panic reports it and stops the others, with an IPI or, without one, at
+//! their next timer tick. If it panics again while reporting, the second panic is reported
+//! on the [early console](earlycon) alone, with the registers, since whatever the report was
+//! doing can't be trusted. Then it halts, or resets the system if the `panic` option on the
+//! command line asks it to.
+
 use core::{
     fmt::Write,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use arrayvec::ArrayString;
+use kados_abi::loader;
 use thiserror::Error;
 
 use crate::{
-    arch::{Arch, Architecture, serial::lock_uart},
+    arch::{
+        Arch, Architecture,
+        earlycon::{self, EarlyCon},
+        serial::{self, lock_uart},
+    },
+    cmdline, early_println, irq, logging,
     mem::{
+        self,
         paging::table::{PageTable, TableKind},
         units::VirtAddr,
     },
-    println,
+    print, println, symbols,
+    task::{context::CpuMask, cpus},
+    time,
 };
 
-fn prevent_double_panic() {
-    static PANICKING: AtomicBool = AtomicBool::new(false);
+/// Stands for no CPU in [`PANIC_CPU`].
+const NO_CPU: usize = usize::MAX;
+/// The CPU handling a panic, or [`NO_CPU`] if none is.
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// How many times the CPU handling a panic has panicked again.
+static NESTED: AtomicUsize = AtomicUsize::new(0);
+/// The CPUs that have stopped because another panicked.
+static HALTED: AtomicU64 = AtomicU64::new(0);
 
-    if PANICKING.swap(true, Ordering::SeqCst) {
-        // Already panicking, avoid infinite loop
-        Arch::hcf()
+/// Stands for halting rather than resetting in [`REBOOT_AFTER`].
+const NEVER: u64 = u64::MAX;
+/// How many seconds to wait after a panic before resetting the system, or [`NEVER`].
+static REBOOT_AFTER: AtomicU64 = AtomicU64::new(NEVER);
+/// How long to wait for the other CPUs to stop.
+const HALT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Reads the `panic` option from the command line: `halt`, the default, or `reboot,<seconds>`
+/// to reset the system that long after a panic. A bare `reboot` resets it straight away.
+pub fn init() {
+    let after = match cmdline::get("panic") {
+        None | Some("halt") => NEVER,
+        Some("reboot") => 0,
+        Some(option) => {
+            if let Some(secs) = option.strip_prefix("reboot,").and_then(|s| s.parse().ok()) {
+                secs
+            } else {
+                log::warn!("cmdline: unknown `panic={option}`, halting on panic");
+                NEVER
+            }
+        }
+    };
+    REBOOT_AFTER.store(after, Ordering::Relaxed);
+}
+
+/// Returns `true` once a CPU has panicked.
+#[must_use]
+pub fn is_panicking() -> bool {
+    PANIC_CPU.load(Ordering::Acquire) != NO_CPU
+}
+
+/// Stops the calling CPU for good, since another has panicked.
+pub fn halt_this_cpu() -> ! {
+    unsafe { Arch::disable_interrupts() };
+    HALTED.fetch_or(CpuMask::single(Arch::cpu_id()).0, Ordering::AcqRel);
+    Arch::hcf()
+}
+
+/// Stops every other online CPU, returning the ones that didn't stop in time.
+fn halt_other_cpus() -> CpuMask {
+    let others = cpus::online().0 & !CpuMask::single(Arch::cpu_id()).0;
+    for cpu in (0..u64::BITS as usize).filter(|&cpu| CpuMask(others).contains(cpu)) {
+        // without the IPI, the CPU's next timer tick stops it
+        irq::try_send_ipi(cpu).ok();
+    }
+    let start = time::uptime();
+    while HALTED.load(Ordering::Acquire) & others != others
+        && time::uptime().saturating_sub(start) < HALT_TIMEOUT
+    {
+        core::hint::spin_loop();
+    }
+    CpuMask(others & !HALTED.load(Ordering::Acquire))
+}
+
+/// Resets the system once the delay the `panic` option gives is up, if it gives one.
+#[cfg(not(feature = "ktest"))]
+fn reboot_if_asked() {
+    let secs = REBOOT_AFTER.load(Ordering::Relaxed);
+    if secs == NEVER {
+        return;
     }
+    early_println!("rebooting in {secs} seconds");
+    let start = time::uptime();
+    while time::uptime().saturating_sub(start) < Duration::from_secs(secs) {
+        core::hint::spin_loop();
+    }
+    Arch::emergency_reset()
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    prevent_double_panic();
+    unsafe { Arch::disable_interrupts() };
+    let cpu = Arch::cpu_id();
+    if let Err(owner) = PANIC_CPU.compare_exchange(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire)
+    {
+        if owner == cpu {
+            nested_panic(info);
+        }
+        // the CPU that panicked first reports it
+        halt_this_cpu();
+    }
+
+    // straight to the UART first, in case the consoles are what's broken
+    early_println!("\nPanic: {}", info);
+
+    let running = halt_other_cpus();
+
+    // nothing that was logging or printing will run again
+    unsafe { logging::flush_on_panic() };
 
-    println!("Panic: {}", info);
+    if serial::uart_phys() == earlycon::uart_phys() {
+        // the serial console has already had it
+        fb_println!("Panic: {}", info);
+    } else {
+        println!("Panic: {}", info);
+    }
+    if running.0 != 0 {
+        println!("CPUs {:#x} didn't stop", running.0);
+    }
+    mem::fault::report_outstanding();
 
     if let Err(e) = unwind_kernel_stack() {
         println!("Error unwinding stack: {}", e);
     }
+    #[cfg(feature = "lockdep")]
+    {
+        println!("Locks held:");
+        crate::sync::lockdep::write_held(&mut Console);
+    }
+    crate::crashdump::on_panic(info);
 
-    Arch::hcf()
+    #[cfg(feature = "ktest")]
+    crate::ktest::on_panic(info);
+    #[cfg(not(feature = "ktest"))]
+    {
+        reboot_if_asked();
+        unsafe { crate::arch::drivers::led::blink_panic() };
+        Arch::hcf()
+    }
+}
+
+/// Reports a panic that struck while the CPU was reporting another, on the early console
+/// alone, then halts or resets the system.
+fn nested_panic(info: &core::panic::PanicInfo) -> ! {
+    if NESTED.fetch_add(1, Ordering::AcqRel) > 0 {
+        // it panicked reporting this one too, so there's nothing left to trust
+        Arch::hcf()
+    }
+    early_println!("\nPanic while panicking: {}", info);
+    early_println!("registers:");
+    crate::arch::crash::write_registers(&mut EarlyCon).ok();
+
+    #[cfg(feature = "ktest")]
+    {
+        early_println!("ktest: aborted after a panic");
+        Arch::exit_qemu(1)
+    }
+    #[cfg(not(feature = "ktest"))]
+    {
+        reboot_if_asked();
+        Arch::hcf()
+    }
 }
 
 /// An error that can occur while unwinding the kernel stack.
@@ -48,25 +197,43 @@ pub enum UnwindStackError {
     FailedToGetSectionData,
 }
 
+/// Writes to the serial console and framebuffer.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        print!("{s}");
+        Ok(())
+    }
+}
+
 /// Unwinds the kernel stack and prints the backtrace.
 // This function is always inlined so we don't push yet another frame to the stack in case we're in a stack overflow.
 #[allow(clippy::inline_always)]
 #[inline]
 #[cold]
 pub fn unwind_kernel_stack() -> Result<(), UnwindStackError> {
+    write_backtrace(&mut Console)
+}
+
+/// Unwinds the kernel stack, writing the backtrace to `out`.
+#[allow(clippy::inline_always)]
+#[inline]
+#[cold]
+pub fn write_backtrace(out: &mut impl Write) -> Result<(), UnwindStackError> {
     let mut fp = Arch::frame_pointer();
     let mut pc_ptr_opt = fp
         .checked_add(size_of::<usize>())
         .map(|p| p as *const usize);
 
     if fp == 0 {
-        println!("<empty backtrace>");
+        writeln!(out, "<empty backtrace>").ok();
         return Ok(());
     }
 
     let mapper = PageTable::current(TableKind::Kernel);
 
-    println!("---BEGIN BACKTRACE---");
+    writeln!(out, "---BEGIN BACKTRACE---").ok();
     for depth in 0..64 {
         if let Some(pc_ptr) = pc_ptr_opt {
             let fp_va = unsafe { VirtAddr::new_unchecked(fp) };
@@ -79,16 +246,20 @@ pub fn unwind_kernel_stack() -> Result<(), UnwindStackError> {
             {
                 let pc = unsafe { *pc_ptr };
                 if pc == 0 {
-                    println!("{:>2}: FP={}:  <empty return>", depth, fp_va);
+                    writeln!(out, "{:>2}: FP={}:  <empty return>", depth, fp_va).ok();
                     break;
                 }
-                println!("{:>2}: FP={} PC={}", depth, fp_va, pc_va);
-                let name = symbol_name(pc);
-
-                if let Some(name) = name {
-                    println!("       {}", rustc_demangle::demangle(&name));
+                let pc = Arch::strip_return_address(pc);
+                writeln!(out, "{:>2}: FP={} PC={}", depth, fp_va, pc_va).ok();
+                if let Some(symbol) = symbols::resolve(pc) {
+                    writeln!(out, "       {}", symbol).ok();
+                } else if symbols::is_available() {
+                    // the embedded table is authoritative, so don't go asking the loader
+                    writeln!(out, "       <unknown>").ok();
+                } else if let Some(name) = symbol_name(pc) {
+                    writeln!(out, "       {}", rustc_demangle::demangle(&name)).ok();
                 } else {
-                    println!("       <unknown>");
+                    writeln!(out, "       <unknown>").ok();
                 }
 
                 fp = unsafe { *fp_va.as_raw_ptr::<usize>() };
@@ -96,25 +267,31 @@ pub fn unwind_kernel_stack() -> Result<(), UnwindStackError> {
                     .checked_add(size_of::<usize>())
                     .map(|p| p as *const usize);
             } else {
-                println!("{:>2}: FP={}:  <guard page>", depth, fp_va);
+                writeln!(out, "{:>2}: FP={}:  <guard page>", depth, fp_va).ok();
                 break;
             }
         } else {
             break;
         }
     }
-    println!("---END BACKTRACE---");
+    writeln!(out, "---END BACKTRACE---").ok();
 
     Ok(())
 }
 
 /// Returns the name of the symbol at the given address.
 /// This function sends a request to the UART and waits for a response.
+///
+/// Prefer [`symbols::resolve`], which doesn't need the loader on the other end; this is
+/// only used for kernels built without an embedded symbol table.
 /// It is a blocking call and may take some time to return.
 #[must_use]
 pub fn symbol_name(addr: usize) -> Option<ArrayString<2048>> {
     let mut uart = lock_uart();
-    uart.write_fmt(format_args!("[sym?]{}\n", addr)).ok()?;
+    for &b in loader::SYMBOL_REQUEST {
+        uart.putchar(b);
+    }
+    uart.write_fmt(format_args!("{}\n", addr)).ok()?;
     let mut out = ArrayString::new();
     loop {
         let b = uart.getchar();
@@ -0,0 +1,175 @@
+//! Per-CPU variables.
+//!
+//! [`percpu!`] declares them. The linker gathers their initial values into the `.percpu`
+//! section, and [`init_cpu`] gives the calling CPU a copy of the section of its own, pointing
+//! the CPU's per-CPU base (`TPIDR_EL1` on `AArch64`) at it. Each variable is at the same offset
+//! into every copy as into the section, so finding this CPU's is an add.
+//!
+//! [`PerCpu::with`] runs a closure on this CPU's copy with interrupts disabled, so neither an
+//! interrupt handler nor a switch to another CPU can get in the way partway through. The
+//! initial values are copied byte for byte, so they mustn't point into themselves.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    __percpu_end, __percpu_start,
+    arch::{Arch, Architecture},
+    irq::MAX_CPUS,
+    mem::{paging::allocator::KernelFrameAllocator, units::FrameCount},
+    sync::with_irqs_disabled,
+    syscall::errno::Errno,
+};
+
+/// The base of each CPU's copy of the per-CPU section, or 0 if it hasn't got one yet.
+static BASES: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Declares a per-CPU variable: a [`PerCpu`] each CPU has its own copy of, starting out as
+/// the given value.
+///
+/// ```ignore
+/// percpu! {
+///     /// How many times this CPU did something.
+///     static COUNT: Cell<u64> = Cell::new(0);
+/// }
+/// ```
+macro_rules! percpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            #[unsafe(link_section = ".percpu")]
+            static TEMPLATE: $crate::percpu::Template<$ty> = $crate::percpu::Template::new($init);
+            $crate::percpu::PerCpu::new(&TEMPLATE)
+        };
+    };
+}
+pub(crate) use percpu;
+
+/// A per-CPU variable's initial value, in the `.percpu` section. Only ever copied.
+#[doc(hidden)]
+pub struct Template<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for Template<T> {}
+
+impl<T> Template<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+}
+
+/// A variable each CPU has its own copy of, declared with [`percpu!`].
+pub struct PerCpu<T: 'static> {
+    template: &'static Template<T>,
+}
+
+// each copy is only reached from its own CPU, except through `for_cpu`, which needs `T: Sync`
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(template: &'static Template<T>) -> Self {
+        Self { template }
+    }
+
+    /// Returns where the variable is in the copy of the section at `base`.
+    fn in_copy(&self, base: usize) -> *mut T {
+        let offset = ptr::from_ref(self.template).addr() - __percpu_start();
+        (base + offset) as *mut T
+    }
+
+    /// Runs `f` on this CPU's copy, with interrupts disabled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this CPU hasn't been through [`init_cpu`].
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        with_irqs_disabled(|| {
+            let base = Arch::percpu_base();
+            assert!(base != 0, "per-CPU variable used before percpu::init_cpu");
+            f(unsafe { &*self.in_copy(base) })
+        })
+    }
+
+    /// Returns this CPU's copy, or `None` if this CPU hasn't been through [`init_cpu`].
+    ///
+    /// # Safety
+    ///
+    /// The copy mustn't be used once the caller could be running on another CPU, or
+    /// concurrently with an interrupt handler using it in a way `T` doesn't allow for.
+    #[must_use]
+    pub unsafe fn current(&self) -> Option<&'static T> {
+        let base = Arch::percpu_base();
+        (base != 0).then(|| unsafe { &*self.in_copy(base) })
+    }
+
+    /// Returns `cpu`'s copy, or `None` if that CPU hasn't been through [`init_cpu`].
+    #[must_use]
+    pub fn for_cpu(&self, cpu: usize) -> Option<&'static T>
+    where
+        T: Sync,
+    {
+        let base = BASES.get(cpu)?.load(Ordering::Acquire);
+        (base != 0).then(|| unsafe { &*self.in_copy(base) })
+    }
+}
+
+impl<T: Copy> PerCpu<Cell<T>> {
+    /// Returns this CPU's value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this CPU hasn't been through [`init_cpu`].
+    #[must_use]
+    pub fn get(&self) -> T {
+        self.with(Cell::get)
+    }
+
+    /// Sets this CPU's value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this CPU hasn't been through [`init_cpu`].
+    pub fn set(&self, value: T) {
+        self.with(|cell| cell.set(value));
+    }
+
+    /// Sets this CPU's value, returning the old one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this CPU hasn't been through [`init_cpu`].
+    pub fn replace(&self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
+
+/// Gives the calling CPU its own copy of the per-CPU variables, and points its per-CPU base at
+/// it. Each CPU calls this once as it comes up, before it uses any of them.
+///
+/// # Errors
+///
+/// Returns [`Errno::EALREADY`] if this CPU already has its copy, [`Errno::EINVAL`] if it's
+/// past [`MAX_CPUS`], or [`Errno::ENOMEM`] if there's no memory for the copy.
+pub fn init_cpu() -> Result<(), Errno> {
+    let cpu = Arch::cpu_id();
+    let slot = BASES.get(cpu).ok_or(Errno::EINVAL)?;
+    if slot.load(Ordering::Acquire) != 0 {
+        return Err(Errno::EALREADY);
+    }
+
+    let size = __percpu_end() - __percpu_start();
+    let frames = unsafe { KernelFrameAllocator.allocate(FrameCount::from_bytes(size.max(1))) }
+        .map_err(|_| Errno::ENOMEM)?;
+    let base = frames.as_hhdm_virt().value();
+    unsafe {
+        ptr::copy_nonoverlapping(__percpu_start() as *const u8, base as *mut u8, size);
+    }
+    slot.store(base, Ordering::Release);
+    unsafe { Arch::set_percpu_base(base) };
+    log::debug!("cpu {cpu}: {size} bytes of per-CPU variables at {base:#x}");
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Kernel random numbers, from a `ChaCha20` generator seeded by the hardware RNG.
+//!
+//! Every request rekeys the generator from its own keystream before handing anything out, so
+//! whoever reads its state later can't work back to earlier output. Once a hardware source is
+//! registered, its output is mixed into the key straight away and then every
+//! [`RESEED_PERIOD`].
+//!
+//! Until then the key comes from the jitter of the system counter, which is enough for the
+//! output not to repeat from boot to boot, but not to keep it secret.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use crate::{
+    sync::IrqMutex,
+    syscall::errno::Errno,
+    task::workqueue::{Work, schedule_work},
+    time, timer,
+};
+
+/// How often the key is mixed with fresh output from the hardware source.
+pub const RESEED_PERIOD: Duration = Duration::from_secs(60);
+
+/// The size of a `ChaCha20` key.
+pub const KEY_SIZE: usize = 32;
+/// The size of a `ChaCha20` block.
+pub const BLOCK_SIZE: usize = 64;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// How many times the counter is sampled for a jitter seed.
+const JITTER_SAMPLES: usize = 256;
+
+static GENERATOR: IrqMutex<Generator> = IrqMutex::new(Generator::new());
+static SOURCE: Once<Source> = Once::new();
+static HARDWARE_SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// A hardware random number generator.
+struct Source {
+    name: &'static str,
+    fill: fn(&mut [u8]) -> Result<(), Errno>,
+}
+
+/// Returns the `ChaCha20` block for `key` at `counter`, with a zero nonce.
+#[must_use]
+pub fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut x = input;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (x, input) in x.iter_mut().zip(input) {
+        *x = x.wrapping_add(input);
+    }
+    x
+}
+
+#[allow(clippy::many_single_char_names)] // named as in RFC 8439
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+struct Generator {
+    key: [u32; 8],
+    seeded: bool,
+}
+
+impl Generator {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            seeded: false,
+        }
+    }
+
+    /// Replaces the key with the start of its own block 0, the rest of which is never used.
+    fn rekey(&mut self) {
+        let block = chacha20_block(&self.key, 0);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    /// Mixes `bytes` into the key, a key's worth at a time.
+    fn mix(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(KEY_SIZE) {
+            for (word, bytes) in self.key.iter_mut().zip(chunk.chunks(4)) {
+                let mut le = [0; 4];
+                le[..bytes.len()].copy_from_slice(bytes);
+                *word ^= u32::from_le_bytes(le);
+            }
+            self.rekey();
+        }
+        self.seeded = true;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if !self.seeded {
+            self.mix(&jitter_seed());
+        }
+        let key = self.key;
+        self.rekey();
+        for (counter, chunk) in (1..).zip(buf.chunks_mut(BLOCK_SIZE)) {
+            let block = chacha20_block(&key, counter);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+    }
+}
+
+/// Samples how long the counter takes to move on, a key's worth of times over.
+fn jitter_seed() -> [u8; KEY_SIZE] {
+    let mut seed = [0; KEY_SIZE];
+    let mut last = time::uptime();
+    for i in 0..JITTER_SAMPLES {
+        let mut spins = 0u32;
+        let now = loop {
+            let now = time::uptime();
+            if now != last {
+                break now;
+            }
+            spins = spins.wrapping_add(1);
+        };
+        let sample = (now.subsec_nanos() ^ spins.rotate_left(16)).to_le_bytes();
+        for (j, byte) in sample.into_iter().enumerate() {
+            seed[(i * 4 + j) % KEY_SIZE] ^= byte.rotate_left(i as u32 % 8);
+        }
+        last = now;
+    }
+    seed
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill_bytes(buf: &mut [u8]) {
+    GENERATOR.lock().fill(buf);
+}
+
+/// Returns a random `u64`.
+#[must_use]
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Returns whether the generator has been seeded from a hardware source.
+#[must_use]
+pub fn is_hardware_seeded() -> bool {
+    HARDWARE_SEEDED.load(Ordering::Acquire)
+}
+
+/// Makes `fill` the generator's hardware source, seeding it from `fill` straight away and then
+/// every [`RESEED_PERIOD`].
+///
+/// # Errors
+///
+/// Returns [`Errno::EBUSY`] if there's already a source, or whatever `fill` failed with while
+/// seeding.
+pub fn register_source(
+    name: &'static str,
+    fill: fn(&mut [u8]) -> Result<(), Errno>,
+) -> Result<(), Errno> {
+    if SOURCE.is_completed() {
+        return Err(Errno::EBUSY);
+    }
+    let source = SOURCE.call_once(|| Source { name, fill });
+    reseed_from(source)?;
+    log::info!("rng: seeded from {name}");
+
+    timer::periodic(RESEED_PERIOD, || {
+        schedule_work(Work::new(|_| reseed(), 0)).ok();
+    });
+    Ok(())
+}
+
+/// Mixes fresh output from the hardware source into the key.
+fn reseed() {
+    let Some(source) = SOURCE.get() else { return };
+    if let Err(e) = reseed_from(source) {
+        log::warn!("rng: can't reseed from {}: {e:?}", source.name);
+    }
+}
+
+fn reseed_from(source: &Source) -> Result<(), Errno> {
+    let mut seed = [0; KEY_SIZE];
+    (source.fill)(&mut seed)?;
+    GENERATOR.lock().mix(&seed);
+    HARDWARE_SEEDED.store(true, Ordering::Release);
+    Ok(())
+}
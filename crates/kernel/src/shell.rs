@@ -0,0 +1,588 @@
+//! A minimal line-oriented debug shell on the serial console.
+//!
+//! Commands are looked up in a registry, so drivers and subsystems can add their own
+//! with [`register`].
+
+use core::{fmt::Write, str::SplitWhitespace, sync::atomic::Ordering};
+
+use alloc::{string::String, vec::Vec};
+use arrayvec::ArrayString;
+use kados_abi::signal::SIGTERM;
+use spin::RwLock;
+
+use crate::{
+    arch::{
+        Arch, Architecture,
+        driver::{self, DeviceStatus},
+        drivers::power::{self, PowerDomain},
+        psci,
+        serial::lock_uart,
+        vectors,
+    },
+    input::{self, EventQueue, KeyCode, KeyEvent},
+    irq,
+    mem::{
+        fault::{self, FaultMode},
+        heap,
+        paging::allocator::{self, kernel_frame_allocator},
+        slab,
+    },
+    serial_print, serial_println, symbols,
+    syscall::errno::Errno,
+    task::{
+        self,
+        addr_space::{Backing, Protection},
+        context::{self, Pid},
+        signal, stats,
+    },
+    time::wall::{self, DateTime},
+};
+
+const PROMPT: &str = "kados> ";
+/// The longest line the shell reads.
+pub const MAX_LINE_LEN: usize = 128;
+
+/// The arguments following a command's name.
+pub type Args<'a> = SplitWhitespace<'a>;
+
+/// A shell command.
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// The name the command is invoked by.
+    pub name: &'static str,
+    /// The arguments the command takes, shown by `help`.
+    pub usage: &'static str,
+    /// A one-line description, shown by `help`.
+    pub help: &'static str,
+    /// Runs the command.
+    pub run: fn(Args) -> Result<(), Errno>,
+}
+
+static COMMANDS: RwLock<Vec<Command>> = RwLock::new(Vec::new());
+
+/// Adds a command to the shell, replacing any existing command with the same name.
+pub fn register(command: Command) {
+    let mut commands = COMMANDS.write();
+    commands.retain(|c| c.name != command.name);
+    commands.push(command);
+}
+
+/// Registers the built-in commands and spawns the shell task.
+pub fn spawn() -> Result<(), Errno> {
+    for command in BUILTINS {
+        register(*command);
+    }
+    task::SpawnBuilder::new().name("shell").spawn(shell_main)?;
+    Ok(())
+}
+
+extern "C" fn shell_main() {
+    let keys = input::subscribe();
+    let mut line = ArrayString::<MAX_LINE_LEN>::new();
+    loop {
+        serial_print!("{PROMPT}");
+        read_line(&keys, &mut line);
+        execute(&line);
+    }
+}
+
+/// Returns the next key typed, feeding the serial console to the input subsystem meanwhile.
+fn next_key(keys: &EventQueue) -> KeyEvent {
+    loop {
+        if let Some(event) = keys.try_pop() {
+            return event;
+        }
+        if let Some(b) = lock_uart().try_getchar() {
+            input::serial::feed(b);
+            continue;
+        }
+        if irq::is_polled() {
+            // nothing will wake us up, so service the timer ourselves
+            irq::poll();
+        } else {
+            // the timer will switch us out in the meantime
+            Arch::halt();
+        }
+    }
+}
+
+fn read_line(keys: &EventQueue, line: &mut ArrayString<MAX_LINE_LEN>) {
+    line.clear();
+    loop {
+        let event = next_key(keys);
+        if !event.pressed {
+            continue;
+        }
+        match (event.code, event.text) {
+            (KeyCode::ENTER, _) => {
+                serial_println!();
+                return;
+            }
+            (KeyCode::BACKSPACE, _) => {
+                if line.pop().is_some() {
+                    serial_print!("\x08 \x08");
+                }
+            }
+            (_, Some(c)) if !c.is_control() => {
+                if line.try_push(c).is_ok() {
+                    serial_print!("{c}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the command on `line`, printing its error if it fails.
+pub fn execute(line: &str) {
+    let mut args = line.split_whitespace();
+    let Some(name) = args.next() else {
+        return;
+    };
+
+    let command = COMMANDS.read().iter().find(|c| c.name == name).copied();
+    let Some(command) = command else {
+        serial_println!("{name}: command not found (try `help`)");
+        return;
+    };
+
+    if let Err(e) = (command.run)(args) {
+        serial_println!("{name}: {e:?}");
+    }
+}
+
+/// Takes a PID from a command's arguments.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there isn't one, or it isn't a number.
+pub fn parse_pid(args: &mut Args) -> Result<Pid, Errno> {
+    let pid = args.next().ok_or(Errno::EINVAL)?;
+    let pid = pid.parse().map_err(|_| Errno::EINVAL)?;
+    Ok(Pid::from_raw(pid))
+}
+
+static BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list available commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        help: "list tasks",
+        run: cmd_ps,
+    },
+    Command {
+        name: "task",
+        usage: "<pid>",
+        help: "show a task's accounting",
+        run: cmd_task,
+    },
+    Command {
+        name: "maps",
+        usage: "<pid>",
+        help: "list a task's memory mappings",
+        run: cmd_maps,
+    },
+    Command {
+        name: "fault",
+        usage: "[frames|heap off|every <n>|after <bytes>]",
+        help: "show or set allocator fault injection",
+        run: cmd_fault,
+    },
+    Command {
+        name: "kill",
+        usage: "<pid> [signal]",
+        help: "send a signal to a user task (SIGTERM by default)",
+        run: cmd_kill,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        help: "show kernel heap and frame allocator usage",
+        run: cmd_mem,
+    },
+    Command {
+        name: "date",
+        usage: "",
+        help: "show the wall clock, and where it was set from",
+        run: cmd_date,
+    },
+    Command {
+        name: "devices",
+        usage: "",
+        help: "list the devices drivers were probed for",
+        run: cmd_devices,
+    },
+    Command {
+        name: "power",
+        usage: "",
+        help: "show the state of each firmware power domain",
+        run: cmd_power,
+    },
+    Command {
+        name: "regs",
+        usage: "<pid>",
+        help: "show the saved registers of a task",
+        run: cmd_regs,
+    },
+    Command {
+        name: "bt",
+        usage: "<pid>",
+        help: "show a frame-pointer backtrace of a task",
+        run: cmd_bt,
+    },
+    Command {
+        name: "vectors",
+        usage: "",
+        help: "show how often each exception vector has been taken",
+        run: cmd_vectors,
+    },
+    Command {
+        name: "irqs",
+        usage: "",
+        help: "show how often each IRQ has been delivered, and its handler's latency",
+        run: cmd_irqs,
+    },
+    Command {
+        name: "sym",
+        usage: "<addr | name>",
+        help: "resolve an address to a symbol, or a symbol to its address",
+        run: cmd_sym,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "reset the system",
+        run: cmd_reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "power off the system",
+        run: cmd_poweroff,
+    },
+    #[cfg(feature = "gdb")]
+    Command {
+        name: "gdb",
+        usage: "",
+        help: "stop the kernel and wait for a debugger on the serial port",
+        run: cmd_gdb,
+    },
+];
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_help(_args: Args) -> Result<(), Errno> {
+    let mut commands = COMMANDS.read().clone();
+    commands.sort_unstable_by_key(|c| c.name);
+    for command in commands {
+        let mut invocation = String::from(command.name);
+        if !command.usage.is_empty() {
+            write!(invocation, " {}", command.usage).ok();
+        }
+        serial_println!("  {invocation:<16} {}", command.help);
+    }
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn cmd_ps(_args: Args) -> Result<(), Errno> {
+    serial_println!(
+        "{:>5}  {:<16} {:>3} {:<10} {:<8} {:<6} {:>12} {:>9} {:>13}",
+        "PID",
+        "NAME",
+        "PRI",
+        "STATUS",
+        "RUNNING",
+        "MODE",
+        "CPU TIME",
+        "SWITCHES",
+        "STACK"
+    );
+    for task in stats::tasks() {
+        let mut status = ArrayString::<32>::new();
+        write!(status, "{:?}", task.status).ok();
+        let name = if task.name.is_empty() {
+            "-"
+        } else {
+            &task.name
+        };
+        let mode = if task.userspace { "user" } else { "kernel" };
+        let running = if task.running { "yes" } else { "no" };
+        let mut stack = ArrayString::<32>::new();
+        match task.stack_high_water {
+            Some(used) => write!(stack, "{used}/{}", task.stack_size).ok(),
+            None => write!(stack, "-").ok(),
+        };
+        serial_println!(
+            "{:>5}  {name:<16} {:>3} {status:<10} {running:<8} {mode:<6} {:>12?} {:>9} {stack:>13}",
+            task.pid,
+            task.priority,
+            task.cpu_time,
+            task.switches
+        );
+    }
+    Ok(())
+}
+
+/// Prints the task table, as `ps` does, for the builder's GDB script to call with
+/// `list_tasks`. The linker script keeps it even though nothing in the kernel calls it.
+///
+/// It takes the locks `ps` takes, and allocates, so it hangs if the kernel was stopped
+/// holding one of them.
+#[unsafe(no_mangle)]
+pub extern "C" fn kados_gdb_list_tasks() {
+    cmd_ps("".split_whitespace()).ok();
+}
+
+fn cmd_task(mut args: Args) -> Result<(), Errno> {
+    let task = stats::task(parse_pid(&mut args)?)?;
+    serial_println!("pid:        {}", task.pid);
+    serial_println!("name:       {}", task.name);
+    serial_println!("priority:   {}", task.priority);
+    serial_println!("status:     {:?}", task.status);
+    serial_println!("cpu time:   {:?}", task.cpu_time);
+    serial_println!("switches:   {}", task.switches);
+    serial_println!("faults:     {}", task.faults);
+    serial_println!(
+        "heap:       {} bytes allocated, {} freed",
+        task.heap_allocated,
+        task.heap_freed
+    );
+    if let Some(used) = task.stack_high_water {
+        serial_println!("stack:      {used} of {} bytes used", task.stack_size);
+    }
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_date(_args: Args) -> Result<(), Errno> {
+    let now = wall::now();
+    serial_println!(
+        "{} ({}.{:09}, from the {})",
+        DateTime::from_unix(now),
+        now.as_secs(),
+        now.subsec_nanos(),
+        wall::source()
+    );
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_mem(_args: Args) -> Result<(), Errno> {
+    serial_println!("heap: {}", heap::stats());
+    for cache in slab::stats().filter(|cache| cache.slabs != 0) {
+        serial_println!(
+            "  slab {:>4}: {} / {} objects in {} slabs",
+            cache.object_size,
+            cache.in_use,
+            cache.capacity,
+            cache.slabs
+        );
+    }
+    serial_println!("frames: {}", kernel_frame_allocator().stats());
+    allocator::for_each_reservation(|reservation| {
+        serial_println!(
+            "  reserved {}: {} frames at {}",
+            reservation.name,
+            reservation.count.frame_count(),
+            reservation.base
+        );
+    });
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_devices(_args: Args) -> Result<(), Errno> {
+    serial_println!("{:<24} {:<12} {}", "NODE", "DRIVER", "STATUS");
+    for device in driver::devices() {
+        let mut status = ArrayString::<48>::new();
+        match device.status {
+            DeviceStatus::Bound => write!(status, "bound").ok(),
+            DeviceStatus::Failed(e) => write!(status, "failed: {e:?}").ok(),
+            DeviceStatus::MissingDependency(dep) => write!(status, "needs {dep}").ok(),
+        };
+        serial_println!("{:<24} {:<12} {status}", device.node, device.driver);
+    }
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_power(_args: Args) -> Result<(), Errno> {
+    for domain in PowerDomain::ALL {
+        match power::power_state(domain) {
+            Ok(state) => serial_println!("{:<8} {state}", format_args!("{domain:?}")),
+            Err(e) => serial_println!("{:<8} {e:?}", format_args!("{domain:?}")),
+        }
+    }
+    Ok(())
+}
+
+fn cmd_maps(mut args: Args) -> Result<(), Errno> {
+    let pid = parse_pid(&mut args)?;
+    let addr_space = context::inspect(pid, |cx| cx.addr_space.clone())?.ok_or(Errno::ENOENT)?;
+    for region in addr_space.read().regions() {
+        let mut prot = ArrayString::<3>::new();
+        for (flag, c) in [
+            (Protection::READ, 'r'),
+            (Protection::WRITE, 'w'),
+            (Protection::EXEC, 'x'),
+        ] {
+            prot.push(if region.prot.contains(flag) { c } else { '-' });
+        }
+        let mut backing = ArrayString::<32>::new();
+        match region.backing {
+            Backing::Anonymous => write!(backing, "anonymous").ok(),
+            Backing::Physical(phys) => write!(backing, "{phys}").ok(),
+            Backing::Device(phys) => write!(backing, "device {phys}").ok(),
+            Backing::Shared(phys) => write!(backing, "shared {phys}").ok(),
+        };
+        serial_println!("{} .. {}  {prot}  {backing}", region.start, region.end());
+    }
+    Ok(())
+}
+
+fn cmd_fault(mut args: Args) -> Result<(), Errno> {
+    let Some(allocator) = args.next() else {
+        serial_println!("{}", fault::FRAMES);
+        serial_println!("{}", fault::HEAP);
+        return Ok(());
+    };
+    let injector = match allocator {
+        "frames" => &fault::FRAMES,
+        "heap" => &fault::HEAP,
+        _ => return Err(Errno::EINVAL),
+    };
+    let mode = match (args.next(), args.next().map(str::parse)) {
+        (Some("off"), None) => FaultMode::Off,
+        (Some("every"), Some(Ok(n))) => FaultMode::EveryNth(n),
+        (Some("after"), Some(Ok(bytes))) => FaultMode::AfterBytes(bytes),
+        _ => return Err(Errno::EINVAL),
+    };
+    injector.arm(mode);
+    serial_println!("{injector}");
+    Ok(())
+}
+
+fn cmd_kill(mut args: Args) -> Result<(), Errno> {
+    let pid = parse_pid(&mut args)?;
+    let sig = match args.next() {
+        Some(sig) => sig.parse().map_err(|_| Errno::EINVAL)?,
+        None => SIGTERM,
+    };
+    signal::send(pid, sig)
+}
+
+fn cmd_regs(mut args: Args) -> Result<(), Errno> {
+    let pid = parse_pid(&mut args)?;
+    let regs = context::inspect(pid, |cx| cx.saved_registers().clone())?;
+    serial_println!("{regs}");
+    Ok(())
+}
+
+fn cmd_bt(mut args: Args) -> Result<(), Errno> {
+    let pid = parse_pid(&mut args)?;
+    let (frames, stack) = context::inspect(pid, |cx| (cx.backtrace(), cx.kstack_bounds()))?;
+    serial_println!("kernel stack {:#x} .. {:#x}", stack.start, stack.end);
+    for (depth, &pc) in frames.iter().enumerate() {
+        if let Some(symbol) = symbols::resolve(pc) {
+            serial_println!("{depth:>2}: PC={pc:#018x}  {symbol}");
+        } else {
+            serial_println!("{depth:>2}: PC={pc:#018x}");
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_vectors(_args: Args) -> Result<(), Errno> {
+    serial_println!(
+        "{:<18} {:<7} {:>10} {:>10}",
+        "ORIGIN",
+        "KIND",
+        "ENTRIES",
+        "EXITS"
+    );
+    for counts in vectors::vector_counts() {
+        serial_println!(
+            "{:<18} {:<7} {:>10} {:>10}",
+            counts.origin,
+            counts.kind,
+            counts.entries,
+            counts.exits
+        );
+    }
+    serial_println!("max nesting depth: {}", vectors::max_exception_depth());
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_irqs(_args: Args) -> Result<(), Errno> {
+    serial_print!("{:>5}", "IRQ");
+    for cpu in 0..irq::MAX_CPUS {
+        let mut name = ArrayString::<8>::new();
+        write!(name, "CPU{cpu}").ok();
+        serial_print!(" {name:>10}");
+    }
+    serial_println!(" {:>10} {:>12} {:>12}", "SPURIOUS", "AVG", "MAX");
+    for (irq, stats) in irq::irq_stats() {
+        serial_print!("{irq:>5}");
+        for count in stats.count {
+            serial_print!(" {count:>10}");
+        }
+        let (mut average, mut max) = (ArrayString::<32>::new(), ArrayString::<32>::new());
+        write!(average, "{:?}", stats.average_time()).ok();
+        write!(max, "{:?}", stats.max_time).ok();
+        serial_println!(" {:>10} {average:>12} {max:>12}", stats.spurious);
+    }
+
+    for (cpu, stats) in irq::cpu_stats().iter().enumerate() {
+        let acks = stats.acks.load(Ordering::Relaxed);
+        if acks > 0 {
+            serial_println!(
+                "CPU{cpu}: {acks} acks, {} spurious",
+                stats.spurious.load(Ordering::Relaxed)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn cmd_sym(mut args: Args) -> Result<(), Errno> {
+    let arg = args.next().ok_or(Errno::EINVAL)?;
+    if !symbols::is_available() {
+        serial_println!("no symbol table (the kernel wasn't built by the builder)");
+        return Err(Errno::ENOENT);
+    }
+
+    let addr = arg
+        .strip_prefix("0x")
+        .and_then(|hex| usize::from_str_radix(hex, 16).ok());
+    if let Some(addr) = addr {
+        let symbol = symbols::resolve(addr).ok_or(Errno::ENOENT)?;
+        serial_println!("{addr:#018x}  {symbol}");
+    } else {
+        let symbol = symbols::lookup(arg).ok_or(Errno::ENOENT)?;
+        serial_println!("{:#018x}  {} ({} bytes)", symbol.addr, arg, symbol.size);
+    }
+    Ok(())
+}
+
+fn cmd_reboot(_args: Args) -> Result<(), Errno> {
+    Err(psci::system_reset())
+}
+
+fn cmd_poweroff(_args: Args) -> Result<(), Errno> {
+    Err(psci::system_off())
+}
+
+#[cfg(feature = "gdb")]
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_gdb(_args: Args) -> Result<(), Errno> {
+    serial_println!("waiting for debugger...");
+    crate::arch::debugging::breakpoint();
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Resolves code addresses to symbol names using the symbol table embedded in the kernel image.
+//!
+//! The builder links the kernel with space reserved in the `.ksyms` section, then fills it in
+//! with the kernel's function symbols. The table is laid out as follows, all little-endian:
+//!
+//! | offset         | size | contents                                          |
+//! |----------------|------|---------------------------------------------------|
+//! | 0              | 4    | magic (`KSYM`)                                    |
+//! | 4              | 4    | number of entries                                 |
+//! | 8              | 8    | link-time address of the table itself             |
+//! | 16             | 16n  | entries: address (u64), size (u32), name (u32)    |
+//! | 16 + 16n       | ..   | NUL-terminated names, indexed by the entries      |
+//!
+//! Entries are sorted by address. Names are stored mangled and demangled on display.
+
+use core::fmt;
+
+use alloc::format;
+
+use crate::{__ksyms_end, __ksyms_start};
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// The space reserved for the symbol table. The builder sets this at build time once it
+/// knows how big the table is.
+const KSYMS_SIZE: usize = match option_env!("KADOS_KSYMS_SIZE") {
+    Some(size) => match usize::from_str_radix(size, 10) {
+        Ok(size) => size,
+        Err(_) => panic!("KADOS_KSYMS_SIZE must be a decimal number"),
+    },
+    None => 4096,
+};
+
+// Only reserves the space; it is always read through `__ksyms_start` so the compiler can't
+// assume it's still all zeros.
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS_RESERVED: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// A symbol in the kernel's symbol table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    /// The mangled name of the symbol.
+    pub name: &'static str,
+    /// The runtime address of the symbol.
+    pub addr: usize,
+    /// The size of the symbol in bytes, or zero if unknown.
+    pub size: usize,
+}
+
+/// An address resolved to a symbol and an offset into it.
+///
+/// Displays as `function+0x1c`, with the name demangled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolOffset {
+    pub symbol: Symbol,
+    pub offset: usize,
+}
+
+impl fmt::Display for SymbolOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the alternate form leaves off the hashes
+        write!(
+            f,
+            "{:#}+{:#x}",
+            rustc_demangle::demangle(self.symbol.name),
+            self.offset
+        )
+    }
+}
+
+/// The embedded symbol table.
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    /// The difference between the runtime and link-time addresses of the kernel.
+    displacement: usize,
+}
+
+impl SymbolTable {
+    fn get() -> Option<Self> {
+        let start = __ksyms_start();
+        let table =
+            unsafe { core::slice::from_raw_parts(start as *const u8, __ksyms_end() - start) };
+
+        let (header, rest) = table.split_first_chunk::<HEADER_SIZE>()?;
+        if header[..4] != MAGIC {
+            return None;
+        }
+        let count = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let link_addr = u64::from_le_bytes(header[8..16].try_into().ok()?) as usize;
+        let (entries, names) = rest.split_at_checked(count.checked_mul(ENTRY_SIZE)?)?;
+
+        Some(Self {
+            entries,
+            names,
+            displacement: start.wrapping_sub(link_addr),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn entry(&self, index: usize) -> Option<Symbol> {
+        let entry = self
+            .entries
+            .get(index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE)?;
+        let addr = u64::from_le_bytes(entry[0..8].try_into().ok()?) as usize;
+        let size = u32::from_le_bytes(entry[8..12].try_into().ok()?) as usize;
+        let name = u32::from_le_bytes(entry[12..16].try_into().ok()?) as usize;
+
+        let name = self.names.get(name..)?;
+        let name = &name[..name.iter().position(|&b| b == 0)?];
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            addr: addr.wrapping_add(self.displacement),
+            size,
+        })
+    }
+
+    fn entry_addr(&self, index: usize) -> usize {
+        let entry = &self.entries[index * ENTRY_SIZE..index * ENTRY_SIZE + 8];
+        let mut addr = [0; 8];
+        addr.copy_from_slice(entry);
+        (u64::from_le_bytes(addr) as usize).wrapping_add(self.displacement)
+    }
+}
+
+/// Returns `true` if the kernel was built with a symbol table.
+#[must_use]
+pub fn is_available() -> bool {
+    SymbolTable::get().is_some()
+}
+
+/// Resolves a code address (e.g. a PC from a backtrace) to the symbol containing it.
+///
+/// Returns `None` if there is no symbol table, or if the address isn't in any function.
+#[must_use]
+pub fn resolve(addr: usize) -> Option<SymbolOffset> {
+    let table = SymbolTable::get()?;
+
+    // binary search for the last symbol starting at or before `addr`
+    let (mut lo, mut hi) = (0, table.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if table.entry_addr(mid) <= addr {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let index = lo.checked_sub(1)?;
+    let symbol = table.entry(index)?;
+    let offset = addr - symbol.addr;
+    // symbols without a size (from assembly) are assumed to run up to the next one
+    if symbol.size != 0 && offset >= symbol.size {
+        return None;
+    }
+    Some(SymbolOffset { symbol, offset })
+}
+
+/// Looks up a symbol by its name, mangled or demangled (without the hash).
+#[must_use]
+pub fn lookup(name: &str) -> Option<Symbol> {
+    let table = SymbolTable::get()?;
+    (0..table.len())
+        .filter_map(|index| table.entry(index))
+        .find(|symbol| {
+            symbol.name == name || format!("{:#}", rustc_demangle::demangle(symbol.name)) == name
+        })
+}
@@ -9,9 +9,21 @@ use thiserror::Error;
 
 use crate::{
     arch::{Arch, Architecture},
-    println,
+    irq,
 };
 
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mutex;
+pub mod rcu;
+pub mod rwlock;
+pub mod wait_queue;
+
+pub use mutex::{Condvar, Mutex, MutexGuard};
+pub use rcu::{Rcu, RcuReadGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use wait_queue::{WaitQueue, WaitTicket};
+
 /// A struct that saves the current interrupt status and restores it when dropped.
 /// This is useful for ensuring that interrupts are disabled while a critical section is executed.
 /// It is important to note that this struct should only be used in a single-threaded context.
@@ -51,6 +63,13 @@ impl Drop for SavedInterruptStatus {
     }
 }
 
+/// Runs `f` with interrupts disabled, restoring the previous interrupt status afterwards.
+pub fn with_irqs_disabled<R>(f: impl FnOnce() -> R) -> R {
+    let _saved = SavedInterruptStatus::save();
+    unsafe { Arch::disable_interrupts() };
+    f()
+}
+
 /// An error that can occur when trying to lock an `IrqMutex` that is already locked.
 ///
 /// This error indicates that the mutex is already held by another thread or interrupt handler.
@@ -84,6 +103,7 @@ impl<T: ?Sized> IrqMutex<T> {
     ///
     /// This function will return an error if the mutex is already locked.
     /// This is useful for avoiding deadlocks in multi-threaded contexts.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn try_lock(&self) -> Result<IrqMutexGuard<'_, T>, TryLockError> {
         if self.0.is_locked() {
             Err(TryLockError) // todo: more verbose error message
@@ -95,9 +115,11 @@ impl<T: ?Sized> IrqMutex<T> {
     /// Locks the `IrqMutex` and returns a guard that can be used to access the inner value.
     ///
     /// This function will disable interrupts while the mutex is locked, and will restore the interrupt status when the guard is dropped.
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        #[cfg(not(feature = "lockdep"))]
         if self.0.is_locked() {
-            println!(
+            crate::println!(
                 "WARNING: Tried to relock IrqMutex of {}",
                 core::any::type_name::<T>()
             );
@@ -109,14 +131,34 @@ impl<T: ?Sized> IrqMutex<T> {
             Arch::disable_interrupts();
         }
 
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(
+            self.addr(),
+            core::any::type_name::<T>(),
+            core::panic::Location::caller(),
+        );
+
         let guard = self.0.lock();
 
         IrqMutexGuard {
             inner: ManuallyDrop::new(guard),
             saved_intr_status: ManuallyDrop::new(saved_intr_status),
+            #[cfg(feature = "lockdep")]
+            lock: self.addr(),
         }
     }
 
+    /// Locks the `IrqMutex` and runs `f` on the inner value, with interrupts disabled
+    /// for exactly as long as the lock is held.
+    ///
+    /// Prefer this over [`lock`](Self::lock) where possible: the guard can't be held across a
+    /// context switch or leak out of an interrupt handler by mistake.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock_irqsave<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut guard = self.lock();
+        f(&mut guard)
+    }
+
     /// Returns `true` if the mutex is currently locked, `false` otherwise.
     pub fn is_locked(&self) -> bool {
         self.0.is_locked()
@@ -127,8 +169,23 @@ impl<T: ?Sized> IrqMutex<T> {
     /// # Safety
     /// See [`spin::mutex::SpinMutex::force_unlock()`]
     pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.addr());
         unsafe { self.0.force_unlock() };
     }
+
+    /// Returns the address lock order diagnostics know this mutex by.
+    #[cfg(feature = "lockdep")]
+    fn addr(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for IrqMutex<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.addr());
+    }
 }
 
 // TODO: Are these needed, and are they safe?
@@ -143,6 +200,8 @@ impl<T: ?Sized> IrqMutex<T> {
 pub struct IrqMutexGuard<'a, T: ?Sized> {
     inner: ManuallyDrop<SpinMutexGuard<'a, T>>,
     saved_intr_status: ManuallyDrop<SavedInterruptStatus>,
+    #[cfg(feature = "lockdep")]
+    lock: usize,
 }
 
 impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
@@ -150,6 +209,8 @@ impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
         unsafe {
             ManuallyDrop::drop(&mut self.inner);
         }
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock);
 
         unsafe {
             ManuallyDrop::drop(&mut self.saved_intr_status);
@@ -169,3 +230,43 @@ impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
         &mut self.inner
     }
 }
+
+/// A spinlock for data that is only ever touched from task context.
+///
+/// Unlike [`IrqMutex`], this leaves interrupts enabled while it's held, so an interrupt
+/// handler that tried to take it could spin forever on a lock its own CPU holds. Debug builds
+/// panic if it's taken from an interrupt handler; use an [`IrqMutex`] for anything that is.
+pub struct TaskMutex<T: ?Sized>(SpinMutex<T>);
+
+impl<T> TaskMutex<T> {
+    /// Creates a new `TaskMutex` instance with the given inner value.
+    pub const fn new(value: T) -> Self {
+        Self(SpinMutex::new(value))
+    }
+}
+
+impl<T: ?Sized> TaskMutex<T> {
+    /// Locks the `TaskMutex` and returns a guard that can be used to access the inner value.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        debug_assert!(
+            !irq::in_irq_context(),
+            "TaskMutex of {} taken from an interrupt handler",
+            core::any::type_name::<T>()
+        );
+        self.0.lock()
+    }
+
+    /// Tries to lock the `TaskMutex` without spinning, returning `None` if it's already held.
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        self.0.try_lock()
+    }
+
+    /// Returns `true` if the mutex is currently locked, `false` otherwise.
+    pub fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
@@ -0,0 +1,215 @@
+//! Lock order diagnostics for [`IrqMutex`](super::IrqMutex), with the `lockdep` feature.
+//!
+//! Each CPU keeps a stack of the locks it holds, with where and from what call chain each was
+//! taken. Taking a lock the CPU already holds is reported as a recursive acquisition, which
+//! would otherwise spin forever. Taking lock B while holding lock A records that A comes
+//! before B; if B was ever held while taking A, the two orders are reported as an inversion,
+//! with the call chains that took them each way.
+//!
+//! Locks are told apart by address, so every instance is its own class. Only direct
+//! inversions are found, not cycles through a third lock. The tasks' `RwSpinlock`s aren't
+//! tracked: the context switch holds two of them across the switch, on behalf of both tasks.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::Write,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use arrayvec::ArrayVec;
+use spin::mutex::SpinMutex;
+
+use crate::{
+    arch::{Arch, Architecture},
+    irq::MAX_CPUS,
+    panicking, println, symbols,
+};
+
+use super::with_irqs_disabled;
+
+/// The most locks a CPU can be tracked holding at once.
+const MAX_HELD: usize = 16;
+/// The most lock orders that can be recorded.
+const MAX_ORDERS: usize = 512;
+/// How many return addresses are kept of each acquisition.
+const TRACE_DEPTH: usize = 8;
+/// How far apart two frame records can be and still be taken to be on the same stack.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+static HELD: [HeldLocks; MAX_CPUS] = [const { HeldLocks::new() }; MAX_CPUS];
+static ORDERS: SpinMutex<ArrayVec<Order, MAX_ORDERS>> = SpinMutex::new(ArrayVec::new_const());
+/// Set while a report is printed, since printing takes locks of its own.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Where and how a lock was taken.
+#[derive(Clone, Copy)]
+struct Acquisition {
+    lock: usize,
+    name: &'static str,
+    site: &'static Location<'static>,
+    trace: [usize; TRACE_DEPTH],
+}
+
+impl Acquisition {
+    fn print(&self) {
+        println!("    {} ({:#x}) at {}", self.name, self.lock, self.site);
+        for &pc in self.trace.iter().take_while(|&&pc| pc != 0) {
+            if let Some(symbol) = symbols::resolve(pc) {
+                println!("      {pc:#018x}  {symbol}");
+            } else {
+                println!("      {pc:#018x}");
+            }
+        }
+    }
+}
+
+/// That `before` was held while `after` was taken.
+#[derive(Clone, Copy)]
+struct Order {
+    before: Acquisition,
+    after: Acquisition,
+    reported: bool,
+}
+
+/// The locks a CPU holds, only ever touched by that CPU with interrupts disabled.
+struct HeldLocks(UnsafeCell<ArrayVec<Acquisition, MAX_HELD>>);
+
+unsafe impl Sync for HeldLocks {}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        Self(UnsafeCell::new(ArrayVec::new_const()))
+    }
+
+    fn with<R>(f: impl FnOnce(&mut ArrayVec<Acquisition, MAX_HELD>) -> R) -> R {
+        with_irqs_disabled(|| {
+            let held = &HELD[Arch::cpu_id().min(MAX_CPUS - 1)];
+            f(unsafe { &mut *held.0.get() })
+        })
+    }
+}
+
+/// Returns the return addresses of the frames on the stack, innermost first.
+fn capture_trace() -> [usize; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    let mut fp = Arch::frame_pointer();
+    for pc in &mut trace {
+        if fp == 0 || !fp.is_multiple_of(align_of::<usize>()) {
+            break;
+        }
+        // frame record: [fp] = caller's fp, [fp + 8] = return address
+        let (next_fp, ret) = unsafe {
+            let record = fp as *const usize;
+            (record.read(), record.add(1).read())
+        };
+        *pc = Arch::strip_return_address(ret);
+        if next_fp <= fp || next_fp - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next_fp;
+    }
+    trace
+}
+
+/// Records that the lock at `lock` is about to be taken, reporting it if that's recursive or
+/// the opposite order to one seen before.
+pub fn acquire(lock: usize, name: &'static str, site: &'static Location<'static>) {
+    let acquisition = Acquisition {
+        lock,
+        name,
+        site,
+        trace: capture_trace(),
+    };
+    let checking = !REPORTING.load(Ordering::Acquire);
+
+    let (recursive, inversions, tracked) = HeldLocks::with(|held| {
+        let recursive = held.iter().find(|h| h.lock == lock).copied();
+        let mut inversions = ArrayVec::<(Acquisition, Order), MAX_HELD>::new();
+        if checking && recursive.is_none() {
+            let mut orders = ORDERS.lock();
+            for h in held.iter() {
+                if let Some(order) = orders
+                    .iter_mut()
+                    .find(|o| o.before.lock == lock && o.after.lock == h.lock)
+                {
+                    if !order.reported {
+                        order.reported = true;
+                        inversions.push((*h, *order));
+                    }
+                } else if !orders
+                    .iter()
+                    .any(|o| o.before.lock == h.lock && o.after.lock == lock)
+                {
+                    orders
+                        .try_push(Order {
+                            before: *h,
+                            after: acquisition,
+                            reported: false,
+                        })
+                        .ok();
+                }
+            }
+        }
+        let tracked = held.try_push(acquisition).is_ok();
+        (recursive, inversions, tracked)
+    });
+    if !tracked {
+        println!("lockdep: too many locks held; not tracking {name}");
+    }
+
+    if !checking || (recursive.is_none() && inversions.is_empty()) {
+        return;
+    }
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Some(first) = recursive {
+        println!("lockdep: recursive locking of {name}, first taken:");
+        first.print();
+        println!("  and again:");
+        acquisition.print();
+    }
+    for (held, order) in inversions {
+        println!(
+            "lockdep: lock order inversion between {} and {name}",
+            held.name
+        );
+        println!("  now, the first was taken, then the second:");
+        held.print();
+        acquisition.print();
+        println!("  but before, the other way around:");
+        order.before.print();
+        order.after.print();
+    }
+    panicking::unwind_kernel_stack().ok();
+    REPORTING.store(false, Ordering::Release);
+}
+
+/// Records that the lock at `lock` was released.
+pub fn release(lock: usize) {
+    HeldLocks::with(|held| {
+        if let Some(index) = held.iter().rposition(|h| h.lock == lock) {
+            held.remove(index);
+        }
+    });
+}
+
+/// Forgets every order the lock at `lock` was part of, since it's going away and something
+/// else may be put at its address.
+pub fn forget(lock: usize) {
+    with_irqs_disabled(|| {
+        ORDERS
+            .lock()
+            .retain(|o| o.before.lock != lock && o.after.lock != lock);
+    });
+}
+
+/// Writes the locks this CPU holds, innermost last.
+pub fn write_held(out: &mut impl Write) {
+    HeldLocks::with(|held| {
+        for h in held.iter() {
+            writeln!(out, "  {} ({:#x}) at {}", h.name, h.lock, h.site).ok();
+        }
+    });
+}
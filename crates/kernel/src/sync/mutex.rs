@@ -0,0 +1,232 @@
+//! A mutex and condition variable that put waiting tasks to sleep instead of spinning.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spinning_top::RwSpinlock;
+
+use crate::task::{
+    context::{self, Context, Priority},
+    switch::switch,
+};
+
+use super::{IrqMutex, WaitQueue, with_irqs_disabled};
+
+/// A mutex that blocks the tasks waiting for it.
+///
+/// Use this for locks that can be held for a long time, e.g. across I/O. It can't be taken
+/// from interrupt handlers; use an [`IrqMutex`](super::IrqMutex) there.
+///
+/// The task holding it inherits the priority of the highest-priority task waiting for it,
+/// so a task of middling priority can't keep it from running and releasing the lock. Only
+/// the holder is raised: if it's waiting on another lock itself, that lock's holder isn't.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    inheritance: IrqMutex<Inheritance>,
+    value: UnsafeCell<T>,
+}
+
+/// Who holds a [`Mutex`], and the priorities of the tasks waiting for it.
+struct Inheritance {
+    owner: Option<Arc<RwSpinlock<Context>>>,
+    waiting: Vec<Priority>,
+}
+
+impl Inheritance {
+    /// Has the owner inherit the highest waiting priority from the lock at address `lock`.
+    fn update(&self, lock: usize) {
+        if let Some(owner) = &self.owner {
+            let priority = self.waiting.iter().max().copied();
+            owner.write().inherit_priority(lock, priority);
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates a new unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            inheritance: IrqMutex::new(Inheritance {
+                owner: None,
+                waiting: Vec::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Locks the mutex, sleeping until it's free.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            let priority = context::current().map(|cx| with_irqs_disabled(|| cx.read().priority));
+            if let Some(priority) = priority {
+                let mut inheritance = self.inheritance.lock();
+                inheritance.waiting.push(priority);
+                inheritance.update(self.addr());
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+            if let Some(priority) = priority {
+                let mut inheritance = self.inheritance.lock();
+                if let Some(i) = inheritance.waiting.iter().position(|&p| p == priority) {
+                    inheritance.waiting.swap_remove(i);
+                }
+                inheritance.update(self.addr());
+            }
+        }
+    }
+
+    /// Locks the mutex if it's free, returning `None` otherwise.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        let mut inheritance = self.inheritance.lock();
+        inheritance.owner = context::current();
+        // tasks still waiting from before pass their priority on to the new owner
+        inheritance.update(self.addr());
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Returns the mutex's address, which the priority it passes on is recorded under.
+    fn addr(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+
+    /// Returns `true` if the mutex is currently locked, `false` otherwise.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Returns a mutable reference to the inner value.
+    ///
+    /// No locking is needed, since this borrows the mutex mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// A guard giving access to the value in a locked [`Mutex`], unlocking it when dropped.
+#[must_use = "Mutex will be unlocked when this is dropped"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let owner = self.mutex.inheritance.lock().owner.take();
+        let lowered = owner.is_some_and(|owner| {
+            with_irqs_disabled(|| {
+                let mut owner = owner.write();
+                let inherited = owner.priority;
+                owner.inherit_priority(self.mutex.addr(), None);
+                owner.priority < inherited
+            })
+        });
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+        if lowered {
+            // the task woken may outrank this one, now it's lost what it inherited
+            switch();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+/// A condition variable, for sleeping until another task changes something behind a
+/// [`Mutex`].
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks `guard`'s mutex and sleeps until notified, then locks it again.
+    ///
+    /// Wakeups can be spurious, so callers should check their condition in a loop, or use
+    /// [`wait_while`](Self::wait_while).
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // queue up before unlocking, so a notify right after the unlock isn't missed
+        let ticket = self.waiters.prepare_wait();
+        drop(guard);
+        match ticket {
+            Some(ticket) => ticket.sleep(),
+            None => core::hint::spin_loop(),
+        }
+        mutex.lock()
+    }
+
+    /// Sleeps until `cond` returns `false` for the value behind `guard`'s mutex.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while cond(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes one task waiting on the condition variable. Returns `false` if there was none.
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    /// Wakes every task waiting on the condition variable, returning how many were woken.
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
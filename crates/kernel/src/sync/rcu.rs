@@ -0,0 +1,126 @@
+//! Read-copy-update: data that's read without locking and replaced wholesale by writers.
+
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::arch::{Arch, Architecture};
+
+use super::{SavedInterruptStatus, TaskMutex};
+
+/// A value that readers access without taking a lock, and writers update by publishing a
+/// modified copy.
+///
+/// Readers never wait for writers, so this suits data read from interrupt handlers and
+/// changed rarely from task context. Writers wait for every reader of the old copy to finish
+/// before freeing it, which is cheap because read-side sections are short and run with
+/// interrupts disabled.
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    /// Counts up once per update; readers register under its parity.
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    /// Serializes writers.
+    update_lock: TaskMutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    /// Creates a new `Rcu` holding `value`.
+    pub fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            update_lock: TaskMutex::new(()),
+        }
+    }
+
+    /// Returns a guard giving access to the current value.
+    ///
+    /// Interrupts stay disabled while the guard is alive, and it mustn't be held across a
+    /// context switch: writers wait for it to be dropped.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        let saved_intr_status = SavedInterruptStatus::save();
+        unsafe { Arch::disable_interrupts() };
+
+        let slot = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let slot = epoch % 2;
+            self.readers[slot].fetch_add(1, Ordering::SeqCst);
+            // if a writer moved the epoch on meanwhile, it might not be waiting for this slot
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break slot;
+            }
+            self.readers[slot].fetch_sub(1, Ordering::SeqCst);
+        };
+
+        RcuReadGuard {
+            rcu: self,
+            value: unsafe { &*self.current.load(Ordering::SeqCst) },
+            slot,
+            _saved_intr_status: saved_intr_status,
+        }
+    }
+
+    /// Replaces the value with a modified copy of it, waiting until nobody is reading the old
+    /// one before dropping it.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Clone,
+    {
+        let _update_guard = self.update_lock.lock();
+
+        let mut new = Box::new(unsafe { (*self.current.load(Ordering::SeqCst)).clone() });
+        let result = f(&mut new);
+        let old = self.current.swap(Box::into_raw(new), Ordering::SeqCst);
+
+        // readers that start from here on see the new copy; wait out the ones that might not
+        let slot = self.epoch.fetch_add(1, Ordering::SeqCst) % 2;
+        while self.readers[slot].load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+
+        drop(unsafe { Box::from_raw(old) });
+        result
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+/// A guard giving access to the value in an [`Rcu`], from [`Rcu::read`].
+///
+/// Interrupts are restored when this is dropped.
+#[must_use = "Interrupt status will be restored when this is dropped"]
+pub struct RcuReadGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    value: &'a T,
+    slot: usize,
+    _saved_intr_status: SavedInterruptStatus,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rcu.readers[self.slot].fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,228 @@
+//! A fair reader-writer spinlock that disables interrupts while it's held.
+//!
+//! Readers and writers take tickets and are let in in the order they took them, so a writer
+//! waits only for those who came before it, and readers who came after a waiting writer wait
+//! for it in turn: a steady stream of readers can't starve writers. Readers who come one after
+//! another are let in together.
+
+use core::{
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use crate::arch::{Arch, Architecture};
+
+use super::SavedInterruptStatus;
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+
+/// A reader-writer spinlock, fair between readers and writers, that disables interrupts while
+/// it's held.
+///
+/// Use this over an [`IrqMutex`](super::IrqMutex) for data that's read much more often than
+/// it's written.
+pub struct RwLock<T: ?Sized> {
+    /// The next ticket to be handed out.
+    users: AtomicU16,
+    /// The ticket of the next reader to be let in.
+    read: AtomicU16,
+    /// The ticket of the next writer to be let in.
+    write: AtomicU16,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new unlocked `RwLock`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            users: AtomicU16::new(0),
+            read: AtomicU16::new(0),
+            write: AtomicU16::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks the `RwLock` for reading, spinning until every writer who came first is done.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let saved = disable_interrupts();
+        #[cfg(feature = "lockdep")]
+        self.lockdep_acquire();
+        let ticket = self.users.fetch_add(1, Ordering::Relaxed);
+        while self.read.load(Ordering::Acquire) != ticket {
+            hint::spin_loop();
+        }
+        // let in whoever is next, if they're reading too
+        self.read.fetch_add(1, Ordering::Release);
+        RwLockReadGuard {
+            lock: self,
+            _saved: saved,
+        }
+    }
+
+    /// Locks the `RwLock` for writing, spinning until everyone who came first is done.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let saved = disable_interrupts();
+        #[cfg(feature = "lockdep")]
+        self.lockdep_acquire();
+        let ticket = self.users.fetch_add(1, Ordering::Relaxed);
+        while self.write.load(Ordering::Acquire) != ticket {
+            hint::spin_loop();
+        }
+        RwLockWriteGuard {
+            lock: self,
+            _saved: saved,
+        }
+    }
+
+    /// Locks the `RwLock` for reading if no writer holds it or is waiting for it, returning
+    /// `None` otherwise.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let saved = disable_interrupts();
+        let ticket = self.read.load(Ordering::Relaxed);
+        self.users
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        #[cfg(feature = "lockdep")]
+        self.lockdep_acquire();
+        self.read.fetch_add(1, Ordering::Release);
+        Some(RwLockReadGuard {
+            lock: self,
+            _saved: saved,
+        })
+    }
+
+    /// Locks the `RwLock` for writing if nobody holds it or is waiting for it, returning
+    /// `None` otherwise.
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let saved = disable_interrupts();
+        let ticket = self.write.load(Ordering::Relaxed);
+        self.users
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        #[cfg(feature = "lockdep")]
+        self.lockdep_acquire();
+        Some(RwLockWriteGuard {
+            lock: self,
+            _saved: saved,
+        })
+    }
+
+    /// Returns `true` if anyone holds the `RwLock` or is waiting for it.
+    pub fn is_locked(&self) -> bool {
+        self.write.load(Ordering::Relaxed) != self.users.load(Ordering::Relaxed)
+    }
+
+    /// Returns a mutable reference to the inner value.
+    ///
+    /// No locking is needed, since this borrows the `RwLock` mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    #[cfg(feature = "lockdep")]
+    #[track_caller]
+    fn lockdep_acquire(&self) {
+        lockdep::acquire(
+            self.addr(),
+            core::any::type_name::<T>(),
+            core::panic::Location::caller(),
+        );
+    }
+
+    /// Returns the address lock order diagnostics know this lock by.
+    #[cfg(feature = "lockdep")]
+    fn addr(&self) -> usize {
+        core::ptr::from_ref(self).cast::<()>() as usize
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.addr());
+    }
+}
+
+fn disable_interrupts() -> SavedInterruptStatus {
+    let saved = SavedInterruptStatus::save();
+    unsafe { Arch::disable_interrupts() };
+    saved
+}
+
+/// A guard giving shared access to the value in an [`RwLock`] locked for reading.
+///
+/// Dropping it unlocks the lock, then restores the interrupt status.
+#[must_use = "RwLock will be unlocked and interrupt status will be restored when this is dropped"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _saved: SavedInterruptStatus,
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock.addr());
+        // one less reader for the next writer to wait for
+        self.lock.write.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+/// A guard giving exclusive access to the value in an [`RwLock`] locked for writing.
+///
+/// Dropping it unlocks the lock, then restores the interrupt status.
+#[must_use = "RwLock will be unlocked and interrupt status will be restored when this is dropped"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _saved: SavedInterruptStatus,
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock.addr());
+        // let in whoever is next, reading or writing
+        self.lock.read.fetch_add(1, Ordering::Release);
+        self.lock.write.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
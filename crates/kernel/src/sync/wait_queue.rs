@@ -0,0 +1,160 @@
+//! Queues of tasks waiting for something to happen.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+use spinning_top::RwSpinlock;
+
+use crate::{
+    irq,
+    task::{
+        context::{self, BlockReason, Context, Status},
+        switch::{switch, unblock},
+    },
+};
+
+use super::IrqMutex;
+
+/// A task parked on a [`WaitQueue`].
+struct Waiter {
+    cx: Arc<RwSpinlock<Context>>,
+    woken: AtomicBool,
+}
+
+/// A queue of blocked tasks, woken in the order they started waiting.
+///
+/// Waiting parks the current task, so the scheduler doesn't run it until it's woken. Waking
+/// is safe from interrupt handlers; waiting isn't.
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<Arc<Waiter>>>,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitQueue {
+    /// Creates an empty wait queue.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task until `cond` returns `true`.
+    ///
+    /// `cond` is checked again after the task is queued, so a wakeup that happens between
+    /// the check and the task going to sleep isn't missed. Without a current task (early in
+    /// boot), this spins instead.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        while !cond() {
+            let Some(ticket) = self.prepare_wait() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            if cond() {
+                return;
+            }
+            ticket.sleep();
+        }
+    }
+
+    /// Queues the current task without going to sleep yet.
+    ///
+    /// Wakeups from then on aren't lost: if one comes before [`WaitTicket::sleep`], the
+    /// sleep returns straight away. Dropping the ticket takes the task off the queue again.
+    /// Returns `None` if there is no current task.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if called from an interrupt handler.
+    #[must_use]
+    pub fn prepare_wait(&self) -> Option<WaitTicket<'_>> {
+        debug_assert!(
+            !irq::in_irq_context(),
+            "can't wait on a WaitQueue in an interrupt handler"
+        );
+        let waiter = Arc::new(Waiter {
+            cx: context::current()?,
+            woken: AtomicBool::new(false),
+        });
+        self.waiters.lock().push_back(waiter.clone());
+        Some(WaitTicket {
+            queue: self,
+            waiter,
+        })
+    }
+
+    /// Wakes the task that has been waiting longest. Returns `false` if there was none.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        let Some(waiter) = waiters.pop_front() else {
+            return false;
+        };
+        // done under the queue lock, so it can't land between a sleeper checking `woken`
+        // and marking itself blocked
+        waiter.woken.store(true, Ordering::Release);
+        unblock(&waiter.cx);
+        true
+    }
+
+    /// Wakes up to `count` tasks, returning how many were woken.
+    pub fn wake_n(&self, count: usize) -> usize {
+        (0..count).take_while(|_| self.wake_one()).count()
+    }
+
+    /// Wakes every waiting task, returning how many were woken.
+    pub fn wake_all(&self) -> usize {
+        self.wake_n(usize::MAX)
+    }
+
+    /// Returns `true` if no tasks are waiting.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+/// A task's place on a [`WaitQueue`], from [`WaitQueue::prepare_wait`].
+#[must_use = "the task is taken off the queue when the ticket is dropped"]
+pub struct WaitTicket<'a> {
+    queue: &'a WaitQueue,
+    waiter: Arc<Waiter>,
+}
+
+impl WaitTicket<'_> {
+    /// Switches away until the task is woken.
+    pub fn sleep(self) {
+        {
+            let _waiters = self.queue.waiters.lock();
+            if self.waiter.woken.load(Ordering::Acquire) {
+                return;
+            }
+            self.waiter.cx.write().status = Status::Blocked {
+                reason: BlockReason::WaitQueue,
+            };
+        }
+        while !self.waiter.woken.load(Ordering::Acquire) {
+            switch();
+        }
+    }
+}
+
+impl Drop for WaitTicket<'_> {
+    fn drop(&mut self) {
+        if self.waiter.woken.load(Ordering::Acquire) {
+            return;
+        }
+        self.queue
+            .waiters
+            .lock()
+            .retain(|waiter| !Arc::ptr_eq(waiter, &self.waiter));
+    }
+}
@@ -0,0 +1,103 @@
+//! File system calls: reading and writing file descriptors, and opening and closing them.
+
+use alloc::{sync::Arc, vec};
+
+use crate::{
+    file::File,
+    ipc,
+    mem::user::{UserPtr, UserSlice},
+    task::context,
+};
+
+use super::errno::Errno;
+
+/// The most bytes copied through the kernel at a time.
+const CHUNK_SIZE: usize = 4096;
+
+fn current_file(fd: usize) -> Result<Arc<dyn File>, Errno> {
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    cx.read().files.get(fd)
+}
+
+/// Reads up to `buf.len()` bytes from `fd` into `buf`, returning how many were read.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open for reading, [`Errno::EFAULT`] if `buf`
+/// isn't writable, or any error from the file.
+pub fn read(fd: usize, buf: UserSlice) -> Result<usize, Errno> {
+    let file = current_file(fd)?;
+    let mut chunk = vec![0; buf.len().min(CHUNK_SIZE)];
+    let len = file.read(&mut chunk)?;
+    buf.write_from(&chunk[..len])
+}
+
+/// Writes `buf` to `fd`, returning how many bytes were written.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open for writing, [`Errno::EFAULT`] if none of
+/// `buf` is readable, or any error from the file before anything was written.
+pub fn write(fd: usize, buf: UserSlice) -> Result<usize, Errno> {
+    let file = current_file(fd)?;
+
+    // copied in pieces, so a large write doesn't need a large kernel buffer
+    let mut chunk = vec![0; buf.len().min(CHUNK_SIZE)];
+    let mut written = 0;
+    while written < buf.len() {
+        // report what was written before a bad page or closed pipe, like a short write
+        let result = buf
+            .subslice(written, chunk.len())
+            .read_into(&mut chunk)
+            .and_then(|len| file.write(&chunk[..len]).map(|n| (len, n)));
+        let (len, n) = match result {
+            Ok(lens) => lens,
+            Err(_) if written != 0 => break,
+            Err(e) => return Err(e),
+        };
+        written += n;
+        if n < len {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+/// Creates a pipe, writing the file descriptors of its read and write ends to `fds`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EMFILE`] if the current task has too many files open, or
+/// [`Errno::EFAULT`] if `fds` isn't writable.
+pub fn pipe(fds: UserPtr<[u32; 2]>) -> Result<(), Errno> {
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let (reader, writer) = ipc::pipe();
+    // nothing else can reach the pipe yet, so closing it can't sleep with the context locked
+    let (read_fd, write_fd) = {
+        let files = &mut cx.write().files;
+        let read_fd = files.insert(Arc::new(reader))?;
+        let write_fd = files.insert(Arc::new(writer)).inspect_err(|_| {
+            files.remove(read_fd).ok();
+        })?;
+        (read_fd, write_fd)
+    };
+    fds.write([read_fd as u32, write_fd as u32])
+        .inspect_err(|_| {
+            let files = &mut cx.write().files;
+            files.remove(read_fd).ok();
+            files.remove(write_fd).ok();
+        })
+}
+
+/// Closes `fd`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open.
+pub fn close(fd: usize) -> Result<(), Errno> {
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let file = cx.write().files.remove(fd)?;
+    // closing a pipe end takes the pipe's lock, which can sleep, so not with the context locked
+    drop(file);
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Futexes: waiting on a word in user memory until another task changes it.
+//!
+//! Waiters are keyed by the physical address of the word, so tasks sharing memory at
+//! different virtual addresses still meet on the same queue.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+
+use crate::{
+    arch::{Arch, Architecture},
+    mem::units::{PhysAddr, VirtAddr},
+    sync::{TaskMutex, WaitQueue},
+    task::context,
+};
+
+use super::errno::Errno;
+
+/// The queues of tasks waiting on each futex word, dropped once nobody is waiting.
+static FUTEXES: TaskMutex<BTreeMap<PhysAddr, Arc<WaitQueue>>> = TaskMutex::new(BTreeMap::new());
+
+/// Translates the address of a futex word in the current task's address space.
+fn resolve(addr: usize) -> Result<PhysAddr, Errno> {
+    if !addr.is_multiple_of(size_of::<u32>()) {
+        return Err(Errno::EINVAL);
+    }
+    let virt = VirtAddr::new(addr).map_err(|_| Errno::EFAULT)?;
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let addr_space = cx.read().addr_space.clone().ok_or(Errno::EFAULT)?;
+    let entry = addr_space
+        .read()
+        .table
+        .translate(virt)
+        .map_err(|_| Errno::EFAULT)?;
+    if !entry.flags().is_present() {
+        return Err(Errno::EFAULT);
+    }
+    let frame = entry.addr().map_err(|_| Errno::EFAULT)?;
+    Ok(frame.add_bytes(addr & Arch::PAGE_OFFSET_MASK))
+}
+
+/// Sleeps until woken by [`wake`], if the word at `addr` still holds `val`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EAGAIN`] if the word holds something else, and [`Errno::EINVAL`] or
+/// [`Errno::EFAULT`] if `addr` is misaligned or unmapped.
+pub fn wait(addr: usize, val: u32) -> Result<(), Errno> {
+    let phys = resolve(addr)?;
+    let mut futexes = FUTEXES.lock();
+    // compared under the lock, so a wake can't slip in between the check and queueing up
+    let current: u32 = unsafe { phys.as_hhdm_virt().read_volatile() }.map_err(|_| Errno::EFAULT)?;
+    if current != val {
+        return Err(Errno::EAGAIN);
+    }
+    let queue = futexes.entry(phys).or_default().clone();
+    let ticket = queue.prepare_wait().ok_or(Errno::ESRCH)?;
+    drop(futexes);
+    ticket.sleep();
+
+    // a wake may have dropped the queue already, and another waiter queued on a new one
+    let mut futexes = FUTEXES.lock();
+    if futexes
+        .get(&phys)
+        .is_some_and(|q| Arc::ptr_eq(q, &queue) && q.is_empty())
+    {
+        futexes.remove(&phys);
+    }
+    Ok(())
+}
+
+/// Wakes up to `count` tasks waiting on the word at `addr`, returning how many were woken.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] or [`Errno::EFAULT`] if `addr` is misaligned or unmapped.
+pub fn wake(addr: usize, count: usize) -> Result<usize, Errno> {
+    let phys = resolve(addr)?;
+    let mut futexes = FUTEXES.lock();
+    let Some(queue) = futexes.get(&phys) else {
+        return Ok(0);
+    };
+    let woken = queue.wake_n(count);
+    if queue.is_empty() {
+        futexes.remove(&phys);
+    }
+    Ok(woken)
+}
@@ -0,0 +1,141 @@
+//! Memory management system calls: mapping and unmapping memory, moving the heap's end, and
+//! shared memory objects.
+
+use alloc::sync::Arc;
+
+use kados_abi::syscall::{FbInfo, MAP_ANONYMOUS, MAP_FIXED};
+
+use crate::{
+    framebuffer,
+    ipc::shm,
+    mem::{units::VirtAddr, user::UserPtr},
+    task::{
+        addr_space::{AddrSpaceLock, Backing, Protection},
+        context,
+    },
+};
+
+use super::errno::Errno;
+
+fn current_addr_space() -> Result<Arc<AddrSpaceLock>, Errno> {
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let addr_space = cx.read().addr_space.clone();
+    addr_space.ok_or(Errno::EFAULT)
+}
+
+fn user_addr(addr: usize) -> Result<VirtAddr, Errno> {
+    let addr = VirtAddr::new(addr).map_err(|_| Errno::EINVAL)?;
+    if addr > VirtAddr::MAX_LOW {
+        return Err(Errno::EINVAL);
+    }
+    Ok(addr)
+}
+
+/// Maps `len` bytes of zeroed memory into the current task, returning its address.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] for unknown `prot` or `flags` bits, or a mapping that isn't
+/// anonymous, and otherwise as for [`AddrSpace::map`](crate::task::addr_space::AddrSpace::map).
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize) -> Result<usize, Errno> {
+    let prot = Protection::from_bits(prot).ok_or(Errno::EINVAL)?;
+    if flags & !(MAP_FIXED | MAP_ANONYMOUS) != 0 || flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let fixed = flags & MAP_FIXED != 0;
+    let addr = if fixed {
+        user_addr(addr)?
+    } else {
+        // only a hint, so a bad one is ignored
+        user_addr(addr).unwrap_or(VirtAddr::NULL)
+    };
+
+    let addr_space = current_addr_space()?;
+    let start = addr_space
+        .write()
+        .map(addr, len, fixed, prot, Backing::Anonymous)?;
+    Ok(start.value())
+}
+
+/// Unmaps the pages in `addr..addr + len` from the current task.
+///
+/// # Errors
+///
+/// See [`AddrSpace::unmap`](crate::task::addr_space::AddrSpace::unmap).
+pub fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    let addr = user_addr(addr)?;
+    current_addr_space()?.write().unmap(addr, len)
+}
+
+/// Moves the current task's program break to `addr`, returning where it ends up.
+///
+/// # Errors
+///
+/// Returns [`Errno::ESRCH`] if there's no current task.
+pub fn brk(addr: usize) -> Result<usize, Errno> {
+    let addr_space = current_addr_space()?;
+    let mut addr_space = addr_space.write();
+    let new = VirtAddr::new(addr).unwrap_or(VirtAddr::NULL);
+    Ok(addr_space.brk(new).value())
+}
+
+/// Creates a shared memory object of `len` bytes, returning its id.
+///
+/// # Errors
+///
+/// See [`shm::create`].
+pub fn shm_create(len: usize) -> Result<usize, Errno> {
+    shm::create(len)
+}
+
+/// Maps all of shared memory object `id` into the current task, returning its address.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] for unknown `prot` or `flags` bits, and otherwise as for
+/// [`shm::map`].
+pub fn shm_map(id: usize, addr: usize, prot: usize, flags: usize) -> Result<usize, Errno> {
+    let prot = Protection::from_bits(prot).ok_or(Errno::EINVAL)?;
+    if flags & !MAP_FIXED != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let fixed = flags & MAP_FIXED != 0;
+    let addr = if fixed {
+        user_addr(addr)?
+    } else {
+        user_addr(addr).unwrap_or(VirtAddr::NULL)
+    };
+
+    let addr_space = current_addr_space()?;
+    let start = shm::map(&mut addr_space.write(), id, addr, fixed, prot)?;
+    Ok(start.value())
+}
+
+/// Takes away shared memory object `id`'s id.
+///
+/// # Errors
+///
+/// See [`shm::destroy`].
+pub fn shm_destroy(id: usize) -> Result<(), Errno> {
+    shm::destroy(id)
+}
+
+/// Maps the framebuffer into the current task, returning the address of its first pixel and
+/// writing what it looks like to `info`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EPERM`] if the current task isn't privileged, [`Errno::EFAULT`] if
+/// `info` isn't writable, and otherwise as for [`framebuffer::map_into`].
+pub fn fb_map(info: UserPtr<FbInfo>) -> Result<usize, Errno> {
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    if !cx.read().privileged {
+        return Err(Errno::EPERM);
+    }
+    // checked first, so a bad pointer doesn't leave the framebuffer mapped
+    info.write(FbInfo::default())?;
+    let addr_space = current_addr_space()?;
+    let (start, fb_info) = framebuffer::map_into(&mut addr_space.write())?;
+    info.write(fb_info)?;
+    Ok(start.value())
+}
@@ -1 +1,64 @@
+//! System call handling.
+
+use kados_abi::syscall::{FUTEX_WAIT, FUTEX_WAKE, Sysno};
+
+use crate::{
+    mem::user::{UserPtr, UserSlice},
+    task::{context::Pid, signal, switch::switch},
+    trace::{trace_syscall, trace_syscall_exit},
+};
+
+use errno::{Errno, ErrnoResult};
+
 pub mod errno;
+pub mod file;
+pub mod futex;
+pub mod mm;
+pub mod sched;
+pub mod task;
+
+/// Runs system call `sysno` with the given arguments, returning what goes back in the
+/// caller's result register: the result, or a negated [`Errno`].
+#[must_use]
+pub fn handle(sysno: usize, args: [usize; 6]) -> usize {
+    trace_syscall(sysno, args[0]);
+    let result = Sysno::try_from(sysno).and_then(|sysno| dispatch(sysno, args));
+    let result = result.to_isize() as usize;
+    trace_syscall_exit(sysno, result);
+    result
+}
+
+fn dispatch(sysno: Sysno, args: [usize; 6]) -> Result<isize, Errno> {
+    match sysno {
+        Sysno::Exit => task::exit(args[0]),
+        Sysno::Write => file::write(args[0], UserSlice::new(args[1], args[2])?).map(|n| n as isize),
+        Sysno::Yield => {
+            switch();
+            Ok(0)
+        }
+        Sysno::Futex => match args[1] {
+            FUTEX_WAIT => futex::wait(args[0], args[2] as u32).map(|()| 0),
+            FUTEX_WAKE => futex::wake(args[0], args[2]).map(|woken| woken as isize),
+            _ => Err(Errno::EINVAL),
+        },
+        Sysno::Mmap => mm::mmap(args[0], args[1], args[2], args[3]).map(|addr| addr as isize),
+        Sysno::Munmap => mm::munmap(args[0], args[1]).map(|()| 0),
+        Sysno::Brk => mm::brk(args[0]).map(|addr| addr as isize),
+        Sysno::Kill => signal::send(Pid::from_raw(args[0]), args[1]).map(|()| 0),
+        Sysno::Sigaction => signal::sigaction(args[0], args[1], args[2]).map(|old| old as isize),
+        // needs the whole register frame, so the exception vector handles it itself
+        Sysno::Sigreturn => Err(Errno::ENOSYS),
+        Sysno::Read => file::read(args[0], UserSlice::new(args[1], args[2])?).map(|n| n as isize),
+        Sysno::Pipe => file::pipe(UserPtr::new(args[0])).map(|()| 0),
+        Sysno::Close => file::close(args[0]).map(|()| 0),
+        Sysno::ShmCreate => mm::shm_create(args[0]).map(|id| id as isize),
+        Sysno::ShmMap => mm::shm_map(args[0], args[1], args[2], args[3]).map(|addr| addr as isize),
+        Sysno::ShmDestroy => mm::shm_destroy(args[0]).map(|()| 0),
+        Sysno::FbMap => mm::fb_map(UserPtr::new(args[0])).map(|addr| addr as isize),
+        Sysno::SchedSetAffinity => sched::sched_setaffinity(args[0]).map(|()| 0),
+        Sysno::SchedGetAffinity => sched::sched_getaffinity().map(|mask| mask as isize),
+        Sysno::Wait4 => {
+            task::wait4(args[0], UserPtr::new(args[1]), args[2]).map(|pid| pid as isize)
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Scheduling system calls.
+
+use crate::task::{
+    context::{self, CpuMask},
+    cpus,
+};
+
+use super::errno::Errno;
+
+/// Sets the CPUs the current task may run on.
+///
+/// # Errors
+///
+/// As for [`cpus::set_affinity`].
+pub fn sched_setaffinity(mask: usize) -> Result<(), Errno> {
+    let pid = context::current().ok_or(Errno::ESRCH)?.read().pid;
+    cpus::set_affinity(pid, CpuMask(mask as u64))
+}
+
+/// Returns the online CPUs the current task may run on.
+///
+/// Only the online ones, so the mask fits in the positive half of the result register.
+///
+/// # Errors
+///
+/// Returns [`Errno::ESRCH`] if there's no current task.
+pub fn sched_getaffinity() -> Result<usize, Errno> {
+    let pid = context::current().ok_or(Errno::ESRCH)?.read().pid;
+    let mask = cpus::affinity(pid)?.0 & cpus::online().0;
+    Ok(mask as usize)
+}
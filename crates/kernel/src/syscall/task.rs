@@ -0,0 +1,42 @@
+//! Ending tasks and collecting how they ended.
+
+use kados_abi::syscall::{ExitStatus, WAIT_ANY, WNOHANG};
+
+use crate::{
+    mem::user::UserPtr,
+    task::{
+        context::{self, Pid},
+        reap,
+    },
+};
+
+use super::errno::Errno;
+
+/// Ends the current task with exit code `code`.
+#[allow(clippy::cast_possible_truncation)] // only the low byte is reported anyway
+pub fn exit(code: usize) -> ! {
+    context::exit_current_with(ExitStatus::Exited(code as i32));
+    unreachable!()
+}
+
+/// Waits for a child of the current task to exit, or child `pid` unless it's [`WAIT_ANY`],
+/// frees it, and writes how it ended to `status` unless that's null. Returns the child's
+/// PID, or 0 if `options` has [`WNOHANG`] and none has exited yet.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] for unknown options, [`Errno::EFAULT`] if `status` can't be
+/// written, or anything [`reap::wait`] can.
+pub fn wait4(pid: usize, status: UserPtr<i32>, options: usize) -> Result<usize, Errno> {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let pid = (pid != WAIT_ANY).then_some(Pid::from_raw(pid));
+    let Some((pid, exit_status)) = reap::wait(pid, options & WNOHANG != 0)? else {
+        return Ok(0);
+    };
+    if status.addr() != 0 {
+        status.write(exit_status.to_raw())?;
+    }
+    Ok(pid.as_raw())
+}
@@ -1,14 +1,129 @@
-use alloc::sync::Arc;
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use kados_abi::syscall::{PROT_EXEC, PROT_READ, PROT_WRITE};
 use spin::{RwLock, RwLockReadGuard, rwlock::RwLockWriteGuard};
 
 use crate::{
+    arch::{Arch, Architecture},
     cpu_local::CpuLocalBlock,
-    mem::paging::table::{PageTable, TableKind},
+    ipc::shm,
+    mem::{
+        paging::{
+            allocator::KernelFrameAllocator,
+            asid::{self, Asid},
+            flush::PageFlushAll,
+            table::{BlockSize, PageFlags, PageTable, PageTableEntry, TableKind},
+        },
+        units::{FrameCount, PhysAddr, VirtAddr},
+    },
     syscall::errno::Errno,
 };
 
+/// Where the program break starts out.
+const HEAP_BASE: usize = 0x0000_0010_0000_0000;
+/// The lowest address mappings are placed at when the caller doesn't choose one.
+const MMAP_BASE: usize = 0x0000_2000_0000_0000;
+
+bitflags! {
+    /// How the pages of a region may be accessed from userspace.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Protection: usize {
+        const READ = PROT_READ;
+        const WRITE = PROT_WRITE;
+        const EXEC = PROT_EXEC;
+    }
+}
+
+impl Protection {
+    fn page_flags(self) -> PageFlags {
+        let mut flags = PageFlags::new().user();
+        if self.contains(Self::WRITE) {
+            flags = flags.writable();
+        }
+        if self.contains(Self::EXEC) {
+            flags = flags.executable();
+        }
+        flags
+    }
+}
+
+/// What a region's pages are mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames, allocated when the region is mapped and freed when it's unmapped.
+    Anonymous,
+    /// Physically contiguous memory starting at the given address, which isn't freed.
+    Physical(PhysAddr),
+    /// Like [`Backing::Physical`], but mapped uncached, for memory a device reads behind the
+    /// CPU's back, like a framebuffer.
+    Device(PhysAddr),
+    /// The frames of a [shared memory object](crate::ipc::shm) starting at the given address,
+    /// which the object frees once nothing maps them.
+    Shared(PhysAddr),
+}
+
+impl Backing {
+    /// Returns the backing of the part of a region `offset` bytes in.
+    #[must_use]
+    fn offset(self, offset: usize) -> Self {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::Physical(phys) => Self::Physical(phys.add_bytes(offset)),
+            Self::Device(phys) => Self::Device(phys.add_bytes(offset)),
+            Self::Shared(phys) => Self::Shared(phys.add_bytes(offset)),
+        }
+    }
+}
+
+/// A mapped range of an address space.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// The size of the region in bytes, a multiple of the page size.
+    pub size: usize,
+    pub prot: Protection,
+    pub backing: Backing,
+}
+
+impl Region {
+    /// Returns the address just past the end of the region.
+    #[must_use]
+    pub fn end(&self) -> VirtAddr {
+        self.start.add_bytes(self.size)
+    }
+
+    /// Returns the part of the region in `start..end`, which must overlap it.
+    fn slice(&self, start: VirtAddr, end: VirtAddr) -> Self {
+        let start = start.max(self.start);
+        let end = end.min(self.end());
+        Self {
+            start,
+            size: end.value() - start.value(),
+            prot: self.prot,
+            backing: self.backing.offset(start.value() - self.start.value()),
+        }
+    }
+
+    /// Returns `true` if `next` starts where this region ends and can be merged into it.
+    fn can_merge(&self, next: &Self) -> bool {
+        self.end() == next.start
+            && self.prot == next.prot
+            && self.backing.offset(self.size) == next.backing
+    }
+}
+
+fn is_page_aligned(addr: usize) -> bool {
+    addr.is_multiple_of(Arch::PAGE_SIZE)
+}
+
 pub struct AddrSpace {
     pub table: PageTable,
+    /// Tags the TLB entries of a user address space's table.
+    asid: Asid,
+    /// The mapped regions, by start address.
+    regions: BTreeMap<VirtAddr, Region>,
+    /// The current program break. The heap is the pages from [`HEAP_BASE`] up to it.
+    brk: VirtAddr,
 }
 
 impl AddrSpace {
@@ -25,14 +140,318 @@ impl AddrSpace {
     pub fn new_user() -> Result<Self, Errno> {
         Ok(Self {
             table: PageTable::create(TableKind::User),
+            asid: Asid::new(),
+            regions: BTreeMap::new(),
+            brk: VirtAddr::new_canonical(HEAP_BASE),
         })
     }
 
     pub fn current_kernel() -> Result<Self, Errno> {
         Ok(Self {
             table: PageTable::current(TableKind::Kernel),
+            asid: Asid::new(),
+            regions: BTreeMap::new(),
+            brk: VirtAddr::new_canonical(HEAP_BASE),
         })
     }
+
+    /// Makes this address space's user table current, tagged with its ASID, so nothing has to
+    /// be flushed. Kernel address spaces have no user half, so [`asid::empty_table`] is used.
+    ///
+    /// # Safety
+    ///
+    /// The user half of the previous address space mustn't be in use any more.
+    pub unsafe fn activate(&self) {
+        unsafe {
+            match self.table.kind() {
+                TableKind::User => {
+                    Arch::switch_user_page_table(self.table.phys_addr(), self.asid.get());
+                }
+                TableKind::Kernel => Arch::switch_user_page_table(asid::empty_table(), 0),
+            }
+        }
+    }
+
+    /// Returns the mapped regions, in address order.
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    /// Returns the region containing `addr`, if it's mapped.
+    #[must_use]
+    pub fn region_at(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| addr < region.end())
+    }
+
+    /// Returns `true` if nothing is mapped in `start..start + size`.
+    fn is_free(&self, start: VirtAddr, size: usize) -> bool {
+        let end = start.add_bytes(size);
+        self.region_at(start).is_none() && self.regions.range(start..end).next().is_none()
+    }
+
+    /// Finds the lowest free range of `size` bytes at or above [`MMAP_BASE`].
+    fn find_free(&self, size: usize) -> Option<VirtAddr> {
+        let mut candidate = VirtAddr::new_canonical(MMAP_BASE);
+        for region in self.regions.range(candidate..).map(|(_, region)| region) {
+            if region.start.value() - candidate.value() >= size {
+                break;
+            }
+            candidate = candidate.max(region.end());
+        }
+        (candidate.value().checked_add(size)? <= VirtAddr::MAX_LOW.value()).then_some(candidate)
+    }
+
+    /// Maps `size` bytes (rounded up to whole pages), returning the address of the mapping.
+    ///
+    /// With `fixed`, the mapping goes at exactly `addr`, replacing anything already mapped
+    /// there. Otherwise `addr` is only a hint, used if the range there is free.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EINVAL`] if `size` is 0 or a fixed `addr` is misaligned or outside the
+    /// user half, and [`Errno::ENOMEM`] if there's no room or no memory for the mapping.
+    pub fn map(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        fixed: bool,
+        prot: Protection,
+        backing: Backing,
+    ) -> Result<VirtAddr, Errno> {
+        if size == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let size = size
+            .checked_next_multiple_of(Arch::PAGE_SIZE)
+            .ok_or(Errno::ENOMEM)?;
+        let in_user_half = addr
+            .value()
+            .checked_add(size)
+            .is_some_and(|end| end <= VirtAddr::MAX_LOW.value());
+
+        let start = if fixed {
+            if addr.is_null() || !is_page_aligned(addr.value()) || !in_user_half {
+                return Err(Errno::EINVAL);
+            }
+            self.unmap(addr, size)?;
+            addr
+        } else if !addr.is_null()
+            && is_page_aligned(addr.value())
+            && in_user_half
+            && self.is_free(addr, size)
+        {
+            addr
+        } else {
+            self.find_free(size).ok_or(Errno::ENOMEM)?
+        };
+
+        let region = Region {
+            start,
+            size,
+            prot,
+            backing,
+        };
+        self.map_pages(&region)?;
+        self.insert(region);
+        Ok(start)
+    }
+
+    /// Unmaps every page in `start..start + size` (rounded up to whole pages). Parts of the
+    /// range that aren't mapped are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::EINVAL`] if `start` is misaligned or the range leaves the user half.
+    pub fn unmap(&mut self, start: VirtAddr, size: usize) -> Result<(), Errno> {
+        let end = size
+            .checked_next_multiple_of(Arch::PAGE_SIZE)
+            .and_then(|size| start.value().checked_add(size))
+            .filter(|&end| end <= VirtAddr::MAX_LOW.value())
+            .ok_or(Errno::EINVAL)?;
+        if !is_page_aligned(start.value()) {
+            return Err(Errno::EINVAL);
+        }
+        let end = VirtAddr::new_canonical(end);
+
+        let first = self.region_at(start).map_or(start, |region| region.start);
+        let overlapping: Vec<VirtAddr> = self
+            .regions
+            .range(first..end)
+            .map(|(&start, _)| start)
+            .collect();
+        for key in overlapping {
+            let Some(region) = self.regions.remove(&key) else {
+                continue;
+            };
+            if region.start < start {
+                self.regions
+                    .insert(region.start, region.slice(region.start, start));
+            }
+            if region.end() > end {
+                self.regions.insert(end, region.slice(end, region.end()));
+            }
+            self.unmap_pages(&region.slice(start, end));
+        }
+        PageFlushAll.flush();
+        Ok(())
+    }
+
+    /// Moves the program break to `new`, mapping or unmapping heap pages to match, and
+    /// returns where the break ends up.
+    ///
+    /// The break doesn't move if `new` is below where the heap starts, or if growing the heap
+    /// would run into another mapping or out of memory.
+    pub fn brk(&mut self, new: VirtAddr) -> VirtAddr {
+        if new.value() < HEAP_BASE || new > VirtAddr::MAX_LOW {
+            return self.brk;
+        }
+        let old_end = self.brk.align_up(Arch::PAGE_SIZE);
+        let new_end = new.align_up(Arch::PAGE_SIZE);
+
+        if new_end > old_end {
+            let size = new_end.value() - old_end.value();
+            if !self.is_free(old_end, size) {
+                return self.brk;
+            }
+            let region = Region {
+                start: old_end,
+                size,
+                prot: Protection::READ | Protection::WRITE,
+                backing: Backing::Anonymous,
+            };
+            if self.map_pages(&region).is_err() {
+                return self.brk;
+            }
+            self.insert(region);
+        } else if new_end < old_end {
+            let size = old_end.value() - new_end.value();
+            if self.unmap(new_end, size).is_err() {
+                return self.brk;
+            }
+        }
+
+        self.brk = new;
+        new
+    }
+
+    /// Adds a region whose pages have been mapped, merging it with its neighbours if it can.
+    fn insert(&mut self, mut region: Region) {
+        let prev = self
+            .regions
+            .range(..region.start)
+            .next_back()
+            .map(|(_, prev)| *prev);
+        if let Some(prev) = prev
+            && prev.can_merge(&region)
+        {
+            self.regions.remove(&prev.start);
+            region = Region {
+                size: prev.size + region.size,
+                ..prev
+            };
+        }
+        let next = self.regions.get(&region.end()).copied();
+        if let Some(next) = next
+            && region.can_merge(&next)
+        {
+            self.regions.remove(&next.start);
+            region.size += next.size;
+        }
+        self.regions.insert(region.start, region);
+    }
+
+    /// Maps the pages of `region`, undoing any it managed to map if it fails partway.
+    fn map_pages(&mut self, region: &Region) -> Result<(), Errno> {
+        let mut flags = region.prot.page_flags();
+        if let Backing::Device(_) = region.backing {
+            flags = flags.uncached();
+        }
+        for offset in (0..region.size).step_by(Arch::PAGE_SIZE) {
+            let page = region.start.add_bytes(offset);
+            if let Err(e) = self.map_page(page, offset, region.backing, flags) {
+                self.unmap_pages(&region.slice(region.start, page));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn map_page(
+        &mut self,
+        page: VirtAddr,
+        offset: usize,
+        backing: Backing,
+        flags: PageFlags,
+    ) -> Result<(), Errno> {
+        let frame = match backing {
+            Backing::Anonymous => {
+                let frame =
+                    unsafe { KernelFrameAllocator.allocate_one() }.map_err(|_| Errno::ENOMEM)?;
+                unsafe { frame.as_hhdm_virt().fill(0, Arch::PAGE_SIZE) }
+                    .map_err(|_| Errno::EFAULT)?;
+                frame
+            }
+            Backing::Physical(phys) | Backing::Device(phys) | Backing::Shared(phys) => {
+                phys.add_bytes(offset)
+            }
+        };
+
+        let Ok(flush) = self.table.map_to(page, frame, BlockSize::Page4KiB, flags) else {
+            if backing == Backing::Anonymous {
+                KernelFrameAllocator.free(frame, FrameCount::new(1)).ok();
+            }
+            return Err(Errno::ENOMEM);
+        };
+        // the page wasn't mapped before, so there's nothing stale to flush
+        unsafe { flush.ignore() };
+        if let Backing::Shared(_) = backing {
+            shm::page_mapped(frame);
+        }
+        Ok(())
+    }
+
+    /// Unmaps the pages of `region`, freeing its frames if it's anonymous, or letting go of
+    /// them if they're shared. The TLB is left for the caller to flush.
+    fn unmap_pages(&mut self, region: &Region) {
+        for offset in (0..region.size).step_by(Arch::PAGE_SIZE) {
+            let page = region.start.add_bytes(offset);
+            let mut old = PageTableEntry::UNUSED;
+            let Ok(flush) = self.table.with_frame_mut(page, |entry| {
+                old = *entry;
+                *entry = PageTableEntry::UNUSED;
+            }) else {
+                continue;
+            };
+            unsafe { flush.ignore() };
+
+            if !old.flags().is_present() {
+                continue;
+            }
+            let Ok(frame) = old.addr() else {
+                continue;
+            };
+            match region.backing {
+                Backing::Anonymous => {
+                    KernelFrameAllocator.free(frame, FrameCount::new(1)).ok();
+                }
+                Backing::Shared(_) => shm::page_unmapped(frame),
+                Backing::Physical(_) | Backing::Device(_) => {}
+            }
+        }
+    }
+}
+
+impl Drop for AddrSpace {
+    fn drop(&mut self) {
+        let regions = core::mem::take(&mut self.regions);
+        for region in regions.values() {
+            self.unmap_pages(region);
+        }
+    }
 }
 
 pub struct AddrSpaceLock {
@@ -1,18 +1,37 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::{collections::btree_set::BTreeSet, sync::Arc};
+use alloc::{collections::btree_set::BTreeSet, string::String, sync::Arc, vec::Vec};
+use arrayvec::ArrayVec;
 use derive_more::{Deref, Display};
-use spin::RwLock;
+use kados_abi::syscall::ExitStatus;
+use spin::Lazy;
 use spinning_top::RwSpinlock;
 
 use crate::{
-    arch::task::ArchContext, cpu_local::CpuLocalBlock,
-    mem::paging::allocator::KernelFrameAllocator, syscall::errno::Errno,
+    arch::{Arch, Architecture, crash::CrashReport, task::ArchContext},
+    cpu_local::CpuLocalBlock,
+    file::FileTable,
+    mem::paging::{KERNEL_STACK_BOTTOM, KERNEL_STACK_TOP, allocator::KernelFrameAllocator},
+    sync::{Rcu, SavedInterruptStatus, with_irqs_disabled},
+    syscall::errno::Errno,
+};
+
+use super::{
+    addr_space::AddrSpaceLock,
+    signal::Signals,
+    stack::Stack,
+    stats::{self, TaskStats},
+    switch::EMPTY_TABLE,
 };
 
-use super::{addr_space::AddrSpaceLock, stack::Stack, switch::EMPTY_TABLE};
+/// Every task, read locklessly by the scheduler and copied on spawn and exit.
+pub static CONTEXTS: Lazy<Rcu<BTreeSet<ContextRef>>> = Lazy::new(|| Rcu::new(BTreeSet::new()));
 
-pub static CONTEXTS: RwLock<BTreeSet<ContextRef>> = RwLock::new(BTreeSet::new());
+/// The maximum number of frames [`Context::backtrace`] will walk.
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
 
 /// Initializes the kernel context.
 ///
@@ -26,24 +45,83 @@ pub fn init() {
 
     cx.status = Status::Runnable;
     cx.running = true;
+    cx.name = String::from("kernel_main");
+    // it's the idle task once the kernel is up
+    cx.base_priority = Priority::IDLE;
+    cx.priority = Priority::IDLE;
+    cx.stats.switch_in(crate::time::uptime());
+    let cx_stats = cx.stats.clone();
     let cx_lock = Arc::new(RwSpinlock::new(cx));
-    CONTEXTS.write().insert(ContextRef(cx_lock.clone()));
+    CONTEXTS.update(|contexts| contexts.insert(ContextRef(cx_lock.clone())));
 
     let block = CpuLocalBlock::current().unwrap();
     block.switch_state.set_current_context(cx_lock.clone());
+    block.switch_state.set_current_stats(&cx_stats);
     block.switch_state.set_idle_context(cx_lock);
+    stats::enable_heap_accounting();
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     Runnable,
     Waiting,
-    Blocked { reason: BlockReason },
+    Blocked {
+        reason: BlockReason,
+    },
+    /// Exited, and waiting to be [reaped](super::reap).
     Dead,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum BlockReason {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// Parked on a [`WaitQueue`](crate::sync::WaitQueue) until something wakes it.
+    WaitQueue,
+}
+
+/// How urgently a task wants the CPU. The scheduler always runs the highest-priority
+/// runnable task, taking turns between tasks of the same priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Display)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    /// Only runs when nothing else wants to.
+    pub const IDLE: Self = Self(0);
+    pub const LOW: Self = Self(64);
+    pub const NORMAL: Self = Self(128);
+    pub const HIGH: Self = Self(192);
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::NORMAL
+    }
+}
+
+/// A set of CPUs a task may run on, one bit per CPU index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+    pub const ALL: Self = Self(u64::MAX);
+
+    /// Returns a mask containing only the given CPU.
+    #[must_use]
+    pub const fn single(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    /// Returns `true` if the mask contains the given CPU.
+    #[must_use]
+    pub const fn contains(self, cpu: usize) -> bool {
+        cpu < u64::BITS as usize && self.0 & (1 << cpu) != 0
+    }
+}
+
+impl Default for CpuMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Display)]
 pub struct Pid(usize);
@@ -53,6 +131,18 @@ impl Pid {
         static NEXT_PID: AtomicUsize = AtomicUsize::new(0);
         Self(NEXT_PID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// Creates a PID from its raw value, e.g. one typed into the shell.
+    #[must_use]
+    pub const fn from_raw(pid: usize) -> Self {
+        Self(pid)
+    }
+
+    /// Returns the PID's raw value.
+    #[must_use]
+    pub const fn as_raw(self) -> usize {
+        self.0
+    }
 }
 
 pub struct Context {
@@ -62,7 +152,30 @@ pub struct Context {
     pub kstack: Option<Stack>,
     pub addr_space: Option<Arc<AddrSpaceLock>>,
     pub userspace: bool,
+    /// Whether the task may make privileged system calls, like mapping the framebuffer.
+    pub privileged: bool,
     pub pid: Pid,
+    /// The task that collects its exit status, if it was spawned
+    /// [waitable](super::SpawnBuilder::waitable).
+    pub parent: Option<Pid>,
+    /// How the task ended, once it has.
+    pub exit_status: Option<ExitStatus>,
+    /// The report of the fault that killed the task, kept for whoever collects its exit status.
+    pub crash: Option<CrashReport>,
+    /// A name to show in debugging output; empty if the task wasn't given one.
+    pub name: String,
+    /// The priority the task is scheduled at: its own, or a higher one it's inherited.
+    pub priority: Priority,
+    /// The priority the task was given, before any it's inherited.
+    pub base_priority: Priority,
+    /// The priorities inherited from tasks waiting on locks it holds, by lock address.
+    inherited: Vec<(usize, Priority)>,
+    /// The CPUs the task may be scheduled on.
+    pub affinity: CpuMask,
+    pub stats: Arc<TaskStats>,
+    pub signals: Arc<Signals>,
+    /// The task's open files.
+    pub files: FileTable,
 }
 
 impl Context {
@@ -74,9 +187,95 @@ impl Context {
             kstack: None,
             addr_space: None,
             userspace: false,
+            privileged: false,
             pid: Pid::alloc(),
+            parent: None,
+            exit_status: None,
+            crash: None,
+            name: String::new(),
+            priority: Priority::default(),
+            base_priority: Priority::default(),
+            inherited: Vec::new(),
+            affinity: CpuMask::ALL,
+            stats: Arc::new(TaskStats::default()),
+            signals: Arc::new(Signals::default()),
+            files: FileTable::with_console(),
         })
     }
+
+    /// Has the task inherit `priority` from the tasks waiting on the lock at address `lock`,
+    /// running at it until it releases the lock if that's higher than its own. `None` takes
+    /// back whatever it inherited from the lock.
+    pub fn inherit_priority(&mut self, lock: usize, priority: Option<Priority>) {
+        self.inherited.retain(|&(held, _)| held != lock);
+        if let Some(priority) = priority {
+            self.inherited.push((lock, priority));
+        }
+        self.priority = self
+            .inherited
+            .iter()
+            .map(|&(_, priority)| priority)
+            .fold(self.base_priority, Ord::max);
+    }
+
+    /// Returns the registers saved when the task was last switched out.
+    ///
+    /// These are only meaningful while the task isn't running.
+    #[must_use]
+    pub fn saved_registers(&self) -> &ArchContext {
+        &self.arch
+    }
+
+    /// Returns the address range of the task's kernel stack.
+    ///
+    /// The boot context has no [`Stack`] of its own and runs on the kernel's main stack.
+    #[must_use]
+    pub fn kstack_bounds(&self) -> Range<usize> {
+        match &self.kstack {
+            Some(stack) => stack.bottom() as usize..stack.initial_top() as usize,
+            None => KERNEL_STACK_BOTTOM..KERNEL_STACK_TOP,
+        }
+    }
+
+    /// Walks the frame records on the kernel stack of a task that isn't running, returning
+    /// the return address of each frame, innermost first.
+    ///
+    /// The walk stops at the first frame record that falls outside the task's kernel stack,
+    /// so a corrupted chain can't send it off into unmapped memory.
+    #[must_use]
+    pub fn backtrace(&self) -> ArrayVec<usize, MAX_BACKTRACE_DEPTH> {
+        let mut frames = ArrayVec::new();
+        let bounds = self.kstack_bounds();
+
+        frames.push(self.arch.return_address());
+
+        let mut fp = self.arch.frame_pointer();
+        while !frames.is_full() {
+            let record = fp..fp.saturating_add(2 * size_of::<usize>());
+            if !fp.is_multiple_of(align_of::<usize>())
+                || !bounds.contains(&record.start)
+                || record.end > bounds.end
+            {
+                break;
+            }
+            // frame record: [fp] = caller's fp, [fp + 8] = return address
+            let (next_fp, pc) = unsafe {
+                let record = fp as *const usize;
+                (record.read(), record.add(1).read())
+            };
+            if pc == 0 {
+                break;
+            }
+            frames.push(Arch::strip_return_address(pc));
+            if next_fp <= fp {
+                // the stack grows down, so the caller's frame must be above ours
+                break;
+            }
+            fp = next_fp;
+        }
+
+        frames
+    }
 }
 
 #[derive(Deref, Clone)]
@@ -108,6 +307,59 @@ pub fn current() -> Option<Arc<RwSpinlock<Context>>> {
         .and_then(|block| block.switch_state.with_context(|cx| cx.map(Arc::clone)))
 }
 
+/// Runs `f` on the task with the given PID, with interrupts disabled so it can't be
+/// switched in (or out) while it's being inspected.
+///
+/// Returns [`Errno::ESRCH`] if there is no such task, or [`Errno::EBUSY`] if it is running
+/// or its context is locked.
+pub fn inspect<R>(pid: Pid, f: impl FnOnce(&Context) -> R) -> Result<R, Errno> {
+    let _saved = SavedInterruptStatus::save();
+    unsafe { Arch::disable_interrupts() };
+
+    let contexts = CONTEXTS.read();
+    let mut any_locked = false;
+    for cx in contexts.iter() {
+        let Some(cx) = cx.try_read() else {
+            any_locked = true;
+            continue;
+        };
+        if cx.pid != pid {
+            continue;
+        }
+        if cx.running {
+            return Err(Errno::EBUSY);
+        }
+        return Ok(f(&cx));
+    }
+    // a locked context might have been the one we were looking for
+    Err(if any_locked { Errno::EBUSY } else { Errno::ESRCH })
+}
+
+/// Returns the task with the given PID, if there is one.
+#[must_use]
+pub fn find(pid: Pid) -> Option<Arc<RwSpinlock<Context>>> {
+    let _saved = SavedInterruptStatus::save();
+    unsafe { Arch::disable_interrupts() };
+
+    CONTEXTS
+        .read()
+        .iter()
+        .find(|cx| cx.read().pid == pid)
+        .map(|cx| Arc::clone(cx))
+}
+
+/// Runs `f` on every task whose context isn't currently locked, with interrupts disabled.
+pub fn for_each(mut f: impl FnMut(&Context)) {
+    let _saved = SavedInterruptStatus::save();
+    unsafe { Arch::disable_interrupts() };
+
+    for cx in CONTEXTS.read().iter() {
+        if let Some(cx) = cx.try_read() {
+            f(&cx);
+        }
+    }
+}
+
 pub fn is_current(cx: &Arc<RwSpinlock<Context>>) -> bool {
     CpuLocalBlock::current().is_some_and(|block| {
         block
@@ -116,14 +368,33 @@ pub fn is_current(cx: &Arc<RwSpinlock<Context>>) -> bool {
     })
 }
 
-pub fn exit(cx: &Arc<RwSpinlock<Context>>) {
-    CONTEXTS.write().remove(&ContextRef(cx.clone()));
+/// Ends the current task, `cx`, with the given status. It's left a zombie until it's
+/// [reaped](super::reap), as it can't free the stack it's running on.
+pub fn exit(cx: &Arc<RwSpinlock<Context>>, status: ExitStatus) {
+    // closed now rather than with the context, which can be dropped partway through a switch,
+    // where closing a pipe couldn't sleep on its lock
+    let files = with_irqs_disabled(|| {
+        let mut cx = cx.write();
+        cx.exit_status = Some(status);
+        core::mem::take(&mut cx.files)
+    });
+    drop(files);
+    super::reap::add_zombie(cx.clone());
+    // the scheduler never picks it again from here, even if it's preempted before switching
+    with_irqs_disabled(|| cx.write().status = Status::Dead);
+    super::reap::notify_exit();
     super::switch::switch();
     unreachable!()
 }
 
+/// Ends the current task with exit code 0.
 pub fn exit_current() {
+    exit_current_with(ExitStatus::Exited(0));
+}
+
+/// Ends the current task with the given status.
+pub fn exit_current_with(status: ExitStatus) {
     if let Some(current) = current() {
-        exit(&current);
+        exit(&current, status);
     }
 }
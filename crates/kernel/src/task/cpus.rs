@@ -0,0 +1,222 @@
+//! Which CPUs run which tasks: task affinity, and parking cores.
+//!
+//! A parked core only runs its idle context, which waits for interrupts in the CPU's
+//! lowest-power state until the core is unparked. Unparking a core, or taking away a CPU a
+//! task is running on, interrupts the core with an IPI so it reschedules straight away. On
+//! interrupt controllers without IPIs, the core gets there at its next timer tick instead.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    arch::{Arch, Architecture},
+    irq::{self, Irq, IrqHandler, register_irq},
+    serial_println,
+    shell::{self, Args, Command},
+    sync::with_irqs_disabled,
+    syscall::errno::Errno,
+};
+
+use super::{
+    context::{self, CpuMask, Pid},
+    switch::{request_switch, switch},
+};
+
+/// The CPUs that have come up and run tasks.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+/// The online CPUs that are parked.
+static PARKED: AtomicU64 = AtomicU64::new(0);
+
+/// Notes that the calling CPU has come up and can run tasks.
+pub fn set_online() {
+    ONLINE.fetch_or(CpuMask::single(Arch::cpu_id()).0, Ordering::AcqRel);
+}
+
+/// Returns the CPUs that have come up and can run tasks, parked or not.
+#[must_use]
+pub fn online() -> CpuMask {
+    CpuMask(ONLINE.load(Ordering::Acquire))
+}
+
+/// Returns the CPUs that are parked.
+#[must_use]
+pub fn parked() -> CpuMask {
+    CpuMask(PARKED.load(Ordering::Acquire))
+}
+
+/// Returns `true` if the given CPU is parked.
+#[must_use]
+pub fn is_parked(cpu: usize) -> bool {
+    parked().contains(cpu)
+}
+
+/// Returns the CPUs the task with the given PID may run on.
+///
+/// # Errors
+///
+/// Returns [`Errno::ESRCH`] if there's no such task.
+pub fn affinity(pid: Pid) -> Result<CpuMask, Errno> {
+    let cx = context::find(pid).ok_or(Errno::ESRCH)?;
+    Ok(with_irqs_disabled(|| cx.read().affinity))
+}
+
+/// Sets the CPUs the task with the given PID may run on, moving it off the one it's running
+/// on if that's no longer one of them.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if `mask` has no online CPUs, or [`Errno::ESRCH`] if there's no
+/// such task.
+pub fn set_affinity(pid: Pid, mask: CpuMask) -> Result<(), Errno> {
+    if mask.0 & online().0 == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let cx = context::find(pid).ok_or(Errno::ESRCH)?;
+    // the scheduler takes the lock from the timer interrupt
+    with_irqs_disabled(|| cx.write().affinity = mask);
+
+    if context::is_current(&cx) {
+        if !mask.contains(Arch::cpu_id()) {
+            switch();
+        }
+    } else {
+        // it could be running on any of the CPUs it's just lost
+        kick(CpuMask(online().0 & !mask.0));
+    }
+    Ok(())
+}
+
+/// Parks the given CPU, so it runs no tasks until it's [unparked](unpark). Its tasks move to
+/// the other CPUs they may run on.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if the CPU isn't online, or [`Errno::EBUSY`] if it's the last
+/// one that isn't parked.
+pub fn park(cpu: usize) -> Result<(), Errno> {
+    if !online().contains(cpu) {
+        return Err(Errno::EINVAL);
+    }
+    let mut parked = PARKED.load(Ordering::Acquire);
+    loop {
+        let new = parked | CpuMask::single(cpu).0;
+        if new & online().0 == online().0 {
+            return Err(Errno::EBUSY);
+        }
+        match PARKED.compare_exchange_weak(parked, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(current) => parked = current,
+        }
+    }
+
+    if cpu == Arch::cpu_id() {
+        switch();
+    } else {
+        kick(CpuMask::single(cpu));
+    }
+    Ok(())
+}
+
+/// Lets a parked CPU run tasks again. Does nothing if it isn't parked.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if the CPU isn't online.
+pub fn unpark(cpu: usize) -> Result<(), Errno> {
+    if !online().contains(cpu) {
+        return Err(Errno::EINVAL);
+    }
+    PARKED.fetch_and(!CpuMask::single(cpu).0, Ordering::AcqRel);
+    kick(CpuMask::single(cpu));
+    Ok(())
+}
+
+/// Interrupts each of the given CPUs but this one, so they reschedule.
+fn kick(cpus: CpuMask) {
+    let this = Arch::cpu_id();
+    for cpu in (0..u64::BITS as usize).filter(|&cpu| cpus.contains(cpu) && cpu != this) {
+        // without IPIs, the next timer tick does it
+        irq::send_ipi(cpu).ok();
+    }
+}
+
+/// Reschedules the CPU it's sent to.
+struct RescheduleIpi;
+
+impl IrqHandler for RescheduleIpi {
+    fn handle_irq(&mut self, _irq: Irq) {
+        request_switch();
+    }
+}
+
+/// Takes IPIs, if the interrupt controller has them, and registers the `cpus`, `park`,
+/// `unpark` and `affinity` commands.
+pub fn init() {
+    if let Some(irq) = irq::ipi() {
+        unsafe { register_irq(irq, RescheduleIpi) };
+    }
+    for command in COMMANDS {
+        shell::register(*command);
+    }
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "cpus",
+        usage: "",
+        help: "list the online CPUs, and which are parked",
+        run: cmd_cpus,
+    },
+    Command {
+        name: "park",
+        usage: "<cpu>",
+        help: "stop running tasks on a CPU and let it sleep",
+        run: cmd_park,
+    },
+    Command {
+        name: "unpark",
+        usage: "<cpu>",
+        help: "run tasks on a parked CPU again",
+        run: cmd_unpark,
+    },
+    Command {
+        name: "affinity",
+        usage: "<pid> [<mask>]",
+        help: "show or set the CPUs a task may run on, as a hex mask",
+        run: cmd_affinity,
+    },
+];
+
+#[allow(clippy::unnecessary_wraps)] // must match `Command::run`
+fn cmd_cpus(_args: Args) -> Result<(), Errno> {
+    let this = Arch::cpu_id();
+    for cpu in (0..u64::BITS as usize).filter(|&cpu| online().contains(cpu)) {
+        let state = if is_parked(cpu) { "parked" } else { "running" };
+        let this = if cpu == this { " (this one)" } else { "" };
+        serial_println!("CPU{cpu}: {state}{this}");
+    }
+    Ok(())
+}
+
+fn parse_cpu(args: &mut Args) -> Result<usize, Errno> {
+    let cpu = args.next().ok_or(Errno::EINVAL)?;
+    cpu.parse().map_err(|_| Errno::EINVAL)
+}
+
+fn cmd_park(mut args: Args) -> Result<(), Errno> {
+    park(parse_cpu(&mut args)?)
+}
+
+fn cmd_unpark(mut args: Args) -> Result<(), Errno> {
+    unpark(parse_cpu(&mut args)?)
+}
+
+fn cmd_affinity(mut args: Args) -> Result<(), Errno> {
+    let pid = shell::parse_pid(&mut args)?;
+    if let Some(mask) = args.next() {
+        let mask = mask.strip_prefix("0x").unwrap_or(mask);
+        let mask = u64::from_str_radix(mask, 16).map_err(|_| Errno::EINVAL)?;
+        set_affinity(pid, CpuMask(mask))?;
+    }
+    serial_println!("{:#x}", affinity(pid)?.0);
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Running programs in userspace.
+//!
+//! [`SpawnBuilder::exec`] loads a static ELF executable into a new address space, gives it a
+//! stack, and starts a user task at its entry point. [`spawn_init`] does that for the `init`
+//! program in the initrd, at boot.
+//!
+//! Segments are copied in, so the executable needn't outlive the task. There's no argument
+//! vector or environment yet: a program starts with nothing but its stack pointer set.
+
+use alloc::sync::Arc;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    arch::{Arch, Architecture},
+    cmdline,
+    cpio::FileKind,
+    elf::Executable,
+    initrd,
+    mem::{units::VirtAddr, user::copy_to_addr_space},
+    syscall::errno::Errno,
+};
+
+use super::{
+    SpawnBuilder,
+    addr_space::{AddrSpace, AddrSpaceLock, Backing, Protection},
+    context::Context,
+};
+
+/// The size of a program's stack.
+pub const USER_STACK_SIZE: usize = 256 * 1024;
+/// Where a program's stack starts, growing down: the top of the user half.
+const USER_STACK_TOP: usize = VirtAddr::MAX_LOW.value();
+
+/// The initrd path `init` is run from unless the command line's `init=` says otherwise.
+const DEFAULT_INIT: &str = "init";
+
+impl SpawnBuilder {
+    /// Creates a user task running the static ELF executable `elf`, starting at its entry
+    /// point with a fresh stack.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Errno::ENOEXEC`] if `elf` isn't an `AArch64` executable that can be loaded,
+    /// or [`Errno::ENOMEM`] if there's no memory for it.
+    pub fn exec(self, elf: &[u8]) -> Result<Arc<RwSpinlock<Context>>, Errno> {
+        let elf = Executable::new(elf)?;
+        let addr_space = AddrSpaceLock::new_user()?;
+        let entry = {
+            let mut addr_space = addr_space.write();
+            let entry = load(&mut addr_space, &elf)?;
+            addr_space.map(
+                VirtAddr::new_canonical(USER_STACK_TOP - USER_STACK_SIZE),
+                USER_STACK_SIZE,
+                true,
+                Protection::READ | Protection::WRITE,
+                Backing::Anonymous,
+            )?;
+            entry
+        };
+        self.user(true)
+            .spawn_in(addr_space, enter_program, Some((entry, USER_STACK_TOP)))
+    }
+}
+
+/// Runs on a new program's kernel stack just before it drops to userspace, where everything
+/// it needs has already been put.
+extern "C" fn enter_program() {}
+
+/// Maps the segments of `elf` into `addr_space` and copies them in, returning the entry
+/// point.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENOEXEC`] if a segment is outside the user half or shares a page with
+/// another, or [`Errno::ENOMEM`] if there's no memory for one.
+pub fn load(addr_space: &mut AddrSpace, elf: &Executable) -> Result<usize, Errno> {
+    for segment in elf.segments() {
+        let segment = segment?;
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let start = segment.vaddr & !Arch::PAGE_OFFSET_MASK;
+        let end = segment
+            .vaddr
+            .checked_add(segment.mem_size)
+            .filter(|&end| start != 0 && end <= VirtAddr::MAX_LOW.value())
+            .ok_or(Errno::ENOEXEC)?;
+        let start = VirtAddr::new_canonical(start);
+        let end_page = VirtAddr::new_canonical(end).align_up(Arch::PAGE_SIZE);
+        if addr_space
+            .regions()
+            .any(|region| region.start < end_page && start < region.end())
+        {
+            return Err(Errno::ENOEXEC);
+        }
+
+        let mut prot = Protection::empty();
+        prot.set(Protection::READ, segment.readable);
+        prot.set(Protection::WRITE, segment.writable);
+        prot.set(Protection::EXEC, segment.executable);
+        addr_space.map(
+            start,
+            end_page.value() - start.value(),
+            true,
+            prot,
+            Backing::Anonymous,
+        )?;
+        // the pages are zeroed, which leaves the rest of the segment as it should be
+        copy_to_addr_space(addr_space, segment.vaddr, segment.data)?;
+    }
+    Ok(elf.entry())
+}
+
+/// Runs the `init` program from the initrd, or whichever path the command line's `init=`
+/// names, as a privileged task.
+///
+/// # Errors
+///
+/// Returns [`Errno::ENOENT`] if there's no initrd or no such file in it, [`Errno::EISDIR`]
+/// if it's a directory, or any error from [`SpawnBuilder::exec`].
+pub fn spawn_init() -> Result<Arc<RwSpinlock<Context>>, Errno> {
+    let path = cmdline::get("init").unwrap_or(DEFAULT_INIT);
+    let entry = initrd::archive()?.lookup(path)?;
+    match entry.kind() {
+        FileKind::File => {}
+        FileKind::Dir => return Err(Errno::EISDIR),
+        _ => return Err(Errno::ENOEXEC),
+    }
+    log::info!("running {} ({} bytes)", entry.path, entry.data.len());
+    SpawnBuilder::new()
+        .name(entry.name())
+        .privileged(true)
+        .exec(entry.data)
+}
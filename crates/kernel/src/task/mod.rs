@@ -1,36 +1,144 @@
 use addr_space::AddrSpaceLock;
-use alloc::sync::Arc;
-use context::{CONTEXTS, Context, ContextRef};
+use alloc::{string::String, sync::Arc};
+use context::{CONTEXTS, Context, ContextRef, CpuMask, Priority};
 use spinning_top::RwSpinlock;
-use stack::Stack;
+use stack::{DEFAULT_STACK_SIZE, Stack};
 
-use crate::syscall::errno::Errno;
+use crate::{arch::task::ArchContext, sync::with_irqs_disabled, syscall::errno::Errno};
 
 pub mod addr_space;
 pub mod context;
+pub mod cpus;
+pub mod exec;
+pub mod reap;
+pub mod signal;
 pub mod stack;
+pub mod stats;
 pub mod switch;
+pub mod workqueue;
 
+/// Spawns an unnamed task with the default options. See [`SpawnBuilder`] for more control.
 pub fn spawn(user: bool, entry_func: extern "C" fn()) -> Result<Arc<RwSpinlock<Context>>, Errno> {
-    let stack = Stack::new()?;
+    SpawnBuilder::new().user(user).spawn(entry_func)
+}
+
+/// A builder for a new task.
+#[derive(Debug, Clone)]
+#[must_use = "call `spawn()` to start the task"]
+pub struct SpawnBuilder {
+    name: String,
+    priority: Priority,
+    stack_size: usize,
+    affinity: CpuMask,
+    user: bool,
+    privileged: bool,
+    waitable: bool,
+}
+
+impl SpawnBuilder {
+    /// Creates a new builder for a kernel task with the default options.
+    pub fn new() -> Self {
+        Self {
+            name: String::new(),
+            priority: Priority::default(),
+            stack_size: DEFAULT_STACK_SIZE,
+            affinity: CpuMask::ALL,
+            user: false,
+            privileged: false,
+            waitable: false,
+        }
+    }
+
+    /// Sets the name shown for the task in debugging output.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Sets the task's scheduling priority.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the size of the task's kernel stack in bytes, rounded up to whole pages.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Sets the CPUs the task may run on.
+    pub fn affinity(mut self, affinity: CpuMask) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    /// Sets whether the task runs in userspace.
+    pub fn user(mut self, user: bool) -> Self {
+        self.user = user;
+        self
+    }
 
-    let cx_lock = Arc::new(RwSpinlock::new(Context::new()?));
+    /// Sets whether the task may make privileged system calls, like mapping the framebuffer.
+    pub fn privileged(mut self, privileged: bool) -> Self {
+        self.privileged = privileged;
+        self
+    }
 
-    CONTEXTS.write().insert(ContextRef(cx_lock.clone()));
+    /// Sets whether the spawning task collects the task's exit status with [`reap::wait`]
+    /// once it exits. If not, it's freed as soon as it has.
+    pub fn waitable(mut self, waitable: bool) -> Self {
+        self.waitable = waitable;
+        self
+    }
 
-    {
-        let mut cx = cx_lock.write();
-        let addr_space = if user {
+    /// Creates the task, which starts running `entry_func` when it's first scheduled.
+    pub fn spawn(self, entry_func: extern "C" fn()) -> Result<Arc<RwSpinlock<Context>>, Errno> {
+        let addr_space = if self.user {
             AddrSpaceLock::new_user()?
         } else {
             AddrSpaceLock::current_kernel()?
         };
-        let _ = cx.addr_space.replace(addr_space);
-        cx.arch.setup_initial_call(&stack, entry_func, user);
+        self.spawn_in(addr_space, entry_func, None)
+    }
+
+    /// Creates the task in `addr_space`. A user task goes on to run in userspace once
+    /// `entry_func` returns, at the program counter and stack pointer in `user_entry`.
+    fn spawn_in(
+        self,
+        addr_space: Arc<AddrSpaceLock>,
+        entry_func: extern "C" fn(),
+        user_entry: Option<(usize, usize)>,
+    ) -> Result<Arc<RwSpinlock<Context>>, Errno> {
+        let parent = if self.waitable {
+            let current = context::current().ok_or(Errno::ESRCH)?;
+            Some(with_irqs_disabled(|| current.read().pid))
+        } else {
+            None
+        };
+        let stack = Stack::with_size(self.stack_size)?;
 
+        let mut cx = Context::new()?;
+        cx.addr_space = Some(addr_space);
+        cx.arch.setup_initial_call(&stack, entry_func, self.user);
+        if self.user
+            && let Some((pc, sp)) = user_entry
+        {
+            ArchContext::set_user_entry(&stack, pc, sp);
+        }
         cx.kstack = Some(stack);
-        cx.userspace = user;
-    }
+        cx.userspace = self.user;
+        cx.privileged = self.privileged;
+        cx.name = self.name;
+        cx.priority = self.priority;
+        cx.base_priority = self.priority;
+        cx.affinity = self.affinity;
+        cx.parent = parent;
+
+        // only visible to the scheduler once it's ready to run
+        let cx_lock = Arc::new(RwSpinlock::new(cx));
+        CONTEXTS.update(|contexts| contexts.insert(ContextRef(cx_lock.clone())));
 
-    Ok(cx_lock)
+        Ok(cx_lock)
+    }
 }
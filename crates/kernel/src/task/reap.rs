@@ -0,0 +1,157 @@
+//! Freeing tasks that have exited.
+//!
+//! A task can't free its own kernel stack or address space while it's still running on
+//! them, so [`context::exit`] leaves it a zombie: [dead](Status::Dead), with everything but
+//! its files, until it's been switched out for good. If it was spawned
+//! [waitable](super::SpawnBuilder::waitable), it stays that way until the task that spawned
+//! it collects its exit status with [`wait`]. Otherwise, or once nothing is left to wait for
+//! it, the reaper task frees it when there's nothing better to do.
+
+use alloc::{sync::Arc, vec::Vec};
+use kados_abi::syscall::ExitStatus;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    sync::{IrqMutex, WaitQueue, with_irqs_disabled},
+    syscall::errno::Errno,
+};
+
+use super::{
+    SpawnBuilder,
+    context::{self, CONTEXTS, Context, ContextRef, Pid, Priority, Status},
+    switch::switch,
+};
+
+/// Tasks that have exited, or are exiting, and haven't been freed yet.
+static ZOMBIES: IrqMutex<Vec<ContextRef>> = IrqMutex::new(Vec::new());
+/// Woken whenever a task exits.
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Adds the current task, which is exiting, to the zombies. It's only one once it's dead.
+pub(super) fn add_zombie(cx: Arc<RwSpinlock<Context>>) {
+    ZOMBIES.lock().push(ContextRef(cx));
+}
+
+/// Wakes anything waiting for a task to exit, once the current task is dead.
+pub(super) fn notify_exit() {
+    EXITED.wake_all();
+}
+
+fn is_zombie(cx: &Context) -> bool {
+    cx.status == Status::Dead
+}
+
+/// Takes the first zombie `f` picks that has been switched out for good, if there's one.
+fn take_zombie(mut f: impl FnMut(&Context) -> bool) -> Option<ContextRef> {
+    let mut zombies = ZOMBIES.lock();
+    let index = zombies.iter().position(|cx| {
+        let cx = cx.read();
+        // it stops running once it's on another task's stack
+        is_zombie(&cx) && !cx.running && f(&cx)
+    })?;
+    Some(zombies.swap_remove(index))
+}
+
+/// Returns `true` if any task that's still alive is one `f` picks.
+fn any_live(mut f: impl FnMut(&Context) -> bool) -> bool {
+    with_irqs_disabled(|| {
+        CONTEXTS.read().iter().any(|cx| {
+            let cx = cx.read();
+            !is_zombie(&cx) && f(&cx)
+        })
+    })
+}
+
+/// Returns `true` if any zombie is one `f` picks, whether or not it's been switched out.
+fn any_zombie(mut f: impl FnMut(&Context) -> bool) -> bool {
+    ZOMBIES.lock().iter().any(|cx| {
+        let cx = cx.read();
+        is_zombie(&cx) && f(&cx)
+    })
+}
+
+/// Frees a zombie, returning its PID and how it ended.
+fn free(zombie: ContextRef) -> (Pid, ExitStatus) {
+    CONTEXTS.update(|contexts| contexts.remove(&zombie));
+    let (pid, status, stack, addr_space) = with_irqs_disabled(|| {
+        let mut cx = zombie.write();
+        let status = cx.exit_status.unwrap_or(ExitStatus::Exited(0));
+        (cx.pid, status, cx.kstack.take(), cx.addr_space.take())
+    });
+    drop(stack);
+    drop(addr_space);
+    // anything else still holding the context only keeps what's left of it
+    drop(zombie);
+    (pid, status)
+}
+
+/// Waits for a child of the current task to exit, or for the child with PID `pid` if one's
+/// given, then frees it, returning its PID and how it ended. With `nohang`, returns `None`
+/// instead of waiting if none has exited yet.
+///
+/// A task's children are the tasks it spawned [waitable](super::SpawnBuilder::waitable).
+///
+/// # Errors
+///
+/// Returns [`Errno::ECHILD`] if the current task has no such child, or [`Errno::ESRCH`] if
+/// there's no current task.
+pub fn wait(pid: Option<Pid>, nohang: bool) -> Result<Option<(Pid, ExitStatus)>, Errno> {
+    let current = context::current().ok_or(Errno::ESRCH)?;
+    let parent = with_irqs_disabled(|| current.read().pid);
+    drop(current);
+    let is_child = |cx: &Context| cx.parent == Some(parent) && pid.is_none_or(|pid| cx.pid == pid);
+
+    loop {
+        if let Some(zombie) = take_zombie(is_child) {
+            return Ok(Some(free(zombie)));
+        }
+        // a task joins the zombies before it dies, so one dying now is seen by one of these
+        if any_live(is_child) {
+            if nohang {
+                return Ok(None);
+            }
+            EXITED.wait_until(|| any_zombie(is_child) || !any_live(is_child));
+        } else if any_zombie(is_child) {
+            // it's still being switched out
+            switch();
+        } else {
+            return Err(Errno::ECHILD);
+        }
+    }
+}
+
+/// Returns `true` if nothing will wait for the task: it wasn't spawned waitable, or the
+/// task that spawned it has exited.
+fn is_orphan(cx: &Context) -> bool {
+    let Some(parent) = cx.parent else {
+        return true;
+    };
+    context::find(parent).is_none_or(|parent| with_irqs_disabled(|| is_zombie(&parent.read())))
+}
+
+extern "C" fn reaper_main() {
+    loop {
+        EXITED.wait_until(|| any_zombie(is_orphan));
+        while let Some(zombie) = take_zombie(is_orphan) {
+            free(zombie);
+        }
+        if any_zombie(is_orphan) {
+            // one's still being switched out
+            switch();
+        }
+    }
+}
+
+/// Starts the reaper task.
+///
+/// # Errors
+///
+/// Returns an error if the task can't be spawned.
+pub fn init() -> Result<(), Errno> {
+    SpawnBuilder::new()
+        .name("reaper")
+        // just above the idle task, so it only runs when nothing else wants to
+        .priority(Priority(Priority::IDLE.0 + 1))
+        .spawn(reaper_main)?;
+    Ok(())
+}
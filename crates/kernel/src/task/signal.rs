@@ -0,0 +1,215 @@
+//! Signals: asynchronous notifications sent to user tasks.
+//!
+//! [`send`] only marks a signal pending on its target. The target acts on it the next time it
+//! returns to userspace, so a task blocked in a system call doesn't see the signal until the
+//! call finishes. By default a signal terminates the task, but a task can ignore a signal or
+//! have a handler called for it instead, except for [`SIGKILL`]. A signal isn't delivered again
+//! while its handler is running; it stays pending until the handler returns with
+//! [`Sysno::Sigreturn`](kados_abi::syscall::Sysno::Sigreturn).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kados_abi::{
+    signal::{NSIG, SIG_DFL, SIG_IGN, SIGKILL, SIGSEGV},
+    syscall::ExitStatus,
+};
+
+use crate::{
+    arch::{
+        signal::{enter_handler, return_from_handler},
+        vectors::InterruptFrame,
+    },
+    sync::IrqMutex,
+    syscall::errno::Errno,
+};
+
+use super::context::{self, Pid};
+
+/// What a task does when a signal is delivered to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Terminate the task.
+    #[default]
+    Default,
+    /// Discard the signal.
+    Ignore,
+    /// Call `handler` with the signal number, which returns to `restorer`.
+    Handler { handler: usize, restorer: usize },
+}
+
+impl Action {
+    fn from_raw(handler: usize, restorer: usize) -> Self {
+        match handler {
+            SIG_DFL => Self::Default,
+            SIG_IGN => Self::Ignore,
+            handler => Self::Handler { handler, restorer },
+        }
+    }
+
+    fn into_raw(self) -> usize {
+        match self {
+            Self::Default => SIG_DFL,
+            Self::Ignore => SIG_IGN,
+            Self::Handler { handler, .. } => handler,
+        }
+    }
+}
+
+/// A task's signal state, shared so signals can be sent to it while it's running.
+pub struct Signals {
+    /// The signals sent to the task that it hasn't acted on yet, one bit per signal number.
+    pending: AtomicU64,
+    /// The signals whose handlers are running.
+    blocked: AtomicU64,
+    actions: IrqMutex<[Action; NSIG]>,
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            pending: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            actions: IrqMutex::new([Action::Default; NSIG]),
+        }
+    }
+}
+
+const fn bit(sig: usize) -> u64 {
+    1 << sig
+}
+
+impl Signals {
+    /// Returns the mask of signals waiting to be acted on.
+    #[must_use]
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Takes the lowest-numbered pending signal that isn't blocked.
+    ///
+    /// Only the task itself takes signals, so nothing else can clear one in between.
+    fn take(&self) -> Option<usize> {
+        let blocked = self.blocked.load(Ordering::Relaxed) & !bit(SIGKILL);
+        let ready = self.pending() & !blocked;
+        if ready == 0 {
+            return None;
+        }
+        let sig = ready.trailing_zeros() as usize;
+        self.pending.fetch_and(!bit(sig), Ordering::AcqRel);
+        Some(sig)
+    }
+
+    fn action(&self, sig: usize) -> Action {
+        if sig == SIGKILL {
+            Action::Default
+        } else {
+            self.actions.lock()[sig]
+        }
+    }
+}
+
+fn check(sig: usize) -> Result<(), Errno> {
+    if sig == 0 || sig >= NSIG {
+        Err(Errno::EINVAL)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends signal `sig` to task `pid`. With `sig` 0, only checks that the task exists.
+///
+/// # Errors
+///
+/// Returns [`Errno::ESRCH`] if there's no such task, [`Errno::EPERM`] if it's a kernel task
+/// (which never returns to userspace to act on a signal), and [`Errno::EINVAL`] if `sig` isn't
+/// a signal number.
+pub fn send(pid: Pid, sig: usize) -> Result<(), Errno> {
+    if sig != 0 {
+        check(sig)?;
+    }
+    let cx = context::find(pid).ok_or(Errno::ESRCH)?;
+    let cx = cx.read();
+    if !cx.userspace {
+        return Err(Errno::EPERM);
+    }
+    if sig != 0 {
+        cx.signals.pending.fetch_or(bit(sig), Ordering::AcqRel);
+    }
+    Ok(())
+}
+
+/// Sets what the current task does with signal `sig`, returning the raw handler it replaced.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if `sig` isn't a signal number or is [`SIGKILL`], and
+/// [`Errno::ESRCH`] if there's no current task.
+pub fn sigaction(sig: usize, handler: usize, restorer: usize) -> Result<usize, Errno> {
+    check(sig)?;
+    if sig == SIGKILL {
+        return Err(Errno::EINVAL);
+    }
+    let cx = context::current().ok_or(Errno::ESRCH)?;
+    let signals = cx.read().signals.clone();
+    let old = core::mem::replace(
+        &mut signals.actions.lock()[sig],
+        Action::from_raw(handler, restorer),
+    );
+    Ok(old.into_raw())
+}
+
+/// Acts on the current task's pending signals, as it's about to return to userspace with
+/// `frame`. At most one handler is entered; any other signals are acted on once it returns.
+pub fn deliver(frame: &mut InterruptFrame) {
+    let Some(signals) = context::current().map(|cx| cx.read().signals.clone()) else {
+        return;
+    };
+    if signals.pending() == 0 {
+        return;
+    }
+
+    let fatal = loop {
+        let Some(sig) = signals.take() else {
+            return;
+        };
+        match signals.action(sig) {
+            Action::Default => break sig,
+            Action::Ignore => {}
+            Action::Handler { handler, restorer } => {
+                let blocked = signals.blocked.fetch_or(bit(sig), Ordering::Relaxed);
+                if let Err(e) = enter_handler(frame, sig, handler, restorer, blocked) {
+                    log::warn!("can't enter the handler for signal {sig}: {e:?}");
+                    break SIGSEGV;
+                }
+                return;
+            }
+        }
+    };
+    drop(signals);
+    terminate(fatal);
+}
+
+/// Returns from the current task's signal handler, restoring the registers and blocked
+/// signals saved in its user stack. A task that corrupted them is terminated.
+pub fn sigreturn(frame: &mut InterruptFrame) {
+    let Some(signals) = context::current().map(|cx| cx.read().signals.clone()) else {
+        return;
+    };
+    match return_from_handler(frame) {
+        Ok(blocked) => signals.blocked.store(blocked, Ordering::Relaxed),
+        Err(e) => {
+            log::warn!("can't restore the registers saved by a signal handler: {e:?}");
+            drop(signals);
+            terminate(SIGSEGV);
+        }
+    }
+}
+
+/// Terminates the current task as if by the default action of signal `sig`, whatever it had
+/// set to handle it. Doesn't return if there's a current task.
+pub fn terminate(sig: usize) {
+    if let Some(pid) = context::current().map(|cx| cx.read().pid) {
+        log::info!("pid {pid}: terminated by signal {sig}");
+    }
+    context::exit_current_with(ExitStatus::Signaled(sig));
+}
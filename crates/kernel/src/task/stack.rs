@@ -7,18 +7,43 @@ use crate::{
     syscall::errno::Errno,
 };
 
+/// What new stacks are filled with, so [`Stack::high_water_mark`] can tell how deep they've
+/// been used.
+const STACK_PAINT: u64 = 0x57ac_57ac_57ac_57ac;
+
+/// The size of a kernel stack unless a task asks for more.
+pub const DEFAULT_STACK_SIZE: usize = Arch::PAGE_SIZE * 16;
+
 pub struct Stack {
     base: PhysAddr,
+    frames: FrameCount,
 }
 
 impl Stack {
     pub fn new() -> Result<Self, Errno> {
+        Self::with_size(DEFAULT_STACK_SIZE)
+    }
+
+    /// Allocates a stack of at least `size` bytes, rounded up to whole pages.
+    pub fn with_size(size: usize) -> Result<Self, Errno> {
+        let frames = FrameCount::from_bytes(size.max(1));
         let base = unsafe {
             KernelFrameAllocator
-                .allocate(FrameCount::new(16))
+                .allocate(frames)
                 .map_err(|_| Errno::ENOMEM)?
         };
-        Ok(Self { base })
+        let stack = Self { base, frames };
+        let words = stack.words();
+        for i in 0..stack.len() / size_of::<u64>() {
+            unsafe { words.add(i).write(STACK_PAINT) };
+        }
+        Ok(stack)
+    }
+
+    /// Returns the lowest address of the stack.
+    #[must_use]
+    pub fn bottom(&self) -> *mut u8 {
+        self.base.as_hhdm_virt().as_raw_ptr_mut::<u8>()
     }
 
     #[must_use]
@@ -31,16 +56,33 @@ impl Stack {
         }
     }
 
+    fn words(&self) -> *mut u64 {
+        self.base.as_hhdm_virt().as_raw_ptr_mut::<u64>()
+    }
+
+    /// Returns the most of the stack that has ever been used, in bytes.
+    ///
+    /// This counts up from the bottom to the first word that isn't paint, so it can
+    /// underestimate if a frame left a gap untouched.
+    #[must_use]
+    pub fn high_water_mark(&self) -> usize {
+        let words = self.words();
+        let untouched = (0..self.len() / size_of::<u64>())
+            .take_while(|&i| unsafe { words.add(i).read_volatile() } == STACK_PAINT)
+            .count();
+        self.len() - untouched * size_of::<u64>()
+    }
+
     #[allow(clippy::len_without_is_empty)]
     #[must_use]
     pub const fn len(&self) -> usize {
-        Arch::PAGE_SIZE * 16
+        self.frames.to_bytes()
     }
 }
 
 impl Drop for Stack {
     fn drop(&mut self) {
-        if let Err(e) = KernelFrameAllocator.free(self.base, FrameCount::new(16)) {
+        if let Err(e) = KernelFrameAllocator.free(self.base, self.frames) {
             log::error!("Stack::drop(): {e}");
         }
     }
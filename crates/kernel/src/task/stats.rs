@@ -0,0 +1,179 @@
+//! Per-task accounting, and a way to list tasks along with it.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{cpu_local::CpuLocalBlock, syscall::errno::Errno, time};
+
+use super::context::{self, Context, Pid, Priority, Status};
+
+/// Set once the CPU-local block exists, so the heap can find the current task's stats.
+static HEAP_ACCOUNTING: AtomicBool = AtomicBool::new(false);
+
+/// Counters kept for each task.
+///
+/// They're atomic so the heap can update the current task's without locking its context.
+#[derive(Debug, Default)]
+pub struct TaskStats {
+    cpu_time_ns: AtomicU64,
+    /// When the task last started running, or 0 if it isn't.
+    switched_in_ns: AtomicU64,
+    switches: AtomicU64,
+    faults: AtomicU64,
+    heap_allocated: AtomicUsize,
+    heap_freed: AtomicUsize,
+}
+
+impl TaskStats {
+    /// Records the task being switched in at `now`.
+    pub(super) fn switch_in(&self, now: Duration) {
+        self.switches.fetch_add(1, Ordering::Relaxed);
+        self.switched_in_ns
+            .store(now.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Records the task being switched out at `now`, adding the time since it was switched
+    /// in to its CPU time.
+    pub(super) fn switch_out(&self, now: Duration) {
+        let switched_in = self.switched_in_ns.swap(0, Ordering::Relaxed);
+        if switched_in != 0 {
+            let ran = (now.as_nanos() as u64).saturating_sub(switched_in);
+            self.cpu_time_ns.fetch_add(ran, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a fault taken by the task.
+    pub fn record_fault(&self) {
+        self.faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the CPU time the task has used, including its current time slice if it's
+    /// running.
+    #[must_use]
+    pub fn cpu_time(&self) -> Duration {
+        let mut ns = self.cpu_time_ns.load(Ordering::Relaxed);
+        let switched_in = self.switched_in_ns.load(Ordering::Relaxed);
+        if switched_in != 0 {
+            ns += (time::uptime().as_nanos() as u64).saturating_sub(switched_in);
+        }
+        Duration::from_nanos(ns)
+    }
+
+    /// Returns how many times the task has been switched in.
+    #[must_use]
+    pub fn switches(&self) -> u64 {
+        self.switches.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many faults the task has taken.
+    #[must_use]
+    pub fn faults(&self) -> u64 {
+        self.faults.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes of heap the task has allocated and freed.
+    ///
+    /// Memory goes to whichever task was running when it was allocated or freed, so a task
+    /// freeing another's allocations can free more than it allocated.
+    #[must_use]
+    pub fn heap_usage(&self) -> (usize, usize) {
+        (
+            self.heap_allocated.load(Ordering::Relaxed),
+            self.heap_freed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Starts charging heap usage to the running task.
+pub(super) fn enable_heap_accounting() {
+    HEAP_ACCOUNTING.store(true, Ordering::Release);
+}
+
+fn with_current(f: impl FnOnce(&TaskStats)) {
+    if !HEAP_ACCOUNTING.load(Ordering::Acquire) {
+        return;
+    }
+    if let Some(stats) =
+        CpuLocalBlock::current().and_then(|block| block.switch_state.current_stats())
+    {
+        f(stats);
+    }
+}
+
+/// Charges a heap allocation of `size` bytes to the running task.
+pub fn account_alloc(size: usize) {
+    with_current(|stats| {
+        stats.heap_allocated.fetch_add(size, Ordering::Relaxed);
+    });
+}
+
+/// Credits a heap deallocation of `size` bytes to the running task.
+pub fn account_free(size: usize) {
+    with_current(|stats| {
+        stats.heap_freed.fetch_add(size, Ordering::Relaxed);
+    });
+}
+
+/// A snapshot of a task's state and accounting.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub pid: Pid,
+    pub name: String,
+    pub priority: Priority,
+    pub status: Status,
+    pub running: bool,
+    pub userspace: bool,
+    pub cpu_time: Duration,
+    pub switches: u64,
+    pub faults: u64,
+    pub heap_allocated: usize,
+    pub heap_freed: usize,
+    /// The most of its kernel stack the task has used, in bytes, if it has a stack of its own.
+    pub stack_high_water: Option<usize>,
+    /// The size of the task's kernel stack, in bytes.
+    pub stack_size: usize,
+}
+
+impl TaskInfo {
+    fn new(cx: &Context) -> Self {
+        let (heap_allocated, heap_freed) = cx.stats.heap_usage();
+        let stack = cx.kstack_bounds();
+        Self {
+            pid: cx.pid,
+            name: cx.name.clone(),
+            priority: cx.priority,
+            status: cx.status,
+            running: cx.running,
+            userspace: cx.userspace,
+            cpu_time: cx.stats.cpu_time(),
+            switches: cx.stats.switches(),
+            faults: cx.stats.faults(),
+            heap_allocated,
+            heap_freed,
+            stack_high_water: cx.kstack.as_ref().map(super::stack::Stack::high_water_mark),
+            stack_size: stack.end - stack.start,
+        }
+    }
+}
+
+/// Returns a snapshot of every task whose context isn't locked, sorted by PID.
+#[must_use]
+pub fn tasks() -> Vec<TaskInfo> {
+    let mut tasks = Vec::new();
+    context::for_each(|cx| tasks.push(TaskInfo::new(cx)));
+    tasks.sort_unstable_by_key(|task| task.pid);
+    tasks
+}
+
+/// Returns a snapshot of the task with the given PID.
+///
+/// # Errors
+///
+/// See [`context::inspect`].
+pub fn task(pid: Pid) -> Result<TaskInfo, Errno> {
+    context::inspect(pid, TaskInfo::new)
+}
@@ -1,27 +1,41 @@
 use core::{
     cell::{Cell, RefCell},
     ops::{Bound, Deref},
-    sync::atomic::{AtomicBool, Ordering},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
 };
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use spin::Once;
 use spinning_top::{RwSpinlock, guard::ArcRwSpinlockWriteGuard};
 
 use crate::{
     arch::{Arch, Architecture, task::switch_to},
     cpu_local::CpuLocalBlock,
-    mem::units::PhysAddr,
+    mem::{paging::asid, units::PhysAddr},
+    sync::IrqMutex,
     task::context::Status,
+    time,
+    trace::trace_ctx_switch,
     util::DebugCheckedPanic,
 };
 
-use super::context::{CONTEXTS, Context, ContextRef, current};
+use super::{
+    context::{CONTEXTS, Context, ContextRef, current},
+    cpus,
+    stats::TaskStats,
+};
 
 pub static SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
+/// Set by interrupt handlers that want to switch tasks once they're done.
+static SWITCH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
 pub static EMPTY_TABLE: Once<PhysAddr> = Once::new();
 
+/// Wakeups that found their task's context locked, left for the next switch to apply.
+static DEFERRED_WAKEUPS: IrqMutex<Vec<Arc<RwSpinlock<Context>>>> = IrqMutex::new(Vec::new());
+
 #[inline]
 #[must_use]
 pub fn empty_table() -> PhysAddr {
@@ -45,9 +59,22 @@ pub struct CpuLocalSwitchState {
     result: Cell<Option<SwitchResultGuard>>,
     current_context: RefCell<Option<Arc<RwSpinlock<Context>>>>,
     idle_context: RefCell<Option<Arc<RwSpinlock<Context>>>>,
+    /// The current context's stats, reachable without locking it. The context outlives
+    /// this pointer, since it's replaced before the context can be dropped.
+    current_stats: AtomicPtr<TaskStats>,
 }
 
 impl CpuLocalSwitchState {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            result: Cell::new(None),
+            current_context: RefCell::new(None),
+            idle_context: RefCell::new(None),
+            current_stats: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
     pub fn with_context<R>(&self, f: impl FnOnce(Option<&Arc<RwSpinlock<Context>>>) -> R) -> R {
         f(self.current_context.borrow().as_ref())
     }
@@ -56,6 +83,17 @@ impl CpuLocalSwitchState {
         *self.current_context.borrow_mut() = Some(new_cx);
     }
 
+    pub fn set_current_stats(&self, stats: &Arc<TaskStats>) {
+        self.current_stats
+            .store(Arc::as_ptr(stats).cast_mut(), Ordering::Release);
+    }
+
+    /// Returns the current context's stats, if there is a current context.
+    #[must_use]
+    pub fn current_stats(&self) -> Option<&TaskStats> {
+        unsafe { self.current_stats.load(Ordering::Acquire).as_ref() }
+    }
+
     pub fn set_idle_context(&self, new_cx: Arc<RwSpinlock<Context>>) {
         *self.idle_context.borrow_mut() = Some(new_cx);
     }
@@ -104,17 +142,58 @@ pub unsafe extern "C" fn switch_arch_hook(block: &'static CpuLocalBlock) {
 
     drop(current_addr_space);
 
-    *block.current_addr_space.borrow_mut() = next_addr_space;
-    if let Some(next) = &*block.current_addr_space.borrow() {
-        let next = next.read();
-        unsafe {
-            next.table.make_current();
-            Arch::invalidate_all();
-        }
+    // the previous address space may go with its last reference, so it stays current until
+    // the next one is
+    let prev = block.current_addr_space.replace(next_addr_space);
+    match &*block.current_addr_space.borrow() {
+        Some(next) => unsafe { next.read().activate() },
+        None => unsafe { Arch::switch_user_page_table(asid::empty_table(), 0) },
     }
+    drop(prev);
+}
+
+/// Asks for a switch to the next runnable task once the running interrupt handler is done.
+pub fn request_switch() {
+    SWITCH_REQUESTED.store(true, Ordering::Release);
 }
 
-/// Switches to the next runnable task.
+/// Switches tasks if [`request_switch`] was called since the last time.
+///
+/// Must not be called with any locks an interrupt handler might take held.
+pub fn switch_if_requested() -> Option<SwitchResult> {
+    SWITCH_REQUESTED.swap(false, Ordering::AcqRel).then(switch)
+}
+
+/// Makes a blocked task runnable again.
+///
+/// Safe to call from interrupt handlers. If the task's context is locked, say because it's
+/// partway through switching out, the wakeup is deferred to the next switch.
+pub fn unblock(cx: &Arc<RwSpinlock<Context>>) {
+    if !try_unblock(cx) {
+        DEFERRED_WAKEUPS.lock().push(cx.clone());
+        request_switch();
+    }
+}
+
+fn try_unblock(cx: &Arc<RwSpinlock<Context>>) -> bool {
+    let Some(mut cx) = cx.try_write() else {
+        return false;
+    };
+    if matches!(cx.status, Status::Blocked { .. }) {
+        cx.status = Status::Runnable;
+    }
+    true
+}
+
+/// Returns `true` if the scheduler may switch to `cx` on the given CPU.
+fn is_eligible(cx: &Context, cpu: usize) -> bool {
+    !cx.running
+        && matches!(cx.status, Status::Runnable | Status::Waiting)
+        && cx.affinity.contains(cpu)
+}
+
+/// Switches to the highest-priority runnable task, taking turns between tasks of equal
+/// priority.
 ///
 /// # Panics
 ///
@@ -129,6 +208,8 @@ pub fn switch() -> SwitchResult {
         core::hint::spin_loop();
     }
 
+    DEFERRED_WAKEUPS.lock().retain(|cx| !try_unblock(cx));
+
     let mut switch_state_opt = None;
     {
         let contexts = CONTEXTS.read();
@@ -141,6 +222,10 @@ pub fn switch() -> SwitchResult {
 
         let idle = block.switch_state.idle_context();
 
+        let cpu = Arch::cpu_id();
+        // a parked core only runs its idle context
+        let parked = cpus::is_parked(cpu);
+        let mut best: Option<ArcRwSpinlockWriteGuard<Context>> = None;
         let mut skip_idle = true;
         for next_lock in contexts
             .range((
@@ -155,20 +240,41 @@ pub fn switch() -> SwitchResult {
             .cloned()
             .chain(Some(Arc::clone(&idle)))
         {
-            if Arc::ptr_eq(&next_lock, &idle) && skip_idle {
-                skip_idle = false;
-                continue;
+            let is_idle = Arc::ptr_eq(&next_lock, &idle);
+            if is_idle {
+                if skip_idle {
+                    skip_idle = false;
+                    continue;
+                }
+                // the idle context only runs when nothing else can
+                if best.is_some() {
+                    break;
+                }
             }
 
-            let mut next_guard = next_lock.write_arc();
-            if !next_guard.running
-                && matches!(next_guard.status, Status::Runnable | Status::Waiting)
+            // the first of the most urgent tasks wins, so equal priorities take turns
+            let next_guard = next_lock.write_arc();
+            if is_eligible(&next_guard, cpu)
+                && (is_idle || !parked)
+                && best
+                    .as_ref()
+                    .is_none_or(|best| next_guard.priority > best.priority)
             {
-                next_guard.status = Status::Runnable;
-                switch_state_opt = Some((prev_guard, next_guard));
-                break;
+                best = Some(next_guard);
             }
         }
+
+        // a runnable task only gives way to one at least as urgent
+        let prev_keeps_cpu = prev_guard.status == Status::Runnable
+            && prev_guard.affinity.contains(cpu)
+            && !parked
+            && best
+                .as_ref()
+                .is_some_and(|best| prev_guard.priority > best.priority);
+        if let Some(mut next_guard) = best.filter(|_| !prev_keeps_cpu) {
+            next_guard.status = Status::Runnable;
+            switch_state_opt = Some((prev_guard, next_guard));
+        }
     }
 
     if let Some((mut prev_guard, mut next_guard)) = switch_state_opt {
@@ -199,6 +305,12 @@ pub fn switch() -> SwitchResult {
 
         block.next_addr_space.set(next_cx.addr_space.clone());
 
+        trace_ctx_switch(prev_cx.pid, next_cx.pid);
+        let now = time::uptime();
+        prev_cx.stats.switch_out(now);
+        next_cx.stats.switch_in(now);
+        block.switch_state.set_current_stats(&next_cx.stats);
+
         unsafe {
             switch_to(prev_cx, next_cx);
         }
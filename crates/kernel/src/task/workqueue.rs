@@ -0,0 +1,99 @@
+//! Deferred work, run by kernel worker tasks instead of in interrupt context.
+//!
+//! Interrupt handlers should do as little as possible: acknowledge the device, then
+//! [`schedule_work`] the rest. Work runs in a worker task with interrupts enabled, where it
+//! can take its time, block, and allocate.
+
+use core::time::Duration;
+
+use alloc::{collections::vec_deque::VecDeque, format};
+
+use crate::{
+    sync::{IrqMutex, WaitQueue},
+    syscall::errno::Errno,
+    timer,
+};
+
+use super::{SpawnBuilder, context::Priority};
+
+/// The number of worker tasks.
+const WORKERS: usize = 2;
+/// How many work items can be waiting at once.
+const QUEUE_CAPACITY: usize = 64;
+
+static PENDING: IrqMutex<VecDeque<Work>> = IrqMutex::new(VecDeque::new());
+static WORKER_WAIT: WaitQueue = WaitQueue::new();
+
+/// A function to run later, with an argument to pass it.
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl Work {
+    /// Creates a work item that calls `func(arg)`.
+    #[must_use]
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Self { func, arg }
+    }
+
+    fn run(self) {
+        (self.func)(self.arg);
+    }
+}
+
+/// Queues `work` to be run by a worker task as soon as one is free.
+///
+/// Safe to call from interrupt handlers.
+///
+/// # Errors
+///
+/// Returns [`Errno::EAGAIN`] if the queue is full, or the workers haven't been started.
+pub fn schedule_work(work: Work) -> Result<(), Errno> {
+    {
+        let mut pending = PENDING.lock();
+        if pending.len() == pending.capacity() {
+            return Err(Errno::EAGAIN);
+        }
+        pending.push_back(work);
+    }
+    WORKER_WAIT.wake_one();
+    Ok(())
+}
+
+/// Queues `work` to be run by a worker task once `delay` has passed.
+///
+/// The delay is only as precise as the timer tick. If the queue is full when it's due, it's
+/// queued on a later tick instead. Safe to call from interrupt handlers.
+pub fn schedule_delayed_work(work: Work, delay: Duration) {
+    timer::oneshot(delay, move || queue_when_due(work));
+}
+
+fn queue_when_due(work: Work) {
+    if schedule_work(work).is_err() {
+        timer::oneshot(Duration::ZERO, move || queue_when_due(work));
+    }
+}
+
+extern "C" fn worker_main() {
+    loop {
+        WORKER_WAIT.wait_until(|| !PENDING.lock().is_empty());
+        let work = PENDING.lock().pop_front();
+        if let Some(work) = work {
+            work.run();
+        }
+    }
+}
+
+/// Allocates the work queues and starts the worker tasks.
+pub fn init() -> Result<(), Errno> {
+    PENDING.lock().reserve_exact(QUEUE_CAPACITY);
+    for i in 0..WORKERS {
+        SpawnBuilder::new()
+            .name(format!("kworker/{i}"))
+            .priority(Priority::HIGH)
+            .spawn(worker_main)?;
+    }
+    Ok(())
+}
@@ -1,7 +1,14 @@
 use core::time::Duration;
 
+pub mod wall;
+
 /// Represents the system uptime (time since boot).
 #[must_use]
 pub fn uptime() -> Duration {
     crate::arch::time::uptime()
 }
+
+/// Does the kernel's periodic housekeeping. Called from the timer interrupt on every tick.
+pub fn tick(now: Duration) {
+    crate::timer::run_expired(now);
+}
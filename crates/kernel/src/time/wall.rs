@@ -0,0 +1,162 @@
+//! The wall clock, as UNIX time.
+//!
+//! The kernel counts time from boot, so the wall clock is kept as the UNIX time at boot, from
+//! whichever [`Source`] knows best: an RTC probed from the device tree, then `time=` on the
+//! command line, then the day the kernel was built, which the builder passes in
+//! `KADOS_BUILD_EPOCH`.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::cmdline;
+
+/// The UNIX time the kernel was built, to the day.
+const BUILD_EPOCH: Option<u64> = match option_env!("KADOS_BUILD_EPOCH") {
+    Some(epoch) => match u64::from_str_radix(epoch, 10) {
+        Ok(epoch) => Some(epoch),
+        Err(_) => panic!("KADOS_BUILD_EPOCH must be a decimal number"),
+    },
+    None => None,
+};
+
+/// The UNIX time at boot, in nanoseconds.
+static BOOT_TIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(Source::None as u8);
+
+/// Where the wall clock was set from, worst first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Source {
+    /// Nothing; the clock starts at the UNIX epoch.
+    None = 0,
+    /// The day the kernel was built.
+    Build = 1,
+    /// `time=` on the command line.
+    Cmdline = 2,
+    /// A real-time clock.
+    Rtc = 3,
+}
+
+impl Source {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            1 => Self::Build,
+            2 => Self::Cmdline,
+            3 => Self::Rtc,
+            _ => Self::None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "unset"),
+            Self::Build => write!(f, "build date"),
+            Self::Cmdline => write!(f, "command line"),
+            Self::Rtc => write!(f, "RTC"),
+        }
+    }
+}
+
+/// Sets the clock from the build date and the command line, whichever is better.
+pub fn init() {
+    if let Some(epoch) = BUILD_EPOCH {
+        set(Duration::from_secs(epoch), Source::Build);
+    }
+    if let Some(time) = cmdline::get("time") {
+        match time.parse() {
+            Ok(secs) => set(Duration::from_secs(secs), Source::Cmdline),
+            Err(_) => log::warn!("cmdline: `time={time}` isn't a number of seconds"),
+        }
+    }
+}
+
+/// Sets the clock to `now`, unless it was already set from a better source than `source`.
+pub fn set(now: Duration, source: Source) {
+    if source < self::source() {
+        return;
+    }
+    let boot = now.saturating_sub(super::uptime());
+    BOOT_TIME_NANOS.store(
+        u64::try_from(boot.as_nanos()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+    SOURCE.store(source as u8, Ordering::Release);
+    log::info!(
+        "wall clock: {} (from the {source})",
+        DateTime::from_unix(now)
+    );
+}
+
+/// Returns where the clock was last set from.
+#[must_use]
+pub fn source() -> Source {
+    Source::from_raw(SOURCE.load(Ordering::Acquire))
+}
+
+/// Returns the current time since the UNIX epoch.
+#[must_use]
+pub fn now() -> Duration {
+    Duration::from_nanos(BOOT_TIME_NANOS.load(Ordering::Relaxed)) + super::uptime()
+}
+
+/// A UTC date and time, to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Splits a time since the UNIX epoch into its date and time of day.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)] // all in range
+    pub fn from_unix(time: Duration) -> Self {
+        let secs = time.as_secs();
+        let days = (secs / 86400) as i64;
+        let of_day = secs % 86400;
+
+        // Howard Hinnant's `civil_from_days`, with eras of 400 years starting in March
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        } as u8;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (of_day / 3600) as u8,
+            minute: (of_day / 60 % 60) as u8,
+            second: (of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
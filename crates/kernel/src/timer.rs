@@ -0,0 +1,175 @@
+//! Software timers: callbacks run after a delay, or every so often, and sleeping tasks.
+//!
+//! All timers share the timer tick, so they fire on the first tick at or after their
+//! deadline. Callbacks run in the timer interrupt with interrupts disabled, so they should be
+//! short; anything more should [`schedule_work`](crate::task::workqueue::schedule_work).
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::time::spin_for,
+    sync::{IrqMutex, WaitQueue},
+    task::context,
+    time,
+};
+
+static TIMERS: IrqMutex<Timers> = IrqMutex::new(Timers {
+    queue: BTreeMap::new(),
+    running: None,
+    running_cancelled: false,
+});
+
+/// Identifies a timer, so it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    fn alloc() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Periodic {
+        period: Duration,
+        func: Box<dyn FnMut() + Send>,
+    },
+}
+
+struct Timers {
+    /// Pending timers, by deadline.
+    queue: BTreeMap<(Duration, TimerId), Callback>,
+    /// The periodic timer whose callback is running, which is re-armed once it returns.
+    running: Option<TimerId>,
+    /// Set if the running timer was cancelled from its own callback.
+    running_cancelled: bool,
+}
+
+fn add(deadline: Duration, callback: Callback) -> TimerId {
+    let id = TimerId::alloc();
+    TIMERS.lock().queue.insert((deadline, id), callback);
+    id
+}
+
+/// Calls `f` once, on the first tick after `delay` has passed.
+///
+/// Safe to call from interrupt handlers, including timer callbacks.
+pub fn oneshot(delay: Duration, f: impl FnOnce() + Send + 'static) -> TimerId {
+    add(time::uptime() + delay, Callback::Once(Box::new(f)))
+}
+
+/// Calls `f` every `period` until the timer is cancelled, starting one period from now.
+///
+/// Calls missed while the kernel is busy aren't made up for: the next call is always a whole
+/// period after the tick the last one ran on.
+///
+/// Safe to call from interrupt handlers, including timer callbacks.
+pub fn periodic(period: Duration, f: impl FnMut() + Send + 'static) -> TimerId {
+    add(
+        time::uptime() + period,
+        Callback::Periodic {
+            period,
+            func: Box::new(f),
+        },
+    )
+}
+
+/// Cancels a timer, returning `false` if it had already fired (or been cancelled).
+///
+/// A periodic timer can cancel itself from its own callback.
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    if timers.running == Some(id) {
+        timers.running_cancelled = true;
+        return true;
+    }
+    let Some(&key) = timers.queue.keys().find(|(_, timer)| *timer == id) else {
+        return false;
+    };
+    timers.queue.remove(&key);
+    true
+}
+
+/// Runs the callbacks of the timers whose deadline has passed. Called on every timer tick.
+pub fn run_expired(now: Duration) {
+    let mut expired = Vec::new();
+    {
+        let mut timers = TIMERS.lock();
+        while let Some(entry) = timers.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let ((_, id), callback) = entry.remove_entry();
+            expired.push((id, callback));
+        }
+    }
+
+    // the lock is dropped while callbacks run, so they can add and cancel timers
+    for (id, callback) in expired {
+        match callback {
+            Callback::Once(func) => func(),
+            Callback::Periodic { period, mut func } => {
+                TIMERS.lock().running = Some(id);
+                func();
+
+                let mut timers = TIMERS.lock();
+                timers.running = None;
+                if !core::mem::take(&mut timers.running_cancelled) {
+                    let next = (now + period).max(time::uptime());
+                    timers
+                        .queue
+                        .insert((next, id), Callback::Periodic { period, func });
+                }
+            }
+        }
+    }
+}
+
+/// A task sleeping in [`sleep`], shared with the timer that wakes it.
+struct Sleeper {
+    fired: AtomicBool,
+    queue: WaitQueue,
+}
+
+/// Blocks the current task until `duration` has passed.
+///
+/// Without a current task (early in boot), this spins instead.
+///
+/// # Panics
+///
+/// In debug builds, panics if called from an interrupt handler.
+pub fn sleep(duration: Duration) {
+    if context::current().is_none() {
+        spin_for(duration);
+        return;
+    }
+
+    let sleeper = Arc::new(Sleeper {
+        fired: AtomicBool::new(false),
+        queue: WaitQueue::new(),
+    });
+    let timer_sleeper = sleeper.clone();
+    oneshot(duration, move || {
+        timer_sleeper.fired.store(true, Ordering::Release);
+        timer_sleeper.queue.wake_all();
+    });
+    sleeper
+        .queue
+        .wait_until(|| sleeper.fired.load(Ordering::Acquire));
+}
+
+/// Blocks the current task until the uptime reaches `deadline`.
+///
+/// # Panics
+///
+/// In debug builds, panics if called from an interrupt handler.
+pub fn sleep_until(deadline: Duration) {
+    sleep(deadline.saturating_sub(time::uptime()));
+}
@@ -0,0 +1,224 @@
+//! Static tracepoints, recorded into per-CPU ring buffers and dumped over serial.
+//!
+//! The tracepoints are the `trace_*` functions, called from the IRQ dispatcher, the scheduler
+//! and the system call handler. While tracing is off they cost a load and a branch. Each CPU's
+//! ring keeps its last [`RING_SIZE`] records, overwriting the oldest.
+//!
+//! `trace=on` on the command line starts tracing at boot, and the `trace` shell command starts
+//! and stops it and dumps what was recorded, in the format described in [`kados_abi::trace`].
+//! `cargo builder trace` turns a serial log with a dump in it into a Chrome trace, which
+//! Perfetto and `chrome://tracing` both open.
+
+use core::{
+    cell::Cell,
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+use arrayvec::ArrayString;
+use kados_abi::trace::{LINE_PREFIX, TRACE_VERSION, TraceEvent, TraceRecord};
+use spin::Once;
+
+use crate::{
+    arch::{Arch, Architecture, serial},
+    cmdline,
+    irq::{Irq, MAX_CPUS},
+    serial_println,
+    shell::{self, Args, Command},
+    sync::with_irqs_disabled,
+    syscall::errno::Errno,
+    task::context::Pid,
+    time,
+};
+
+/// How many records each CPU keeps.
+pub const RING_SIZE: usize = 2048;
+
+static RINGS: Once<[Ring; MAX_CPUS]> = Once::new();
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// One CPU's records.
+struct Ring {
+    records: Box<[Cell<TraceRecord>]>,
+    /// How many records have been written since the ring was last cleared.
+    written: AtomicUsize,
+}
+
+// each ring is only written by its own CPU with interrupts disabled, and only read or cleared
+// while tracing is stopped
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new() -> Self {
+        Self {
+            records: (0..RING_SIZE)
+                .map(|_| Cell::new(TraceRecord::default()))
+                .collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, record: TraceRecord) {
+        let written = self.written.load(Ordering::Relaxed);
+        self.records[written % RING_SIZE].set(record);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// Returns the records still in the ring, oldest first, and how many were overwritten.
+    fn records(&self) -> (impl Iterator<Item = TraceRecord> + '_, usize) {
+        let written = self.written.load(Ordering::Acquire);
+        let lost = written.saturating_sub(RING_SIZE);
+        let records = (lost..written).map(|i| self.records[i % RING_SIZE].get());
+        (records, lost)
+    }
+}
+
+/// Reads `trace=` from the command line, and adds the `trace` shell command.
+pub fn init() {
+    if cmdline::enabled("trace", false) {
+        start();
+        log::info!("tracing from boot");
+    }
+    shell::register(Command {
+        name: "trace",
+        usage: "[on|off|dump|clear]",
+        help: "start, stop, dump or clear the event trace",
+        run: cmd_trace,
+    });
+}
+
+/// Starts recording events, setting up the rings the first time.
+pub fn start() {
+    RINGS.call_once(|| core::array::from_fn(|_| Ring::new()));
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stops recording events. What's been recorded is kept until it's [cleared](clear).
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Returns `true` while events are being recorded.
+#[must_use]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns how many records the rings hold.
+#[must_use]
+pub fn recorded() -> usize {
+    RINGS
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|ring| ring.written.load(Ordering::Acquire).min(RING_SIZE))
+        .sum()
+}
+
+/// Throws away every record.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBUSY`] if tracing is on.
+pub fn clear() -> Result<(), Errno> {
+    if is_enabled() {
+        return Err(Errno::EBUSY);
+    }
+    for ring in RINGS.get().into_iter().flatten() {
+        ring.written.store(0, Ordering::Release);
+    }
+    Ok(())
+}
+
+/// Stops tracing, and writes every record over serial.
+pub fn dump() {
+    stop();
+    serial_println!("{LINE_PREFIX}begin {TRACE_VERSION}");
+    let (mut count, mut lost) = (0, 0);
+    for ring in RINGS.get().into_iter().flatten() {
+        let (records, overwritten) = ring.records();
+        lost += overwritten;
+        for record in records {
+            let mut hex = ArrayString::<{ TraceRecord::SIZE * 2 }>::new();
+            for byte in record.encode() {
+                write!(hex, "{byte:02x}").ok();
+            }
+            // in one write, so nothing else printed can end up in the middle of the line
+            serial::write_fmt(format_args!("{LINE_PREFIX}{hex}\n"));
+            count += 1;
+        }
+    }
+    serial_println!("{LINE_PREFIX}end {count} {lost}");
+}
+
+#[inline]
+fn record(event: TraceEvent, arg0: u32, arg1: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        record_slow(event, arg0, arg1);
+    }
+}
+
+#[cold]
+fn record_slow(event: TraceEvent, arg0: u32, arg1: u64) {
+    let Some(rings) = RINGS.get() else { return };
+    with_irqs_disabled(|| {
+        let cpu = Arch::cpu_id().min(MAX_CPUS - 1);
+        rings[cpu].push(TraceRecord {
+            timestamp: time::uptime().as_nanos() as u64,
+            cpu: cpu as u16,
+            event: event as u16,
+            arg0,
+            arg1,
+        });
+    });
+}
+
+/// Records that `irq`'s handler is about to run.
+#[inline]
+pub fn trace_irq_entry(irq: Irq) {
+    record(TraceEvent::IrqEntry, irq.value(), 0);
+}
+
+/// Records that `irq`'s handler has finished.
+#[inline]
+pub fn trace_irq_exit(irq: Irq) {
+    record(TraceEvent::IrqExit, irq.value(), 0);
+}
+
+/// Records that the CPU is switching from task `prev` to task `next`.
+#[inline]
+pub fn trace_ctx_switch(prev: Pid, next: Pid) {
+    record(
+        TraceEvent::ContextSwitch,
+        prev.as_raw() as u32,
+        next.as_raw() as u64,
+    );
+}
+
+/// Records that system call `sysno` is about to run, with `arg` as its first argument.
+#[inline]
+pub fn trace_syscall(sysno: usize, arg: usize) {
+    record(TraceEvent::SyscallEntry, sysno as u32, arg as u64);
+}
+
+/// Records that system call `sysno` is returning `result` to its caller.
+#[inline]
+pub fn trace_syscall_exit(sysno: usize, result: usize) {
+    record(TraceEvent::SyscallExit, sysno as u32, result as u64);
+}
+
+fn cmd_trace(mut args: Args) -> Result<(), Errno> {
+    match args.next() {
+        None => {
+            let state = if is_enabled() { "on" } else { "off" };
+            serial_println!("tracing {state}, {} records", recorded());
+        }
+        Some("on") => start(),
+        Some("off") => stop(),
+        Some("dump") => dump(),
+        Some("clear") => clear()?,
+        Some(_) => return Err(Errno::EINVAL),
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! The first program the kernel runs, from the initrd.
+//!
+//! For now it only shows that userspace works: it prints a greeting, gives up its time slice a
+//! few times, and exits.
+
+#![no_std]
+#![no_main]
+
+use kados_runtime::{println, syscall};
+
+kados_runtime::entry!(main);
+
+/// How many times to yield before exiting.
+const YIELDS: usize = 3;
+
+fn main() -> i32 {
+    println!("init: hello from userspace!");
+    for i in 1..=YIELDS {
+        syscall::sched_yield();
+        println!("init: back from yield {i}/{YIELDS}");
+    }
+    println!("init: exiting");
+    0
+}
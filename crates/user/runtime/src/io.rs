@@ -0,0 +1,72 @@
+//! The standard streams, and the macros that print to them.
+
+use core::fmt;
+
+use crate::syscall;
+
+/// The standard input stream's file descriptor.
+pub const STDIN: usize = 0;
+/// The standard output stream's file descriptor.
+pub const STDOUT: usize = 1;
+/// The standard error stream's file descriptor.
+pub const STDERR: usize = 2;
+
+/// A file descriptor to format text to.
+#[derive(Debug, Clone, Copy)]
+pub struct Fd(pub usize);
+
+impl fmt::Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            match syscall::write(self.0, rest) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => rest = &rest[written..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: usize, args: fmt::Arguments) {
+    fmt::Write::write_fmt(&mut Fd(fd), args).ok();
+}
+
+/// Prints a formatted string to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDOUT, format_args!($($arg)*))
+    };
+}
+
+/// Prints a formatted string and a newline to standard output.
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDOUT, format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Prints a formatted string to standard error.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDERR, format_args!($($arg)*))
+    };
+}
+
+/// Prints a formatted string and a newline to standard error.
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_print($crate::io::STDERR, format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
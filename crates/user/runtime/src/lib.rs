@@ -0,0 +1,76 @@
+//! The runtime for kados userspace programs: the entry point, system call wrappers, and a panic
+//! handler.
+//!
+//! A program is a `#![no_std]`, `#![no_main]` binary that names its main function with
+//! [`entry!`]:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! kados_runtime::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     kados_runtime::println!("hello");
+//!     0
+//! }
+//! ```
+//!
+//! The kernel starts a program at `_start` with only its stack pointer set, and main's return
+//! value becomes its exit code. A panic prints its message to standard error and exits with
+//! [`PANIC_EXIT_CODE`].
+//!
+//! Programs are linked with `link.ld`, which the builder passes to the linker: a static
+//! executable whose segments each start on a page of their own, as the kernel's loader needs.
+
+#![no_std]
+
+use core::{arch::naked_asm, panic::PanicInfo};
+
+pub use kados_abi::errno::Errno;
+
+pub mod io;
+pub mod syscall;
+
+/// The exit code of a program that panicked.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+/// Names a program's main function, a `fn() -> i32` returning the program's exit code.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[unsafe(no_mangle)]
+        fn __kados_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+unsafe extern "Rust" {
+    /// The program's main function, as named by [`entry!`].
+    safe fn __kados_main() -> i32;
+}
+
+/// Where the kernel starts a program.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn _start() -> ! {
+    naked_asm!(
+        // the outermost frame, so a backtrace stops here
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "bl {start}",
+        start = sym start,
+    );
+}
+
+extern "C" fn start() -> ! {
+    syscall::exit(__kados_main())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("panicked: {info}");
+    syscall::exit(PANIC_EXIT_CODE)
+}
@@ -0,0 +1,197 @@
+//! System calls, made as [`kados_abi::syscall`] describes.
+
+use core::arch::asm;
+
+use kados_abi::{
+    errno::Errno,
+    syscall::{ExitStatus, FbInfo, Sysno, WAIT_ANY, WNOHANG},
+};
+
+/// Makes system call `sysno` with `args`, returning its result.
+///
+/// # Errors
+///
+/// Returns the error the system call failed with.
+///
+/// # Safety
+///
+/// The arguments must be what the system call expects. Any memory it's given must be valid
+/// for it to read or write.
+pub unsafe fn syscall(sysno: Sysno, args: [usize; 6]) -> Result<usize, Errno> {
+    let result: isize;
+    unsafe {
+        asm!(
+            "svc #0",
+            inlateout("x0") args[0] => result,
+            in("x1") args[1],
+            in("x2") args[2],
+            in("x3") args[3],
+            in("x4") args[4],
+            in("x5") args[5],
+            in("x8") sysno as usize,
+            options(nostack),
+        );
+    }
+    if result < 0 {
+        // the kernel only ever returns negated errors, but in case it's confused
+        let errno = i32::try_from(-result).ok().and_then(Errno::from_raw);
+        Err(errno.unwrap_or(Errno::EINVAL))
+    } else {
+        Ok(result.cast_unsigned())
+    }
+}
+
+/// Ends the program with exit code `code`.
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall(Sysno::Exit, [code.cast_unsigned() as usize, 0, 0, 0, 0, 0]).ok() };
+    unreachable!("exit returned");
+}
+
+/// Writes `buf` to file descriptor `fd`, returning how many bytes were written.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open for writing, or [`Errno::EFAULT`] if nothing
+/// could be written.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, Errno> {
+    unsafe {
+        syscall(
+            Sysno::Write,
+            [fd, buf.as_ptr() as usize, buf.len(), 0, 0, 0],
+        )
+    }
+}
+
+/// Reads up to `buf.len()` bytes from file descriptor `fd` into `buf`, returning how many
+/// were read, or 0 at end of file. Sleeps until there's something to read.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open for reading, or [`Errno::EFAULT`] if `buf`
+/// couldn't be written.
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    unsafe {
+        syscall(
+            Sysno::Read,
+            [fd, buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0],
+        )
+    }
+}
+
+/// Creates a pipe, returning the file descriptors of its read and write ends.
+///
+/// # Errors
+///
+/// Returns [`Errno::EMFILE`] if the program has too many files open.
+pub fn pipe() -> Result<(usize, usize), Errno> {
+    let mut fds = [0u32; 2];
+    unsafe { syscall(Sysno::Pipe, [fds.as_mut_ptr() as usize, 0, 0, 0, 0, 0])? };
+    Ok((fds[0] as usize, fds[1] as usize))
+}
+
+/// Closes file descriptor `fd`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EBADF`] if `fd` isn't open.
+pub fn close(fd: usize) -> Result<(), Errno> {
+    unsafe { syscall(Sysno::Close, [fd, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Creates a zeroed shared memory object of `size` bytes, returning its id.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if `size` is 0 or too big, or [`Errno::ENOMEM`] if there's no
+/// memory for it.
+pub fn shm_create(size: usize) -> Result<usize, Errno> {
+    unsafe { syscall(Sysno::ShmCreate, [size, 0, 0, 0, 0, 0]) }
+}
+
+/// Maps all of shared memory object `id` wherever there's room, returning its address.
+/// `prot` is a mask of `PROT_*`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no object with that id, or [`Errno::ENOMEM`] if
+/// there's no room for it.
+pub fn shm_map(id: usize, prot: usize) -> Result<*mut u8, Errno> {
+    unsafe { syscall(Sysno::ShmMap, [id, 0, prot, 0, 0, 0]) }.map(|addr| addr as *mut u8)
+}
+
+/// Takes away shared memory object `id`'s id. Its memory is freed once nothing maps it.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if there's no object with that id.
+pub fn shm_destroy(id: usize) -> Result<(), Errno> {
+    unsafe { syscall(Sysno::ShmDestroy, [id, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Unmaps any pages in `addr..addr + len`.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if `addr` isn't page-aligned or the range isn't in userspace.
+///
+/// # Safety
+///
+/// Nothing may use the memory once it's unmapped.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    unsafe { syscall(Sysno::Munmap, [addr as usize, len, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Maps the framebuffer, returning the address of its first pixel and what it looks like.
+///
+/// # Errors
+///
+/// Returns [`Errno::EPERM`] if the program isn't privileged, or [`Errno::ENODEV`] if there's
+/// no framebuffer.
+pub fn fb_map() -> Result<(*mut u8, FbInfo), Errno> {
+    let mut info = FbInfo::default();
+    let addr = unsafe { syscall(Sysno::FbMap, [(&raw mut info) as usize, 0, 0, 0, 0, 0])? };
+    Ok((addr as *mut u8, info))
+}
+
+/// Gives up the rest of the program's time slice.
+pub fn sched_yield() {
+    unsafe { syscall(Sysno::Yield, [0; 6]).ok() };
+}
+
+/// Sets the CPUs the program may run on, one bit per CPU.
+///
+/// # Errors
+///
+/// Returns [`Errno::EINVAL`] if none of the CPUs in `mask` are online.
+pub fn sched_setaffinity(mask: usize) -> Result<(), Errno> {
+    unsafe { syscall(Sysno::SchedSetAffinity, [mask, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Returns the online CPUs the program may run on, one bit per CPU.
+#[must_use]
+pub fn sched_getaffinity() -> usize {
+    unsafe { syscall(Sysno::SchedGetAffinity, [0; 6]) }.unwrap_or(0)
+}
+
+/// Waits for child `pid` to exit, or any child if it's `None`, returning its PID and how it
+/// ended. With `nohang`, returns `None` straight away if none has exited yet.
+///
+/// # Errors
+///
+/// Returns [`Errno::ECHILD`] if the program has no such child.
+pub fn wait4(pid: Option<usize>, nohang: bool) -> Result<Option<(usize, ExitStatus)>, Errno> {
+    let mut status = 0i32;
+    let options = if nohang { WNOHANG } else { 0 };
+    let args = [
+        pid.unwrap_or(WAIT_ANY),
+        (&raw mut status) as usize,
+        options,
+        0,
+        0,
+        0,
+    ];
+    match unsafe { syscall(Sysno::Wait4, args)? } {
+        0 => Ok(None),
+        pid => Ok(Some((pid, ExitStatus::from_raw(status)))),
+    }
+}
@@ -0,0 +1,125 @@
+//! The boards the kernel can be built for, and what the builder needs to know to run it on each.
+
+use std::fmt::Display;
+
+/// A board to build for, picked with `--board`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Board {
+    /// Raspberry Pi 4 Model B
+    #[default]
+    Rpi4,
+    /// Raspberry Pi 5
+    Rpi5,
+    /// QEMU's generic `virt` machine
+    QemuVirt,
+}
+
+impl Board {
+    #[must_use]
+    pub fn profile(self) -> &'static BoardProfile {
+        match self {
+            Self::Rpi4 => &RPI4,
+            Self::Rpi5 => &RPI5,
+            Self::QemuVirt => &QEMU_VIRT,
+        }
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.profile().name)
+    }
+}
+
+/// Everything board specific about emulating a board and putting the kernel on its SD card.
+#[derive(Debug)]
+pub struct BoardProfile {
+    pub name: &'static str,
+    /// How to emulate the board, if QEMU can.
+    pub qemu: Option<QemuMachine>,
+    /// The firmware the board boots with, if it boots from an SD card.
+    pub firmware: Option<Firmware>,
+}
+
+/// How QEMU emulates a board.
+#[derive(Debug)]
+pub struct QemuMachine {
+    /// The `-M` machine.
+    pub machine: &'static str,
+    /// The `-cpu` model.
+    pub cpu: &'static str,
+    /// The `-m` memory size.
+    pub memory: &'static str,
+    /// Whether QEMU is given the firmware's device tree, rather than generating its own.
+    pub firmware_dtb: bool,
+    /// The `-device` for a network card the kernel can drive, if QEMU emulates one.
+    pub nic: Option<&'static str>,
+}
+
+/// The firmware a board boots from its SD card with.
+#[derive(Debug)]
+pub struct Firmware {
+    /// The git repository it's cloned from.
+    pub repo: &'static str,
+    /// The board's device tree, in the repository's `boot` directory.
+    pub dtb: &'static str,
+    /// The rest of the files the board needs from the `boot` directory, by their path there,
+    /// which is also their path on the boot partition.
+    pub files: &'static [&'static str],
+    /// The `config.txt` to put on the boot partition, relative to the repository root.
+    pub config_txt: &'static str,
+    /// What the firmware expects the kernel image to be called.
+    pub kernel_name: &'static str,
+}
+
+const RPI_FIRMWARE_REPO: &str = "https://github.com/raspberrypi/firmware.git";
+
+pub static RPI4: BoardProfile = BoardProfile {
+    name: "rpi4",
+    qemu: Some(QemuMachine {
+        machine: "raspi4b",
+        cpu: "cortex-a72",
+        memory: "2G",
+        firmware_dtb: true,
+        // QEMU doesn't emulate the Pi 4's GENET
+        nic: None,
+    }),
+    firmware: Some(Firmware {
+        repo: RPI_FIRMWARE_REPO,
+        dtb: "bcm2711-rpi-4-b.dtb",
+        files: &[
+            "start4.elf",
+            "bootcode.bin",
+            "fixup4.dat",
+            "overlays/disable-bt.dtbo",
+        ],
+        config_txt: "config.txt",
+        kernel_name: "kernel8.img",
+    }),
+};
+
+/// QEMU has no Pi 5 machine, and the Pi 5 keeps its bootloader in EEPROM rather than on the
+/// card, so only the device tree and overlays come from the firmware repository.
+pub static RPI5: BoardProfile = BoardProfile {
+    name: "rpi5",
+    qemu: None,
+    firmware: Some(Firmware {
+        repo: RPI_FIRMWARE_REPO,
+        dtb: "bcm2712-rpi-5-b.dtb",
+        files: &["overlays/disable-bt.dtbo"],
+        config_txt: "config-rpi5.txt",
+        kernel_name: "kernel_2712.img",
+    }),
+};
+
+pub static QEMU_VIRT: BoardProfile = BoardProfile {
+    name: "qemu-virt",
+    qemu: Some(QemuMachine {
+        machine: "virt,gic-version=2",
+        cpu: "cortex-a72",
+        memory: "2G",
+        firmware_dtb: false,
+        nic: Some("virtio-net-device"),
+    }),
+    firmware: None,
+};
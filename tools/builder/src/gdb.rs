@@ -0,0 +1,105 @@
+//! Debugging the kernel in QEMU with GDB.
+//!
+//! GDB is started with a script that connects it to QEMU's GDB server, loads the kernel's
+//! symbols, and defines some commands for looking around the kernel:
+//!
+//! - `dump_pt <vaddr>` walks the page tables for an address, printing each level's
+//!   descriptor. It reads the tables through the HHDM, so it works once the bootloader has
+//!   mapped it.
+//! - `list_tasks` calls into the kernel to print the task table, as the shell's `ps` does, to
+//!   the serial console.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use kados_abi::boot::HHDM_PHYSICAL_OFFSET;
+
+/// GDBs that can debug AArch64, in the order they're tried.
+const GDBS: &[&str] = &[
+    "gdb-multiarch",
+    "aarch64-none-elf-gdb",
+    "aarch64-elf-gdb",
+    "aarch64-linux-gnu-gdb",
+    "gdb",
+];
+
+/// Returns the first of [`GDBS`] that's installed.
+pub fn find() -> anyhow::Result<&'static str> {
+    GDBS.iter()
+        .copied()
+        .find(|gdb| {
+            Command::new(gdb)
+                .arg("--version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        })
+        .ok_or_else(|| anyhow::anyhow!("no GDB found in PATH, tried {}", GDBS.join(", ")))
+}
+
+/// Writes the script GDB is started with to `path`, loading the kernel's symbols from `sym`.
+pub fn write_script(path: &Path, sym: &Path) -> anyhow::Result<()> {
+    let script = format!(
+        r#"# Written by `cargo builder gdb`; changes are lost on the next run.
+set pagination off
+set confirm off
+target remote localhost:1234
+symbol-file {sym}
+
+# The tables are read through the HHDM, at {hhdm:#x}. A 4K granule and four levels, as the
+# kernel sets up.
+define dump_pt
+  set language c
+  set $va = (unsigned long) ($arg0)
+  if $va >> 63
+    set $table = $TTBR1_EL1 & 0x0000fffffffff000
+  else
+    set $table = $TTBR0_EL1 & 0x0000fffffffff000
+  end
+  set $level = 0
+  while $level < 4
+    set $shift = 39 - 9 * $level
+    set $index = ($va >> $shift) & 0x1ff
+    set $desc = *(unsigned long *) ({hhdm:#x} + $table + $index * 8)
+    printf "L%d[%3d] @ %#014lx: %#018lx\n", $level, $index, $table, $desc
+    if !($desc & 1)
+      printf "%#018lx is not mapped\n", $va
+      set $level = 4
+    else
+      if $level == 3 || !($desc & 2)
+        set $size = 1UL << $shift
+        set $pa = ($desc & 0x0000fffffffff000 & ~($size - 1)) | ($va & ($size - 1))
+        printf "%#018lx -> %#014lx\n", $va, $pa
+        set $level = 4
+      else
+        set $table = $desc & 0x0000fffffffff000
+        set $level = $level + 1
+      end
+    end
+  end
+  set language auto
+end
+document dump_pt
+Walk the page tables for a virtual address: dump_pt <vaddr>
+Addresses in the upper half are looked up through TTBR1_EL1, the rest through TTBR0_EL1.
+end
+
+define list_tasks
+  set language c
+  call (void) kados_gdb_list_tasks()
+  set language auto
+end
+document list_tasks
+Print the task table to the serial console, as the shell's `ps` command does.
+It takes the locks `ps` does, so it hangs if the kernel was stopped holding one.
+end
+"#,
+        sym = sym.display(),
+        hhdm = HHDM_PHYSICAL_OFFSET,
+    );
+    std::fs::write(path, script)?;
+    Ok(())
+}
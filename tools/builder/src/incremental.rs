@@ -0,0 +1,209 @@
+//! Skipping build steps whose inputs haven't changed since they last succeeded.
+//!
+//! Each step hashes what goes into it: the source trees of the crates it builds, files like
+//! linker scripts and the target spec, artifacts of earlier steps, and settings like
+//! `RUSTFLAGS`. The hashes each step last succeeded with are kept in the target directory, and a
+//! step is skipped if none has changed and its outputs are all still there. The hashes are only
+//! ever compared with ones the same builder wrote, so a new toolchain hashing differently just
+//! rebuilds everything once.
+
+use std::{
+    collections::{BTreeMap, hash_map::DefaultHasher},
+    fmt::Write as _,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+/// The hashes of what goes into a build step, each under a name that says what it is.
+#[derive(Debug, Default)]
+pub struct Inputs {
+    hashes: BTreeMap<String, u64>,
+}
+
+impl Inputs {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a setting, like the flags passed to a tool.
+    #[must_use]
+    pub fn value(mut self, name: &str, value: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        self.hashes.insert(name.to_string(), hasher.finish());
+        self
+    }
+
+    /// Adds a file's contents. A file that doesn't exist hashes differently from every file
+    /// that does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but can't be read.
+    pub fn file(self, name: &str, path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(self.value(name, contents))
+    }
+
+    /// Adds the names and contents of every file under a directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of it can't be read.
+    pub fn dir(self, name: &str, path: &Path) -> anyhow::Result<Self> {
+        let mut hasher = DefaultHasher::new();
+        hash_dir(&mut hasher, path, path)?;
+        let hash = hasher.finish();
+        Ok(self.value(name, hash))
+    }
+}
+
+/// Hashes every file under `dir`, in a fixed order, by its path relative to `root` and its
+/// contents.
+fn hash_dir(hasher: &mut DefaultHasher, root: &Path, dir: &Path) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_dir(hasher, root, &path)?;
+        } else {
+            path.strip_prefix(root)?.hash(hasher);
+            std::fs::read(&path)?.hash(hasher);
+        }
+    }
+    Ok(())
+}
+
+/// A build step that's been checked against the hashes it last succeeded with.
+#[derive(Debug)]
+pub struct Step {
+    name: String,
+    inputs: Inputs,
+    /// Why the step has to run, or nothing if it can be skipped.
+    reasons: Vec<String>,
+}
+
+impl Step {
+    /// Returns `true` if the step can be skipped.
+    #[must_use]
+    pub fn is_fresh(&self) -> bool {
+        self.reasons.is_empty()
+    }
+}
+
+/// The hashes each step last succeeded with, and what's happened to each step this build.
+#[derive(Debug)]
+pub struct BuildCache {
+    path: PathBuf,
+    /// Each step's inputs, by step and input name.
+    hashes: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Each step that's finished this build, and why it ran, if it did.
+    summary: Vec<(String, Vec<String>)>,
+}
+
+impl BuildCache {
+    /// Loads the hashes kept at `path`, or starts afresh if there are none or they can't be
+    /// read.
+    #[must_use]
+    pub fn load(path: PathBuf) -> Self {
+        let mut hashes = BTreeMap::<String, BTreeMap<String, u64>>::new();
+        for line in std::fs::read_to_string(&path).unwrap_or_default().lines() {
+            // `step<TAB>input<TAB>hash`
+            let mut fields = line.splitn(3, '\t');
+            let (Some(step), Some(input), Some(Ok(hash))) = (
+                fields.next(),
+                fields.next(),
+                fields.next().map(|hash| u64::from_str_radix(hash, 16)),
+            ) else {
+                continue;
+            };
+            hashes
+                .entry(step.to_string())
+                .or_default()
+                .insert(input.to_string(), hash);
+        }
+        Self {
+            path,
+            hashes,
+            summary: Vec::new(),
+        }
+    }
+
+    /// Checks the step called `name` against the inputs it last succeeded with, and whether
+    /// the `outputs` it makes are still there.
+    #[must_use]
+    pub fn check(&self, name: &str, inputs: Inputs, outputs: &[PathBuf]) -> Step {
+        let mut reasons = Vec::new();
+        match self.hashes.get(name) {
+            None => reasons.push("it hasn't been built".to_string()),
+            Some(last) => {
+                for (input, hash) in &inputs.hashes {
+                    if last.get(input) != Some(hash) {
+                        reasons.push(format!("{input} changed"));
+                    }
+                }
+                for input in last.keys() {
+                    if !inputs.hashes.contains_key(input) {
+                        reasons.push(format!("{input} is no longer used"));
+                    }
+                }
+            }
+        }
+        for output in outputs {
+            if !output.exists() {
+                reasons.push(format!("{} is missing", output.display()));
+            }
+        }
+        Step {
+            name: name.to_string(),
+            inputs,
+            reasons,
+        }
+    }
+
+    /// Records that a step has succeeded, or been skipped, and saves the hashes it ran with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the hashes can't be saved.
+    pub fn finish(&mut self, step: Step) -> anyhow::Result<()> {
+        if !step.is_fresh() {
+            self.hashes.insert(step.name.clone(), step.inputs.hashes);
+            self.save()?;
+        }
+        self.summary.push((step.name, step.reasons));
+        Ok(())
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let mut text = String::new();
+        for (step, inputs) in &self.hashes {
+            for (input, hash) in inputs {
+                writeln!(text, "{step}\t{input}\t{hash:016x}")?;
+            }
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, text)?;
+        Ok(())
+    }
+
+    /// Logs which steps ran this build, and why, and which were skipped.
+    pub fn log_summary(&self) {
+        for (step, reasons) in &self.summary {
+            if reasons.is_empty() {
+                log::info!("{step}: up to date");
+            } else {
+                log::info!("{step}: rebuilt, since {}", reasons.join(", "));
+            }
+        }
+    }
+}
@@ -1,8 +1,26 @@
-use std::{fmt::Display, path::PathBuf};
-
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use board::{Board, BoardProfile, Firmware};
 use clap::{Parser, Subcommand};
+use incremental::{BuildCache, Inputs, Step};
+use kados_abi::boot::INITRD_LOAD_ADDR;
 use xshell::{Shell, cmd};
 
+mod board;
+mod gdb;
+mod incremental;
+mod trace;
+
 #[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
     /// Checks that the correct dependencies are installed
@@ -23,13 +41,60 @@ pub enum Mode {
         #[clap(short, long, default_value_t = false)]
         release: bool,
     },
-    /// Copy the kernel to an SD card for the Raspberry Pi
+    /// Build the kernel, run it in QEMU stopped at its first instruction, and attach GDB to it
+    /// with the kernel's symbols and some helper commands
+    Gdb {
+        #[clap(short, long, default_value_t = false)]
+        release: bool,
+    },
+    /// Build the kernel with its in-kernel tests and run them in QEMU
+    Test {
+        #[clap(short, long, default_value_t = false)]
+        release: bool,
+        /// Seconds to wait for the tests to finish before giving up
+        #[clap(long, default_value_t = 120)]
+        timeout: u64,
+    },
+    /// Type-check every crate, each for the target and with the flags it's built with
+    Check {
+        #[clap(short, long, default_value_t = false)]
+        release: bool,
+        /// Check the kernel with all of its optional features enabled
+        #[clap(long, default_value_t = false)]
+        all_features: bool,
+    },
+    /// Lint every crate with clippy, each for the target and with the flags it's built with
+    Clippy {
+        #[clap(short, long, default_value_t = false)]
+        release: bool,
+        /// Lint the kernel with all of its optional features enabled
+        #[clap(long, default_value_t = false)]
+        all_features: bool,
+        /// Fail on any warning
+        #[clap(long, default_value_t = false)]
+        deny_warnings: bool,
+    },
+    /// Copy the kernel to an SD card for the board
     Flash {
         /// Device to flash to (e.g. /dev/sdb)
         device: String,
         #[clap(short, long, default_value_t = false)]
         release: bool,
     },
+    /// Build an SD card image for the board, which can be written to a card with `dd`
+    Image {
+        /// Path to write the image to
+        #[clap(default_value = "target/kados.img")]
+        output: PathBuf,
+        #[clap(short, long, default_value_t = false)]
+        release: bool,
+        /// Size of the image, with a K, M or G suffix
+        #[clap(long, default_value = "64M", value_parser = parse_size)]
+        size: u64,
+        /// Put the chainloader on the image instead of the kernel
+        #[clap(long, default_value_t = false)]
+        chainloader: bool,
+    },
     /// Build and copy the chainloader to an SD card for the Raspberry Pi
     FlashChainloader {
         /// Device to flash to (e.g. /dev/sdb)
@@ -39,6 +104,17 @@ pub enum Mode {
     Load {
         #[clap(short, long, default_value_t = false)]
         release: bool,
+        /// Compress the kernel with LZ4 before sending it
+        #[clap(long, default_value_t = false)]
+        compress: bool,
+    },
+    /// Convert an event trace the kernel dumped over serial into a Chrome trace, for Perfetto
+    Trace {
+        /// Serial log with the output of the kernel's `trace dump` shell command in it
+        serial_log: PathBuf,
+        /// Path to write the Chrome trace JSON to
+        #[clap(short, long, default_value = "target/trace.json")]
+        output: PathBuf,
     },
 }
 
@@ -48,6 +124,157 @@ pub struct Args {
     /// Mode of operation
     #[command(subcommand)]
     mode: Mode,
+
+    /// Build the kernel as a position-independent executable, relocated at boot
+    #[clap(long, global = true, default_value_t = false)]
+    pie: bool,
+
+    /// Build the kernel with the GDB remote stub, which takes over the serial port on breakpoints
+    #[clap(long, global = true, default_value_t = false)]
+    gdb: bool,
+
+    /// Build the kernel with pointer authentication and BTI, and emulate a CPU that has them
+    #[clap(long, global = true, default_value_t = false)]
+    pauth: bool,
+
+    /// Build the kernel with stack canaries, checked before returning from functions with buffers
+    #[clap(long, global = true, default_value_t = false)]
+    stack_protector: bool,
+
+    /// Build the kernel with red zones around heap allocations, checked when they're freed
+    #[clap(long, global = true, default_value_t = false)]
+    heap_debug: bool,
+
+    /// Build the kernel to report recursive locking and lock order inversions
+    #[clap(long, global = true, default_value_t = false)]
+    lockdep: bool,
+
+    /// Build the kernel to run the scheduler benchmarks at boot and exit QEMU with the results
+    #[clap(long, global = true, default_value_t = false)]
+    bench: bool,
+
+    /// Board to build for, which decides how it's emulated and what goes on its SD card
+    #[clap(long, global = true, value_enum, default_value_t = Board::default())]
+    board: Board,
+
+    /// On the Pi 5, report on RP1's UART0 on GPIO 14 and 15 rather than the debug connector
+    #[clap(long, global = true, default_value_t = false)]
+    rp1_uart: bool,
+
+    /// Command line to boot the kernel with, like `log=debug console=serial`
+    #[clap(long, global = true, default_value = "")]
+    cmdline: String,
+
+    /// Initial ramdisk (a cpio archive) to boot the kernel with, instead of one with the
+    /// userspace programs in
+    #[clap(long, global = true)]
+    initrd: Option<PathBuf>,
+
+    #[command(flatten)]
+    mem_sizes: MemSizes,
+
+    #[command(flatten)]
+    qemu: QemuOptions,
+}
+
+/// Sizes of the kernel's memory pools, baked in at build time. Any left out keep the
+/// kernel's defaults.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct MemSizes {
+    /// Size of the kernel heap at boot, in bytes or with a K, M or G suffix [default: 64M]
+    #[clap(long, global = true, value_parser = parse_size)]
+    heap_size: Option<u64>,
+
+    /// Size the kernel heap can grow to, in bytes or with a K, M or G suffix [default: 1G]
+    #[clap(long, global = true, value_parser = parse_size)]
+    heap_max_size: Option<u64>,
+
+    /// Size of the DMA heap, in bytes or with a K, M or G suffix [default: 128K]
+    #[clap(long, global = true, value_parser = parse_size)]
+    dma_size: Option<u64>,
+}
+
+/// How QEMU runs the kernel, in the modes that run it. Any left out keep the board's
+/// defaults.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct QemuOptions {
+    /// Number of CPUs to emulate [default: the board's]
+    #[clap(long, global = true)]
+    smp: Option<u32>,
+
+    /// Amount of RAM to emulate, as QEMU's `-m` takes it, like `1G` [default: the board's]
+    #[clap(long, global = true)]
+    mem: Option<String>,
+
+    /// Display for the framebuffer, as QEMU's `-display` takes it, like `gtk` or `sdl`
+    #[clap(long, global = true, conflicts_with = "headless")]
+    display: Option<String>,
+
+    /// Show the framebuffer nowhere, like `--display none`
+    #[clap(long, global = true, default_value_t = false)]
+    headless: bool,
+
+    /// What QEMU logs to `target/log.txt`, as its `-d` takes it, or nothing if empty
+    /// [default: int,guest_errors]
+    #[clap(long, global = true)]
+    qemu_log: Option<String>,
+
+    /// Network to connect the board's network card to, if QEMU emulates one
+    #[clap(long, global = true, value_enum, default_value_t = Net::default())]
+    net: Net,
+
+    /// Forward a host port to the kernel on the `user` network, like `tcp::8080-:80`; may be
+    /// given more than once
+    #[clap(long, global = true)]
+    hostfwd: Vec<String>,
+
+    /// Extra argument to pass QEMU, after the builder's own; may be given more than once
+    #[clap(long = "qemu-arg", global = true, allow_hyphen_values = true)]
+    extra_args: Vec<String>,
+}
+
+/// A network for QEMU to connect the board's network card to, picked with `--net`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Net {
+    /// No network card
+    #[default]
+    None,
+    /// QEMU's user-mode network, a NAT to the host's
+    User,
+}
+
+/// Parses a size like `4096`, `512K` or `64M`, which must be a whole number of 4KiB pages.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let size = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("`{s}` isn't a size"))?;
+    if size == 0 || size % 4096 != 0 {
+        return Err(format!("`{s}` isn't a whole number of 4KiB pages"));
+    }
+    Ok(size)
+}
+
+/// Returns the UNIX time the kernel is built, which it starts its wall clock from when it has
+/// nothing better: `SOURCE_DATE_EPOCH` if it's set, or else today. It's rounded down to the day,
+/// so that it doesn't rebuild the kernel every time.
+fn build_epoch() -> u64 {
+    let now = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    now - now % 86400
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -65,10 +292,25 @@ impl Display for Profile {
     }
 }
 
+#[derive(Clone)]
 pub struct Context {
     sh: Shell,
     profile: Profile,
     build_root: PathBuf,
+    board: &'static BoardProfile,
+    rp1_uart: bool,
+    cmdline: String,
+    initrd: Option<PathBuf>,
+    pie: bool,
+    gdb: bool,
+    pauth: bool,
+    stack_protector: bool,
+    heap_debug: bool,
+    lockdep: bool,
+    bench: bool,
+    ktest: bool,
+    mem_sizes: MemSizes,
+    qemu: QemuOptions,
 }
 
 impl Context {
@@ -87,9 +329,107 @@ impl Context {
                 .parent()
                 .unwrap()
                 .to_path_buf(),
+            board: Board::default().profile(),
+            rp1_uart: false,
+            cmdline: String::new(),
+            initrd: None,
+            pie: false,
+            gdb: false,
+            pauth: false,
+            stack_protector: false,
+            heap_debug: false,
+            lockdep: false,
+            bench: false,
+            ktest: false,
+            mem_sizes: MemSizes::default(),
+            qemu: QemuOptions::default(),
         })
     }
 
+    #[must_use]
+    pub fn with_board(mut self, board: Board) -> Self {
+        self.board = board.profile();
+        self
+    }
+
+    #[must_use]
+    pub fn with_rp1_uart(mut self, rp1_uart: bool) -> Self {
+        self.rp1_uart = rp1_uart;
+        self
+    }
+
+    #[must_use]
+    pub fn with_cmdline(mut self, cmdline: String) -> Self {
+        self.cmdline = cmdline;
+        self
+    }
+
+    #[must_use]
+    pub fn with_initrd(mut self, initrd: Option<PathBuf>) -> Self {
+        self.initrd = initrd;
+        self
+    }
+
+    #[must_use]
+    pub fn with_pie(mut self, pie: bool) -> Self {
+        self.pie = pie;
+        self
+    }
+
+    #[must_use]
+    pub fn with_gdb(mut self, gdb: bool) -> Self {
+        self.gdb = gdb;
+        self
+    }
+
+    #[must_use]
+    pub fn with_pauth(mut self, pauth: bool) -> Self {
+        self.pauth = pauth;
+        self
+    }
+
+    #[must_use]
+    pub fn with_stack_protector(mut self, stack_protector: bool) -> Self {
+        self.stack_protector = stack_protector;
+        self
+    }
+
+    #[must_use]
+    pub fn with_heap_debug(mut self, heap_debug: bool) -> Self {
+        self.heap_debug = heap_debug;
+        self
+    }
+
+    #[must_use]
+    pub fn with_lockdep(mut self, lockdep: bool) -> Self {
+        self.lockdep = lockdep;
+        self
+    }
+
+    #[must_use]
+    pub fn with_bench(mut self, bench: bool) -> Self {
+        self.bench = bench;
+        self
+    }
+
+    #[must_use]
+    pub fn with_ktest(mut self, ktest: bool) -> Self {
+        self.ktest = ktest;
+        self
+    }
+
+    #[must_use]
+    pub fn with_mem_sizes(mut self, mem_sizes: MemSizes) -> Self {
+        self.mem_sizes = mem_sizes;
+        self
+    }
+
+    #[must_use]
+    pub fn with_qemu(mut self, qemu: QemuOptions) -> Self {
+        self.qemu = qemu;
+        self
+    }
+
     pub fn target_dir(&self) -> PathBuf {
         self.build_root
             .join("target")
@@ -121,6 +461,20 @@ impl Context {
         self.kernel_elf_path().with_extension("sym")
     }
 
+    pub fn gdb_script_path(&self) -> PathBuf {
+        self.target_dir().join("kados.gdbinit")
+    }
+
+    pub fn kernel_ksyms_path(&self) -> PathBuf {
+        self.kernel_elf_path().with_extension("ksyms")
+    }
+
+    /// Remembers how much space the last build reserved for the symbol table, so a rebuild
+    /// with an unchanged table doesn't have to link twice.
+    pub fn kernel_ksyms_size_path(&self) -> PathBuf {
+        self.kernel_elf_path().with_extension("ksyms-size")
+    }
+
     pub fn chainloader_elf_path(&self) -> PathBuf {
         self.target_dir().join("chainloader")
     }
@@ -139,7 +493,40 @@ impl Context {
             .join("linker.ld")
     }
 
-    pub fn rpi_firmware_dir(&self) -> PathBuf {
+    /// Where the userspace programs are built. They have a target directory of their own, so
+    /// Cargo can build them at the same time as the kernel.
+    pub fn user_target_dir(&self) -> PathBuf {
+        self.build_root.join("target").join("user")
+    }
+
+    pub fn user_program_path(&self, program: &str) -> PathBuf {
+        self.user_target_dir()
+            .join("aarch64-kados")
+            .join(self.profile.to_string())
+            .join(program)
+    }
+
+    /// Where the hashes of each build step's inputs are kept, to skip the steps whose inputs
+    /// haven't changed.
+    pub fn build_hashes_path(&self) -> PathBuf {
+        self.target_dir().join("build-hashes")
+    }
+
+    /// Where the userspace programs are packed into an initrd.
+    pub fn user_initrd_path(&self) -> PathBuf {
+        self.target_dir().join("initrd.cpio")
+    }
+
+    /// The initrd the kernel boots with: the one `--initrd` gave, or else the one packed with
+    /// the userspace programs, if they've been built.
+    pub fn initrd_path(&self) -> Option<PathBuf> {
+        self.initrd.clone().or_else(|| {
+            let packed = self.user_initrd_path();
+            packed.exists().then_some(packed)
+        })
+    }
+
+    pub fn firmware_dir(&self) -> PathBuf {
         self.build_root.join("target").join("firmware")
     }
 
@@ -156,6 +543,23 @@ impl Context {
                 self.linker_script_path(module).display(),
                 self.target_dir().display(),
             ));
+            if self.pie {
+                flags.push_str(
+                    " -Crelocation-model=pie -Clink-arg=-pie -Clink-arg=-znotext -Clink-arg=--no-dynamic-linker",
+                );
+            }
+            if self.pauth {
+                flags.push_str(" -Zbranch-protection=bti,pac-ret");
+            }
+            if self.stack_protector {
+                flags.push_str(" -Zstack-protector=strong");
+            }
+        } else if USER_PROGRAMS.contains(&module) {
+            let runtime_dir = self.build_root.join("crates").join("user").join("runtime");
+            flags.push_str(&format!(
+                " -Clink-arg=-T{}",
+                runtime_dir.join("link.ld").display()
+            ));
         } else {
             flags.push_str(&format!(
                 " -Clink-arg=-T{}",
@@ -181,9 +585,86 @@ impl Context {
             cargo_args.push("--release".to_string());
         }
 
+        if USER_PROGRAMS.contains(&module) {
+            cargo_args.push("--target-dir".to_string());
+            cargo_args.push(self.user_target_dir().to_string_lossy().into_owned());
+        }
+
+        if module == "bootloader" || module == "chainloader" {
+            // their features are named after the boards, and pick which UART they report to
+            cargo_args.push("--no-default-features".to_string());
+            cargo_args.push("--features".to_string());
+            if self.rp1_uart {
+                cargo_args.push(format!("{},rp1-uart", self.board.name));
+            } else {
+                cargo_args.push(self.board.name.to_string());
+            }
+        }
+
+        if module == "kernel" {
+            // the board feature says where the early console's UART is
+            cargo_args.push("--no-default-features".to_string());
+            let mut features = vec![self.board.name];
+            if self.rp1_uart {
+                features.push("rp1-uart");
+            }
+            if self.pie {
+                features.push("pie");
+            }
+            if self.gdb {
+                features.push("gdb");
+            }
+            if self.pauth {
+                features.push("pauth");
+            }
+            if self.stack_protector {
+                features.push("stack-protector");
+            }
+            if self.heap_debug {
+                features.push("heap-debug");
+            }
+            if self.lockdep {
+                features.push("lockdep");
+            }
+            if self.bench {
+                features.push("bench");
+            }
+            if self.ktest {
+                features.push("ktest");
+            }
+            cargo_args.push("--features".to_string());
+            cargo_args.push(features.join(","));
+        }
+
         cargo_args
     }
 
+    /// Runs `cargo check` or `cargo clippy` over every crate in the workspace.
+    pub fn check_all(&self, subcommand: &str, deny_warnings: bool) -> anyhow::Result<()> {
+        let lint_args: &[&str] = if deny_warnings {
+            &["--", "-D", "warnings"]
+        } else {
+            &[]
+        };
+
+        for module in CROSS_CRATES {
+            log::info!("Running cargo {subcommand} on {module}");
+            cmd!(self.sh, "cargo")
+                .args(self.cargo_args(subcommand, module))
+                .args(lint_args)
+                .env("RUSTFLAGS", self.rustflags(module))
+                .run()?;
+        }
+
+        log::info!("Running cargo {subcommand} on the host crates");
+        let packages = HOST_CRATES.iter().flat_map(|name| ["-p", name]);
+        cmd!(self.sh, "cargo {subcommand} {packages...} {lint_args...}").run()?;
+
+        log::info!("All crates passed cargo {subcommand}!");
+
+        Ok(())
+    }
+
     pub fn build_bootloader(&self) -> anyhow::Result<()> {
         log::info!("Building bootloader with Cargo");
 
@@ -197,34 +678,287 @@ impl Context {
         Ok(())
     }
 
+    /// Builds the bootloader, the kernel image and, unless `--initrd` gave one, the initrd,
+    /// skipping the steps whose inputs haven't changed since they last succeeded. The initrd is
+    /// built alongside the rest.
     pub fn full_build_kernel(&self) -> anyhow::Result<()> {
-        self.build_bootloader()?;
+        let mut cache = BuildCache::load(self.build_hashes_path());
+
+        let initrd = match self.initrd {
+            Some(_) => None,
+            None => Some(cache.check("initrd", self.initrd_inputs()?, &[self.user_initrd_path()])),
+        };
+        std::thread::scope(|scope| {
+            let initrd = initrd.map(|step| {
+                let cx = self.clone();
+                scope.spawn(move || -> anyhow::Result<Step> {
+                    if !step.is_fresh() {
+                        cx.build_initrd()?;
+                    }
+                    Ok(step)
+                })
+            });
+
+            let kernel = self.build_bootloader_and_kernel(&mut cache);
+            if let Some(initrd) = initrd {
+                let step = initrd
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("building the initrd panicked"))??;
+                cache.finish(step)?;
+            }
+            kernel
+        })?;
+
+        cache.log_summary();
+        log::info!("Kernel build complete!");
 
-        log::info!("Building kernel with Cargo");
+        Ok(())
+    }
 
-        cmd!(self.sh, "cargo")
-            .args(self.cargo_args("build", "kernel"))
-            .env("RUSTFLAGS", self.rustflags("kernel"))
-            .run()?;
+    /// What goes into building `modules` with Cargo, from the crates in `crates` named by
+    /// `sources`: their source trees, the target spec, the workspace's manifest and lockfile,
+    /// the toolchain, and the flags each module is built with.
+    fn cargo_inputs(&self, modules: &[&str], sources: &[&str]) -> anyhow::Result<Inputs> {
+        let toolchain = cmd!(self.sh, "rustc -vV").read()?;
+        // passed through to the crates, which bake them in with `option_env!` (like `KADOS_LOG`)
+        let kados_vars: BTreeMap<_, _> = std::env::vars_os()
+            .filter(|(var, _)| var.as_encoded_bytes().starts_with(b"KADOS_"))
+            .collect();
+        let mut inputs = Inputs::new()
+            .value("the toolchain", toolchain)
+            .value("the KADOS_* environment variables", kados_vars)
+            .file("the target spec", &self.target_json_path())?;
+        for module in modules {
+            inputs = inputs
+                .value(&format!("RUSTFLAGS for {module}"), self.rustflags(module))
+                .value(
+                    &format!("the Cargo arguments for {module}"),
+                    self.cargo_args("build", module),
+                );
+        }
+        for file in [
+            "Cargo.toml",
+            "Cargo.lock",
+            "rust-toolchain.toml",
+            ".cargo/config.toml",
+        ] {
+            inputs = inputs.file(file, &self.build_root.join(file))?;
+        }
+        for source in sources {
+            let dir = self.build_root.join("crates").join(source);
+            inputs = inputs.dir(&format!("crates/{source}"), &dir)?;
+        }
+        Ok(inputs)
+    }
+
+    fn initrd_inputs(&self) -> anyhow::Result<Inputs> {
+        Ok(self
+            .cargo_inputs(&USER_PROGRAMS, &["user", "abi"])?
+            .value("the build date", build_epoch()))
+    }
+
+    /// Builds the bootloader, then the kernel it's linked into, then the kernel's raw binary
+    /// and debug symbols, each unless its inputs haven't changed.
+    fn build_bootloader_and_kernel(&self, cache: &mut BuildCache) -> anyhow::Result<()> {
+        let bootloader = cache.check(
+            "bootloader",
+            self.cargo_inputs(&["bootloader"], &["bootloader", "abi", "early-uart"])?,
+            &[self.bootloader_elf_path()],
+        );
+        if !bootloader.is_fresh() {
+            self.build_bootloader()?;
+        }
+        cache.finish(bootloader)?;
+
+        let MemSizes {
+            heap_size,
+            heap_max_size,
+            dma_size,
+        } = self.mem_sizes;
+        let kernel = cache.check(
+            "kernel",
+            self.cargo_inputs(&["kernel"], &["kernel", "abi", "early-uart"])?
+                .file("the bootloader", &self.bootloader_elf_path())?
+                .value("the build date", build_epoch())
+                .value("the memory sizes", (heap_size, heap_max_size, dma_size)),
+            &[self.kernel_elf_path()],
+        );
+        if !kernel.is_fresh() {
+            self.link_kernel()?;
+        }
+        cache.finish(kernel)?;
 
         let kernel_elf_path = self.kernel_elf_path();
         let kernel_bin_path = self.kernel_bin_path();
         let kernel_sym_path = self.kernel_sym_path();
+        let image = cache.check(
+            "kernel image",
+            Inputs::new().file("the kernel ELF", &kernel_elf_path)?,
+            &[kernel_bin_path.clone(), kernel_sym_path.clone()],
+        );
+        if !image.is_fresh() {
+            cmd!(
+                self.sh,
+                "llvm-objcopy --only-keep-debug {kernel_elf_path} {kernel_sym_path}"
+            )
+            .run()?;
 
-        cmd!(
+            cmd!(
+                self.sh,
+                "llvm-objcopy -O binary --strip-all {kernel_elf_path} {kernel_bin_path}"
+            )
+            .run()?;
+        }
+        cache.finish(image)?;
+
+        Ok(())
+    }
+
+    /// Builds the kernel and embeds its symbol table, relinking it with more room for the
+    /// table if it doesn't fit.
+    fn link_kernel(&self) -> anyhow::Result<()> {
+        let size_path = self.kernel_ksyms_size_path();
+        let mut ksyms_size = std::fs::read_to_string(&size_path)
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(KSYMS_PAGE_SIZE);
+        loop {
+            self.build_kernel(ksyms_size)?;
+
+            let symbols = self.kernel_symbols()?;
+            let table = symbols.encode();
+            if table.len() <= symbols.reserved {
+                self.embed_symbols(table, symbols.reserved)?;
+                break;
+            }
+
+            // the table only follows the code in the image, so growing it moves no functions
+            ksyms_size = table.len().next_multiple_of(KSYMS_PAGE_SIZE);
+            log::info!("Symbol table needs {ksyms_size} bytes, relinking");
+        }
+        std::fs::write(&size_path, ksyms_size.to_string())?;
+
+        Ok(())
+    }
+
+    /// Builds the userspace programs and packs them into a cpio archive at
+    /// [`user_initrd_path`](Self::user_initrd_path), each at the root and executable.
+    fn build_initrd(&self) -> anyhow::Result<()> {
+        let mut archive = Vec::new();
+        let mtime = u32::try_from(build_epoch())?;
+        for (ino, program) in (1..).zip(USER_PROGRAMS) {
+            log::info!("Building {program} with Cargo");
+            cmd!(self.sh, "cargo")
+                .args(self.cargo_args("build", program))
+                .env("RUSTFLAGS", self.rustflags(program))
+                .run()?;
+            // the kernel doesn't need the debug info, and it's most of the file
+            let elf = self.user_program_path(program);
+            let stripped = elf.with_extension("stripped");
+            cmd!(self.sh, "llvm-objcopy --strip-all {elf} {stripped}").run()?;
+            let data = std::fs::read(&stripped)?;
+            push_cpio_entry(&mut archive, program, ino, 0o100_755, mtime, &data)?;
+        }
+        push_cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 0, &[])?;
+
+        let initrd_path = self.user_initrd_path();
+        std::fs::write(&initrd_path, archive)?;
+        log::info!("Packed the userspace programs into {}", initrd_path.display());
+
+        Ok(())
+    }
+
+    fn build_kernel(&self, ksyms_size: usize) -> anyhow::Result<()> {
+        log::info!("Building kernel with Cargo");
+
+        let mut cmd = cmd!(self.sh, "cargo")
+            .args(self.cargo_args("build", "kernel"))
+            .env("RUSTFLAGS", self.rustflags("kernel"))
+            .env("KADOS_KSYMS_SIZE", ksyms_size.to_string())
+            .env("KADOS_BUILD_EPOCH", build_epoch().to_string());
+        let MemSizes {
+            heap_size,
+            heap_max_size,
+            dma_size,
+        } = self.mem_sizes;
+        for (var, size) in [
+            ("KADOS_HEAP_SIZE", heap_size),
+            ("KADOS_HEAP_MAX_SIZE", heap_max_size),
+            ("KADOS_DMA_SIZE", dma_size),
+        ] {
+            if let Some(size) = size {
+                cmd = cmd.env(var, size.to_string());
+            }
+        }
+        cmd.run()?;
+
+        Ok(())
+    }
+
+    /// Reads the kernel's function symbols and the space reserved for them from the kernel ELF.
+    fn kernel_symbols(&self) -> anyhow::Result<KernelSymbols> {
+        let kernel_elf_path = self.kernel_elf_path();
+        let output = cmd!(
             self.sh,
-            "llvm-objcopy --only-keep-debug {kernel_elf_path} {kernel_sym_path}"
+            "llvm-nm --defined-only --numeric-sort --print-size {kernel_elf_path}"
         )
-        .run()?;
+        .read()?;
+
+        let mut symbols = KernelSymbols::default();
+        let mut ksyms_start = None;
+        let mut ksyms_end = None;
+        for line in output.lines() {
+            // `addr [size] type name`; symbols from assembly have no size
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (addr, size, kind, name) = match fields[..] {
+                [addr, size, kind, name] => (addr, Some(size), kind, name),
+                [addr, kind, name] => (addr, None, kind, name),
+                _ => continue,
+            };
+            let addr = u64::from_str_radix(addr, 16)?;
+            match name {
+                "__ksyms_start" => ksyms_start = Some(addr),
+                "__ksyms_end" => ksyms_end = Some(addr),
+                _ => {}
+            }
+
+            let is_code = matches!(kind, "t" | "T" | "w" | "W");
+            // skip mapping symbols (`$x`, `$d`) and aliases of a symbol we already have
+            if !is_code
+                || name.starts_with('$')
+                || symbols
+                    .entries
+                    .last()
+                    .is_some_and(|&(last, ..)| last == addr)
+            {
+                continue;
+            }
+            let size = size.map_or(Ok(0), |size| u32::from_str_radix(size, 16))?;
+            symbols.entries.push((addr, size, name.to_string()));
+        }
+
+        let (Some(start), Some(end)) = (ksyms_start, ksyms_end) else {
+            anyhow::bail!("kernel has no .ksyms section; is the linker script up to date?");
+        };
+        symbols.link_addr = start;
+        symbols.reserved = usize::try_from(end - start)?;
+        Ok(symbols)
+    }
+
+    /// Writes the symbol table into the kernel ELF's `.ksyms` section.
+    fn embed_symbols(&self, mut table: Vec<u8>, reserved: usize) -> anyhow::Result<()> {
+        // the section is part of a loaded segment, so it can't change size
+        table.resize(reserved, 0);
+        let kernel_elf_path = self.kernel_elf_path();
+        let kernel_ksyms_path = self.kernel_ksyms_path();
+        std::fs::write(&kernel_ksyms_path, table)?;
 
         cmd!(
             self.sh,
-            "llvm-objcopy -O binary --strip-all {kernel_elf_path} {kernel_bin_path}"
+            "llvm-objcopy --update-section .ksyms={kernel_ksyms_path} {kernel_elf_path}"
         )
         .run()?;
 
-        log::info!("Kernel build complete!");
-
         Ok(())
     }
 
@@ -251,19 +985,12 @@ impl Context {
 
     pub fn flash_chainloader_rpi(&self, device: &str) -> anyhow::Result<()> {
         log::info!("Copying chainloader to SD card device {device} (will sudo)");
-        let chainloader_bin_path = self.chainloader_bin_path();
 
         cmd!(self.sh, "sudo umount {device}")
             .ignore_status()
             .run()?;
 
-        self.copy_common(device)?;
-
-        cmd!(
-            self.sh,
-            "sudo cp {chainloader_bin_path} /mnt/rpi-sd/kernel8.img"
-        )
-        .run()?;
+        self.copy_boot_files(device, self.chainloader_bin_path())?;
 
         cmd!(self.sh, "sudo umount {device}").run()?;
 
@@ -272,17 +999,14 @@ impl Context {
         Ok(())
     }
 
-    pub fn flash_kernel_rpi(&self, device: &str) -> anyhow::Result<()> {
+    pub fn flash_kernel(&self, device: &str) -> anyhow::Result<()> {
         log::info!("Copying kernel to SD card device {device} (will sudo)");
-        let kernel_bin_path = self.kernel_bin_path();
 
         cmd!(self.sh, "sudo umount {device}")
             .ignore_status()
             .run()?;
 
-        cmd!(self.sh, "sudo cp {kernel_bin_path} /mnt/rpi-sd/kernel8.img").run()?;
-
-        self.copy_common(device)?;
+        self.copy_boot_files(device, self.kernel_bin_path())?;
 
         cmd!(self.sh, "sudo umount {device}").run()?;
 
@@ -291,81 +1015,215 @@ impl Context {
         Ok(())
     }
 
-    fn copy_common(&self, device: &str) -> anyhow::Result<()> {
-        let firmware_dir = self.rpi_firmware_dir();
+    /// The firmware the board boots with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the board doesn't boot from an SD card.
+    fn firmware(&self) -> anyhow::Result<&'static Firmware> {
+        self.board
+            .firmware
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("{} doesn't boot from an SD card", self.board.name))
+    }
+
+    /// The files that go on the board's boot partition, as where each comes from and its path on
+    /// the partition, with `kernel` as the image the firmware boots.
+    ///
+    /// Writes `config.txt` and `cmdline.txt` out to the target directory first, since they
+    /// depend on the initrd and the kernel command line.
+    fn boot_files(&self, kernel: PathBuf) -> anyhow::Result<Vec<(PathBuf, &'static str)>> {
+        let firmware = self.firmware()?;
+        let boot_dir = self.firmware_dir().join("boot");
+        std::fs::create_dir_all(self.target_dir())?;
+
+        let mut config_txt = std::fs::read_to_string(self.build_root.join(firmware.config_txt))?;
+        let initrd = self.initrd_path();
+        if initrd.is_some() {
+            if !config_txt.ends_with('\n') {
+                config_txt.push('\n');
+            }
+            config_txt.push_str(&format!("initramfs initrd.img {INITRD_LOAD_ADDR:#x}\n"));
+        }
+        let config_txt_path = self.target_dir().join("config.txt");
+        std::fs::write(&config_txt_path, config_txt)?;
+
+        let cmdline_path = self.target_dir().join("cmdline.txt");
+        std::fs::write(&cmdline_path, format!("{}\n", self.cmdline))?;
+
+        let mut files = vec![
+            (config_txt_path, "config.txt"),
+            (cmdline_path, "cmdline.txt"),
+            (boot_dir.join(firmware.dtb), firmware.dtb),
+        ];
+        files.extend(
+            firmware
+                .files
+                .iter()
+                .map(|&file| (boot_dir.join(file), file)),
+        );
+        files.push((kernel, firmware.kernel_name));
+        if let Some(initrd) = initrd {
+            files.push((initrd, "initrd.img"));
+        }
+        Ok(files)
+    }
+
+    fn copy_boot_files(&self, device: &str, kernel: PathBuf) -> anyhow::Result<()> {
+        let files = self.boot_files(kernel)?;
 
         cmd!(self.sh, "sudo mkdir -p /mnt/rpi-sd").run()?;
         cmd!(self.sh, "sudo mount {device} /mnt/rpi-sd").run()?;
         cmd!(self.sh, "sudo rm -rf /mnt/rpi-sd/*").run()?;
-        cmd!(self.sh, "sudo mkdir -p /mnt/rpi-sd/overlays").run()?;
 
-        cmd!(self.sh, "sudo cp config.txt /mnt/rpi-sd/config.txt").run()?;
-        cmd!(
-            self.sh,
-            "sudo cp {firmware_dir}/boot/start4.elf /mnt/rpi-sd/start4.elf"
-        )
-        .run()?;
-        cmd!(
-            self.sh,
-            "sudo cp {firmware_dir}/boot/bootcode.bin /mnt/rpi-sd/bootcode.bin"
-        )
-        .run()?;
-        cmd!(
-            self.sh,
-            "sudo cp {firmware_dir}/boot/fixup4.dat /mnt/rpi-sd/fixup4.dat"
-        )
-        .run()?;
-        cmd!(
-            self.sh,
-            "sudo cp {firmware_dir}/boot/bcm2711-rpi-4-b.dtb /mnt/rpi-sd/bcm2711-rpi-4-b.dtb"
-        )
-        .run()?;
-        cmd!(
-            self.sh,
-            "sudo cp {firmware_dir}/boot/overlays/disable-bt.dtbo /mnt/rpi-sd/overlays/disable-bt.dtbo"
-        )
-        .run()?;
+        for (src, dest) in files {
+            if let Some((dir, _)) = dest.rsplit_once('/') {
+                cmd!(self.sh, "sudo mkdir -p /mnt/rpi-sd/{dir}").run()?;
+            }
+            cmd!(self.sh, "sudo cp {src} /mnt/rpi-sd/{dest}").run()?;
+        }
 
         Ok(())
     }
 
-    pub fn run_qemu_rpi(&self, debug_adapter: bool) -> anyhow::Result<()> {
-        log::info!("Running QEMU");
+    /// Writes an SD card image of `size` bytes to `output`: an MBR with a single FAT32 boot
+    /// partition holding the firmware, `config.txt`, the device tree and `kernel`.
+    pub fn build_image(&self, output: &Path, size: u64, kernel: PathBuf) -> anyhow::Result<()> {
+        log::info!("Writing SD card image to {}", output.display());
+        if size < IMAGE_MIN_SIZE {
+            anyhow::bail!("SD card images must be at least {IMAGE_MIN_SIZE} bytes");
+        }
+        let files = self.boot_files(kernel)?;
+
+        let mut image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)?;
+        image.set_len(size)?;
+        image.write_all(&mbr(size)?)?;
+
+        let mut partition =
+            Partition::new(image, IMAGE_PARTITION_OFFSET, size - IMAGE_PARTITION_OFFSET)?;
+        fatfs::format_volume(
+            &mut partition,
+            fatfs::FormatVolumeOptions::new()
+                .fat_type(fatfs::FatType::Fat32)
+                .volume_label(*b"KADOS-BOOT "),
+        )?;
+
+        let fs = fatfs::FileSystem::new(partition, fatfs::FsOptions::new())?;
+        let root = fs.root_dir();
+        for (src, dest) in files {
+            if let Some((dir, _)) = dest.rsplit_once('/') {
+                root.create_dir(dir)?;
+            }
+            let mut file = root.create_file(dest)?;
+            file.truncate()?;
+            file.write_all(&std::fs::read(&src)?)?;
+        }
+        drop(root);
+        fs.unmount()?;
 
-        let kernel_arg = format!("{}", self.kernel_bin_path().display());
-        let dtb_arg = format!(
-            "{}",
-            self.rpi_firmware_dir()
-                .join("boot")
-                .join("bcm2711-rpi-4-b.dtb")
-                .display()
-        );
+        log::info!("SD card image complete!");
 
-        let mut qemu_args = vec![];
+        Ok(())
+    }
 
-        qemu_args.extend([
+    /// How to run the kernel in QEMU, with its serial port connected to the QEMU character
+    /// device `serial`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if QEMU can't emulate the board.
+    fn qemu_args(&self, serial: &str) -> anyhow::Result<Vec<String>> {
+        let Some(qemu) = &self.board.qemu else {
+            anyhow::bail!("QEMU can't emulate {}", self.board.name);
+        };
+
+        // the boards' own cores don't have pointer authentication or BTI, but `max` does
+        let cpu = if self.pauth { "max" } else { qemu.cpu };
+        let memory = self.qemu.mem.as_deref().unwrap_or(qemu.memory);
+        let mut args = [
             "-M",
-            "raspi4b",
+            qemu.machine,
             "-cpu",
-            "cortex-a72",
+            cpu,
             "-kernel",
-            &kernel_arg,
-            "-dtb",
-            &dtb_arg,
-            "-D",
-            "target/log.txt",
-            "-d",
-            "int,guest_errors",
+            &format!("{}", self.kernel_bin_path().display()),
             "-m",
-            "2G",
+            memory,
             "-serial",
-            "stdio",
+            serial,
             "-semihosting",
-        ]);
+        ]
+        .map(String::from)
+        .to_vec();
+        let log = self.qemu.qemu_log.as_deref().unwrap_or("int,guest_errors");
+        if !log.is_empty() {
+            args.extend(["-D", "target/log.txt", "-d", log].map(String::from));
+        }
+        if let Some(smp) = self.qemu.smp {
+            args.push("-smp".to_string());
+            args.push(smp.to_string());
+        }
+        if let Some(display) = &self.qemu.display {
+            args.push("-display".to_string());
+            args.push(display.clone());
+        } else if self.qemu.headless {
+            args.push("-display".to_string());
+            args.push("none".to_string());
+        }
+        match self.qemu.net {
+            Net::None => {
+                if !self.qemu.hostfwd.is_empty() {
+                    anyhow::bail!("--hostfwd needs --net user");
+                }
+            }
+            Net::User => {
+                let Some(nic) = qemu.nic else {
+                    anyhow::bail!(
+                        "QEMU has no network card for {} the kernel can drive",
+                        self.board.name
+                    );
+                };
+                let mut netdev = "user,id=net0".to_string();
+                for rule in &self.qemu.hostfwd {
+                    netdev.push_str(",hostfwd=");
+                    netdev.push_str(rule);
+                }
+                args.push("-netdev".to_string());
+                args.push(netdev);
+                args.push("-device".to_string());
+                args.push(format!("{nic},netdev=net0"));
+            }
+        }
+        if qemu.firmware_dtb {
+            let dtb = self.firmware_dir().join("boot").join(self.firmware()?.dtb);
+            args.push("-dtb".to_string());
+            args.push(format!("{}", dtb.display()));
+        }
+        if !self.cmdline.is_empty() {
+            args.push("-append".to_string());
+            args.push(self.cmdline.clone());
+        }
+        if let Some(initrd) = self.initrd_path() {
+            args.push("-initrd".to_string());
+            args.push(format!("{}", initrd.display()));
+        }
+        args.extend(self.qemu.extra_args.iter().cloned());
 
+        Ok(args)
+    }
+
+    pub fn run_qemu(&self, debug_adapter: bool) -> anyhow::Result<()> {
+        log::info!("Running QEMU");
+
+        let mut qemu_args = self.qemu_args("stdio")?;
         if debug_adapter {
-            qemu_args.push("-s");
-            qemu_args.push("-S");
+            qemu_args.push("-s".to_string());
+            qemu_args.push("-S".to_string());
         }
 
         cmd!(self.sh, "qemu-system-aarch64").args(qemu_args).run()?;
@@ -373,18 +1231,110 @@ impl Context {
         Ok(())
     }
 
-    pub fn build_dependencies_rpi(&self) -> anyhow::Result<()> {
-        let firmware_dir = self.rpi_firmware_dir();
+    /// Runs a kernel built with the `ktest` feature in QEMU, echoing its serial output, and
+    /// fails unless it reports that every test passed.
+    #[allow(clippy::print_stdout)]
+    pub fn test_qemu(&self, timeout: Duration) -> anyhow::Result<()> {
+        log::info!("Running kernel tests in QEMU");
+
+        let mut qemu = Command::new("qemu-system-aarch64")
+            .args(self.qemu_args("stdio")?)
+            .args(["-display", "none"])
+            .current_dir(&self.build_root)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = qemu.stdout.take().expect("stdout is piped");
+
+        // read on another thread, so a kernel that hangs can be timed out
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let deadline = Instant::now() + timeout;
+        let mut report = TestReport::default();
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => {
+                    let line = line.trim_end();
+                    println!("{line}");
+                    report.parse_line(line);
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    qemu.kill()?;
+                    qemu.wait()?;
+                    anyhow::bail!("kernel tests didn't finish within {timeout:?}");
+                }
+            }
+        }
+        let status = qemu.wait()?;
+
+        report.check()?;
+        if !status.success() {
+            anyhow::bail!("QEMU exited with {status} after the tests passed");
+        }
+        log::info!("All {} kernel tests passed!", report.passed);
+
+        Ok(())
+    }
+
+    /// Runs the kernel in QEMU, stopped at its first instruction, and GDB attached to it with
+    /// the kernel's symbols and the commands in [`gdb`]. QEMU is stopped when GDB exits.
+    ///
+    /// The kernel's serial output goes to `target/serial.log`, so it doesn't fight GDB for the
+    /// terminal.
+    pub fn run_gdb(&self) -> anyhow::Result<()> {
+        let gdb = gdb::find()?;
+        let script = self.gdb_script_path();
+        gdb::write_script(&script, &self.kernel_sym_path())?;
+
+        log::info!("Running QEMU, with the kernel's serial output in target/serial.log");
+        let mut qemu = Command::new("qemu-system-aarch64")
+            .args(self.qemu_args("file:target/serial.log")?)
+            .args(["-s", "-S"])
+            .current_dir(&self.build_root)
+            .stdin(Stdio::null())
+            // or Ctrl-C in GDB would kill it too
+            .process_group(0)
+            .spawn()?;
+
+        log::info!("Running {gdb}");
+        let status = Command::new(gdb)
+            .arg("-q")
+            .arg("-x")
+            .arg(&script)
+            .current_dir(&self.build_root)
+            .status();
+
+        qemu.kill().ok();
+        qemu.wait()?;
+        let status = status?;
+        if !status.success() {
+            anyhow::bail!("{gdb} exited with {status}");
+        }
+
+        Ok(())
+    }
+
+    pub fn build_dependencies(&self) -> anyhow::Result<()> {
+        let Some(firmware) = &self.board.firmware else {
+            return Ok(());
+        };
+        let firmware_dir = self.firmware_dir();
 
         log::info!("Building dependencies");
 
         if !firmware_dir.exists() {
-            log::info!("Downloading RPi Firmware");
-            cmd!(
-                self.sh,
-                "git clone --depth=1 https://github.com/raspberrypi/firmware.git {firmware_dir}"
-            )
-            .run()?;
+            log::info!("Downloading {} firmware", self.board.name);
+            let repo = firmware.repo;
+            cmd!(self.sh, "git clone --depth=1 {repo} {firmware_dir}").run()?;
         } else {
             let _guard = self.sh.push_dir(&firmware_dir);
             cmd!(self.sh, "git fetch").run()?;
@@ -394,6 +1344,216 @@ impl Context {
     }
 }
 
+/// What the kernel reported while running its tests. See the kernel's `ktest` module for the
+/// format.
+#[derive(Default)]
+pub struct TestReport {
+    passed: usize,
+    failed: Vec<String>,
+    finished: bool,
+    aborted: bool,
+}
+
+impl TestReport {
+    pub fn parse_line(&mut self, line: &str) {
+        let Some(line) = line.strip_prefix("ktest: ") else {
+            return;
+        };
+        if let Some(name) = line.strip_suffix(" ... ok") {
+            log::debug!("passed: {name}");
+            self.passed += 1;
+        } else if let Some(name) = line.strip_suffix(" ... FAILED") {
+            self.failed.push(name.to_string());
+        } else if line.ends_with(" failed") && line.contains(" passed, ") {
+            self.finished = true;
+        } else if line == "aborted after a panic" {
+            self.aborted = true;
+        }
+    }
+
+    /// Fails unless the kernel ran every test and they all passed.
+    pub fn check(&self) -> anyhow::Result<()> {
+        for name in &self.failed {
+            log::error!("FAILED: {name}");
+        }
+        if self.aborted {
+            anyhow::bail!("the kernel panicked while running its tests");
+        }
+        if !self.finished {
+            anyhow::bail!("the kernel exited without finishing its tests");
+        }
+        if !self.failed.is_empty() {
+            anyhow::bail!(
+                "{} kernel tests failed, {} passed",
+                self.failed.len(),
+                self.passed
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The granularity the kernel's symbol table space is reserved in.
+const KSYMS_PAGE_SIZE: usize = 4096;
+
+/// The kernel's function symbols, to be embedded in the image for the kernel's `symbols`
+/// module. See there for the table format.
+#[derive(Default)]
+pub struct KernelSymbols {
+    /// `(address, size, mangled name)`, sorted by address.
+    entries: Vec<(u64, u32, String)>,
+    /// The link-time address of the `.ksyms` section.
+    link_addr: u64,
+    /// The size of the `.ksyms` section.
+    reserved: usize,
+}
+
+impl KernelSymbols {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut names = Vec::new();
+        let mut table = Vec::new();
+        table.extend_from_slice(b"KSYM");
+        table.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        table.extend_from_slice(&self.link_addr.to_le_bytes());
+        for (addr, size, name) in &self.entries {
+            table.extend_from_slice(&addr.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        table.extend_from_slice(&names);
+        table
+    }
+}
+
+/// The crates built for the kernel's target.
+const CROSS_CRATES: [&str; 4] = ["bootloader", "chainloader", "kernel", "init"];
+/// The userspace programs, which are packed into the initrd unless `--initrd` gives one.
+const USER_PROGRAMS: [&str; 1] = ["init"];
+/// The crates built for the host. The ABI crate is built for both.
+const HOST_CRATES: [&str; 3] = ["kados-abi", "builder", "loader"];
+
+/// Where the boot partition starts in an SD card image, aligned to the card's erase blocks.
+const IMAGE_PARTITION_OFFSET: u64 = 1024 * 1024;
+/// The smallest image that fits a FAT32 partition.
+const IMAGE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Appends an entry to a cpio archive in the "newc" format the kernel reads, padding its path
+/// and contents to multiples of 4 bytes.
+fn push_cpio_entry(
+    archive: &mut Vec<u8>,
+    path: &str,
+    ino: u32,
+    mode: u32,
+    mtime: u32,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let name_size = u32::try_from(path.len() + 1)?;
+    let file_size = u32::try_from(data.len())?;
+    let nlink = u32::from(mode != 0);
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize and check
+    let fields = [
+        ino, mode, 0, 0, nlink, mtime, file_size, 0, 0, 0, 0, name_size, 0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(path.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    Ok(())
+}
+
+/// Builds a master boot record with a single FAT32 partition spanning an image of `size`
+/// bytes from [`IMAGE_PARTITION_OFFSET`].
+fn mbr(size: u64) -> anyhow::Result<[u8; 512]> {
+    let start = u32::try_from(IMAGE_PARTITION_OFFSET / 512)?;
+    let sectors = u32::try_from((size - IMAGE_PARTITION_OFFSET) / 512)?;
+
+    let mut mbr = [0u8; 512];
+    let entry = &mut mbr[446..462];
+    // CHS addresses say "use the LBA ones", as for any disk this size
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = 0x0c; // FAT32 with LBA
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+    Ok(mbr)
+}
+
+/// A window onto the part of an image file a partition takes up, so it can be formatted and
+/// filled as if it were a disk of its own.
+struct Partition {
+    image: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Partition {
+    fn new(mut image: File, start: u64, len: u64) -> io::Result<Self> {
+        image.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            image,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Limits a read or write of `len` bytes to the end of the partition.
+    fn clamp(&self, len: usize) -> usize {
+        usize::try_from(self.len - self.pos).map_or(len, |left| len.min(left))
+    }
+}
+
+impl Read for Partition {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.clamp(buf.len());
+        let n = self.image.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Partition {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.clamp(buf.len());
+        let n = self.image.write(&buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.image.flush()
+    }
+}
+
+impl Seek for Partition {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+        };
+        let Some(pos) = pos.filter(|&pos| pos <= self.len) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek outside the partition",
+            ));
+        };
+        self.image.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
 #[allow(clippy::print_stdout)]
 pub fn check_dependencies() -> anyhow::Result<()> {
     log::info!("Checking dependencies...");
@@ -412,6 +1572,13 @@ pub fn check_dependencies() -> anyhow::Result<()> {
         );
         return Err(e.into());
     }
+    if let Err(e) = cmd!(sh, "llvm-nm --version").run() {
+        log::error!("`llvm-nm` is not installed or not found in PATH.");
+        log::error!(
+            "Please install `llvm-tools` from your package manager or via `rustup component add llvm-tools-preview`"
+        );
+        return Err(e.into());
+    }
     if let Err(e) = cmd!(sh, "qemu-system-aarch64 --version").run() {
         log::error!("`qemu-system-aarch64` is not installed or not found in PATH.");
         log::error!(
@@ -431,48 +1598,218 @@ fn main() -> anyhow::Result<()> {
         .init();
     let args = Args::parse();
 
+    // converting a trace needs none of the build tools
+    if let Mode::Trace { serial_log, output } = &args.mode {
+        return trace::convert(serial_log, output);
+    }
     check_dependencies()?;
 
     match args.mode {
         Mode::CheckDependencies => {} // handled above
         Mode::Build { release } => {
-            let cx = Context::new(release)?;
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes);
             cx.full_build_kernel()?;
         }
         Mode::Debug { release } => {
-            let cx = Context::new(release)?;
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes)
+                .with_qemu(args.qemu.clone());
+            cx.full_build_kernel()?;
+            cx.build_dependencies()?;
+            cx.run_qemu(true)?;
+        }
+        Mode::Gdb { release } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes)
+                .with_qemu(args.qemu.clone());
             cx.full_build_kernel()?;
-            cx.build_dependencies_rpi()?;
-            cx.run_qemu_rpi(true)?;
+            cx.build_dependencies()?;
+            cx.run_gdb()?;
         }
         Mode::Run { release } => {
-            let cx = Context::new(release)?;
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes)
+                .with_qemu(args.qemu.clone());
+            cx.full_build_kernel()?;
+            cx.build_dependencies()?;
+            cx.run_qemu(false)?;
+        }
+        Mode::Test { release, timeout } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_ktest(true)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_mem_sizes(args.mem_sizes)
+                .with_qemu(args.qemu.clone());
             cx.full_build_kernel()?;
-            cx.build_dependencies_rpi()?;
-            cx.run_qemu_rpi(false)?;
+            cx.build_dependencies()?;
+            cx.test_qemu(Duration::from_secs(timeout))?;
+        }
+        Mode::Check {
+            release,
+            all_features,
+        } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie || all_features)
+                .with_gdb(args.gdb || all_features)
+                .with_pauth(args.pauth || all_features)
+                .with_stack_protector(args.stack_protector || all_features)
+                .with_heap_debug(args.heap_debug || all_features)
+                .with_lockdep(args.lockdep || all_features)
+                .with_bench(args.bench || all_features)
+                .with_ktest(all_features);
+            cx.check_all("check", false)?;
+        }
+        Mode::Clippy {
+            release,
+            all_features,
+            deny_warnings,
+        } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie || all_features)
+                .with_gdb(args.gdb || all_features)
+                .with_pauth(args.pauth || all_features)
+                .with_stack_protector(args.stack_protector || all_features)
+                .with_heap_debug(args.heap_debug || all_features)
+                .with_lockdep(args.lockdep || all_features)
+                .with_bench(args.bench || all_features)
+                .with_ktest(all_features);
+            cx.check_all("clippy", deny_warnings)?;
         }
         Mode::Flash { device, release } => {
-            let cx = Context::new(release)?;
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes);
             cx.full_build_kernel()?;
-            cx.build_dependencies_rpi()?;
-            cx.flash_kernel_rpi(device.as_str())?;
+            cx.build_dependencies()?;
+            cx.flash_kernel(device.as_str())?;
+        }
+        Mode::Image {
+            output,
+            release,
+            size,
+            chainloader,
+        } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes);
+            let kernel = if chainloader {
+                cx.build_chainloader_rpi()?;
+                cx.chainloader_bin_path()
+            } else {
+                cx.full_build_kernel()?;
+                cx.kernel_bin_path()
+            };
+            cx.build_dependencies()?;
+            cx.build_image(&output, size, kernel)?;
         }
         Mode::FlashChainloader { device } => {
-            let cx = Context::new(true)?;
+            let cx = Context::new(true)?
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone());
             cx.build_chainloader_rpi()?;
             cx.flash_chainloader_rpi(device.as_str())?;
         }
-        Mode::Load { release } => {
-            let cx = Context::new(release)?;
+        Mode::Load { release, compress } => {
+            let cx = Context::new(release)?
+                .with_pie(args.pie)
+                .with_gdb(args.gdb)
+                .with_pauth(args.pauth)
+                .with_stack_protector(args.stack_protector)
+                .with_heap_debug(args.heap_debug)
+                .with_lockdep(args.lockdep)
+                .with_board(args.board)
+                .with_rp1_uart(args.rp1_uart)
+                .with_cmdline(args.cmdline.clone())
+                .with_initrd(args.initrd.clone())
+                .with_bench(args.bench)
+                .with_mem_sizes(args.mem_sizes);
             cx.full_build_kernel()?;
             let kernel_bin_path = cx.kernel_bin_path();
             let kernel_sym_path = cx.kernel_sym_path();
+            let compress = compress.then_some("--compress");
             cmd!(
                 cx.sh,
-                "cargo loader client {kernel_bin_path} --symbol-path {kernel_sym_path}"
+                "cargo loader client {kernel_bin_path} --symbol-path {kernel_sym_path} {compress...}"
             )
             .run()?;
         }
+        Mode::Trace { .. } => {} // handled above
     }
 
     Ok(())
@@ -0,0 +1,204 @@
+//! Converts a kernel event trace, dumped over serial with `trace dump`, into a Chrome trace
+//! (the JSON "Trace Event Format"), which Perfetto and `chrome://tracing` both open.
+//!
+//! The trace has a track for each CPU, showing which task was running on it and the IRQs it
+//! handled, and a track for each task, showing the system calls it made.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    path::Path,
+};
+
+use kados_abi::{
+    syscall::Sysno,
+    trace::{LINE_PREFIX, TRACE_VERSION, TraceEvent, TraceRecord},
+};
+
+/// The Chrome trace "process" holding the CPU tracks.
+const CPUS_PID: u32 = 0;
+/// The Chrome trace "process" holding the task tracks.
+const TASKS_PID: u32 = 1;
+/// The track of system calls made before a context switch showed which task made them.
+const UNKNOWN_TASK: i64 = -1;
+
+/// Reads the last trace dump in the serial log at `log`, and writes it to `output` as a
+/// Chrome trace.
+pub fn convert(log: &Path, output: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(log)?;
+    let (mut records, lost) = parse_dump(&text)?;
+    if lost != 0 {
+        log::warn!("{lost} records were overwritten before the dump");
+    }
+    // each CPU's records are already in order, so a stable sort keeps them so
+    records.sort_by_key(|record| record.timestamp);
+
+    let events = chrome_events(&records);
+    let mut json = String::from("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n");
+    json.push_str(&events.join(",\n"));
+    json.push_str("\n]}\n");
+    std::fs::write(output, json)?;
+
+    log::info!(
+        "Wrote {} records as {} trace events to {}",
+        records.len(),
+        events.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Returns the records of the last complete dump in `text`, and how many were lost.
+fn parse_dump(text: &str) -> anyhow::Result<(Vec<TraceRecord>, u64)> {
+    let mut dump = None;
+    let mut current: Option<Vec<TraceRecord>> = None;
+    for (number, line) in text.lines().enumerate() {
+        // the prefix may not start the line, if something else was printed without a newline
+        let Some(start) = line.find(LINE_PREFIX) else {
+            continue;
+        };
+        let line = line[start + LINE_PREFIX.len()..].trim_end();
+        if let Some(version) = line.strip_prefix("begin ") {
+            if version.parse() != Ok(TRACE_VERSION) {
+                anyhow::bail!("line {}: unknown trace version {version}", number + 1);
+            }
+            current = Some(Vec::new());
+        } else if let Some(counts) = line.strip_prefix("end ") {
+            let Some(records) = current.take() else {
+                continue;
+            };
+            let (count, lost) = counts
+                .split_once(' ')
+                .and_then(|(count, lost)| Some((count.parse().ok()?, lost.parse().ok()?)))
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed end of dump", number + 1))?;
+            if records.len() != count {
+                anyhow::bail!(
+                    "line {}: the dump has {} records, but should have {count}",
+                    number + 1,
+                    records.len()
+                );
+            }
+            dump = Some((records, lost));
+        } else if let Some(records) = &mut current {
+            let record = decode_hex(line)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed record `{line}`", number + 1))?;
+            records.push(record);
+        }
+    }
+    dump.ok_or_else(|| anyhow::anyhow!("no complete trace dump found; run `trace dump` first"))
+}
+
+fn decode_hex(hex: &str) -> Option<TraceRecord> {
+    if hex.len() != TraceRecord::SIZE * 2 {
+        return None;
+    }
+    let mut bytes = [0; TraceRecord::SIZE];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().as_chunks::<2>().0) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(TraceRecord::decode(&bytes))
+}
+
+/// Formats a time since boot in nanoseconds as the microseconds Chrome traces use.
+fn micros(nanos: u64) -> String {
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+fn syscall_name(sysno: u32) -> String {
+    match usize::try_from(sysno)
+        .ok()
+        .and_then(|n| Sysno::try_from(n).ok())
+    {
+        Some(sysno) => format!("{sysno:?}").to_lowercase(),
+        None => format!("syscall {sysno}"),
+    }
+}
+
+/// Turns the records, in time order, into Chrome trace events.
+fn chrome_events(records: &[TraceRecord]) -> Vec<String> {
+    let mut events = Vec::new();
+    // which task each CPU is running, and since when
+    let mut running: BTreeMap<u16, (u32, u64)> = BTreeMap::new();
+    let mut cpus = BTreeSet::new();
+    let mut tasks = BTreeSet::new();
+
+    for record in records {
+        let ts = micros(record.timestamp);
+        let cpu = record.cpu;
+        cpus.insert(cpu);
+        let task = running
+            .get(&cpu)
+            .map_or(UNKNOWN_TASK, |&(pid, _)| i64::from(pid));
+        match TraceEvent::try_from(record.event) {
+            Ok(TraceEvent::IrqEntry) => events.push(format!(
+                r#"{{"name":"irq {}","cat":"irq","ph":"B","ts":{ts},"pid":{CPUS_PID},"tid":{cpu}}}"#,
+                record.arg0
+            )),
+            Ok(TraceEvent::IrqExit) => events.push(format!(
+                r#"{{"ph":"E","ts":{ts},"pid":{CPUS_PID},"tid":{cpu}}}"#
+            )),
+            Ok(TraceEvent::ContextSwitch) => {
+                if let Some((pid, since)) = running.remove(&cpu) {
+                    events.push(task_slice(cpu, pid, since, record.timestamp));
+                }
+                let next = record.arg1 as u32;
+                running.insert(cpu, (next, record.timestamp));
+                tasks.insert(i64::from(next));
+            }
+            Ok(TraceEvent::SyscallEntry) => {
+                tasks.insert(task);
+                events.push(format!(
+                    r#"{{"name":"{}","cat":"syscall","ph":"B","ts":{ts},"pid":{TASKS_PID},"tid":{task},"args":{{"arg0":"{:#x}"}}}}"#,
+                    syscall_name(record.arg0),
+                    record.arg1
+                ));
+            }
+            Ok(TraceEvent::SyscallExit) => {
+                tasks.insert(task);
+                events.push(format!(
+                    r#"{{"ph":"E","ts":{ts},"pid":{TASKS_PID},"tid":{task},"args":{{"result":{}}}}}"#,
+                    record.arg1 as i64
+                ));
+            }
+            Err(event) => log::warn!("skipping a record of unknown event {event}"),
+        }
+    }
+
+    // whatever was running when the trace stopped ran until the end of it
+    let end = records.last().map_or(0, |record| record.timestamp);
+    for (cpu, (pid, since)) in running {
+        events.push(task_slice(cpu, pid, since, end));
+    }
+
+    for (pid, name) in [(CPUS_PID, "CPUs"), (TASKS_PID, "tasks")] {
+        events.push(format!(
+            r#"{{"name":"process_name","ph":"M","pid":{pid},"args":{{"name":"{name}"}}}}"#
+        ));
+    }
+    for cpu in cpus {
+        events.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":{CPUS_PID},"tid":{cpu},"args":{{"name":"CPU {cpu}"}}}}"#
+        ));
+    }
+    for task in tasks {
+        let mut name = String::new();
+        if task == UNKNOWN_TASK {
+            name.push_str("unknown task");
+        } else {
+            write!(name, "pid {task}").ok();
+        }
+        events.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":{TASKS_PID},"tid":{task},"args":{{"name":"{name}"}}}}"#
+        ));
+    }
+    events
+}
+
+/// A complete event for task `pid` running on `cpu` from `start` to `end`.
+fn task_slice(cpu: u16, pid: u32, start: u64, end: u64) -> String {
+    format!(
+        r#"{{"name":"pid {pid}","cat":"task","ph":"X","ts":{},"dur":{},"pid":{CPUS_PID},"tid":{cpu}}}"#,
+        micros(start),
+        micros(end.saturating_sub(start))
+    )
+}
@@ -1,15 +1,20 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use indicatif::{ProgressBar, ProgressStyle};
+use kados_abi::loader::{self, Crc32, FrameHeader, ImageHeader};
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpStream, tcp::WriteHalf},
+    sync::mpsc,
+    time::{Duration, timeout},
 };
 use xmas_elf::{ElfFile, sections::SectionData, symbol_table::Entry};
 
+use crate::monitor::{Input, MonitorConfig, ProgressDecoder};
+
 #[derive(Debug, clap::Args)]
 pub struct ClientConfig {
     /// Path to the kernel binary to send over serial
@@ -20,21 +25,42 @@ pub struct ClientConfig {
     /// Address to connect to
     #[clap(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1235))]
     addr: SocketAddr,
-    /// Chunk size for kernel transfer
-    #[clap(long, default_value_t = 16*1024)]
+    /// Compress the kernel with LZ4 before sending it
+    #[clap(long, default_value_t = false)]
+    compress: bool,
+    /// Chunk size for kernel transfer, at most 4096
+    #[clap(long, default_value_t = loader::MAX_CHUNK)]
     chunk_size: usize,
+    #[clap(flatten)]
+    monitor: MonitorConfig,
+}
+
+/// How the monitor was left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorExit {
+    Quit,
+    /// The user asked for the kernel to be sent again.
+    Resend,
 }
 
 pub struct Client {
+    kernel_path: PathBuf,
+    compress: bool,
     kernel: Vec<u8>,
     symbols: Option<Vec<u8>>,
     conn: TcpStream,
     chunk_size: usize,
+    monitor: MonitorConfig,
+    stdin: mpsc::UnboundedReceiver<String>,
+    /// Set when the monitor saw the chainloader announce itself, so there's no need to wait for
+    /// it before sending the kernel.
+    chainloader_ready: bool,
+    progress: ProgressDecoder,
 }
 
 impl Client {
     pub async fn connect(config: &ClientConfig) -> io::Result<Self> {
-        let kernel = tokio::fs::read(&config.kernel_path).await?;
+        let kernel = read_kernel(&config.kernel_path, config.compress).await?;
         let symbols = if let Some(symbol_path) = &config.symbol_path {
             Some(tokio::fs::read(symbol_path).await?)
         } else {
@@ -47,13 +73,25 @@ impl Client {
         log::info!("Connected to server at {}", config.addr);
 
         Ok(Self {
+            kernel_path: config.kernel_path.clone(),
+            compress: config.compress,
             kernel,
             symbols,
             conn,
-            chunk_size: config.chunk_size,
+            chunk_size: config.chunk_size.clamp(1, loader::MAX_CHUNK),
+            monitor: config.monitor.clone(),
+            stdin: stdin_lines(),
+            chainloader_ready: false,
+            progress: ProgressDecoder::default(),
         })
     }
 
+    /// Reads the kernel from disk again, in case it has been rebuilt.
+    pub async fn reload_kernel(&mut self) -> io::Result<()> {
+        self.kernel = read_kernel(&self.kernel_path, self.compress).await?;
+        Ok(())
+    }
+
     pub async fn send_kernel(&mut self) -> io::Result<()> {
         log::info!("Sending kernel to server...");
         tokio::select! {
@@ -73,77 +111,82 @@ impl Client {
 
     async fn send_kernel_inner(&mut self) -> io::Result<()> {
         let (mut reader, mut writer) = self.conn.split();
+        let header = ImageHeader::new(&self.kernel);
 
-        log::info!("Power cycle your Pi now!");
-        let mut num_breaks = 0;
-        while num_breaks < 3 {
-            let c = reader.read_u8().await?;
-            if c == b'\x03' {
-                num_breaks += 1;
-            } else {
-                num_breaks = 0;
-            }
+        if !self.chainloader_ready {
+            log::info!("Power cycle your Pi now!");
         }
+        for _ in 0..loader::MAX_RETRIES {
+            if !std::mem::take(&mut self.chainloader_ready) {
+                let mut num_breaks = 0;
+                while num_breaks < loader::BREAK_COUNT {
+                    let c = reader.read_u8().await?;
+                    self.progress.log(&[c]);
+                    if c == loader::BREAK {
+                        num_breaks += 1;
+                    } else {
+                        num_breaks = 0;
+                    }
+                }
+            }
 
-        log::info!("Sending kernel size ({:#x} bytes)", self.kernel.len());
-        writer
-            .write_all(&(self.kernel.len() as u32).to_le_bytes())
-            .await?;
+            log::info!(
+                "Sending kernel header ({:#x} bytes, CRC {:#010x})",
+                header.len,
+                header.crc
+            );
+            send_frame(&mut reader, &mut writer, 0, &header.encode()).await?;
 
-        let mut ok = [0u8; 2];
-        reader.read_exact(&mut ok).await?;
-        if &ok != b"OK" {
-            return Err(io::Error::other("Error in kernel transfer"));
-        }
+            log::info!("Sending kernel...");
+            let pbar = ProgressBar::new(self.kernel.len() as u64).with_style(
+                ProgressStyle::default_bar()
+                    .template("[{elapsed_precise}/{duration_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec})")
+                    .unwrap(),
+            );
+            for (seq, chunk) in (1..).zip(self.kernel.chunks(self.chunk_size)) {
+                send_frame(&mut reader, &mut writer, seq, chunk).await?;
+                pbar.inc(chunk.len() as u64);
+            }
+            pbar.finish();
 
-        log::info!("Sending kernel...");
-
-        let it = self.kernel.chunks(self.chunk_size);
-        let pbar = ProgressBar::new(self.kernel.len() as u64).with_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}/{duration_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec})")
-                .unwrap(),
-        );
-        let mut echo = vec![0u8; self.chunk_size];
-        for chunk in it {
-            writer.write_all(chunk).await?;
-
-            let current_chunk_size = chunk.len();
-            reader.read_exact(&mut echo[..current_chunk_size]).await?;
-            if &echo[..current_chunk_size] != chunk {
+            let mut result = [0u8; 4];
+            reader.read_exact(&mut result).await?;
+            if result == loader::DONE {
+                log::info!("Kernel sent!");
+                return Ok(());
+            } else if result == loader::BAD_IMAGE {
+                log::warn!("Chainloader got a corrupted kernel, sending it again");
+            } else {
                 return Err(io::Error::other("Error in kernel transfer"));
             }
-            pbar.inc(current_chunk_size as u64);
-        }
-        pbar.finish();
-        drop(echo);
-        let mut ty = [0u8; 4];
-        reader.read_exact(&mut ty).await?;
-        if &ty != b"TY:)" {
-            return Err(io::Error::other("Error in kernel transfer"));
         }
 
-        log::info!("Kernel sent!");
-
-        Ok(())
+        Err(io::Error::other("Kernel corrupted too many times"))
     }
 
-    pub async fn monitor(&mut self) -> io::Result<()> {
-        tokio::select! {
+    /// Shows the board's output and sends it lines typed on stdin, until the user quits or asks
+    /// for the kernel to be sent again.
+    pub async fn monitor(&mut self) -> io::Result<MonitorExit> {
+        log::info!("Monitoring; type ~? for help");
+        let exit = tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 log::info!("Received Ctrl+C, exiting...");
+                MonitorExit::Quit
             }
             res = self.monitor_inner() => {
-                if let Err(e) = res {
+                res.unwrap_or_else(|e| {
                     log::error!("Error in monitor: {e}");
-                }
+                    MonitorExit::Quit
+                })
             }
+        };
+        if exit == MonitorExit::Quit {
+            self.conn.shutdown().await.ok();
         }
-        self.conn.shutdown().await.ok();
-        Ok(())
+        Ok(exit)
     }
 
-    async fn monitor_inner(&mut self) -> io::Result<()> {
+    async fn monitor_inner(&mut self) -> io::Result<MonitorExit> {
         let symbols = self
             .symbols
             .as_ref()
@@ -153,18 +196,170 @@ impl Client {
                 log::error!("Error parsing symbol file: {e}");
                 io::Error::new(io::ErrorKind::InvalidData, e)
             })?;
+        let mut output = self.monitor.output_filter();
+        let mut crashes = self.monitor.crash_capture();
+        let mut log_file = self.monitor.open_log_file().await?;
 
         let (mut rx, mut tx) = self.conn.split();
         let mut buf = vec![0u8; self.chunk_size];
+        let mut text = Vec::with_capacity(self.chunk_size);
+        let mut passed = Vec::with_capacity(self.chunk_size);
+        let mut num_breaks = 0;
         loop {
-            let size = rx.read(&mut buf).await?;
-            let data = &buf[..size];
-            let is_symbol_request =
-                maybe_handle_symbol_request(symbols.as_ref(), data, &mut tx).await?;
-            if !is_symbol_request {
-                tokio::io::stdout().write_all(data).await?;
-                tokio::io::stdout().flush().await?;
+            tokio::select! {
+                size = rx.read(&mut buf) => {
+                    let size = size?;
+                    if size == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    let data = &buf[..size];
+                    if maybe_handle_symbol_request(symbols.as_ref(), data, &mut tx).await? {
+                        continue;
+                    }
+
+                    for &b in data {
+                        num_breaks = if b == loader::BREAK { num_breaks + 1 } else { 0 };
+                        if num_breaks == loader::BREAK_COUNT {
+                            log::info!("The chainloader is waiting for a kernel; type ~r to send it");
+                            self.chainloader_ready = true;
+                        }
+                    }
+
+                    passed.clear();
+                    for (header, payload) in crashes.feed(data, &mut passed) {
+                        match crashes.save(&header, &payload).await {
+                            Ok(path) => log::info!("Saved a crash record to {}", path.display()),
+                            Err(e) => log::error!("Error saving a crash record: {e}"),
+                        }
+                    }
+                    let passed = self.progress.log(&passed);
+
+                    text.clear();
+                    output.filter(&passed, &mut text);
+                    tokio::io::stdout().write_all(&text).await?;
+                    tokio::io::stdout().flush().await?;
+                    if let Some(log_file) = &mut log_file {
+                        log_file.write(&text).await?;
+                    }
+                }
+                line = self.stdin.recv() => {
+                    let Some(line) = line else {
+                        return Ok(MonitorExit::Quit);
+                    };
+                    match Input::parse(&line) {
+                        Input::Quit => return Ok(MonitorExit::Quit),
+                        Input::Resend => return Ok(MonitorExit::Resend),
+                        Input::Help => println!("{}", Input::HELP),
+                        Input::Unknown(escape) => {
+                            log::warn!("Unknown escape ~{escape}; type ~? for help");
+                        }
+                        Input::Line(line) => {
+                            tx.write_all(line.as_bytes()).await?;
+                            tx.write_all(b"\r").await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads lines from stdin on a thread of their own, since a read from stdin can't be cancelled
+/// and would otherwise hold up the runtime shutting down.
+fn stdin_lines() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Reads a kernel image from disk, compressing it if asked to.
+async fn read_kernel(path: &Path, compress: bool) -> io::Result<Vec<u8>> {
+    let kernel = tokio::fs::read(path).await?;
+    if !compress {
+        return Ok(kernel);
+    }
+    let compressed = self::compress(&kernel);
+    log::info!(
+        "Compressed kernel from {:#x} to {:#x} bytes",
+        kernel.len(),
+        compressed.len()
+    );
+    Ok(compressed)
+}
+
+/// Compresses a kernel image into the form the chainloader recognizes.
+fn compress(kernel: &[u8]) -> Vec<u8> {
+    let mut image = loader::LZ4_MAGIC.to_vec();
+    image.extend_from_slice(&(kernel.len() as u32).to_le_bytes());
+    image.extend_from_slice(&lz4_flex::block::compress(kernel));
+    image
+}
+
+/// Sends a frame to the chainloader, again and again until it's acknowledged.
+async fn send_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    seq: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let header = FrameHeader {
+        seq,
+        len: payload.len() as u16,
+    }
+    .encode();
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(payload);
+
+    let mut frame = Vec::with_capacity(1 + header.len() + payload.len() + 4);
+    frame.push(loader::SOH);
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&crc.finish().to_le_bytes());
+
+    for _ in 0..loader::MAX_RETRIES {
+        writer.write_all(&frame).await?;
+        let answer = timeout(
+            Duration::from_millis(loader::ACK_TIMEOUT_MS),
+            wait_for_ack(reader, seq),
+        )
+        .await;
+        match answer {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => log::warn!("Frame {seq} was corrupted, sending it again"),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => log::warn!("Frame {seq} wasn't answered, sending it again"),
+        }
+    }
+
+    Err(io::Error::other(format!(
+        "Frame {seq} failed {} times",
+        loader::MAX_RETRIES
+    )))
+}
+
+/// Reads the chainloader's answers until it acknowledges frame `seq` or asks for it again,
+/// returning whether it was acknowledged. Acknowledgements of earlier frames are left over
+/// from retransmissions, and are skipped.
+async fn wait_for_ack(reader: &mut (impl AsyncRead + Unpin), seq: u32) -> io::Result<bool> {
+    loop {
+        match reader.read_u8().await? {
+            loader::ACK => {
+                if reader.read_u32_le().await? == seq {
+                    return Ok(true);
+                }
             }
+            loader::NAK => return Ok(false),
+            _ => {}
         }
     }
 }
@@ -174,21 +369,24 @@ async fn maybe_handle_symbol_request(
     data: &[u8],
     tx: &mut WriteHalf<'_>,
 ) -> io::Result<bool> {
-    if data.starts_with(b"[sym?]") {
+    if let Some(addr) = data.strip_prefix(loader::SYMBOL_REQUEST) {
         if let Some(symbols) = symbols.as_ref() {
-            let addr = String::from_utf8_lossy(data.strip_prefix(b"[sym?]").unwrap());
+            let addr = String::from_utf8_lossy(addr);
             if let Ok(addr) = addr.trim().parse::<u64>() {
                 if let Some(name) = find_symbol(symbols, addr) {
                     tx.write_all(name).await?;
                     tx.write_all(b"\n").await?;
                 } else {
-                    tx.write_all(b"unknown\n").await?;
+                    tx.write_all(loader::SYMBOL_UNKNOWN).await?;
+                    tx.write_all(b"\n").await?;
                 }
             } else {
-                tx.write_all(b"unknown\n").await?;
+                tx.write_all(loader::SYMBOL_UNKNOWN).await?;
+                tx.write_all(b"\n").await?;
             }
         } else {
-            tx.write_all(b"unknown\n").await?;
+            tx.write_all(loader::SYMBOL_UNKNOWN).await?;
+            tx.write_all(b"\n").await?;
         }
         Ok(true)
     } else {
@@ -1,9 +1,10 @@
 pub mod client;
+pub mod monitor;
 pub mod server;
 
 use clap::{Parser, Subcommand};
 
-use client::{Client, ClientConfig};
+use client::{Client, ClientConfig, MonitorExit};
 use server::{Server, ServerConfig};
 
 pub fn is_disconnect(e: &std::io::Error) -> bool {
@@ -40,8 +41,13 @@ async fn main() -> anyhow::Result<()> {
         Command::Client(cfg) => {
             log::info!("Running as client");
             let mut client = Client::connect(&cfg).await?;
-            client.send_kernel().await?;
-            client.monitor().await?;
+            loop {
+                client.send_kernel().await?;
+                if client.monitor().await? == MonitorExit::Quit {
+                    break;
+                }
+                client.reload_kernel().await?;
+            }
         }
         Command::Server(cfg) => {
             log::info!("Running as server");
@@ -0,0 +1,385 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use kados_abi::{
+    crash::{CRASH_RECORD_MAGIC, CrashRecordHeader},
+    loader::{self, BootStage, crc32},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{self, AsyncWriteExt},
+};
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct MonitorConfig {
+    /// Prefix each line of output with the time since the monitor started
+    #[clap(long, default_value_t = false)]
+    timestamps: bool,
+    /// Strip ANSI escape sequences from the output
+    #[clap(long, default_value_t = false)]
+    strip_ansi: bool,
+    /// Also write the output to this file
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    /// Size in bytes at which the log file is rotated
+    #[clap(long, default_value_t = 8 * 1024 * 1024)]
+    log_max_size: u64,
+    /// Number of rotated log files to keep, as `<log file>.1` (newest) and up
+    #[clap(long, default_value_t = 4)]
+    log_keep: usize,
+    /// Directory to save crash records the kernel sends when it panics
+    #[clap(long, default_value = "target/crash")]
+    crash_dir: PathBuf,
+}
+
+impl MonitorConfig {
+    pub fn output_filter(&self) -> OutputFilter {
+        OutputFilter::new(self.timestamps, self.strip_ansi)
+    }
+
+    pub fn crash_capture(&self) -> CrashCapture {
+        CrashCapture::new(self.crash_dir.clone())
+    }
+
+    pub async fn open_log_file(&self) -> io::Result<Option<LogFile>> {
+        match &self.log_file {
+            Some(path) => Ok(Some(
+                LogFile::open(path, self.log_max_size, self.log_keep).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+/// What the user asked for with an escape command, or a line to send to the board.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// `~q`: quit.
+    Quit,
+    /// `~r`: send the kernel again.
+    Resend,
+    /// `~?`: list the escape commands.
+    Help,
+    /// A `~` followed by anything else.
+    Unknown(String),
+    /// Anything else, with `~~` at the start standing for a single `~`.
+    Line(String),
+}
+
+impl Input {
+    pub const HELP: &str =
+        "~q  quit\n~r  send the kernel again\n~~  send a line starting with ~\n~?  show this help";
+
+    pub fn parse(line: &str) -> Self {
+        match line.strip_prefix('~') {
+            None => Self::Line(line.to_owned()),
+            Some(rest) if rest.starts_with('~') => Self::Line(rest.to_owned()),
+            Some("q") => Self::Quit,
+            Some("r") => Self::Resend,
+            Some("?") => Self::Help,
+            Some(rest) => Self::Unknown(rest.to_owned()),
+        }
+    }
+}
+
+/// Where an [`OutputFilter`] is in an ANSI escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Text,
+    /// Just after the escape.
+    Escape,
+    /// In a control sequence, which runs up to a byte in `0x40..=0x7e`.
+    Csi,
+}
+
+/// Turns the board's output into what's shown and logged, stripping ANSI escape sequences and
+/// adding timestamps if asked to. Sequences and lines may be split across reads.
+pub struct OutputFilter {
+    timestamps: bool,
+    strip_ansi: bool,
+    start: Instant,
+    ansi: AnsiState,
+    at_line_start: bool,
+}
+
+impl OutputFilter {
+    pub fn new(timestamps: bool, strip_ansi: bool) -> Self {
+        Self {
+            timestamps,
+            strip_ansi,
+            start: Instant::now(),
+            ansi: AnsiState::Text,
+            at_line_start: true,
+        }
+    }
+
+    /// Filters `data`, appending the result to `out`.
+    pub fn filter(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &b in data {
+            if self.strip_ansi && !self.keep(b) {
+                continue;
+            }
+            if self.timestamps && self.at_line_start {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                out.extend_from_slice(format!("[{elapsed:>10.3}] ").as_bytes());
+            }
+            out.push(b);
+            self.at_line_start = b == b'\n';
+        }
+    }
+
+    /// Returns whether `b` is text rather than part of an escape sequence.
+    fn keep(&mut self, b: u8) -> bool {
+        self.ansi = match (self.ansi, b) {
+            (AnsiState::Text, 0x1b) => AnsiState::Escape,
+            (AnsiState::Text, _) => return true,
+            (AnsiState::Escape, b'[') => AnsiState::Csi,
+            (AnsiState::Csi, 0x40..=0x7e) | (AnsiState::Escape, _) => AnsiState::Text,
+            (AnsiState::Csi, _) => AnsiState::Csi,
+        };
+        false
+    }
+}
+
+/// Where a [`CrashCapture`] is in a crash record.
+#[derive(Debug)]
+enum CaptureState {
+    /// Looking for the magic, with this much of it seen so far.
+    Scanning(usize),
+    /// Reading the rest of the header.
+    Header(Vec<u8>),
+    /// Reading the payload.
+    Payload(CrashRecordHeader, Vec<u8>),
+}
+
+/// Picks the crash records a panicking kernel sends out of the board's output, passing
+/// everything else through, and saves them to files. Records may be split across reads.
+pub struct CrashCapture {
+    dir: PathBuf,
+    state: CaptureState,
+}
+
+impl CrashCapture {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            state: CaptureState::Scanning(0),
+        }
+    }
+
+    /// Feeds `data` through, appending whatever isn't part of a crash record to `out` and
+    /// returning the records it completes.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> Vec<(CrashRecordHeader, Vec<u8>)> {
+        let mut records = Vec::new();
+        for &b in data {
+            match &mut self.state {
+                CaptureState::Scanning(seen) => {
+                    if b == CRASH_RECORD_MAGIC[*seen] {
+                        *seen += 1;
+                    } else {
+                        // the magic only starts with its first byte, so no part of what was
+                        // held back can start another one
+                        out.extend_from_slice(&CRASH_RECORD_MAGIC[..*seen]);
+                        *seen = usize::from(b == CRASH_RECORD_MAGIC[0]);
+                        if *seen == 0 {
+                            out.push(b);
+                        }
+                    }
+                    if *seen == CRASH_RECORD_MAGIC.len() {
+                        self.state = CaptureState::Header(CRASH_RECORD_MAGIC.to_vec());
+                    }
+                }
+                CaptureState::Header(header) => {
+                    header.push(b);
+                    if let Ok(bytes) = <&[u8; CrashRecordHeader::SIZE]>::try_from(&header[..]) {
+                        let header = CrashRecordHeader::decode(bytes);
+                        self.state = if !header.is_valid() {
+                            log::warn!(
+                                "Ignoring a crash record of unknown version {}",
+                                header.version
+                            );
+                            CaptureState::Scanning(0)
+                        } else if header.len == 0 {
+                            records.push((header, Vec::new()));
+                            CaptureState::Scanning(0)
+                        } else {
+                            CaptureState::Payload(header, Vec::with_capacity(header.len as usize))
+                        };
+                    }
+                }
+                CaptureState::Payload(header, payload) => {
+                    payload.push(b);
+                    if payload.len() == header.len as usize {
+                        records.push((*header, std::mem::take(payload)));
+                        self.state = CaptureState::Scanning(0);
+                    }
+                }
+            }
+        }
+        records
+    }
+
+    /// Saves a record's payload to `<crash dir>/crash-<unix time>.txt`, returning the path.
+    pub async fn save(&self, header: &CrashRecordHeader, payload: &[u8]) -> io::Result<PathBuf> {
+        if crc32(payload) != header.crc32 {
+            log::warn!("The crash record's checksum doesn't match; saving it anyway");
+        }
+        fs::create_dir_all(&self.dir).await?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self.dir.join(format!("crash-{secs}.txt"));
+        fs::write(&path, payload).await?;
+        Ok(path)
+    }
+}
+
+/// Where a [`ProgressDecoder`] is in a progress report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgressState {
+    /// Looking for [`loader::PROGRESS`], with this much of it seen so far.
+    Scanning(usize),
+    /// Waiting for the stage's code.
+    Code,
+    /// Dropping the `\r\n` after the code.
+    LineEnd,
+}
+
+/// Picks the progress reports of the boot stages out of the board's output, passing
+/// everything else through. Reports may be split across reads.
+pub struct ProgressDecoder {
+    state: ProgressState,
+}
+
+impl Default for ProgressDecoder {
+    fn default() -> Self {
+        Self {
+            state: ProgressState::Scanning(0),
+        }
+    }
+}
+
+impl ProgressDecoder {
+    /// Feeds `data` through, appending whatever isn't part of a progress report to `out` and
+    /// returning the codes of the reports it completes, which [`BootStage::from_code`] decodes.
+    fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> Vec<u8> {
+        let mut codes = Vec::new();
+        for &b in data {
+            self.state = match self.state {
+                ProgressState::Scanning(seen) if b == loader::PROGRESS[seen] => {
+                    if seen + 1 == loader::PROGRESS.len() {
+                        ProgressState::Code
+                    } else {
+                        ProgressState::Scanning(seen + 1)
+                    }
+                }
+                ProgressState::Scanning(seen) => {
+                    // as with crash records, only the first byte of the prefix can start one
+                    out.extend_from_slice(&loader::PROGRESS[..seen]);
+                    if b == loader::PROGRESS[0] {
+                        ProgressState::Scanning(1)
+                    } else {
+                        out.push(b);
+                        ProgressState::Scanning(0)
+                    }
+                }
+                ProgressState::Code => {
+                    codes.push(b);
+                    ProgressState::LineEnd
+                }
+                ProgressState::LineEnd if b == b'\r' => ProgressState::LineEnd,
+                ProgressState::LineEnd if b == b'\n' => ProgressState::Scanning(0),
+                ProgressState::LineEnd => {
+                    out.push(b);
+                    ProgressState::Scanning(0)
+                }
+            };
+        }
+        codes
+    }
+
+    /// Logs the reports completed by `data`, returning what isn't part of one.
+    pub fn log(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for code in self.feed(data, &mut out) {
+            match BootStage::from_code(code) {
+                Some(stage) if stage.is_fatal() => log::error!("Boot: {}", stage.description()),
+                Some(stage) => log::info!("Boot: {}", stage.description()),
+                None => log::warn!("Boot: unknown progress code {:?}", char::from(code)),
+            }
+        }
+        out
+    }
+}
+
+/// A log of the monitor's output, rotated once it gets too big.
+pub struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    /// Opens the log at `path`, appending to it if it already exists.
+    pub async fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let size = file.metadata().await?.len();
+        log::info!("Logging output to {}", path.display());
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + data.len() as u64 > self.max_size {
+            self.rotate().await?;
+        }
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Moves `<path>` to `<path>.1`, `<path>.1` to `<path>.2` and so on, dropping the oldest,
+    /// and starts a new `<path>`.
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1)).await?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)
+            .await?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
@@ -1,9 +1,14 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
+use kados_abi::loader;
+
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::{TcpListener, tcp::OwnedWriteHalf},
@@ -29,6 +34,9 @@ pub struct ServerConfig {
     /// Size of serial read/write chunks
     #[clap(long, default_value_t = 16*1024)]
     chunk_size: usize,
+    /// Address to bind a GDB remote protocol proxy to, for the kernel's GDB stub
+    #[clap(long)]
+    gdb_addr: Option<SocketAddr>,
 }
 
 pub struct SerialConnection {
@@ -51,12 +59,64 @@ pub struct MonitorClient {
     pub task: JoinHandle<io::Result<()>>,
 }
 
+/// Splits what comes over the serial port into console output and the kernel GDB stub's
+/// traffic, which the stub brackets with [`loader::GDB_ENTER`] and [`loader::GDB_EXIT`].
+#[derive(Default)]
+pub struct Demux {
+    in_gdb: bool,
+    /// How much of the next marker has been seen. Those bytes are held back until it's clear
+    /// whether they're really the marker.
+    matched: usize,
+}
+
+impl Demux {
+    /// Sorts `data` into `console` and `gdb`, leaving out the markers.
+    pub fn feed(&mut self, data: &[u8], console: &mut Vec<u8>, gdb: &mut Vec<u8>) {
+        for &b in data {
+            let marker = if self.in_gdb {
+                loader::GDB_EXIT
+            } else {
+                loader::GDB_ENTER
+            };
+            if b == marker[self.matched] {
+                self.matched += 1;
+                if self.matched == marker.len() {
+                    self.in_gdb = !self.in_gdb;
+                    self.matched = 0;
+                }
+                continue;
+            }
+
+            let out = if self.in_gdb {
+                &mut *gdb
+            } else {
+                &mut *console
+            };
+            out.extend_from_slice(&marker[..self.matched]);
+            self.matched = usize::from(b == marker[0]);
+            if self.matched == 0 {
+                out.push(b);
+            }
+        }
+    }
+
+    /// Returns whether the kernel is stopped in its GDB stub.
+    pub fn in_gdb(&self) -> bool {
+        self.in_gdb
+    }
+}
+
 pub struct Server {
     serial: Arc<SerialConnection>,
     monitor_socket: TcpListener,
     monitor_clients: RwLock<BTreeMap<SocketAddr, Mutex<MonitorClient>>>,
     disconnected_clients: RwLock<BTreeSet<SocketAddr>>,
     chunk_size: usize,
+    gdb_socket: Option<TcpListener>,
+    /// The debugger connected to the GDB proxy, if any.
+    gdb_client: Mutex<Option<OwnedWriteHalf>>,
+    /// Set while the kernel is stopped in its GDB stub, when only the debugger may talk to it.
+    in_gdb: AtomicBool,
 }
 
 impl Server {
@@ -64,6 +124,13 @@ impl Server {
         let serial_port = SerialStream::open(&tokio_serial::new(&config.device, config.baud))?;
         let monitor_socket = TcpListener::bind(config.monitor_addr).await?;
         log::info!("Listening on {}", config.monitor_addr);
+        let gdb_socket = if let Some(gdb_addr) = config.gdb_addr {
+            let socket = TcpListener::bind(gdb_addr).await?;
+            log::info!("Listening for GDB on {gdb_addr}");
+            Some(socket)
+        } else {
+            None
+        };
 
         Ok(Arc::new(Self {
             serial: Arc::new(SerialConnection::new(serial_port)),
@@ -71,6 +138,9 @@ impl Server {
             monitor_clients: RwLock::new(BTreeMap::new()),
             disconnected_clients: RwLock::new(BTreeSet::new()),
             chunk_size: config.chunk_size,
+            gdb_socket,
+            gdb_client: Mutex::new(None),
+            in_gdb: AtomicBool::new(false),
         }))
     }
 
@@ -81,6 +151,7 @@ impl Server {
         let serial_loop = tokio::spawn(serial_clone.serial_loop());
         let monitor_loop = tokio::spawn(monitor_clone.accept_monitor_connections());
         let reap_loop = tokio::spawn(reap_clone.reap_disconnected_clients());
+        let gdb_loop = tokio::spawn(self.clone().accept_gdb_connections());
         tokio::select! {
             res = serial_loop => {
                 if let Err(e) = res {
@@ -97,6 +168,11 @@ impl Server {
                     log::error!("Reap loop error: {e}");
                 }
             }
+            res = gdb_loop => {
+                if let Err(e) = res {
+                    log::error!("GDB loop error: {e}");
+                }
+            }
             _ = tokio::signal::ctrl_c() => {
                 log::info!("Received Ctrl+C, shutting down...");
             }
@@ -134,16 +210,39 @@ impl Server {
 
     async fn serial_loop(self: Arc<Self>) -> io::Result<()> {
         let mut buf = vec![0u8; self.chunk_size];
+        let mut demux = Demux::default();
+        let mut console = Vec::with_capacity(self.chunk_size);
+        let mut gdb = Vec::new();
         loop {
             let n = self.serial.rx.lock().await.read(&mut buf).await?;
             if n == 0 {
                 log::warn!("Serial connection closed");
                 break;
             }
+
+            console.clear();
+            gdb.clear();
+            if self.gdb_socket.is_some() {
+                demux.feed(&buf[..n], &mut console, &mut gdb);
+                if demux.in_gdb() != self.in_gdb.swap(demux.in_gdb(), Ordering::AcqRel) {
+                    if demux.in_gdb() {
+                        log::info!("Kernel stopped in its GDB stub");
+                    } else {
+                        log::info!("Kernel resumed by GDB");
+                    }
+                }
+                self.forward_to_gdb(&gdb).await;
+            } else {
+                console.extend_from_slice(&buf[..n]);
+            }
+            if console.is_empty() {
+                continue;
+            }
+
             let monitor_clients = self.monitor_clients.read().await;
             for (addr, client) in monitor_clients.iter() {
                 let mut conn = client.lock().await;
-                match conn.tx.write_all(&buf[..n]).await {
+                match conn.tx.write_all(&console).await {
                     Ok(()) => {}
                     Err(e) => {
                         if is_disconnect(&e) {
@@ -189,6 +288,10 @@ impl Server {
                             return Err(e);
                         }
                     };
+                    if self_clone.in_gdb.load(Ordering::Acquire) {
+                        log::debug!("Dropping input from {addr} while the kernel is stopped");
+                        continue;
+                    }
                     let mut serial_tx = self_clone.serial.tx.lock().await;
                     serial_tx.write_all(&buf[..n]).await?;
                 }
@@ -200,4 +303,78 @@ impl Server {
                 .insert(addr, Mutex::new(MonitorClient { tx, task }));
         }
     }
+
+    /// Sends the GDB stub's traffic to the debugger, or drops it if none is connected. Once
+    /// one connects, its first packet acknowledgement gets the stub going again.
+    async fn forward_to_gdb(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut gdb_client = self.gdb_client.lock().await;
+        let Some(tx) = gdb_client.as_mut() else {
+            log::debug!("Dropping {} bytes from the GDB stub", data.len());
+            return;
+        };
+        if let Err(e) = tx.write_all(data).await {
+            if is_disconnect(&e) {
+                log::warn!("GDB disconnected: {e}");
+            } else {
+                log::error!("Error writing to GDB: {e}");
+            }
+            *gdb_client = None;
+        }
+    }
+
+    async fn accept_gdb_connections(self: Arc<Self>) -> io::Result<()> {
+        let Some(socket) = &self.gdb_socket else {
+            return std::future::pending().await;
+        };
+        loop {
+            let (conn, addr) = socket.accept().await?;
+            conn.set_nodelay(true)?;
+            let (mut rx, tx) = conn.into_split();
+            {
+                let mut gdb_client = self.gdb_client.lock().await;
+                if gdb_client.is_some() {
+                    log::warn!("Refusing GDB connection from {addr}, one is already attached");
+                    continue;
+                }
+                *gdb_client = Some(tx);
+            }
+            log::info!("Accepted GDB connection from {addr}");
+            if !self.in_gdb.load(Ordering::Acquire) {
+                log::warn!("The kernel isn't stopped; use its `gdb` shell command to stop it");
+            }
+
+            let self_clone = self.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; self_clone.chunk_size];
+                loop {
+                    let n = match rx.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) => {
+                            if !is_disconnect(&e) {
+                                log::error!("Error reading from GDB: {e}");
+                            }
+                            break;
+                        }
+                    };
+                    // the stub only listens while the kernel is stopped; anything else would
+                    // end up in the shell
+                    if !self_clone.in_gdb.load(Ordering::Acquire) {
+                        log::warn!("Dropping GDB packet while the kernel is running");
+                        continue;
+                    }
+                    let mut serial_tx = self_clone.serial.tx.lock().await;
+                    if let Err(e) = serial_tx.write_all(&buf[..n]).await {
+                        log::error!("Error writing to serial: {e}");
+                        break;
+                    }
+                }
+                log::info!("GDB connection from {addr} closed");
+                *self_clone.gdb_client.lock().await = None;
+            });
+        }
+    }
 }